@@ -7,9 +7,19 @@
 //! - $limit: Limit results  
 //! - $skip: Skip documents
 //! - $group: Group and aggregate
+//! - $vectorSearch: Nearest neighbours from a vector index
 
+use crate::document::Decimal128;
+use crate::index::statistics::PerformanceTimer;
+use crate::query::explain::ExecutionStats;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Most documents a pipeline returns
+const MAX_RESULT_DOCS: usize = 100_000; // 100k documents max in memory
 
 /// Pipeline stage in aggregation
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,28 +55,188 @@ pub enum PipelineStage {
         _id: serde_json::Value,
         fields: HashMap<String, AggregateOp>,
     },
+    
+    #[serde(rename = "vectorSearch")]
+    VectorSearch(VectorSearch),
+}
+
+/// Nearest-neighbour search that feeds a pipeline
+///
+/// Must be the first stage: it reads from the collection's vector index
+/// rather than from a stream of documents, so storage runs it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VectorSearch {
+    /// Vector index to search; defaults to the vector index on `path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+    /// Field holding the embeddings
+    pub path: String,
+    /// Vector to find the neighbours of
+    #[serde(alias = "queryVector")]
+    pub query_vector: Vec<f64>,
+    /// Number of documents to return
+    pub k: usize,
+    /// Candidates the search considers; more finds the true neighbours more often
+    #[serde(default, alias = "numCandidates", skip_serializing_if = "Option::is_none")]
+    pub num_candidates: Option<usize>,
+    /// Query filter a document must match to be returned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<serde_json::Value>,
+    /// Field the similarity score is written to
+    #[serde(default = "default_score_field", alias = "scoreField")]
+    pub score_field: String,
+}
+
+fn default_score_field() -> String {
+    "score".to_string()
+}
+
+impl VectorSearch {
+    /// Candidates to consider, ten per result unless set
+    pub fn candidates(&self) -> usize {
+        self.num_candidates.unwrap_or(self.k.saturating_mul(10)).max(self.k)
+    }
+}
+
+impl PipelineStage {
+    /// Stage name, as written after `$`
+    pub fn name(&self) -> &'static str {
+        match self {
+            PipelineStage::Match { .. } => "match",
+            PipelineStage::Project { .. } => "project",
+            PipelineStage::Sort { .. } => "sort",
+            PipelineStage::Limit { .. } => "limit",
+            PipelineStage::Skip { .. } => "skip",
+            PipelineStage::Group { .. } => "group",
+            PipelineStage::VectorSearch(_) => "vectorSearch",
+        }
+    }
 }
 
 /// Aggregate operations for $group
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "$")]
+///
+/// Encoded as `{"$": "count"}` and `{"$": "sum", "field": "$amount"}`: the
+/// operand goes in `field`, since a tagged map cannot carry a bare string.
+/// Decoding also accepts the operand as the rest of the map, as in
+/// `{"$": "sum", "value": 1}`, and the externally tagged `{"count": {}}`.
+#[derive(Debug, Clone)]
 pub enum AggregateOp {
-    #[serde(rename = "sum")]
     Sum(serde_json::Value),
-    
-    #[serde(rename = "count")]
+
     Count {},
-    
-    #[serde(rename = "avg")]
+
     Avg(String),
-    
-    #[serde(rename = "min")]
+
     Min(String),
-    
-    #[serde(rename = "max")]
+
     Max(String),
 }
 
+impl AggregateOp {
+    /// Operator name, as written in `$`
+    fn name(&self) -> &'static str {
+        match self {
+            AggregateOp::Sum(_) => "sum",
+            AggregateOp::Count {} => "count",
+            AggregateOp::Avg(_) => "avg",
+            AggregateOp::Min(_) => "min",
+            AggregateOp::Max(_) => "max",
+        }
+    }
+
+    /// Build an operator from its name and operand
+    fn from_parts(name: &str, operand: serde_json::Value) -> Result<Self, String> {
+        let field = |operand: serde_json::Value| match operand {
+            serde_json::Value::String(field) => Ok(field),
+            other => Err(format!("${} expects a field name, got {}", name, other)),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "sum" => Ok(AggregateOp::Sum(operand)),
+            "count" => Ok(AggregateOp::Count {}),
+            "avg" => field(operand).map(AggregateOp::Avg),
+            "min" => field(operand).map(AggregateOp::Min),
+            "max" => field(operand).map(AggregateOp::Max),
+            other => Err(format!("unknown aggregate operator '{}'", other)),
+        }
+    }
+}
+
+impl Serialize for AggregateOp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let operand = match self {
+            AggregateOp::Sum(value) => Some(value.clone()),
+            AggregateOp::Count {} => None,
+            AggregateOp::Avg(field) | AggregateOp::Min(field) | AggregateOp::Max(field) => {
+                Some(serde_json::Value::String(field.clone()))
+            }
+        };
+        let mut map = serializer.serialize_map(Some(1 + usize::from(operand.is_some())))?;
+        map.serialize_entry("$", self.name())?;
+        if let Some(operand) = operand {
+            map.serialize_entry("field", &operand)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for AggregateOp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let mut map = serde_json::Map::deserialize(deserializer)?;
+        let (name, operand) = match map.remove("$") {
+            Some(serde_json::Value::String(name)) => {
+                let operand = match map.remove("field") {
+                    Some(field) if map.is_empty() => field,
+                    Some(field) => {
+                        map.insert("field".to_string(), field);
+                        serde_json::Value::Object(map)
+                    }
+                    None => serde_json::Value::Object(map),
+                };
+                (name, operand)
+            }
+            Some(other) => return Err(D::Error::custom(format!("aggregate operator must be a string, got {}", other))),
+            None if map.len() == 1 => map.into_iter().next().unwrap_or_default(),
+            None => return Err(D::Error::custom("aggregate operator needs a \"$\" tag")),
+        };
+        AggregateOp::from_parts(&name, operand).map_err(D::Error::custom)
+    }
+}
+
+/// Output of a pipeline stage
+type DocumentStream = Box<dyn Iterator<Item = crate::document::Document>>;
+
+/// Counters of the instrumented stages, in pipeline order
+type StageCounters = Vec<Rc<RefCell<StageCounter>>>;
+
+/// Documents and inclusive time of one instrumented stage
+struct StageCounter {
+    documents_out: usize,
+    time: Duration,
+}
+
+/// Stage output that updates its counter on every pull
+struct CountedStage {
+    inner: DocumentStream,
+    counter: Rc<RefCell<StageCounter>>,
+}
+
+impl Iterator for CountedStage {
+    type Item = crate::document::Document;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        let timer = PerformanceTimer::start();
+        let item = self.inner.next();
+        let mut counter = self.counter.borrow_mut();
+        counter.time += timer.elapsed();
+        counter.documents_out += usize::from(item.is_some());
+        item
+    }
+}
+
 /// Aggregation pipeline
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Pipeline {
@@ -83,11 +253,58 @@ impl Pipeline {
     /// as required by the memory safety guardrail.
     pub fn execute(&self, documents: Vec<crate::document::Document>) ->  Result<Vec<crate::document::Document>, AggregationError>
     {
+        let (current, _) = self.build(documents, false)?;
+        
+        // Collect results with memory limit
+        let results: Vec<_> = current.take(MAX_RESULT_DOCS).collect();
+        
+        Ok(results)
+    }
+    
+    /// Execute the pipeline and report the documents and time of each stage
+    ///
+    /// Stages still stream; a stage's time excludes the time spent pulling
+    /// documents from the stages before it.
+    pub fn execute_with_stats(
+        &self,
+        documents: Vec<crate::document::Document>,
+    ) -> Result<(Vec<crate::document::Document>, ExecutionStats), AggregationError> {
+        let total = PerformanceTimer::start();
+        let mut stats = ExecutionStats::new();
+        stats.documents_examined = documents.len() as u64;
+        
+        let (current, counters) = self.build(documents, true)?;
+        let results: Vec<_> = current.take(MAX_RESULT_DOCS).collect();
+        
+        let mut upstream = Duration::ZERO;
+        for (stage, counter) in self.stages.iter().zip(counters) {
+            let counter = counter.borrow();
+            stats.record_stage(stage.name(), counter.documents_out, counter.time.saturating_sub(upstream));
+            upstream = counter.time;
+        }
+        stats.documents_returned = results.len() as u64;
+        stats.execution_time = total.elapsed();
+        
+        Ok((results, stats))
+    }
+    
+    /// Chain the stage iterators
+    ///
+    /// With `instrument`, each stage's output is wrapped in a counter that
+    /// records the documents it yields and the time spent building and
+    /// pulling from it, upstream stages included.
+    fn build(
+        &self,
+        documents: Vec<crate::document::Document>,
+        instrument: bool,
+    ) -> Result<(DocumentStream, StageCounters), AggregationError> {
         // Start with document iterator
-        let mut current: Box<dyn Iterator<Item = crate::document::Document>> = Box::new(documents.into_iter());
+        let mut current: DocumentStream = Box::new(documents.into_iter());
+        let mut counters = Vec::new();
         
         // Apply each stage in sequence
         for stage in &self.stages {
+            let timer = PerformanceTimer::start();
             current = match stage {
                 PipelineStage::Match { filter } => {
                     Box::new(Self::apply_match(current, filter.clone())?)
@@ -109,14 +326,41 @@ impl Pipeline {
                     // Group requires materialization but we enforce MAX_GROUP_SIZE
                     Box::new(Self::apply_group(current, _id.clone(), fields.clone())?)
                 }
+                PipelineStage::VectorSearch(_) => {
+                    return Err(AggregationError::InvalidStage(
+                        "$vectorSearch must be the first stage of a collection pipeline".to_string(),
+                    ))
+                }
             };
+            
+            if instrument {
+                let counter = Rc::new(RefCell::new(StageCounter {
+                    documents_out: 0,
+                    time: timer.elapsed(),
+                }));
+                counters.push(counter.clone());
+                current = Box::new(CountedStage { inner: current, counter });
+            }
         }
         
-        // Collect results with memory limit
-        const MAX_RESULT_DOCS: usize = 100_000; // 100k documents max in memory
-        let results: Vec<_> = current.take(MAX_RESULT_DOCS).collect();
+        Ok((current, counters))
+    }
+    
+    /// Describe each stage as a document value, in pipeline order
+    pub fn describe(&self) -> Vec<crate::document::Value> {
+        use crate::document::Value;
+        use std::collections::BTreeMap;
         
-        Ok(results)
+        self.stages
+            .iter()
+            .map(|stage| {
+                let spec = serde_json::to_value(stage).unwrap_or(serde_json::Value::Null);
+                Value::Object(BTreeMap::from([
+                    ("stage".to_string(), Value::String(stage.name().to_string())),
+                    ("spec".to_string(), Self::json_to_doc_value(&spec)),
+                ]))
+            })
+            .collect()
     }
     
     /// Apply $match stage - filter documents
@@ -307,14 +551,22 @@ impl Pipeline {
             (Some(Value::Float64(a)), Some(Value::Float64(b))) => {
                 a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)
             }
+            (Some(a @ Value::Decimal128(_)), Some(b)) | (Some(a), Some(b @ Value::Decimal128(_))) => {
+                match (a.as_decimal(), b.as_decimal()) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    _ => std::cmp::Ordering::Equal,
+                }
+            }
             (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
             (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+            (Some(Value::Uuid(a)), Some(Value::Uuid(b))) => a.cmp(b),
+            (Some(Value::Timestamp(a)), Some(Value::Timestamp(b))) => a.cmp(b),
             _ => std::cmp::Ordering::Equal,
         }
     }
     
     /// Helper: Extract group key from document
-    fn extract_group_key(doc: &crate::document::Document, group_by: &serde_json::Value) -> String {
+    pub(crate) fn extract_group_key(doc: &crate::document::Document, group_by: &serde_json::Value) -> String {
         if let Some(s) = group_by.as_str() {
             // Simple field reference like "_id": "$category"
             if let Some(field) = s.strip_prefix('$') {
@@ -349,6 +601,9 @@ impl Pipeline {
                 Value::Array(arr.iter().map(Self::json_to_doc_value).collect())
             }
             serde_json::Value::Object(obj) => {
+                if let Ok(Some(typed)) = crate::query::QueryParser::typed_value(obj) {
+                    return typed;
+                }
                 let mut map = BTreeMap::new();
                 for (k, v) in obj {
                     map.insert(k.clone(), Self::json_to_doc_value(v));
@@ -402,12 +657,23 @@ impl GroupAccumulator {
 }
 
 /// Accumulator state for aggregation operations
+///
+/// Sums, averages and extremes are kept both as floats and as exact
+/// decimals. Once a decimal input shows up the result is the exact one,
+/// so `$sum` over decimal amounts never rounds through `f64`.
 struct AccumulatorState {
     op: AggregateOp,
     sum: f64,
     count: usize,
     min: Option<f64>,
     max: Option<f64>,
+    /// Whether every input so far had an exact decimal value
+    exact: bool,
+    /// Whether any input was a decimal
+    saw_decimal: bool,
+    exact_sum: Decimal128,
+    exact_min: Option<Decimal128>,
+    exact_max: Option<Decimal128>,
 }
 
 impl AccumulatorState {
@@ -418,7 +684,36 @@ impl AccumulatorState {
             count: 0,
             min: None,
             max: None,
+            exact: true,
+            saw_decimal: false,
+            exact_sum: Decimal128::ZERO,
+            exact_min: None,
+            exact_max: None,
+        }
+    }
+
+    /// Exact counterpart of [`to_number`](Self::to_number), or `None` for
+    /// infinite and NaN floats
+    fn exact_value(&mut self, val: &crate::document::Value) -> Option<Decimal128> {
+        self.saw_decimal |= matches!(val, crate::document::Value::Decimal128(_));
+        let decimal = if val.is_number() { val.as_decimal() } else { Some(Decimal128::ZERO) };
+        self.exact &= decimal.is_some();
+        decimal.filter(|_| self.exact)
+    }
+
+    fn add_exact(&mut self, val: &crate::document::Value) -> Result<(), AggregationError> {
+        if let Some(decimal) = self.exact_value(val) {
+            self.exact_sum = self
+                .exact_sum
+                .checked_add(decimal)
+                .map_err(|e| AggregationError::ExecutionError(e.to_string()))?;
         }
+        Ok(())
+    }
+
+    /// Whether results should be the exact decimals
+    fn is_decimal(&self) -> bool {
+        self.saw_decimal && self.exact
     }
     
     fn add(&mut self, doc: &crate::document::Document) -> Result<(), AggregationError> {
@@ -430,6 +725,7 @@ impl AccumulatorState {
                     if let Some(field) = s.strip_prefix('$') {
                         if let Some(val) = doc.get(field) {
                             self.sum += Self::to_number(val);
+                            self.add_exact(val)?;
                         }
                     }
                 }
@@ -440,6 +736,7 @@ impl AccumulatorState {
             AggregateOp::Avg(field) => {
                 if let Some(val) = doc.get(field) {
                     self.sum += Self::to_number(val);
+                    self.add_exact(val)?;
                     self.count += 1;
                 }
             }
@@ -447,12 +744,18 @@ impl AccumulatorState {
                 if let Some(val) = doc.get(field) {
                     let num = Self::to_number(val);
                     self.min = Some(self.min.map_or(num, |m| m.min(num)));
+                    if let Some(decimal) = self.exact_value(val) {
+                        self.exact_min = Some(self.exact_min.map_or(decimal, |m| m.min(decimal)));
+                    }
                 }
             }
             AggregateOp::Max(field) => {
                 if let Some(val) = doc.get(field) {
                     let num = Self::to_number(val);
                     self.max = Some(self.max.map_or(num, |m| m.max(num)));
+                    if let Some(decimal) = self.exact_value(val) {
+                        self.exact_max = Some(self.exact_max.map_or(decimal, |m| m.max(decimal)));
+                    }
                 }
             }
         }
@@ -463,6 +766,19 @@ impl AccumulatorState {
     fn value(self) -> crate::document::Value {
         use crate::document::Value;
         
+        if self.is_decimal() {
+            let exact = match self.op {
+                AggregateOp::Sum(_) => Some(self.exact_sum),
+                AggregateOp::Avg(_) => self.exact_sum.checked_div(Decimal128::from(self.count as i64)).ok(),
+                AggregateOp::Min(_) => self.exact_min,
+                AggregateOp::Max(_) => self.exact_max,
+                AggregateOp::Count {} => None,
+            };
+            if let Some(exact) = exact {
+                return Value::Decimal128(exact);
+            }
+        }
+
         match self.op {
             AggregateOp::Sum(_) => Value::Float64(self.sum),
             AggregateOp::Count {} => Value::Int64(self.count as i64),
@@ -485,6 +801,7 @@ impl AccumulatorState {
             Value::Int32(i) => *i as f64,
             Value::Int64(i) => *i as f64,
             Value::Float64(f) => *f,
+            Value::Decimal128(d) => d.to_f64(),
             _ => 0.0,
         }
     }
@@ -502,3 +819,103 @@ pub enum AggregationError {
     #[error("Execution error: {0}")]
     ExecutionError(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Document, Value};
+
+    #[test]
+    fn test_execute_with_stats_counts_each_stage() {
+        let documents: Vec<Document> = (0..10)
+            .map(|i| {
+                let mut doc = Document::new();
+                doc.insert("n".to_string(), Value::Int32(i));
+                doc.insert("even".to_string(), Value::Bool(i % 2 == 0));
+                doc
+            })
+            .collect();
+        let pipeline = Pipeline::new(vec![
+            PipelineStage::Match { filter: serde_json::json!({"even": true}) },
+            PipelineStage::Sort { fields: HashMap::from([("n".to_string(), -1)]) },
+            PipelineStage::Limit { count: 2 },
+        ]);
+
+        let (results, stats) = pipeline.execute_with_stats(documents.clone()).unwrap();
+        let fields = |docs: Vec<Document>| docs.into_iter().map(|d| d.fields).collect::<Vec<_>>();
+        assert_eq!(fields(results.clone()), fields(pipeline.execute(documents).unwrap()));
+        assert_eq!(results[0].get("n"), Some(&Value::Int32(8)));
+        assert_eq!(stats.documents_examined, 10);
+        assert_eq!(stats.documents_returned, 2);
+
+        let stages: Vec<_> = stats.stages.iter().map(|s| (s.stage.as_str(), s.documents_out)).collect();
+        // Limit stops pulling from the sort after two documents
+        assert_eq!(stages, vec![("match", 5), ("sort", 2), ("limit", 2)]);
+        assert!(stats.stages.iter().map(|s| s.time).sum::<Duration>() <= stats.execution_time);
+    }
+
+    #[test]
+    fn test_group_sums_decimals_exactly() {
+        let amounts = ["0.10", "0.20", "1E-2"];
+        let mut documents: Vec<Document> = amounts
+            .iter()
+            .map(|amount| {
+                let mut doc = Document::new();
+                doc.insert("account".to_string(), Value::String("cash".to_string()));
+                doc.insert("amount".to_string(), Value::Decimal128(amount.parse().unwrap()));
+                doc
+            })
+            .collect();
+        let mut plain = Document::new();
+        plain.insert("account".to_string(), Value::String("cash".to_string()));
+        plain.insert("amount".to_string(), Value::Int32(2));
+        documents.push(plain);
+
+        let fields = HashMap::from([
+            ("total".to_string(), AggregateOp::Sum(serde_json::json!("$amount"))),
+            ("mean".to_string(), AggregateOp::Avg("amount".to_string())),
+            ("smallest".to_string(), AggregateOp::Min("amount".to_string())),
+            ("count".to_string(), AggregateOp::Count {}),
+        ]);
+        let pipeline = Pipeline::new(vec![PipelineStage::Group { _id: serde_json::json!("$account"), fields }]);
+        let rows = pipeline.execute(documents.clone()).unwrap();
+
+        let decimal = |s: &str| Value::Decimal128(s.parse().unwrap());
+        assert_eq!(rows[0].get("total"), Some(&decimal("2.31")));
+        assert_eq!(rows[0].get("mean"), Some(&decimal("0.5775")));
+        assert_eq!(rows[0].get("smallest"), Some(&decimal("0.01")));
+        assert_eq!(rows[0].get("count"), Some(&Value::Int64(4)));
+
+        // Without decimal inputs the results stay floats
+        let rows = Pipeline::new(vec![PipelineStage::Group {
+            _id: serde_json::json!("$account"),
+            fields: HashMap::from([("total".to_string(), AggregateOp::Sum(serde_json::json!("$amount")))]),
+        }])
+        .execute(documents.split_off(3))
+        .unwrap();
+        assert_eq!(rows[0].get("total"), Some(&Value::Float64(2.0)));
+    }
+
+    #[test]
+    fn test_vector_search_stage_parses_and_needs_storage() {
+        let stage: PipelineStage = serde_json::from_value(serde_json::json!({
+            "$": "vectorSearch",
+            "path": "embedding",
+            "queryVector": [0.5, 1],
+            "k": 3,
+            "filter": {"category": "books"}
+        }))
+        .unwrap();
+        let PipelineStage::VectorSearch(search) = &stage else {
+            panic!("expected a vector search stage, got {:?}", stage);
+        };
+        assert_eq!(search.query_vector, vec![0.5, 1.0]);
+        assert_eq!(search.candidates(), 30);
+        assert_eq!(search.score_field, "score");
+        assert_eq!(stage.name(), "vectorSearch");
+
+        // Running over an in-memory document stream has no index to search
+        let result = Pipeline::new(vec![stage]).execute(vec![Document::new()]);
+        assert!(matches!(result, Err(AggregationError::InvalidStage(_))));
+    }
+}
@@ -2,15 +2,24 @@
 //!
 //! This module provides the core data structures for document storage:
 //! - Document: A JSON-like document with nested fields
-//! - Value: An enum supporting all JSON types plus ObjectId, DateTime, Binary
+//! - Value: An enum supporting all JSON types plus ObjectId, DateTime, Binary,
+//!   Decimal128, Uuid and Timestamp
 //! - Field path navigation for nested document access
 
+pub mod decimal;
+pub mod extended_json;
+pub mod patch;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::fmt;
 use uuid::Uuid;
 
+pub use decimal::{Decimal128, DecimalError};
+pub use extended_json::ExtendedJsonError;
+pub use patch::{JsonPatch, PatchError, PatchOperation};
+
 /// Maximum document size in bytes (16 MB)
 pub const MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
 
@@ -114,7 +123,43 @@ impl fmt::Display for ObjectId {
     }
 }
 
-/// Value type supporting all JSON types plus ObjectId, DateTime, Binary
+/// BSON-style timestamp: seconds since the epoch and an ordinal within the second
+///
+/// Timestamps order by time, then by increment, so they can stamp events
+/// in the order they happened even within one second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    /// Seconds since the Unix epoch
+    pub time: u32,
+    /// Ordinal of the event within its second
+    pub increment: u32,
+}
+
+impl Timestamp {
+    /// Create a timestamp
+    pub fn new(time: u32, increment: u32) -> Self {
+        Self { time, increment }
+    }
+
+    /// Pack into the 64-bit BSON layout, time in the high word
+    pub fn to_u64(&self) -> u64 {
+        (self.time as u64) << 32 | self.increment as u64
+    }
+
+    /// Unpack from the 64-bit BSON layout
+    pub fn from_u64(value: u64) -> Self {
+        Self { time: (value >> 32) as u32, increment: value as u32 }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timestamp({}, {})", self.time, self.increment)
+    }
+}
+
+/// Value type supporting all JSON types plus ObjectId, DateTime, Binary,
+/// Decimal128, Uuid and Timestamp
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", content = "value")]
 pub enum Value {
@@ -140,6 +185,12 @@ pub enum Value {
     ObjectId(ObjectId),
     /// DateTime with UTC timezone
     DateTime(DateTime<Utc>),
+    /// Exact decimal with 34 significant digits
+    Decimal128(Decimal128),
+    /// UUID, kept apart from binary data of the same bytes
+    Uuid(Uuid),
+    /// BSON-style timestamp
+    Timestamp(Timestamp),
 }
 
 impl Value {
@@ -153,9 +204,12 @@ impl Value {
         matches!(self, Value::Bool(_))
     }
 
-    /// Check if value is a number (int or float)
+    /// Check if value is a number (int, float or decimal)
     pub fn is_number(&self) -> bool {
-        matches!(self, Value::Int32(_) | Value::Int64(_) | Value::Float64(_))
+        matches!(
+            self,
+            Value::Int32(_) | Value::Int64(_) | Value::Float64(_) | Value::Decimal128(_)
+        )
     }
 
     /// Check if value is a string
@@ -190,12 +244,27 @@ impl Value {
         }
     }
 
-    /// Get as f64
+    /// Get as f64, rounding decimals to the nearest float
     pub fn as_f64(&self) -> Option<f64> {
         match self {
             Value::Int32(i) => Some(*i as f64),
             Value::Int64(i) => Some(*i as f64),
             Value::Float64(f) => Some(*f),
+            Value::Decimal128(d) => Some(d.to_f64()),
+            _ => None,
+        }
+    }
+
+    /// Get as an exact decimal
+    ///
+    /// Floats convert through their shortest round-trip digits, so `0.1`
+    /// becomes the decimal `0.1`.
+    pub fn as_decimal(&self) -> Option<Decimal128> {
+        match self {
+            Value::Int32(i) => Some(Decimal128::from(*i)),
+            Value::Int64(i) => Some(Decimal128::from(*i)),
+            Value::Float64(f) => Decimal128::from_f64(*f).ok(),
+            Value::Decimal128(d) => Some(*d),
             _ => None,
         }
     }
@@ -251,6 +320,9 @@ impl Value {
             }
             Value::ObjectId(_) => 12,
             Value::DateTime(_) => 8,
+            Value::Decimal128(_) => 16,
+            Value::Uuid(_) => 16,
+            Value::Timestamp(_) => 8,
         }
     }
 
@@ -334,6 +406,24 @@ impl From<DateTime<Utc>> for Value {
     }
 }
 
+impl From<Decimal128> for Value {
+    fn from(d: Decimal128) -> Self {
+        Value::Decimal128(d)
+    }
+}
+
+impl From<Uuid> for Value {
+    fn from(uuid: Uuid) -> Self {
+        Value::Uuid(uuid)
+    }
+}
+
+impl From<Timestamp> for Value {
+    fn from(ts: Timestamp) -> Self {
+        Value::Timestamp(ts)
+    }
+}
+
 /// Document metadata
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DocumentMetadata {
@@ -465,6 +555,21 @@ impl Document {
         Some(current)
     }
 
+    /// Get every value a path reaches, fanning out over arrays
+    ///
+    /// A path segment that meets an array of subdocuments continues into
+    /// each element, so `items.sku` yields the `sku` of every item. Numeric
+    /// segments still index into arrays. Arrays at the end of the path are
+    /// returned whole.
+    pub fn values_by_path(&self, path: &str) -> Vec<&Value> {
+        let parts: Vec<&str> = path.split('.').collect();
+        let mut values = Vec::new();
+        if let Some(value) = self.fields.get(parts[0]) {
+            collect_path_values(value, &parts[1..], &mut values);
+        }
+        values
+    }
+
     /// Set field by path (e.g., "user.address.city")
     pub fn set_by_path(&mut self, path: &str, value: Value) -> Result<(), DocumentError> {
         let parts: Vec<&str> = path.split('.').collect();
@@ -589,6 +694,35 @@ impl Default for Document {
     }
 }
 
+/// Follow the remaining path segments from `value`, collecting every leaf
+fn collect_path_values<'a>(value: &'a Value, parts: &[&str], values: &mut Vec<&'a Value>) {
+    let Some((part, rest)) = parts.split_first() else {
+        values.push(value);
+        return;
+    };
+
+    match value {
+        Value::Object(obj) => {
+            if let Some(value) = obj.get(*part) {
+                collect_path_values(value, rest, values);
+            }
+        }
+        Value::Array(arr) => match part.parse::<usize>() {
+            Ok(index) => {
+                if let Some(value) = arr.get(index) {
+                    collect_path_values(value, rest, values);
+                }
+            }
+            Err(_) => {
+                for element in arr.iter().filter(|element| matches!(element, Value::Object(_))) {
+                    collect_path_values(element, parts, values);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
 /// Document-related errors
 #[derive(Debug, thiserror::Error)]
 pub enum DocumentError {
@@ -659,6 +793,31 @@ mod tests {
         assert_eq!(v.as_str(), Some("test"));
     }
 
+    #[test]
+    fn test_decimal_uuid_and_timestamp_values() {
+        let price: Decimal128 = "19.90".parse().unwrap();
+        let v: Value = price.into();
+        assert!(v.is_number());
+        assert_eq!(v.as_f64(), Some(19.9));
+        assert_eq!(v.as_decimal(), Some(price));
+        assert_eq!(Value::Float64(0.1).as_decimal(), Some("0.1".parse().unwrap()));
+        assert_eq!(Value::Float64(f64::NAN).as_decimal(), None);
+
+        let ts = Timestamp::new(1_700_000_000, 4);
+        assert_eq!(Timestamp::from_u64(ts.to_u64()), ts);
+        assert!(Timestamp::new(5, 9) < Timestamp::new(6, 0));
+
+        let mut doc = Document::new();
+        doc.insert("price".to_string(), v);
+        doc.insert("ref".to_string(), Uuid::new_v4().into());
+        doc.insert("seen".to_string(), ts.into());
+        let json = doc.to_json().unwrap();
+        assert!(json.contains(r#"{"type":"Decimal128","value":"19.90"}"#), "{}", json);
+        let restored = Document::from_json(&json).unwrap();
+        assert_eq!(restored.fields, doc.fields);
+        assert_eq!(restored.get("price").map(|v| format!("{:?}", v)), doc.get("price").map(|v| format!("{:?}", v)));
+    }
+
     #[test]
     fn test_document_basic_operations() {
         let mut doc = Document::new();
@@ -709,6 +868,33 @@ mod tests {
         assert!(doc.get_by_path("user.email").is_none());
     }
 
+    #[test]
+    fn test_values_by_path_fans_out_over_arrays() {
+        let item = |sku: &str, tags: Vec<Value>| {
+            Value::Object(BTreeMap::from([
+                ("sku".to_string(), Value::from(sku)),
+                ("tags".to_string(), Value::Array(tags)),
+            ]))
+        };
+        let mut doc = Document::new();
+        doc.insert(
+            "items".to_string(),
+            Value::Array(vec![
+                item("a1", vec!["red".into()]),
+                Value::from("loose"),
+                item("b2", vec!["blue".into(), "red".into()]),
+            ]),
+        );
+
+        assert_eq!(doc.values_by_path("items.sku"), vec![&Value::from("a1"), &Value::from("b2")]);
+        assert_eq!(doc.values_by_path("items.2.sku"), vec![&Value::from("b2")]);
+        // Arrays at the end of the path stay whole
+        assert_eq!(doc.values_by_path("items.tags").len(), 2);
+        assert_eq!(doc.values_by_path("items").len(), 1);
+        assert!(doc.values_by_path("items.price").is_empty());
+        assert!(doc.values_by_path("missing").is_empty());
+    }
+
     #[test]
     fn test_document_set_by_path() {
         let mut doc = Document::new();
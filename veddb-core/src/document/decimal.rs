@@ -0,0 +1,646 @@
+//! Exact decimal numbers for [`Value::Decimal128`]
+//!
+//! A [`Decimal128`] is a signed coefficient of up to 34 decimal digits
+//! scaled by a power of ten, the finite range of IEEE 754-2008 decimal128.
+//! Arithmetic is exact whenever the result fits in 34 digits and otherwise
+//! rounds half to even, so sums of money amounts never pick up binary
+//! rounding error.
+//!
+//! Values keep their exponent, so `1.50` and `1.5` print differently but
+//! compare equal.
+//!
+//! [`Value::Decimal128`]: super::Value::Decimal128
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of significant digits a decimal can hold
+pub const MAX_DIGITS: u32 = 34;
+
+/// Smallest exponent of a decimal's coefficient
+pub const MIN_EXPONENT: i32 = -6176;
+
+/// Largest exponent of a decimal's coefficient
+pub const MAX_EXPONENT: i32 = 6111;
+
+/// Largest coefficient magnitude, `10^34 - 1`
+const MAX_COEFFICIENT: u128 = 9_999_999_999_999_999_999_999_999_999_999_999;
+
+/// Errors from building or computing with decimals
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum DecimalError {
+    #[error("invalid decimal: {0}")]
+    Invalid(String),
+
+    #[error("decimal overflow")]
+    Overflow,
+
+    #[error("decimal division by zero")]
+    DivisionByZero,
+
+    #[error("{0} has no decimal representation")]
+    NotFinite(f64),
+}
+
+/// Exact base-10 number with 34 significant digits
+#[derive(Debug, Clone, Copy)]
+pub struct Decimal128 {
+    coefficient: i128,
+    exponent: i32,
+}
+
+impl Decimal128 {
+    /// Zero with exponent zero
+    pub const ZERO: Decimal128 = Decimal128 { coefficient: 0, exponent: 0 };
+
+    /// Build `coefficient × 10^exponent`
+    ///
+    /// Coefficients longer than 34 digits are rounded half to even, and
+    /// exponents beyond the range are clamped where the value allows.
+    pub fn new(coefficient: i128, exponent: i32) -> Result<Self, DecimalError> {
+        finish(
+            coefficient < 0,
+            Wide::from_u128(coefficient.unsigned_abs()),
+            exponent as i64,
+            false,
+        )
+    }
+
+    /// Signed coefficient
+    pub fn coefficient(&self) -> i128 {
+        self.coefficient
+    }
+
+    /// Power of ten the coefficient is scaled by
+    pub fn exponent(&self) -> i32 {
+        self.exponent
+    }
+
+    /// Whether the value is zero, whatever its exponent
+    pub fn is_zero(&self) -> bool {
+        self.coefficient == 0
+    }
+
+    /// Whether the value is below zero
+    pub fn is_negative(&self) -> bool {
+        self.coefficient < 0
+    }
+
+    /// Decimal with the shortest digits that round-trip to `value`
+    ///
+    /// `0.1_f64` becomes exactly `0.1` rather than the binary fraction
+    /// it stands for.
+    pub fn from_f64(value: f64) -> Result<Self, DecimalError> {
+        if !value.is_finite() {
+            return Err(DecimalError::NotFinite(value));
+        }
+        format!("{:e}", value).parse()
+    }
+
+    /// Nearest `f64`
+    pub fn to_f64(&self) -> f64 {
+        format!("{}e{}", self.coefficient, self.exponent)
+            .parse()
+            .unwrap_or(f64::NAN)
+    }
+
+    /// The value as an `i64`, if it is a whole number in range
+    pub fn to_i64(&self) -> Option<i64> {
+        let mut coefficient = self.coefficient;
+        if self.exponent >= 0 {
+            for _ in 0..self.exponent {
+                coefficient = coefficient.checked_mul(10)?;
+            }
+        } else {
+            for _ in 0..self.exponent.unsigned_abs() {
+                if coefficient % 10 != 0 {
+                    return None;
+                }
+                coefficient /= 10;
+                if coefficient == 0 {
+                    break;
+                }
+            }
+        }
+        i64::try_from(coefficient).ok()
+    }
+
+    /// The whole part, dropping any fraction toward zero
+    pub fn trunc(&self) -> Decimal128 {
+        if self.exponent >= 0 {
+            return *self;
+        }
+        let shift = self.exponent.unsigned_abs();
+        let coefficient = if shift > MAX_DIGITS { 0 } else { self.coefficient / 10i128.pow(shift) };
+        Decimal128 { coefficient, exponent: 0 }
+    }
+
+    /// Exact sum, rounded when it needs more than 34 digits
+    pub fn checked_add(self, other: Self) -> Result<Self, DecimalError> {
+        if other.is_zero() || self.is_zero() {
+            let (value, zero) = if other.is_zero() { (self, other) } else { (other, self) };
+            return Ok(pad(value, zero.exponent));
+        }
+
+        let (big, mut small) = if self.adjusted() >= other.adjusted() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        // An operand far below the other's last rounded digit only decides
+        // the direction of rounding, which any value that small shares
+        let reach = big.adjusted() as i64 - 36;
+        if (small.adjusted() as i64) < reach {
+            small = Decimal128 { coefficient: small.coefficient.signum(), exponent: reach as i32 };
+        }
+
+        let exponent = big.exponent.min(small.exponent);
+        let a = scaled(big, exponent);
+        let b = scaled(small, exponent);
+        let (negative, magnitude) = match (big.is_negative(), small.is_negative()) {
+            (x, y) if x == y => (x, a.add(b).ok_or(DecimalError::Overflow)?),
+            (x, _) if a >= b => (x, a.sub(b)),
+            (_, y) => (y, b.sub(a)),
+        };
+        finish(negative, magnitude, exponent as i64, false)
+    }
+
+    /// Exact difference, rounded when it needs more than 34 digits
+    pub fn checked_sub(self, other: Self) -> Result<Self, DecimalError> {
+        self.checked_add(-other)
+    }
+
+    /// Exact product, rounded when it needs more than 34 digits
+    pub fn checked_mul(self, other: Self) -> Result<Self, DecimalError> {
+        let magnitude = Wide::product(self.coefficient.unsigned_abs(), other.coefficient.unsigned_abs());
+        finish(
+            self.is_negative() != other.is_negative(),
+            magnitude,
+            self.exponent as i64 + other.exponent as i64,
+            false,
+        )
+    }
+
+    /// Quotient rounded to 34 digits
+    ///
+    /// Exact quotients drop trailing zeros down to the difference of the
+    /// operands' exponents, so `1.00 / 2` is `0.50`.
+    pub fn checked_div(self, other: Self) -> Result<Self, DecimalError> {
+        if other.is_zero() {
+            return Err(DecimalError::DivisionByZero);
+        }
+        let ideal = self.exponent as i64 - other.exponent as i64;
+        let negative = self.is_negative() != other.is_negative();
+        if self.is_zero() {
+            return finish(negative, Wide::default(), ideal, false);
+        }
+
+        // Enough extra dividend digits for a quotient of at least 35 digits
+        let divisor = other.coefficient.unsigned_abs();
+        let shift = MAX_DIGITS + 1 + digits(divisor) - digits(self.coefficient.unsigned_abs());
+        let mut dividend = Wide::from_u128(self.coefficient.unsigned_abs());
+        for _ in 0..shift {
+            dividend = dividend.mul_small(10).ok_or(DecimalError::Overflow)?;
+        }
+
+        let (mut quotient, remainder) = dividend.div_rem(divisor);
+        let mut exponent = ideal - shift as i64;
+        if remainder == 0 {
+            while exponent < ideal {
+                let (reduced, digit) = quotient.div_rem_small(10);
+                if digit != 0 {
+                    break;
+                }
+                quotient = reduced;
+                exponent += 1;
+            }
+        }
+        finish(negative, quotient, exponent, remainder != 0)
+    }
+
+    /// Exponent of the most significant digit
+    fn adjusted(&self) -> i32 {
+        self.exponent + digits(self.coefficient.unsigned_abs()) as i32 - 1
+    }
+}
+
+impl Default for Decimal128 {
+    fn default() -> Self {
+        Decimal128::ZERO
+    }
+}
+
+impl std::ops::Neg for Decimal128 {
+    type Output = Decimal128;
+
+    fn neg(self) -> Self {
+        Decimal128 { coefficient: -self.coefficient, exponent: self.exponent }
+    }
+}
+
+impl PartialEq for Decimal128 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal128 {}
+
+impl PartialOrd for Decimal128 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal128 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let sign = self.coefficient.signum().cmp(&other.coefficient.signum());
+        if sign != Ordering::Equal || self.is_zero() {
+            return sign;
+        }
+        // Same sign: compare magnitudes by leading digit position, then by
+        // the digits themselves padded to a common length
+        let a = self.coefficient.unsigned_abs();
+        let b = other.coefficient.unsigned_abs();
+        let magnitude = self.adjusted().cmp(&other.adjusted()).then_with(|| {
+            let pad = |c: u128| c * 10u128.pow(MAX_DIGITS - digits(c));
+            pad(a).cmp(&pad(b))
+        });
+        if self.is_negative() {
+            magnitude.reverse()
+        } else {
+            magnitude
+        }
+    }
+}
+
+impl From<i64> for Decimal128 {
+    fn from(value: i64) -> Self {
+        Decimal128 { coefficient: value as i128, exponent: 0 }
+    }
+}
+
+impl From<i32> for Decimal128 {
+    fn from(value: i32) -> Self {
+        Decimal128 { coefficient: value as i128, exponent: 0 }
+    }
+}
+
+impl FromStr for Decimal128 {
+    type Err = DecimalError;
+
+    /// Parse `[+-]digits[.digits][e[+-]digits]`, rounding past 34 digits
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DecimalError::Invalid(s.to_string());
+        let (negative, body) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (mantissa, exponent) = match body.find(['e', 'E']) {
+            Some(at) => (&body[..at], body[at + 1..].parse::<i64>().map_err(|_| invalid())?),
+            None => (body, 0),
+        };
+        let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if whole.is_empty() && fraction.is_empty()
+            || !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        // Keep 36 significant digits; anything beyond only matters for rounding
+        let mut magnitude = Wide::default();
+        let mut kept = 0u32;
+        let mut dropped = 0i64;
+        let mut sticky = false;
+        for digit in whole.bytes().chain(fraction.bytes()).map(|b| (b - b'0') as u64) {
+            if kept < MAX_DIGITS + 2 {
+                magnitude = magnitude
+                    .mul_small(10)
+                    .and_then(|m| m.add(Wide::from_u128(digit as u128)))
+                    .ok_or_else(invalid)?;
+                if !magnitude.is_zero() {
+                    kept += 1;
+                }
+            } else {
+                dropped += 1;
+                sticky |= digit != 0;
+            }
+        }
+        let exponent = exponent
+            .clamp(-(1 << 40), 1 << 40)
+            .saturating_sub(fraction.len() as i64)
+            .saturating_add(dropped);
+        finish(negative, magnitude, exponent, sticky)
+    }
+}
+
+impl fmt::Display for Decimal128 {
+    /// Plain notation for moderate exponents, scientific otherwise
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_negative() {
+            f.write_str("-")?;
+        }
+        let digits = self.coefficient.unsigned_abs().to_string();
+        let adjusted = self.adjusted();
+        if self.exponent <= 0 && adjusted >= -6 {
+            let point = digits.len() as i32 + self.exponent;
+            if self.exponent == 0 {
+                f.write_str(&digits)
+            } else if point > 0 {
+                let (whole, fraction) = digits.split_at(point as usize);
+                write!(f, "{}.{}", whole, fraction)
+            } else {
+                write!(f, "0.{}{}", "0".repeat(point.unsigned_abs() as usize), digits)
+            }
+        } else {
+            let (first, rest) = digits.split_at(1);
+            f.write_str(first)?;
+            if !rest.is_empty() {
+                write!(f, ".{}", rest)?;
+            }
+            write!(f, "E{}{}", if adjusted < 0 { "-" } else { "+" }, adjusted.unsigned_abs())
+        }
+    }
+}
+
+impl Serialize for Decimal128 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal128 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Number of decimal digits in `value`, counting zero as one digit
+fn digits(value: u128) -> u32 {
+    value.checked_ilog10().map_or(1, |log| log + 1)
+}
+
+/// `coefficient` rescaled to `exponent`, which must not exceed its own
+fn scaled(value: Decimal128, exponent: i32) -> Wide {
+    let mut magnitude = Wide::from_u128(value.coefficient.unsigned_abs());
+    for _ in exponent..value.exponent {
+        // Callers keep the shift small enough for 256 bits
+        magnitude = magnitude.mul_small(10).unwrap_or(magnitude);
+    }
+    magnitude
+}
+
+/// `value` with trailing zeros appended down towards `exponent`, as far
+/// as 34 digits allow
+fn pad(value: Decimal128, exponent: i32) -> Decimal128 {
+    let mut padded = value;
+    while padded.exponent > exponent
+        && padded.coefficient.unsigned_abs() * 10 <= MAX_COEFFICIENT
+    {
+        padded.coefficient *= 10;
+        padded.exponent -= 1;
+    }
+    padded
+}
+
+/// Round an exact magnitude to 34 digits and the exponent range
+///
+/// `sticky` marks a magnitude that is slightly larger than `magnitude`
+/// itself, as left behind by an inexact division.
+fn finish(negative: bool, mut magnitude: Wide, mut exponent: i64, mut sticky: bool) -> Result<Decimal128, DecimalError> {
+    let limit = Wide::from_u128(MAX_COEFFICIENT);
+    let mut last = None;
+    while magnitude > limit || exponent < MIN_EXPONENT as i64 {
+        if magnitude.is_zero() {
+            // Everything left is below the smallest exponent
+            last = Some(0);
+            exponent = MIN_EXPONENT as i64;
+            break;
+        }
+        let (reduced, digit) = magnitude.div_rem_small(10);
+        if let Some(previous) = last {
+            sticky |= previous != 0;
+        }
+        last = Some(digit);
+        magnitude = reduced;
+        exponent += 1;
+    }
+    if let Some(digit) = last {
+        let odd = magnitude.div_rem_small(2).1 == 1;
+        if digit > 5 || digit == 5 && (sticky || odd) {
+            magnitude = magnitude.add(Wide::from_u128(1)).ok_or(DecimalError::Overflow)?;
+            if magnitude > limit {
+                magnitude = magnitude.div_rem_small(10).0;
+                exponent += 1;
+            }
+        }
+    }
+
+    let mut coefficient = magnitude.to_u128().ok_or(DecimalError::Overflow)?;
+    while exponent > MAX_EXPONENT as i64 {
+        if coefficient == 0 {
+            exponent = MAX_EXPONENT as i64;
+        } else if coefficient * 10 <= MAX_COEFFICIENT {
+            coefficient *= 10;
+            exponent -= 1;
+        } else {
+            return Err(DecimalError::Overflow);
+        }
+    }
+    let coefficient = coefficient as i128;
+    Ok(Decimal128 {
+        coefficient: if negative { -coefficient } else { coefficient },
+        exponent: exponent as i32,
+    })
+}
+
+/// Unsigned 256-bit integer, wide enough for the exact product of two
+/// coefficients or a coefficient shifted by 40 digits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Wide([u64; 4]);
+
+impl Wide {
+    fn from_u128(value: u128) -> Self {
+        Wide([value as u64, (value >> 64) as u64, 0, 0])
+    }
+
+    fn to_u128(self) -> Option<u128> {
+        (self.0[2] == 0 && self.0[3] == 0).then(|| self.0[0] as u128 | (self.0[1] as u128) << 64)
+    }
+
+    fn is_zero(self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    fn product(a: u128, b: u128) -> Self {
+        let a = [a as u64, (a >> 64) as u64];
+        let b = [b as u64, (b >> 64) as u64];
+        let mut out = [0u64; 4];
+        for (i, x) in a.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, y) in b.iter().enumerate() {
+                let sum = *x as u128 * *y as u128 + out[i + j] as u128 + carry;
+                out[i + j] = sum as u64;
+                carry = sum >> 64;
+            }
+            out[i + 2] = carry as u64;
+        }
+        Wide(out)
+    }
+
+    fn mul_small(self, factor: u64) -> Option<Self> {
+        let mut out = [0u64; 4];
+        let mut carry = 0u128;
+        for (limb, slot) in self.0.iter().zip(out.iter_mut()) {
+            let product = *limb as u128 * factor as u128 + carry;
+            *slot = product as u64;
+            carry = product >> 64;
+        }
+        (carry == 0).then_some(Wide(out))
+    }
+
+    fn add(self, other: Self) -> Option<Self> {
+        let mut out = [0u64; 4];
+        let mut carry = false;
+        for ((slot, a), b) in out.iter_mut().zip(self.0).zip(other.0) {
+            let (sum, first) = a.overflowing_add(b);
+            let (sum, second) = sum.overflowing_add(carry as u64);
+            *slot = sum;
+            carry = first || second;
+        }
+        (!carry).then_some(Wide(out))
+    }
+
+    /// `self - other`; `other` must not exceed `self`
+    fn sub(self, other: Self) -> Self {
+        let mut out = [0u64; 4];
+        let mut borrow = false;
+        for ((slot, a), b) in out.iter_mut().zip(self.0).zip(other.0) {
+            let (difference, first) = a.overflowing_sub(b);
+            let (difference, second) = difference.overflowing_sub(borrow as u64);
+            *slot = difference;
+            borrow = first || second;
+        }
+        Wide(out)
+    }
+
+    fn div_rem_small(self, divisor: u64) -> (Self, u64) {
+        let mut out = [0u64; 4];
+        let mut remainder = 0u128;
+        for i in (0..4).rev() {
+            let current = remainder << 64 | self.0[i] as u128;
+            out[i] = (current / divisor as u128) as u64;
+            remainder = current % divisor as u128;
+        }
+        (Wide(out), remainder as u64)
+    }
+
+    /// Long division by a coefficient-sized divisor (below 2^127)
+    fn div_rem(self, divisor: u128) -> (Self, u128) {
+        let mut quotient = [0u64; 4];
+        let mut remainder = 0u128;
+        for bit in (0..256).rev() {
+            remainder = remainder << 1 | (self.0[bit / 64] >> (bit % 64) & 1) as u128;
+            if remainder >= divisor {
+                remainder -= divisor;
+                quotient[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        (Wide(quotient), remainder)
+    }
+}
+
+impl PartialOrd for Wide {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Wide {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(s: &str) -> Decimal128 {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        for (input, shown) in [
+            ("0", "0"),
+            ("1.50", "1.50"),
+            ("-0.001", "-0.001"),
+            (".5", "0.5"),
+            ("1e3", "1E+3"),
+            ("12345e-2", "123.45"),
+            ("1E-7", "1E-7"),
+            ("0.0000001", "1E-7"),
+            ("1.2E+6144", "1.200000000000000000000000000000000E+6144"),
+            ("-0E-9", "0E-9"),
+        ] {
+            assert_eq!(dec(input).to_string(), shown, "{}", input);
+        }
+        // 35 significant digits round half to even
+        assert_eq!(dec("1.0000000000000000000000000000000005").to_string(), "1.000000000000000000000000000000000");
+        assert_eq!(dec("1.0000000000000000000000000000000015").to_string(), "1.000000000000000000000000000000002");
+
+        for bad in ["", "-", "1.2.3", "abc", "1e", "NaN", "1e99999"] {
+            assert!(bad.parse::<Decimal128>().is_err(), "{:?}", bad);
+        }
+        assert_eq!(Decimal128::from_f64(0.1).unwrap(), dec("0.1"));
+        assert!(matches!(Decimal128::from_f64(f64::NAN), Err(DecimalError::NotFinite(_))));
+        assert_eq!(dec("2.5").to_f64(), 2.5);
+        assert_eq!(dec("-12.000").to_i64(), Some(-12));
+        assert_eq!(dec("1.5").to_i64(), None);
+        assert_eq!(dec("-7.9").trunc().to_i64(), Some(-7));
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        // The classic binary rounding failure is exact in decimal
+        let sum = dec("0.1").checked_add(dec("0.2")).unwrap();
+        assert_eq!(sum.to_string(), "0.3");
+        assert_eq!(sum, dec("0.30"));
+
+        assert_eq!(dec("10.25").checked_sub(dec("0.75")).unwrap().to_string(), "9.50");
+        assert_eq!(dec("1").checked_sub(dec("1E-34")).unwrap().to_string(), format!("0.{}", "9".repeat(34)));
+        assert_eq!(dec("1").checked_sub(dec("1E-40")).unwrap().to_string(), format!("1.{}", "0".repeat(33)));
+        assert_eq!(dec("1E+40").checked_add(dec("1")).unwrap().to_string(), format!("1.{}E+40", "0".repeat(33)));
+        assert_eq!(dec("1.5").checked_add(Decimal128::ZERO).unwrap().to_string(), "1.5");
+        assert_eq!(dec("-1.10").checked_mul(dec("3")).unwrap().to_string(), "-3.30");
+        assert_eq!(dec("1.00").checked_div(dec("2")).unwrap().to_string(), "0.50");
+        assert_eq!(dec("1").checked_div(dec("3")).unwrap().to_string(), "0.3333333333333333333333333333333333");
+        assert_eq!(dec("2").checked_div(dec("3")).unwrap().to_string(), "0.6666666666666666666666666666666667");
+
+        let max = dec("9.999999999999999999999999999999999E+6144");
+        assert_eq!(max.checked_mul(dec("10")), Err(DecimalError::Overflow));
+        assert_eq!(dec("1").checked_div(Decimal128::ZERO), Err(DecimalError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_ordering_ignores_representation() {
+        let mut values: Vec<Decimal128> = ["1.10", "-2", "0", "1.1", "-0.5", "1E+2", "99.99", "-1E+3", "0.000"]
+            .iter()
+            .map(|s| dec(s))
+            .collect();
+        values.sort();
+        let shown: Vec<String> = values.iter().map(|d| d.to_string()).collect();
+        assert_eq!(shown, ["-1E+3", "-2", "-0.5", "0", "0.000", "1.10", "1.1", "99.99", "1E+2"]);
+        assert_eq!(dec("1.10"), dec("1.1"));
+        assert!(dec("-1.5") < dec("-1.49"));
+
+        let json = serde_json::to_string(&dec("12.50")).unwrap();
+        assert_eq!(json, "\"12.50\"");
+        assert_eq!(serde_json::from_str::<Decimal128>(&json).unwrap().to_string(), "12.50");
+    }
+}
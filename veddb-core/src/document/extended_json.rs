@@ -0,0 +1,356 @@
+//! Extended JSON mapping for [`Value`]
+//!
+//! Plain JSON has no dates, object ids, binary data or 64-bit integers, so
+//! values of those types travel as single-key wrapper objects in the style
+//! of MongoDB's canonical Extended JSON:
+//!
+//! | Value        | Extended JSON                                          |
+//! |--------------|--------------------------------------------------------|
+//! | `Int32`      | `5`                                                    |
+//! | `Int64`      | `{"$numberLong": "5"}`                                 |
+//! | `Float64`    | `5.0`, or `{"$numberDouble": "NaN"}` when not finite   |
+//! | `Decimal128` | `{"$numberDecimal": "19.90"}`                          |
+//! | `ObjectId`   | `{"$oid": "65a1f0c2e4b0a1b2c3d4e5f6"}`                 |
+//! | `DateTime`   | `{"$date": "2024-01-02T03:04:05.123456789Z"}`          |
+//! | `Binary`     | `{"$binary": {"base64": "AAE=", "subType": "00"}}`     |
+//! | `Uuid`       | `{"$uuid": "67e55044-10b1-426f-9247-bb680e5fe0c8"}`    |
+//! | `Timestamp`  | `{"$timestamp": {"t": 1700000000, "i": 1}}`            |
+//!
+//! Dates are written as RFC 3339 strings with as many fractional digits as
+//! they need, so nanoseconds survive; `{"$date": {"$numberLong": "<ms>"}}`
+//! and plain millisecond numbers are read as well. Binary data of subtype
+//! `04` with 16 bytes reads as a UUID.
+
+use super::{Decimal128, Document, ObjectId, Timestamp, Value};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::BTreeMap;
+
+/// Binary subtype of generic data
+const SUBTYPE_GENERIC: u8 = 0x00;
+/// Binary subtype of UUIDs
+const SUBTYPE_UUID: u8 = 0x04;
+
+/// Errors reading Extended JSON
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ExtendedJsonError {
+    #[error("invalid {wrapper}: {reason}")]
+    Invalid { wrapper: String, reason: String },
+
+    #[error("unsupported binary subtype {0:#04x}")]
+    UnsupportedSubtype(u8),
+}
+
+/// Extended JSON for a value
+pub fn to_extended_json(value: &Value) -> JsonValue {
+    convert(value, false)
+}
+
+/// Relaxed Extended JSON for a value, which writes `Int64` as a plain
+/// number
+///
+/// For specifications such as sorts and projections whose numbers are
+/// flags rather than data; `Int64` values do not read back as `Int64`.
+pub fn to_relaxed_extended_json(value: &Value) -> JsonValue {
+    convert(value, true)
+}
+
+fn convert(value: &Value, relaxed: bool) -> JsonValue {
+    match value {
+        Value::Null => JsonValue::Null,
+        Value::Bool(b) => JsonValue::Bool(*b),
+        Value::Int32(i) => JsonValue::from(*i),
+        Value::Int64(i) if relaxed => JsonValue::from(*i),
+        Value::Int64(i) => wrap("$numberLong", JsonValue::String(i.to_string())),
+        Value::Float64(f) => match serde_json::Number::from_f64(*f) {
+            Some(number) => JsonValue::Number(number),
+            None => wrap("$numberDouble", JsonValue::String(non_finite_name(*f).to_string())),
+        },
+        Value::Decimal128(d) => wrap("$numberDecimal", JsonValue::String(d.to_string())),
+        Value::String(s) => JsonValue::String(s.clone()),
+        Value::Binary(bytes) => wrap(
+            "$binary",
+            serde_json::json!({ "base64": BASE64.encode(bytes), "subType": format!("{:02x}", SUBTYPE_GENERIC) }),
+        ),
+        Value::Uuid(uuid) => wrap("$uuid", JsonValue::String(uuid.to_string())),
+        Value::ObjectId(oid) => wrap("$oid", JsonValue::String(oid.to_string())),
+        Value::DateTime(dt) => wrap(
+            "$date",
+            JsonValue::String(dt.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        ),
+        Value::Timestamp(ts) => wrap("$timestamp", serde_json::json!({ "t": ts.time, "i": ts.increment })),
+        Value::Array(items) => JsonValue::Array(items.iter().map(|item| convert(item, relaxed)).collect()),
+        Value::Object(fields) => JsonValue::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), convert(value, relaxed)))
+                .collect(),
+        ),
+    }
+}
+
+/// Extended JSON object of a document's fields, with its id as `_id`
+/// unless a field of that name is stored
+pub fn document_to_extended_json(document: &Document) -> JsonValue {
+    let mut object = Map::new();
+    object.insert("_id".to_string(), JsonValue::String(document.id.to_string()));
+    for (name, value) in &document.fields {
+        object.insert(name.clone(), to_extended_json(value));
+    }
+    JsonValue::Object(object)
+}
+
+/// Value for Extended JSON
+///
+/// Whole numbers in the `i32` range become `Int32` and other whole
+/// numbers `Int64`; objects that are a single known wrapper become the
+/// wrapped type.
+pub fn from_extended_json(json: &JsonValue) -> Result<Value, ExtendedJsonError> {
+    Ok(match json {
+        JsonValue::Null => Value::Null,
+        JsonValue::Bool(b) => Value::Bool(*b),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => i32::try_from(i).map_or(Value::Int64(i), Value::Int32),
+            // Integers beyond i64 only fit a float
+            None => Value::Float64(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        JsonValue::String(s) => Value::String(s.clone()),
+        JsonValue::Array(items) => {
+            Value::Array(items.iter().map(from_extended_json).collect::<Result<_, _>>()?)
+        }
+        JsonValue::Object(object) => match typed_value(object)? {
+            Some(value) => value,
+            None => Value::Object(
+                object
+                    .iter()
+                    .map(|(name, value)| Ok((name.clone(), from_extended_json(value)?)))
+                    .collect::<Result<BTreeMap<_, _>, ExtendedJsonError>>()?,
+            ),
+        },
+    })
+}
+
+/// Value of an object that is a single type wrapper such as `{"$oid": ...}`
+///
+/// Returns `None` for any other object, including wrappers with extra keys.
+pub fn typed_value(object: &Map<String, JsonValue>) -> Result<Option<Value>, ExtendedJsonError> {
+    let mut entries = object.iter();
+    let (Some((key, inner)), None) = (entries.next(), entries.next()) else {
+        return Ok(None);
+    };
+    let invalid = |reason: &str| ExtendedJsonError::Invalid { wrapper: key.clone(), reason: reason.to_string() };
+
+    let value = match key.as_str() {
+        "$numberInt" => Value::Int32(
+            number_text(inner)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid("expected a 32-bit integer string"))?,
+        ),
+        "$numberLong" => Value::Int64(
+            number_text(inner)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid("expected a 64-bit integer string"))?,
+        ),
+        "$numberDouble" => Value::Float64(
+            inner
+                .as_str()
+                .and_then(parse_double)
+                .ok_or_else(|| invalid("expected a number string, Infinity, -Infinity or NaN"))?,
+        ),
+        "$numberDecimal" => Value::Decimal128(
+            inner
+                .as_str()
+                .and_then(|s| s.parse::<Decimal128>().ok())
+                .ok_or_else(|| invalid("expected a decimal string"))?,
+        ),
+        "$oid" => {
+            let bytes = inner
+                .as_str()
+                .and_then(|s| hex::decode(s).ok())
+                .and_then(|bytes| <[u8; 12]>::try_from(bytes).ok())
+                .ok_or_else(|| invalid("expected 24 hexadecimal digits"))?;
+            Value::ObjectId(ObjectId::from_bytes(bytes))
+        }
+        "$date" => Value::DateTime(parse_date(inner).ok_or_else(|| {
+            invalid("expected an RFC 3339 string or milliseconds since the epoch")
+        })?),
+        "$binary" => {
+            let bytes = inner
+                .get("base64")
+                .and_then(JsonValue::as_str)
+                .and_then(|s| BASE64.decode(s).ok())
+                .ok_or_else(|| invalid("expected base64 data"))?;
+            let subtype = inner
+                .get("subType")
+                .and_then(JsonValue::as_str)
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                .ok_or_else(|| invalid("expected a hexadecimal subType"))?;
+            match subtype {
+                SUBTYPE_GENERIC => Value::Binary(bytes),
+                SUBTYPE_UUID => Value::Uuid(
+                    uuid::Uuid::from_slice(&bytes).map_err(|_| invalid("a UUID has 16 bytes"))?,
+                ),
+                other => return Err(ExtendedJsonError::UnsupportedSubtype(other)),
+            }
+        }
+        "$uuid" => Value::Uuid(
+            inner
+                .as_str()
+                .and_then(|s| uuid::Uuid::parse_str(s).ok())
+                .ok_or_else(|| invalid("expected a UUID string"))?,
+        ),
+        "$timestamp" => {
+            let part = |name: &str| {
+                inner
+                    .get(name)
+                    .and_then(JsonValue::as_u64)
+                    .and_then(|n| u32::try_from(n).ok())
+                    .ok_or_else(|| invalid("expected unsigned 32-bit t and i"))
+            };
+            Value::Timestamp(Timestamp::new(part("t")?, part("i")?))
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(value))
+}
+
+fn wrap(key: &str, inner: JsonValue) -> JsonValue {
+    let mut object = Map::new();
+    object.insert(key.to_string(), inner);
+    JsonValue::Object(object)
+}
+
+fn non_finite_name(f: f64) -> &'static str {
+    if f.is_nan() {
+        "NaN"
+    } else if f > 0.0 {
+        "Infinity"
+    } else {
+        "-Infinity"
+    }
+}
+
+/// Digits of a wrapped integer, given as a string or a JSON integer
+fn number_text(inner: &JsonValue) -> Option<String> {
+    match inner {
+        JsonValue::String(s) => Some(s.clone()),
+        JsonValue::Number(n) if n.is_i64() => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn parse_double(s: &str) -> Option<f64> {
+    match s {
+        "Infinity" => Some(f64::INFINITY),
+        "-Infinity" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        other => other.parse().ok(),
+    }
+}
+
+fn parse_date(inner: &JsonValue) -> Option<DateTime<Utc>> {
+    let millis = match inner {
+        JsonValue::String(s) => return DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)),
+        JsonValue::Number(n) => n.as_i64()?,
+        JsonValue::Object(object) => match typed_value(object).ok()?? {
+            Value::Int64(millis) => millis,
+            Value::Int32(millis) => millis as i64,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    DateTime::from_timestamp_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_round_trip_keeps_every_type() {
+        let mut nested = BTreeMap::new();
+        nested.insert("at".to_string(), Value::DateTime(DateTime::from_timestamp(1_700_000_000, 123_456_789).unwrap()));
+        nested.insert("raw".to_string(), Value::Binary(vec![0, 1, 255]));
+        let values = vec![
+            Value::Null,
+            Value::Bool(true),
+            Value::Int32(-7),
+            Value::Int64(7),
+            Value::Float64(5.0),
+            Value::Float64(f64::NEG_INFINITY),
+            Value::Decimal128("19.90".parse().unwrap()),
+            Value::String("$oid".to_string()),
+            Value::ObjectId(ObjectId::new()),
+            Value::Uuid(uuid::Uuid::new_v4()),
+            Value::Timestamp(Timestamp::new(9, 2)),
+            Value::Array(vec![Value::Int64(i64::MAX), Value::Null]),
+            Value::Object(nested),
+        ];
+
+        for value in values {
+            let json = to_extended_json(&value);
+            // Through text too, as it travels on the wire
+            let text = serde_json::to_string(&json).unwrap();
+            let restored = from_extended_json(&serde_json::from_str(&text).unwrap()).unwrap();
+            assert_eq!(format!("{:?}", restored), format!("{:?}", value), "{}", text);
+        }
+
+        let nan = from_extended_json(&to_extended_json(&Value::Float64(f64::NAN))).unwrap();
+        assert!(matches!(nan, Value::Float64(f) if f.is_nan()));
+        assert_eq!(
+            to_extended_json(&Value::DateTime(DateTime::from_timestamp(0, 5_000_000).unwrap())),
+            json!({"$date": "1970-01-01T00:00:00.005Z"})
+        );
+        assert_eq!(to_extended_json(&Value::Binary(vec![0, 1])), json!({"$binary": {"base64": "AAE=", "subType": "00"}}));
+        assert_eq!(
+            to_relaxed_extended_json(&Value::Array(vec![Value::Int64(-1), Value::ObjectId(ObjectId::from_bytes([0; 12]))])),
+            json!([-1, {"$oid": "000000000000000000000000"}])
+        );
+    }
+
+    #[test]
+    fn test_alternate_forms() {
+        let millis = Value::DateTime(DateTime::from_timestamp_millis(1_500).unwrap());
+        assert_eq!(from_extended_json(&json!({"$date": {"$numberLong": "1500"}})).unwrap(), millis);
+        assert_eq!(from_extended_json(&json!({"$date": 1500})).unwrap(), millis);
+        assert_eq!(
+            from_extended_json(&json!({"$date": "1970-01-01T01:00:01.5+01:00"})).unwrap(),
+            millis
+        );
+        assert_eq!(from_extended_json(&json!({"$numberInt": "12"})).unwrap(), Value::Int32(12));
+        assert_eq!(from_extended_json(&json!({"$numberLong": 12})).unwrap(), Value::Int64(12));
+        assert_eq!(from_extended_json(&json!(3_000_000_000u64)).unwrap(), Value::Int64(3_000_000_000));
+
+        let uuid = uuid::Uuid::new_v4();
+        let binary = json!({"$binary": {"base64": BASE64.encode(uuid.as_bytes()), "subType": "04"}});
+        assert_eq!(from_extended_json(&binary).unwrap(), Value::Uuid(uuid));
+
+        // Extra keys make an ordinary object
+        let object = from_extended_json(&json!({"$oid": "x", "name": "a"})).unwrap();
+        assert!(object.is_object());
+    }
+
+    #[test]
+    fn test_invalid_wrappers_are_rejected() {
+        for bad in [
+            json!({"$oid": "123"}),
+            json!({"$date": "yesterday"}),
+            json!({"$date": true}),
+            json!({"$binary": {"base64": "***", "subType": "00"}}),
+            json!({"$binary": {"base64": "AAE=", "subType": "04"}}),
+            json!({"$numberLong": "9223372036854775808"}),
+            json!({"$numberInt": "3000000000"}),
+            json!({"$numberDouble": "lots"}),
+            json!([{"$uuid": 5}]),
+        ] {
+            assert!(matches!(from_extended_json(&bad), Err(ExtendedJsonError::Invalid { .. })), "{}", bad);
+        }
+        assert_eq!(
+            from_extended_json(&json!({"$binary": {"base64": "AAE=", "subType": "80"}})),
+            Err(ExtendedJsonError::UnsupportedSubtype(0x80))
+        );
+    }
+}
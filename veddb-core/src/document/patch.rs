@@ -0,0 +1,429 @@
+//! JSON Patch (RFC 6902) and JSON Merge Patch (RFC 7396) for documents
+//!
+//! Patch paths are JSON Pointers (RFC 6901) into a document's fields:
+//! `/address/city` is the field path `address.city` and `/tags/0` the
+//! first element of `tags`. A patch applies as a whole or not at all; a
+//! failing operation, including a failing `test`, leaves the document
+//! unchanged.
+
+use super::{Document, Value};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+
+/// Errors parsing or applying a patch
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum PatchError {
+    #[error("invalid patch: {0}")]
+    Invalid(String),
+
+    #[error("invalid JSON pointer: {0}")]
+    InvalidPointer(String),
+
+    #[error("path not found: {0}")]
+    PathNotFound(String),
+
+    #[error("test failed at {path:?}")]
+    TestFailed { path: String },
+
+    #[error("patch result is not an object")]
+    NotAnObject,
+}
+
+/// One JSON Patch operation
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// An RFC 6902 JSON Patch: operations applied in order
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct JsonPatch {
+    pub operations: Vec<PatchOperation>,
+}
+
+impl JsonPatch {
+    /// Parse a patch from its JSON array form
+    ///
+    /// Operation values are Extended JSON, so `{"$date": ...}` adds a date.
+    pub fn from_json(json: &JsonValue) -> Result<Self, PatchError> {
+        let items = json
+            .as_array()
+            .ok_or_else(|| PatchError::Invalid("a patch is an array of operations".to_string()))?;
+        let operations = items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| parse_operation(item).map_err(|reason| PatchError::Invalid(format!("operation {}: {}", index, reason))))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { operations })
+    }
+
+    /// Apply the patch to a set of fields, all operations or none
+    pub fn apply(&self, fields: &mut BTreeMap<String, Value>) -> Result<(), PatchError> {
+        let mut root = Value::Object(fields.clone());
+        for operation in &self.operations {
+            apply_operation(&mut root, operation)?;
+        }
+        match root {
+            Value::Object(patched) => {
+                *fields = patched;
+                Ok(())
+            }
+            _ => Err(PatchError::NotAnObject),
+        }
+    }
+}
+
+impl Document {
+    /// Apply an RFC 6902 JSON Patch; on error the document is unchanged
+    pub fn apply_json_patch(&mut self, patch: &JsonPatch) -> Result<(), PatchError> {
+        patch.apply(&mut self.fields)?;
+        self.update_metadata();
+        Ok(())
+    }
+
+    /// Apply an RFC 7396 Merge Patch, which must be an object
+    ///
+    /// `null` members remove fields, object members merge recursively and
+    /// anything else replaces the field.
+    pub fn apply_merge_patch(&mut self, patch: &Value) -> Result<(), PatchError> {
+        let Value::Object(members) = patch else {
+            return Err(PatchError::NotAnObject);
+        };
+        for (name, member) in members {
+            merge_member(&mut self.fields, name, member);
+        }
+        self.update_metadata();
+        Ok(())
+    }
+}
+
+/// Apply a Merge Patch to a value
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(members) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(BTreeMap::new());
+    }
+    if let Value::Object(fields) = target {
+        for (name, member) in members {
+            merge_member(fields, name, member);
+        }
+    }
+}
+
+fn merge_member(fields: &mut BTreeMap<String, Value>, name: &str, member: &Value) {
+    if member.is_null() {
+        fields.remove(name);
+    } else {
+        merge_patch(fields.entry(name.to_string()).or_insert(Value::Null), member);
+    }
+}
+
+fn parse_operation(item: &JsonValue) -> Result<PatchOperation, String> {
+    let object = item.as_object().ok_or("an operation is an object")?;
+    let text = |name: &str| {
+        object
+            .get(name)
+            .and_then(JsonValue::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| format!("missing string \"{}\"", name))
+    };
+    let value = || {
+        let json = object.get("value").ok_or("missing \"value\"")?;
+        super::extended_json::from_extended_json(json).map_err(|e| e.to_string())
+    };
+
+    let op = text("op")?;
+    let path = text("path")?;
+    Ok(match op.as_str() {
+        "add" => PatchOperation::Add { path, value: value()? },
+        "remove" => PatchOperation::Remove { path },
+        "replace" => PatchOperation::Replace { path, value: value()? },
+        "move" => PatchOperation::Move { from: text("from")?, path },
+        "copy" => PatchOperation::Copy { from: text("from")?, path },
+        "test" => PatchOperation::Test { path, value: value()? },
+        other => return Err(format!("unknown op \"{}\"", other)),
+    })
+}
+
+fn apply_operation(root: &mut Value, operation: &PatchOperation) -> Result<(), PatchError> {
+    match operation {
+        PatchOperation::Add { path, value } => add(root, path, value.clone()),
+        PatchOperation::Remove { path } => remove(root, path).map(drop),
+        PatchOperation::Replace { path, value } => {
+            let target = resolve_mut(root, &parse_pointer(path)?)
+                .ok_or_else(|| PatchError::PathNotFound(path.clone()))?;
+            *target = value.clone();
+            Ok(())
+        }
+        PatchOperation::Move { from, path } => {
+            if from == path {
+                return resolve(root, &parse_pointer(from)?)
+                    .map(drop)
+                    .ok_or_else(|| PatchError::PathNotFound(from.clone()));
+            }
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(PatchError::Invalid(format!("cannot move {} into itself", from)));
+            }
+            let value = remove(root, from)?;
+            add(root, path, value)
+        }
+        PatchOperation::Copy { from, path } => {
+            let value = resolve(root, &parse_pointer(from)?)
+                .cloned()
+                .ok_or_else(|| PatchError::PathNotFound(from.clone()))?;
+            add(root, path, value)
+        }
+        PatchOperation::Test { path, value } => {
+            match resolve(root, &parse_pointer(path)?) {
+                Some(current) if json_equal(current, value) => Ok(()),
+                _ => Err(PatchError::TestFailed { path: path.clone() }),
+            }
+        }
+    }
+}
+
+fn add(root: &mut Value, path: &str, value: Value) -> Result<(), PatchError> {
+    let tokens = parse_pointer(path)?;
+    let Some((last, parent)) = tokens.split_last() else {
+        *root = value;
+        return Ok(());
+    };
+    match resolve_mut(root, parent) {
+        Some(Value::Object(fields)) => {
+            fields.insert(last.clone(), value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = if last == "-" {
+                items.len()
+            } else {
+                array_index(last, path)?
+            };
+            if index > items.len() {
+                return Err(PatchError::PathNotFound(path.to_string()));
+            }
+            items.insert(index, value);
+            Ok(())
+        }
+        _ => Err(PatchError::PathNotFound(path.to_string())),
+    }
+}
+
+fn remove(root: &mut Value, path: &str) -> Result<Value, PatchError> {
+    let tokens = parse_pointer(path)?;
+    let not_found = || PatchError::PathNotFound(path.to_string());
+    let (last, parent) = tokens
+        .split_last()
+        .ok_or_else(|| PatchError::Invalid("cannot remove the whole document".to_string()))?;
+    match resolve_mut(root, parent) {
+        Some(Value::Object(fields)) => fields.remove(last).ok_or_else(not_found),
+        Some(Value::Array(items)) => {
+            let index = array_index(last, path)?;
+            if index < items.len() {
+                Ok(items.remove(index))
+            } else {
+                Err(not_found())
+            }
+        }
+        _ => Err(not_found()),
+    }
+}
+
+fn resolve<'a>(root: &'a Value, tokens: &[String]) -> Option<&'a Value> {
+    tokens.iter().try_fold(root, |current, token| match current {
+        Value::Object(fields) => fields.get(token),
+        Value::Array(items) => items.get(array_index(token, token).ok()?),
+        _ => None,
+    })
+}
+
+fn resolve_mut<'a>(root: &'a mut Value, tokens: &[String]) -> Option<&'a mut Value> {
+    tokens.iter().try_fold(root, |current, token| match current {
+        Value::Object(fields) => fields.get_mut(token),
+        Value::Array(items) => items.get_mut(array_index(token, token).ok()?),
+        _ => None,
+    })
+}
+
+/// Reference tokens of a JSON Pointer, with `~1` and `~0` unescaped
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, PatchError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = pointer
+        .strip_prefix('/')
+        .ok_or_else(|| PatchError::InvalidPointer(pointer.to_string()))?;
+    rest.split('/')
+        .map(|token| {
+            let mut unescaped = String::with_capacity(token.len());
+            let mut chars = token.chars();
+            while let Some(c) = chars.next() {
+                if c != '~' {
+                    unescaped.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('0') => unescaped.push('~'),
+                    Some('1') => unescaped.push('/'),
+                    _ => return Err(PatchError::InvalidPointer(pointer.to_string())),
+                }
+            }
+            Ok(unescaped)
+        })
+        .collect()
+}
+
+/// Array index of a token: decimal digits without leading zeros
+fn array_index(token: &str, path: &str) -> Result<usize, PatchError> {
+    let well_formed = !token.is_empty()
+        && token.bytes().all(|b| b.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    well_formed
+        .then(|| token.parse().ok())
+        .flatten()
+        .ok_or_else(|| PatchError::InvalidPointer(path.to_string()))
+}
+
+/// JSON equality for `test`: numbers compare by value, whatever their type
+fn json_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(x, y)| json_equal(x, y))
+        }
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len() && x.iter().all(|(name, x)| y.get(name).is_some_and(|y| json_equal(x, y)))
+        }
+        _ if a.is_number() && b.is_number() => match (a.as_decimal(), b.as_decimal()) {
+            (Some(x), Some(y)) => x == y,
+            _ => a.as_f64() == b.as_f64(),
+        },
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn document(json: JsonValue) -> Document {
+        let Value::Object(fields) = super::super::extended_json::from_extended_json(&json).unwrap() else {
+            panic!("not an object");
+        };
+        Document::from_fields(fields)
+    }
+
+    #[test]
+    fn test_json_patch_operations() {
+        let mut doc = document(json!({
+            "name": "widget",
+            "price": 10,
+            "tags": ["a", "b"],
+            "dims": {"w": 2},
+            "a/b": 1,
+            "m~n": 2
+        }));
+        let patch = JsonPatch::from_json(&json!([
+            {"op": "test", "path": "/price", "value": 10.0},
+            {"op": "replace", "path": "/price", "value": 12},
+            {"op": "add", "path": "/tags/1", "value": "x"},
+            {"op": "add", "path": "/tags/-", "value": "z"},
+            {"op": "remove", "path": "/tags/0"},
+            {"op": "copy", "from": "/dims", "path": "/size"},
+            {"op": "move", "from": "/dims/w", "path": "/width"},
+            {"op": "add", "path": "/made", "value": {"$date": "2024-01-02T00:00:00Z"}},
+            {"op": "remove", "path": "/a~1b"},
+            {"op": "test", "path": "/m~0n", "value": 2}
+        ]))
+        .unwrap();
+        let version = doc.metadata.version;
+        doc.apply_json_patch(&patch).unwrap();
+
+        assert_eq!(doc.get("price"), Some(&Value::Int32(12)));
+        assert_eq!(
+            doc.get("tags"),
+            Some(&Value::Array(vec!["x".into(), "b".into(), "z".into()]))
+        );
+        assert_eq!(doc.get_by_path("size.w"), Some(&Value::Int32(2)));
+        assert_eq!(doc.get("dims"), Some(&Value::Object(BTreeMap::new())));
+        assert_eq!(doc.get("width"), Some(&Value::Int32(2)));
+        assert!(matches!(doc.get("made"), Some(Value::DateTime(_))));
+        assert!(!doc.contains_key("a/b"));
+        assert_eq!(doc.metadata.version, version + 1);
+    }
+
+    #[test]
+    fn test_failed_patch_leaves_document_unchanged() {
+        let mut doc = document(json!({"stock": 5, "name": "widget"}));
+        let before = doc.fields.clone();
+
+        let failing = [
+            (json!([{"op": "replace", "path": "/name", "value": "gadget"}, {"op": "test", "path": "/stock", "value": 4}]),
+             PatchError::TestFailed { path: "/stock".to_string() }),
+            (json!([{"op": "remove", "path": "/name"}, {"op": "remove", "path": "/missing"}]),
+             PatchError::PathNotFound("/missing".to_string())),
+            (json!([{"op": "add", "path": "/name/first", "value": 1}]),
+             PatchError::PathNotFound("/name/first".to_string())),
+            (json!([{"op": "add", "path": "", "value": [1]}]), PatchError::NotAnObject),
+            (json!([{"op": "add", "path": "name", "value": 1}]), PatchError::InvalidPointer("name".to_string())),
+        ];
+        for (patch, expected) in failing {
+            let patch = JsonPatch::from_json(&patch).unwrap();
+            assert_eq!(doc.apply_json_patch(&patch), Err(expected));
+            assert_eq!(doc.fields, before);
+        }
+
+        for invalid in [
+            json!({"op": "add"}),
+            json!([{"op": "add", "path": "/x"}]),
+            json!([{"op": "swap", "path": "/x"}]),
+            json!([{"op": "move", "path": "/x"}]),
+        ] {
+            assert!(matches!(JsonPatch::from_json(&invalid), Err(PatchError::Invalid(_))), "{}", invalid);
+        }
+        let into_itself = JsonPatch::from_json(&json!([{"op": "move", "from": "/stock", "path": "/stock/x"}])).unwrap();
+        assert!(matches!(doc.apply_json_patch(&into_itself), Err(PatchError::Invalid(_))));
+        let padded = JsonPatch::from_json(&json!([{"op": "add", "path": "/list", "value": [1]}, {"op": "add", "path": "/list/01", "value": 2}])).unwrap();
+        assert!(matches!(doc.apply_json_patch(&padded), Err(PatchError::InvalidPointer(_))));
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut doc = document(json!({
+            "title": "Goodbye!",
+            "author": {"givenName": "John", "familyName": "Doe"},
+            "tags": ["example", "sample"],
+            "content": "This will be unchanged"
+        }));
+        let patch = super::super::extended_json::from_extended_json(&json!({
+            "title": "Hello!",
+            "phoneNumber": "+01-123-456-7890",
+            "author": {"familyName": null},
+            "tags": ["example"]
+        }))
+        .unwrap();
+        doc.apply_merge_patch(&patch).unwrap();
+
+        let expected = document(json!({
+            "title": "Hello!",
+            "author": {"givenName": "John"},
+            "tags": ["example"],
+            "content": "This will be unchanged",
+            "phoneNumber": "+01-123-456-7890"
+        }));
+        assert_eq!(doc.fields, expected.fields);
+
+        let mut scalar = Value::Int32(1);
+        merge_patch(&mut scalar, &Value::Object(BTreeMap::from([("a".to_string(), Value::Null)])));
+        assert_eq!(scalar, Value::Object(BTreeMap::new()));
+        assert_eq!(doc.apply_merge_patch(&Value::Array(Vec::new())), Err(PatchError::NotAnObject));
+    }
+}
@@ -2,11 +2,20 @@
 //!
 //! Provides efficient indexing using B-tree data structure
 
-use crate::document::{DocumentId, Value};
+use super::statistics::KeyDistribution;
+use super::vector::VectorIndex;
+use crate::document::{Decimal128, Document, DocumentId, ObjectId, Timestamp, Value};
+use crate::query::{Collation, Filter, QueryExecutor};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
 use std::sync::{Arc, RwLock};
 
+/// Fewest modifications to a key distribution that count as drift
+const MIN_STATISTICS_DRIFT: u64 = 16;
+
 /// B-tree index for efficient document lookups
 pub struct BTreeIndex {
     /// Index name
@@ -17,10 +26,28 @@ pub struct BTreeIndex {
     unique: bool,
     /// Whether the index is sparse (only indexes documents with the field)
     sparse: bool,
+    /// Per-field descending flags; keys are stored in this order
+    descending: Vec<bool>,
+    /// Whether any document has been indexed under an array value
+    multikey: AtomicBool,
+    /// Whether two documents' keys compared equal without being identical,
+    /// e.g. `Int32(5)` and `Float(5.0)`, so they share one stored key
+    lossy_keys: AtomicBool,
+    /// Only documents matching this filter are indexed
+    partial_filter: Option<Filter>,
+    /// How field values become stored key values
+    key_form: KeyForm,
+    /// Nearest-neighbour graph of a vector index, keyed alongside the tree
+    vector: Option<Arc<VectorIndex>>,
     /// The actual B-tree storage
     tree: Arc<RwLock<BTreeMap<IndexKey, Vec<DocumentId>>>>,
     /// Index statistics
     stats: Arc<RwLock<IndexStats>>,
+    /// Key distribution for query planning, shared with planner snapshots
+    /// and copied only when written while one is alive
+    distribution: RwLock<Arc<KeyDistribution>>,
+    /// Distribution modifications already reported as drift
+    reported_modifications: AtomicU64,
 }
 
 impl BTreeIndex {
@@ -31,11 +58,68 @@ impl BTreeIndex {
             fields,
             unique,
             sparse,
+            descending: Vec::new(),
+            multikey: AtomicBool::new(false),
+            lossy_keys: AtomicBool::new(false),
+            partial_filter: None,
+            key_form: KeyForm::default(),
+            vector: None,
             tree: Arc::new(RwLock::new(BTreeMap::new())),
             stats: Arc::new(RwLock::new(IndexStats::default())),
+            distribution: RwLock::new(Arc::new(KeyDistribution::default())),
+            reported_modifications: AtomicU64::new(0),
         }
     }
 
+    /// Store the given fields in descending order
+    ///
+    /// Must be set before any entries are inserted.
+    pub fn with_directions(mut self, descending: Vec<bool>) -> Self {
+        self.descending = descending;
+        self
+    }
+
+    /// Index only the documents matching `filter`
+    ///
+    /// Must be set before any entries are inserted.
+    pub fn with_partial_filter(mut self, filter: Option<Filter>) -> Self {
+        self.partial_filter = filter;
+        self
+    }
+
+    /// Store collation keys or hashes in place of the field values
+    ///
+    /// Must be set before any entries are inserted.
+    pub fn with_key_form(mut self, key_form: KeyForm) -> Self {
+        self.key_form = key_form;
+        self
+    }
+
+    /// Make this a vector index over its single field
+    ///
+    /// The tree holds each document's vector as bytes so entries persist
+    /// like any other key; the graph answers similarity searches. Must be
+    /// set before any entries are inserted.
+    pub fn with_vector(mut self, vector: VectorIndex) -> Self {
+        self.vector = Some(Arc::new(vector));
+        self
+    }
+
+    /// Nearest-neighbour graph, if this is a vector index
+    pub fn vector(&self) -> Option<&Arc<VectorIndex>> {
+        self.vector.as_ref()
+    }
+
+    /// Check whether the field at `position` is stored in descending order
+    pub fn is_descending(&self, position: usize) -> bool {
+        self.descending.get(position).copied().unwrap_or(false)
+    }
+
+    /// How field values become stored key values
+    pub fn key_form(&self) -> &KeyForm {
+        &self.key_form
+    }
+
     /// Get index name
     pub fn name(&self) -> &str {
         &self.name
@@ -56,79 +140,215 @@ impl BTreeIndex {
         self.sparse
     }
 
+    /// Filter a document must match to be indexed, if the index is partial
+    pub fn partial_filter(&self) -> Option<&Filter> {
+        self.partial_filter.as_ref()
+    }
+
+    /// Whether the index has an entry for every document of the collection
+    pub fn holds_every_document(&self) -> bool {
+        !self.sparse && self.partial_filter.is_none() && self.vector.is_none()
+    }
+
+    /// Whether a document belongs in the index under its partial filter
+    pub fn covers(&self, document: &Document) -> Result<bool, IndexError> {
+        match &self.partial_filter {
+            Some(filter) => QueryExecutor::new()
+                .matches_filter(document, filter)
+                .map_err(|e| IndexError::OperationFailed(e.to_string())),
+            None => Ok(true),
+        }
+    }
+
+    /// Check if the index holds one entry per array element for some document
+    ///
+    /// Once set the flag stays set until the index is cleared.
+    pub fn is_multikey(&self) -> bool {
+        self.multikey.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Mark the index multikey, e.g. when reloading persisted entries
+    pub fn set_multikey(&self) {
+        self.multikey.store(true, AtomicOrdering::Relaxed);
+    }
+
+    /// Check if a stored key may differ from the values of a document under it
+    ///
+    /// Equal keys are stored once, so a document whose value compares equal
+    /// to an earlier one's without being identical is filed under the
+    /// earlier value. Once set the flag stays set until the index is cleared.
+    pub fn has_lossy_keys(&self) -> bool {
+        self.lossy_keys.load(AtomicOrdering::Relaxed)
+    }
+
     /// Insert a document into the index
+    ///
+    /// An array-valued field adds one key per element. Either every key is
+    /// added or, on a unique constraint violation, none is.
     pub fn insert(&self, doc_id: DocumentId, entry: IndexEntry) -> Result<(), IndexError> {
-        let key = IndexKey::from_entry(&entry, &self.fields)?;
-        
-        // Skip if sparse and key has null values
-        if self.sparse && key.has_null() {
-            return Ok(());
+        let keys = self.entry_keys(&entry)?;
+        if self.vector.is_none() && entry.is_multikey(&self.fields) {
+            self.set_multikey();
         }
+        self.insert_keys(doc_id, keys, entry.size_estimate())
+    }
+
+    /// Insert a document under a key whose values are already in stored form
+    ///
+    /// Used to reload persisted entries, which hold collation keys and
+    /// hashes rather than the original field values.
+    pub fn insert_stored(&self, doc_id: DocumentId, values: Vec<IndexValue>) -> Result<(), IndexError> {
+        let size = self
+            .fields
+            .iter()
+            .zip(&values)
+            .map(|(field, value)| field.len() + value.to_value().map(|v| v.size_bytes()).unwrap_or(0))
+            .sum();
+        let key = IndexKey::from_index_values(values).with_directions(&self.descending);
+        self.insert_keys(doc_id, vec![key], size)
+    }
+
+    /// Add stored keys for a document, all or none
+    fn insert_keys(&self, doc_id: DocumentId, keys: Vec<IndexKey>, size: usize) -> Result<(), IndexError> {
+        let vectors = match &self.vector {
+            Some(graph) => keys
+                .iter()
+                .filter_map(|key| key.values.first())
+                .map(|value| graph.spec().decode(value))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
 
         let mut tree = self.tree.write().unwrap();
         let mut stats = self.stats.write().unwrap();
 
+        // Re-inserting a key that is already present changes nothing
+        let keys: Vec<IndexKey> = keys
+            .into_iter()
+            .filter(|key| !tree.get(key).map(|docs| docs.contains(&doc_id)).unwrap_or(false))
+            .collect();
         if self.unique {
-            // Check for uniqueness violation
-            if let Some(existing_docs) = tree.get(&key) {
-                if !existing_docs.is_empty() && !existing_docs.contains(&doc_id) {
-                    return Err(IndexError::UniqueConstraintViolation {
-                        index: self.name.clone(),
-                        key: key.to_string(),
-                    });
-                }
+            if let Some(key) = keys.iter().find(|key| tree.get(*key).is_some_and(|docs| !docs.is_empty())) {
+                return Err(IndexError::UniqueConstraintViolation {
+                    index: self.name.clone(),
+                    key: DuplicateKey::new(&self.fields, &key.values),
+                });
             }
-            
-            // For unique indexes, store single document
-            tree.insert(key, vec![doc_id]);
-        } else {
-            // For non-unique indexes, append to document list
-            tree.entry(key).or_insert_with(Vec::new).push(doc_id);
+        }
+        if keys.is_empty() {
+            return Ok(());
         }
 
-        stats.total_entries += 1;
-        stats.total_size_bytes += entry.size_estimate();
+        let mut distribution = self.distribution.write().unwrap();
+        let distribution = Arc::make_mut(&mut distribution);
+        for key in &keys {
+            let shared = match tree.get_key_value(key) {
+                Some((stored, _)) => {
+                    if !stored.is_identical(key) {
+                        self.lossy_keys.store(true, AtomicOrdering::Relaxed);
+                    }
+                    self.fields.len()
+                }
+                None => shared_prefix_len(&tree, key),
+            };
+            tree.entry(key.clone()).or_default().push(doc_id);
+            distribution.record_insert(&key.values, shared);
+        }
 
+        stats.total_entries += keys.len() as u64;
+        stats.total_size_bytes += size;
+
+        if let Some(graph) = &self.vector {
+            for vector in vectors {
+                graph.insert(doc_id, vector)?;
+            }
+        }
         Ok(())
     }
 
     /// Remove a document from the index
+    ///
+    /// Returns whether any of the document's keys were present.
     pub fn remove(&self, doc_id: DocumentId, entry: IndexEntry) -> Result<bool, IndexError> {
-        let key = IndexKey::from_entry(&entry, &self.fields)?;
-        
-        // Skip if sparse and key has null values
-        if self.sparse && key.has_null() {
-            return Ok(false);
+        let keys = self.entry_keys(&entry)?;
+        if let Some(graph) = &self.vector {
+            graph.remove(&doc_id);
         }
 
         let mut tree = self.tree.write().unwrap();
         let mut stats = self.stats.write().unwrap();
+        let mut distribution = self.distribution.write().unwrap();
+        let distribution = Arc::make_mut(&mut distribution);
+
+        let mut removed = 0;
+        for key in &keys {
+            let Some(doc_list) = tree.get_mut(key) else {
+                continue;
+            };
+            let Some(pos) = doc_list.iter().position(|&id| id == doc_id) else {
+                continue;
+            };
+            doc_list.remove(pos);
+            removed += 1;
+
+            // Remove empty entries
+            let shared = if doc_list.is_empty() {
+                tree.remove(key);
+                shared_prefix_len(&tree, key)
+            } else {
+                self.fields.len()
+            };
+            distribution.record_remove(&key.values, shared);
+        }
 
-        if let Some(doc_list) = tree.get_mut(&key) {
-            if let Some(pos) = doc_list.iter().position(|&id| id == doc_id) {
-                doc_list.remove(pos);
-                stats.total_entries -= 1;
-                stats.total_size_bytes -= entry.size_estimate();
-
-                // Remove empty entries
-                if doc_list.is_empty() {
-                    tree.remove(&key);
-                }
+        if removed == 0 {
+            return Ok(false);
+        }
+        stats.total_entries -= removed;
+        stats.total_size_bytes = stats.total_size_bytes.saturating_sub(entry.size_estimate());
+        Ok(true)
+    }
 
-                return Ok(true);
+    /// Keys a document's entry is stored under, in stored order
+    ///
+    /// A sparse index skips keys holding a null or missing value. Array
+    /// elements that collate or hash alike share one key. A vector index
+    /// has one key holding the whole vector, or none when the field holds
+    /// no vector of the index's length.
+    fn entry_keys(&self, entry: &IndexEntry) -> Result<Vec<IndexKey>, IndexError> {
+        if let Some(graph) = &self.vector {
+            let value = self.fields.first().and_then(|field| entry.get_field(field));
+            return Ok(graph
+                .spec()
+                .key(value)
+                .map(|key| IndexKey::from_index_values(vec![key]))
+                .into_iter()
+                .collect());
+        }
+        let mut keys: Vec<IndexKey> = Vec::new();
+        for key in IndexKey::keys_from_entry(entry, &self.fields)? {
+            if self.sparse && key.has_null() {
+                continue;
+            }
+            let key = self.key_form.apply_key(key).with_directions(&self.descending);
+            if !keys.contains(&key) {
+                keys.push(key);
             }
         }
-
-        Ok(false)
+        Ok(keys)
     }
 
     /// Find documents by exact key match
     pub fn find_exact(&self, key: &IndexKey) -> Result<Vec<DocumentId>, IndexError> {
+        let key = key.clone().with_directions(&self.descending);
         let tree = self.tree.read().unwrap();
-        Ok(tree.get(key).cloned().unwrap_or_default())
+        Ok(tree.get(&key).cloned().unwrap_or_default())
     }
 
     /// Find documents by key range
+    ///
+    /// Bounds are compared in stored key order, so on descending fields
+    /// `start` is the larger value.
     pub fn find_range(
         &self,
         start: Option<&IndexKey>,
@@ -136,45 +356,178 @@ impl BTreeIndex {
         include_start: bool,
         include_end: bool,
     ) -> Result<Vec<DocumentId>, IndexError> {
+        use std::ops::Bound;
+
+        let start = start.map(|k| k.clone().with_directions(&self.descending));
+        let end = end.map(|k| k.clone().with_directions(&self.descending));
+
+        // BTreeMap::range panics on inverted or empty-exclusive bounds
+        if let (Some(s), Some(e)) = (&start, &end) {
+            match s.cmp(e) {
+                Ordering::Greater => return Ok(Vec::new()),
+                Ordering::Equal if !(include_start && include_end) => return Ok(Vec::new()),
+                _ => {}
+            }
+        }
+
+        let lower = match start {
+            Some(k) if include_start => Bound::Included(k),
+            Some(k) => Bound::Excluded(k),
+            None => Bound::Unbounded,
+        };
+        let upper = match end {
+            Some(k) if include_end => Bound::Included(k),
+            Some(k) => Bound::Excluded(k),
+            None => Bound::Unbounded,
+        };
+
         let tree = self.tree.read().unwrap();
         let mut results = Vec::new();
+        for doc_ids in tree.range((lower, upper)).map(|(_, ids)| ids) {
+            results.extend_from_slice(doc_ids);
+        }
 
-        // Collect all matching entries
-        for (key, doc_ids) in tree.iter() {
-            let mut include = true;
+        Ok(results)
+    }
 
-            // Check start bound
-            if let Some(s) = start {
-                if include_start {
-                    if key < s {
-                        include = false;
-                    }
-                } else {
-                    if key <= s {
-                        include = false;
-                    }
+    /// Find documents whose leading fields equal `prefix` and whose next
+    /// field falls between `lower` and `upper`
+    ///
+    /// Each bound is a value plus an inclusive flag. Results come back in
+    /// stored key order, so a descending field yields larger values first.
+    pub fn find_prefix_range(
+        &self,
+        prefix: &[Value],
+        lower: Option<(&Value, bool)>,
+        upper: Option<(&Value, bool)>,
+    ) -> Result<Vec<DocumentId>, IndexError> {
+        let mut results = Vec::new();
+        self.scan_prefix_range(prefix, lower, upper, false, |_, doc_id| {
+            results.push(doc_id);
+            true
+        })?;
+        Ok(results)
+    }
+
+    /// Visit the entries matched by [`find_prefix_range`](Self::find_prefix_range)
+    ///
+    /// `visit` receives each key and document id and returns `false` to stop
+    /// the scan. With `reverse` the entries are visited in the opposite of
+    /// stored order.
+    pub fn scan_prefix_range<F>(
+        &self,
+        prefix: &[Value],
+        lower: Option<(&Value, bool)>,
+        upper: Option<(&Value, bool)>,
+        reverse: bool,
+        mut visit: F,
+    ) -> Result<(), IndexError>
+    where
+        F: FnMut(&IndexKey, DocumentId) -> bool,
+    {
+        let position = prefix.len();
+        let bounded = lower.is_some() || upper.is_some();
+        if position > self.fields.len() || (position == self.fields.len() && bounded) {
+            return Err(IndexError::OperationFailed(format!(
+                "Index '{}' has {} fields, cannot bound field {}",
+                self.name,
+                self.fields.len(),
+                position
+            )));
+        }
+
+        let prefix: Vec<IndexValue> = prefix
+            .iter()
+            .map(IndexValue::from_value)
+            .collect::<Result<_, _>>()?;
+        let lower = lower
+            .map(|(v, inclusive)| IndexValue::from_value(v).map(|v| (v, inclusive)))
+            .transpose()?;
+        let upper = upper
+            .map(|(v, inclusive)| IndexValue::from_value(v).map(|v| (v, inclusive)))
+            .transpose()?;
+
+        // In stored order a descending field meets its upper bound first
+        let descending = self.is_descending(position);
+        let (first, last) = if descending { (&upper, &lower) } else { (&lower, &upper) };
+        let locate = |key: &IndexKey| -> RangePosition {
+            let Some(value) = key.values.get(position) else {
+                return RangePosition::Inside;
+            };
+            let stored = |ordering: Ordering| if descending { ordering.reverse() } else { ordering };
+            if let Some((bound, inclusive)) = first {
+                match stored(value.cmp(bound)) {
+                    Ordering::Less => return RangePosition::Before,
+                    Ordering::Equal if !inclusive => return RangePosition::Before,
+                    _ => {}
                 }
             }
+            if let Some((bound, inclusive)) = last {
+                match stored(value.cmp(bound)) {
+                    Ordering::Greater => return RangePosition::After,
+                    Ordering::Equal if !inclusive => return RangePosition::After,
+                    _ => {}
+                }
+            }
+            RangePosition::Inside
+        };
 
-            // Check end bound
-            if let Some(e) = end {
-                if include_end {
-                    if key > e {
-                        include = false;
-                    }
-                } else {
-                    if key >= e {
-                        include = false;
+        let tree = self.tree.read().unwrap();
+
+        if reverse && prefix.is_empty() {
+            // Whole-index walks can start from the end of the tree
+            for (key, doc_ids) in tree.iter().rev() {
+                match locate(key) {
+                    RangePosition::After => continue,
+                    RangePosition::Before => break,
+                    RangePosition::Inside => {}
+                }
+                for doc_id in doc_ids.iter().rev() {
+                    if !visit(key, *doc_id) {
+                        return Ok(());
                     }
                 }
             }
+            return Ok(());
+        }
+
+        // Seek to the bound that comes first in stored order
+        let mut start = prefix.clone();
+        if let Some((value, _)) = first {
+            start.push(value.clone());
+        }
+        let start = IndexKey::from_index_values(start).with_directions(&self.descending);
+
+        let mut matched = Vec::new();
+        for (key, doc_ids) in tree.range(start..) {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            match locate(key) {
+                RangePosition::Before => continue,
+                RangePosition::After => break,
+                RangePosition::Inside => {}
+            }
+            if reverse {
+                matched.push((key, doc_ids));
+                continue;
+            }
+            for doc_id in doc_ids {
+                if !visit(key, *doc_id) {
+                    return Ok(());
+                }
+            }
+        }
 
-            if include {
-                results.extend_from_slice(doc_ids);
+        for (key, doc_ids) in matched.into_iter().rev() {
+            for doc_id in doc_ids.iter().rev() {
+                if !visit(key, *doc_id) {
+                    return Ok(());
+                }
             }
         }
 
-        Ok(results)
+        Ok(())
     }
 
     /// Get index statistics
@@ -182,6 +535,70 @@ impl BTreeIndex {
         self.stats.read().unwrap().clone()
     }
 
+    /// Get the key distribution used for query planning
+    pub fn key_distribution(&self) -> Arc<KeyDistribution> {
+        Arc::clone(&self.distribution.read().unwrap())
+    }
+
+    /// Check whether the key distribution has drifted since it was last reported
+    ///
+    /// Drift is a tenth of the entries, and at least
+    /// `MIN_STATISTICS_DRIFT`, inserted or removed since the last analysis
+    /// or report. Each drift is reported once, so plans costed on the old
+    /// distribution can be dropped.
+    pub fn take_statistics_drift(&self) -> bool {
+        let distribution = self.distribution.read().unwrap();
+        let reported = self.reported_modifications.load(AtomicOrdering::Relaxed);
+        let threshold = (distribution.entries / 10).max(MIN_STATISTICS_DRIFT);
+        distribution.modifications.saturating_sub(reported) >= threshold
+            && self
+                .reported_modifications
+                .compare_exchange(
+                    reported,
+                    distribution.modifications,
+                    AtomicOrdering::Relaxed,
+                    AtomicOrdering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    /// Number of entries in the index, as tracked by its key distribution
+    pub fn entry_count(&self) -> u64 {
+        self.distribution.read().unwrap().entries
+    }
+
+    /// Rebuild the key distribution from every entry in the index
+    pub fn analyze(&self) -> KeyDistribution {
+        let tree = self.tree.read().unwrap();
+        let fields = self.fields.len();
+
+        let mut entries = 0u64;
+        let mut distinct_prefixes = vec![0u64; fields];
+        let mut values = vec![BTreeMap::<IndexValue, u64>::new(); fields];
+        let mut previous: Option<&IndexKey> = None;
+
+        for (key, doc_ids) in tree.iter() {
+            let count = doc_ids.len() as u64;
+            entries += count;
+
+            // Keys sharing a prefix are adjacent in stored order
+            let shared = previous.map(|p| common_prefix_len(p, key)).unwrap_or(0);
+            for distinct in distinct_prefixes.iter_mut().skip(shared) {
+                *distinct += 1;
+            }
+            for (counts, value) in values.iter_mut().zip(&key.values) {
+                *counts.entry(value.clone()).or_insert(0) += count;
+            }
+            previous = Some(key);
+        }
+
+        let distribution = KeyDistribution::build(entries, distinct_prefixes, &values);
+        let mut current = self.distribution.write().unwrap();
+        *current = Arc::new(distribution.clone());
+        self.reported_modifications.store(0, AtomicOrdering::Relaxed);
+        distribution
+    }
+
     /// Get the number of unique keys in the index
     pub fn key_count(&self) -> usize {
         self.tree.read().unwrap().len()
@@ -199,14 +616,95 @@ impl BTreeIndex {
         
         tree.clear();
         *stats = IndexStats::default();
+        if let Some(graph) = &self.vector {
+            graph.clear();
+        }
+        self.multikey.store(false, AtomicOrdering::Relaxed);
+        self.lossy_keys.store(false, AtomicOrdering::Relaxed);
+        *self.distribution.write().unwrap() = Arc::default();
+        self.reported_modifications.store(0, AtomicOrdering::Relaxed);
     }
 }
 
+/// Number of leading values two keys have in common
+fn common_prefix_len(a: &IndexKey, b: &IndexKey) -> usize {
+    a.values
+        .iter()
+        .zip(&b.values)
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Longest prefix `key` shares with its neighbours in the tree
+///
+/// Keys sharing a prefix are adjacent in stored order, so the neighbours
+/// share the longest prefix of any key in the tree.
+fn shared_prefix_len(tree: &BTreeMap<IndexKey, Vec<DocumentId>>, key: &IndexKey) -> usize {
+    use std::ops::Bound;
+
+    let before = tree
+        .range((Bound::Unbounded, Bound::Excluded(key)))
+        .next_back()
+        .map(|(k, _)| common_prefix_len(k, key));
+    let after = tree
+        .range((Bound::Excluded(key), Bound::Unbounded))
+        .next()
+        .map(|(k, _)| common_prefix_len(k, key));
+    before.unwrap_or(0).max(after.unwrap_or(0))
+}
+
+/// Where a key lies relative to a range, in stored order
+enum RangePosition {
+    Before,
+    Inside,
+    After,
+}
+
 /// Index key for B-tree storage
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+///
+/// Keys compare field by field, reversing the fields flagged descending;
+/// a key that is a prefix of another sorts first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexKey {
     /// Values for each indexed field
     values: Vec<IndexValue>,
+    /// Per-field descending flags
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    descending: Vec<bool>,
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl std::hash::Hash for IndexKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.values.hash(state);
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for (position, (a, b)) in self.values.iter().zip(&other.values).enumerate() {
+            let descending = self.descending.get(position).copied().unwrap_or(false)
+                || other.descending.get(position).copied().unwrap_or(false);
+            let ordering = if descending { b.cmp(a) } else { a.cmp(b) };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        self.values.len().cmp(&other.values.len())
+    }
 }
 
 impl IndexKey {
@@ -215,12 +713,82 @@ impl IndexKey {
         let mut values = Vec::new();
         
         for field in fields {
-            let value = entry.get_field(field)
-                .ok_or_else(|| IndexError::MissingField(field.clone()))?;
-            values.push(IndexValue::from_value(value)?);
+            values.push(match entry.get_field(field) {
+                Some(value) => IndexValue::from_value(value)?,
+                None => IndexValue::Missing,
+            });
         }
 
-        Ok(Self { values })
+        Ok(Self::from_index_values(values))
+    }
+
+    /// Create every key an entry is indexed under
+    ///
+    /// An array-valued field contributes one key per distinct element, and
+    /// an empty array indexes as missing. At most one field may hold an
+    /// array, since the keys of two arrays would be their cross product.
+    pub fn keys_from_entry(entry: &IndexEntry, fields: &[String]) -> Result<Vec<Self>, IndexError> {
+        let arrays: Vec<&String> = fields
+            .iter()
+            .filter(|field| matches!(entry.get_field(field), Some(Value::Array(_))))
+            .collect();
+        if arrays.len() > 1 {
+            return Err(IndexError::ParallelArrays(arrays.into_iter().cloned().collect()));
+        }
+
+        let mut keys = vec![Vec::with_capacity(fields.len())];
+        for field in fields {
+            match entry.get_field(field) {
+                Some(Value::Array(elements)) => {
+                    let mut distinct: Vec<IndexValue> = Vec::new();
+                    for element in elements {
+                        let value = IndexValue::from_value(element)?;
+                        if !distinct.contains(&value) {
+                            distinct.push(value);
+                        }
+                    }
+                    if distinct.is_empty() {
+                        distinct.push(IndexValue::Missing);
+                    }
+                    keys = keys
+                        .into_iter()
+                        .flat_map(|key| {
+                            distinct.iter().map(move |value| {
+                                let mut key = key.clone();
+                                key.push(value.clone());
+                                key
+                            })
+                        })
+                        .collect();
+                }
+                Some(value) => {
+                    let value = IndexValue::from_value(value)?;
+                    keys.iter_mut().for_each(|key| key.push(value.clone()));
+                }
+                None => keys.iter_mut().for_each(|key| key.push(IndexValue::Missing)),
+            }
+        }
+
+        Ok(keys.into_iter().map(Self::from_index_values).collect())
+    }
+
+    fn from_index_values(values: Vec<IndexValue>) -> Self {
+        Self {
+            values,
+            descending: Vec::new(),
+        }
+    }
+
+    /// Apply per-field descending flags to this key
+    pub fn with_directions(mut self, descending: &[bool]) -> Self {
+        self.descending = descending.to_vec();
+        self
+    }
+
+    /// Check whether the leading values of this key equal `prefix`
+    pub fn starts_with(&self, prefix: &[IndexValue]) -> bool {
+        self.values.len() >= prefix.len()
+            && self.values.iter().zip(prefix).all(|(a, b)| a == b)
     }
 
     /// Create index key from values
@@ -230,14 +798,14 @@ impl IndexKey {
             .map(|v| IndexValue::from_value(&v))
             .collect();
         
-        Ok(Self {
-            values: index_values?,
-        })
+        Ok(Self::from_index_values(index_values?))
     }
 
-    /// Check if key contains null values
+    /// Check if key contains null or missing values
     pub fn has_null(&self) -> bool {
-        self.values.iter().any(|v| matches!(v, IndexValue::Null))
+        self.values
+            .iter()
+            .any(|v| matches!(v, IndexValue::Null | IndexValue::Missing))
     }
 
     /// Get values
@@ -245,6 +813,12 @@ impl IndexKey {
         &self.values
     }
 
+    /// Whether both keys hold the same values with the same types
+    pub fn is_identical(&self, other: &IndexKey) -> bool {
+        self.values.len() == other.values.len()
+            && self.values.iter().zip(&other.values).all(|(a, b)| a.is_identical(b))
+    }
+
     /// Convert to string representation
     pub fn to_string(&self) -> String {
         let value_strs: Vec<String> = self.values
@@ -255,13 +829,98 @@ impl IndexKey {
     }
 }
 
+/// How an index turns field values into the values it stores
+///
+/// Under a collation strings are stored as collation keys, so they order
+/// and compare as the collation says. A hashed index then stores a 64-bit
+/// hash of each value, which spreads keys evenly but supports equality
+/// lookups only. Null and missing values are kept as they are so sparse
+/// indexes and null lookups behave as for any other index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyForm {
+    hashed: bool,
+    collation: Option<Collation>,
+}
+
+impl KeyForm {
+    /// Key form of a hashed and/or collated index; `simple` collation is none
+    pub fn new(hashed: bool, collation: Option<Collation>) -> Self {
+        Self {
+            hashed,
+            collation: collation.filter(|c| !c.is_simple()),
+        }
+    }
+
+    /// Whether stored values equal the field values
+    pub fn is_plain(&self) -> bool {
+        !self.hashed && self.collation.is_none()
+    }
+
+    /// Whether stored values are hashes
+    pub fn is_hashed(&self) -> bool {
+        self.hashed
+    }
+
+    /// Collation applied to stored strings
+    pub fn collation(&self) -> Option<&Collation> {
+        self.collation.as_ref()
+    }
+
+    /// Stored form of one value
+    pub fn apply(&self, value: IndexValue) -> IndexValue {
+        let value = match (&self.collation, value) {
+            (Some(collation), IndexValue::String(s)) => IndexValue::String(collation.key(&s)),
+            (_, value) => value,
+        };
+        if self.hashed && !matches!(value, IndexValue::Missing | IndexValue::Null) {
+            value.hashed()
+        } else {
+            value
+        }
+    }
+
+    /// Stored form of a query value, e.g. an equality predicate's operand
+    ///
+    /// Values an index cannot hold, such as objects, are returned as given.
+    pub fn apply_value(&self, value: &Value) -> Value {
+        if self.is_plain() {
+            return value.clone();
+        }
+        match IndexValue::from_value(value) {
+            Ok(index_value) => self.apply(index_value).to_value().unwrap_or_else(|| value.clone()),
+            Err(_) => value.clone(),
+        }
+    }
+
+    /// Stored form of every value of a key
+    pub fn apply_key(&self, key: IndexKey) -> IndexKey {
+        if self.is_plain() {
+            return key;
+        }
+        IndexKey {
+            values: key.values.into_iter().map(|v| self.apply(v)).collect(),
+            descending: key.descending,
+        }
+    }
+}
+
 /// Index value that can be stored in B-tree
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+///
+/// Values order by type first; integers, floats and decimals share one
+/// numeric bracket so that `Int(2) < Float(2.5) < Int(3)`. Decimals meet
+/// other numbers at their nearest float, and each other exactly. Each
+/// variant keeps enough of the original value to rebuild it for covered
+/// queries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum IndexValue {
-    /// Null value (lowest sort order)
+    /// Field absent from the document (lowest sort order)
+    Missing,
+    /// Null value
     Null,
     /// Boolean value
     Bool(bool),
+    /// 32-bit integer value
+    Int32(i32),
     /// Integer value (stored as i64 for consistency)
     Int(i64),
     /// Float value (stored as ordered bytes for comparison)
@@ -272,8 +931,215 @@ pub enum IndexValue {
     Binary(Vec<u8>),
     /// ObjectId value
     ObjectId([u8; 12]),
-    /// DateTime value (stored as timestamp)
-    DateTime(i64),
+    /// DateTime value (seconds since the epoch and subsecond nanoseconds)
+    DateTime(i64, u32),
+    /// Exact decimal value
+    Decimal128(Decimal128),
+    /// UUID value
+    Uuid([u8; 16]),
+    /// Timestamp value (seconds and increment)
+    Timestamp(u32, u32),
+}
+
+impl IndexValue {
+    /// Sort bracket for the value's type
+    fn type_rank(&self) -> u8 {
+        match self {
+            IndexValue::Missing => 0,
+            IndexValue::Null => 1,
+            IndexValue::Bool(_) => 2,
+            IndexValue::Int32(_) | IndexValue::Int(_) | IndexValue::Float(_) | IndexValue::Decimal128(_) => 3,
+            IndexValue::String(_) => 4,
+            IndexValue::Binary(_) => 5,
+            IndexValue::ObjectId(_) => 6,
+            IndexValue::DateTime(..) => 7,
+            IndexValue::Uuid(_) => 8,
+            IndexValue::Timestamp(..) => 9,
+        }
+    }
+
+    /// 64-bit hash of the value, equal for values that compare equal
+    ///
+    /// Numbers hash by their float value, so `Int32(5)`, `Int(5)` and
+    /// `Float(5.0)` share a hash just as they share a sort position.
+    pub fn hashed(&self) -> IndexValue {
+        let mut hasher = Sha256::new();
+        hasher.update([self.type_rank()]);
+        match self {
+            IndexValue::Missing | IndexValue::Null => {}
+            IndexValue::Bool(b) => hasher.update([u8::from(*b)]),
+            IndexValue::Int32(_) | IndexValue::Int(_) | IndexValue::Float(_) | IndexValue::Decimal128(_) => {
+                // Adding zero turns negative zero into zero
+                let number = self.numeric().unwrap_or_default() + 0.0;
+                hasher.update(number.to_bits().to_be_bytes());
+            }
+            IndexValue::String(s) => hasher.update(s.as_bytes()),
+            IndexValue::Binary(b) => hasher.update(b),
+            IndexValue::ObjectId(oid) => hasher.update(oid),
+            IndexValue::DateTime(secs, nanos) => {
+                hasher.update(secs.to_be_bytes());
+                hasher.update(nanos.to_be_bytes());
+            }
+            IndexValue::Uuid(uuid) => hasher.update(uuid),
+            IndexValue::Timestamp(time, increment) => {
+                hasher.update(time.to_be_bytes());
+                hasher.update(increment.to_be_bytes());
+            }
+        }
+        let digest = hasher.finalize();
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        IndexValue::Int(i64::from_be_bytes(bytes))
+    }
+
+    /// Whether two values sort by value rather than by type
+    pub fn same_type(&self, other: &Self) -> bool {
+        self.type_rank() == other.type_rank()
+    }
+
+    /// Whether two values rebuild the same document value
+    ///
+    /// Stricter than equality: `Int32(5)`, `Int(5)` and `Float(5.0)` are
+    /// equal but not identical, and neither are `0.0` and `-0.0` or
+    /// decimals of different scale.
+    pub fn is_identical(&self, other: &Self) -> bool {
+        match (self, other) {
+            (IndexValue::Int32(a), IndexValue::Int32(b)) => a == b,
+            (IndexValue::Int(a), IndexValue::Int(b)) => a == b,
+            (IndexValue::Float(a), IndexValue::Float(b)) => a.0.to_bits() == b.0.to_bits(),
+            (IndexValue::Decimal128(a), IndexValue::Decimal128(b)) => {
+                a.coefficient() == b.coefficient() && a.exponent() == b.exponent()
+            }
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b) && a == b,
+        }
+    }
+
+    /// Integer payload of either integer variant
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            IndexValue::Int32(i) => Some(*i as i64),
+            IndexValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Position on a number line shared by values of one sort bracket
+    fn numeric(&self) -> Option<f64> {
+        match self {
+            IndexValue::Int32(i) => Some(*i as f64),
+            IndexValue::Int(i) => Some(*i as f64),
+            IndexValue::Float(f) => Some(f.0),
+            IndexValue::Decimal128(d) => Some(d.to_f64()),
+            IndexValue::DateTime(secs, nanos) => Some(*secs as f64 + *nanos as f64 / 1e9),
+            IndexValue::Timestamp(time, increment) => Some(*time as f64 + *increment as f64 / 4294967296.0),
+            _ => None,
+        }
+    }
+
+    /// Fraction of the way from `lower` to `upper` at which this value lies
+    ///
+    /// Only numbers and dates can be interpolated; other types, or bounds
+    /// of a different type, return `None`.
+    pub fn interpolate(&self, lower: &IndexValue, upper: &IndexValue) -> Option<f64> {
+        if self.type_rank() != lower.type_rank() || self.type_rank() != upper.type_rank() {
+            return None;
+        }
+        let (value, low, high) = (self.numeric()?, lower.numeric()?, upper.numeric()?);
+        if high <= low {
+            return None;
+        }
+        Some(((value - low) / (high - low)).clamp(0.0, 1.0))
+    }
+
+    /// Rebuild the document value, or `None` for a missing field
+    pub fn to_value(&self) -> Option<Value> {
+        Some(match self {
+            IndexValue::Missing => return None,
+            IndexValue::Null => Value::Null,
+            IndexValue::Bool(b) => Value::Bool(*b),
+            IndexValue::Int32(i) => Value::Int32(*i),
+            IndexValue::Int(i) => Value::Int64(*i),
+            IndexValue::Float(f) => Value::Float64(f.0),
+            IndexValue::String(s) => Value::String(s.clone()),
+            IndexValue::Binary(b) => Value::Binary(b.clone()),
+            IndexValue::ObjectId(oid) => Value::ObjectId(ObjectId::from_bytes(*oid)),
+            IndexValue::DateTime(secs, nanos) => {
+                Value::DateTime(chrono::DateTime::from_timestamp(*secs, *nanos)?)
+            }
+            IndexValue::Decimal128(d) => Value::Decimal128(*d),
+            IndexValue::Uuid(uuid) => Value::Uuid(uuid::Uuid::from_bytes(*uuid)),
+            IndexValue::Timestamp(time, increment) => Value::Timestamp(Timestamp::new(*time, *increment)),
+        })
+    }
+}
+
+impl PartialEq for IndexValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexValue {}
+
+impl std::hash::Hash for IndexValue {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.type_rank().hash(state);
+        match self {
+            IndexValue::Missing | IndexValue::Null => {}
+            IndexValue::Bool(b) => b.hash(state),
+            // Numerically equal ints and floats must hash alike
+            IndexValue::Int32(i) => OrderedFloat(*i as f64).hash(state),
+            IndexValue::Int(i) => OrderedFloat(*i as f64).hash(state),
+            IndexValue::Float(f) => f.hash(state),
+            IndexValue::Decimal128(d) => OrderedFloat(d.to_f64()).hash(state),
+            IndexValue::String(s) => s.hash(state),
+            IndexValue::Binary(b) => b.hash(state),
+            IndexValue::ObjectId(oid) => oid.hash(state),
+            IndexValue::DateTime(secs, nanos) => (secs, nanos).hash(state),
+            IndexValue::Uuid(uuid) => uuid.hash(state),
+            IndexValue::Timestamp(time, increment) => (time, increment).hash(state),
+        }
+    }
+}
+
+impl PartialOrd for IndexValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if let (Some(a), Some(b)) = (self.as_int(), other.as_int()) {
+            return a.cmp(&b);
+        }
+
+        match (self, other) {
+            (IndexValue::Bool(a), IndexValue::Bool(b)) => a.cmp(b),
+            (IndexValue::Float(a), IndexValue::Float(b)) => a.cmp(b),
+            (IndexValue::Float(a), IndexValue::Int32(_) | IndexValue::Int(_)) => {
+                a.cmp(&OrderedFloat(other.as_int().unwrap_or_default() as f64))
+            }
+            (IndexValue::Int32(_) | IndexValue::Int(_), IndexValue::Float(b)) => {
+                OrderedFloat(self.as_int().unwrap_or_default() as f64).cmp(b)
+            }
+            (IndexValue::Decimal128(a), IndexValue::Decimal128(b)) => a.cmp(b),
+            (IndexValue::Decimal128(_), _) | (_, IndexValue::Decimal128(_)) if self.same_type(other) => {
+                OrderedFloat(self.numeric().unwrap_or_default()).cmp(&OrderedFloat(other.numeric().unwrap_or_default()))
+            }
+            (IndexValue::String(a), IndexValue::String(b)) => a.cmp(b),
+            (IndexValue::Binary(a), IndexValue::Binary(b)) => a.cmp(b),
+            (IndexValue::ObjectId(a), IndexValue::ObjectId(b)) => a.cmp(b),
+            (IndexValue::DateTime(a_secs, a_nanos), IndexValue::DateTime(b_secs, b_nanos)) => {
+                (a_secs, a_nanos).cmp(&(b_secs, b_nanos))
+            }
+            (IndexValue::Uuid(a), IndexValue::Uuid(b)) => a.cmp(b),
+            (IndexValue::Timestamp(a_time, a_inc), IndexValue::Timestamp(b_time, b_inc)) => {
+                (a_time, a_inc).cmp(&(b_time, b_inc))
+            }
+            _ => self.type_rank().cmp(&other.type_rank()),
+        }
+    }
 }
 
 impl IndexValue {
@@ -282,13 +1148,16 @@ impl IndexValue {
         match value {
             Value::Null => Ok(IndexValue::Null),
             Value::Bool(b) => Ok(IndexValue::Bool(*b)),
-            Value::Int32(i) => Ok(IndexValue::Int(*i as i64)),
+            Value::Int32(i) => Ok(IndexValue::Int32(*i)),
             Value::Int64(i) => Ok(IndexValue::Int(*i)),
             Value::Float64(f) => Ok(IndexValue::Float(OrderedFloat(*f))),
             Value::String(s) => Ok(IndexValue::String(s.clone())),
             Value::Binary(b) => Ok(IndexValue::Binary(b.clone())),
             Value::ObjectId(oid) => Ok(IndexValue::ObjectId(*oid.as_bytes())),
-            Value::DateTime(dt) => Ok(IndexValue::DateTime(dt.timestamp())),
+            Value::DateTime(dt) => Ok(IndexValue::DateTime(dt.timestamp(), dt.timestamp_subsec_nanos())),
+            Value::Decimal128(d) => Ok(IndexValue::Decimal128(*d)),
+            Value::Uuid(uuid) => Ok(IndexValue::Uuid(*uuid.as_bytes())),
+            Value::Timestamp(ts) => Ok(IndexValue::Timestamp(ts.time, ts.increment)),
             Value::Array(_) | Value::Object(_) => {
                 Err(IndexError::UnsupportedValueType(format!("{:?}", value)))
             }
@@ -300,23 +1169,37 @@ impl IndexValue {
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct OrderedFloat(f64);
 
+impl OrderedFloat {
+    /// Wrap a float
+    pub fn new(value: f64) -> Self {
+        Self(value)
+    }
+
+    /// The wrapped float
+    pub fn get(self) -> f64 {
+        self.0
+    }
+}
+
 impl Eq for OrderedFloat {}
 
 impl std::hash::Hash for OrderedFloat {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.to_bits().hash(state);
+        // 0.0 and -0.0 compare equal, so they must hash alike
+        let value = if self.0 == 0.0 { 0.0 } else { self.0 };
+        value.to_bits().hash(state);
     }
 }
 
 impl PartialOrd for OrderedFloat {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
 impl Ord for OrderedFloat {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.partial_cmp(&other.0).unwrap_or(std::cmp::Ordering::Equal)
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
     }
 }
 
@@ -335,6 +1218,41 @@ impl IndexEntry {
         }
     }
 
+    /// Collect the indexed fields of a document
+    ///
+    /// Absent fields stay out of the entry and index as missing. A path
+    /// that passes through arrays is stored as an array of every element
+    /// it reaches, which the index expands into one key per element.
+    pub fn from_document(document: &Document, fields: &[String]) -> Self {
+        let mut entry = Self::new();
+        for field in fields {
+            let values = document.values_by_path(field);
+            let direct = document.get_by_path(field);
+            let value = match values.as_slice() {
+                [] => continue,
+                [value] if direct.is_some_and(|direct| std::ptr::eq(direct, *value)) => (*value).clone(),
+                values => Value::Array(
+                    values
+                        .iter()
+                        .flat_map(|value| match value {
+                            Value::Array(elements) => elements.clone(),
+                            value => vec![(*value).clone()],
+                        })
+                        .collect(),
+                ),
+            };
+            entry.add_field(field.clone(), value);
+        }
+        entry
+    }
+
+    /// Check whether any of the given fields holds an array
+    pub fn is_multikey(&self, fields: &[String]) -> bool {
+        fields
+            .iter()
+            .any(|field| matches!(self.fields.get(field), Some(Value::Array(_))))
+    }
+
     /// Add field value
     pub fn add_field(&mut self, name: String, value: Value) {
         self.fields.insert(name, value);
@@ -378,8 +1296,8 @@ pub struct IndexStats {
 /// Index errors
 #[derive(Debug, thiserror::Error)]
 pub enum IndexError {
-    #[error("Unique constraint violation in index '{index}' for key '{key}'")]
-    UniqueConstraintViolation { index: String, key: String },
+    #[error("Unique constraint violation in index '{index}' for key {key}")]
+    UniqueConstraintViolation { index: String, key: DuplicateKey },
 
     #[error("Missing field: {0}")]
     MissingField(String),
@@ -387,14 +1305,63 @@ pub enum IndexError {
     #[error("Unsupported value type for indexing: {0}")]
     UnsupportedValueType(String),
 
+    #[error("Cannot index parallel arrays: fields {0:?} all hold arrays")]
+    ParallelArrays(Vec<String>),
+
+    #[error("Invalid index '{index}': {reason}")]
+    InvalidDefinition { index: String, reason: String },
+
+    #[error("Corrupt index entry: {0}")]
+    CorruptEntry(String),
+
+    #[error("Vector has {actual} dimensions, index expects {expected}")]
+    VectorDimensions { expected: usize, actual: usize },
+
     #[error("Index operation failed: {0}")]
     OperationFailed(String),
 }
 
+/// Key a unique index already holds for another document
+///
+/// Fields missing from the document are reported as null.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateKey {
+    /// Indexed fields and their values, in key order
+    pub fields: Vec<(String, Value)>,
+}
+
+impl DuplicateKey {
+    /// Pair each indexed field with its value in the key
+    pub fn new(fields: &[String], values: &[IndexValue]) -> Self {
+        Self {
+            fields: fields
+                .iter()
+                .zip(values)
+                .map(|(field, value)| (field.clone(), value.to_value().unwrap_or(Value::Null)))
+                .collect(),
+        }
+    }
+
+    /// The key as an object of field names to values
+    pub fn to_value(&self) -> Value {
+        Value::Object(self.fields.iter().cloned().collect())
+    }
+}
+
+impl std::fmt::Display for DuplicateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(field, value)| format!("{}: {:?}", field, value))
+            .collect();
+        write!(f, "{{{}}}", fields.join(", "))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::document::ObjectId;
     use chrono::Utc;
 
     #[test]
@@ -520,6 +1487,111 @@ mod tests {
         assert!(matches!(result.unwrap_err(), IndexError::UniqueConstraintViolation { .. }));
     }
 
+    #[test]
+    fn test_unique_violation_reports_key_and_sparse_skips_missing() {
+        let fields = vec!["email".to_string(), "tenant".to_string()];
+        let unique = BTreeIndex::new("email_tenant".to_string(), fields.clone(), true, false);
+        let sparse = BTreeIndex::new("email_tenant".to_string(), fields, true, true);
+
+        let mut entry = IndexEntry::new();
+        entry.add_field("email".to_string(), Value::String("a@example.com".to_string()));
+        unique.insert(DocumentId::new(), entry.clone()).unwrap();
+        match unique.insert(DocumentId::new(), entry.clone()) {
+            Err(IndexError::UniqueConstraintViolation { index, key }) => {
+                assert_eq!(index, "email_tenant");
+                assert_eq!(
+                    key.fields,
+                    vec![
+                        ("email".to_string(), Value::String("a@example.com".to_string())),
+                        ("tenant".to_string(), Value::Null),
+                    ]
+                );
+                assert_eq!(key.to_string(), "{email: String(\"a@example.com\"), tenant: Null}");
+            }
+            other => panic!("expected a unique violation, got {:?}", other),
+        }
+
+        // A sparse unique index ignores documents lacking part of the key
+        sparse.insert(DocumentId::new(), entry.clone()).unwrap();
+        sparse.insert(DocumentId::new(), entry.clone()).unwrap();
+        assert_eq!(sparse.entry_count(), 0);
+        entry.add_field("tenant".to_string(), Value::Int64(1));
+        sparse.insert(DocumentId::new(), entry.clone()).unwrap();
+        assert!(sparse.insert(DocumentId::new(), entry).is_err());
+    }
+
+    #[test]
+    fn test_multikey_entries_per_array_element() {
+        let index = BTreeIndex::new("tags".to_string(), vec!["tags".to_string()], true, false);
+        let tags = |values: &[&str]| {
+            let mut doc = Document::new();
+            doc.insert("tags".to_string(), Value::Array(values.iter().map(|v| Value::from(*v)).collect()));
+            doc
+        };
+        let key = |value: &str| IndexKey::from_values(vec![Value::from(value)]).unwrap();
+
+        let first = tags(&["red", "sale", "red"]);
+        index.insert(first.id, IndexEntry::from_document(&first, index.fields())).unwrap();
+        assert!(index.is_multikey());
+        assert_eq!(index.key_count(), 2);
+        assert_eq!(index.find_exact(&key("sale")).unwrap(), vec![first.id]);
+
+        // A unique clash on one element leaves none of the others indexed
+        let second = tags(&["blue", "sale"]);
+        let err = index.insert(second.id, IndexEntry::from_document(&second, index.fields())).unwrap_err();
+        assert!(matches!(err, IndexError::UniqueConstraintViolation { .. }));
+        assert!(index.find_exact(&key("blue")).unwrap().is_empty());
+
+        assert!(index.remove(first.id, IndexEntry::from_document(&first, index.fields())).unwrap());
+        assert!(index.is_empty());
+
+        // Paths through arrays of subdocuments reach every element
+        let mut order = Document::new();
+        let item = |sku: &str| Value::Object(BTreeMap::from([("sku".to_string(), Value::from(sku))]));
+        order.insert("items".to_string(), Value::Array(vec![item("a1")]));
+        order.insert("tags".to_string(), Value::Array(vec!["x".into(), "y".into()]));
+        let fields = ["items.sku".to_string(), "tags".to_string()];
+        let entry = IndexEntry::from_document(&order, &fields[..1]);
+        assert!(entry.is_multikey(&fields[..1]));
+        assert_eq!(IndexKey::keys_from_entry(&entry, &fields[..1]).unwrap(), vec![key("a1")]);
+        let entry = IndexEntry::from_document(&order, &fields);
+        assert!(matches!(
+            IndexKey::keys_from_entry(&entry, &fields),
+            Err(IndexError::ParallelArrays(arrays)) if arrays == fields
+        ));
+    }
+
+    #[test]
+    fn test_key_form_collates_and_hashes() {
+        let collated = KeyForm::new(false, Some(Collation::new("en").with_strength(2)));
+        let index = BTreeIndex::new("email".to_string(), vec!["email".to_string()], true, false)
+            .with_key_form(collated);
+        let email = |value: Value| {
+            let mut doc = Document::new();
+            doc.insert("email".to_string(), value);
+            doc
+        };
+
+        // Elements equal under the collation share one key
+        let first = email(Value::Array(vec!["Ann@Example.com".into(), "ann@example.com".into()]));
+        index.insert(first.id, IndexEntry::from_document(&first, index.fields())).unwrap();
+        assert_eq!(index.entry_count(), 1);
+        let stored = IndexKey::from_values(vec![Value::from("ann@example.com")]).unwrap();
+        assert_eq!(index.find_exact(&stored).unwrap(), vec![first.id]);
+
+        let second = email(Value::from("ANN@example.COM"));
+        let err = index.insert(second.id, IndexEntry::from_document(&second, index.fields())).unwrap_err();
+        assert!(matches!(err, IndexError::UniqueConstraintViolation { .. }));
+
+        // Equal numbers hash alike, null stays null, and nothing else is kept
+        let hashed = KeyForm::new(true, None);
+        assert_eq!(hashed.apply(IndexValue::Int32(5)), hashed.apply(IndexValue::Float(OrderedFloat::new(5.0))));
+        assert_ne!(hashed.apply(IndexValue::Int32(5)), hashed.apply(IndexValue::Int32(6)));
+        assert_eq!(hashed.apply(IndexValue::Null), IndexValue::Null);
+        assert!(matches!(hashed.apply(IndexValue::String("ann".to_string())), IndexValue::Int(_)));
+        assert!(KeyForm::new(false, Some(Collation::new("simple"))).is_plain());
+    }
+
     #[test]
     fn test_btree_sparse_index() {
         let index = BTreeIndex::new(
@@ -605,4 +1677,151 @@ mod tests {
         let results = index.find_exact(&key).unwrap();
         assert_eq!(results, vec![doc_id]);
     }
-}
\ No newline at end of file
+
+    fn insert_pair(index: &BTreeIndex, category: &str, priority: Value) -> DocumentId {
+        let doc_id = DocumentId::new();
+        let mut entry = IndexEntry::new();
+        entry.add_field("category".to_string(), Value::String(category.to_string()));
+        entry.add_field("priority".to_string(), priority);
+        index.insert(doc_id, entry).unwrap();
+        doc_id
+    }
+
+    #[test]
+    fn test_numeric_values_share_ordering() {
+        assert!(IndexValue::Int(2) < IndexValue::Float(OrderedFloat(2.5)));
+        assert!(IndexValue::Float(OrderedFloat(2.5)) < IndexValue::Int(3));
+        assert_eq!(IndexValue::Int(3), IndexValue::Float(OrderedFloat(3.0)));
+        assert!(IndexValue::Float(OrderedFloat(1e9)) < IndexValue::String(String::new()));
+    }
+
+    #[test]
+    fn test_prefix_range_on_compound_index() {
+        let index = BTreeIndex::new(
+            "idx_category_priority".to_string(),
+            vec!["category".to_string(), "priority".to_string()],
+            false,
+            false,
+        );
+
+        let bug1 = insert_pair(&index, "bug", Value::Int32(1));
+        let bug2 = insert_pair(&index, "bug", Value::Float64(2.5));
+        let bug3 = insert_pair(&index, "bug", Value::Int64(3));
+        insert_pair(&index, "feature", Value::Int32(2));
+        insert_pair(&index, "alpha", Value::Int32(2));
+
+        let bug = [Value::String("bug".to_string())];
+        let all_bugs = index.find_prefix_range(&bug, None, None).unwrap();
+        assert_eq!(all_bugs, vec![bug1, bug2, bug3]);
+
+        let bounded = index
+            .find_prefix_range(&bug, Some((&Value::Int32(1), false)), Some((&Value::Int32(3), true)))
+            .unwrap();
+        assert_eq!(bounded, vec![bug2, bug3]);
+
+        let missing = [Value::String("chore".to_string())];
+        assert!(index.find_prefix_range(&missing, None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prefix_range_honours_descending_field() {
+        let index = BTreeIndex::new(
+            "idx_category_priority".to_string(),
+            vec!["category".to_string(), "priority".to_string()],
+            false,
+            false,
+        )
+        .with_directions(vec![false, true]);
+
+        let low = insert_pair(&index, "bug", Value::Int32(1));
+        let mid = insert_pair(&index, "bug", Value::Int32(5));
+        let high = insert_pair(&index, "bug", Value::Int32(9));
+
+        let bug = [Value::String("bug".to_string())];
+        assert_eq!(index.find_prefix_range(&bug, None, None).unwrap(), vec![high, mid, low]);
+
+        let bounded = index
+            .find_prefix_range(&bug, Some((&Value::Int32(1), true)), Some((&Value::Int32(9), false)))
+            .unwrap();
+        assert_eq!(bounded, vec![mid, low]);
+    }
+
+    #[test]
+    fn test_prefix_range_rejects_bound_past_last_field() {
+        let index = BTreeIndex::new(
+            "idx_name".to_string(),
+            vec!["name".to_string()],
+            false,
+            false,
+        );
+
+        let result = index.find_prefix_range(
+            &[Value::String("a".to_string())],
+            Some((&Value::Int32(1), true)),
+            None,
+        );
+        assert!(matches!(result, Err(IndexError::OperationFailed(_))));
+    }
+
+    #[test]
+    fn test_range_with_inverted_bounds_is_empty() {
+        let index = BTreeIndex::new(
+            "age_index".to_string(),
+            vec!["age".to_string()],
+            false,
+            false,
+        );
+
+        let mut entry = IndexEntry::new();
+        entry.add_field("age".to_string(), Value::Int32(30));
+        index.insert(DocumentId::new(), entry).unwrap();
+
+        let high = IndexKey::from_values(vec![Value::Int32(40)]).unwrap();
+        let low = IndexKey::from_values(vec![Value::Int32(20)]).unwrap();
+        assert!(index.find_range(Some(&high), Some(&low), true, true).unwrap().is_empty());
+        assert!(index.find_range(Some(&low), Some(&low), false, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_distribution_follows_inserts_and_removes() {
+        let index = BTreeIndex::new(
+            "idx_category_priority".to_string(),
+            vec!["category".to_string(), "priority".to_string()],
+            false,
+            false,
+        )
+        .with_directions(vec![false, true]);
+
+        let pair = |category: &str, priority: i32| {
+            let mut entry = IndexEntry::new();
+            entry.add_field("category".to_string(), Value::String(category.to_string()));
+            entry.add_field("priority".to_string(), Value::Int32(priority));
+            entry
+        };
+
+        let mut ids = Vec::new();
+        for (category, priority) in [("bug", 1), ("bug", 2), ("bug", 2), ("feature", 1), ("alpha", 3)] {
+            let doc_id = DocumentId::new();
+            index.insert(doc_id, pair(category, priority)).unwrap();
+            ids.push(doc_id);
+        }
+        index.remove(ids[1], pair("bug", 2)).unwrap();
+        index.remove(ids[3], pair("feature", 1)).unwrap();
+
+        let incremental = index.key_distribution();
+        assert_eq!(incremental.entries, 3);
+        assert_eq!(incremental.distinct_prefixes, vec![2, 3]);
+        assert_eq!(incremental.modifications, 7);
+
+        let analyzed = index.analyze();
+        assert_eq!(analyzed.entries, incremental.entries);
+        assert_eq!(analyzed.distinct_prefixes, incremental.distinct_prefixes);
+        assert_eq!(analyzed.modifications, 0);
+
+        let bug = IndexValue::String("bug".to_string());
+        assert_eq!(analyzed.estimate_rows(std::slice::from_ref(&bug), None, None), 2.0);
+
+        index.clear();
+        assert_eq!(index.key_distribution().entries, 0);
+    }
+}
@@ -1,10 +1,18 @@
 //! Index builder for background index construction
 //!
-//! Builds indexes without blocking normal operations
+//! Builds indexes without blocking normal operations. A build streams the
+//! collection in batches supplied by the caller, while writes made during
+//! the build are captured in a [`SideWriteBuffer`] and applied once the
+//! scan completes. Each build reports its progress through an
+//! [`IndexBuild`] and can be cancelled between batches.
 
 use super::btree::{BTreeIndex, IndexEntry, IndexError};
-use crate::document::Document;
-use std::sync::Arc;
+use crate::document::{Document, DocumentId, Value};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use tokio::time::{sleep, Duration};
 
 /// Index builder for background operations
@@ -50,8 +58,10 @@ impl IndexBuilder {
         // Process documents in batches
         for batch in documents.chunks(self.batch_size) {
             for document in batch {
-                let entry = self.create_index_entry(document, index.fields())?;
-                index.insert(document.id, entry)?;
+                if index.covers(document)? {
+                    let entry = self.create_index_entry(document, index.fields())?;
+                    index.insert(document.id, entry)?;
+                }
                 processed += 1;
             }
 
@@ -71,6 +81,7 @@ impl IndexBuilder {
                 sleep(Duration::from_millis(self.batch_delay_ms)).await;
             }
         }
+        index.analyze();
 
         log::info!(
             "Completed background index build for '{}': {} documents processed",
@@ -81,6 +92,79 @@ impl IndexBuilder {
         Ok(())
     }
 
+    /// Stream batches from `next_batch` into `index` until it returns none
+    ///
+    /// `next_batch` is asked for up to the builder's batch size at a time.
+    /// Returns `false` if the build was cancelled before the scan finished.
+    pub async fn run<F>(
+        &self,
+        index: &BTreeIndex,
+        build: &IndexBuild,
+        mut next_batch: F,
+    ) -> Result<bool, IndexError>
+    where
+        F: FnMut(usize) -> Result<Vec<Document>, IndexError>,
+    {
+        loop {
+            if build.is_cancelled() {
+                return Ok(false);
+            }
+
+            let batch = next_batch(self.batch_size)?;
+            if batch.is_empty() {
+                return Ok(true);
+            }
+            self.index_batch(index, build, &batch)?;
+
+            // Small delay to avoid blocking other operations
+            if self.batch_delay_ms > 0 {
+                sleep(Duration::from_millis(self.batch_delay_ms)).await;
+            }
+        }
+    }
+
+    /// Like [`run`](Self::run), in the calling thread and without pausing
+    /// between batches
+    pub fn run_blocking<F>(
+        &self,
+        index: &BTreeIndex,
+        build: &IndexBuild,
+        mut next_batch: F,
+    ) -> Result<bool, IndexError>
+    where
+        F: FnMut(usize) -> Result<Vec<Document>, IndexError>,
+    {
+        loop {
+            if build.is_cancelled() {
+                return Ok(false);
+            }
+
+            let batch = next_batch(self.batch_size)?;
+            if batch.is_empty() {
+                return Ok(true);
+            }
+            self.index_batch(index, build, &batch)?;
+        }
+    }
+
+    /// Insert one batch of scanned documents and record the progress
+    pub fn index_batch(
+        &self,
+        index: &BTreeIndex,
+        build: &IndexBuild,
+        documents: &[Document],
+    ) -> Result<(), IndexError> {
+        // Documents outside a partial filter count as processed
+        for document in documents {
+            if index.covers(document)? {
+                let entry = self.create_index_entry(document, index.fields())?;
+                index.insert(document.id, entry)?;
+            }
+        }
+        build.record_documents(documents.len() as u64);
+        Ok(())
+    }
+
     /// Rebuild an existing index
     pub async fn rebuild_index(
         &self,
@@ -98,18 +182,8 @@ impl IndexBuilder {
 
     /// Create index entry from document
     fn create_index_entry(&self, document: &Document, fields: &[String]) -> Result<IndexEntry, IndexError> {
-        let mut entry = IndexEntry::new();
-        
-        for field in fields {
-            if let Some(value) = document.get_by_path(field) {
-                entry.add_field(field.clone(), value.clone());
-            } else {
-                // Field not present - use null
-                entry.add_field(field.clone(), crate::document::Value::Null);
-            }
-        }
-        
-        Ok(entry)
+        // The same entries writes through the index manager produce
+        Ok(IndexEntry::from_document(document, fields))
     }
 
     /// Estimate build time for an index
@@ -129,6 +203,231 @@ impl Default for IndexBuilder {
     }
 }
 
+/// Lifecycle of an index build
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexBuildState {
+    /// Scanning the collection or applying side writes
+    Running,
+    /// The index is built and in use
+    Completed,
+    /// The build stopped with an error and the index was dropped
+    Failed,
+    /// The build was cancelled and the index was dropped
+    Cancelled,
+}
+
+impl IndexBuildState {
+    /// Name used in build reports
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexBuildState::Running => "running",
+            IndexBuildState::Completed => "completed",
+            IndexBuildState::Failed => "failed",
+            IndexBuildState::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Progress record of one index build
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexBuildProgress {
+    /// Build id returned to the client
+    pub id: String,
+    /// Collection being indexed
+    pub collection: String,
+    /// Name of the index being built
+    pub index: String,
+    /// Current state
+    pub state: IndexBuildState,
+    /// Documents scanned so far, including those scanned before a restart
+    pub documents_processed: u64,
+    /// Documents in the collection when the build started
+    pub documents_total: u64,
+    /// Writes captured during the build and applied at the end
+    pub side_writes_applied: u64,
+    /// When the build started
+    pub started_at: DateTime<Utc>,
+    /// When the build completed, failed or was cancelled
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Why the build failed
+    pub error: Option<String>,
+}
+
+impl IndexBuildProgress {
+    /// Start a record for a new build
+    pub fn new(id: String, collection: String, index: String, documents_total: u64) -> Self {
+        Self {
+            id,
+            collection,
+            index,
+            state: IndexBuildState::Running,
+            documents_processed: 0,
+            documents_total,
+            side_writes_applied: 0,
+            started_at: Utc::now(),
+            finished_at: None,
+            error: None,
+        }
+    }
+
+    /// Documents still to scan
+    ///
+    /// Documents inserted during the build are counted as they are scanned,
+    /// so this never goes below zero.
+    pub fn documents_remaining(&self) -> u64 {
+        self.documents_total.saturating_sub(self.documents_processed)
+    }
+
+    /// Share of the scan done, from 0 to 100
+    pub fn percent_done(&self) -> f64 {
+        match self.state {
+            IndexBuildState::Completed => 100.0,
+            _ if self.documents_total == 0 => 0.0,
+            _ => (self.documents_processed as f64 * 100.0 / self.documents_total as f64).min(99.9),
+        }
+    }
+
+    /// Report the build, with `eta` the estimated time left while running
+    pub fn to_value(&self, eta: Option<Duration>) -> Value {
+        let mut value = BTreeMap::from([
+            ("id".to_string(), Value::String(self.id.clone())),
+            ("collection".to_string(), Value::String(self.collection.clone())),
+            ("index".to_string(), Value::String(self.index.clone())),
+            ("state".to_string(), Value::String(self.state.as_str().to_string())),
+            ("documents_processed".to_string(), Value::Int64(self.documents_processed as i64)),
+            ("documents_total".to_string(), Value::Int64(self.documents_total as i64)),
+            ("side_writes_applied".to_string(), Value::Int64(self.side_writes_applied as i64)),
+            ("percent_done".to_string(), Value::Float64(self.percent_done())),
+            ("started_at".to_string(), Value::DateTime(self.started_at)),
+        ]);
+        if let Some(eta) = eta {
+            let millis = i64::try_from(eta.as_millis()).unwrap_or(i64::MAX);
+            value.insert("eta_millis".to_string(), Value::Int64(millis));
+        }
+        if let Some(finished_at) = self.finished_at {
+            value.insert("finished_at".to_string(), Value::DateTime(finished_at));
+        }
+        if let Some(error) = &self.error {
+            value.insert("error".to_string(), Value::String(error.clone()));
+        }
+        Value::Object(value)
+    }
+}
+
+/// Handle to a build in progress, shared by the build task and its observers
+#[derive(Debug)]
+pub struct IndexBuild {
+    progress: RwLock<IndexBuildProgress>,
+    cancelled: AtomicBool,
+    claimed: AtomicBool,
+}
+
+impl IndexBuild {
+    /// Track a build from its progress record
+    pub fn new(progress: IndexBuildProgress) -> Self {
+        Self {
+            progress: RwLock::new(progress),
+            cancelled: AtomicBool::new(false),
+            claimed: AtomicBool::new(false),
+        }
+    }
+
+    /// Claim the build for a worker, returning `false` if one already runs it
+    pub fn claim(&self) -> bool {
+        !self.claimed.swap(true, Ordering::Relaxed)
+    }
+
+    /// Build id
+    pub fn id(&self) -> String {
+        self.progress.read().unwrap().id.clone()
+    }
+
+    /// Snapshot of the progress record
+    pub fn progress(&self) -> IndexBuildProgress {
+        self.progress.read().unwrap().clone()
+    }
+
+    /// Ask the build to stop at the next batch boundary
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether cancellation was requested
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Count scanned documents
+    pub fn record_documents(&self, count: u64) {
+        self.progress.write().unwrap().documents_processed += count;
+    }
+
+    /// Mark the build finished
+    pub fn finish(&self, state: IndexBuildState, side_writes_applied: u64, error: Option<String>) {
+        let mut progress = self.progress.write().unwrap();
+        progress.state = state;
+        progress.side_writes_applied = side_writes_applied;
+        progress.finished_at = Some(Utc::now());
+        progress.error = error;
+    }
+}
+
+/// Write made to a collection while one of its indexes is building
+#[derive(Debug, Clone)]
+pub enum SideWrite {
+    /// A document version gained an entry
+    Insert(DocumentId, IndexEntry),
+    /// A document version lost its entry
+    Remove(DocumentId, IndexEntry),
+}
+
+/// Writes captured during a build, applied in order once the scan is done
+///
+/// Applying a write the scan already reflected is harmless: inserts of an
+/// entry that is present and removals of one that is absent change nothing.
+#[derive(Debug, Default)]
+pub struct SideWriteBuffer {
+    writes: Mutex<Vec<SideWrite>>,
+}
+
+impl SideWriteBuffer {
+    /// Create an empty buffer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capture a write
+    pub fn record(&self, write: SideWrite) {
+        self.writes.lock().unwrap().push(write);
+    }
+
+    /// Number of captured writes not yet applied
+    pub fn len(&self) -> usize {
+        self.writes.lock().unwrap().len()
+    }
+
+    /// Whether no writes are waiting
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Apply and clear the captured writes, returning how many were applied
+    pub fn drain_into(&self, index: &BTreeIndex) -> Result<u64, IndexError> {
+        let writes = std::mem::take(&mut *self.writes.lock().unwrap());
+        let applied = writes.len() as u64;
+        for write in writes {
+            match write {
+                SideWrite::Insert(doc_id, entry) => index.insert(doc_id, entry)?,
+                SideWrite::Remove(doc_id, entry) => {
+                    index.remove(doc_id, entry)?;
+                }
+            }
+        }
+        Ok(applied)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -230,6 +529,91 @@ mod tests {
         let entry = builder.create_index_entry(&doc, &["name".to_string(), "age".to_string()]).unwrap();
         
         assert_eq!(entry.get_field("name"), Some(&Value::String("John".to_string())));
-        assert_eq!(entry.get_field("age"), Some(&Value::Null));
+        assert_eq!(entry.get_field("age"), None);
+    }
+
+    fn name_index() -> BTreeIndex {
+        BTreeIndex::new("idx_name".to_string(), vec!["name".to_string()], false, false)
+    }
+
+    #[tokio::test]
+    async fn test_run_streams_batches_and_reports_progress() {
+        let builder = IndexBuilder::with_settings(10, 0);
+        let index = name_index();
+        let build = IndexBuild::new(IndexBuildProgress::new(
+            "b1".to_string(),
+            "users".to_string(),
+            "idx_name".to_string(),
+            25,
+        ));
+
+        let mut remaining = create_test_documents(25);
+        let mut requested = Vec::new();
+        let completed = builder
+            .run(&index, &build, |limit| {
+                requested.push(limit);
+                let take = remaining.len().min(limit);
+                Ok(remaining.drain(..take).collect())
+            })
+            .await
+            .unwrap();
+
+        assert!(completed);
+        assert_eq!(requested, vec![10, 10, 10, 10]);
+        assert_eq!(index.key_count(), 25);
+        let progress = build.progress();
+        assert_eq!(progress.documents_processed, 25);
+        assert_eq!(progress.documents_remaining(), 0);
+        assert!(progress.percent_done() < 100.0);
+
+        build.finish(IndexBuildState::Completed, 0, None);
+        assert_eq!(build.progress().percent_done(), 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_when_cancelled_or_failing() {
+        let builder = IndexBuilder::with_settings(10, 0);
+        let index = name_index();
+        let build = IndexBuild::new(IndexBuildProgress::new(
+            "b1".to_string(),
+            "users".to_string(),
+            "idx_name".to_string(),
+            0,
+        ));
+
+        build.cancel();
+        let completed = builder
+            .run(&index, &build, |_| Ok(create_test_documents(1)))
+            .await
+            .unwrap();
+        assert!(!completed);
+        assert!(index.is_empty());
+
+        let failing = IndexBuild::new(build.progress());
+        let result = builder
+            .run(&index, &failing, |_| Err(IndexError::OperationFailed("scan failed".to_string())))
+            .await;
+        assert!(matches!(result, Err(IndexError::OperationFailed(_))));
+    }
+
+    #[test]
+    fn test_side_writes_apply_in_order_and_idempotently() {
+        let builder = IndexBuilder::new();
+        let index = name_index();
+        let docs = create_test_documents(2);
+        let entry = |doc: &Document| builder.create_index_entry(doc, index.fields()).unwrap();
+
+        // The scan saw the first document; the buffer also captured its insert
+        index.insert(docs[0].id, entry(&docs[0])).unwrap();
+        let buffer = SideWriteBuffer::new();
+        buffer.record(SideWrite::Insert(docs[0].id, entry(&docs[0])));
+        buffer.record(SideWrite::Insert(docs[1].id, entry(&docs[1])));
+        buffer.record(SideWrite::Remove(docs[1].id, entry(&docs[1])));
+        assert_eq!(buffer.len(), 3);
+
+        assert_eq!(buffer.drain_into(&index).unwrap(), 3);
+        assert!(buffer.is_empty());
+        assert_eq!(index.key_count(), 1);
+        assert_eq!(index.statistics().total_entries, 1);
     }
 }
\ No newline at end of file
@@ -0,0 +1,464 @@
+//! Order-preserving binary encoding of index entries
+//!
+//! Persisted index entries are stored as plain byte strings, so the
+//! encoding must sort bytewise in the same order as [`IndexKey`] compares
+//! in memory. Each value starts with its type bracket, followed by a
+//! payload that sorts within the bracket:
+//!
+//! - numbers: the value as an order-flipped `f64`, a subtype byte, and
+//!   for integers the exact `i64`, so large integers keep their order;
+//!   decimals follow with their sign, leading digit position and
+//!   significant digits, then their exponent
+//! - strings and binary: the bytes with `0x00` escaped as `0x00 0xFF`,
+//!   terminated by `0x00 0x00`
+//! - dates: order-flipped seconds and big-endian nanoseconds
+//! - UUIDs: the 16 bytes; timestamps: big-endian time and increment
+//!
+//! Fields stored in descending order have every byte of their encoding
+//! complemented. An entry is the encoded values followed by the 16-byte
+//! document ID.
+//!
+//! [`IndexKey`]: super::btree::IndexKey
+
+use super::btree::{IndexEntry, IndexError, IndexKey, IndexValue, OrderedFloat};
+use crate::document::{Decimal128, Document, DocumentId};
+
+/// Version of the entry encoding; persisted entries of another version
+/// are rebuilt rather than decoded
+pub const INDEX_FORMAT_VERSION: u32 = 1;
+
+const TAG_MISSING: u8 = 0;
+const TAG_NULL: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_BINARY: u8 = 5;
+const TAG_OBJECT_ID: u8 = 6;
+const TAG_DATE_TIME: u8 = 7;
+const TAG_UUID: u8 = 8;
+const TAG_TIMESTAMP: u8 = 9;
+
+const NUMBER_FLOAT: u8 = 0;
+const NUMBER_INT32: u8 = 1;
+const NUMBER_INT: u8 = 2;
+const NUMBER_DECIMAL: u8 = 3;
+
+const DECIMAL_NEGATIVE: u8 = 0;
+const DECIMAL_ZERO: u8 = 1;
+const DECIMAL_POSITIVE: u8 = 2;
+
+const SIGN_BIT: u64 = 1 << 63;
+const EXPONENT_SIGN_BIT: u32 = 1 << 31;
+
+/// Keys an index over `fields` stores for a document, as value lists
+///
+/// Absent fields are stored as [`IndexValue::Missing`]. A field holding an
+/// array yields one key per distinct element.
+pub fn key_values(document: &Document, fields: &[String]) -> Result<Vec<Vec<IndexValue>>, IndexError> {
+    let entry = IndexEntry::from_document(document, fields);
+    Ok(IndexKey::keys_from_entry(&entry, fields)?
+        .into_iter()
+        .map(|key| key.values().to_vec())
+        .collect())
+}
+
+/// Encode an index entry for persistent storage
+///
+/// `descending` holds per-field direction flags; fields beyond its
+/// length are ascending.
+pub fn encode_entry(values: &[IndexValue], descending: &[bool], doc_id: DocumentId) -> Vec<u8> {
+    let mut out = Vec::with_capacity(values.len() * 10 + 16);
+    for (position, value) in values.iter().enumerate() {
+        let start = out.len();
+        encode_value(value, &mut out);
+        if descending.get(position).copied().unwrap_or(false) {
+            for byte in &mut out[start..] {
+                *byte = !*byte;
+            }
+        }
+    }
+    out.extend_from_slice(&doc_id.to_bytes());
+    out
+}
+
+/// Decode an entry written by [`encode_entry`] for an index of `field_count` fields
+pub fn decode_entry(
+    bytes: &[u8],
+    field_count: usize,
+    descending: &[bool],
+) -> Result<(Vec<IndexValue>, DocumentId), IndexError> {
+    let mut reader = Reader { bytes, position: 0, mask: 0 };
+    let mut values = Vec::with_capacity(field_count);
+    for position in 0..field_count {
+        reader.mask = if descending.get(position).copied().unwrap_or(false) { 0xFF } else { 0 };
+        values.push(reader.value()?);
+    }
+
+    reader.mask = 0;
+    let id = reader.array::<16>()?;
+    if reader.position != bytes.len() {
+        return Err(corrupt("trailing bytes after document ID"));
+    }
+    Ok((values, DocumentId::from_bytes(id)))
+}
+
+/// Checksum contribution of one encoded entry
+///
+/// An index's checksum is the wrapping sum of its entries' checksums, so
+/// it can be maintained incrementally as entries come and go.
+pub fn entry_checksum(entry: &[u8]) -> u64 {
+    // FNV-1a
+    entry.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn encode_value(value: &IndexValue, out: &mut Vec<u8>) {
+    match value {
+        IndexValue::Missing => out.push(TAG_MISSING),
+        IndexValue::Null => out.push(TAG_NULL),
+        IndexValue::Bool(b) => out.extend_from_slice(&[TAG_BOOL, *b as u8]),
+        IndexValue::Int32(i) => encode_number(*i as f64, NUMBER_INT32, Some(*i as i64), out),
+        IndexValue::Int(i) => encode_number(*i as f64, NUMBER_INT, Some(*i), out),
+        IndexValue::Float(f) => encode_number(f.get(), NUMBER_FLOAT, None, out),
+        IndexValue::Decimal128(d) => {
+            encode_number(d.to_f64(), NUMBER_DECIMAL, None, out);
+            encode_decimal(d, out);
+        }
+        IndexValue::String(s) => {
+            out.push(TAG_STRING);
+            encode_bytes(s.as_bytes(), out);
+        }
+        IndexValue::Binary(b) => {
+            out.push(TAG_BINARY);
+            encode_bytes(b, out);
+        }
+        IndexValue::ObjectId(oid) => {
+            out.push(TAG_OBJECT_ID);
+            out.extend_from_slice(oid);
+        }
+        IndexValue::DateTime(secs, nanos) => {
+            out.push(TAG_DATE_TIME);
+            out.extend_from_slice(&((*secs as u64) ^ SIGN_BIT).to_be_bytes());
+            out.extend_from_slice(&nanos.to_be_bytes());
+        }
+        IndexValue::Uuid(uuid) => {
+            out.push(TAG_UUID);
+            out.extend_from_slice(uuid);
+        }
+        IndexValue::Timestamp(time, increment) => {
+            out.push(TAG_TIMESTAMP);
+            out.extend_from_slice(&time.to_be_bytes());
+            out.extend_from_slice(&increment.to_be_bytes());
+        }
+    }
+}
+
+/// Exact part of a decimal, ordering decimals that share a float
+///
+/// Magnitudes order by the position of their leading digit and then by
+/// their digits without trailing zeros; negative magnitudes are
+/// complemented. The exponent comes last so the value rebuilds exactly.
+fn encode_decimal(value: &Decimal128, out: &mut Vec<u8>) {
+    let coefficient = value.coefficient().unsigned_abs();
+    if coefficient == 0 {
+        out.push(DECIMAL_ZERO);
+    } else {
+        let mask = if value.is_negative() { 0xFF } else { 0 };
+        out.push(if value.is_negative() { DECIMAL_NEGATIVE } else { DECIMAL_POSITIVE });
+
+        let digits = coefficient.to_string();
+        let adjusted = value.exponent() + digits.len() as i32 - 1;
+        let magnitude = ((adjusted as u32) ^ EXPONENT_SIGN_BIT).to_be_bytes();
+        let significant = digits.trim_end_matches('0').bytes().chain([0]);
+        out.extend(magnitude.into_iter().chain(significant).map(|byte| byte ^ mask));
+    }
+    out.extend_from_slice(&((value.exponent() as u32) ^ EXPONENT_SIGN_BIT).to_be_bytes());
+}
+
+fn encode_number(value: f64, subtype: u8, exact: Option<i64>, out: &mut Vec<u8>) {
+    // 0.0 and -0.0 compare equal, so they must encode alike
+    let value = if value == 0.0 { 0.0 } else { value };
+    let bits = value.to_bits();
+    let ordered = if bits & SIGN_BIT != 0 { !bits } else { bits ^ SIGN_BIT };
+
+    out.push(TAG_NUMBER);
+    out.extend_from_slice(&ordered.to_be_bytes());
+    out.push(subtype);
+    if let Some(exact) = exact {
+        out.extend_from_slice(&((exact as u64) ^ SIGN_BIT).to_be_bytes());
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for byte in bytes {
+        out.push(*byte);
+        if *byte == 0 {
+            out.push(0xFF);
+        }
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+fn corrupt(reason: &str) -> IndexError {
+    IndexError::CorruptEntry(reason.to_string())
+}
+
+/// Cursor over an encoded entry, un-complementing descending fields
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    mask: u8,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, IndexError> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or_else(|| corrupt("entry ends mid-value"))?;
+        self.position += 1;
+        Ok(byte ^ self.mask)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], IndexError> {
+        let mut out = [0u8; N];
+        for byte in &mut out {
+            *byte = self.byte()?;
+        }
+        Ok(out)
+    }
+
+    fn int(&mut self) -> Result<i64, IndexError> {
+        Ok((u64::from_be_bytes(self.array()?) ^ SIGN_BIT) as i64)
+    }
+
+    fn exponent(&mut self, mask: u8) -> Result<i32, IndexError> {
+        let bytes = self.array::<4>()?.map(|byte| byte ^ mask);
+        Ok((u32::from_be_bytes(bytes) ^ EXPONENT_SIGN_BIT) as i32)
+    }
+
+    fn decimal(&mut self) -> Result<Decimal128, IndexError> {
+        let mut coefficient = 0i128;
+        let sign = self.byte()?;
+        if sign != DECIMAL_ZERO {
+            let mask = match sign {
+                DECIMAL_NEGATIVE => 0xFF,
+                DECIMAL_POSITIVE => 0,
+                _ => return Err(corrupt("unknown decimal sign")),
+            };
+            let adjusted = self.exponent(mask)?;
+            let mut digits = 0;
+            loop {
+                match self.byte()? ^ mask {
+                    0 => break,
+                    digit @ b'0'..=b'9' if digits < 34 => {
+                        coefficient = coefficient * 10 + (digit - b'0') as i128;
+                        digits += 1;
+                    }
+                    _ => return Err(corrupt("invalid decimal digits")),
+                }
+            }
+            let exponent = self.exponent(0)?;
+            let padding = adjusted - (digits - 1) - exponent;
+            if !(0..=34 - digits).contains(&padding) {
+                return Err(corrupt("decimal exponent out of range"));
+            }
+            coefficient *= 10i128.pow(padding as u32);
+            if mask != 0 {
+                coefficient = -coefficient;
+            }
+            return Decimal128::new(coefficient, exponent).map_err(|_| corrupt("decimal out of range"));
+        }
+        Decimal128::new(0, self.exponent(0)?).map_err(|_| corrupt("decimal out of range"))
+    }
+
+    fn escaped(&mut self) -> Result<Vec<u8>, IndexError> {
+        let mut out = Vec::new();
+        loop {
+            match self.byte()? {
+                0 => match self.byte()? {
+                    0 => return Ok(out),
+                    0xFF => out.push(0),
+                    _ => return Err(corrupt("invalid escape in string")),
+                },
+                byte => out.push(byte),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<IndexValue, IndexError> {
+        Ok(match self.byte()? {
+            TAG_MISSING => IndexValue::Missing,
+            TAG_NULL => IndexValue::Null,
+            TAG_BOOL => IndexValue::Bool(self.byte()? != 0),
+            TAG_NUMBER => {
+                let ordered = u64::from_be_bytes(self.array()?);
+                let bits = if ordered & SIGN_BIT != 0 { ordered ^ SIGN_BIT } else { !ordered };
+                match self.byte()? {
+                    NUMBER_FLOAT => IndexValue::Float(OrderedFloat::new(f64::from_bits(bits))),
+                    NUMBER_INT32 => IndexValue::Int32(
+                        i32::try_from(self.int()?).map_err(|_| corrupt("int32 out of range"))?,
+                    ),
+                    NUMBER_INT => IndexValue::Int(self.int()?),
+                    NUMBER_DECIMAL => IndexValue::Decimal128(self.decimal()?),
+                    _ => return Err(corrupt("unknown number subtype")),
+                }
+            }
+            TAG_STRING => IndexValue::String(
+                String::from_utf8(self.escaped()?).map_err(|_| corrupt("string is not UTF-8"))?,
+            ),
+            TAG_BINARY => IndexValue::Binary(self.escaped()?),
+            TAG_OBJECT_ID => IndexValue::ObjectId(self.array()?),
+            TAG_DATE_TIME => {
+                let secs = self.int()?;
+                IndexValue::DateTime(secs, u32::from_be_bytes(self.array()?))
+            }
+            TAG_UUID => IndexValue::Uuid(self.array()?),
+            TAG_TIMESTAMP => {
+                let time = u32::from_be_bytes(self.array()?);
+                IndexValue::Timestamp(time, u32::from_be_bytes(self.array()?))
+            }
+            _ => return Err(corrupt("unknown value tag")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Value;
+
+    fn samples() -> Vec<IndexValue> {
+        vec![
+            IndexValue::Missing,
+            IndexValue::Null,
+            IndexValue::Bool(false),
+            IndexValue::Bool(true),
+            IndexValue::Float(OrderedFloat::new(f64::NEG_INFINITY)),
+            IndexValue::Int(i64::MIN),
+            IndexValue::Int32(-5),
+            IndexValue::Float(OrderedFloat::new(-0.5)),
+            IndexValue::Int(0),
+            IndexValue::Float(OrderedFloat::new(2.5)),
+            IndexValue::Int32(3),
+            IndexValue::Int(1 << 53),
+            IndexValue::Int((1 << 53) + 1),
+            IndexValue::Decimal128("-1.5".parse().unwrap()),
+            IndexValue::Decimal128("-1.25".parse().unwrap()),
+            IndexValue::Decimal128("0E-3".parse().unwrap()),
+            IndexValue::Decimal128("1.1".parse().unwrap()),
+            IndexValue::Decimal128("1.10000000000000000000001".parse().unwrap()),
+            IndexValue::Decimal128("2.50".parse().unwrap()),
+            IndexValue::Decimal128("1E+400".parse().unwrap()),
+            IndexValue::String(String::new()),
+            IndexValue::String("a".to_string()),
+            IndexValue::String("a\0b".to_string()),
+            IndexValue::String("ab".to_string()),
+            IndexValue::Binary(vec![0, 0]),
+            IndexValue::Binary(vec![1]),
+            IndexValue::ObjectId([0; 12]),
+            IndexValue::ObjectId([1; 12]),
+            IndexValue::DateTime(-1, 500),
+            IndexValue::DateTime(0, 0),
+            IndexValue::DateTime(0, 1),
+            IndexValue::Uuid([0; 16]),
+            IndexValue::Uuid([7; 16]),
+            IndexValue::Timestamp(1, 9),
+            IndexValue::Timestamp(2, 0),
+        ]
+    }
+
+    #[test]
+    fn test_encoding_preserves_order() {
+        let id = DocumentId::new();
+        for direction in [false, true] {
+            let values = samples();
+            let encoded: Vec<Vec<u8>> = values
+                .iter()
+                .map(|v| encode_entry(std::slice::from_ref(v), &[direction], id))
+                .collect();
+
+            for (i, a) in values.iter().enumerate() {
+                for (j, b) in values.iter().enumerate() {
+                    let expected = if direction { b.cmp(a) } else { a.cmp(b) };
+                    // Numerically equal values may encode differently to keep their variant
+                    if expected != std::cmp::Ordering::Equal {
+                        assert_eq!(encoded[i].cmp(&encoded[j]), expected, "{:?} vs {:?}", a, b);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_compound_prefix_orders_before_longer_string() {
+        let id = DocumentId::from_bytes([0xFF; 16]);
+        let short = encode_entry(
+            &[IndexValue::String("a".into()), IndexValue::Int(9)],
+            &[],
+            id,
+        );
+        let long = encode_entry(
+            &[IndexValue::String("ab".into()), IndexValue::Int(0)],
+            &[],
+            DocumentId::from_bytes([0; 16]),
+        );
+        assert!(short < long);
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let id = DocumentId::new();
+        let values = samples();
+        let descending: Vec<bool> = (0..values.len()).map(|i| i % 2 == 1).collect();
+
+        let encoded = encode_entry(&values, &descending, id);
+        let (decoded, decoded_id) = decode_entry(&encoded, values.len(), &descending).unwrap();
+
+        assert_eq!(decoded_id, id);
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", values));
+    }
+
+    #[test]
+    fn test_decode_rejects_corrupt_entries() {
+        let id = DocumentId::new();
+        let encoded = encode_entry(&[IndexValue::String("abc".into())], &[], id);
+
+        assert!(matches!(
+            decode_entry(&encoded[..encoded.len() - 1], 1, &[]),
+            Err(IndexError::CorruptEntry(_))
+        ));
+        assert!(matches!(decode_entry(&encoded, 2, &[]), Err(IndexError::CorruptEntry(_))));
+
+        let mut bad_tag = encoded.clone();
+        bad_tag[0] = 0x42;
+        assert!(matches!(decode_entry(&bad_tag, 1, &[]), Err(IndexError::CorruptEntry(_))));
+    }
+
+    #[test]
+    fn test_key_values_and_checksum() {
+        let mut doc = Document::new();
+        doc.insert("name".to_string(), Value::String("Ada".to_string()));
+
+        let keys = key_values(&doc, &["name".to_string(), "age".to_string()]).unwrap();
+        assert_eq!(keys, vec![vec![IndexValue::String("Ada".into()), IndexValue::Missing]]);
+        let values = keys[0].clone();
+
+        // Each array element becomes a key of its own
+        doc.insert("tags".to_string(), Value::Array(vec![Value::Int32(1), Value::Int32(2), Value::Int32(1)]));
+        let keys = key_values(&doc, &["name".to_string(), "tags".to_string()]).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1], vec![IndexValue::String("Ada".into()), IndexValue::Int32(2)]);
+        doc.insert("name".to_string(), Value::Array(Vec::new()));
+        assert!(matches!(
+            key_values(&doc, &["name".to_string(), "tags".to_string()]),
+            Err(IndexError::ParallelArrays(_))
+        ));
+
+        let a = encode_entry(&values, &[], doc.id);
+        let b = encode_entry(&values, &[true], doc.id);
+        assert_eq!(entry_checksum(&a), entry_checksum(&a.clone()));
+        assert_ne!(entry_checksum(&a), entry_checksum(&b));
+    }
+}
@@ -2,13 +2,17 @@
 //!
 //! Manages multiple indexes for a collection and provides unified interface
 
-use super::btree::{BTreeIndex, IndexEntry, IndexError, IndexKey};
-use super::builder::IndexBuilder;
-use super::statistics::IndexStatistics;
-use crate::document::{Document, DocumentId};
-use crate::schema::{IndexDefinition, IndexType};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use super::btree::{BTreeIndex, IndexEntry, IndexError, IndexKey, IndexValue, KeyForm};
+use super::builder::{IndexBuilder, SideWrite, SideWriteBuffer};
+use super::statistics::{CollectionStatistics, IndexStatistics, KeyDistribution};
+use super::vector::{VectorIndex, VectorSpec, MAX_VECTOR_DIMS};
+use crate::document::{Document, DocumentId, Value};
+use crate::query::Filter;
+use crate::schema::IndexDefinition;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
 use tokio::sync::Mutex;
 
 /// Index manager for a collection
@@ -17,10 +21,27 @@ pub struct IndexManager {
     collection_name: String,
     /// Active indexes
     indexes: Arc<RwLock<HashMap<String, Arc<BTreeIndex>>>>,
+    /// Definitions the active indexes were created from
+    definitions: Arc<RwLock<HashMap<String, IndexDefinition>>>,
     /// Index builder for background operations
     builder: Arc<Mutex<IndexBuilder>>,
     /// Index statistics
     statistics: Arc<RwLock<IndexStatistics>>,
+    /// Indexes being built, invisible to queries until their build finishes
+    building: Arc<RwLock<HashMap<String, Arc<BuildingIndex>>>>,
+    /// Bumped whenever indexes are added, removed, re-analyzed, or become multikey
+    version: AtomicU64,
+    /// Serializes document writes so a unique key is checked and claimed at once
+    writes: StdMutex<()>,
+    /// Documents in the collection, counted by the writes seen since it was last set
+    documents: AtomicU64,
+}
+
+/// An index under construction and the writes made while it builds
+struct BuildingIndex {
+    index: Arc<BTreeIndex>,
+    definition: IndexDefinition,
+    side_writes: SideWriteBuffer,
 }
 
 impl IndexManager {
@@ -29,55 +50,203 @@ impl IndexManager {
         Self {
             collection_name,
             indexes: Arc::new(RwLock::new(HashMap::new())),
+            definitions: Arc::new(RwLock::new(HashMap::new())),
             builder: Arc::new(Mutex::new(IndexBuilder::new())),
             statistics: Arc::new(RwLock::new(IndexStatistics::new())),
+            building: Arc::new(RwLock::new(HashMap::new())),
+            version: AtomicU64::new(0),
+            writes: StdMutex::new(()),
+            documents: AtomicU64::new(0),
         }
     }
 
     /// Create an index from definition
     pub async fn create_index(&self, definition: IndexDefinition) -> Result<(), IndexError> {
-        let fields = match &definition.index_type {
-            IndexType::Single { field } => vec![field.clone()],
-            IndexType::Compound { fields } => fields.clone(),
-            IndexType::Text { field } => vec![field.clone()],
-            IndexType::Geospatial { field } => vec![field.clone()],
-        };
+        self.register_index(definition)
+    }
+
+    /// Register an empty index from definition
+    pub fn register_index(&self, definition: IndexDefinition) -> Result<(), IndexError> {
+        Self::validate_definition(&definition)?;
+        let index = Self::new_index(&definition);
+        self.activate(definition, index);
+        Ok(())
+    }
+
+    /// Create the empty B-tree for a definition
+    fn new_index(definition: &IndexDefinition) -> Arc<BTreeIndex> {
+        let fields = definition.fields();
+        let descending = (0..fields.len())
+            .map(|position| definition.is_descending(position))
+            .collect();
 
-        let index = Arc::new(BTreeIndex::new(
+        let index = BTreeIndex::new(
             definition.name.clone(),
             fields,
             definition.unique,
             definition.sparse,
-        ));
+        )
+        .with_directions(descending)
+        .with_partial_filter(definition.partial_filter.clone())
+        .with_key_form(KeyForm::new(definition.is_hashed(), definition.collation.clone()));
+        match definition.vector_spec() {
+            Some((dims, metric)) => Arc::new(index.with_vector(VectorIndex::new(VectorSpec::new(dims, metric)))),
+            None => Arc::new(index),
+        }
+    }
 
+    /// Reject option combinations an index cannot honour
+    fn validate_definition(definition: &IndexDefinition) -> Result<(), IndexError> {
+        let invalid = |reason: &str| IndexError::InvalidDefinition {
+            index: definition.name.clone(),
+            reason: reason.to_string(),
+        };
+        if let Some(filter) = &definition.partial_filter {
+            if !Self::is_partial_filter(filter) {
+                return Err(invalid(
+                    "a partial filter may only use equality, range, $in, $exists and $and",
+                ));
+            }
+        }
+        if definition.expire_after_seconds.is_some() && definition.fields().len() != 1 {
+            return Err(invalid("a TTL index must have exactly one field"));
+        }
+        // Distinct values may share a hash, so uniqueness cannot be judged
+        if definition.is_hashed() && definition.unique {
+            return Err(invalid("a hashed index cannot be unique"));
+        }
+        if let Some(collation) = &definition.collation {
+            collation.validate().map_err(|e| invalid(&e.to_string()))?;
+        }
+        if let Some((dims, _)) = definition.vector_spec() {
+            if !(1..=MAX_VECTOR_DIMS).contains(&dims) {
+                return Err(invalid(&format!("a vector index needs 1 to {} dimensions", MAX_VECTOR_DIMS)));
+            }
+            if definition.unique || definition.collation.is_some() || definition.expire_after_seconds.is_some() {
+                return Err(invalid("a vector index cannot be unique, collated or TTL"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a filter is made only of predicates a partial index supports
+    fn is_partial_filter(filter: &Filter) -> bool {
+        match filter {
+            Filter::Eq { .. }
+            | Filter::Gt { .. }
+            | Filter::Gte { .. }
+            | Filter::Lt { .. }
+            | Filter::Lte { .. }
+            | Filter::In { .. }
+            | Filter::Exists { .. } => true,
+            Filter::And(filters) => !filters.is_empty() && filters.iter().all(Self::is_partial_filter),
+            _ => false,
+        }
+    }
+
+    /// Make an index visible to queries and writes
+    fn activate(&self, definition: IndexDefinition, index: Arc<BTreeIndex>) {
         // Add to active indexes
         {
             let mut indexes = self.indexes.write().unwrap();
             indexes.insert(definition.name.clone(), index.clone());
         }
+        {
+            let mut definitions = self.definitions.write().unwrap();
+            definitions.insert(definition.name.clone(), definition.clone());
+        }
 
         // Update statistics
         {
             let mut stats = self.statistics.write().unwrap();
             stats.add_index(definition.name.clone());
         }
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
 
-        Ok(())
+    /// Start building an index in the background
+    ///
+    /// The returned index is filled by the build's scan; writes made until
+    /// [`finish_build`](Self::finish_build) are captured in a side-write
+    /// buffer instead of touching it.
+    pub fn begin_build(&self, definition: IndexDefinition) -> Result<Arc<BTreeIndex>, IndexError> {
+        Self::validate_definition(&definition)?;
+        let mut building = self.building.write().unwrap();
+        if building.contains_key(&definition.name) || self.has_index(&definition.name) {
+            return Err(IndexError::OperationFailed(format!(
+                "Index '{}' already exists",
+                definition.name
+            )));
+        }
+
+        let index = Self::new_index(&definition);
+        building.insert(
+            definition.name.clone(),
+            Arc::new(BuildingIndex {
+                index: index.clone(),
+                definition,
+                side_writes: SideWriteBuffer::new(),
+            }),
+        );
+        Ok(index)
+    }
+
+    /// Apply an index's side writes and make it visible to queries
+    ///
+    /// Writers are held off while the buffer drains, so no write is lost
+    /// between the last side write and activation. Returns the number of
+    /// side writes applied; on failure the build is discarded.
+    pub fn finish_build(&self, index_name: &str) -> Result<u64, IndexError> {
+        let mut building = self.building.write().unwrap();
+        let build = building.remove(index_name).ok_or_else(|| {
+            IndexError::OperationFailed(format!("Index '{}' is not building", index_name))
+        })?;
+
+        let applied = build.side_writes.drain_into(&build.index)?;
+        build.index.analyze();
+        self.activate(build.definition.clone(), build.index.clone());
+        Ok(applied)
+    }
+
+    /// Discard an index build, returning whether it existed
+    pub fn abort_build(&self, index_name: &str) -> bool {
+        self.building.write().unwrap().remove(index_name).is_some()
+    }
+
+    /// Index being built under this name
+    pub fn building_index(&self, index_name: &str) -> Option<Arc<BTreeIndex>> {
+        self.building
+            .read()
+            .unwrap()
+            .get(index_name)
+            .map(|build| build.index.clone())
+    }
+
+    /// Whether writes must maintain any index, active or building
+    pub fn has_indexes(&self) -> bool {
+        self.index_count() > 0 || !self.building.read().unwrap().is_empty()
     }
 
     /// Drop an index
     pub async fn drop_index(&self, index_name: &str) -> Result<bool, IndexError> {
+        Ok(self.unregister_index(index_name))
+    }
+
+    /// Remove an index, returning whether it existed
+    pub fn unregister_index(&self, index_name: &str) -> bool {
         let removed = {
             let mut indexes = self.indexes.write().unwrap();
             indexes.remove(index_name).is_some()
         };
+        self.definitions.write().unwrap().remove(index_name);
 
         if removed {
             let mut stats = self.statistics.write().unwrap();
             stats.remove_index(index_name);
+            self.version.fetch_add(1, Ordering::Relaxed);
         }
 
-        Ok(removed)
+        removed
     }
 
     /// Build index in background for existing documents
@@ -103,17 +272,71 @@ impl IndexManager {
         Ok(())
     }
 
+    /// Populate a registered index from existing documents
+    ///
+    /// On a unique constraint violation the index is left empty.
+    pub fn populate_index(&self, index_name: &str, documents: &[Document]) -> Result<(), IndexError> {
+        let index = self.get_index(index_name).ok_or_else(|| {
+            IndexError::OperationFailed(format!("Index '{}' not found", index_name))
+        })?;
+
+        for document in documents {
+            let Some(entry) = self.index_entry(&index, document)? else {
+                continue;
+            };
+            if let Err(e) = index.insert(document.id, entry) {
+                index.clear();
+                return Err(e);
+            }
+        }
+        index.analyze();
+        self.version.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Fill a registered index from previously persisted entries
+    ///
+    /// Each entry is one key in stored form, as produced by
+    /// [`key_values`](super::encoding::key_values) and the index's key
+    /// form; a document indexed under several keys appears once per key.
+    /// On a unique constraint
+    /// violation the index is left empty.
+    pub fn load_entries(
+        &self,
+        index_name: &str,
+        entries: Vec<(DocumentId, Vec<IndexValue>)>,
+    ) -> Result<(), IndexError> {
+        let index = self.get_index(index_name).or_else(|| self.building_index(index_name)).ok_or_else(|| {
+            IndexError::OperationFailed(format!("Index '{}' not found", index_name))
+        })?;
+
+        for (doc_id, values) in entries {
+            if let Err(e) = index.insert_stored(doc_id, values) {
+                index.clear();
+                return Err(e);
+            }
+        }
+        index.analyze();
+        self.version.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
     /// Insert document into all applicable indexes
+    ///
+    /// Either every index takes the document or none does; a unique
+    /// violation leaves the indexes as they were.
     pub fn insert_document(&self, doc_id: DocumentId, document: &Document) -> Result<(), IndexError> {
+        let _writes = self.writes.lock().unwrap();
+        // Held until the write is captured, so a build cannot finish in between
+        let building = self.building.read().unwrap();
         let indexes = self.indexes.read().unwrap();
-        
-        for (index_name, index) in indexes.iter() {
-            let entry = self.create_index_entry(document, index.fields())?;
-            
-            if let Err(e) = index.insert(doc_id, entry) {
-                // If insertion fails, we need to rollback previous insertions
-                self.rollback_insertions(doc_id, document, index_name)?;
-                return Err(e);
+
+        self.insert_entries(&indexes, doc_id, document)?;
+        for build in building.values() {
+            if let Some(entry) = self.index_entry(&build.index, document)? {
+                build.side_writes.record(SideWrite::Insert(doc_id, entry));
             }
         }
 
@@ -122,24 +345,45 @@ impl IndexManager {
             let mut stats = self.statistics.write().unwrap();
             stats.record_insert();
         }
+        self.documents.fetch_add(1, Ordering::Relaxed);
 
         Ok(())
     }
 
     /// Update document in all applicable indexes
+    ///
+    /// The old entries are restored if the new document violates a unique
+    /// index; no other write can claim the old keys in between.
     pub fn update_document(
         &self,
         doc_id: DocumentId,
         old_document: &Document,
         new_document: &Document,
     ) -> Result<(), IndexError> {
+        let _writes = self.writes.lock().unwrap();
+        let building = self.building.read().unwrap();
+        let indexes = self.indexes.read().unwrap();
+
         // Remove old entries and insert new ones
-        self.remove_document(doc_id, old_document)?;
-        self.insert_document(doc_id, new_document)?;
+        self.remove_entries(&indexes, doc_id, old_document)?;
+        if let Err(e) = self.insert_entries(&indexes, doc_id, new_document) {
+            self.insert_entries(&indexes, doc_id, old_document)?;
+            return Err(e);
+        }
+        for build in building.values() {
+            if let Some(old_entry) = self.index_entry(&build.index, old_document)? {
+                build.side_writes.record(SideWrite::Remove(doc_id, old_entry));
+            }
+            if let Some(new_entry) = self.index_entry(&build.index, new_document)? {
+                build.side_writes.record(SideWrite::Insert(doc_id, new_entry));
+            }
+        }
 
         // Update statistics
         {
             let mut stats = self.statistics.write().unwrap();
+            stats.record_delete();
+            stats.record_insert();
             stats.record_update();
         }
 
@@ -148,11 +392,15 @@ impl IndexManager {
 
     /// Remove document from all applicable indexes
     pub fn remove_document(&self, doc_id: DocumentId, document: &Document) -> Result<(), IndexError> {
+        let _writes = self.writes.lock().unwrap();
+        let building = self.building.read().unwrap();
         let indexes = self.indexes.read().unwrap();
-        
-        for index in indexes.values() {
-            let entry = self.create_index_entry(document, index.fields())?;
-            index.remove(doc_id, entry)?;
+
+        self.remove_entries(&indexes, doc_id, document)?;
+        for build in building.values() {
+            if let Some(entry) = self.index_entry(&build.index, document)? {
+                build.side_writes.record(SideWrite::Remove(doc_id, entry));
+            }
         }
 
         // Update statistics
@@ -160,7 +408,63 @@ impl IndexManager {
             let mut stats = self.statistics.write().unwrap();
             stats.record_delete();
         }
+        let _ = self
+            .documents
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| Some(count.saturating_sub(1)));
+
+        Ok(())
+    }
+
+    /// Add a document's entries to every index, undoing them all if one fails
+    fn insert_entries(
+        &self,
+        indexes: &HashMap<String, Arc<BTreeIndex>>,
+        doc_id: DocumentId,
+        document: &Document,
+    ) -> Result<(), IndexError> {
+        let mut inserted = Vec::new();
+        for index in indexes.values() {
+            let (multikey, lossy) = (index.is_multikey(), index.has_lossy_keys());
+            let result = match self.index_entry(index, document) {
+                Ok(Some(entry)) => index.insert(doc_id, entry.clone()).map(|_| entry),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            if (!multikey && index.is_multikey()) || (!lossy && index.has_lossy_keys()) {
+                // Plans chosen before may bound the index wrongly or be covered by it
+                self.version.fetch_add(1, Ordering::Relaxed);
+            } else if index.take_statistics_drift() {
+                // Plans chosen before were costed on a different distribution
+                self.version.fetch_add(1, Ordering::Relaxed);
+            }
+            match result {
+                Ok(entry) => inserted.push((index, entry)),
+                Err(e) => {
+                    for (index, entry) in inserted {
+                        index.remove(doc_id, entry)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
 
+    /// Remove a document's entries from every index
+    fn remove_entries(
+        &self,
+        indexes: &HashMap<String, Arc<BTreeIndex>>,
+        doc_id: DocumentId,
+        document: &Document,
+    ) -> Result<(), IndexError> {
+        for index in indexes.values() {
+            if let Some(entry) = self.index_entry(index, document)? {
+                index.remove(doc_id, entry)?;
+                if index.take_statistics_drift() {
+                    self.version.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
         Ok(())
     }
 
@@ -217,6 +521,135 @@ impl IndexManager {
         }
     }
 
+    /// Find documents whose leading index fields equal `prefix` and whose
+    /// next field lies between the given bounds
+    pub fn find_prefix_range_with_index(
+        &self,
+        index_name: &str,
+        prefix: &[Value],
+        lower: Option<(&Value, bool)>,
+        upper: Option<(&Value, bool)>,
+    ) -> Result<Vec<DocumentId>, IndexError> {
+        let index = self.get_index(index_name).ok_or_else(|| {
+            IndexError::OperationFailed(format!("Index '{}' not found", index_name))
+        })?;
+
+        let results = index.find_prefix_range(prefix, lower, upper)?;
+        self.statistics.write().unwrap().record_lookup(index_name, results.len());
+        Ok(results)
+    }
+
+    /// Visit index entries in a prefix range until `visit` returns `false`
+    pub fn scan_prefix_range_with_index<F>(
+        &self,
+        index_name: &str,
+        prefix: &[Value],
+        lower: Option<(&Value, bool)>,
+        upper: Option<(&Value, bool)>,
+        reverse: bool,
+        mut visit: F,
+    ) -> Result<(), IndexError>
+    where
+        F: FnMut(&IndexKey, DocumentId) -> bool,
+    {
+        let index = self.get_index(index_name).ok_or_else(|| {
+            IndexError::OperationFailed(format!("Index '{}' not found", index_name))
+        })?;
+
+        let mut visited = 0;
+        index.scan_prefix_range(prefix, lower, upper, reverse, |key, doc_id| {
+            visited += 1;
+            visit(key, doc_id)
+        })?;
+        self.statistics.write().unwrap().record_lookup(index_name, visited);
+        Ok(())
+    }
+
+    /// Nearest documents to `vector` in a vector index, most similar first
+    ///
+    /// Only documents `accept` lets through are returned; the filter runs
+    /// while the graph is searched, so up to `k` results come back however
+    /// selective it is. Each result carries its similarity score.
+    pub fn vector_search<F>(
+        &self,
+        index_name: &str,
+        vector: &[f64],
+        k: usize,
+        num_candidates: usize,
+        accept: F,
+    ) -> Result<Vec<(DocumentId, f64)>, IndexError>
+    where
+        F: FnMut(DocumentId) -> bool,
+    {
+        let index = self.get_index(index_name).ok_or_else(|| {
+            IndexError::OperationFailed(format!("Index '{}' not found", index_name))
+        })?;
+        let graph = index.vector().ok_or_else(|| IndexError::InvalidDefinition {
+            index: index_name.to_string(),
+            reason: "not a vector index".to_string(),
+        })?;
+
+        let results = graph.search(vector, k, num_candidates, accept)?;
+        self.statistics.write().unwrap().record_lookup(index_name, results.len());
+        Ok(results)
+    }
+
+    /// Name of the active vector index on a field
+    pub fn vector_index_for(&self, field: &str) -> Option<String> {
+        let definitions = self.definitions.read().unwrap();
+        let mut names: Vec<&String> = definitions
+            .iter()
+            .filter(|(_, definition)| definition.vector_spec().is_some() && definition.fields() == [field])
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names.first().map(|name| name.to_string())
+    }
+
+    /// Definitions of all active indexes, ordered by name
+    pub fn definitions(&self) -> Vec<IndexDefinition> {
+        let definitions = self.definitions.read().unwrap();
+        let mut definitions: Vec<IndexDefinition> = definitions.values().cloned().collect();
+        definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        definitions
+    }
+
+    /// Names of the active indexes that hold one entry per array element
+    pub fn multikey_indexes(&self) -> HashSet<String> {
+        let indexes = self.indexes.read().unwrap();
+        indexes
+            .iter()
+            .filter(|(_, index)| index.is_multikey())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Names of the active indexes whose stored keys may not hold the
+    /// documents' own values, so they cannot cover queries
+    pub fn lossy_key_indexes(&self) -> HashSet<String> {
+        let indexes = self.indexes.read().unwrap();
+        indexes
+            .iter()
+            .filter(|(_, index)| index.has_lossy_keys())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Mark an index, active or building, as multikey
+    ///
+    /// Used when reloading persisted entries, which hold one key each and
+    /// so lose track of the arrays they came from.
+    pub fn mark_multikey(&self, index_name: &str) -> Result<(), IndexError> {
+        let index = self.get_index(index_name).or_else(|| self.building_index(index_name)).ok_or_else(|| {
+            IndexError::OperationFailed(format!("Index '{}' not found", index_name))
+        })?;
+        if !index.is_multikey() {
+            index.set_multikey();
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
     /// Get index by name
     pub fn get_index(&self, index_name: &str) -> Option<Arc<BTreeIndex>> {
         let indexes = self.indexes.read().unwrap();
@@ -234,6 +667,71 @@ impl IndexManager {
         self.statistics.read().unwrap().clone()
     }
 
+    /// Rebuild the key distribution of one index, or of every index
+    pub fn analyze(&self, index_name: Option<&str>) -> Result<Vec<(String, KeyDistribution)>, IndexError> {
+        let indexes: Vec<Arc<BTreeIndex>> = match index_name {
+            Some(name) => vec![self.get_index(name).ok_or_else(|| {
+                IndexError::OperationFailed(format!("Index '{}' not found", name))
+            })?],
+            None => self.indexes.read().unwrap().values().cloned().collect(),
+        };
+
+        let mut analyzed: Vec<(String, KeyDistribution)> = indexes
+            .iter()
+            .map(|index| (index.name().to_string(), index.analyze()))
+            .collect();
+        analyzed.sort_by(|a, b| a.0.cmp(&b.0));
+        self.version.fetch_add(1, Ordering::Relaxed);
+        Ok(analyzed)
+    }
+
+    /// Version of the index catalog and its statistics
+    ///
+    /// Changes whenever an index is added, removed, populated, re-analyzed,
+    /// becomes multikey, starts sharing keys between unequal values or has
+    /// its key distribution drift, so plans chosen under an older version
+    /// are stale.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    /// Set the collection's document count, e.g. when its indexes are loaded
+    ///
+    /// Inserts and removals through the manager keep it current afterwards.
+    /// It lets the planner price collection scans when no index holds every
+    /// document.
+    pub fn set_document_count(&self, documents: u64) {
+        self.documents.store(documents, Ordering::Relaxed);
+    }
+
+    /// Estimated document count, without copying key distributions
+    pub fn document_estimate(&self) -> u64 {
+        let indexes = self.indexes.read().unwrap();
+        indexes
+            .values()
+            .filter(|index| index.holds_every_document())
+            .map(|index| index.entry_count())
+            .max()
+            .unwrap_or(0)
+            .max(self.documents.load(Ordering::Relaxed))
+    }
+
+    /// Key distributions of every index for the query planner
+    pub fn collection_statistics(&self) -> CollectionStatistics {
+        let indexes = self.indexes.read().unwrap();
+        let mut statistics = CollectionStatistics::default();
+        for (name, index) in indexes.iter() {
+            let distribution = index.key_distribution();
+            // A non-sparse, non-partial index holds an entry for every document
+            if index.holds_every_document() {
+                statistics.documents = statistics.documents.max(distribution.entries);
+            }
+            statistics.indexes.insert(name.clone(), distribution);
+        }
+        statistics.documents = statistics.documents.max(self.documents.load(Ordering::Relaxed));
+        statistics
+    }
+
     /// Check if an index exists
     pub fn has_index(&self, index_name: &str) -> bool {
         let indexes = self.indexes.read().unwrap();
@@ -248,39 +746,89 @@ impl IndexManager {
 
     /// Create index entry from document
     fn create_index_entry(&self, document: &Document, fields: &[String]) -> Result<IndexEntry, IndexError> {
-        let mut entry = IndexEntry::new();
-        
-        for field in fields {
-            if let Some(value) = document.get_by_path(field) {
-                entry.add_field(field.clone(), value.clone());
-            } else {
-                // Field not present - use null for sparse indexes
-                entry.add_field(field.clone(), crate::document::Value::Null);
-            }
+        Ok(IndexEntry::from_document(document, fields))
+    }
+
+    /// A document's entry in an index, or `None` if its partial filter leaves the document out
+    fn index_entry(&self, index: &BTreeIndex, document: &Document) -> Result<Option<IndexEntry>, IndexError> {
+        if !index.covers(document)? {
+            return Ok(None);
         }
-        
-        Ok(entry)
+        self.create_index_entry(document, index.fields()).map(Some)
     }
 
-    /// Rollback insertions in case of failure
-    fn rollback_insertions(
-        &self,
-        doc_id: DocumentId,
-        document: &Document,
-        failed_index: &str,
-    ) -> Result<(), IndexError> {
-        let indexes = self.indexes.read().unwrap();
-        
-        for (index_name, index) in indexes.iter() {
-            if index_name == failed_index {
-                break; // Stop at the failed index
+    /// Documents whose TTL has run out as of `now`
+    ///
+    /// A document expires once the date in a TTL index's field is more
+    /// than the index's `expire_after_seconds` in the past; with an array
+    /// of dates the earliest one counts. Values other than dates never
+    /// expire.
+    pub fn expired_documents(&self, now: DateTime<Utc>) -> Result<Vec<DocumentId>, IndexError> {
+        let mut expired = Vec::new();
+        let mut seen = HashSet::new();
+        for definition in self.definitions() {
+            let Some(seconds) = definition.expire_after_seconds else {
+                continue;
+            };
+            let Some(cutoff) = Self::ttl_cutoff(seconds, now) else {
+                continue;
+            };
+
+            let earliest = Value::DateTime(DateTime::<Utc>::MIN_UTC);
+            let cutoff = Value::DateTime(cutoff);
+            self.scan_prefix_range_with_index(
+                &definition.name,
+                &[],
+                Some((&earliest, true)),
+                Some((&cutoff, false)),
+                false,
+                |_, doc_id| {
+                    if seen.insert(doc_id) {
+                        expired.push(doc_id);
+                    }
+                    true
+                },
+            )?;
+        }
+        Ok(expired)
+    }
+
+    /// Whether a document's TTL has run out as of `now`
+    ///
+    /// Checks the document itself rather than the index entries, so a
+    /// document rewritten after [`expired_documents`](Self::expired_documents)
+    /// listed it is not deleted by mistake.
+    pub fn is_expired(&self, document: &Document, now: DateTime<Utc>) -> Result<bool, IndexError> {
+        for definition in self.definitions() {
+            let fields = definition.fields();
+            let (Some(seconds), [field]) = (definition.expire_after_seconds, fields.as_slice()) else {
+                continue;
+            };
+            let Some(cutoff) = Self::ttl_cutoff(seconds, now) else {
+                continue;
+            };
+            let covered = match self.get_index(&definition.name) {
+                Some(index) => index.covers(document)?,
+                None => false,
+            };
+            let expired = document.values_by_path(field).into_iter().any(|value| match value {
+                Value::DateTime(date) => *date < cutoff,
+                Value::Array(values) => values.iter().any(|v| matches!(v, Value::DateTime(date) if *date < cutoff)),
+                _ => false,
+            });
+            if covered && expired {
+                return Ok(true);
             }
-            
-            let entry = self.create_index_entry(document, index.fields())?;
-            index.remove(doc_id, entry)?;
         }
-        
-        Ok(())
+        Ok(false)
+    }
+
+    /// Dates before this have expired under a TTL of `seconds`
+    fn ttl_cutoff(seconds: u64, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        i64::try_from(seconds)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
+            .and_then(|ttl| now.checked_sub_signed(ttl))
     }
 
     /// Optimize all indexes (rebuild for better performance)
@@ -332,7 +880,6 @@ impl IndexManager {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::document::Value;
     use crate::schema::IndexDefinition;
 
     fn create_test_document(name: &str, age: i32) -> Document {
@@ -483,6 +1030,54 @@ mod tests {
         assert!(matches!(result.unwrap_err(), IndexError::UniqueConstraintViolation { .. }));
     }
 
+    #[tokio::test]
+    async fn test_populate_and_prefix_range() {
+        let manager = IndexManager::new("test_collection".to_string());
+
+        let index_def = IndexDefinition::compound(vec!["name".to_string(), "age".to_string()])
+            .with_directions(vec![1, -1]);
+        manager.register_index(index_def.clone()).unwrap();
+
+        let young = create_test_document("John", 20);
+        let old = create_test_document("John", 40);
+        let other = create_test_document("Mary", 30);
+        manager
+            .populate_index("idx_name_age", &[young.clone(), old.clone(), other])
+            .unwrap();
+
+        let results = manager
+            .find_prefix_range_with_index(
+                "idx_name_age",
+                &[Value::String("John".to_string())],
+                Some((&Value::Int32(18), true)),
+                None,
+            )
+            .unwrap();
+        assert_eq!(results, vec![old.id, young.id]);
+        let definitions = manager.definitions();
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].name, index_def.name);
+        assert_eq!(definitions[0].directions, vec![1, -1]);
+    }
+
+    #[tokio::test]
+    async fn test_populate_rejects_duplicates() {
+        let manager = IndexManager::new("test_collection".to_string());
+        manager
+            .register_index(IndexDefinition::single("name".to_string()).unique())
+            .unwrap();
+
+        let docs = vec![create_test_document("John", 20), create_test_document("John", 40)];
+        let result = manager.populate_index("idx_name", &docs);
+
+        assert!(matches!(result, Err(IndexError::UniqueConstraintViolation { .. })));
+        assert!(manager.get_index("idx_name").unwrap().is_empty());
+        assert!(matches!(
+            manager.populate_index("idx_missing", &docs),
+            Err(IndexError::OperationFailed(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_statistics() {
         let manager = IndexManager::new("test_collection".to_string());
@@ -500,4 +1095,171 @@ mod tests {
         assert_eq!(stats.total_inserts(), 1);
         assert_eq!(stats.index_count(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_partial_index_skips_unmatched_documents() {
+        let manager = IndexManager::new("users".to_string());
+        let definition = IndexDefinition::single("name".to_string())
+            .with_partial_filter(Filter::gte("age", 18));
+        manager.register_index(definition).unwrap();
+
+        let adult = create_test_document("Ann", 30);
+        let mut minor = create_test_document("Bob", 12);
+        manager.insert_document(adult.id, &adult).unwrap();
+        manager.insert_document(minor.id, &minor).unwrap();
+        let index = manager.get_index("idx_name").unwrap();
+        assert_eq!(index.entry_count(), 1);
+        // Not every document has an entry; the count comes from the writes
+        assert_eq!(manager.collection_statistics().documents, 2);
+
+        // Updates move a document in or out of the index
+        let before = minor.clone();
+        minor.insert("age".to_string(), Value::Int32(20));
+        manager.update_document(minor.id, &before, &minor).unwrap();
+        assert_eq!(index.entry_count(), 2);
+        manager.update_document(adult.id, &adult, &create_test_document("Ann", 3)).unwrap();
+        assert_eq!(index.entry_count(), 1);
+
+        // Only simple predicates may select the indexed documents
+        let invalid = IndexDefinition::single("name".to_string())
+            .named("by_name")
+            .with_partial_filter(Filter::Or(vec![Filter::eq("age", 1), Filter::eq("age", 2)]));
+        assert!(matches!(
+            manager.register_index(invalid),
+            Err(IndexError::InvalidDefinition { index, .. }) if index == "by_name"
+        ));
+    }
+
+    #[test]
+    fn test_ttl_index_lists_expired_documents() {
+        let manager = IndexManager::new("sessions".to_string());
+        manager
+            .register_index(IndexDefinition::single("seen_at".to_string()).expire_after(60))
+            .unwrap();
+
+        let now = Utc::now();
+        let session = |seen_at: Value| {
+            let mut doc = Document::new();
+            doc.insert("seen_at".to_string(), seen_at);
+            doc
+        };
+        let stale = session(Value::DateTime(now - chrono::Duration::seconds(120)));
+        let fresh = session(Value::DateTime(now - chrono::Duration::seconds(30)));
+        let undated = session(Value::String("yesterday".to_string()));
+        let mixed = session(Value::Array(vec![
+            Value::DateTime(now),
+            Value::DateTime(now - chrono::Duration::seconds(600)),
+        ]));
+        for doc in [&stale, &fresh, &undated, &mixed] {
+            manager.insert_document(doc.id, doc).unwrap();
+        }
+
+        let mut expired = manager.expired_documents(now).unwrap();
+        expired.sort();
+        let mut expected = vec![stale.id, mixed.id];
+        expected.sort();
+        assert_eq!(expired, expected);
+        assert!(manager.is_expired(&stale, now).unwrap());
+        assert!(!manager.is_expired(&fresh, now).unwrap());
+        assert!(!manager.is_expired(&undated, now).unwrap());
+
+        // A TTL index reads its date from a single field
+        let compound = IndexDefinition::compound(vec!["a".to_string(), "b".to_string()]).expire_after(1);
+        assert!(manager.register_index(compound).is_err());
+    }
+
+    #[test]
+    fn test_hashed_and_collated_definitions_are_validated() {
+        use crate::query::Collation;
+
+        let manager = IndexManager::new("users".to_string());
+        manager.register_index(IndexDefinition::hashed("user".to_string())).unwrap();
+        manager
+            .register_index(
+                IndexDefinition::single("email".to_string())
+                    .with_collation(Collation::new("en").with_strength(2))
+                    .unique(),
+            )
+            .unwrap();
+
+        let mut doc = Document::new();
+        doc.insert("user".to_string(), Value::from("ann"));
+        doc.insert("email".to_string(), Value::from("Ann@Example.com"));
+        manager.insert_document(doc.id, &doc).unwrap();
+        let mut other = Document::new();
+        other.insert("email".to_string(), Value::from("ann@example.com"));
+        assert!(matches!(
+            manager.insert_document(other.id, &other),
+            Err(IndexError::UniqueConstraintViolation { .. })
+        ));
+
+        // Distinct values may share a hash, so a hashed index cannot be unique
+        let unique_hashed = IndexDefinition::hashed("id".to_string()).unique();
+        assert!(matches!(
+            manager.register_index(unique_hashed),
+            Err(IndexError::InvalidDefinition { .. })
+        ));
+        let bad_collation = IndexDefinition::single("name".to_string()).with_collation(Collation::new("en").with_strength(0));
+        assert!(matches!(
+            manager.register_index(bad_collation),
+            Err(IndexError::InvalidDefinition { .. })
+        ));
+    }
+
+    #[test]
+    fn test_vector_index_search_and_validation() {
+        use crate::schema::VectorMetric;
+
+        let manager = IndexManager::new("items".to_string());
+        manager
+            .register_index(IndexDefinition::vector("embedding".to_string(), 2, VectorMetric::L2))
+            .unwrap();
+        assert_eq!(manager.vector_index_for("embedding").as_deref(), Some("idx_embedding_vector"));
+        assert_eq!(manager.vector_index_for("name"), None);
+
+        let mut docs = Vec::new();
+        for (x, y) in [(0.0, 0.0), (1.0, 0.0), (5.0, 5.0)] {
+            let mut doc = Document::new();
+            doc.insert(
+                "embedding".to_string(),
+                Value::Array(vec![Value::Float64(x), Value::Float64(y)]),
+            );
+            manager.insert_document(doc.id, &doc).unwrap();
+            docs.push(doc);
+        }
+        // A value of the wrong shape is simply not indexed
+        let mut odd = Document::new();
+        odd.insert("embedding".to_string(), Value::from("none"));
+        manager.insert_document(odd.id, &odd).unwrap();
+
+        let found = manager.vector_search("idx_embedding_vector", &[0.9, 0.0], 2, 10, |_| true).unwrap();
+        assert_eq!(found.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![docs[1].id, docs[0].id]);
+
+        // Moving a vector moves it in the graph; deleting drops it
+        let mut moved = docs[2].clone();
+        moved.insert("embedding".to_string(), Value::Array(vec![Value::Float64(1.0), Value::Float64(0.1)]));
+        manager.update_document(docs[2].id, &docs[2], &moved).unwrap();
+        manager.remove_document(docs[1].id, &docs[1]).unwrap();
+        let found = manager.vector_search("idx_embedding_vector", &[0.9, 0.0], 1, 10, |_| true).unwrap();
+        assert_eq!(found[0].0, docs[2].id);
+
+        assert!(matches!(
+            manager.vector_search("idx_embedding_vector", &[1.0], 1, 10, |_| true),
+            Err(IndexError::VectorDimensions { expected: 2, actual: 1 })
+        ));
+        manager.register_index(IndexDefinition::single("name".to_string())).unwrap();
+        assert!(matches!(
+            manager.vector_search("idx_name", &[1.0, 0.0], 1, 10, |_| true),
+            Err(IndexError::InvalidDefinition { .. })
+        ));
+        for invalid in [
+            IndexDefinition::vector("v".to_string(), 0, VectorMetric::Cosine),
+            IndexDefinition::vector("v".to_string(), 3, VectorMetric::Dot).unique(),
+        ] {
+            assert!(matches!(
+                manager.register_index(invalid),
+                Err(IndexError::InvalidDefinition { .. })
+            ));
+        }
+    }
+}
@@ -5,13 +5,17 @@
 //! - Compound indexes
 //! - Unique indexes
 //! - Background index building
+//! - Vector similarity indexes (HNSW)
 
 pub mod btree;
 pub mod manager;
 pub mod builder;
+pub mod encoding;
 pub mod statistics;
+pub mod vector;
 
 pub use btree::{BTreeIndex, IndexEntry, IndexKey};
 pub use manager::IndexManager;
 pub use builder::IndexBuilder;
-pub use statistics::IndexStatistics;
\ No newline at end of file
+pub use statistics::{CollectionStatistics, IndexStatistics, KeyDistribution};
+pub use vector::{VectorIndex, VectorSpec};
\ No newline at end of file
@@ -1,9 +1,14 @@
 //! Index statistics tracking
 //!
-//! Tracks performance metrics and usage statistics for indexes
+//! Tracks performance metrics and usage statistics for indexes, and the
+//! key distributions the query planner uses to estimate selectivity
 
+use super::btree::IndexValue;
+use crate::document::Value;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 /// Index statistics for performance monitoring
@@ -242,6 +247,340 @@ pub struct GlobalStats {
     pub total_lookups: u64,
 }
 
+/// Statistics the query planner reads for one collection
+#[derive(Debug, Clone, Default)]
+pub struct CollectionStatistics {
+    /// Documents in the collection, taken from the largest index holding
+    /// every document or from the collection's document count
+    pub documents: u64,
+    /// Key distribution of each index by name, shared with the index
+    pub indexes: HashMap<String, Arc<KeyDistribution>>,
+}
+
+/// Number of buckets in each equi-depth histogram
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+/// Distribution of the keys in one index
+///
+/// Built from the whole index by [`analyze`](crate::index::BTreeIndex::analyze)
+/// and adjusted on every insert and remove. Distinct counts stay exact;
+/// histogram bucket boundaries drift until the next analysis.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyDistribution {
+    /// Number of indexed documents
+    pub entries: u64,
+    /// Distinct key prefixes; entry `i` counts prefixes of `i + 1` fields
+    pub distinct_prefixes: Vec<u64>,
+    /// Equi-depth histogram of each indexed field
+    pub histograms: Vec<Histogram>,
+    /// Entries inserted or removed since the last analysis
+    pub modifications: u64,
+}
+
+impl KeyDistribution {
+    /// Build a distribution from per-field value counts
+    ///
+    /// `values[i]` maps each value of field `i` to its number of entries.
+    pub fn build(entries: u64, distinct_prefixes: Vec<u64>, values: &[BTreeMap<IndexValue, u64>]) -> Self {
+        Self {
+            entries,
+            distinct_prefixes,
+            histograms: values.iter().map(Histogram::build).collect(),
+            modifications: 0,
+        }
+    }
+
+    /// Number of distinct full keys
+    pub fn distinct_keys(&self) -> u64 {
+        self.distinct_prefixes.last().copied().unwrap_or(0)
+    }
+
+    /// Account for an entry added under `values`
+    ///
+    /// `shared` is the longest prefix the key shares with a key already in
+    /// the index (all fields when the key itself was present).
+    pub fn record_insert(&mut self, values: &[IndexValue], shared: usize) {
+        self.ensure_fields(values.len());
+        self.entries += 1;
+        self.modifications += 1;
+        for count in self.distinct_prefixes.iter_mut().skip(shared) {
+            *count += 1;
+        }
+        for (histogram, value) in self.histograms.iter_mut().zip(values) {
+            histogram.add(value);
+        }
+    }
+
+    /// Account for an entry removed from under `values`
+    ///
+    /// `shared` is the longest prefix the key shares with a key still in
+    /// the index (all fields when the key itself remains).
+    pub fn record_remove(&mut self, values: &[IndexValue], shared: usize) {
+        self.ensure_fields(values.len());
+        self.entries = self.entries.saturating_sub(1);
+        self.modifications += 1;
+        for count in self.distinct_prefixes.iter_mut().skip(shared) {
+            *count = count.saturating_sub(1);
+        }
+        // Only the leading field's value is known to be gone from the index;
+        // the other fields may still hold it under another prefix
+        for (position, (histogram, value)) in self.histograms.iter_mut().zip(values).enumerate() {
+            histogram.remove(value, position == 0 && shared == 0);
+        }
+    }
+
+    fn ensure_fields(&mut self, fields: usize) {
+        if self.distinct_prefixes.len() < fields {
+            self.distinct_prefixes.resize(fields, 0);
+        }
+        if self.histograms.len() < fields {
+            self.histograms.resize_with(fields, Histogram::default);
+        }
+    }
+
+    /// Estimate how many entries have leading fields equal to `prefix` and
+    /// the next field between `lower` and `upper`
+    ///
+    /// The first prefix value is looked up in its histogram; each further
+    /// prefix field divides by the average fan-out of that field. A range
+    /// is assumed independent of the prefix.
+    pub fn estimate_rows(
+        &self,
+        prefix: &[IndexValue],
+        lower: Option<(&IndexValue, bool)>,
+        upper: Option<(&IndexValue, bool)>,
+    ) -> f64 {
+        let total = self.entries as f64;
+        if total == 0.0 {
+            return 0.0;
+        }
+
+        let mut rows = total;
+        for (position, value) in prefix.iter().enumerate() {
+            rows = if position == 0 {
+                match self.histograms.first() {
+                    Some(histogram) => histogram.equal_rows(value),
+                    None => total / self.distinct_prefixes.first().copied().unwrap_or(1).max(1) as f64,
+                }
+            } else {
+                let before = self.distinct_prefixes.get(position - 1).copied().unwrap_or(1).max(1);
+                let after = self.distinct_prefixes.get(position).copied().unwrap_or(1).max(1);
+                rows * before as f64 / after as f64
+            };
+        }
+
+        if lower.is_some() || upper.is_some() {
+            if let Some(histogram) = self.histograms.get(prefix.len()) {
+                rows *= histogram.range_rows(lower, upper) / total;
+            }
+        }
+
+        rows.clamp(0.0, total)
+    }
+
+    /// Describe the distribution as a document value
+    pub fn to_value(&self) -> Value {
+        let count = |n: u64| Value::Int64(n as i64);
+        let histograms = self
+            .histograms
+            .iter()
+            .map(|histogram| {
+                Value::Array(
+                    histogram
+                        .buckets
+                        .iter()
+                        .map(|bucket| {
+                            let bound = |v: &IndexValue| v.to_value().unwrap_or(Value::Null);
+                            Value::Object(BTreeMap::from([
+                                ("lower".to_string(), bound(&bucket.lower)),
+                                ("upper".to_string(), bound(&bucket.upper)),
+                                ("count".to_string(), count(bucket.count)),
+                                ("distinct".to_string(), count(bucket.distinct)),
+                            ]))
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+
+        Value::Object(BTreeMap::from([
+            ("entries".to_string(), count(self.entries)),
+            ("distinct_keys".to_string(), count(self.distinct_keys())),
+            (
+                "distinct_prefixes".to_string(),
+                Value::Array(self.distinct_prefixes.iter().map(|n| count(*n)).collect()),
+            ),
+            ("histograms".to_string(), Value::Array(histograms)),
+            ("modifications".to_string(), count(self.modifications)),
+        ]))
+    }
+}
+
+/// Equi-depth histogram over the values of one field
+///
+/// Buckets are ordered, do not overlap and never split a value, so each
+/// holds roughly the same number of entries.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Histogram {
+    /// Buckets in value order
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// Values between `lower` and `upper`, both inclusive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    /// Smallest value in the bucket
+    pub lower: IndexValue,
+    /// Largest value in the bucket
+    pub upper: IndexValue,
+    /// Number of entries in the bucket
+    pub count: u64,
+    /// Number of distinct values in the bucket
+    pub distinct: u64,
+}
+
+impl Histogram {
+    /// Build a histogram from value counts in value order
+    pub fn build(values: &BTreeMap<IndexValue, u64>) -> Self {
+        let total: u64 = values.values().sum();
+        let depth = total.div_ceil(HISTOGRAM_BUCKETS as u64).max(1);
+
+        let mut buckets: Vec<HistogramBucket> = Vec::new();
+        for (value, count) in values {
+            match buckets.last_mut() {
+                Some(bucket) if bucket.count < depth => {
+                    bucket.upper = value.clone();
+                    bucket.count += count;
+                    bucket.distinct += 1;
+                }
+                _ => buckets.push(HistogramBucket {
+                    lower: value.clone(),
+                    upper: value.clone(),
+                    count: *count,
+                    distinct: 1,
+                }),
+            }
+        }
+
+        Self { buckets }
+    }
+
+    /// Count one more entry with `value`
+    ///
+    /// A value past either end of the histogram is new; it joins the edge
+    /// bucket while that bucket is below the average depth and starts a
+    /// bucket of its own otherwise. Values between buckets widen the
+    /// bucket below them.
+    pub fn add(&mut self, value: &IndexValue) {
+        let total: u64 = self.buckets.iter().map(|bucket| bucket.count).sum();
+        let depth = (total + 1).div_ceil(HISTOGRAM_BUCKETS as u64).max(1);
+        let single = HistogramBucket {
+            lower: value.clone(),
+            upper: value.clone(),
+            count: 1,
+            distinct: 1,
+        };
+
+        let position = self.buckets.partition_point(|bucket| bucket.lower <= *value);
+        if position == 0 {
+            match self.buckets.first_mut() {
+                Some(bucket) if bucket.count < depth => {
+                    bucket.lower = value.clone();
+                    bucket.count += 1;
+                    bucket.distinct += 1;
+                }
+                _ => self.buckets.insert(0, single),
+            }
+            return;
+        }
+
+        let last = position == self.buckets.len();
+        let bucket = &mut self.buckets[position - 1];
+        if *value <= bucket.upper {
+            bucket.count += 1;
+        } else if !last || bucket.count < depth {
+            bucket.upper = value.clone();
+            bucket.count += 1;
+            bucket.distinct += 1;
+        } else {
+            self.buckets.push(single);
+        }
+    }
+
+    /// Count one fewer entry with `value`
+    ///
+    /// `last` tells that no entry with `value` remains, so the bucket holds
+    /// one distinct value fewer. A bucket left without entries is dropped.
+    pub fn remove(&mut self, value: &IndexValue, last: bool) {
+        let Some(position) = self
+            .buckets
+            .iter()
+            .position(|bucket| bucket.lower <= *value && *value <= bucket.upper)
+        else {
+            return;
+        };
+
+        let bucket = &mut self.buckets[position];
+        bucket.count = bucket.count.saturating_sub(1);
+        if bucket.count == 0 {
+            self.buckets.remove(position);
+            return;
+        }
+        if last {
+            bucket.distinct = bucket.distinct.saturating_sub(1);
+        }
+        bucket.distinct = bucket.distinct.clamp(1, bucket.count);
+    }
+
+    /// Estimate the entries equal to `value`
+    pub fn equal_rows(&self, value: &IndexValue) -> f64 {
+        self.buckets
+            .iter()
+            .find(|bucket| bucket.lower <= *value && *value <= bucket.upper)
+            .map(|bucket| bucket.count as f64 / bucket.distinct.max(1) as f64)
+            .unwrap_or(0.0)
+    }
+
+    /// Estimate the entries between `lower` and `upper`
+    ///
+    /// Buckets cut by a bound contribute the interpolated share of their
+    /// entries, or a third per cut when the values cannot be interpolated,
+    /// and never less than one value's worth.
+    pub fn range_rows(&self, lower: Option<(&IndexValue, bool)>, upper: Option<(&IndexValue, bool)>) -> f64 {
+        self.buckets
+            .iter()
+            .map(|bucket| {
+                if let Some((low, inclusive)) = lower {
+                    if bucket.upper < *low || (bucket.upper == *low && !inclusive) {
+                        return 0.0;
+                    }
+                }
+                if let Some((high, inclusive)) = upper {
+                    if bucket.lower > *high || (bucket.lower == *high && !inclusive) {
+                        return 0.0;
+                    }
+                }
+
+                let start = match lower {
+                    Some((low, _)) if *low > bucket.lower => {
+                        low.interpolate(&bucket.lower, &bucket.upper).unwrap_or(1.0 / 3.0)
+                    }
+                    _ => 0.0,
+                };
+                let end = match upper {
+                    Some((high, _)) if *high < bucket.upper => {
+                        high.interpolate(&bucket.lower, &bucket.upper).unwrap_or(2.0 / 3.0)
+                    }
+                    _ => 1.0,
+                };
+
+                let count = bucket.count as f64;
+                (count * (end - start).max(0.0)).max(count / bucket.distinct.max(1) as f64)
+            })
+            .sum()
+    }
+}
+
 /// Performance metrics helper
 pub struct PerformanceTimer {
     start: Instant,
@@ -391,6 +730,113 @@ mod tests {
         assert!(elapsed >= Duration::from_millis(10));
     }
 
+    fn counts(values: impl IntoIterator<Item = (i64, u64)>) -> BTreeMap<IndexValue, u64> {
+        values.into_iter().map(|(v, n)| (IndexValue::Int(v), n)).collect()
+    }
+
+    #[test]
+    fn test_histogram_is_equi_depth() {
+        let histogram = Histogram::build(&counts((0..320).map(|v| (v, 1))));
+        assert_eq!(histogram.buckets.len(), HISTOGRAM_BUCKETS);
+        assert!(histogram.buckets.iter().all(|b| b.count == 10 && b.distinct == 10));
+
+        assert_eq!(histogram.equal_rows(&IndexValue::Int(5)), 1.0);
+        assert_eq!(histogram.equal_rows(&IndexValue::Int(1000)), 0.0);
+
+        let low = IndexValue::Int(0);
+        let high = IndexValue::Int(100);
+        let rows = histogram.range_rows(Some((&low, true)), Some((&high, false)));
+        assert!((rows - 100.0).abs() < 2.0, "estimated {}", rows);
+        assert_eq!(histogram.range_rows(Some((&IndexValue::Int(400), true)), None), 0.0);
+    }
+
+    #[test]
+    fn test_histogram_keeps_skewed_value_in_one_bucket() {
+        // One value holds most entries; it must not be split across buckets
+        let histogram = Histogram::build(&counts([(1, 900), (2, 50), (3, 50)]));
+        assert_eq!(histogram.equal_rows(&IndexValue::Int(1)), 900.0);
+        assert!(histogram.equal_rows(&IndexValue::Int(3)) <= 50.0);
+    }
+
+    #[test]
+    fn test_histogram_absorbs_values_outside_buckets() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.range_rows(None, None), 0.0);
+
+        histogram.add(&IndexValue::Int(10));
+        histogram.add(&IndexValue::Int(5));
+        histogram.add(&IndexValue::Int(20));
+        assert_eq!(histogram.buckets.len(), 3);
+        assert_eq!(histogram.equal_rows(&IndexValue::Int(20)), 1.0);
+        assert_eq!(histogram.range_rows(None, None), 3.0);
+
+        histogram.remove(&IndexValue::Int(20), true);
+        histogram.remove(&IndexValue::Int(99), true);
+        assert_eq!(histogram.range_rows(None, None), 2.0);
+
+        // Appending in order keeps buckets near the average depth
+        let mut appended = Histogram::default();
+        for value in 0..3200 {
+            appended.add(&IndexValue::Int(value));
+        }
+        let deepest = appended.buckets.iter().map(|b| b.count).max().unwrap();
+        assert!(deepest <= 100, "deepest bucket holds {}", deepest);
+        assert!(appended.buckets.len() < 200, "{} buckets", appended.buckets.len());
+    }
+
+    #[test]
+    fn test_histogram_remove_drops_distinct_values() {
+        let mut histogram = Histogram {
+            buckets: vec![HistogramBucket {
+                lower: IndexValue::Int(1),
+                upper: IndexValue::Int(3),
+                count: 4,
+                distinct: 3,
+            }],
+        };
+
+        // One of two entries for 1 goes; the value remains
+        histogram.remove(&IndexValue::Int(1), false);
+        assert_eq!(histogram.buckets[0].distinct, 3);
+
+        histogram.remove(&IndexValue::Int(2), true);
+        assert_eq!(histogram.buckets[0].distinct, 2);
+        assert_eq!(histogram.equal_rows(&IndexValue::Int(3)), 1.0);
+
+        histogram.remove(&IndexValue::Int(1), true);
+        histogram.remove(&IndexValue::Int(3), true);
+        assert!(histogram.buckets.is_empty());
+
+        // Removing the last entry for a leading value counts it out
+        let mut distribution = KeyDistribution::build(3, vec![3], &[counts([(1, 1), (2, 1), (3, 1)])]);
+        distribution.record_remove(&[IndexValue::Int(2)], 0);
+        assert_eq!(distribution.distinct_keys(), 2);
+        assert_eq!(distribution.histograms[0].buckets.len(), 2);
+        assert_eq!(distribution.estimate_rows(&[IndexValue::Int(1)], None, None), 1.0);
+    }
+
+    #[test]
+    fn test_estimate_rows_uses_prefix_fanout() {
+        // 100 entries over 4 leading values, each with 5 distinct second values
+        let first = counts((0..4).map(|v| (v, 25)));
+        let second = counts((0..5).map(|v| (v, 20)));
+        let distribution = KeyDistribution::build(100, vec![4, 20], &[first, second]);
+
+        assert_eq!(distribution.distinct_keys(), 20);
+        assert_eq!(distribution.estimate_rows(&[], None, None), 100.0);
+        assert_eq!(distribution.estimate_rows(&[IndexValue::Int(1)], None, None), 25.0);
+        assert_eq!(
+            distribution.estimate_rows(&[IndexValue::Int(1), IndexValue::Int(2)], None, None),
+            5.0
+        );
+
+        let low = IndexValue::Int(3);
+        let rows = distribution.estimate_rows(&[IndexValue::Int(1)], Some((&low, true)), None);
+        assert!(rows > 0.0 && rows < 25.0, "estimated {}", rows);
+
+        assert_eq!(KeyDistribution::default().estimate_rows(&[IndexValue::Int(1)], None, None), 0.0);
+    }
+
     #[test]
     fn test_reset_statistics() {
         let mut stats = IndexStatistics::new();
@@ -0,0 +1,575 @@
+//! Vector similarity index
+//!
+//! An HNSW (hierarchical navigable small world) graph over the vectors of
+//! one field. Every node links to its nearest neighbours on each layer up to
+//! its own level; a search descends greedily from the top layer and widens
+//! to a list of candidates on the bottom layer.
+
+use super::btree::{IndexError, IndexValue};
+use crate::document::{DocumentId, Value};
+use crate::schema::VectorMetric;
+use sha2::{Digest, Sha256};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::RwLock;
+
+/// Most dimensions a vector index accepts
+pub const MAX_VECTOR_DIMS: usize = 4096;
+
+/// Links per node on the upper layers; the bottom layer keeps twice as many
+const LINKS: usize = 16;
+
+/// Candidates considered while linking a new node
+const EF_CONSTRUCTION: usize = 100;
+
+/// Highest layer a node can be placed on
+const MAX_LEVEL: usize = 16;
+
+/// Dimensions and metric of a vector index
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VectorSpec {
+    /// Length of every indexed vector
+    pub dims: usize,
+    /// How similarity is measured
+    pub metric: VectorMetric,
+}
+
+impl VectorSpec {
+    /// Create a spec
+    pub fn new(dims: usize, metric: VectorMetric) -> Self {
+        Self { dims, metric }
+    }
+
+    /// The vector a field value holds, if it fits the index
+    ///
+    /// The value must be an array of `dims` finite numbers; cosine also
+    /// rejects the zero vector, which has no direction.
+    pub fn vector(&self, value: &Value) -> Option<Vec<f64>> {
+        let Value::Array(elements) = value else {
+            return None;
+        };
+        if elements.len() != self.dims {
+            return None;
+        }
+        let vector = elements
+            .iter()
+            .map(|element| match element {
+                Value::Int32(i) => Some(*i as f64),
+                Value::Int64(i) => Some(*i as f64),
+                Value::Float64(f) if f.is_finite() => Some(*f),
+                _ => None,
+            })
+            .collect::<Option<Vec<f64>>>()?;
+        if self.metric == VectorMetric::Cosine && norm(&vector) == 0.0 {
+            return None;
+        }
+        Some(vector)
+    }
+
+    /// Index key of a field value: the vector as little-endian `f64` bytes
+    ///
+    /// Values that are not a vector of the index get no entry.
+    pub fn key(&self, value: Option<&Value>) -> Option<IndexValue> {
+        let vector = self.vector(value?)?;
+        Some(IndexValue::Binary(vector.iter().flat_map(|x| x.to_le_bytes()).collect()))
+    }
+
+    /// Vector stored in an index key
+    pub fn decode(&self, value: &IndexValue) -> Result<Vec<f64>, IndexError> {
+        match value {
+            IndexValue::Binary(bytes) if bytes.len() == self.dims * 8 => Ok(bytes
+                .chunks_exact(8)
+                .map(|chunk| {
+                    let mut raw = [0u8; 8];
+                    raw.copy_from_slice(chunk);
+                    f64::from_le_bytes(raw)
+                })
+                .collect()),
+            other => Err(IndexError::CorruptEntry(format!(
+                "expected a {}-dimensional vector, found {:?}",
+                self.dims, other
+            ))),
+        }
+    }
+
+    /// Similarity reported for a distance; larger is more similar
+    ///
+    /// Cosine reports the cosine similarity, dot the dot product and L2
+    /// `1 / (1 + distance)`.
+    pub fn score(&self, distance: f64) -> f64 {
+        match self.metric {
+            VectorMetric::Cosine => 1.0 - distance,
+            VectorMetric::L2 => 1.0 / (1.0 + distance.sqrt()),
+            VectorMetric::Dot => -distance,
+        }
+    }
+
+    /// Distance that ranks neighbours; smaller is nearer
+    fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        match self.metric {
+            // Cosine vectors are normalized before they reach the graph
+            VectorMetric::Cosine => 1.0 - dot(a, b),
+            VectorMetric::L2 => a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum(),
+            VectorMetric::Dot => -dot(a, b),
+        }
+    }
+
+    /// Check a vector's length and bring it to the form the graph stores
+    fn prepare(&self, mut vector: Vec<f64>) -> Result<Vec<f64>, IndexError> {
+        if vector.len() != self.dims {
+            return Err(IndexError::VectorDimensions {
+                expected: self.dims,
+                actual: vector.len(),
+            });
+        }
+        if vector.iter().any(|x| !x.is_finite()) {
+            return Err(IndexError::UnsupportedValueType("vector with a non-finite component".to_string()));
+        }
+        if self.metric == VectorMetric::Cosine {
+            let length = norm(&vector);
+            if length == 0.0 {
+                return Err(IndexError::UnsupportedValueType("zero vector under cosine similarity".to_string()));
+            }
+            vector.iter_mut().for_each(|x| *x /= length);
+        }
+        Ok(vector)
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn norm(vector: &[f64]) -> f64 {
+    dot(vector, vector).sqrt()
+}
+
+/// Most links a node keeps on a layer
+fn max_links(layer: usize) -> usize {
+    if layer == 0 {
+        LINKS * 2
+    } else {
+        LINKS
+    }
+}
+
+/// Layer a document's node is placed on
+///
+/// Drawn from the exponential distribution HNSW uses, seeded by the
+/// document id so a rebuilt graph places every node where it was.
+fn level_for(id: &DocumentId) -> usize {
+    let digest = Sha256::digest(id.to_bytes());
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&digest[..8]);
+    let uniform = (u64::from_le_bytes(raw) >> 11) as f64 / (1u64 << 53) as f64;
+    let level = -(1.0 - uniform).ln() / (LINKS as f64).ln();
+    (level as usize).min(MAX_LEVEL)
+}
+
+/// Node ranked by its distance to a query
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f64,
+    id: DocumentId,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then_with(|| self.id.cmp(&other.id))
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    vector: Vec<f64>,
+    /// Neighbours on each layer from 0 up to the node's level
+    links: Vec<Vec<DocumentId>>,
+}
+
+#[derive(Debug, Default)]
+struct Graph {
+    nodes: HashMap<DocumentId, Node>,
+    /// Node on the top layer where every search starts
+    entry: Option<DocumentId>,
+}
+
+impl Graph {
+    fn top_level(&self) -> usize {
+        self.entry
+            .and_then(|id| self.nodes.get(&id))
+            .map(|node| node.links.len() - 1)
+            .unwrap_or(0)
+    }
+
+    /// Nearest node to `query` on each layer above `floor`, starting at the entry
+    fn descend(&self, spec: &VectorSpec, query: &[f64], floor: usize) -> Vec<Candidate> {
+        let Some(entry) = self.entry.and_then(|id| self.nodes.get(&id).map(|node| (id, node))) else {
+            return Vec::new();
+        };
+        let mut nearest = vec![Candidate {
+            distance: spec.distance(query, &entry.1.vector),
+            id: entry.0,
+        }];
+        for layer in (floor + 1..=self.top_level()).rev() {
+            nearest = self.search_layer(spec, query, nearest, 1, layer, &mut |_| true);
+        }
+        nearest
+    }
+
+    /// Best-first search of one layer, nearest first
+    ///
+    /// Nodes `accept` rejects are still traversed, so a selective filter
+    /// does not cut the graph apart; they are only left out of the results.
+    fn search_layer(
+        &self,
+        spec: &VectorSpec,
+        query: &[f64],
+        entry: Vec<Candidate>,
+        ef: usize,
+        layer: usize,
+        accept: &mut dyn FnMut(DocumentId) -> bool,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<DocumentId> = entry.iter().map(|c| c.id).collect();
+        let mut results: BinaryHeap<Candidate> = BinaryHeap::new();
+        for candidate in &entry {
+            if accept(candidate.id) {
+                results.push(*candidate);
+            }
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+        let mut pending: BinaryHeap<Reverse<Candidate>> = entry.into_iter().map(Reverse).collect();
+
+        while let Some(Reverse(current)) = pending.pop() {
+            let full = results.len() >= ef;
+            if full && results.peek().is_some_and(|worst| current.distance > worst.distance) {
+                break;
+            }
+            let Some(links) = self.nodes.get(&current.id).and_then(|node| node.links.get(layer)) else {
+                continue;
+            };
+            for &id in links {
+                if !visited.insert(id) {
+                    continue;
+                }
+                let Some(node) = self.nodes.get(&id) else {
+                    continue;
+                };
+                let candidate = Candidate {
+                    distance: spec.distance(query, &node.vector),
+                    id,
+                };
+                let closer = results.len() < ef || results.peek().is_some_and(|worst| candidate.distance < worst.distance);
+                if closer {
+                    pending.push(Reverse(candidate));
+                    if accept(id) {
+                        results.push(candidate);
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec()
+    }
+
+    fn insert(&mut self, spec: &VectorSpec, id: DocumentId, vector: Vec<f64>) {
+        let level = level_for(&id);
+        if self.entry.is_none() {
+            self.nodes.insert(id, Node { vector, links: vec![Vec::new(); level + 1] });
+            self.entry = Some(id);
+            return;
+        }
+
+        let top = self.top_level();
+        let mut nearest = self.descend(spec, &vector, level);
+        let mut links = vec![Vec::new(); level + 1];
+        for layer in (0..=level.min(top)).rev() {
+            nearest = self.search_layer(spec, &vector, nearest, EF_CONSTRUCTION, layer, &mut |_| true);
+            links[layer] = nearest.iter().take(max_links(layer)).map(|c| c.id).collect();
+        }
+
+        self.nodes.insert(id, Node { vector, links: links.clone() });
+        for (layer, neighbours) in links.iter().enumerate() {
+            for &neighbour in neighbours {
+                self.link(spec, neighbour, id, layer);
+            }
+        }
+        if level > top {
+            self.entry = Some(id);
+        }
+    }
+
+    /// Add a link from `from` to `to`, keeping only the nearest neighbours
+    fn link(&mut self, spec: &VectorSpec, from: DocumentId, to: DocumentId, layer: usize) {
+        let Some(node) = self.nodes.get(&from) else {
+            return;
+        };
+        let Some(current) = node.links.get(layer) else {
+            return;
+        };
+        if from == to || current.contains(&to) {
+            return;
+        }
+
+        let mut ids = current.clone();
+        ids.push(to);
+        if ids.len() > max_links(layer) {
+            let mut ranked: Vec<Candidate> = ids
+                .iter()
+                .filter_map(|id| {
+                    self.nodes.get(id).map(|other| Candidate {
+                        distance: spec.distance(&node.vector, &other.vector),
+                        id: *id,
+                    })
+                })
+                .collect();
+            ranked.sort();
+            ids = ranked.into_iter().take(max_links(layer)).map(|c| c.id).collect();
+        }
+        if let Some(node) = self.nodes.get_mut(&from) {
+            node.links[layer] = ids;
+        }
+    }
+
+    /// Drop a node and reconnect its neighbours to each other
+    ///
+    /// Links are not symmetric, so other nodes may still point at the
+    /// removed one; searches skip such links and pruning drops them.
+    fn remove(&mut self, spec: &VectorSpec, id: &DocumentId) -> bool {
+        let Some(node) = self.nodes.remove(id) else {
+            return false;
+        };
+        for (layer, neighbours) in node.links.iter().enumerate() {
+            for &neighbour in neighbours {
+                if let Some(links) = self.nodes.get_mut(&neighbour).and_then(|n| n.links.get_mut(layer)) {
+                    links.retain(|linked| linked != id);
+                }
+                for &other in neighbours {
+                    self.link(spec, neighbour, other, layer);
+                }
+            }
+        }
+        if self.entry == Some(*id) {
+            self.entry = self
+                .nodes
+                .iter()
+                .max_by(|a, b| a.1.links.len().cmp(&b.1.links.len()).then_with(|| b.0.cmp(a.0)))
+                .map(|(id, _)| *id);
+        }
+        true
+    }
+}
+
+/// HNSW graph over the vectors of one indexed field
+#[derive(Debug)]
+pub struct VectorIndex {
+    spec: VectorSpec,
+    graph: RwLock<Graph>,
+}
+
+impl VectorIndex {
+    /// Create an empty vector index
+    pub fn new(spec: VectorSpec) -> Self {
+        Self {
+            spec,
+            graph: RwLock::new(Graph::default()),
+        }
+    }
+
+    /// Dimensions and metric of the index
+    pub fn spec(&self) -> VectorSpec {
+        self.spec
+    }
+
+    /// Number of indexed vectors
+    pub fn len(&self) -> usize {
+        self.graph.read().unwrap().nodes.len()
+    }
+
+    /// Whether the index holds no vectors
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Index a document's vector, replacing any vector it had
+    pub fn insert(&self, id: DocumentId, vector: Vec<f64>) -> Result<(), IndexError> {
+        let vector = self.spec.prepare(vector)?;
+        let mut graph = self.graph.write().unwrap();
+        graph.remove(&self.spec, &id);
+        graph.insert(&self.spec, id, vector);
+        Ok(())
+    }
+
+    /// Remove a document's vector
+    pub fn remove(&self, id: &DocumentId) -> bool {
+        self.graph.write().unwrap().remove(&self.spec, id)
+    }
+
+    /// Remove every vector
+    pub fn clear(&self) {
+        *self.graph.write().unwrap() = Graph::default();
+    }
+
+    /// The `k` nearest documents `accept` lets through, most similar first
+    ///
+    /// `num_candidates` sizes the candidate list of the bottom layer; a
+    /// larger list finds the true neighbours more often at the cost of
+    /// more distance computations. Each result carries its similarity
+    /// score.
+    pub fn search<F>(
+        &self,
+        query: &[f64],
+        k: usize,
+        num_candidates: usize,
+        mut accept: F,
+    ) -> Result<Vec<(DocumentId, f64)>, IndexError>
+    where
+        F: FnMut(DocumentId) -> bool,
+    {
+        let query = self.spec.prepare(query.to_vec())?;
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        let graph = self.graph.read().unwrap();
+        let entry = graph.descend(&self.spec, &query, 0);
+        let found = graph.search_layer(&self.spec, &query, entry, num_candidates.max(k), 0, &mut accept);
+        Ok(found
+            .into_iter()
+            .take(k)
+            .map(|c| (c.id, self.spec.score(c.distance)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random vectors
+    fn vectors(count: usize, dims: usize, mut seed: u64) -> Vec<Vec<f64>> {
+        let mut next = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((seed >> 33) as f64 / (1u64 << 31) as f64) * 2.0 - 1.0
+        };
+        (0..count).map(|_| (0..dims).map(|_| next()).collect()).collect()
+    }
+
+    fn brute_force(spec: &VectorSpec, data: &[(DocumentId, Vec<f64>)], query: &[f64], k: usize) -> Vec<DocumentId> {
+        let query = spec.prepare(query.to_vec()).unwrap();
+        let mut ranked: Vec<Candidate> = data
+            .iter()
+            .map(|(id, v)| Candidate {
+                distance: spec.distance(&query, &spec.prepare(v.clone()).unwrap()),
+                id: *id,
+            })
+            .collect();
+        ranked.sort();
+        ranked.into_iter().take(k).map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn test_search_recalls_nearest_neighbours() {
+        for metric in [VectorMetric::Cosine, VectorMetric::L2, VectorMetric::Dot] {
+            let spec = VectorSpec::new(8, metric);
+            let index = VectorIndex::new(spec);
+            let data: Vec<(DocumentId, Vec<f64>)> =
+                vectors(300, 8, 7).into_iter().map(|v| (DocumentId::new(), v)).collect();
+            for (id, vector) in &data {
+                index.insert(*id, vector.clone()).unwrap();
+            }
+            assert_eq!(index.len(), 300);
+
+            let mut hits = 0;
+            let queries = vectors(20, 8, 99);
+            for query in &queries {
+                let expected = brute_force(&spec, &data, query, 10);
+                let found = index.search(query, 10, 64, |_| true).unwrap();
+                assert_eq!(found.len(), 10);
+                assert!(found.windows(2).all(|w| w[0].1 >= w[1].1), "scores must descend");
+                hits += found.iter().filter(|(id, _)| expected.contains(id)).count();
+            }
+            assert!(hits >= 180, "{:?} recall too low: {}/200", metric, hits);
+        }
+    }
+
+    #[test]
+    fn test_remove_and_filter() {
+        let spec = VectorSpec::new(4, VectorMetric::L2);
+        let index = VectorIndex::new(spec);
+        let data: Vec<(DocumentId, Vec<f64>)> =
+            vectors(120, 4, 3).into_iter().map(|v| (DocumentId::new(), v)).collect();
+        for (id, vector) in &data {
+            index.insert(*id, vector.clone()).unwrap();
+        }
+
+        // An identical vector scores 1 under L2
+        let (target, vector) = &data[5];
+        let found = index.search(vector, 1, 32, |_| true).unwrap();
+        assert_eq!(found[0].0, *target);
+        assert!((found[0].1 - 1.0).abs() < 1e-9);
+
+        // Removing every other node, entry point included, keeps the rest reachable
+        let removed: HashSet<DocumentId> = data.iter().step_by(2).map(|(id, _)| *id).collect();
+        for id in &removed {
+            assert!(index.remove(id));
+        }
+        assert!(!index.remove(&data[0].0));
+        assert_eq!(index.len(), 60);
+        let found = index.search(&data[1].1, 60, 60, |_| true).unwrap();
+        assert_eq!(found.len(), 60);
+        assert!(found.iter().all(|(id, _)| !removed.contains(id)));
+
+        // The filter applies during the search, so k results still come back
+        let allowed: HashSet<DocumentId> = data.iter().skip(1).step_by(4).map(|(id, _)| *id).collect();
+        let found = index.search(&data[3].1, 5, 20, |id| allowed.contains(&id)).unwrap();
+        assert_eq!(found.len(), 5);
+        assert!(found.iter().all(|(id, _)| allowed.contains(id)));
+
+        index.clear();
+        assert!(index.is_empty());
+        assert!(index.search(&data[1].1, 3, 10, |_| true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_spec_keys_and_errors() {
+        let spec = VectorSpec::new(3, VectorMetric::Cosine);
+        let value = Value::Array(vec![Value::Float64(0.5), Value::Int32(2), Value::Int64(-1)]);
+        let key = spec.key(Some(&value)).unwrap();
+        assert_eq!(spec.decode(&key).unwrap(), vec![0.5, 2.0, -1.0]);
+
+        // Wrong length, non-numbers and the zero vector get no entry
+        assert!(spec.key(Some(&Value::Array(vec![Value::Float64(1.0)]))).is_none());
+        assert!(spec
+            .key(Some(&Value::Array(vec![Value::Float64(1.0), Value::String("x".into()), Value::Null])))
+            .is_none());
+        assert!(spec.key(Some(&Value::Array(vec![Value::Float64(0.0); 3]))).is_none());
+        assert!(spec.key(None).is_none());
+        assert!(spec.decode(&IndexValue::Binary(vec![0; 7])).is_err());
+
+        let index = VectorIndex::new(spec);
+        assert!(matches!(
+            index.insert(DocumentId::new(), vec![1.0, 2.0]),
+            Err(IndexError::VectorDimensions { expected: 3, actual: 2 })
+        ));
+        assert!(index.search(&[0.0, 0.0, 0.0], 1, 10, |_| true).is_err());
+
+        let id = DocumentId::new();
+        index.insert(id, vec![1.0, 0.0, 0.0]).unwrap();
+        index.insert(id, vec![0.0, 1.0, 0.0]).unwrap();
+        assert_eq!(index.len(), 1);
+        let found = index.search(&[0.0, 2.0, 0.0], 1, 10, |_| true).unwrap();
+        assert_eq!(found[0].0, id);
+        assert!((found[0].1 - 1.0).abs() < 1e-9);
+    }
+}
@@ -54,11 +54,17 @@ pub enum OpCode {
     CreateCollection = 0x16,
     DropCollection = 0x17,
     ListCollections = 0x18,
+    ModifyCollection = 0x1F,
     
     // Index management
     CreateIndex = 0x19,
     DropIndex = 0x1A,
     ListIndexes = 0x1B,
+    Analyze = 0x1D,
+    Explain = 0x1E,
+    
+    // Views
+    CreateView = 0x1C,
     
     // Advanced data structures - Lists
     LPush = 0x20,
@@ -126,6 +132,25 @@ pub enum OpCode {
     ImportKey = 0x64,
     GetKeyMetadata = 0x65,
     GetKeysExpiring = 0x66,
+    
+    // Query Plan Cache (0x70-0x73)
+    ListPlanCache = 0x70,
+    ClearPlanCache = 0x71,
+
+    // Index Builds (0x74-0x77)
+    ListIndexBuilds = 0x74,
+    GetIndexBuild = 0x75,
+    CancelIndexBuild = 0x76,
+
+    // Capped collections and change streams (0x78-0x79)
+    Tail = 0x78,
+    Watch = 0x79,
+
+    // File storage (0x7A-0x7D)
+    PutFile = 0x7A,
+    GetFile = 0x7B,
+    DeleteFile = 0x7C,
+    ListFiles = 0x7D,
 }
 
 impl TryFrom<u8> for OpCode {
@@ -158,6 +183,10 @@ impl TryFrom<u8> for OpCode {
             0x19 => Ok(OpCode::CreateIndex),
             0x1A => Ok(OpCode::DropIndex),
             0x1B => Ok(OpCode::ListIndexes),
+            0x1C => Ok(OpCode::CreateView),
+            0x1D => Ok(OpCode::Analyze),
+            0x1E => Ok(OpCode::Explain),
+            0x1F => Ok(OpCode::ModifyCollection),
             0x20 => Ok(OpCode::LPush),
             0x21 => Ok(OpCode::RPush),
             0x22 => Ok(OpCode::LPop),
@@ -214,6 +243,25 @@ impl TryFrom<u8> for OpCode {
             0x65 => Ok(OpCode::GetKeyMetadata),
             0x66 => Ok(OpCode::GetKeysExpiring),
             
+            // Query plan cache
+            0x70 => Ok(OpCode::ListPlanCache),
+            0x71 => Ok(OpCode::ClearPlanCache),
+
+            // Index builds
+            0x74 => Ok(OpCode::ListIndexBuilds),
+            0x75 => Ok(OpCode::GetIndexBuild),
+            0x76 => Ok(OpCode::CancelIndexBuild),
+
+            // Capped collections and change streams
+            0x78 => Ok(OpCode::Tail),
+            0x79 => Ok(OpCode::Watch),
+
+            // File storage
+            0x7A => Ok(OpCode::PutFile),
+            0x7B => Ok(OpCode::GetFile),
+            0x7C => Ok(OpCode::DeleteFile),
+            0x7D => Ok(OpCode::ListFiles),
+            
             // Aggregation Pipeline
             0x3F => Ok(OpCode::Aggregate),
             
@@ -248,6 +296,9 @@ pub enum Status {
     CollectionNotFound = 0x0B,
     IndexExists = 0x0C,
     IndexNotFound = 0x0D,
+    DuplicateKey = 0x0E,
+    DocumentValidationFailed = 0x0F,
+    ReferenceViolation = 0x10,
 }
 
 impl TryFrom<u8> for Status {
@@ -269,6 +320,9 @@ impl TryFrom<u8> for Status {
             0x0B => Ok(Status::CollectionNotFound),
             0x0C => Ok(Status::IndexExists),
             0x0D => Ok(Status::IndexNotFound),
+            0x0E => Ok(Status::DuplicateKey),
+            0x0F => Ok(Status::DocumentValidationFailed),
+            0x10 => Ok(Status::ReferenceViolation),
             _ => Err(()),
         }
     }
@@ -456,11 +510,86 @@ impl Command {
         Ok(Self::new(OpCode::CreateCollection, seq, Vec::new(), payload))
     }
 
+    pub fn modify_collection(seq: u32, request: &ModifyCollectionRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::ModifyCollection, seq, Vec::new(), payload))
+    }
+
+    pub fn tail(seq: u32, request: &TailRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::Tail, seq, Vec::new(), payload))
+    }
+
+    pub fn watch(seq: u32, request: &WatchRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::Watch, seq, Vec::new(), payload))
+    }
+
+    pub fn put_file(seq: u32, request: &PutFileRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::PutFile, seq, Vec::new(), payload))
+    }
+
+    pub fn get_file(seq: u32, request: &GetFileRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::GetFile, seq, Vec::new(), payload))
+    }
+
+    pub fn delete_file(seq: u32, request: &DeleteFileRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::DeleteFile, seq, Vec::new(), payload))
+    }
+
+    pub fn list_files(seq: u32, request: &ListFilesRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::ListFiles, seq, Vec::new(), payload))
+    }
+
     pub fn create_index(seq: u32, request: &CreateIndexRequest) -> Result<Self, String> {
         let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
         Ok(Self::new(OpCode::CreateIndex, seq, Vec::new(), payload))
     }
 
+    pub fn create_view(seq: u32, request: &CreateViewRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::CreateView, seq, Vec::new(), payload))
+    }
+
+    pub fn analyze(seq: u32, request: &AnalyzeRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::Analyze, seq, Vec::new(), payload))
+    }
+
+    pub fn explain(seq: u32, request: &ExplainRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::Explain, seq, Vec::new(), payload))
+    }
+
+    pub fn list_plan_cache(seq: u32, request: &ListPlanCacheRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::ListPlanCache, seq, Vec::new(), payload))
+    }
+
+    pub fn clear_plan_cache(seq: u32, request: &ClearPlanCacheRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::ClearPlanCache, seq, Vec::new(), payload))
+    }
+
+    pub fn list_index_builds(seq: u32, request: &ListIndexBuildsRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::ListIndexBuilds, seq, Vec::new(), payload))
+    }
+
+    pub fn get_index_build(seq: u32, request: &GetIndexBuildRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::GetIndexBuild, seq, Vec::new(), payload))
+    }
+
+    pub fn cancel_index_build(seq: u32, request: &CancelIndexBuildRequest) -> Result<Self, String> {
+        let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
+        Ok(Self::new(OpCode::CancelIndexBuild, seq, Vec::new(), payload))
+    }
+
     pub fn list_op(seq: u32, request: &ListOpRequest) -> Result<Self, String> {
         let payload = serde_json::to_vec(request).map_err(|e| format!("Serialization error: {}", e))?;
         let opcode = match &request.operation {
@@ -658,6 +787,18 @@ pub struct QueryRequest {
     pub sort: Option<Value>,
     pub skip: Option<u64>,
     pub limit: Option<u64>,
+    /// String comparison rules for the filter and sort
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collation: Option<crate::query::Collation>,
+    /// Return matches as Extended JSON in `OperationResponse::documents`
+    #[serde(default)]
+    pub extended_json: bool,
+    /// Read the collection as it was at this time; it must keep history
+    #[serde(default, alias = "asOf", skip_serializing_if = "Option::is_none")]
+    pub as_of: Option<chrono::DateTime<chrono::Utc>>,
+    /// Read each document as written in this version; it must keep history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
 }
 
 /// Document insertion request
@@ -667,6 +808,19 @@ pub struct InsertDocRequest {
     pub document: Document,
 }
 
+/// How `UpdateDocRequest::update` is applied to each matching document
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateFormat {
+    /// The update object replaces the document's fields
+    #[default]
+    Replace,
+    /// An RFC 6902 JSON Patch: an array of operations
+    JsonPatch,
+    /// An RFC 7396 Merge Patch object
+    MergePatch,
+}
+
 /// Document update request
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateDocRequest {
@@ -674,6 +828,8 @@ pub struct UpdateDocRequest {
     pub filter: Value,
     pub update: Value,
     pub upsert: bool,
+    #[serde(default)]
+    pub update_format: UpdateFormat,
 }
 
 /// Document deletion request
@@ -687,7 +843,152 @@ pub struct DeleteDocRequest {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateCollectionRequest {
     pub name: String,
-    pub schema: Option<Value>, // JSON schema
+    pub schema: Option<Value>, // JSON Schema subset or native field definitions
+    /// `error` (default) rejects documents failing the schema, `warn` only logs them
+    #[serde(default, alias = "validationAction", skip_serializing_if = "Option::is_none")]
+    pub validation_action: Option<crate::schema::ValidationAction>,
+    /// Size limits making the collection capped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capped: Option<crate::storage::CappedOptions>,
+    /// Retention making the collection keep every version of its documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<crate::storage::HistoryOptions>,
+}
+
+/// Tailable cursor request over a capped collection
+///
+/// The server answers at once with the documents inserted after `after`
+/// (from the oldest when absent), then sends a further response with the
+/// same sequence number whenever matching documents are inserted, until
+/// the client sends its next command or closes the connection. Each
+/// response's data holds the `documents` and the `cursor` to resume from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TailRequest {
+    pub collection: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<crate::document::DocumentId>,
+    /// Most documents per response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_size: Option<u64>,
+}
+
+/// Change stream request
+///
+/// The server answers at once with the `resume_token` the stream starts
+/// from, then sends a further response with the same sequence number for
+/// every batch of change events, until the client sends its next command
+/// or closes the connection. Each response's data holds the `events` and
+/// the `resume_token` to pass as `resume_after` after a disconnect.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WatchRequest {
+    /// Collection to watch; every collection when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    /// Filter over the change events, as in a `$match` stage
+    #[serde(default, alias = "$match", skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Value>,
+    #[serde(default, alias = "resumeAfter", skip_serializing_if = "Option::is_none")]
+    pub resume_after: Option<String>,
+    /// Attach the document as it is now to update events
+    #[serde(default, alias = "fullDocument")]
+    pub full_document: bool,
+    /// Most events per response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_size: Option<u64>,
+}
+
+/// File upload request
+///
+/// Without `file_id` a new upload of `filename` begins; with it, `data`
+/// is appended to that unfinished upload at `offset`, which must be the
+/// file's current length (the end of the file when absent). `data` is
+/// base64-encoded, and `finish` seals the file once it is appended. Each
+/// response's data is the file's document, whose `length` is where an
+/// interrupted upload resumes; a request with only `file_id` reads it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PutFileRequest {
+    /// Bucket holding the file; `fs` when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(default, alias = "fileId", skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<crate::document::DocumentId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(default, alias = "contentType", skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Application fields stored with the file, an object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    #[serde(default, alias = "chunkSize", skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub data: String,
+    #[serde(default)]
+    pub finish: bool,
+}
+
+/// File download request
+///
+/// The server answers at once with the file's document and the `range`
+/// it will send, then sends a further response with the same sequence
+/// number for each chunk of the range, holding its `offset` and base64
+/// `data`, until the range is sent or the client sends its next command.
+/// `end` is exclusive and defaults to the file's length.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetFileRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(alias = "fileId")]
+    pub file_id: crate::document::DocumentId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<u64>,
+}
+
+/// File deletion request, for finished and unfinished uploads alike
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteFileRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(alias = "fileId")]
+    pub file_id: crate::document::DocumentId,
+}
+
+/// File listing request, filtering the bucket's file documents
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListFilesRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Value>,
+}
+
+/// Collection settings change request
+///
+/// Each setting that is present replaces the collection's current one:
+/// `schema` takes the same forms as in [`CreateCollectionRequest`] and
+/// `cache` replaces the whole cache configuration. `history` starts keeping
+/// document versions, or changes the retention of a collection already
+/// keeping them. With `revalidate`, the stored documents are checked
+/// against the resulting schema and the violations reported.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModifyCollectionRequest {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Value>,
+    #[serde(default, alias = "validationAction", skip_serializing_if = "Option::is_none")]
+    pub validation_action: Option<crate::schema::ValidationAction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<crate::schema::CollectionCacheConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<crate::storage::HistoryOptions>,
+    #[serde(default)]
+    pub revalidate: bool,
 }
 
 /// Collection drop request
@@ -708,7 +1009,8 @@ pub struct CreateIndexRequest {
     pub collection: String,
     pub name: String,
     pub fields: Vec<IndexField>,
-    pub unique: bool,
+    #[serde(flatten)]
+    pub options: IndexOptions,
 }
 
 /// Index drop request
@@ -719,9 +1021,37 @@ pub struct DropIndexRequest {
 }
 
 /// List indexes request
+///
+/// When `filter` or `sort` is given, each index reports whether the
+/// planner would select it for that query.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListIndexesRequest {
     pub collection: String,
+    pub filter: Option<Value>,
+    pub sort: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collation: Option<crate::query::Collation>,
+}
+
+/// Index statistics refresh request
+///
+/// Rebuilds the key distributions the planner costs plans with, for one
+/// index or, without `index`, every index of the collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalyzeRequest {
+    pub collection: String,
+    #[serde(default)]
+    pub index: Option<String>,
+}
+
+/// View creation request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateViewRequest {
+    pub name: String,
+    pub source: String,
+    pub pipeline: Vec<crate::aggregation::PipelineStage>,
+    #[serde(default)]
+    pub materialized: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -730,6 +1060,107 @@ pub struct IndexField {
     pub direction: i32, // 1 for ascending, -1 for descending
 }
 
+/// Constraints of an index beyond its fields
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexOptions {
+    /// Reject a write whose key another document already holds
+    #[serde(default)]
+    pub unique: bool,
+    /// Leave out documents whose key contains a missing or null field
+    #[serde(default)]
+    pub sparse: bool,
+    /// Index only the documents matching this filter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partial_filter: Option<crate::query::Filter>,
+    /// Delete documents this many seconds after the date in the indexed field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expire_after_seconds: Option<u64>,
+    /// Store hashes of the field value, for equality lookups only
+    #[serde(default)]
+    pub hashed: bool,
+    /// String comparison rules for the index keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collation: Option<crate::query::Collation>,
+    /// Make this a nearest-neighbour index over embeddings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector: Option<VectorOptions>,
+}
+
+/// Shape of the embeddings a vector index holds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VectorOptions {
+    /// Length of every embedding
+    pub dims: usize,
+    /// How similarity is measured
+    pub metric: crate::schema::VectorMetric,
+}
+
+impl IndexOptions {
+    /// Options of a unique index
+    pub fn unique() -> Self {
+        Self {
+            unique: true,
+            ..Self::default()
+        }
+    }
+
+    /// Also leave out documents with missing or null key fields
+    pub fn sparse(mut self) -> Self {
+        self.sparse = true;
+        self
+    }
+
+    /// Index only the documents matching `filter`
+    pub fn partial(mut self, filter: crate::query::Filter) -> Self {
+        self.partial_filter = Some(filter);
+        self
+    }
+
+    /// Expire documents `seconds` after the date in the indexed field
+    pub fn expire_after(mut self, seconds: u64) -> Self {
+        self.expire_after_seconds = Some(seconds);
+        self
+    }
+
+    /// Options of a hashed index
+    pub fn hashed() -> Self {
+        Self {
+            hashed: true,
+            ..Self::default()
+        }
+    }
+
+    /// Compare the indexed strings under `collation`
+    pub fn collation(mut self, collation: crate::query::Collation) -> Self {
+        self.collation = Some(collation);
+        self
+    }
+
+    /// Options of a vector index over `dims`-dimensional embeddings
+    pub fn vector(dims: usize, metric: crate::schema::VectorMetric) -> Self {
+        Self {
+            vector: Some(VectorOptions { dims, metric }),
+            ..Self::default()
+        }
+    }
+}
+
+impl From<&crate::schema::IndexDefinition> for IndexOptions {
+    fn from(definition: &crate::schema::IndexDefinition) -> Self {
+        Self {
+            unique: definition.unique,
+            sparse: definition.sparse,
+            partial_filter: definition.partial_filter.clone(),
+            expire_after_seconds: definition.expire_after_seconds,
+            hashed: definition.is_hashed(),
+            collation: definition.collation.clone(),
+            vector: definition
+                .vector_spec()
+                .map(|(dims, metric)| VectorOptions { dims, metric }),
+        }
+    }
+}
+
 /// List operation request (for Redis-like data structures)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListOpRequest {
@@ -816,6 +1247,63 @@ pub struct AggregateRequest {
     pub pipeline: Vec<crate::aggregation::PipelineStage>,
 }
 
+/// Query plan explanation request
+///
+/// Exactly one of `query` and `aggregate` must be set. With the
+/// `executionStats` verbosity the request is also run and per-stage
+/// statistics are returned; its results are discarded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExplainRequest {
+    #[serde(default)]
+    pub query: Option<QueryRequest>,
+    #[serde(default)]
+    pub aggregate: Option<AggregateRequest>,
+    #[serde(default)]
+    pub verbosity: crate::query::ExplainVerbosity,
+}
+
+/// Plan cache listing request
+///
+/// Without `collection`, the cached plans of every collection are listed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListPlanCacheRequest {
+    #[serde(default)]
+    pub collection: Option<String>,
+}
+
+/// Plan cache eviction request
+///
+/// Clears every cached plan, those of one `collection`, or with `shape`
+/// only the entry whose shape text matches.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClearPlanCacheRequest {
+    #[serde(default)]
+    pub collection: Option<String>,
+    #[serde(default)]
+    pub shape: Option<String>,
+}
+
+/// Index build listing request
+///
+/// Without `collection`, the builds of every collection are listed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListIndexBuildsRequest {
+    #[serde(default)]
+    pub collection: Option<String>,
+}
+
+/// Progress request for one index build
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetIndexBuildRequest {
+    pub id: String,
+}
+
+/// Cancellation request for a running index build
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelIndexBuildRequest {
+    pub id: String,
+}
+
 // ============================================================================
 // User Management Request/Response Structs
 // ============================================================================
@@ -870,6 +1358,9 @@ pub struct OperationResponse {
     pub data: Option<Value>,
     pub error: Option<String>,
     pub affected_count: Option<u64>,
+    /// Query matches as Extended JSON, when the request asked for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub documents: Option<Vec<serde_json::Value>>,
 }
 
 /// Helper functions for v0.2.0 protocol serialization
@@ -908,6 +1399,7 @@ impl OperationResponse {
             data,
             error: None,
             affected_count: None,
+            documents: None,
         }
     }
 
@@ -917,6 +1409,7 @@ impl OperationResponse {
             data: None,
             error: Some(message),
             affected_count: None,
+            documents: None,
         }
     }
 }
@@ -971,6 +1464,10 @@ mod tests {
             sort: None,
             skip: None,
             limit: Some(10),
+            collation: None,
+            extended_json: false,
+            as_of: None,
+            version: None,
         };
         
         let cmd = Command::query(2, &query_req).unwrap();
@@ -978,6 +1475,209 @@ mod tests {
         assert_eq!(cmd.header.version, PROTOCOL_V2);
     }
 
+    #[test]
+    fn test_v2_create_view_command() {
+        let payload = br#"{"name":"by_region","source":"sales","pipeline":[{"$":"match","filter":{}}]}"#;
+        let view_req: CreateViewRequest = serde_json::from_slice(payload).unwrap();
+        assert!(!view_req.materialized);
+
+        let cmd = Command::create_view(3, &view_req).unwrap();
+        let decoded = Command::from_bytes(&cmd.to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::CreateView);
+        let round_trip: CreateViewRequest = serde_json::from_slice(&decoded.value).unwrap();
+        assert_eq!(round_trip.source, "sales");
+    }
+
+    #[test]
+    fn test_aggregate_request_accepts_every_accumulator_encoding() {
+        use crate::aggregation::{AggregateOp, PipelineStage};
+
+        // As sent by clients written against the original $group encoding
+        let payload = br#"{"collection":"sales","pipeline":[
+            {"$":"match","filter":{"region":"eu"}},
+            {"$":"group","_id":"$region","fields":{"orders":{"$":"count"},"raw":{"$":"sum","value":1}}}
+        ]}"#;
+        let req: AggregateRequest = serde_json::from_slice(payload).unwrap();
+        let PipelineStage::Group { fields, .. } = &req.pipeline[1] else {
+            panic!("expected a group stage, got {:?}", req.pipeline[1]);
+        };
+        assert!(matches!(fields["orders"], AggregateOp::Count {}));
+        assert!(matches!(&fields["raw"], AggregateOp::Sum(value) if value == &serde_json::json!({"value": 1})));
+
+        let payload = br#"{"collection":"sales","pipeline":[{"$":"group","_id":"$region","fields":{
+            "orders":{"Count":{}},"revenue":{"$":"sum","field":"$amount"},"mean":{"avg":"amount"}
+        }}]}"#;
+        let req: AggregateRequest = serde_json::from_slice(payload).unwrap();
+        let PipelineStage::Group { fields, .. } = &req.pipeline[0] else {
+            panic!("expected a group stage, got {:?}", req.pipeline[0]);
+        };
+        assert!(matches!(fields["orders"], AggregateOp::Count {}));
+        assert!(matches!(&fields["revenue"], AggregateOp::Sum(value) if value == "$amount"));
+        assert!(matches!(&fields["mean"], AggregateOp::Avg(field) if field == "amount"));
+
+        // Every operator survives a round trip through its encoding
+        let encoded = serde_json::to_value(&fields["mean"]).unwrap();
+        assert_eq!(encoded, serde_json::json!({"$": "avg", "field": "amount"}));
+        assert_eq!(serde_json::to_value(AggregateOp::Count {}).unwrap(), serde_json::json!({"$": "count"}));
+        assert!(serde_json::from_value::<AggregateOp>(serde_json::json!({"$": "median", "field": "x"})).is_err());
+    }
+
+    #[test]
+    fn test_v2_modify_collection_command() {
+        let payload = br#"{"name":"orders","validationAction":"warn","cache":{"strategy":{"WriteBehind":{"delay_ms":50}}}}"#;
+        let req: ModifyCollectionRequest = serde_json::from_slice(payload).unwrap();
+        assert_eq!(req.validation_action, Some(crate::schema::ValidationAction::Warn));
+        let cache = req.cache.as_ref().unwrap();
+        assert_eq!(cache.strategy, crate::schema::CacheStrategy::WriteBehind { delay_ms: 50 });
+        assert!(cache.ttl.is_none());
+        assert!(!req.revalidate);
+
+        let cmd = Command::modify_collection(5, &req).unwrap();
+        let decoded = Command::from_bytes(&cmd.to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::ModifyCollection);
+        let round_trip: ModifyCollectionRequest = serde_json::from_slice(&decoded.value).unwrap();
+        assert_eq!(round_trip.name, "orders");
+        assert!(round_trip.schema.is_none());
+
+        assert!(serde_json::from_slice::<ModifyCollectionRequest>(br#"{"name":"orders","validationAction":"ignore"}"#).is_err());
+    }
+
+    #[test]
+    fn test_v2_history_requests() {
+        let create: CreateCollectionRequest =
+            serde_json::from_slice(br#"{"name":"ledger","schema":null,"history":{"maxAgeSecs":86400}}"#).unwrap();
+        let history = create.history.unwrap();
+        assert_eq!((history.max_age_secs, history.max_versions), (Some(86400), None));
+        let modify: ModifyCollectionRequest = serde_json::from_slice(br#"{"name":"ledger","history":{"max_versions":10}}"#).unwrap();
+        assert_eq!(modify.history.unwrap().max_versions, Some(10));
+
+        let as_of = QueryRequest::from_bytes(
+            br#"{"collection":"ledger","filter":null,"projection":null,"sort":null,"skip":null,"limit":null,"asOf":"2026-01-02T03:04:05Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(as_of.as_of.unwrap().to_rfc3339(), "2026-01-02T03:04:05+00:00");
+        assert!(as_of.version.is_none());
+        let round_trip = QueryRequest::from_bytes(&as_of.to_bytes().unwrap()).unwrap();
+        assert_eq!(round_trip.as_of, as_of.as_of);
+
+        let version = QueryRequest::from_bytes(
+            br#"{"collection":"ledger","filter":null,"projection":null,"sort":null,"skip":null,"limit":null,"version":3}"#,
+        )
+        .unwrap();
+        assert_eq!(version.version, Some(3));
+        assert!(QueryRequest::from_bytes(
+            br#"{"collection":"ledger","filter":null,"projection":null,"sort":null,"skip":null,"limit":null,"asOf":"yesterday"}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_v2_analyze_command() {
+        let all: AnalyzeRequest = serde_json::from_slice(br#"{"collection":"orders"}"#).unwrap();
+        assert!(all.index.is_none());
+
+        let one = AnalyzeRequest {
+            collection: "orders".to_string(),
+            index: Some("status_total".to_string()),
+        };
+        let cmd = Command::analyze(4, &one).unwrap();
+        let decoded = Command::from_bytes(&cmd.to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::Analyze);
+        let round_trip: AnalyzeRequest = serde_json::from_slice(&decoded.value).unwrap();
+        assert_eq!(round_trip.index.as_deref(), Some("status_total"));
+    }
+
+    #[test]
+    fn test_v2_explain_command() {
+        let planner: ExplainRequest = serde_json::from_slice(
+            br#"{"query":{"collection":"orders","filter":null,"projection":null,"sort":null,"skip":null,"limit":5}}"#,
+        )
+        .unwrap();
+        assert_eq!(planner.verbosity, crate::query::ExplainVerbosity::QueryPlanner);
+        assert!(planner.aggregate.is_none());
+
+        let stats = ExplainRequest {
+            query: None,
+            aggregate: Some(AggregateRequest {
+                collection: "orders".to_string(),
+                pipeline: vec![crate::aggregation::PipelineStage::Limit { count: 3 }],
+            }),
+            verbosity: crate::query::ExplainVerbosity::ExecutionStats,
+        };
+        let cmd = Command::explain(5, &stats).unwrap();
+        let decoded = Command::from_bytes(&cmd.to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::Explain);
+        let json: serde_json::Value = serde_json::from_slice(&decoded.value).unwrap();
+        assert_eq!(json["verbosity"], "executionStats");
+        let round_trip: ExplainRequest = serde_json::from_slice(&decoded.value).unwrap();
+        assert_eq!(round_trip.aggregate.unwrap().pipeline.len(), 1);
+
+        assert!(serde_json::from_slice::<ExplainRequest>(br#"{"verbosity":"allPlansExecution"}"#).is_err());
+    }
+
+    #[test]
+    fn test_v2_plan_cache_commands() {
+        let all: ListPlanCacheRequest = serde_json::from_slice(b"{}").unwrap();
+        assert!(all.collection.is_none());
+
+        let list = Command::list_plan_cache(6, &ListPlanCacheRequest { collection: Some("orders".to_string()) }).unwrap();
+        let decoded = Command::from_bytes(&list.to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::ListPlanCache);
+
+        let clear = ClearPlanCacheRequest {
+            collection: Some("orders".to_string()),
+            shape: Some("filter=eq(status)".to_string()),
+        };
+        let decoded = Command::from_bytes(&Command::clear_plan_cache(7, &clear).unwrap().to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::ClearPlanCache);
+        let round_trip: ClearPlanCacheRequest = serde_json::from_slice(&decoded.value).unwrap();
+        assert_eq!(round_trip.shape.as_deref(), Some("filter=eq(status)"));
+        assert!(OpCode::try_from(0x72).is_err());
+    }
+
+    #[test]
+    fn test_v2_index_build_commands() {
+        let all: ListIndexBuildsRequest = serde_json::from_slice(b"{}").unwrap();
+        assert!(all.collection.is_none());
+
+        let list = Command::list_index_builds(8, &ListIndexBuildsRequest { collection: Some("orders".to_string()) }).unwrap();
+        let decoded = Command::from_bytes(&list.to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::ListIndexBuilds);
+
+        let get = Command::get_index_build(9, &GetIndexBuildRequest { id: "b1".to_string() }).unwrap();
+        let decoded = Command::from_bytes(&get.to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::GetIndexBuild);
+
+        let cancel = Command::cancel_index_build(10, &CancelIndexBuildRequest { id: "b1".to_string() }).unwrap();
+        let decoded = Command::from_bytes(&cancel.to_bytes()).unwrap();
+        assert_eq!(decoded.header.opcode().unwrap(), OpCode::CancelIndexBuild);
+        let round_trip: CancelIndexBuildRequest = serde_json::from_slice(&decoded.value).unwrap();
+        assert_eq!(round_trip.id, "b1");
+
+        assert!(serde_json::from_slice::<GetIndexBuildRequest>(b"{}").is_err());
+        assert!(OpCode::try_from(0x77).is_err());
+    }
+
+    #[test]
+    fn test_list_indexes_request_query_is_optional() {
+        let plain: ListIndexesRequest = serde_json::from_slice(br#"{"collection":"orders"}"#).unwrap();
+        assert!(plain.filter.is_none());
+        assert!(plain.sort.is_none());
+
+        let with_query = ListIndexesRequest {
+            collection: "orders".to_string(),
+            filter: Some(Value::Object(std::collections::BTreeMap::from([(
+                "status".to_string(),
+                Value::String("open".to_string()),
+            )]))),
+            sort: None,
+            collation: None,
+        };
+        let bytes = serde_json::to_vec(&with_query).unwrap();
+        let decoded: ListIndexesRequest = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.filter, with_query.filter);
+    }
+
     #[test]
     fn test_response_serialization() {
         let resp = Response::ok(42, b"result".to_vec());
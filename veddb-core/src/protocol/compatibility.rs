@@ -9,7 +9,7 @@ use crate::document::{Document, DocumentId, DocumentMetadata, Value};
 use chrono::Utc;
 use crate::protocol::{
     Command, Response, OpCode, Status, PROTOCOL_V1, PROTOCOL_V2,
-    InsertDocRequest, QueryRequest, UpdateDocRequest, DeleteDocRequest,
+    InsertDocRequest, QueryRequest, UpdateDocRequest, UpdateFormat, DeleteDocRequest,
     OperationResponse,
 };
 
@@ -144,6 +144,10 @@ impl CompatibilityHandler {
             sort: None,
             skip: None,
             limit: Some(1),
+            collation: None,
+            extended_json: false,
+            as_of: None,
+            version: None,
         };
 
         let payload = serde_json::to_vec(&request)
@@ -219,6 +223,7 @@ impl CompatibilityHandler {
             filter: Value::Object(filter),
             update: Value::Object(update_fields),
             upsert: false,
+            update_format: UpdateFormat::Replace,
         };
 
         let payload = serde_json::to_vec(&request)
@@ -245,6 +250,10 @@ impl CompatibilityHandler {
             sort: None,
             skip: None,
             limit: Some(1000), // Reasonable default limit
+            collation: None,
+            extended_json: false,
+            as_of: None,
+            version: None,
         };
 
         let payload = serde_json::to_vec(&request)
@@ -15,14 +15,19 @@ use tokio::time::timeout;
 use uuid::Uuid;
 use log::{info, warn, error, debug};
 use sysinfo::{System, Pid};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 
 use crate::auth::{AuthSystem, JwtService, User, UserClaims, Role};
+use crate::document::{extended_json, Document, JsonPatch, PatchError};
 use crate::encryption::tls::TlsAcceptor;
-use crate::storage::HybridStorageEngine;
+use crate::storage::{FileError, FileInfo, FileStore, HybridStorageEngine, NewFile, ReferenceError};
+use crate::index::btree::IndexError;
+use crate::schema::ValidationError;
 use crate::protocol::{
     Command, Response, OpCode, Status, AuthRequest, AuthResponse, 
     CompatibilityHandler, PROTOCOL_V2,
-    CreateCollectionRequest, CreateIndexRequest, InsertDocRequest, UpdateDocRequest, DeleteDocRequest, QueryRequest,
+    CreateCollectionRequest, CreateIndexRequest, InsertDocRequest, UpdateDocRequest, UpdateFormat, DeleteDocRequest, QueryRequest,
     ListCollectionsRequest, DropCollectionRequest, ListIndexesRequest, DropIndexRequest,
     OperationResponse, Value,
     CreateUserRequest, DeleteUserRequest, UpdateUserRoleRequest, UserInfoResponse, ServerInfoResponse
@@ -41,6 +46,12 @@ pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// Connection read timeout
 pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest single wait of a streaming tail for new inserts
+const TAIL_WAIT: Duration = Duration::from_secs(60);
+/// Documents or events per streamed response when the request gives no batch size
+const DEFAULT_TAIL_BATCH: usize = 100;
+/// Largest file range a `GetFile` answered in one response may cover
+const MAX_FILE_RESPONSE: u64 = 8 * 1024 * 1024;
 
 /// Connection write timeout  
 pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
@@ -177,6 +188,88 @@ impl Connection {
     }
 }
 
+/// A parsed `UpdateDocRequest::update`
+enum DocumentUpdate<'a> {
+    Replace(&'a BTreeMap<String, Value>),
+    JsonPatch(JsonPatch),
+    MergePatch(&'a Value),
+}
+
+impl<'a> DocumentUpdate<'a> {
+    /// Parse an update in `format`
+    ///
+    /// Returns `None` for a replacement that is not an object, which
+    /// leaves documents unchanged.
+    fn parse(format: UpdateFormat, update: &'a Value) -> Result<Option<Self>, PatchError> {
+        Ok(match (format, update) {
+            (UpdateFormat::Replace, Value::Object(fields)) => Some(Self::Replace(fields)),
+            (UpdateFormat::Replace, _) => None,
+            (UpdateFormat::JsonPatch, _) => {
+                Some(Self::JsonPatch(JsonPatch::from_json(&extended_json::to_extended_json(update))?))
+            }
+            (UpdateFormat::MergePatch, Value::Object(_)) => Some(Self::MergePatch(update)),
+            (UpdateFormat::MergePatch, _) => return Err(PatchError::NotAnObject),
+        })
+    }
+
+    /// Fields of `doc` after the update
+    fn apply(&self, doc: &Document) -> Result<BTreeMap<String, Value>, PatchError> {
+        let mut patched = doc.clone();
+        match self {
+            Self::Replace(fields) => return Ok((*fields).clone()),
+            Self::JsonPatch(patch) => patched.apply_json_patch(patch)?,
+            Self::MergePatch(patch) => patched.apply_merge_patch(patch)?,
+        }
+        Ok(patched.fields)
+    }
+
+    /// Apply the update to a document matched by `filter`, holding its lock
+    ///
+    /// The document is read again once locked and skipped if it is gone or
+    /// no longer matches, so a patch, its `test` ops included, applies to the
+    /// stored version and no other update lands before its write.
+    async fn write(
+        &self,
+        storage: &HybridStorageEngine,
+        collection: &str,
+        id: crate::document::DocumentId,
+        filter: &crate::query::Filter,
+    ) -> anyhow::Result<UpdateOutcome> {
+        let lock = storage.lock_document(collection, id).await;
+        let Some(doc) = storage.get_document(collection, id).await? else {
+            return Ok(UpdateOutcome::Skipped);
+        };
+        let matches = crate::query::executor::QueryExecutor::new()
+            .matches_filter(&doc, filter)
+            .map_err(|e| anyhow::anyhow!("Filter matching error: {}", e))?;
+        if !matches {
+            return Ok(UpdateOutcome::Skipped);
+        }
+        let mut new_doc = Document::with_id(id);
+        new_doc.fields = match self.apply(&doc) {
+            Ok(fields) => fields,
+            Err(e) => return Ok(UpdateOutcome::PatchFailed(e)),
+        };
+
+        // Patches are recorded as field changes, replacements whole
+        match self {
+            Self::Replace(_) => storage.update_document_locked(&lock, new_doc).await?,
+            _ => storage.patch_document_locked(&lock, new_doc).await?,
+        }
+        Ok(UpdateOutcome::Updated)
+    }
+}
+
+/// What [`DocumentUpdate::write`] did with a matched document
+#[derive(Debug)]
+enum UpdateOutcome {
+    Updated,
+    /// Deleted or changed to no longer match since it was found
+    Skipped,
+    /// The patch does not apply to the stored version
+    PatchFailed(PatchError),
+}
+
 /// Connection pool manager
 pub struct ConnectionManager {
     /// Active connections
@@ -361,6 +454,17 @@ impl ConnectionManager {
                 conn.update_activity();
             }
 
+            // Tails, change streams and file downloads keep the connection streaming until the client speaks again
+            let opcode = command.header.opcode().ok();
+            if matches!(opcode, Some(OpCode::Tail | OpCode::Watch | OpCode::GetFile)) && self.is_authenticated(connection_id).await {
+                match opcode {
+                    Some(OpCode::Tail) => self.follow_tail(&connection_arc, &command).await?,
+                    Some(OpCode::Watch) => self.follow_watch(&connection_arc, &command).await?,
+                    _ => self.stream_file(&connection_arc, &command).await?,
+                }
+                continue;
+            }
+
             // Process command - catch errors and send error response
             // instead of breaking the connection
             let response = match self.process_command(connection_id, command.clone()).await {
@@ -385,30 +489,477 @@ impl ConnectionManager {
         }
     }
 
-    /// Convert document::Value to plain serde_json::Value (strips type tags)
+    /// Stream a tailable cursor until the client sends its next command or disconnects
+    ///
+    /// The first response goes out at once, even when empty, so the client
+    /// knows the cursor is open; the rest only when matching documents
+    /// are inserted. Every response carries the tail command's sequence
+    /// number. An error, such as the collection being dropped, ends the tail.
+    async fn follow_tail(&self, connection_arc: &Arc<RwLock<Connection>>, command: &Command) -> Result<(), ConnectionError> {
+        let seq = command.header.seq;
+        let (req, filter) = match Self::parse_tail(&command.value) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let mut conn = connection_arc.write().await;
+                return conn.write_response(Response::new(Status::Error, seq, e.to_string().into_bytes())).await;
+            }
+        };
+        let mut position = req.after;
+        let mut wait = Duration::ZERO;
+        loop {
+            let batch = tokio::select! {
+                batch = self.next_tail_batch(&req, filter.as_ref(), &mut position, wait) => batch,
+                _ = Self::client_input(connection_arc) => return Ok(()),
+            };
+            let (response, done) = match batch {
+                Ok(documents) if documents.is_empty() && !wait.is_zero() => continue,
+                Ok(documents) => (Response::ok(seq, Self::tail_payload(documents, position)?), false),
+                Err(e) => (Response::new(Status::Error, seq, e.to_string().into_bytes()), true),
+            };
+            connection_arc.write().await.write_response(response).await?;
+            if done {
+                return Ok(());
+            }
+            wait = TAIL_WAIT;
+        }
+    }
+
+    /// Stream change events until the client sends its next command or disconnects
+    ///
+    /// The first response carries no events, only the resume token the
+    /// stream starts from, so a client can resume from it even if nothing
+    /// changes before it disconnects. Each further response holds a batch
+    /// of events and the token to resume after them.
+    async fn follow_watch(&self, connection_arc: &Arc<RwLock<Connection>>, command: &Command) -> Result<(), ConnectionError> {
+        let seq = command.header.seq;
+        let opened = match Self::parse_watch(&command.value) {
+            Ok((options, batch_size)) => crate::replication::ChangeStream::open(self.storage.clone(), options)
+                .await
+                .map(|stream| (stream, batch_size))
+                .map_err(|e| ConnectionError::ProtocolError(e.to_string())),
+            Err(e) => Err(e),
+        };
+        let (mut stream, batch_size) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                let mut conn = connection_arc.write().await;
+                return conn.write_response(Response::new(Status::Error, seq, e.to_string().into_bytes())).await;
+            }
+        };
+        let opening = Response::ok(seq, Self::watch_payload(&[], stream.resume_token())?);
+        connection_arc.write().await.write_response(opening).await?;
+        loop {
+            let batch = tokio::select! {
+                batch = stream.next_batch(batch_size) => batch,
+                _ = Self::client_input(connection_arc) => return Ok(()),
+            };
+            let (response, done) = match batch {
+                Ok(events) => (Response::ok(seq, Self::watch_payload(&events, stream.resume_token())?), false),
+                Err(e) => (Response::new(Status::Error, seq, e.to_string().into_bytes()), true),
+            };
+            connection_arc.write().await.write_response(response).await?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Stream a file range chunk by chunk until it is sent or the client sends its next command
+    ///
+    /// The first response holds the file's document and the range to be
+    /// sent; each further one the bytes of the range within one chunk. A
+    /// read error, such as a corrupt chunk, ends the stream.
+    async fn stream_file(&self, connection_arc: &Arc<RwLock<Connection>>, command: &Command) -> Result<(), ConnectionError> {
+        let seq = command.header.seq;
+        let (store, info, range) = match self.open_file_range(&command.value).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                let mut conn = connection_arc.write().await;
+                return conn.write_response(Response::new(Status::Error, seq, e.to_string().into_bytes())).await;
+            }
+        };
+        let opening = Response::ok(seq, Self::file_payload(&info, &range, None)?);
+        connection_arc.write().await.write_response(opening).await?;
+        let mut offset = range.start;
+        while offset < range.end {
+            let end = ((offset / info.chunk_size + 1) * info.chunk_size).min(range.end);
+            let read = tokio::select! {
+                read = store.read_range(&info, offset, end) => read,
+                _ = Self::client_input(connection_arc) => return Ok(()),
+            };
+            let (response, done) = match read {
+                Ok(data) => (Response::ok(seq, Self::file_data_payload(offset, &data)?), false),
+                Err(e) => (Response::new(Status::Error, seq, e.to_string().into_bytes()), true),
+            };
+            connection_arc.write().await.write_response(response).await?;
+            if done {
+                return Ok(());
+            }
+            offset = end;
+        }
+        Ok(())
+    }
+
+    /// The bucket, finished file and byte range a `GetFile` request reads
+    async fn open_file_range(
+        &self,
+        payload: &[u8],
+    ) -> Result<(FileStore, FileInfo, std::ops::Range<u64>), ConnectionError> {
+        let req: crate::protocol::GetFileRequest = serde_json::from_slice(payload)
+            .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        let store = FileStore::new(self.storage.clone(), req.bucket.as_deref())
+            .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        let info = store.info(req.file_id).await.map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        if !info.is_finished() {
+            return Err(ConnectionError::ProtocolError(FileError::Unfinished(info.id).to_string()));
+        }
+        let start = req.start.unwrap_or(0);
+        let end = req.end.map_or(info.length, |end| end.min(info.length));
+        if start > end {
+            let error = FileError::InvalidRange { id: info.id, start, end, length: info.length };
+            return Err(ConnectionError::ProtocolError(error.to_string()));
+        }
+        Ok((store, info, start..end))
+    }
+
+    /// Begin, append to or finish an upload as a `PutFile` request asks
+    async fn put_file(&self, payload: &[u8]) -> Result<FileInfo, ConnectionError> {
+        let req: crate::protocol::PutFileRequest = serde_json::from_slice(payload)
+            .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        let data = BASE64
+            .decode(&req.data)
+            .map_err(|e| ConnectionError::ProtocolError(format!("Invalid file data: {}", e)))?;
+        let store = FileStore::new(self.storage.clone(), req.bucket.as_deref())
+            .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        let failed = |e: anyhow::Error| ConnectionError::ProtocolError(e.to_string());
+
+        let mut info = match req.file_id {
+            Some(id) => store.info(id).await.map_err(failed)?,
+            None => {
+                let filename = req
+                    .filename
+                    .ok_or_else(|| ConnectionError::ProtocolError("A new upload needs a filename".to_string()))?;
+                let metadata = match req.metadata {
+                    None => BTreeMap::new(),
+                    Some(Value::Object(fields)) => fields,
+                    Some(_) => return Err(ConnectionError::ProtocolError("File metadata must be an object".to_string())),
+                };
+                let file = NewFile { filename, content_type: req.content_type, metadata, chunk_size: req.chunk_size };
+                store.begin(file).await.map_err(failed)?
+            }
+        };
+        if !data.is_empty() {
+            info = store.append(info.id, req.offset.unwrap_or(info.length), &data).await.map_err(failed)?;
+        }
+        if req.finish {
+            info = store.finish(info.id).await.map_err(failed)?;
+        }
+        Ok(info)
+    }
+
+    /// A file's document as sent to clients
+    fn file_value(info: &FileInfo) -> Value {
+        let mut doc = info.to_document();
+        doc.fields.insert("_id".to_string(), Value::String(info.id.to_string()));
+        Value::Object(doc.fields)
+    }
+
+    /// Opening `GetFile` response payload: the file, its range and, when
+    /// answered in one response, the range's bytes
+    fn file_payload(info: &FileInfo, range: &std::ops::Range<u64>, data: Option<&[u8]>) -> Result<Vec<u8>, ConnectionError> {
+        let mut bounds = BTreeMap::new();
+        bounds.insert("start".to_string(), Value::Int64(range.start as i64));
+        bounds.insert("end".to_string(), Value::Int64(range.end as i64));
+        let mut payload = BTreeMap::new();
+        payload.insert("file".to_string(), Self::file_value(info));
+        payload.insert("range".to_string(), Value::Object(bounds));
+        if let Some(data) = data {
+            payload.insert("data".to_string(), Value::String(BASE64.encode(data)));
+        }
+        let op_res = OperationResponse::success(Some(Value::Object(payload)));
+        serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))
+    }
+
+    /// `InsertDoc` response payload: the id the document was stored under
+    ///
+    /// A capped collection replaces the client's `_id` with one that orders
+    /// the document after earlier inserts, so clients read the id from here.
+    fn inserted_payload(doc_id: crate::document::DocumentId) -> Result<Vec<u8>, ConnectionError> {
+        let mut data = BTreeMap::new();
+        data.insert("_id".to_string(), Value::String(doc_id.to_string()));
+        let op_res = OperationResponse {
+            affected_count: Some(1),
+            ..OperationResponse::success(Some(Value::Object(data)))
+        };
+        serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))
+    }
+
+    /// Streamed `GetFile` response payload: bytes of the file from `offset`
+    fn file_data_payload(offset: u64, data: &[u8]) -> Result<Vec<u8>, ConnectionError> {
+        let mut payload = BTreeMap::new();
+        payload.insert("offset".to_string(), Value::Int64(offset as i64));
+        payload.insert("data".to_string(), Value::String(BASE64.encode(data)));
+        let op_res = OperationResponse {
+            affected_count: Some(data.len() as u64),
+            ..OperationResponse::success(Some(Value::Object(payload)))
+        };
+        serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))
+    }
+
+    /// Wait until the client sends input, closes the connection or the socket fails
+    ///
+    /// The input stays unread for the command loop.
+    async fn client_input(connection_arc: &Arc<RwLock<Connection>>) {
+        let conn = connection_arc.read().await;
+        let mut byte = [0u8; 1];
+        let _ = conn.stream.peek(&mut byte).await;
+    }
+
+    /// Parse a watch request into change stream options and a batch size
+    fn parse_watch(payload: &[u8]) -> Result<(crate::replication::ChangeStreamOptions, usize), ConnectionError> {
+        let req: crate::protocol::WatchRequest = serde_json::from_slice(payload)
+            .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        let filter = req
+            .filter
+            .as_ref()
+            .map(|filter| crate::query::parser::QueryParser::parse_filter(&Self::value_to_plain_json(filter)))
+            .transpose()
+            .map_err(|e| ConnectionError::ProtocolError(format!("Invalid filter: {}", e)))?;
+        let resume_after = req
+            .resume_after
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(|e: crate::replication::ChangeStreamError| ConnectionError::ProtocolError(e.to_string()))?;
+        let options = crate::replication::ChangeStreamOptions {
+            collection: req.collection,
+            filter,
+            resume_after,
+            full_document: req.full_document,
+        };
+        Ok((options, req.batch_size.map_or(DEFAULT_TAIL_BATCH, |size| size.max(1) as usize)))
+    }
+
+    /// Watch response payload: the events and the token to resume after
+    fn watch_payload(
+        events: &[crate::replication::ChangeEvent],
+        token: Option<crate::replication::ResumeToken>,
+    ) -> Result<Vec<u8>, ConnectionError> {
+        let events = events.iter().map(|event| Value::Object(event.to_document().fields)).collect();
+        let mut data = BTreeMap::new();
+        data.insert("events".to_string(), Value::Array(events));
+        data.insert("resume_token".to_string(), token.map_or(Value::Null, |token| Value::String(token.to_string())));
+        let op_res = OperationResponse::success(Some(Value::Object(data)));
+        serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))
+    }
+
+    /// Parse a tail request and its filter
+    fn parse_tail(payload: &[u8]) -> Result<(crate::protocol::TailRequest, Option<crate::query::Filter>), ConnectionError> {
+        let req: crate::protocol::TailRequest = serde_json::from_slice(payload)
+            .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        let filter = req
+            .filter
+            .as_ref()
+            .map(|filter| crate::query::parser::QueryParser::parse_filter(&Self::value_to_plain_json(filter)))
+            .transpose()
+            .map_err(|e| ConnectionError::ProtocolError(format!("Invalid filter: {}", e)))?;
+        Ok((req, filter))
+    }
+
+    /// Matching documents inserted after `position`, waiting up to `max_wait` for one
+    ///
+    /// `position` moves past every document read, matching or not, so a
+    /// filtered-out insert is not read again.
+    async fn next_tail_batch(
+        &self,
+        req: &crate::protocol::TailRequest,
+        filter: Option<&crate::query::Filter>,
+        position: &mut Option<crate::document::DocumentId>,
+        max_wait: Duration,
+    ) -> Result<Vec<Document>, ConnectionError> {
+        let executor = crate::query::QueryExecutor::new();
+        let limit = req.batch_size.map_or(DEFAULT_TAIL_BATCH, |size| size.max(1) as usize);
+        let deadline = tokio::time::Instant::now() + max_wait;
+        loop {
+            let wait = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let documents = self.storage.tail_wait(&req.collection, *position, limit, wait).await
+                .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+            let Some(last) = documents.last() else { return Ok(Vec::new()) };
+            *position = Some(last.id);
+            let mut matching = Vec::with_capacity(documents.len());
+            for doc in documents {
+                let keep = match filter {
+                    Some(filter) => executor.matches_filter(&doc, filter)
+                        .map_err(|e| ConnectionError::ProtocolError(format!("Filter matching error: {}", e)))?,
+                    None => true,
+                };
+                if keep {
+                    matching.push(doc);
+                }
+            }
+            if !matching.is_empty() {
+                return Ok(matching);
+            }
+        }
+    }
+
+    /// Tail response payload: the documents and the cursor to resume from
+    fn tail_payload(documents: Vec<Document>, position: Option<crate::document::DocumentId>) -> Result<Vec<u8>, ConnectionError> {
+        let count = documents.len() as u64;
+        let documents = documents
+            .into_iter()
+            .map(|mut doc| {
+                doc.fields.insert("_id".to_string(), Value::String(doc.id.to_string()));
+                Value::Object(doc.fields)
+            })
+            .collect();
+        let mut data = BTreeMap::new();
+        data.insert("documents".to_string(), Value::Array(documents));
+        data.insert("cursor".to_string(), position.map_or(Value::Null, |id| Value::String(id.to_string())));
+        let op_res = OperationResponse {
+            affected_count: Some(count),
+            ..OperationResponse::success(Some(Value::Object(data)))
+        };
+        serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))
+    }
+
+    /// Build a query from the wire-level query fields
+    fn build_query(
+        filter: Option<&Value>,
+        projection: Option<&Value>,
+        sort: Option<&Value>,
+        skip: Option<u64>,
+        limit: Option<u64>,
+        collation: Option<&crate::query::Collation>,
+    ) -> Result<crate::query::Query, ConnectionError> {
+        let mut spec = serde_json::Map::new();
+        if let Some(filter) = filter {
+            spec.insert("filter".to_string(), Self::value_to_plain_json(filter));
+        }
+        if let Some(projection) = projection {
+            spec.insert("projection".to_string(), extended_json::to_relaxed_extended_json(projection));
+        }
+        if let Some(sort) = sort {
+            spec.insert("sort".to_string(), extended_json::to_relaxed_extended_json(sort));
+        }
+        if let Some(skip) = skip {
+            spec.insert("skip".to_string(), serde_json::Value::from(skip));
+        }
+        if let Some(limit) = limit {
+            spec.insert("limit".to_string(), serde_json::Value::from(limit));
+        }
+        if let Some(collation) = collation {
+            let collation = serde_json::to_value(collation)
+                .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+            spec.insert("collation".to_string(), collation);
+        }
+
+        crate::query::QueryParser::parse_from_value(&serde_json::Value::Object(spec))
+            .map_err(|e| ConnectionError::ProtocolError(format!("Invalid query: {}", e)))
+    }
+
+    /// Error response for a write rejected by a unique index
+    ///
+    /// Returns `None` if `error` has another cause. The payload names the
+    /// index and the duplicated key so clients need not parse the message.
+    fn duplicate_key_response(
+        seq: u32,
+        collection: &str,
+        error: &anyhow::Error,
+        affected_count: Option<u64>,
+    ) -> Result<Option<Response>, ConnectionError> {
+        let Some(IndexError::UniqueConstraintViolation { index, key }) = error.downcast_ref::<IndexError>() else {
+            return Ok(None);
+        };
+
+        let mut details = BTreeMap::new();
+        details.insert("code".to_string(), Value::String("duplicate_key".to_string()));
+        details.insert("collection".to_string(), Value::String(collection.to_string()));
+        details.insert("index".to_string(), Value::String(index.clone()));
+        details.insert("key".to_string(), key.to_value());
+
+        let mut op_res = OperationResponse::error(format!("{:#}", error));
+        op_res.data = Some(Value::Object(details));
+        op_res.affected_count = affected_count;
+        let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        Ok(Some(Response::new(Status::DuplicateKey, seq, payload)))
+    }
+
+    /// Error response for a write rejected by the collection's schema
+    ///
+    /// Returns `None` if `error` has another cause.
+    fn validation_failure_response(
+        seq: u32,
+        collection: &str,
+        error: &anyhow::Error,
+        affected_count: Option<u64>,
+    ) -> Result<Option<Response>, ConnectionError> {
+        let Some(violation) = error.downcast_ref::<ValidationError>() else {
+            return Ok(None);
+        };
+
+        let mut details = BTreeMap::new();
+        details.insert("code".to_string(), Value::String("validation_failed".to_string()));
+        details.insert("collection".to_string(), Value::String(collection.to_string()));
+        details.insert("reason".to_string(), Value::String(violation.to_string()));
+
+        let mut op_res = OperationResponse::error(format!("{:#}", error));
+        op_res.data = Some(Value::Object(details));
+        op_res.affected_count = affected_count;
+        let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        Ok(Some(Response::new(Status::DocumentValidationFailed, seq, payload)))
+    }
+
+    /// Error response for a write or delete blocked by a reference field
+    ///
+    /// Returns `None` if `error` has another cause.
+    fn reference_violation_response(
+        seq: u32,
+        collection: &str,
+        error: &anyhow::Error,
+        affected_count: Option<u64>,
+    ) -> Result<Option<Response>, ConnectionError> {
+        let Some(violation) = error.downcast_ref::<ReferenceError>() else {
+            return Ok(None);
+        };
+
+        let mut details = BTreeMap::new();
+        details.insert("code".to_string(), Value::String("reference_violation".to_string()));
+        details.insert("collection".to_string(), Value::String(collection.to_string()));
+        details.insert("reason".to_string(), Value::String(violation.to_string()));
+
+        let mut op_res = OperationResponse::error(format!("{:#}", error));
+        op_res.data = Some(Value::Object(details));
+        op_res.affected_count = affected_count;
+        let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+        Ok(Some(Response::new(Status::ReferenceViolation, seq, payload)))
+    }
+
+    /// Report entry for a document whose patch failed
+    fn patch_failure(id: crate::document::DocumentId, error: &PatchError) -> Value {
+        let code = match error {
+            PatchError::TestFailed { .. } => "test_failed",
+            _ => "patch_failed",
+        };
+        let mut details = BTreeMap::new();
+        details.insert("_id".to_string(), Value::String(id.to_string()));
+        details.insert("code".to_string(), Value::String(code.to_string()));
+        details.insert("reason".to_string(), Value::String(error.to_string()));
+        Value::Object(details)
+    }
+
+    /// A document read from history with its version and write time as
+    /// `_version` and `_updated_at` fields
+    fn with_version_fields(mut doc: Document) -> Document {
+        doc.fields.insert("_version".to_string(), Value::Int64(doc.metadata.version as i64));
+        doc.fields.insert("_updated_at".to_string(), Value::DateTime(doc.metadata.updated_at));
+        doc
+    }
+
+    /// Convert document::Value to Extended JSON (strips serde type tags)
     /// This is needed because filters come in as Value enums but QueryParser expects flat JSON
     fn value_to_plain_json(v: &Value) -> serde_json::Value {
-        match v {
-            Value::Null => serde_json::Value::Null,
-            Value::Bool(b) => serde_json::Value::Bool(*b),
-            Value::Int32(i) => serde_json::Value::Number((*i).into()),
-            Value::Int64(i) => serde_json::Value::Number((*i).into()),
-            Value::Float64(f) => serde_json::Number::from_f64(*f)
-                .map(serde_json::Value::Number)
-                .unwrap_or(serde_json::Value::Null),
-            Value::String(s) => serde_json::Value::String(s.clone()),
-            Value::Array(arr) => serde_json::Value::Array(
-                arr.iter().map(Self::value_to_plain_json).collect()
-            ),
-            Value::Object(obj) => serde_json::Value::Object(
-                obj.iter()
-                   .map(|(k, v)| (k.clone(), Self::value_to_plain_json(v)))
-                   .collect()
-            ),
-            Value::Binary(_) => serde_json::Value::Null,
-            Value::ObjectId(_) => serde_json::Value::Null,
-            Value::DateTime(_) => serde_json::Value::Null,
-        }
+        extended_json::to_extended_json(v)
     }
 
     /// Process a single command
@@ -439,11 +990,76 @@ impl ConnectionManager {
             },
             OpCode::CreateCollection => {
                 let req: CreateCollectionRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
-                self.storage.create_collection(&req.name).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let schema = match (&req.schema, req.validation_action) {
+                    (Some(schema), action) => {
+                        let mut schema = crate::schema::Schema::from_json(&extended_json::to_relaxed_extended_json(schema))
+                            .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                        schema.validation_action = action.unwrap_or_default();
+                        Some(schema)
+                    }
+                    (None, Some(_)) => {
+                        return Err(ConnectionError::ProtocolError("validationAction requires a schema".to_string()));
+                    }
+                    (None, None) => None,
+                };
+                if let Some(options) = &req.history {
+                    options.validate().map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                }
+                match (req.capped, schema) {
+                    (Some(options), schema) => self.storage.create_capped_collection(&req.name, options, schema),
+                    (None, Some(schema)) => self.storage.create_collection_with_schema(&req.name, schema),
+                    (None, None) => self.storage.create_collection(&req.name),
+                }
+                .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                // A collection that cannot keep the requested history is not left behind
+                if let Some(options) = req.history {
+                    if let Err(e) = self.storage.enable_history(&req.name, options).await {
+                        if let Err(drop_error) = self.storage.drop_collection(&req.name) {
+                            log::error!("Failed to drop collection '{}' after enabling its history failed: {}", req.name, drop_error);
+                        }
+                        return Err(ConnectionError::ProtocolError(e.to_string()));
+                    }
+                }
                 let op_res = OperationResponse::success(None);
                 let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
                 Ok(Response::ok(command.header.seq, payload))
             },
+            OpCode::ModifyCollection => {
+                let req: crate::protocol::ModifyCollectionRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                if req.schema.is_none() && req.validation_action.is_none() && req.cache.is_none() && req.history.is_none() && !req.revalidate {
+                    return Err(ConnectionError::ProtocolError("ModifyCollection needs a setting to change or revalidate".to_string()));
+                }
+                let schema = req
+                    .schema
+                    .as_ref()
+                    .map(|schema| crate::schema::Schema::from_json(&extended_json::to_relaxed_extended_json(schema)))
+                    .transpose()
+                    .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+
+                let mut result = BTreeMap::new();
+                let changes_schema = schema.is_some() || req.validation_action.is_some() || req.cache.is_some();
+                if changes_schema || req.history.is_some() {
+                    let modification = crate::storage::CollectionModification {
+                        schema,
+                        validation_action: req.validation_action,
+                        cache_config: req.cache,
+                        history: req.history,
+                    };
+                    let version = self.storage.modify_collection(&req.name, modification).await
+                        .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                    if changes_schema {
+                        result.insert("version".to_string(), Value::Int64(version as i64));
+                    }
+                }
+                if req.revalidate {
+                    let report = self.storage.revalidate_collection(&req.name)
+                        .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                    result.insert("revalidation".to_string(), report.to_value());
+                }
+                let op_res = OperationResponse::success(Some(Value::Object(result)));
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
             OpCode::DropCollection => {
                 let req: crate::protocol::DropCollectionRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
                 self.storage.drop_collection(&req.name).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
@@ -452,6 +1068,73 @@ impl ConnectionManager {
                 Ok(Response::ok(command.header.seq, payload))
             },
 
+            // Outside the command loop a watch only reports its starting token
+            OpCode::Watch => {
+                let (options, _) = Self::parse_watch(&command.value)?;
+                let stream = crate::replication::ChangeStream::open(self.storage.clone(), options).await
+                    .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, Self::watch_payload(&[], stream.resume_token())?))
+            },
+
+            // Outside the command loop a tail answers once, without waiting
+            OpCode::Tail => {
+                let (req, filter) = Self::parse_tail(&command.value)?;
+                let mut position = req.after;
+                let documents = self.next_tail_batch(&req, filter.as_ref(), &mut position, Duration::ZERO).await?;
+                Ok(Response::ok(command.header.seq, Self::tail_payload(documents, position)?))
+            },
+
+            // File storage
+            OpCode::PutFile => {
+                let info = self.put_file(&command.value).await?;
+                let op_res = OperationResponse::success(Some(Self::file_value(&info)));
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+            // Outside the command loop a download answers in one response
+            OpCode::GetFile => {
+                let (store, info, range) = self.open_file_range(&command.value).await?;
+                if range.end - range.start > MAX_FILE_RESPONSE {
+                    return Err(ConnectionError::ProtocolError(format!(
+                        "Range of {} bytes exceeds the {} bytes of one response; read it in ranges",
+                        range.end - range.start,
+                        MAX_FILE_RESPONSE
+                    )));
+                }
+                let data = store.read_range(&info, range.start, range.end).await
+                    .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, Self::file_payload(&info, &range, Some(&data))?))
+            },
+            OpCode::DeleteFile => {
+                let req: crate::protocol::DeleteFileRequest = serde_json::from_slice(&command.value)
+                    .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let store = FileStore::new(self.storage.clone(), req.bucket.as_deref())
+                    .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let deleted = store.delete(req.file_id).await.map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let op_res = OperationResponse {
+                    affected_count: Some(deleted as u64),
+                    ..OperationResponse::success(None)
+                };
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+            OpCode::ListFiles => {
+                let req: crate::protocol::ListFilesRequest = serde_json::from_slice(&command.value)
+                    .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let filter = req
+                    .filter
+                    .as_ref()
+                    .map(|filter| crate::query::parser::QueryParser::parse_filter(&Self::value_to_plain_json(filter)))
+                    .transpose()
+                    .map_err(|e| ConnectionError::ProtocolError(format!("Invalid filter: {}", e)))?;
+                let store = FileStore::new(self.storage.clone(), req.bucket.as_deref())
+                    .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let files = store.list(filter).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let op_res = OperationResponse::success(Some(Value::Array(files.iter().map(Self::file_value).collect())));
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
             // Index Management
             OpCode::ListIndexes => {
                 let req: crate::protocol::ListIndexesRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
@@ -483,7 +1166,25 @@ impl ConnectionManager {
                     }
                 }
 
-                let proto_vals: Vec<Value> = indexes.into_iter().map(json_to_value).collect();
+                let mut proto_vals: Vec<Value> = indexes.into_iter().map(json_to_value).collect();
+
+                // Report which index the planner would pick for the given query
+                if req.filter.is_some() || req.sort.is_some() {
+                    let query = Self::build_query(req.filter.as_ref(), None, req.sort.as_ref(), None, None, req.collation.as_ref())?;
+                    let plan = self.storage.plan_query(&req.collection, &query)
+                        .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                    for index in proto_vals.iter_mut() {
+                        if let Value::Object(map) = index {
+                            let scan = plan.index_scan.as_ref().filter(|scan| {
+                                map.get("name").and_then(|n| n.as_str()) == Some(scan.index.as_str())
+                            });
+                            map.insert("selected".to_string(), Value::Bool(scan.is_some()));
+                            if let Some(scan) = scan {
+                                map.insert("plan".to_string(), scan.to_value());
+                            }
+                        }
+                    }
+                }
                 
                 let op_res = OperationResponse::success(Some(Value::Array(proto_vals)));
                 let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
@@ -491,8 +1192,10 @@ impl ConnectionManager {
             },
             OpCode::CreateIndex => {
                 let req: CreateIndexRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
-                self.storage.create_index(&req.collection, &req.name, req.fields, req.unique).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
-                let op_res = OperationResponse::success(None);
+                let build_id = self.storage.start_index_build(&req.collection, &req.name, req.fields, req.options).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let mut result = BTreeMap::new();
+                result.insert("build_id".to_string(), Value::String(build_id));
+                let op_res = OperationResponse::success(Some(Value::Object(result)));
                 let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
                 Ok(Response::ok(command.header.seq, payload))
             },
@@ -504,63 +1207,197 @@ impl ConnectionManager {
                 Ok(Response::ok(command.header.seq, payload))
             },
 
+            OpCode::Analyze => {
+                let req: crate::protocol::AnalyzeRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let analyzed = self.storage.analyze(&req.collection, req.index.as_deref()).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let indexes = analyzed
+                    .into_iter()
+                    .map(|(name, distribution)| {
+                        let mut value = distribution.to_value();
+                        if let Value::Object(map) = &mut value {
+                            map.insert("name".to_string(), Value::String(name));
+                        }
+                        value
+                    })
+                    .collect();
+                let op_res = OperationResponse::success(Some(Value::Array(indexes)));
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
+            OpCode::Explain => {
+                let req: crate::protocol::ExplainRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let explained = match (&req.query, &req.aggregate) {
+                    (Some(query_req), None) => {
+                        let query = Self::build_query(
+                            query_req.filter.as_ref(),
+                            query_req.projection.as_ref(),
+                            query_req.sort.as_ref(),
+                            query_req.skip,
+                            query_req.limit,
+                            query_req.collation.as_ref(),
+                        )?;
+                        self.storage.explain_query(&query_req.collection, &query, req.verbosity)
+                    }
+                    (None, Some(aggregate_req)) => {
+                        let pipeline = crate::aggregation::Pipeline::new(aggregate_req.pipeline.clone());
+                        self.storage.explain_aggregate(&aggregate_req.collection, &pipeline, req.verbosity)
+                    }
+                    _ => {
+                        return Err(ConnectionError::ProtocolError(
+                            "Explain needs exactly one of query or aggregate".to_string(),
+                        ))
+                    }
+                };
+                let explained = explained.map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let op_res = OperationResponse::success(Some(explained));
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
+            OpCode::ListPlanCache => {
+                let req: crate::protocol::ListPlanCacheRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let entries = self
+                    .storage
+                    .list_plan_cache(req.collection.as_deref())
+                    .into_iter()
+                    .map(|(collection, entry)| {
+                        let mut value = entry.to_value();
+                        if let Value::Object(map) = &mut value {
+                            map.insert("collection".to_string(), Value::String(collection));
+                        }
+                        value
+                    })
+                    .collect();
+                let op_res = OperationResponse::success(Some(Value::Array(entries)));
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
+            OpCode::ClearPlanCache => {
+                let req: crate::protocol::ClearPlanCacheRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let cleared = self.storage.clear_plan_cache(req.collection.as_deref(), req.shape.as_deref());
+                let mut result = BTreeMap::new();
+                result.insert("cleared".to_string(), Value::Int64(cleared as i64));
+                let op_res = OperationResponse::success(Some(Value::Object(result)));
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
+            // Index builds
+            OpCode::ListIndexBuilds => {
+                let req: crate::protocol::ListIndexBuildsRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let builds = self
+                    .storage
+                    .list_index_builds(req.collection.as_deref())
+                    .iter()
+                    .map(|progress| progress.to_value(self.storage.index_build_eta(progress)))
+                    .collect();
+                let op_res = OperationResponse::success(Some(Value::Array(builds)));
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
+            OpCode::GetIndexBuild => {
+                let req: crate::protocol::GetIndexBuildRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let op_res = match self.storage.get_index_build(&req.id) {
+                    Some(progress) => OperationResponse::success(Some(progress.to_value(self.storage.index_build_eta(&progress)))),
+                    None => OperationResponse::error(format!("Index build '{}' not found", req.id)),
+                };
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
+            OpCode::CancelIndexBuild => {
+                let req: crate::protocol::CancelIndexBuildRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let op_res = match self.storage.cancel_index_build(&req.id) {
+                    Ok(progress) => OperationResponse::success(Some(progress.to_value(None))),
+                    Err(e) => OperationResponse::error(format!("Failed to cancel index build: {}", e)),
+                };
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
+            // Views
+            OpCode::CreateView => {
+                let req: crate::protocol::CreateViewRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                let definition = if req.materialized {
+                    crate::storage::ViewDefinition::materialized(req.name, req.source, req.pipeline)
+                } else {
+                    crate::storage::ViewDefinition::plain(req.name, req.source, req.pipeline)
+                };
+                let op_res = match self.storage.create_view(definition) {
+                    Ok(()) => OperationResponse::success(None),
+                    Err(e) => OperationResponse::error(format!("Failed to create view: {}", e)),
+                };
+                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+                Ok(Response::ok(command.header.seq, payload))
+            },
+
             // Document Operations
             OpCode::Query => {
                 let req: crate::protocol::QueryRequest = serde_json::from_slice(&command.value)
                     .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
 
-                // Convert filter Value to plain JSON for parser
-                let filter_json = match &req.filter {
-                    Some(f) => Self::value_to_plain_json(f),
-                    None => serde_json::Value::Object(serde_json::Map::new()),
+                let query = Self::build_query(
+                    req.filter.as_ref(),
+                    req.projection.as_ref(),
+                    req.sort.as_ref(),
+                    req.skip,
+                    req.limit,
+                    req.collation.as_ref(),
+                )?;
+                let point = match (req.as_of, req.version) {
+                    (Some(_), Some(_)) => {
+                        return Err(ConnectionError::ProtocolError("A query takes as_of or version, not both".to_string()));
+                    }
+                    (Some(time), None) => Some(crate::storage::HistoryPoint::AsOf(time)),
+                    (None, Some(version)) => Some(crate::storage::HistoryPoint::Version(version)),
+                    (None, None) => None,
                 };
-                
-                // Check if filter is empty object -> match all
-                let documents = if let serde_json::Value::Object(map) = &filter_json {
-                    if map.is_empty() {
-                         // Find all documents
-                         self.storage.scan_collection(&req.collection).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?
-                    } else {
-                         // Parse filter and find
-                         use crate::query::parser::QueryParser;
-                         use crate::query::executor::QueryExecutor;
-                         
-                         let filter = QueryParser::parse_filter(&filter_json)
-                             .map_err(|e| ConnectionError::ProtocolError(format!("Invalid filter: {}", e)))?;
-                             
-                         // Scan and filter
-                         let all_docs = self.storage.scan_collection(&req.collection).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
-                         let executor = QueryExecutor::new();
-                         
-                         let mut matched = Vec::new();
-                         for doc in all_docs {
-                             if executor.matches_filter(&doc, &filter).map_err(|e| ConnectionError::ProtocolError(e.to_string()))? {
-                                 matched.push(doc);
-                             }
-                         }
-                         matched
+                let documents = match point {
+                    Some(point) => self.storage.query_history(&req.collection, &query, point).await
+                        .map(|documents| documents.into_iter().map(Self::with_version_fields).collect()),
+                    None => self.storage.query(&req.collection, &query),
+                }
+                .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
+
+                let op_res = if req.extended_json {
+                    OperationResponse {
+                        documents: Some(documents.iter().map(extended_json::document_to_extended_json).collect()),
+                        ..OperationResponse::success(None)
                     }
                 } else {
-                    // Fallback for non-object filter (shouldn't happen for valid query)
-                    Vec::new()
+                    OperationResponse::success(Some(Value::Array(
+                        documents.into_iter().map(|mut d| {
+                            // View rows such as $group output carry their own _id
+                            d.fields.entry("_id".to_string()).or_insert_with(|| Value::String(d.id.to_string()));
+                            Value::Object(d.fields)
+                        }).collect()
+                    )))
                 };
-
-                let op_res = OperationResponse::success(Some(Value::Array(
-                    documents.into_iter().map(|mut d| {
-                        d.fields.insert("_id".to_string(), Value::String(d.id.to_string()));
-                        Value::Object(d.fields)
-                    }).collect()
-                )));
                 let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
                 Ok(Response::ok(command.header.seq, payload))
             },
 
             OpCode::InsertDoc => {
                 let req: InsertDocRequest = serde_json::from_slice(&command.value).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
-                self.storage.insert_document(&req.collection, req.document).await.map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
-                let op_res = OperationResponse::success(None);
-                let payload = serde_json::to_vec(&op_res).map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
-                Ok(Response::ok(command.header.seq, payload))
+                let doc_id = match self.storage.insert_document(&req.collection, req.document).await {
+                    Ok(doc_id) => doc_id,
+                    Err(e) => {
+                        if let Some(response) = Self::duplicate_key_response(command.header.seq, &req.collection, &e, None)? {
+                            return Ok(response);
+                        }
+                        if let Some(response) = Self::validation_failure_response(command.header.seq, &req.collection, &e, None)? {
+                            return Ok(response);
+                        }
+                        if let Some(response) = Self::reference_violation_response(command.header.seq, &req.collection, &e, None)? {
+                            return Ok(response);
+                        }
+                        return Err(ConnectionError::ProtocolError(e.to_string()));
+                    }
+                };
+                Ok(Response::ok(command.header.seq, Self::inserted_payload(doc_id)?))
             },
             OpCode::UpdateDoc => {
                 let req: UpdateDocRequest = serde_json::from_slice(&command.value)
@@ -568,8 +1405,6 @@ impl ConnectionManager {
                 
                 use crate::query::parser::QueryParser;
                 use crate::query::executor::QueryExecutor;
-                use crate::document::{Document, DocumentId};
-                
                 // Convert filter Value to plain JSON for parser
                 let filter_json = Self::value_to_plain_json(&req.filter);
                 let filter = QueryParser::parse_filter(&filter_json)
@@ -579,29 +1414,45 @@ impl ConnectionManager {
                 let documents = self.storage.scan_collection(&req.collection)
                     .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
                 
+                let update = DocumentUpdate::parse(req.update_format, &req.update)
+                    .map_err(|e| ConnectionError::ProtocolError(format!("Invalid update: {}", e)))?;
                 let executor = QueryExecutor::new();
                 let mut updated_count = 0;
+                let mut patch_failures = Vec::new();
                 
                 for doc in documents {
                     if executor.matches_filter(&doc, &filter)
                         .map_err(|e| ConnectionError::ProtocolError(format!("Filter matching error: {}", e)))?
                     {
-                        // Create updated document with same ID but new fields
-                        if let Value::Object(update_fields) = &req.update {
-                            let mut new_doc = Document::with_id(doc.id);
-                            new_doc.fields = update_fields.clone();
-                            
-                            match self.storage.update_document(&req.collection, doc.id, new_doc).await {
-                                Ok(()) => updated_count += 1,
-                                Err(e) => {
-                                    log::warn!("Failed to update document {}: {}", doc.id, e);
+                        let Some(update) = &update else { continue };
+                        match update.write(&self.storage, &req.collection, doc.id, &filter).await {
+                            Ok(UpdateOutcome::Updated) => updated_count += 1,
+                            Ok(UpdateOutcome::Skipped) => {}
+                            // A failed patch skips only this document
+                            Ok(UpdateOutcome::PatchFailed(e)) => patch_failures.push(Self::patch_failure(doc.id, &e)),
+                            Err(e) => {
+                                // Stop at a unique violation; the updates so far are kept
+                                if let Some(response) = Self::duplicate_key_response(command.header.seq, &req.collection, &e, Some(updated_count))? {
+                                    return Ok(response);
+                                }
+                                if let Some(response) = Self::validation_failure_response(command.header.seq, &req.collection, &e, Some(updated_count))? {
+                                    return Ok(response);
                                 }
+                                if let Some(response) = Self::reference_violation_response(command.header.seq, &req.collection, &e, Some(updated_count))? {
+                                    return Ok(response);
+                                }
+                                log::warn!("Failed to update document {}: {}", doc.id, e);
                             }
                         }
                     }
                 }
                 
-                let mut op_res = OperationResponse::success(None);
+                let data = (!patch_failures.is_empty()).then(|| {
+                    let mut data = BTreeMap::new();
+                    data.insert("patch_failures".to_string(), Value::Array(patch_failures));
+                    Value::Object(data)
+                });
+                let mut op_res = OperationResponse::success(data);
                 op_res.affected_count = Some(updated_count);
                 let payload = serde_json::to_vec(&op_res)
                     .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
@@ -631,15 +1482,30 @@ impl ConnectionManager {
                 
                 // Find all documents that match the filter
                 let mut deleted_count = 0;
+                let mut cascaded: BTreeMap<String, u64> = BTreeMap::new();
+                let mut nulled: BTreeMap<String, u64> = BTreeMap::new();
                 for doc in documents {
                     if executor.matches_filter(&doc, &filter)
                         .map_err(|e| ConnectionError::ProtocolError(format!("Filter matching error: {}", e)))?
                     {
-                        // Delete this document
-                        match self.storage.delete_document(&req.collection, doc.id).await {
-                            Ok(true) => deleted_count += 1,
-                            Ok(false) => {}, // Document already deleted  
+                        // Delete this document and apply on_delete actions of references to it
+                        match self.storage.delete_document_cascading(&req.collection, doc.id).await {
+                            Ok(outcome) => {
+                                if outcome.deleted {
+                                    deleted_count += 1;
+                                }
+                                for (collection, count) in outcome.cascaded {
+                                    *cascaded.entry(collection).or_default() += count;
+                                }
+                                for (collection, count) in outcome.nulled {
+                                    *nulled.entry(collection).or_default() += count;
+                                }
+                            }
                             Err(e) => {
+                                // Stop at a restricted delete; the deletes so far are kept
+                                if let Some(response) = Self::reference_violation_response(command.header.seq, &req.collection, &e, Some(deleted_count))? {
+                                    return Ok(response);
+                                }
                                 // Log error but continue deleting other documents
                                 log::warn!("Failed to delete document {}: {}", doc.id, e);
                             }
@@ -648,7 +1514,16 @@ impl ConnectionManager {
                 }
                 
                 // Return operation response with accurate deletion count
-                let mut op_res = OperationResponse::success(None);
+                let counts = |counts: BTreeMap<String, u64>| {
+                    Value::Object(counts.into_iter().map(|(collection, count)| (collection, Value::Int64(count as i64))).collect())
+                };
+                let data = (!cascaded.is_empty() || !nulled.is_empty()).then(|| {
+                    let mut data = BTreeMap::new();
+                    data.insert("cascaded".to_string(), counts(cascaded));
+                    data.insert("nulled".to_string(), counts(nulled));
+                    Value::Object(data)
+                });
+                let mut op_res = OperationResponse::success(data);
                 op_res.affected_count = Some(deleted_count);
                 let payload = serde_json::to_vec(&op_res)
                     .map_err(|e| ConnectionError::ProtocolError(e.to_string()))?;
@@ -1250,19 +2125,11 @@ impl ConnectionManager {
                 let req: crate::protocol::AggregateRequest = serde_json::from_slice(&command.value)
                     .map_err(|e| ConnectionError::ProtocolError(format!("Invalid aggregation request: {}", e)))?;
                 
-                // Get collection documents as iterator for streaming execution
-                let collection_name = req.collection.clone();
-                
-                // Create pipeline executor
+                // Storage picks the pipeline's input: a vector index for a
+                // leading $vectorSearch, otherwise the whole collection or view
                 let pipeline = crate::aggregation::Pipeline::new(req.pipeline);
                 
-                // Get documents from storage
-                // Note: Current API returns Vec. In future, add true streaming iterator for better memory efficiency.
-                let documents = self.storage.scan_collection(&collection_name)
-                    .map_err(|e| ConnectionError::ProtocolError(format!("Failed to scan collection: {}", e)))?;
-                
-                // Execute aggregation pipeline with streaming engine
-                match pipeline.execute(documents) {
+                match self.storage.aggregate(&req.collection, &pipeline) {
                     Ok(results) => {
                         // Convert results to Value::Array
                         use crate::document::Value;
@@ -1563,6 +2430,334 @@ mod tests {
     use crate::auth::{AuthSystem, JwtService, Role};
     use crate::protocol::PROTOCOL_V2;
 
+    #[test]
+    fn test_duplicate_key_response() {
+        let violation = IndexError::UniqueConstraintViolation {
+            index: "email".to_string(),
+            key: crate::index::btree::DuplicateKey {
+                fields: vec![("email".to_string(), Value::String("a@example.com".to_string()))],
+            },
+        };
+        let error = anyhow::Error::new(violation).context("Failed to insert");
+
+        let response = ConnectionManager::duplicate_key_response(7, "users", &error, Some(2)).unwrap().unwrap();
+        assert_eq!(response.header.status(), Ok(Status::DuplicateKey));
+        let seq = response.header.seq;
+        assert_eq!(seq, 7);
+        let op_res = OperationResponse::from_bytes(&response.payload).unwrap();
+        assert!(!op_res.success);
+        assert_eq!(op_res.affected_count, Some(2));
+        let Some(Value::Object(details)) = op_res.data else { panic!("missing details") };
+        assert_eq!(details["code"], Value::String("duplicate_key".to_string()));
+        assert_eq!(details["collection"], Value::String("users".to_string()));
+        assert_eq!(details["index"], Value::String("email".to_string()));
+        let Value::Object(key) = &details["key"] else { panic!("key is not an object") };
+        assert_eq!(key["email"], Value::String("a@example.com".to_string()));
+
+        let other = anyhow::anyhow!("disk full");
+        assert!(ConnectionManager::duplicate_key_response(7, "users", &other, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_validation_failure_response() {
+        let violation = ValidationError::RequiredFieldMissing("email".to_string());
+        let error = anyhow::Error::new(violation).context("Document failed validation for collection 'users'");
+
+        let response = ConnectionManager::validation_failure_response(9, "users", &error, Some(1)).unwrap().unwrap();
+        assert_eq!(response.header.status(), Ok(Status::DocumentValidationFailed));
+        let op_res = OperationResponse::from_bytes(&response.payload).unwrap();
+        assert!(!op_res.success);
+        assert_eq!(op_res.affected_count, Some(1));
+        let Some(Value::Object(details)) = op_res.data else { panic!("missing details") };
+        assert_eq!(details["code"], Value::String("validation_failed".to_string()));
+        assert_eq!(details["reason"], Value::String("Required field missing: email".to_string()));
+
+        let other = anyhow::anyhow!("disk full");
+        assert!(ConnectionManager::validation_failure_response(9, "users", &other, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reference_violation_response() {
+        let violation = ReferenceError::Restricted {
+            collection: "customers".to_string(),
+            id: crate::document::DocumentId::new(),
+            referencing: "orders".to_string(),
+            field: "customer".to_string(),
+            count: 2,
+        };
+        let error = anyhow::Error::new(violation);
+
+        let response = ConnectionManager::reference_violation_response(4, "customers", &error, Some(3)).unwrap().unwrap();
+        assert_eq!(response.header.status(), Ok(Status::ReferenceViolation));
+        let op_res = OperationResponse::from_bytes(&response.payload).unwrap();
+        assert!(!op_res.success);
+        assert_eq!(op_res.affected_count, Some(3));
+        let Some(Value::Object(details)) = op_res.data else { panic!("missing details") };
+        assert_eq!(details["code"], Value::String("reference_violation".to_string()));
+        assert_eq!(details["collection"], Value::String("customers".to_string()));
+
+        let other = anyhow::anyhow!("disk full");
+        assert!(ConnectionManager::reference_violation_response(4, "customers", &other, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_typed_filters_survive_the_wire() {
+        use crate::document::{Document, ObjectId};
+        use crate::query::QueryExecutor;
+
+        let owner = ObjectId::new();
+        let created = chrono::DateTime::from_timestamp(1_700_000_000, 123_456_789).unwrap();
+        let mut doc = Document::new();
+        doc.insert("owner".to_string(), Value::ObjectId(owner));
+        doc.insert("created".to_string(), Value::DateTime(created));
+        doc.insert("blob".to_string(), Value::Binary(vec![1, 2, 3]));
+        doc.insert("views".to_string(), Value::Int64(42));
+
+        let mut filter = BTreeMap::new();
+        filter.insert("owner".to_string(), Value::ObjectId(owner));
+        filter.insert("blob".to_string(), Value::Binary(vec![1, 2, 3]));
+        filter.insert("views".to_string(), Value::Int64(42));
+        let mut after = BTreeMap::new();
+        after.insert("$gte".to_string(), Value::DateTime(created));
+        filter.insert("created".to_string(), Value::Object(after));
+        let mut sort = BTreeMap::new();
+        sort.insert("views".to_string(), Value::Int64(-1));
+
+        // The request travels as bytes, as it does over a connection
+        let request = QueryRequest {
+            collection: "events".to_string(),
+            filter: Some(Value::Object(filter.clone())),
+            projection: None,
+            sort: Some(Value::Object(sort)),
+            skip: None,
+            limit: None,
+            collation: None,
+            extended_json: true,
+            as_of: None,
+            version: None,
+        };
+        let request = QueryRequest::from_bytes(&request.to_bytes().unwrap()).unwrap();
+        let query = ConnectionManager::build_query(request.filter.as_ref(), None, request.sort.as_ref(), None, None, None).unwrap();
+        let executor = QueryExecutor::new();
+        assert!(executor.matches_filter(&doc, &query.filter).unwrap());
+
+        // A strictly greater date excludes the document
+        let mut newer = BTreeMap::new();
+        newer.insert("$gt".to_string(), Value::DateTime(created));
+        filter.insert("created".to_string(), Value::Object(newer));
+        let query = ConnectionManager::build_query(Some(&Value::Object(filter)), None, None, None, None, None).unwrap();
+        assert!(!executor.matches_filter(&doc, &query.filter).unwrap());
+
+        let response = OperationResponse {
+            documents: Some(vec![extended_json::document_to_extended_json(&doc)]),
+            ..OperationResponse::success(None)
+        };
+        let response = OperationResponse::from_bytes(&response.to_bytes().unwrap()).unwrap();
+        let returned = &response.documents.unwrap()[0];
+        assert_eq!(returned["_id"], serde_json::Value::String(doc.id.to_string()));
+        assert_eq!(returned["owner"]["$oid"], serde_json::Value::String(owner.to_string()));
+        assert_eq!(extended_json::from_extended_json(&returned["created"]).unwrap(), Value::DateTime(created));
+        assert_eq!(extended_json::from_extended_json(&returned["views"]).unwrap(), Value::Int64(42));
+    }
+
+    #[test]
+    fn test_update_formats() {
+        let mut doc = Document::new();
+        doc.insert("stock".to_string(), Value::Int32(5));
+        doc.insert("name".to_string(), Value::String("widget".to_string()));
+
+        // Patches arrive inside the request like any other update value
+        let payload = br#"{"collection":"items","filter":{"type":"Object","value":{}},"upsert":false,
+            "update_format":"json_patch",
+            "update":{"type":"Array","value":[
+                {"type":"Object","value":{"op":{"type":"String","value":"test"},"path":{"type":"String","value":"/stock"},"value":{"type":"Int64","value":5}}},
+                {"type":"Object","value":{"op":{"type":"String","value":"replace"},"path":{"type":"String","value":"/stock"},"value":{"type":"Int32","value":4}}}
+            ]}}"#;
+        let req: UpdateDocRequest = serde_json::from_slice(payload).unwrap();
+        let update = DocumentUpdate::parse(req.update_format, &req.update).unwrap().unwrap();
+        let fields = update.apply(&doc).unwrap();
+        assert_eq!(fields["stock"], Value::Int32(4));
+        assert_eq!(fields["name"], Value::String("widget".to_string()));
+
+        // A stale test fails this document only
+        doc.insert("stock".to_string(), Value::Int32(3));
+        let error = update.apply(&doc).unwrap_err();
+        let Value::Object(failure) = ConnectionManager::patch_failure(doc.id, &error) else { panic!("not an object") };
+        assert_eq!(failure["code"], Value::String("test_failed".to_string()));
+        assert_eq!(failure["_id"], Value::String(doc.id.to_string()));
+
+        let mut members = BTreeMap::new();
+        members.insert("name".to_string(), Value::Null);
+        members.insert("color".to_string(), Value::String("red".to_string()));
+        let merge = Value::Object(members);
+        let fields = DocumentUpdate::parse(UpdateFormat::MergePatch, &merge).unwrap().unwrap().apply(&doc).unwrap();
+        assert_eq!(fields.keys().collect::<Vec<_>>(), ["color", "stock"]);
+
+        // Requests without a format keep replacing whole documents
+        let legacy: UpdateDocRequest = serde_json::from_slice(
+            br#"{"collection":"items","filter":{"type":"Object","value":{}},"update":{"type":"Object","value":{}},"upsert":false}"#,
+        ).unwrap();
+        assert_eq!(legacy.update_format, UpdateFormat::Replace);
+        assert!(DocumentUpdate::parse(UpdateFormat::Replace, &legacy.update).unwrap().unwrap().apply(&doc).unwrap().is_empty());
+        assert!(DocumentUpdate::parse(UpdateFormat::Replace, &Value::Int32(1)).unwrap().is_none());
+
+        assert_eq!(DocumentUpdate::parse(UpdateFormat::MergePatch, &Value::Int32(1)).err(), Some(PatchError::NotAnObject));
+        assert!(matches!(DocumentUpdate::parse(UpdateFormat::JsonPatch, &merge), Err(PatchError::Invalid(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_patch_tests_hold_under_concurrent_updates() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let persistent = Arc::new(crate::storage::PersistentLayer::new(temp_dir.path()).unwrap());
+        let storage = Arc::new(HybridStorageEngine::new(Default::default(), persistent));
+        let mut doc = Document::new();
+        doc.insert("stock".to_string(), Value::Int32(1));
+        let id = storage.insert_document("items", doc).await.unwrap();
+
+        // Every writer takes the last unit only if it is still there
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let patch = serde_json::json!([
+                        {"op": "test", "path": "/stock", "value": 1},
+                        {"op": "replace", "path": "/stock", "value": 0}
+                    ]);
+                    let update = DocumentUpdate::JsonPatch(JsonPatch::from_json(&patch).unwrap());
+                    update.write(&storage, "items", id, &crate::query::Filter::Empty).await.unwrap()
+                })
+            })
+            .collect();
+        let mut taken = 0;
+        for writer in writers {
+            match writer.await.unwrap() {
+                UpdateOutcome::Updated => taken += 1,
+                UpdateOutcome::PatchFailed(e) => assert!(matches!(e, PatchError::TestFailed { .. })),
+                UpdateOutcome::Skipped => panic!("the document still matches"),
+            }
+        }
+        assert_eq!(taken, 1);
+        let stored = storage.get_document("items", id).await.unwrap().unwrap();
+        assert_eq!(stored.get("stock").and_then(Value::as_i64), Some(0));
+
+        // A document that stopped matching once locked is left alone
+        let merge = Value::Object(BTreeMap::from([("stock".to_string(), Value::Int32(5))]));
+        let restock = DocumentUpdate::MergePatch(&merge);
+        let filter = crate::query::Filter::eq("stock", 1);
+        assert!(matches!(restock.write(&storage, "items", id, &filter).await.unwrap(), UpdateOutcome::Skipped));
+    }
+
+    #[test]
+    fn test_tail_request_and_payload() {
+        let mut doc = Document::new();
+        doc.insert("level".to_string(), Value::String("error".to_string()));
+
+        let payload = br#"{"collection":"log","filter":{"type":"Object","value":{"level":{"type":"String","value":"error"}}},"batch_size":10}"#;
+        let (req, filter) = ConnectionManager::parse_tail(payload).unwrap();
+        assert_eq!((req.collection.as_str(), req.after, req.batch_size), ("log", None, Some(10)));
+        assert!(crate::query::QueryExecutor::new().matches_filter(&doc, &filter.unwrap()).unwrap());
+
+        let command = Command::tail(7, &req).unwrap();
+        assert_eq!(command.header.opcode().unwrap(), OpCode::Tail);
+
+        let response: OperationResponse = serde_json::from_slice(&ConnectionManager::tail_payload(vec![doc.clone()], Some(doc.id)).unwrap()).unwrap();
+        assert_eq!(response.affected_count, Some(1));
+        let Some(Value::Object(data)) = response.data else { panic!("not an object") };
+        assert_eq!(data["cursor"], Value::String(doc.id.to_string()));
+        let Value::Array(documents) = &data["documents"] else { panic!("not an array") };
+        let Value::Object(first) = &documents[0] else { panic!("not an object") };
+        assert_eq!(first["_id"], Value::String(doc.id.to_string()));
+
+        // An empty first batch still tells the client the cursor is open
+        let response: OperationResponse = serde_json::from_slice(&ConnectionManager::tail_payload(Vec::new(), None).unwrap()).unwrap();
+        let Some(Value::Object(data)) = response.data else { panic!("not an object") };
+        assert_eq!(data["cursor"], Value::Null);
+
+        assert!(ConnectionManager::parse_tail(br#"{"filter":null}"#).is_err());
+        assert!(ConnectionManager::parse_tail(br#"{"collection":"log","filter":{"type":"Int32","value":1}}"#).is_err());
+    }
+
+    #[test]
+    fn test_inserted_payload_returns_the_stored_id() {
+        let doc_id = crate::document::DocumentId::new();
+        let response = OperationResponse::from_bytes(&ConnectionManager::inserted_payload(doc_id).unwrap()).unwrap();
+        assert!(response.success);
+        assert_eq!(response.affected_count, Some(1));
+        let Some(Value::Object(data)) = response.data else { panic!("not an object") };
+        assert_eq!(data["_id"], Value::String(doc_id.to_string()));
+    }
+
+    #[test]
+    fn test_watch_request_and_payload() {
+        let payload = br#"{"collection":"orders","resumeAfter":"000000000000002a","fullDocument":true,
+            "$match":{"type":"Object","value":{"operationType":{"type":"String","value":"update"}}}}"#;
+        let (options, batch_size) = ConnectionManager::parse_watch(payload).unwrap();
+        assert_eq!(options.collection.as_deref(), Some("orders"));
+        assert_eq!(options.resume_after, Some(crate::replication::ResumeToken::new(42)));
+        assert!(options.full_document && options.filter.is_some());
+        assert_eq!(batch_size, DEFAULT_TAIL_BATCH);
+        assert_eq!(Command::watch(3, &crate::protocol::WatchRequest::default()).unwrap().header.opcode().unwrap(), OpCode::Watch);
+
+        let event = crate::replication::ChangeEvent {
+            token: crate::replication::ResumeToken::new(43),
+            kind: crate::replication::ChangeKind::Delete,
+            collection: "orders".to_string(),
+            document_id: crate::document::DocumentId::new(),
+            timestamp: chrono::Utc::now(),
+            full_document: None,
+            updated_fields: BTreeMap::new(),
+            removed_fields: Vec::new(),
+        };
+        let response: OperationResponse = serde_json::from_slice(&ConnectionManager::watch_payload(&[event.clone()], Some(event.token)).unwrap()).unwrap();
+        let Some(Value::Object(data)) = response.data else { panic!("not an object") };
+        assert_eq!(data["resume_token"], Value::String("000000000000002b".to_string()));
+        let Value::Array(events) = &data["events"] else { panic!("not an array") };
+        let Value::Object(first) = &events[0] else { panic!("not an object") };
+        assert_eq!(first["operationType"], Value::String("delete".to_string()));
+
+        assert!(ConnectionManager::parse_watch(br#"{"resume_after":"not-a-token"}"#).is_err());
+    }
+
+    #[test]
+    fn test_file_requests_and_payloads() {
+        let put: crate::protocol::PutFileRequest =
+            serde_json::from_slice(br#"{"filename":"a.bin","contentType":"application/octet-stream","chunkSize":4,"data":"AAEC","finish":true}"#).unwrap();
+        assert_eq!(BASE64.decode(&put.data).unwrap(), [0, 1, 2]);
+        assert_eq!((put.chunk_size, put.file_id, put.finish), (Some(4), None, true));
+        assert_eq!(Command::put_file(1, &put).unwrap().header.opcode().unwrap(), OpCode::PutFile);
+        let get: crate::protocol::GetFileRequest =
+            serde_json::from_slice(format!(r#"{{"fileId":"{}","start":2}}"#, uuid::Uuid::nil()).as_bytes()).unwrap();
+        assert_eq!((get.start, get.end), (Some(2), None));
+        assert_eq!(Command::get_file(2, &get).unwrap().header.opcode().unwrap(), OpCode::GetFile);
+        assert!(serde_json::from_slice::<crate::protocol::DeleteFileRequest>(br#"{"bucket":"fs"}"#).is_err());
+        let list = crate::protocol::ListFilesRequest::default();
+        assert_eq!(Command::list_files(3, &list).unwrap().header.opcode().unwrap(), OpCode::ListFiles);
+
+        let info = FileInfo {
+            id: crate::document::DocumentId::new(),
+            filename: "a.bin".to_string(),
+            length: 3,
+            chunk_size: 4,
+            content_type: None,
+            metadata: BTreeMap::new(),
+            upload_date: chrono::Utc::now(),
+            sha256: Some("00".to_string()),
+        };
+        let response: OperationResponse = serde_json::from_slice(&ConnectionManager::file_payload(&info, &(1..3), Some(&[1, 2])).unwrap()).unwrap();
+        let Some(Value::Object(data)) = response.data else { panic!("not an object") };
+        assert_eq!(data["data"], Value::String("AQI=".to_string()));
+        let Value::Object(file) = &data["file"] else { panic!("not an object") };
+        assert_eq!(file["_id"], Value::String(info.id.to_string()));
+        assert_eq!(file["finished"], Value::Bool(true));
+        let Value::Object(range) = &data["range"] else { panic!("not an object") };
+        assert_eq!((&range["start"], &range["end"]), (&Value::Int64(1), &Value::Int64(3)));
+
+        let response: OperationResponse = serde_json::from_slice(&ConnectionManager::file_data_payload(4, &[9]).unwrap()).unwrap();
+        assert_eq!(response.affected_count, Some(1));
+        let Some(Value::Object(data)) = response.data else { panic!("not an object") };
+        assert_eq!((&data["offset"], &data["data"]), (&Value::Int64(4), &Value::String("CQ==".to_string())));
+    }
+
     #[tokio::test]
     async fn test_session_creation() {
         let user = User {
@@ -3,6 +3,7 @@
 //! Defines the structure for MongoDB-compatible queries
 
 use crate::document::Value;
+use super::collation::Collation;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
@@ -19,6 +20,9 @@ pub struct Query {
     pub skip: Option<u64>,
     /// Maximum number of documents to return
     pub limit: Option<u64>,
+    /// String comparison rules for filters and sort
+    #[serde(default)]
+    pub collation: Option<Collation>,
 }
 
 impl Query {
@@ -30,6 +34,7 @@ impl Query {
             sort: None,
             skip: None,
             limit: None,
+            collation: None,
         }
     }
 
@@ -41,6 +46,7 @@ impl Query {
             sort: None,
             skip: None,
             limit: None,
+            collation: None,
         }
     }
 
@@ -68,6 +74,12 @@ impl Query {
         self
     }
 
+    /// Set collation
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.collation = Some(collation);
+        self
+    }
+
     /// Check if this is a simple key lookup (single equality filter)
     pub fn is_simple_key_lookup(&self) -> bool {
         matches!(self.filter, Filter::Eq { .. })
@@ -160,16 +172,191 @@ pub enum Filter {
         options: Option<String>,
     },
 
+    /// All: array field contains every value
+    All {
+        field: String,
+        values: Vec<Value>,
+    },
+
+    /// Element match: some array element matches the filter
+    ///
+    /// Paths in `filter` are relative to the element; the empty path is
+    /// the element itself.
+    ElemMatch {
+        field: String,
+        filter: Box<Filter>,
+    },
+
+    /// Size: array field has exactly `size` elements
+    Size {
+        field: String,
+        size: usize,
+    },
+
+    /// Type: field, or one of its array elements, has one of the types
+    Type {
+        field: String,
+        types: Vec<ValueType>,
+    },
+
+    /// Modulo: numeric field divided by `divisor` leaves `remainder`
+    ///
+    /// Fractional values are truncated toward zero first.
+    Mod {
+        field: String,
+        divisor: i64,
+        remainder: i64,
+    },
+
+    /// Bitwise: integer field has every bit of `mask` set
+    BitsAllSet {
+        field: String,
+        mask: u64,
+    },
+
+    /// Bitwise: integer field has some bit of `mask` set
+    BitsAnySet {
+        field: String,
+        mask: u64,
+    },
+
+    /// Expression: comparison between fields of the same document
+    ///
+    /// Operands compare whole values: an array field is not expanded into
+    /// its elements, and a missing field sorts before null.
+    Expr {
+        op: ExprOp,
+        left: ExprOperand,
+        right: ExprOperand,
+    },
+
     /// Logical AND: all conditions must match
     And(Vec<Filter>),
 
     /// Logical OR: at least one condition must match
     Or(Vec<Filter>),
 
+    /// Logical NOR: no condition may match
+    Nor(Vec<Filter>),
+
     /// Logical NOT: condition must not match
     Not(Box<Filter>),
 }
 
+/// Value type named by a `$type` filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ValueType {
+    Null,
+    Bool,
+    /// 32-bit integer
+    Int,
+    /// 64-bit integer
+    Long,
+    Double,
+    /// 128-bit decimal
+    Decimal,
+    /// Any of `int`, `long`, `double` and `decimal`
+    Number,
+    String,
+    /// Binary data, UUIDs included
+    BinData,
+    Array,
+    Object,
+    ObjectId,
+    Date,
+    Timestamp,
+}
+
+impl ValueType {
+    /// Type for a name such as `"string"` or `"objectId"`
+    pub fn from_alias(alias: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(alias.to_string())).ok()
+    }
+
+    /// Type for a BSON type number such as 2 for strings
+    pub fn from_code(code: i64) -> Option<Self> {
+        Some(match code {
+            1 => ValueType::Double,
+            2 => ValueType::String,
+            3 => ValueType::Object,
+            4 => ValueType::Array,
+            5 => ValueType::BinData,
+            7 => ValueType::ObjectId,
+            8 => ValueType::Bool,
+            9 => ValueType::Date,
+            10 => ValueType::Null,
+            16 => ValueType::Int,
+            17 => ValueType::Timestamp,
+            18 => ValueType::Long,
+            19 => ValueType::Decimal,
+            _ => return None,
+        })
+    }
+
+    /// Check whether a value has this type
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            ValueType::Null => matches!(value, Value::Null),
+            ValueType::Bool => matches!(value, Value::Bool(_)),
+            ValueType::Int => matches!(value, Value::Int32(_)),
+            ValueType::Long => matches!(value, Value::Int64(_)),
+            ValueType::Double => matches!(value, Value::Float64(_)),
+            ValueType::Decimal => matches!(value, Value::Decimal128(_)),
+            ValueType::Number => value.is_number(),
+            ValueType::String => matches!(value, Value::String(_)),
+            ValueType::BinData => matches!(value, Value::Binary(_) | Value::Uuid(_)),
+            ValueType::Array => matches!(value, Value::Array(_)),
+            ValueType::Object => matches!(value, Value::Object(_)),
+            ValueType::ObjectId => matches!(value, Value::ObjectId(_)),
+            ValueType::Date => matches!(value, Value::DateTime(_)),
+            ValueType::Timestamp => matches!(value, Value::Timestamp(_)),
+        }
+    }
+}
+
+/// Comparison made by an `$expr` filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExprOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl ExprOp {
+    /// Operator for a name such as `"$gte"`
+    pub fn from_operator(operator: &str) -> Option<Self> {
+        let name = operator.strip_prefix('$')?;
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+
+    /// Whether the comparison holds for the ordering of left to right
+    pub fn holds(&self, ordering: std::cmp::Ordering) -> bool {
+        use std::cmp::Ordering;
+        match self {
+            ExprOp::Eq => ordering == Ordering::Equal,
+            ExprOp::Ne => ordering != Ordering::Equal,
+            ExprOp::Gt => ordering == Ordering::Greater,
+            ExprOp::Gte => ordering != Ordering::Less,
+            ExprOp::Lt => ordering == Ordering::Less,
+            ExprOp::Lte => ordering != Ordering::Greater,
+        }
+    }
+}
+
+/// Side of an `$expr` comparison
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ExprOperand {
+    /// Value at a field path, written `"$path"`
+    Field(String),
+    /// Constant value
+    Literal(Value),
+}
+
 impl Filter {
     /// Create an equality filter
     pub fn eq(field: impl Into<String>, value: impl Into<Value>) -> Self {
@@ -252,6 +439,72 @@ impl Filter {
         }
     }
 
+    /// Create an all filter
+    pub fn all(field: impl Into<String>, values: Vec<Value>) -> Self {
+        Self::All {
+            field: field.into(),
+            values,
+        }
+    }
+
+    /// Create an element match filter
+    pub fn elem_match(field: impl Into<String>, filter: Filter) -> Self {
+        Self::ElemMatch {
+            field: field.into(),
+            filter: Box::new(filter),
+        }
+    }
+
+    /// Create an array size filter
+    pub fn size(field: impl Into<String>, size: usize) -> Self {
+        Self::Size {
+            field: field.into(),
+            size,
+        }
+    }
+
+    /// Create a type filter
+    pub fn has_type(field: impl Into<String>, value_type: ValueType) -> Self {
+        Self::Type {
+            field: field.into(),
+            types: vec![value_type],
+        }
+    }
+
+    /// Create a modulo filter
+    pub fn modulo(field: impl Into<String>, divisor: i64, remainder: i64) -> Self {
+        Self::Mod {
+            field: field.into(),
+            divisor,
+            remainder,
+        }
+    }
+
+    /// Create a filter requiring every bit of `mask`
+    pub fn bits_all_set(field: impl Into<String>, mask: u64) -> Self {
+        Self::BitsAllSet {
+            field: field.into(),
+            mask,
+        }
+    }
+
+    /// Create a filter requiring some bit of `mask`
+    pub fn bits_any_set(field: impl Into<String>, mask: u64) -> Self {
+        Self::BitsAnySet {
+            field: field.into(),
+            mask,
+        }
+    }
+
+    /// Create a filter comparing two fields
+    pub fn fields_compare(op: ExprOp, left: impl Into<String>, right: impl Into<String>) -> Self {
+        Self::Expr {
+            op,
+            left: ExprOperand::Field(left.into()),
+            right: ExprOperand::Field(right.into()),
+        }
+    }
+
     /// Create an AND filter
     pub fn and(filters: Vec<Filter>) -> Self {
         Self::And(filters)
@@ -262,6 +515,11 @@ impl Filter {
         Self::Or(filters)
     }
 
+    /// Create a NOR filter
+    pub fn nor(filters: Vec<Filter>) -> Self {
+        Self::Nor(filters)
+    }
+
     /// Create a NOT filter
     pub fn not(filter: Filter) -> Self {
         Self::Not(Box::new(filter))
@@ -288,10 +546,24 @@ impl Filter {
             | Filter::In { field, .. }
             | Filter::Nin { field, .. }
             | Filter::Exists { field, .. }
-            | Filter::Regex { field, .. } => {
+            | Filter::Regex { field, .. }
+            | Filter::All { field, .. }
+            | Filter::ElemMatch { field, .. }
+            | Filter::Size { field, .. }
+            | Filter::Type { field, .. }
+            | Filter::Mod { field, .. }
+            | Filter::BitsAllSet { field, .. }
+            | Filter::BitsAnySet { field, .. } => {
                 fields.push(field.clone());
             }
-            Filter::And(filters) | Filter::Or(filters) => {
+            Filter::Expr { left, right, .. } => {
+                for operand in [left, right] {
+                    if let ExprOperand::Field(field) = operand {
+                        fields.push(field.clone());
+                    }
+                }
+            }
+            Filter::And(filters) | Filter::Or(filters) | Filter::Nor(filters) => {
                 for f in filters {
                     f.collect_fields(fields);
                 }
@@ -321,6 +593,22 @@ impl Filter {
             | Filter::Nin { field: f, .. } => f == field,
             Filter::Exists { field: f, .. } => f == field,
             Filter::Regex { field: f, .. } => f == field,
+            Filter::All { field: f, values } => f == field && !values.is_empty(),
+            Filter::ElemMatch { field: f, filter } => {
+                let inner = if f == field {
+                    Some("")
+                } else {
+                    field.strip_prefix(f.as_str()).and_then(|rest| rest.strip_prefix('.'))
+                };
+                inner.is_some_and(|inner| filter.can_use_index(inner))
+            }
+            Filter::Size { .. }
+            | Filter::Type { .. }
+            | Filter::Mod { .. }
+            | Filter::BitsAllSet { .. }
+            | Filter::BitsAnySet { .. }
+            | Filter::Expr { .. }
+            | Filter::Nor(_) => false,
             Filter::And(filters) => filters.iter().any(|f| f.can_use_index(field)),
             Filter::Or(filters) => filters.iter().all(|f| f.can_use_index(field)),
             Filter::Not(filter) => filter.can_use_index(field),
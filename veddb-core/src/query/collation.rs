@@ -0,0 +1,316 @@
+//! String collation for indexes, filters and sorts
+//!
+//! A collation turns each string into a collation key whose byte order is
+//! the collation's order, so index keys, `Sort` and equality filters all
+//! compare strings the same way.
+
+use super::ast::Filter;
+use crate::document::Value;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// Locale that compares strings byte-wise, as if no collation were given
+pub const SIMPLE_LOCALE: &str = "simple";
+
+/// Width of the digit count that prefixes each number under numeric ordering
+const DIGIT_COUNT_WIDTH: usize = 4;
+
+/// How strings compare
+///
+/// `strength` 1 ignores case and diacritics, 2 ignores case only, and 3
+/// compares case-insensitively first and breaks ties by exact spelling,
+/// so only identical strings are equal. With `numeric_ordering` runs of
+/// digits compare by their numeric value, so `"item9"` sorts before
+/// `"item10"`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Collation {
+    /// Language tag such as `en` or `tr`, or `simple` for byte-wise comparison
+    pub locale: String,
+    /// Comparison level, 1 to 3
+    #[serde(default = "default_strength")]
+    pub strength: u8,
+    /// Compare runs of digits as numbers
+    #[serde(default, alias = "numericOrdering")]
+    pub numeric_ordering: bool,
+}
+
+fn default_strength() -> u8 {
+    3
+}
+
+impl Collation {
+    /// Collation for a locale at the default strength
+    pub fn new(locale: impl Into<String>) -> Self {
+        Self {
+            locale: locale.into(),
+            strength: default_strength(),
+            numeric_ordering: false,
+        }
+    }
+
+    /// Set the comparison level
+    pub fn with_strength(mut self, strength: u8) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    /// Compare runs of digits as numbers
+    pub fn with_numeric_ordering(mut self) -> Self {
+        self.numeric_ordering = true;
+        self
+    }
+
+    /// Check the locale tag and strength
+    pub fn validate(&self) -> Result<(), CollationError> {
+        if !(1..=3).contains(&self.strength) {
+            return Err(CollationError::InvalidStrength(self.strength));
+        }
+        if self.is_simple() {
+            return Ok(());
+        }
+
+        let mut parts = self.locale.split(['-', '_']);
+        let language = parts.next().unwrap_or_default();
+        let valid = (2..=3).contains(&language.len())
+            && language.chars().all(|c| c.is_ascii_alphabetic())
+            && parts.all(|part| (2..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric()));
+        if !valid {
+            return Err(CollationError::InvalidLocale(self.locale.clone()));
+        }
+        Ok(())
+    }
+
+    /// Whether strings compare byte-wise
+    pub fn is_simple(&self) -> bool {
+        self.locale == SIMPLE_LOCALE
+    }
+
+    /// Collation key of a string; keys compare as the strings collate
+    pub fn key(&self, s: &str) -> String {
+        if self.is_simple() {
+            return s.to_string();
+        }
+
+        let folded = match self.strength {
+            1 => fold_diacritics(&self.fold_case(s)),
+            _ => self.fold_case(s),
+        };
+        let primary = if self.numeric_ordering { number_keys(&folded) } else { folded };
+        if self.strength < 3 {
+            return primary;
+        }
+
+        // NUL sorts before every other character, so the exact spelling
+        // only decides between strings whose folded forms are equal
+        let mut key = primary;
+        key.push('\0');
+        key.push_str(s);
+        key
+    }
+
+    /// Compare two strings under this collation
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        if self.is_simple() {
+            return a.cmp(b);
+        }
+        self.key(a).cmp(&self.key(b))
+    }
+
+    /// Replace strings, also inside arrays, with their collation keys
+    pub fn key_value(&self, value: &Value) -> Value {
+        match value {
+            Value::String(s) => Value::String(self.key(s)),
+            Value::Array(values) => Value::Array(values.iter().map(|v| self.key_value(v)).collect()),
+            other => other.clone(),
+        }
+    }
+
+    /// Rewrite a filter's comparison values as collation keys
+    ///
+    /// Regular expressions keep their pattern; they never use collation.
+    pub fn key_filter(&self, filter: &Filter) -> Filter {
+        let keys = |values: &[Value]| values.iter().map(|v| self.key_value(v)).collect();
+        match filter {
+            Filter::Eq { field, value } => Filter::Eq { field: field.clone(), value: self.key_value(value) },
+            Filter::Ne { field, value } => Filter::Ne { field: field.clone(), value: self.key_value(value) },
+            Filter::Gt { field, value } => Filter::Gt { field: field.clone(), value: self.key_value(value) },
+            Filter::Gte { field, value } => Filter::Gte { field: field.clone(), value: self.key_value(value) },
+            Filter::Lt { field, value } => Filter::Lt { field: field.clone(), value: self.key_value(value) },
+            Filter::Lte { field, value } => Filter::Lte { field: field.clone(), value: self.key_value(value) },
+            Filter::In { field, values } => Filter::In { field: field.clone(), values: keys(values) },
+            Filter::Nin { field, values } => Filter::Nin { field: field.clone(), values: keys(values) },
+            Filter::All { field, values } => Filter::All { field: field.clone(), values: keys(values) },
+            Filter::ElemMatch { field, filter } => Filter::ElemMatch {
+                field: field.clone(),
+                filter: Box::new(self.key_filter(filter)),
+            },
+            Filter::And(filters) => Filter::And(filters.iter().map(|f| self.key_filter(f)).collect()),
+            Filter::Or(filters) => Filter::Or(filters.iter().map(|f| self.key_filter(f)).collect()),
+            Filter::Nor(filters) => Filter::Nor(filters.iter().map(|f| self.key_filter(f)).collect()),
+            Filter::Not(filter) => Filter::Not(Box::new(self.key_filter(filter))),
+            // `$expr` keys both of its operands when it is evaluated
+            Filter::Empty
+            | Filter::Exists { .. }
+            | Filter::Regex { .. }
+            | Filter::Size { .. }
+            | Filter::Type { .. }
+            | Filter::Mod { .. }
+            | Filter::BitsAllSet { .. }
+            | Filter::BitsAnySet { .. }
+            | Filter::Expr { .. } => filter.clone(),
+        }
+    }
+
+    /// Lowercase a string, with the dotted and dotless `i` of Turkic locales
+    fn fold_case(&self, s: &str) -> String {
+        let language = self.locale.split(['-', '_']).next().unwrap_or_default();
+        if language.eq_ignore_ascii_case("tr") || language.eq_ignore_ascii_case("az") {
+            s.chars()
+                .map(|c| match c {
+                    'I' => "ı".to_string(),
+                    'İ' => "i".to_string(),
+                    c => c.to_lowercase().collect(),
+                })
+                .collect()
+        } else {
+            s.to_lowercase()
+        }
+    }
+}
+
+/// Collation the query and the index agree on, with `simple` meaning none
+pub fn effective(collation: Option<&Collation>) -> Option<&Collation> {
+    collation.filter(|c| !c.is_simple())
+}
+
+/// Replace each run of ASCII digits with its digit count and value
+///
+/// Leading zeros are dropped and the count is zero-padded, so a longer
+/// number sorts after a shorter one and equal lengths compare digit by
+/// digit.
+fn number_keys(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if !c.is_ascii_digit() {
+            out.push(c);
+            continue;
+        }
+        let mut digits = String::from(c);
+        while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+            digits.push(d);
+        }
+        let significant = match digits.trim_start_matches('0') {
+            "" => "0",
+            rest => rest,
+        };
+        out.push_str(&format!("{:0width$}", significant.len(), width = DIGIT_COUNT_WIDTH));
+        out.push_str(significant);
+    }
+    out
+}
+
+/// Strip diacritics from lowercase Latin letters
+fn fold_diacritics(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        let base = match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+            'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+            'ď' | 'đ' | 'ð' => "d",
+            'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+            'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+            'ĥ' | 'ħ' => "h",
+            'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' => "i",
+            'ĵ' => "j",
+            'ķ' => "k",
+            'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+            'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+            'ŕ' | 'ŗ' | 'ř' => "r",
+            'ś' | 'ŝ' | 'ş' | 'š' => "s",
+            'ţ' | 'ť' | 'ŧ' => "t",
+            'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+            'ŵ' => "w",
+            'ý' | 'ÿ' | 'ŷ' => "y",
+            'ź' | 'ż' | 'ž' => "z",
+            'æ' => "ae",
+            'œ' => "oe",
+            'ß' => "ss",
+            'þ' => "th",
+            c => {
+                out.push(c);
+                continue;
+            }
+        };
+        out.push_str(base);
+    }
+    out
+}
+
+/// Collation errors
+#[derive(Debug, thiserror::Error)]
+pub enum CollationError {
+    #[error("Invalid collation strength {0}: expected 1, 2 or 3")]
+    InvalidStrength(u8),
+
+    #[error("Invalid collation locale '{0}'")]
+    InvalidLocale(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strength_controls_case_and_diacritics() {
+        let primary = Collation::new("en").with_strength(1);
+        let secondary = Collation::new("en").with_strength(2);
+        let tertiary = Collation::new("en");
+
+        assert_eq!(primary.compare("Résumé", "resume"), Ordering::Equal);
+        assert_eq!(secondary.compare("Ann@Example.com", "ann@example.com"), Ordering::Equal);
+        assert_ne!(secondary.compare("résumé", "resume"), Ordering::Equal);
+        assert_ne!(tertiary.compare("Ann", "ann"), Ordering::Equal);
+        // Case only breaks ties; it does not put every capital first
+        assert_eq!(tertiary.compare("Bob", "alice"), Ordering::Greater);
+        assert_eq!(Collation::new(SIMPLE_LOCALE).compare("Bob", "alice"), Ordering::Less);
+
+        // Turkish folds the capital I to the dotless i
+        let turkish = Collation::new("tr").with_strength(2);
+        assert_eq!(turkish.compare("KIŞ", "kış"), Ordering::Equal);
+        assert_ne!(turkish.compare("KIŞ", "kiş"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_numeric_ordering_and_filters() {
+        let numeric = Collation::new("en").with_strength(2).with_numeric_ordering();
+        assert_eq!(numeric.compare("item9", "item10"), Ordering::Less);
+        assert_eq!(numeric.compare("item010", "Item10"), Ordering::Equal);
+        assert_eq!(Collation::new("en").compare("item9", "item10"), Ordering::Greater);
+
+        let filter = Filter::And(vec![
+            Filter::eq("name", "ANN"),
+            Filter::regex("name", "^A"),
+            Filter::gt("age", 3),
+        ]);
+        assert_eq!(
+            numeric.key_filter(&filter),
+            Filter::And(vec![Filter::eq("name", "ann"), Filter::regex("name", "^A"), Filter::gt("age", 3)])
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_locale_and_strength() {
+        assert!(Collation::new("en-US").validate().is_ok());
+        assert!(Collation::new(SIMPLE_LOCALE).validate().is_ok());
+        assert!(matches!(
+            Collation::new("english!").validate(),
+            Err(CollationError::InvalidLocale(_))
+        ));
+        assert!(matches!(
+            Collation::new("en").with_strength(4).validate(),
+            Err(CollationError::InvalidStrength(4))
+        ));
+    }
+}
@@ -2,56 +2,210 @@
 //!
 //! Executes queries with filtering, projection, sorting, skip, and limit
 
-use super::ast::{Filter, Projection, Query, Sort, SortOrder};
-use super::planner::{QueryPlanner, QueryPlanError};
-use crate::document::{Document, Value};
+use super::ast::{ExprOperand, Filter, Projection, Query, Sort, SortOrder};
+use super::collation::{self, Collation};
+use super::explain::ExecutionStats;
+use super::plan_cache::{CachedSolution, PlanCache, QueryShape};
+use super::planner::{IndexScan, QueryPlan, QueryPlanner, QueryPlanError};
+use crate::document::{Decimal128, Document, DocumentId, Value};
+use crate::index::btree::IndexKey;
 use crate::index::manager::IndexManager; // Import IndexManager
+use crate::index::statistics::PerformanceTimer;
 use regex::Regex;
+use std::borrow::Cow;
+use std::cell::{OnceCell, RefCell};
 use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashMap;
 use std::sync::Arc;
 
+/// Where the executor loads documents from
+pub trait DocumentSource {
+    /// Load every document in the collection
+    fn scan(&self) -> Result<Vec<Document>, QueryExecutionError>;
+
+    /// Load a single document by id
+    fn fetch(&self, doc_id: DocumentId) -> Result<Option<Document>, QueryExecutionError>;
+}
+
+/// Documents already in memory
+///
+/// Each document is handed out once: `fetch` moves it out of the set.
+struct LoadedDocuments {
+    state: RefCell<LoadedState>,
+}
+
+enum LoadedState {
+    /// Original order, used by collection scans
+    List(Vec<Document>),
+    /// Keyed by id once the first fetch arrives
+    ById(HashMap<DocumentId, Document>),
+}
+
+impl LoadedDocuments {
+    fn new(documents: Vec<Document>) -> Self {
+        Self {
+            state: RefCell::new(LoadedState::List(documents)),
+        }
+    }
+}
+
+impl DocumentSource for LoadedDocuments {
+    fn scan(&self) -> Result<Vec<Document>, QueryExecutionError> {
+        Ok(match self.state.replace(LoadedState::List(Vec::new())) {
+            LoadedState::List(documents) => documents,
+            LoadedState::ById(documents) => documents.into_values().collect(),
+        })
+    }
+
+    fn fetch(&self, doc_id: DocumentId) -> Result<Option<Document>, QueryExecutionError> {
+        let mut state = self.state.borrow_mut();
+        if let LoadedState::List(documents) = &mut *state {
+            let by_id = std::mem::take(documents).into_iter().map(|d| (d.id, d)).collect();
+            *state = LoadedState::ById(by_id);
+        }
+        match &mut *state {
+            LoadedState::ById(documents) => Ok(documents.remove(&doc_id)),
+            LoadedState::List(_) => Ok(None),
+        }
+    }
+}
+
 /// Query executor
 pub struct QueryExecutor {
-    planner: QueryPlanner,
+    planner: OnceCell<QueryPlanner>,
     index_manager: Option<Arc<IndexManager>>,
+    plan_cache: Option<Arc<PlanCache>>,
 }
 
 impl QueryExecutor {
     /// Create a new query executor
     pub fn new() -> Self {
         Self {
-            planner: QueryPlanner::new(),
+            planner: OnceCell::new(),
             index_manager: None,
+            plan_cache: None,
         }
     }
 
     /// Set index manager for index scan optimization
+    ///
+    /// The planner is rebuilt from the manager's index definitions and
+    /// statistics the first time a query needs planning.
     pub fn set_index_manager(&mut self, index_manager: Arc<IndexManager>) {
+        self.planner = OnceCell::new();
         self.index_manager = Some(index_manager);
     }
 
+    /// Reuse and record plans in this cache
+    ///
+    /// Only used together with an index manager, whose catalog version
+    /// and document count decide when cached plans are stale.
+    pub fn set_plan_cache(&mut self, plan_cache: Arc<PlanCache>) {
+        self.plan_cache = Some(plan_cache);
+    }
+
+    /// Plan a query without executing it
+    ///
+    /// Always plans afresh; the plan cache is neither read nor filled.
+    pub fn plan(&self, query: &Query) -> Result<QueryPlan, QueryExecutionError> {
+        Ok(self.planner().create_plan(query)?)
+    }
+
+    /// Planner over the index manager's definitions and statistics
+    fn planner(&self) -> &QueryPlanner {
+        self.planner.get_or_init(|| match &self.index_manager {
+            Some(index_manager) => QueryPlanner::with_indexes(index_manager.definitions())
+                .with_multikey(&index_manager.multikey_indexes())
+                .with_lossy_keys(&index_manager.lossy_key_indexes())
+                .with_statistics(index_manager.collection_statistics()),
+            None => QueryPlanner::new(),
+        })
+    }
+
+    /// Plan a query, reusing the cached access path for its shape
+    ///
+    /// Returns the shape when the plan cache is in use, so the execution
+    /// can be recorded against it.
+    fn choose_plan(&self, query: &Query) -> Result<(QueryPlan, Option<QueryShape>), QueryExecutionError> {
+        let (Some(plan_cache), Some(index_manager)) = (&self.plan_cache, &self.index_manager) else {
+            return Ok((self.plan(query)?, None));
+        };
+
+        let shape = QueryShape::of(query);
+        let version = index_manager.version();
+        let documents = index_manager.document_estimate();
+        if let Some(solution) = plan_cache.lookup(&shape, version, documents) {
+            // Rebuilding the scans needs the definitions but not the statistics
+            let planner = QueryPlanner::with_indexes(index_manager.definitions())
+                .with_multikey(&index_manager.multikey_indexes())
+                .with_lossy_keys(&index_manager.lossy_key_indexes());
+            if let Some(plan) = planner.plan_for_solution(query, &solution)? {
+                return Ok((plan, Some(shape)));
+            }
+        }
+
+        let plan = self.plan(query)?;
+        plan_cache.insert(shape.clone(), CachedSolution::from_plan(&plan), version, documents);
+        Ok((plan, Some(shape)))
+    }
+
     /// Execute a query against a collection
     pub fn execute(
         &self,
         documents: Vec<Document>,
         query: &Query,
     ) -> Result<Vec<Document>, QueryExecutionError> {
+        self.execute_from(&LoadedDocuments::new(documents), query)
+    }
+
+    /// Execute a query, loading documents from `source` only as needed
+    ///
+    /// Index scans fetch documents one at a time, covered queries fetch
+    /// none, and only collection scans load the whole collection.
+    pub fn execute_from(
+        &self,
+        source: &dyn DocumentSource,
+        query: &Query,
+    ) -> Result<Vec<Document>, QueryExecutionError> {
+        let (results, _, _) = self.execute_with_stats(source, query)?;
+        Ok(results)
+    }
+
+    /// Execute a query and report the plan used and the work each stage did
+    pub fn execute_with_stats(
+        &self,
+        source: &dyn DocumentSource,
+        query: &Query,
+    ) -> Result<(Vec<Document>, QueryPlan, ExecutionStats), QueryExecutionError> {
+        let total = PerformanceTimer::start();
+        let mut stats = ExecutionStats::new();
+
         // Create query plan
-        let plan = self.planner.create_plan(query)?;
+        let (plan, shape) = self.choose_plan(query)?;
 
         // Execute based on plan
-        let mut results = if plan.use_index.is_some() {
-            // Index scan (to be implemented with actual indexes in task 4.3)
-            self.execute_index_scan(documents, query)?
-        } else {
-            // Collection scan
-            self.execute_collection_scan(documents, query)?
+        let timer = PerformanceTimer::start();
+        let mut results = match (&plan.index_scan, &self.index_manager) {
+            (Some(scan), Some(index_manager)) => {
+                self.execute_index_scan(source, query, &plan, scan, index_manager, &mut stats)?
+            }
+            _ => {
+                let documents = source.scan()?;
+                stats.documents_examined = documents.len() as u64;
+                self.execute_collection_scan(documents, query)?
+            }
         };
+        stats.record_stage(plan.execution_strategy.as_str(), results.len(), timer.elapsed());
 
         // Apply post-processing
-        results = self.apply_post_processing(results, query)?;
+        results = self.apply_post_processing(results, query, &plan, &mut stats)?;
 
-        Ok(results)
+        stats.documents_returned = results.len() as u64;
+        stats.execution_time = total.elapsed();
+        if let (Some(plan_cache), Some(shape)) = (&self.plan_cache, &shape) {
+            plan_cache.record_execution(shape, stats.keys_examined + stats.documents_examined);
+        }
+        Ok((results, plan, stats))
     }
 
     /// Execute a collection scan
@@ -61,9 +215,10 @@ impl QueryExecutor {
         query: &Query,
     ) -> Result<Vec<Document>, QueryExecutionError> {
         let mut results = Vec::new();
+        let (filter, collation) = Self::collated_filter(query);
 
         for doc in documents {
-            if self.matches_filter(&doc, &query.filter)? {
+            if self.matches_keyed(&doc, &filter, collation)? {
                 results.push(doc);
             }
         }
@@ -72,226 +227,251 @@ impl QueryExecutor {
     }
 
     /// Execute an index scan using IndexManager
+    ///
+    /// Documents from every key range are unioned and the full filter is
+    /// re-applied, since the index only narrows the candidates. When the
+    /// scan yields documents in the requested order (or no order is
+    /// requested), it stops once `skip + limit` documents have matched.
+    /// For an index intersection, only documents every other scan in
+    /// `plan.intersect` also yields are loaded.
     fn execute_index_scan(
         &self,
-        documents: Vec<Document>,
+        source: &dyn DocumentSource,
         query: &Query,
+        plan: &QueryPlan,
+        scan: &IndexScan,
+        index_manager: &IndexManager,
+        stats: &mut ExecutionStats,
     ) -> Result<Vec<Document>, QueryExecutionError> {
-        use crate::index::btree::IndexKey;
-        use std::collections::HashMap;
+        use std::collections::HashSet;
 
-        // If no index manager, fall back to collection scan
-        let index_manager = match &self.index_manager {
-            Some(mgr) => mgr,
-            None => return self.execute_collection_scan(documents, query),
-        };
-
-        // Get execution plan to determine which index to use
-        let plan = self.planner.create_plan(query)?;
-        let index_name = match &plan.use_index {
-            Some(name) => name,
-            None => return self.execute_collection_scan(documents, query),
+        let wanted = match query.limit {
+            Some(limit) if query.sort.is_none() || scan.sorted => {
+                Some(limit.saturating_add(query.skip.unwrap_or(0)) as usize)
+            }
+            _ => None,
         };
+        let enough = |results: &Vec<Document>| wanted.map(|w| results.len() >= w).unwrap_or(false);
+
+        let mut allowed: Option<HashSet<DocumentId>> = None;
+        for other in &plan.intersect {
+            let mut ids = HashSet::new();
+            for range in &other.ranges {
+                index_manager
+                    .scan_prefix_range_with_index(
+                        &other.index,
+                        &range.prefix,
+                        range.lower.as_ref().map(|b| (&b.value, b.inclusive)),
+                        range.upper.as_ref().map(|b| (&b.value, b.inclusive)),
+                        false,
+                        |_, doc_id| {
+                            stats.keys_examined += 1;
+                            if allowed.as_ref().map(|a| a.contains(&doc_id)).unwrap_or(true) {
+                                ids.insert(doc_id);
+                            }
+                            true
+                        },
+                    )
+                    .map_err(|e| QueryExecutionError::ExecutionError(format!("Index range error: {}", e)))?;
+            }
+            allowed = Some(ids);
+        }
 
-        // Build document map for fast lookup by ID
-        let doc_map: HashMap<crate::document::DocumentId, &Document> = documents
-            .iter()
-            .map(|d| (d.id, d))
-            .collect();
+        let mut seen = HashSet::new();
+        let mut results = Vec::new();
+        let mut failure = None;
+        let (filter, collation) = Self::collated_filter(query);
 
-        // Get document IDs from index based on filter type
-        let doc_ids = self.get_doc_ids_from_index(index_manager, index_name, &query.filter)?;
+        for range in &scan.ranges {
+            if enough(&results) {
+                break;
+            }
 
-        // Fetch documents by ID
-        let mut results: Vec<Document> = doc_ids
-            .into_iter()
-            .filter_map(|doc_id| doc_map.get(&doc_id).map(|d| (*d).clone()))
-            .collect();
+            index_manager
+                .scan_prefix_range_with_index(
+                    &scan.index,
+                    &range.prefix,
+                    range.lower.as_ref().map(|b| (&b.value, b.inclusive)),
+                    range.upper.as_ref().map(|b| (&b.value, b.inclusive)),
+                    scan.reverse,
+                    |key, doc_id| {
+                        stats.keys_examined += 1;
+                        if !seen.insert(doc_id) {
+                            return true;
+                        }
+                        if allowed.as_ref().map(|a| !a.contains(&doc_id)).unwrap_or(false) {
+                            return true;
+                        }
+                        let doc = if plan.covered {
+                            Self::covered_document(doc_id, &scan.fields, key).map(Some)
+                        } else {
+                            source.fetch(doc_id).inspect(|doc| {
+                                stats.documents_examined += u64::from(doc.is_some());
+                            })
+                        };
+                        let matched = doc.and_then(|doc| match doc {
+                            Some(doc) if self.matches_keyed(&doc, &filter, collation)? => Ok(Some(doc)),
+                            _ => Ok(None),
+                        });
+                        match matched {
+                            Ok(Some(doc)) => results.push(doc),
+                            Ok(None) => {}
+                            Err(e) => {
+                                failure = Some(e);
+                                return false;
+                            }
+                        }
+                        !enough(&results)
+                    },
+                )
+                .map_err(|e| QueryExecutionError::ExecutionError(format!("Index range error: {}", e)))?;
 
-        // Apply remaining filters (in case of complex AND/OR conditions)
-        results.retain(|doc| self.matches_filter(doc, &query.filter).unwrap_or(false));
+            if let Some(e) = failure.take() {
+                return Err(e);
+            }
+        }
 
         Ok(results)
     }
 
-    /// Get document IDs from index based on filter
-    fn get_doc_ids_from_index(
-        &self,
-        index_manager: &IndexManager,
-        index_name: &str,
-        filter: &Filter,
-    ) -> Result<Vec<crate::document::DocumentId>, QueryExecutionError> {
-        use crate::index::btree::IndexKey;
-
-        match filter {
-            // Exact match
-            Filter::Eq { field: _, value } => {
-                let key = IndexKey::from_values(vec![value.clone()])
-                    .map_err(|e| QueryExecutionError::ExecutionError(format!("Index key error: {}", e)))?;
-                
-                index_manager.find_with_index(index_name, &key)
-                    .map_err(|e| QueryExecutionError::ExecutionError(format!("Index lookup error: {}", e)))
-            }
-
-            // Greater than
-            Filter::Gt { field: _, value } => {
-                let start_key = IndexKey::from_values(vec![value.clone()])
-                    .map_err(|e| QueryExecutionError::ExecutionError(format!("Index key error: {}", e)))?;
-                
-                index_manager.find_range_with_index(
-                    index_name,
-                    Some(&start_key),
-                    None,
-                    false, // exclude start
-                    false,
-                )
-                .map_err(|e| QueryExecutionError::ExecutionError(format!("Index range error: {}", e)))
-            }
-
-            // Greater than or equal
-            Filter::Gte { field: _, value } => {
-                let start_key = IndexKey::from_values(vec![value.clone()])
-                    .map_err(|e| QueryExecutionError::ExecutionError(format!("Index key error: {}", e)))?;
-                
-                index_manager.find_range_with_index(
-                    index_name,
-                    Some(&start_key),
-                    None,
-                    true, // include start
-                    false,
-                )
-                .map_err(|e| QueryExecutionError::ExecutionError(format!("Index range error: {}", e)))
-            }
-
-            // Less than
-            Filter::Lt { field: _, value } => {
-                let end_key = IndexKey::from_values(vec![value.clone()])
-                    .map_err(|e| QueryExecutionError::ExecutionError(format!("Index key error: {}", e)))?;
-                
-                index_manager.find_range_with_index(
-                    index_name,
-                    None,
-                    Some(&end_key),
-                    false,
-                    false, // exclude end
-                )
-                .map_err(|e| QueryExecutionError::ExecutionError(format!("Index range error: {}", e)))
-            }
-
-            // Less than or equal
-            Filter::Lte { field: _, value } => {
-                let end_key = IndexKey::from_values(vec![value.clone()])
-                    .map_err(|e| QueryExecutionError::ExecutionError(format!("Index key error: {}", e)))?;
-                
-                index_manager.find_range_with_index(
-                    index_name,
-                    None,
-                    Some(&end_key),
-                    false,
-                    true, // include end
-                )
-                .map_err(|e| QueryExecutionError::ExecutionError(format!("Index range error: {}", e)))
+    /// Rebuild the indexed fields of a document from its index key
+    fn covered_document(
+        doc_id: DocumentId,
+        fields: &[String],
+        key: &IndexKey,
+    ) -> Result<Document, QueryExecutionError> {
+        let mut doc = Document::with_id(doc_id);
+        for (field, value) in fields.iter().zip(key.values()) {
+            if let Some(value) = value.to_value() {
+                doc.set_by_path(field, value)
+                    .map_err(|e| QueryExecutionError::ExecutionError(e.to_string()))?;
             }
-
-            // AND: Extract first indexable sub-filter
-            Filter::And(filters) => {
-                for f in filters {
-                    if matches!(f, Filter::Eq { .. } | Filter::Gt { .. } | Filter::Gte { .. } | Filter::Lt { .. } | Filter::Lte { .. }) {
-                        return self.get_doc_ids_from_index(index_manager, index_name, f);
-                    }
-                }
-                // No indexable sub-filter, return empty
-                Ok(Vec::new())
-            }
-
-            // Other filters: not optimized with index
-            _ => Ok(Vec::new()),
         }
+        Ok(doc)
     }
 
     /// Check if a document matches a filter
+    ///
+    /// A field holding an array matches when the whole array or any of its
+    /// elements does, and paths fan out over arrays of subdocuments.
     pub fn matches_filter(&self, doc: &Document, filter: &Filter) -> Result<bool, QueryExecutionError> {
+        self.matches_keyed(doc, filter, None)
+    }
+
+    /// Check if a document matches a filter with strings compared under `collation`
+    pub fn matches_filter_collated(
+        &self,
+        doc: &Document,
+        filter: &Filter,
+        collation: Option<&Collation>,
+    ) -> Result<bool, QueryExecutionError> {
+        match collation::effective(collation) {
+            Some(collation) => self.matches_keyed(doc, &collation.key_filter(filter), Some(collation)),
+            None => self.matches_keyed(doc, filter, None),
+        }
+    }
+
+    /// The query's filter with its strings turned into collation keys, and
+    /// the collation document values must be keyed with to match it
+    fn collated_filter(query: &Query) -> (Cow<'_, Filter>, Option<&Collation>) {
+        match collation::effective(query.collation.as_ref()) {
+            Some(collation) => (Cow::Owned(collation.key_filter(&query.filter)), Some(collation)),
+            None => (Cow::Borrowed(&query.filter), None),
+        }
+    }
+
+    /// Match a filter whose strings are already collation keys of `collation`
+    fn matches_keyed(
+        &self,
+        doc: &Document,
+        filter: &Filter,
+        collation: Option<&Collation>,
+    ) -> Result<bool, QueryExecutionError> {
         match filter {
             Filter::Empty => Ok(true),
             
             Filter::Eq { field, value } => {
-                let doc_value = doc.get_by_path(field);
-                Ok(doc_value == Some(value))
+                Ok(Self::keyed_values(doc, field, collation).iter().any(|v| v.as_ref() == value))
             }
             
             Filter::Ne { field, value } => {
-                let doc_value = doc.get_by_path(field);
-                Ok(doc_value != Some(value))
+                Ok(!Self::keyed_values(doc, field, collation).iter().any(|v| v.as_ref() == value))
             }
             
             Filter::Gt { field, value } => {
-                let doc_value = doc.get_by_path(field);
-                Ok(doc_value.map(|v| self.compare_values(v, value) == CmpOrdering::Greater).unwrap_or(false))
+                Ok(self.any_compares(doc, field, value, collation, |cmp| cmp == CmpOrdering::Greater))
             }
             
             Filter::Gte { field, value } => {
-                let doc_value = doc.get_by_path(field);
-                Ok(doc_value.map(|v| {
-                    let cmp = self.compare_values(v, value);
-                    cmp == CmpOrdering::Greater || cmp == CmpOrdering::Equal
-                }).unwrap_or(false))
+                Ok(self.any_compares(doc, field, value, collation, |cmp| cmp != CmpOrdering::Less))
             }
             
             Filter::Lt { field, value } => {
-                let doc_value = doc.get_by_path(field);
-                Ok(doc_value.map(|v| self.compare_values(v, value) == CmpOrdering::Less).unwrap_or(false))
+                Ok(self.any_compares(doc, field, value, collation, |cmp| cmp == CmpOrdering::Less))
             }
             
             Filter::Lte { field, value } => {
-                let doc_value = doc.get_by_path(field);
-                Ok(doc_value.map(|v| {
-                    let cmp = self.compare_values(v, value);
-                    cmp == CmpOrdering::Less || cmp == CmpOrdering::Equal
-                }).unwrap_or(false))
+                Ok(self.any_compares(doc, field, value, collation, |cmp| cmp != CmpOrdering::Greater))
             }
             
             Filter::In { field, values } => {
-                let doc_value = doc.get_by_path(field);
-                Ok(doc_value.map(|v| values.contains(v)).unwrap_or(false))
+                Ok(Self::keyed_values(doc, field, collation).iter().any(|v| values.contains(v)))
             }
             
             Filter::Nin { field, values } => {
-                let doc_value = doc.get_by_path(field);
-                Ok(doc_value.map(|v| !values.contains(v)).unwrap_or(true))
+                Ok(!Self::keyed_values(doc, field, collation).iter().any(|v| values.contains(v)))
             }
             
             Filter::Exists { field, exists } => {
-                let has_field = doc.get_by_path(field).is_some();
+                let has_field = !doc.values_by_path(field).is_empty();
                 Ok(has_field == *exists)
             }
             
             Filter::Regex { field, pattern, options } => {
-                let doc_value = doc.get_by_path(field);
-                if let Some(Value::String(s)) = doc_value {
-                    let regex = if let Some(opts) = options {
-                        // Parse regex options (i = case insensitive, m = multiline, etc.)
-                        let case_insensitive = opts.contains('i');
-                        if case_insensitive {
-                            Regex::new(&format!("(?i){}", pattern))
-                        } else {
-                            Regex::new(pattern)
-                        }
+                let candidates = Self::field_values(doc, field);
+                if !candidates.iter().any(|v| matches!(v, Value::String(_))) {
+                    return Ok(false);
+                }
+                let regex = if let Some(opts) = options {
+                    // Parse regex options (i = case insensitive, m = multiline, etc.)
+                    let case_insensitive = opts.contains('i');
+                    if case_insensitive {
+                        Regex::new(&format!("(?i){}", pattern))
                     } else {
                         Regex::new(pattern)
-                    };
-                    
-                    match regex {
-                        Ok(re) => Ok(re.is_match(s)),
-                        Err(e) => Err(QueryExecutionError::InvalidRegex(e.to_string())),
                     }
                 } else {
-                    Ok(false)
+                    Regex::new(pattern)
+                };
+
+                match regex {
+                    Ok(re) => Ok(candidates.iter().any(|v| matches!(v, Value::String(s) if re.is_match(s)))),
+                    Err(e) => Err(QueryExecutionError::InvalidRegex(e.to_string())),
                 }
             }
+
+            Filter::All { field, values } => {
+                let candidates = Self::keyed_values(doc, field, collation);
+                Ok(!values.is_empty() && values.iter().all(|value| candidates.iter().any(|v| v.as_ref() == value)))
+            }
+
+            Filter::ElemMatch { field, filter } => {
+                for value in doc.values_by_path(field) {
+                    let Value::Array(elements) = value else {
+                        continue;
+                    };
+                    for element in elements {
+                        if self.matches_keyed(&Self::element_document(doc, element), filter, collation)? {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            }
             
             Filter::And(filters) => {
                 for f in filters {
-                    if !self.matches_filter(doc, f)? {
+                    if !self.matches_keyed(doc, f, collation)? {
                         return Ok(false);
                     }
                 }
@@ -300,17 +480,190 @@ impl QueryExecutor {
             
             Filter::Or(filters) => {
                 for f in filters {
-                    if self.matches_filter(doc, f)? {
+                    if self.matches_keyed(doc, f, collation)? {
                         return Ok(true);
                     }
                 }
                 Ok(false)
             }
             
+            Filter::Nor(filters) => {
+                for f in filters {
+                    if self.matches_keyed(doc, f, collation)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            
             Filter::Not(filter) => {
-                Ok(!self.matches_filter(doc, filter)?)
+                Ok(!self.matches_keyed(doc, filter, collation)?)
+            }
+
+            Filter::Size { field, size } => Ok(doc
+                .values_by_path(field)
+                .iter()
+                .any(|v| matches!(v, Value::Array(elements) if elements.len() == *size))),
+
+            Filter::Type { field, types } => Ok(Self::field_values(doc, field)
+                .iter()
+                .any(|v| types.iter().any(|t| t.matches(v)))),
+
+            Filter::Mod { field, divisor, remainder } => Ok(Self::field_values(doc, field)
+                .iter()
+                .filter_map(|v| Self::integer_value(v))
+                .any(|n| n.wrapping_rem(*divisor) == *remainder)),
+
+            Filter::BitsAllSet { field, mask } => Ok(Self::field_values(doc, field)
+                .iter()
+                .filter_map(|v| Self::bit_value(v))
+                .any(|bits| bits & mask == *mask)),
+
+            Filter::BitsAnySet { field, mask } => Ok(Self::field_values(doc, field)
+                .iter()
+                .filter_map(|v| Self::bit_value(v))
+                .any(|bits| bits & mask != 0)),
+
+            Filter::Expr { op, left, right } => {
+                let operand = |operand: &ExprOperand| -> Option<Value> {
+                    let value = match operand {
+                        ExprOperand::Field(path) => doc.get_by_path(path)?.clone(),
+                        ExprOperand::Literal(value) => value.clone(),
+                    };
+                    Some(match collation {
+                        Some(collation) => collation.key_value(&value),
+                        None => value,
+                    })
+                };
+                let ordering = self.compare_total(operand(left).as_ref(), operand(right).as_ref());
+                Ok(op.holds(ordering))
+            }
+        }
+    }
+
+    /// Integer a numeric value holds, truncating fractions toward zero
+    fn integer_value(value: &Value) -> Option<i64> {
+        match value {
+            Value::Int32(i) => Some(*i as i64),
+            Value::Int64(i) => Some(*i),
+            Value::Float64(f) if f.is_finite() => Some(f.trunc() as i64),
+            Value::Decimal128(d) => d.trunc().to_i64(),
+            _ => None,
+        }
+    }
+
+    /// Two's complement bits of a whole number; fractions have none
+    fn bit_value(value: &Value) -> Option<u64> {
+        match value {
+            Value::Float64(f) if f.fract() != 0.0 => None,
+            Value::Decimal128(d) if d.trunc() != *d => None,
+            value => Self::integer_value(value).map(|n| n as u64),
+        }
+    }
+
+    /// Compare two possibly missing values across types
+    ///
+    /// Values of different kinds order as missing, null, numbers, strings,
+    /// objects, arrays, binary and UUIDs, object ids, booleans, dates, then
+    /// timestamps, so unlike values never compare equal.
+    fn compare_total(&self, a: Option<&Value>, b: Option<&Value>) -> CmpOrdering {
+        fn rank(value: Option<&Value>) -> u8 {
+            match value {
+                None => 0,
+                Some(Value::Null) => 1,
+                Some(Value::Int32(_) | Value::Int64(_) | Value::Float64(_) | Value::Decimal128(_)) => 2,
+                Some(Value::String(_)) => 3,
+                Some(Value::Object(_)) => 4,
+                Some(Value::Array(_)) => 5,
+                Some(Value::Binary(_) | Value::Uuid(_)) => 6,
+                Some(Value::ObjectId(_)) => 7,
+                Some(Value::Bool(_)) => 8,
+                Some(Value::DateTime(_)) => 9,
+                Some(Value::Timestamp(_)) => 10,
+            }
+        }
+
+        match (a, b) {
+            _ if rank(a) != rank(b) => rank(a).cmp(&rank(b)),
+            (Some(Value::Array(a)), Some(Value::Array(b))) => a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| self.compare_total(Some(x), Some(y)))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len())),
+            (Some(Value::Object(a)), Some(Value::Object(b))) => a
+                .iter()
+                .zip(b)
+                .map(|((ka, va), (kb, vb))| ka.cmp(kb).then_with(|| self.compare_total(Some(va), Some(vb))))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len())),
+            (Some(Value::Binary(a)), Some(Value::Binary(b))) => a.cmp(b),
+            // A UUID sorts as 16 bytes of binary, after plain binary of the same bytes
+            (Some(Value::Binary(a)), Some(Value::Uuid(b))) => a.as_slice().cmp(b.as_bytes()).then(CmpOrdering::Less),
+            (Some(Value::Uuid(a)), Some(Value::Binary(b))) => a.as_bytes().as_slice().cmp(b).then(CmpOrdering::Greater),
+            (Some(a), Some(b)) => self.compare_values(a, b),
+            (None, None) => CmpOrdering::Equal,
+            _ => unreachable!("values of equal rank are both present or both missing"),
+        }
+    }
+
+    /// Values a predicate on `field` is tested against
+    ///
+    /// Every value the path reaches, followed by the elements of those
+    /// that are arrays.
+    fn field_values<'a>(doc: &'a Document, field: &str) -> Vec<&'a Value> {
+        let values = doc.values_by_path(field);
+        let elements: Vec<&Value> = values
+            .iter()
+            .filter_map(|value| match value {
+                Value::Array(elements) => Some(elements.iter()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        values.into_iter().chain(elements).collect()
+    }
+
+    /// Values of `field` with their strings turned into collation keys
+    fn keyed_values<'a>(doc: &'a Document, field: &str, collation: Option<&Collation>) -> Vec<Cow<'a, Value>> {
+        let values = Self::field_values(doc, field).into_iter();
+        match collation {
+            Some(collation) => values.map(|v| Cow::Owned(collation.key_value(v))).collect(),
+            None => values.map(Cow::Borrowed).collect(),
+        }
+    }
+
+    /// Check whether some non-array value of `field` compares to `value` as wanted
+    fn any_compares<F>(
+        &self,
+        doc: &Document,
+        field: &str,
+        value: &Value,
+        collation: Option<&Collation>,
+        wanted: F,
+    ) -> bool
+    where
+        F: Fn(CmpOrdering) -> bool,
+    {
+        Self::keyed_values(doc, field, collation)
+            .into_iter()
+            .filter(|v| !matches!(v.as_ref(), Value::Array(_)))
+            .any(|v| wanted(self.compare_values(&v, value)))
+    }
+
+    /// View an array element as a document for `$elemMatch`
+    ///
+    /// A subdocument's fields become top-level fields; the element itself
+    /// is reachable under the empty path.
+    fn element_document(doc: &Document, element: &Value) -> Document {
+        let mut element_doc = Document::with_id(doc.id);
+        if let Value::Object(fields) = element {
+            for (name, value) in fields {
+                element_doc.insert(name.clone(), value.clone());
             }
         }
+        element_doc.insert(String::new(), element.clone());
+        element_doc
     }
 
     /// Compare two values
@@ -348,58 +701,95 @@ impl QueryExecutor {
             (Value::DateTime(a), Value::DateTime(b)) => a.cmp(b),
             
             (Value::ObjectId(a), Value::ObjectId(b)) => a.cmp(b),
+
+            (Value::Decimal128(a), b) if b.is_number() => Self::compare_decimal(a, b),
+            (a, Value::Decimal128(b)) if a.is_number() => Self::compare_decimal(b, a).reverse(),
+
+            (Value::Uuid(a), Value::Uuid(b)) => a.cmp(b),
+
+            (Value::Timestamp(a), Value::Timestamp(b)) => a.cmp(b),
             
             _ => CmpOrdering::Equal,
         }
     }
 
+    /// Exact comparison of a decimal with another number
+    ///
+    /// Floats compare through their shortest round-trip digits, so the
+    /// float `0.1` equals the decimal `0.1`.
+    fn compare_decimal(a: &Decimal128, b: &Value) -> CmpOrdering {
+        match b {
+            Value::Float64(f) if f.is_infinite() => 0.0.partial_cmp(f).unwrap_or(CmpOrdering::Equal),
+            b => b.as_decimal().map_or(CmpOrdering::Equal, |b| a.cmp(&b)),
+        }
+    }
+
     /// Apply post-processing (projection, sort, skip, limit)
+    ///
+    /// Each step that runs is recorded as a stage in `stats`.
     fn apply_post_processing(
         &self,
         mut documents: Vec<Document>,
         query: &Query,
+        plan: &QueryPlan,
+        stats: &mut ExecutionStats,
     ) -> Result<Vec<Document>, QueryExecutionError> {
-        // Apply sort
+        // Apply sort unless the index scan already produced sorted output
         if let Some(ref sort) = query.sort {
-            self.apply_sort(&mut documents, sort)?;
+            if plan.needs_sort {
+                let timer = PerformanceTimer::start();
+                self.apply_sort(&mut documents, sort, query.collation.as_ref())?;
+                stats.record_stage("sort", documents.len(), timer.elapsed());
+            }
         }
 
         // Apply skip
         if let Some(skip) = query.skip {
             if skip > 0 {
+                let timer = PerformanceTimer::start();
                 documents = documents.into_iter().skip(skip as usize).collect();
+                stats.record_stage("skip", documents.len(), timer.elapsed());
             }
         }
 
         // Apply limit
         if let Some(limit) = query.limit {
+            let timer = PerformanceTimer::start();
             documents.truncate(limit as usize);
+            stats.record_stage("limit", documents.len(), timer.elapsed());
         }
 
         // Apply projection
         if let Some(ref projection) = query.projection {
+            let timer = PerformanceTimer::start();
             documents = self.apply_projection(documents, projection)?;
+            stats.record_stage("projection", documents.len(), timer.elapsed());
         }
 
         Ok(documents)
     }
 
-    /// Apply sorting to documents
+    /// Apply sorting to documents, comparing strings under `collation`
     fn apply_sort(
         &self,
         documents: &mut Vec<Document>,
         sort: &Sort,
+        collation: Option<&Collation>,
     ) -> Result<(), QueryExecutionError> {
+        let collation = collation::effective(collation);
         documents.sort_by(|a, b| {
             for (field, order) in &sort.fields {
                 let a_val = a.get_by_path(field);
                 let b_val = b.get_by_path(field);
 
-                let cmp = match (a_val, b_val) {
-                    (Some(av), Some(bv)) => self.compare_values(av, bv),
-                    (Some(_), None) => CmpOrdering::Greater,
-                    (None, Some(_)) => CmpOrdering::Less,
-                    (None, None) => CmpOrdering::Equal,
+                let cmp = match (a_val, b_val, collation) {
+                    (Some(av), Some(bv), Some(collation)) => {
+                        self.compare_values(&collation.key_value(av), &collation.key_value(bv))
+                    }
+                    (Some(av), Some(bv), None) => self.compare_values(av, bv),
+                    (Some(_), None, _) => CmpOrdering::Greater,
+                    (None, Some(_), _) => CmpOrdering::Less,
+                    (None, None, _) => CmpOrdering::Equal,
                 };
 
                 let cmp = match order {
@@ -463,7 +853,6 @@ pub enum QueryExecutionError {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::document::DocumentId;
 
     fn create_test_documents() -> Vec<Document> {
         let mut docs = Vec::new();
@@ -590,6 +979,36 @@ mod tests {
         assert_eq!(results[9].get("age").unwrap().as_i64(), Some(20));
     }
 
+    #[test]
+    fn test_collation_applies_to_filters_and_sort() {
+        let executor = QueryExecutor::new();
+        let docs: Vec<Document> = ["bob", "Ann", "carl", "item10", "item9"]
+            .iter()
+            .map(|name| {
+                let mut doc = Document::with_id(DocumentId::new());
+                doc.insert("name".to_string(), Value::from(*name));
+                doc
+            })
+            .collect();
+        let names = |results: Vec<Document>| -> Vec<String> {
+            results.iter().map(|d| d.get("name").unwrap().as_str().unwrap().to_string()).collect()
+        };
+        let collation = Collation::new("en").with_strength(2).with_numeric_ordering();
+
+        let query = Query::with_filter(Filter::eq("name", "ANN"));
+        assert!(executor.execute(docs.clone(), &query).unwrap().is_empty());
+        let results = executor.execute(docs.clone(), &query.collation(collation.clone())).unwrap();
+        assert_eq!(names(results), vec!["Ann"]);
+
+        let query = Query::with_filter(Filter::gte("name", "B")).collation(collation.clone());
+        assert_eq!(names(executor.execute(docs.clone(), &query).unwrap()).len(), 4);
+
+        let query = Query::new().sort(Sort::new().asc("name"));
+        assert_eq!(names(executor.execute(docs.clone(), &query).unwrap()), vec!["Ann", "bob", "carl", "item10", "item9"]);
+        let results = executor.execute(docs, &query.collation(collation)).unwrap();
+        assert_eq!(names(results), vec!["Ann", "bob", "carl", "item9", "item10"]);
+    }
+
     #[test]
     fn test_execute_with_skip_limit() {
         let executor = QueryExecutor::new();
@@ -648,5 +1067,632 @@ mod tests {
         let results = executor.execute(docs, &query).unwrap();
         assert_eq!(results.len(), 5);
     }
-}
 
+    #[test]
+    fn test_index_scan_matches_collection_scan() {
+        use crate::schema::IndexDefinition;
+
+        let mut docs = create_test_documents();
+        for (i, doc) in docs.iter_mut().enumerate() {
+            let city = ["Oslo", "Lima", "Pune"][i % 3];
+            doc.insert("city".to_string(), Value::String(city.to_string()));
+        }
+
+        let manager = Arc::new(IndexManager::new("users".to_string()));
+        manager
+            .register_index(
+                IndexDefinition::compound(vec!["city".to_string(), "age".to_string()])
+                    .with_directions(vec![1, -1]),
+            )
+            .unwrap();
+        manager.populate_index("idx_city_age", &docs).unwrap();
+
+        let mut indexed = QueryExecutor::new();
+        indexed.set_index_manager(manager);
+        let scanner = QueryExecutor::new();
+
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::in_values("city", vec![Value::from("Oslo"), Value::from("Pune")]),
+            Filter::gte("age", 22i32),
+            Filter::lt("age", 29i32),
+            Filter::eq("active", true),
+        ]))
+        .sort(Sort::new().asc("age"));
+
+        let plan = indexed.planner().create_plan(&query).unwrap();
+        assert_eq!(plan.use_index, Some("idx_city_age".to_string()));
+        assert_eq!(plan.index_scan.as_ref().unwrap().ranges.len(), 2);
+
+        let from_index = indexed.execute(docs.clone(), &query).unwrap();
+        let from_scan = scanner.execute(docs, &query).unwrap();
+        let ids = |docs: &[Document]| docs.iter().map(|d| d.id).collect::<Vec<_>>();
+        assert!(!from_index.is_empty());
+        assert_eq!(ids(&from_index), ids(&from_scan));
+    }
+
+    /// Products with tag arrays, score arrays and arrays of line items
+    fn create_array_documents() -> Vec<Document> {
+        let item = |sku: &str, qty: i32| {
+            Value::Object(std::collections::BTreeMap::from([
+                ("sku".to_string(), Value::from(sku)),
+                ("qty".to_string(), Value::Int32(qty)),
+            ]))
+        };
+        let rows: [(&[&str], &[i32], Vec<Value>); 5] = [
+            (&["red", "sale"], &[70, 90], vec![item("a1", 1), item("b2", 5)]),
+            (&["blue"], &[82], vec![item("a1", 5)]),
+            (&["red", "blue", "red"], &[60], vec![item("b2", 1), item("c3", 2)]),
+            (&[], &[], Vec::new()),
+            (&["sale"], &[95, 81], vec![item("c3", 9)]),
+        ];
+        rows.into_iter()
+            .map(|(tags, scores, items)| {
+                let mut doc = Document::with_id(DocumentId::new());
+                doc.insert("tags".to_string(), Value::Array(tags.iter().map(|t| Value::from(*t)).collect()));
+                doc.insert("scores".to_string(), Value::Array(scores.iter().map(|s| Value::Int32(*s)).collect()));
+                doc.insert("items".to_string(), Value::Array(items));
+                doc
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_size_type_mod_bits_and_nor() {
+        use crate::query::ValueType;
+
+        let executor = QueryExecutor::new();
+        let docs = create_array_documents();
+        let matching = |filter: Filter| {
+            docs.iter()
+                .enumerate()
+                .filter(|(_, doc)| executor.matches_filter(doc, &filter).unwrap())
+                .map(|(position, _)| position)
+                .collect::<Vec<usize>>()
+        };
+
+        // $size counts the array itself, never its elements
+        assert_eq!(matching(Filter::size("scores", 2)), vec![0, 4]);
+        assert_eq!(matching(Filter::size("tags", 0)), vec![3]);
+        assert!(matching(Filter::size("items.sku", 1)).is_empty());
+
+        // $type matches the field or any of its elements
+        assert_eq!(matching(Filter::has_type("tags", ValueType::Array)), vec![0, 1, 2, 3, 4]);
+        assert_eq!(matching(Filter::has_type("tags", ValueType::String)), vec![0, 1, 2, 4]);
+        assert_eq!(matching(Filter::has_type("scores", ValueType::Number)), vec![0, 1, 2, 4]);
+        assert!(matching(Filter::has_type("scores", ValueType::Long)).is_empty());
+
+        assert_eq!(matching(Filter::modulo("scores", 5, 0)), vec![0, 2, 4]);
+        assert_eq!(matching(Filter::modulo("scores", 10, 1)), vec![4]);
+
+        // 70, 82 and 95 have bit 1 set; only 95 and 81 are odd
+        assert_eq!(matching(Filter::bits_all_set("scores", 0b10)), vec![0, 1, 4]);
+        assert_eq!(matching(Filter::bits_any_set("scores", 1)), vec![4]);
+        assert_eq!(matching(Filter::bits_all_set("scores", 0b11)), vec![4]);
+
+        let nor = Filter::nor(vec![Filter::eq("tags", "red"), Filter::size("tags", 0)]);
+        assert_eq!(matching(nor), vec![1, 4]);
+
+        let mut signed = Document::new();
+        signed.insert("flags".to_string(), Value::Int32(-1));
+        signed.insert("ratio".to_string(), Value::Float64(7.5));
+        assert!(executor.matches_filter(&signed, &Filter::bits_all_set("flags", u64::MAX)).unwrap());
+        assert!(!executor.matches_filter(&signed, &Filter::bits_any_set("ratio", u64::MAX)).unwrap());
+        assert!(executor.matches_filter(&signed, &Filter::modulo("ratio", 4, 3)).unwrap());
+    }
+
+    #[test]
+    fn test_decimal_uuid_and_timestamp_values() {
+        use crate::document::Timestamp;
+        use crate::query::{QueryParser, ValueType};
+
+        let executor = QueryExecutor::new();
+        let amounts = [
+            Value::Decimal128("10.10".parse().unwrap()),
+            Value::Int32(10),
+            Value::Float64(10.1),
+            Value::Decimal128("9.99".parse().unwrap()),
+        ];
+        let docs: Vec<Document> = amounts
+            .iter()
+            .enumerate()
+            .map(|(i, amount)| {
+                let mut doc = Document::new();
+                doc.insert("n".to_string(), Value::Int32(i as i32));
+                doc.insert("amount".to_string(), amount.clone());
+                doc.insert("seen".to_string(), Value::Timestamp(Timestamp::new(100, 3 - i as u32)));
+                doc
+            })
+            .collect();
+        let positions = |query: serde_json::Value| {
+            let query = QueryParser::parse_from_value(&query).unwrap();
+            executor
+                .execute(docs.clone(), &query)
+                .unwrap()
+                .iter()
+                .map(|doc| doc.get("n").and_then(Value::as_i64).unwrap())
+                .collect::<Vec<i64>>()
+        };
+
+        // Equality ignores trailing zeros; ordering compares exactly with
+        // other numbers, the float 10.1 standing for the decimal 10.1
+        assert_eq!(positions(serde_json::json!({"filter": {"amount": {"$numberDecimal": "10.1"}}})), vec![0]);
+        assert_eq!(positions(serde_json::json!({"filter": {"amount": {"$gte": {"$numberDecimal": "10.1"}}}})), vec![0, 2]);
+        assert_eq!(positions(serde_json::json!({"filter": {"amount": {"$gt": {"$numberDecimal": "10.00"}}}})), vec![0, 2]);
+        assert_eq!(positions(serde_json::json!({"filter": {}, "sort": {"amount": 1}})), vec![3, 1, 0, 2]);
+        assert_eq!(positions(serde_json::json!({"filter": {"amount": {"$type": "decimal"}}})), vec![0, 3]);
+        assert_eq!(positions(serde_json::json!({"filter": {"amount": {"$mod": [5, 0]}}})), vec![0, 1, 2]);
+
+        // Timestamps order by time, then increment
+        assert_eq!(positions(serde_json::json!({"filter": {}, "sort": {"seen": 1}})), vec![3, 2, 1, 0]);
+        assert_eq!(
+            positions(serde_json::json!({"filter": {"seen": {"$lt": {"$timestamp": {"t": 100, "i": 2}}}}})),
+            vec![2, 3]
+        );
+        assert!(executor.matches_filter(&docs[0], &Filter::has_type("seen", ValueType::Timestamp)).unwrap());
+
+        let id = uuid::Uuid::new_v4();
+        let mut tagged = Document::new();
+        tagged.insert("ref".to_string(), Value::Uuid(id));
+        let query = QueryParser::parse_from_value(&serde_json::json!({"filter": {"ref": {"$uuid": id.to_string()}}})).unwrap();
+        assert!(executor.matches_filter(&tagged, &query.filter).unwrap());
+        assert!(executor.matches_filter(&tagged, &Filter::has_type("ref", ValueType::BinData)).unwrap());
+        assert!(!executor.matches_filter(&tagged, &Filter::eq("ref", Value::Uuid(uuid::Uuid::new_v4()))).unwrap());
+    }
+
+    #[test]
+    fn test_expr_compares_fields() {
+        use crate::query::{ExprOp, ExprOperand};
+
+        let executor = QueryExecutor::new();
+        let rows = [
+            (Some(Value::Int32(120)), Value::Int32(100)),
+            (Some(Value::Int64(80)), Value::Int32(100)),
+            (Some(Value::Float64(100.0)), Value::Int32(100)),
+            (None, Value::Int32(50)),
+            (Some(Value::from("n/a")), Value::Int32(1)),
+        ];
+        let docs: Vec<Document> = rows
+            .into_iter()
+            .map(|(spent, budget)| {
+                let mut doc = Document::new();
+                if let Some(spent) = spent {
+                    doc.insert("spent".to_string(), spent);
+                }
+                doc.insert("budget".to_string(), budget);
+                doc
+            })
+            .collect();
+        let matching = |filter: Filter| {
+            docs.iter()
+                .enumerate()
+                .filter(|(_, doc)| executor.matches_filter(doc, &filter).unwrap())
+                .map(|(position, _)| position)
+                .collect::<Vec<usize>>()
+        };
+
+        // Numbers compare by value across types; strings sort after numbers
+        // and a missing field before everything
+        assert_eq!(matching(Filter::fields_compare(ExprOp::Gt, "spent", "budget")), vec![0, 4]);
+        assert_eq!(matching(Filter::fields_compare(ExprOp::Lte, "spent", "budget")), vec![1, 2, 3]);
+        assert_eq!(matching(Filter::fields_compare(ExprOp::Eq, "spent", "budget")), vec![2]);
+        let at_least = Filter::Expr {
+            op: ExprOp::Gte,
+            left: ExprOperand::Field("spent".to_string()),
+            right: ExprOperand::Literal(Value::Int32(100)),
+        };
+        assert_eq!(matching(at_least), vec![0, 2, 4]);
+
+        // Arrays compare whole, not element by element
+        let mut doc = Document::new();
+        doc.insert("a".to_string(), Value::Array(vec![Value::Int32(1), Value::Int32(2)]));
+        doc.insert("b".to_string(), Value::Array(vec![Value::Int32(1)]));
+        doc.insert("name".to_string(), Value::from("Ann"));
+        doc.insert("alias".to_string(), Value::from("ann"));
+        assert!(executor.matches_filter(&doc, &Filter::fields_compare(ExprOp::Gt, "a", "b")).unwrap());
+        assert!(!executor.matches_filter(&doc, &Filter::fields_compare(ExprOp::Eq, "a", "b")).unwrap());
+
+        let same_name = Filter::fields_compare(ExprOp::Eq, "name", "alias");
+        assert!(!executor.matches_filter(&doc, &same_name).unwrap());
+        let case_insensitive = Collation::new("en").with_strength(2);
+        assert!(executor.matches_filter_collated(&doc, &same_name, Some(&case_insensitive)).unwrap());
+    }
+
+    #[test]
+    fn test_array_fields_match_any_element() {
+        let executor = QueryExecutor::new();
+        let docs = create_array_documents();
+        let matching = |filter: Filter| {
+            let positions: Vec<usize> = docs
+                .iter()
+                .enumerate()
+                .filter(|(_, doc)| executor.matches_filter(doc, &filter).unwrap())
+                .map(|(position, _)| position)
+                .collect();
+            positions
+        };
+
+        assert_eq!(matching(Filter::eq("tags", "red")), vec![0, 2]);
+        assert_eq!(matching(Filter::eq("tags", Value::Array(vec!["blue".into()]))), vec![1]);
+        assert_eq!(matching(Filter::ne("tags", "red")), vec![1, 3, 4]);
+        assert_eq!(matching(Filter::gt("scores", 85i32)), vec![0, 4]);
+        assert_eq!(matching(Filter::in_values("tags", vec!["blue".into(), "sale".into()])), vec![0, 1, 2, 4]);
+        assert_eq!(matching(Filter::nin("tags", vec!["red".into(), "sale".into()])), vec![1, 3]);
+        assert_eq!(matching(Filter::eq("items.sku", "c3")), vec![2, 4]);
+        assert_eq!(matching(Filter::all("tags", vec!["red".into(), "sale".into()])), vec![0]);
+        assert!(matching(Filter::all("tags", Vec::new())).is_empty());
+
+        // Separate conditions may be met by different elements; $elemMatch needs one element
+        let between = vec![Filter::gt("scores", 75i32), Filter::lt("scores", 85i32)];
+        assert_eq!(matching(Filter::and(between.clone())), vec![0, 1, 4]);
+        let between = between
+            .into_iter()
+            .map(|filter| match filter {
+                Filter::Gt { value, .. } => Filter::gt("", value),
+                Filter::Lt { value, .. } => Filter::lt("", value),
+                other => other,
+            })
+            .collect();
+        assert_eq!(matching(Filter::elem_match("scores", Filter::and(between))), vec![1, 4]);
+        let item = Filter::and(vec![Filter::eq("sku", "a1"), Filter::gte("qty", 5i32)]);
+        assert_eq!(matching(Filter::elem_match("items", item)), vec![1]);
+    }
+
+    #[test]
+    fn test_multikey_index_scan_matches_collection_scan() {
+        use crate::schema::IndexDefinition;
+
+        // Unmatched filler keeps the array predicates selective enough to index
+        let mut docs = create_array_documents();
+        for _ in 0..200 {
+            let mut doc = Document::with_id(DocumentId::new());
+            doc.insert("tags".to_string(), Value::Array(vec!["plain".into()]));
+            doc.insert("scores".to_string(), Value::Array(vec![Value::Int32(10), Value::Int32(20)]));
+            docs.push(doc);
+        }
+        let manager = Arc::new(IndexManager::new("products".to_string()));
+        for field in ["tags", "scores", "items.sku"] {
+            let definition = IndexDefinition::single(field.to_string());
+            let name = definition.name.clone();
+            manager.register_index(definition).unwrap();
+            manager.populate_index(&name, &docs).unwrap();
+        }
+        assert_eq!(manager.multikey_indexes().len(), 3);
+
+        let mut indexed = QueryExecutor::new();
+        indexed.set_index_manager(manager);
+        let scanner = QueryExecutor::new();
+        let ids = |docs: Vec<Document>| {
+            let mut ids: Vec<DocumentId> = docs.iter().map(|d| d.id).collect();
+            ids.sort();
+            ids
+        };
+
+        let queries = [
+            Filter::in_values("tags", vec!["blue".into(), "sale".into()]),
+            Filter::all("tags", vec!["red".into(), "sale".into()]),
+            Filter::and(vec![Filter::gt("scores", 75i32), Filter::lt("scores", 85i32)]),
+            Filter::elem_match("scores", Filter::and(vec![Filter::gt("", 75i32), Filter::lt("", 85i32)])),
+            Filter::elem_match("items", Filter::and(vec![Filter::eq("sku", "a1"), Filter::gte("qty", 5i32)])),
+        ];
+        for filter in queries {
+            let query = Query::with_filter(filter);
+            let plan = indexed.planner().create_plan(&query).unwrap();
+            let scan = plan.index_scan.as_ref().expect("array predicates should use an index");
+            assert!(scan.multikey);
+
+            let from_index = ids(indexed.execute(docs.clone(), &query).unwrap());
+            let from_scan = ids(scanner.execute(docs.clone(), &query).unwrap());
+            assert!(!from_index.is_empty());
+            assert_eq!(from_index, from_scan, "{:?}", query.filter);
+        }
+
+        // Element values cannot rebuild the arrays a projection returns
+        let query = Query::with_filter(Filter::eq("tags", "red")).projection(Projection::new().include("tags"));
+        let plan = indexed.planner().create_plan(&query).unwrap();
+        assert_eq!(plan.use_index, Some("idx_tags".to_string()));
+        assert!(!plan.covered);
+        let results = indexed.execute(docs.clone(), &query).unwrap();
+        assert!(results.iter().all(|doc| matches!(doc.get("tags"), Some(Value::Array(_)))));
+    }
+
+    /// Counts how many documents the executor loads
+    struct CountingSource {
+        docs: Vec<Document>,
+        fetched: RefCell<usize>,
+        scanned: RefCell<bool>,
+    }
+
+    impl CountingSource {
+        fn new(docs: Vec<Document>) -> Self {
+            Self {
+                docs,
+                fetched: RefCell::new(0),
+                scanned: RefCell::new(false),
+            }
+        }
+    }
+
+    impl DocumentSource for CountingSource {
+        fn scan(&self) -> Result<Vec<Document>, QueryExecutionError> {
+            *self.scanned.borrow_mut() = true;
+            Ok(self.docs.clone())
+        }
+
+        fn fetch(&self, doc_id: DocumentId) -> Result<Option<Document>, QueryExecutionError> {
+            *self.fetched.borrow_mut() += 1;
+            Ok(self.docs.iter().find(|d| d.id == doc_id).cloned())
+        }
+    }
+
+    fn age_index(docs: &[Document]) -> Arc<IndexManager> {
+        use crate::schema::IndexDefinition;
+
+        let manager = Arc::new(IndexManager::new("users".to_string()));
+        manager
+            .register_index(IndexDefinition::single("age".to_string()))
+            .unwrap();
+        manager.populate_index("idx_age", docs).unwrap();
+        manager
+    }
+
+    #[test]
+    fn test_execution_stats_count_keys_and_documents() {
+        let docs = create_test_documents();
+        let mut indexed = QueryExecutor::new();
+        indexed.set_index_manager(age_index(&docs));
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::gte("age", 25i32),
+            Filter::eq("active", true),
+        ]))
+        .limit(1);
+
+        // Age 25 is inactive, so the walk stops after the second key
+        let source = CountingSource::new(docs.clone());
+        let (results, plan, stats) = indexed.execute_with_stats(&source, &query).unwrap();
+        assert_eq!(plan.execution_strategy, crate::query::planner::ExecutionStrategy::IndexScan);
+        assert_eq!(results[0].get("age"), Some(&Value::Int32(26)));
+        assert_eq!((stats.keys_examined, stats.documents_examined, stats.documents_returned), (2, 2, 1));
+        let stages: Vec<_> = stats.stages.iter().map(|s| (s.stage.as_str(), s.documents_out)).collect();
+        assert_eq!(stages, vec![("index_scan", 1), ("limit", 1)]);
+
+        let source = CountingSource::new(docs);
+        let (_, _, stats) = QueryExecutor::new().execute_with_stats(&source, &query).unwrap();
+        assert_eq!((stats.keys_examined, stats.documents_examined, stats.documents_returned), (0, 10, 1));
+        let stages: Vec<_> = stats.stages.iter().map(|s| (s.stage.as_str(), s.documents_out)).collect();
+        assert_eq!(stages, vec![("collection_scan", 2), ("limit", 1)]);
+    }
+
+    #[test]
+    fn test_plan_cache_reuses_plans_across_values() {
+        use crate::schema::IndexDefinition;
+
+        let docs = create_test_documents();
+        let manager = age_index(&docs);
+        let cache = Arc::new(PlanCache::new());
+        let mut executor = QueryExecutor::new();
+        executor.set_index_manager(manager.clone());
+        executor.set_plan_cache(cache.clone());
+
+        let older = |age: i32| Query::with_filter(Filter::gte("age", age));
+        let run = |query: &Query| {
+            let mut ages: Vec<_> = executor
+                .execute(docs.clone(), query)
+                .unwrap()
+                .into_iter()
+                .filter_map(|d| d.get("age").cloned())
+                .collect();
+            ages.sort_by_key(|age| format!("{:?}", age));
+            ages
+        };
+
+        assert_eq!(run(&older(27)).len(), 3);
+        // Same shape, new bound: the cached index is re-scanned with it
+        assert_eq!(run(&older(28)), vec![Value::Int32(28), Value::Int32(29)]);
+        let entries = cache.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].solution.indexes, vec!["idx_age".to_string()]);
+        assert_eq!(entries[0].executions, 2);
+
+        // A new index makes the entry stale, so the shape is planned again
+        manager.register_index(IndexDefinition::single("name".to_string())).unwrap();
+        assert_eq!(run(&older(29)), vec![Value::Int32(29)]);
+        assert_eq!(cache.entries()[0].executions, 1);
+
+        // Planning alone leaves the cache untouched
+        executor.plan(&Query::with_filter(Filter::eq("name", "User1"))).unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_covered_query_reads_no_documents() {
+        let docs = create_test_documents();
+        let mut indexed = QueryExecutor::new();
+        indexed.set_index_manager(age_index(&docs));
+
+        let query = Query::with_filter(Filter::gte("age", 25i32))
+            .projection(Projection::new().include("age"))
+            .sort(Sort::new().asc("age"));
+        assert!(indexed.plan(&query).unwrap().covered);
+
+        let source = CountingSource::new(docs.clone());
+        let covered = indexed.execute_from(&source, &query).unwrap();
+        let scanned = QueryExecutor::new().execute(docs, &query).unwrap();
+
+        assert_eq!(*source.fetched.borrow(), 0);
+        assert!(!*source.scanned.borrow());
+        let rows = |docs: &[Document]| {
+            docs.iter()
+                .map(|d| (d.id, d.fields.keys().cloned().collect::<Vec<_>>(), d.get("age").cloned()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(covered.len(), 5);
+        assert_eq!(rows(&covered), rows(&scanned));
+    }
+
+    #[test]
+    fn test_mixed_numeric_keys_fetch_documents() {
+        let mut docs = create_test_documents();
+        let manager = age_index(&docs);
+        let executor = |manager: &Arc<IndexManager>| {
+            let mut indexed = QueryExecutor::new();
+            indexed.set_index_manager(manager.clone());
+            indexed
+        };
+
+        let query = Query::with_filter(Filter::gte("age", 25i32))
+            .projection(Projection::new().include("age"))
+            .sort(Sort::new().asc("age"));
+        assert!(executor(&manager).plan(&query).unwrap().covered);
+
+        // Int64(26) and Float64(27.0) share the keys of the Int32 ages
+        let version = manager.version();
+        for value in [Value::Int64(26), Value::Float64(27.0)] {
+            let mut doc = Document::new();
+            doc.insert("age".to_string(), value);
+            manager.insert_document(doc.id, &doc).unwrap();
+            docs.push(doc);
+        }
+        assert!(manager.version() > version);
+        let indexed = executor(&manager);
+        let plan = indexed.plan(&query).unwrap();
+        assert!(!plan.covered);
+        assert!(plan.index_scan.is_some());
+
+        let source = CountingSource::new(docs.clone());
+        let results = indexed.execute_from(&source, &query).unwrap();
+        assert_eq!(results.len(), 7);
+        assert_eq!(*source.fetched.borrow(), 7);
+        let stored: HashMap<DocumentId, Option<Value>> =
+            docs.iter().map(|d| (d.id, d.get("age").cloned())).collect();
+        for doc in &results {
+            assert_eq!(doc.get("age").cloned(), stored[&doc.id]);
+        }
+    }
+
+    #[test]
+    fn test_collated_index_never_covers() {
+        use crate::schema::IndexDefinition;
+
+        let mut docs = create_test_documents();
+        for (doc, name) in docs.iter_mut().zip(["Alice", "alice"]) {
+            doc.insert("name".to_string(), Value::String(name.to_string()));
+        }
+        let case_insensitive = Collation::new("en").with_strength(2);
+        let manager = Arc::new(IndexManager::new("users".to_string()));
+        manager
+            .register_index(IndexDefinition::single("name".to_string()).with_collation(case_insensitive.clone()))
+            .unwrap();
+        manager.populate_index("idx_name", &docs).unwrap();
+        let mut indexed = QueryExecutor::new();
+        indexed.set_index_manager(manager);
+
+        let query = Query::with_filter(Filter::eq("name", "ALICE"))
+            .projection(Projection::new().include("name"))
+            .collation(case_insensitive);
+        let plan = indexed.plan(&query).unwrap();
+        assert_eq!(plan.index_scan.as_ref().map(|scan| scan.index.as_str()), Some("idx_name"));
+        assert!(!plan.covered);
+
+        let mut names: Vec<Value> = indexed
+            .execute(docs, &query)
+            .unwrap()
+            .iter()
+            .filter_map(|doc| doc.get("name").cloned())
+            .collect();
+        names.sort_by_key(|name| name.as_str().map(str::to_string));
+        assert_eq!(names, vec![Value::String("Alice".to_string()), Value::String("alice".to_string())]);
+    }
+
+    #[test]
+    fn test_reverse_sort_limit_stops_index_scan() {
+        let docs = create_test_documents();
+        let mut indexed = QueryExecutor::new();
+        indexed.set_index_manager(age_index(&docs));
+
+        let query = Query::new().sort(Sort::new().desc("age")).skip(1).limit(3);
+        let plan = indexed.plan(&query).unwrap();
+        assert!(!plan.needs_sort);
+        assert!(plan.index_scan.as_ref().unwrap().reverse);
+
+        let source = CountingSource::new(docs.clone());
+        let from_index = indexed.execute_from(&source, &query).unwrap();
+        let from_scan = QueryExecutor::new().execute(docs, &query).unwrap();
+
+        assert_eq!(*source.fetched.borrow(), 4);
+        let ages = |docs: &[Document]| docs.iter().map(|d| d.get("age").cloned()).collect::<Vec<_>>();
+        assert_eq!(ages(&from_index), vec![Some(Value::Int32(28)), Some(Value::Int32(27)), Some(Value::Int32(26))]);
+        assert_eq!(ages(&from_index), ages(&from_scan));
+    }
+
+    #[test]
+    fn test_index_scan_reports_source_errors() {
+        struct FailingSource;
+
+        impl DocumentSource for FailingSource {
+            fn scan(&self) -> Result<Vec<Document>, QueryExecutionError> {
+                Ok(Vec::new())
+            }
+
+            fn fetch(&self, _doc_id: DocumentId) -> Result<Option<Document>, QueryExecutionError> {
+                Err(QueryExecutionError::ExecutionError("disk unavailable".to_string()))
+            }
+        }
+
+        let mut indexed = QueryExecutor::new();
+        indexed.set_index_manager(age_index(&create_test_documents()));
+
+        let query = Query::with_filter(Filter::gte("age", 25i32));
+        let result = indexed.execute_from(&FailingSource, &query);
+        assert!(matches!(result, Err(QueryExecutionError::ExecutionError(msg)) if msg == "disk unavailable"));
+    }
+
+    #[test]
+    fn test_index_intersection_matches_collection_scan() {
+        use crate::query::planner::ExecutionStrategy;
+        use crate::schema::IndexDefinition;
+
+        let docs: Vec<Document> = (0..500)
+            .map(|i| {
+                let mut doc = Document::with_id(DocumentId::new());
+                doc.insert("a".to_string(), Value::Int64(i % 10));
+                doc.insert("b".to_string(), Value::Int64(i % 7));
+                doc
+            })
+            .collect();
+
+        let manager = Arc::new(IndexManager::new("items".to_string()));
+        for field in ["a", "b"] {
+            manager
+                .register_index(IndexDefinition::single(field.to_string()))
+                .unwrap();
+            manager.populate_index(&format!("idx_{}", field), &docs).unwrap();
+        }
+        let mut indexed = QueryExecutor::new();
+        indexed.set_index_manager(manager);
+
+        let query = Query::with_filter(Filter::and(vec![Filter::eq("a", 3i64), Filter::eq("b", 2i64)]));
+        let plan = indexed.plan(&query).unwrap();
+        assert_eq!(plan.execution_strategy, ExecutionStrategy::IndexIntersection);
+
+        let source = CountingSource::new(docs.clone());
+        let mut from_index: Vec<DocumentId> = indexed
+            .execute_from(&source, &query)
+            .unwrap()
+            .iter()
+            .map(|d| d.id)
+            .collect();
+        let mut from_scan: Vec<DocumentId> = QueryExecutor::new()
+            .execute(docs, &query)
+            .unwrap()
+            .iter()
+            .map(|d| d.id)
+            .collect();
+        from_index.sort();
+        from_scan.sort();
+
+        // Only documents in both index scans are loaded
+        assert_eq!(*source.fetched.borrow(), from_scan.len());
+        assert!(!from_scan.is_empty());
+        assert_eq!(from_index, from_scan);
+    }
+}
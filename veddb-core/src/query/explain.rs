@@ -0,0 +1,131 @@
+//! Query explain output
+//!
+//! Verbosity levels accepted by `Explain` and the statistics gathered when
+//! a query or aggregation pipeline runs under `executionStats`.
+
+use crate::document::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How much an explain reports
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExplainVerbosity {
+    /// Report the chosen and rejected plans without running the query
+    #[default]
+    QueryPlanner,
+    /// Also run the query and report what each stage did
+    ExecutionStats,
+}
+
+/// Work done while executing a query or pipeline
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionStats {
+    /// Documents loaded from storage
+    pub documents_examined: u64,
+    /// Index entries read, across every index scan
+    pub keys_examined: u64,
+    /// Documents in the final result
+    pub documents_returned: u64,
+    /// Wall time of the whole execution
+    pub execution_time: Duration,
+    /// Per-stage statistics in execution order
+    pub stages: Vec<StageStats>,
+}
+
+impl ExecutionStats {
+    /// Create empty statistics
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a finished stage
+    pub fn record_stage(&mut self, stage: impl Into<String>, documents_out: usize, time: Duration) {
+        self.stages.push(StageStats {
+            stage: stage.into(),
+            documents_out: documents_out as u64,
+            time,
+        });
+    }
+
+    /// Describe the statistics as a document value
+    pub fn to_value(&self) -> Value {
+        Value::Object(BTreeMap::from([
+            ("documents_examined".to_string(), Value::Int64(self.documents_examined as i64)),
+            ("keys_examined".to_string(), Value::Int64(self.keys_examined as i64)),
+            ("documents_returned".to_string(), Value::Int64(self.documents_returned as i64)),
+            ("execution_time_micros".to_string(), micros(self.execution_time)),
+            (
+                "stages".to_string(),
+                Value::Array(self.stages.iter().map(StageStats::to_value).collect()),
+            ),
+        ]))
+    }
+}
+
+/// Work done by one stage
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageStats {
+    /// Stage name, as in the plan description
+    pub stage: String,
+    /// Documents the stage passed on
+    pub documents_out: u64,
+    /// Time spent in the stage itself, excluding earlier stages
+    pub time: Duration,
+}
+
+impl StageStats {
+    /// Describe the stage as a document value
+    pub fn to_value(&self) -> Value {
+        Value::Object(BTreeMap::from([
+            ("stage".to_string(), Value::String(self.stage.clone())),
+            ("documents_out".to_string(), Value::Int64(self.documents_out as i64)),
+            ("time_micros".to_string(), micros(self.time)),
+        ]))
+    }
+}
+
+fn micros(duration: Duration) -> Value {
+    Value::Int64(i64::try_from(duration.as_micros()).unwrap_or(i64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_names() {
+        let verbosity: ExplainVerbosity = serde_json::from_str("\"executionStats\"").unwrap();
+        assert_eq!(verbosity, ExplainVerbosity::ExecutionStats);
+        assert_eq!(ExplainVerbosity::default(), ExplainVerbosity::QueryPlanner);
+        assert!(serde_json::from_str::<ExplainVerbosity>("\"allPlans\"").is_err());
+    }
+
+    #[test]
+    fn test_stats_to_value() {
+        let mut stats = ExecutionStats::new();
+        stats.documents_examined = 4;
+        stats.keys_examined = 6;
+        stats.documents_returned = 2;
+        stats.record_stage("index_scan", 4, Duration::from_micros(30));
+        stats.record_stage("limit", 2, Duration::from_micros(1));
+
+        let Value::Object(value) = stats.to_value() else {
+            panic!("stats should describe themselves as an object");
+        };
+        assert_eq!(value["keys_examined"], Value::Int64(6));
+        let Value::Array(stages) = &value["stages"] else {
+            panic!("stages should be an array");
+        };
+        assert_eq!(stages.len(), 2);
+        assert_eq!(
+            stages[0],
+            Value::Object(BTreeMap::from([
+                ("stage".to_string(), Value::String("index_scan".to_string())),
+                ("documents_out".to_string(), Value::Int64(4)),
+                ("time_micros".to_string(), Value::Int64(30)),
+            ]))
+        );
+    }
+}
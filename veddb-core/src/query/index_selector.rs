@@ -2,12 +2,18 @@
 //!
 //! Analyzes queries and selects the most appropriate index
 
-use super::ast::{Filter, Query, Sort};
+use super::ast::{Filter, Query, Sort, SortOrder};
+use super::planner::{IndexScan, KeyBound, KeyRange};
+use crate::document::Value;
+use super::collation;
+use crate::index::btree::{IndexValue, KeyForm};
 use crate::schema::IndexDefinition;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
 /// Index selector for choosing optimal indexes
 pub struct IndexSelector {
-    /// Available indexes (will be populated from actual collection indexes in task 4.3)
+    /// Indexes available on the collection
     available_indexes: Vec<IndexCandidate>,
 }
 
@@ -31,148 +37,253 @@ impl IndexSelector {
         }
     }
 
-    /// Select the best index for a query
-    pub fn select_index(&self, query: &Query) -> Result<Option<String>, IndexSelectionError> {
-        if self.available_indexes.is_empty() {
-            return Ok(None);
+    /// Flag the named indexes as multikey
+    ///
+    /// A multikey index holds a document once per array element, so it
+    /// cannot cover a query or supply sort order, and range predicates on
+    /// it only combine when they apply to the same element.
+    pub fn with_multikey(mut self, indexes: &HashSet<String>) -> Self {
+        for candidate in &mut self.available_indexes {
+            candidate.multikey = indexes.contains(&candidate.name);
         }
+        self
+    }
 
-        let mut candidates = Vec::new();
-
-        // Analyze filter for index candidates
-        self.analyze_filter(&query.filter, &mut candidates)?;
-
-        // Score candidates based on query characteristics
-        for candidate in &mut candidates {
-            candidate.score = self.calculate_score(candidate, query)?;
+    /// Flag the named indexes as storing keys that may not rebuild the
+    /// documents' values, so they cannot cover a query
+    pub fn with_lossy_keys(mut self, indexes: &HashSet<String>) -> Self {
+        for candidate in &mut self.available_indexes {
+            candidate.lossy_keys = indexes.contains(&candidate.name);
         }
+        self
+    }
 
-        // Sort by score (higher is better)
-        candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    /// Select the best index for a query
+    pub fn select_index(&self, query: &Query) -> Result<Option<String>, IndexSelectionError> {
+        Ok(self.select_scan(query)?.map(|scan| scan.index))
+    }
 
-        // Return the best candidate
-        Ok(candidates.first().map(|c| c.name.clone()))
+    /// Select the best index for a query along with the key ranges to scan
+    ///
+    /// An index is usable when its leading fields are bound by equality or
+    /// `$in` predicates, optionally followed by a range on the next field.
+    /// `$in` values expand into one key range per combination. A query with
+    /// a limit may also walk a whole index whose order matches its sort, so
+    /// the scan can stop once enough documents are found.
+    pub fn select_scan(&self, query: &Query) -> Result<Option<IndexScan>, IndexSelectionError> {
+        Ok(self.candidate_scans(query)?.into_iter().next())
     }
 
-    /// Analyze filter to find potential index candidates
-    fn analyze_filter(
-        &self,
-        filter: &Filter,
-        candidates: &mut Vec<IndexCandidate>,
-    ) -> Result<(), IndexSelectionError> {
-        match filter {
-            Filter::Empty => {}
-            
-            // Single field filters
-            Filter::Eq { field, .. } |
-            Filter::Ne { field, .. } |
-            Filter::Gt { field, .. } |
-            Filter::Gte { field, .. } |
-            Filter::Lt { field, .. } |
-            Filter::Lte { field, .. } |
-            Filter::In { field, .. } |
-            Filter::Nin { field, .. } |
-            Filter::Exists { field, .. } => {
-                self.find_indexes_for_field(field, candidates);
+    /// Every usable index scan for a query, best heuristic score first
+    ///
+    /// The cost-based planner prices each of these instead of trusting the
+    /// score order.
+    ///
+    /// An index serves only queries with the same collation, since its
+    /// keys order strings by that collation. Such a query's string
+    /// predicates are matched as collation keys.
+    pub fn candidate_scans(&self, query: &Query) -> Result<Vec<IndexScan>, IndexSelectionError> {
+        let query_collation = collation::effective(query.collation.as_ref());
+        let predicates = FieldPredicates::from_filter(&query.filter);
+        let collated = query_collation.map(|c| FieldPredicates::from_filter(&c.key_filter(&query.filter)));
+        let mut scored: Vec<(f64, IndexScan)> = Vec::new();
+
+        for candidate in &self.available_indexes {
+            if matches!(candidate.index_type, IndexType::Text { .. } | IndexType::Vector { .. }) {
+                continue;
             }
-            
-            Filter::Regex { field, .. } => {
-                // Text indexes are preferred for regex
-                self.find_text_indexes_for_field(field, candidates);
-                // Fall back to regular indexes
-                self.find_indexes_for_field(field, candidates);
+            if candidate.key_form.collation() != query_collation {
+                continue;
             }
-            
-            // Logical operators
-            Filter::And(filters) => {
-                for f in filters {
-                    self.analyze_filter(f, candidates)?;
+            // A partial index lacks documents outside its filter
+            if let Some(partial) = &candidate.partial_filter {
+                if !implies(&query.filter, partial) {
+                    continue;
                 }
-                // Also look for compound indexes that cover multiple fields
-                self.find_compound_indexes(filters, candidates);
             }
-            
-            Filter::Or(filters) => {
-                // OR queries are harder to optimize with indexes
-                // Look for indexes on individual branches
-                for f in filters {
-                    self.analyze_filter(f, candidates)?;
+
+            // A hashed index applies the collation itself when hashing
+            let predicates = match &collated {
+                Some(collated) if !candidate.key_form.is_hashed() => collated,
+                _ => &predicates,
+            };
+            let (mut prefix_match, full_scan) = match self.match_prefix(candidate, predicates) {
+                Some(prefix_match) => (prefix_match, false),
+                // A sparse index lacks documents without the field
+                None if query.limit.is_some() && !candidate.sparse => {
+                    (PrefixMatch::full_scan(candidate), true)
+                }
+                None => continue,
+            };
+
+            let order = query
+                .sort
+                .as_ref()
+                .filter(|_| !candidate.multikey && !candidate.key_form.is_hashed())
+                .and_then(|sort| self.scan_order(candidate, sort, &prefix_match));
+            if full_scan && order.is_none() {
+                continue;
+            }
+            prefix_match.scan.sorted = order.is_some();
+            prefix_match.scan.reverse = order.unwrap_or(false);
+
+            let mut score = self.calculate_score(candidate, query)?;
+            if prefix_match.scan.sorted {
+                score += 5.0;
+                if query.limit.is_some() {
+                    score += 10.0;
                 }
             }
-            
-            Filter::Not(filter) => {
-                // NOT queries typically can't use indexes effectively
-                self.analyze_filter(filter, candidates)?;
+            score += prefix_match.equality_fields as f64 * 10.0;
+            if prefix_match.has_range {
+                score += 5.0;
+            }
+            if candidate.unique && prefix_match.equality_fields == candidate.fields.len() {
+                score += 10.0;
             }
+            // Prefer fewer key ranges when coverage is otherwise equal
+            score -= prefix_match.scan.ranges.len() as f64 * 0.01;
+
+            scored.push((score, prefix_match.scan));
         }
 
-        Ok(())
+        // Stable, so equal scores keep index definition order
+        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        Ok(scored.into_iter().map(|(_, scan)| scan).collect())
     }
 
-    /// Find indexes that can be used for a specific field
-    pub fn find_indexes_for_field(&self, field: &str, candidates: &mut Vec<IndexCandidate>) {
-        for index in &self.available_indexes {
-            if index.can_use_for_field(field) {
-                if !candidates.iter().any(|c| c.name == index.name) {
-                    candidates.push(index.clone());
+    /// Match the leading fields of an index against the query predicates
+    ///
+    /// A hashed index only answers equality and `$in`, with each value
+    /// replaced by its hash.
+    fn match_prefix(
+        &self,
+        candidate: &IndexCandidate,
+        predicates: &FieldPredicates,
+    ) -> Option<PrefixMatch> {
+        let mut prefixes: Vec<Vec<Value>> = vec![Vec::new()];
+        let mut equality_fields = 0;
+        let mut lower = None;
+        let mut upper = None;
+
+        for field in &candidate.fields {
+            if let Some(values) = predicates.equalities.get(field) {
+                // A sparse index has no entries for null, so it cannot answer null lookups
+                if candidate.sparse && values.iter().any(|v| matches!(v, Value::Null)) {
+                    break;
+                }
+                if prefixes.len() * values.len() > MAX_KEY_RANGES {
+                    break;
                 }
+                let values: Vec<Value> = if candidate.key_form.is_hashed() {
+                    values.iter().map(|v| candidate.key_form.apply_value(v)).collect()
+                } else {
+                    values.clone()
+                };
+                prefixes = prefixes
+                    .into_iter()
+                    .flat_map(|prefix| {
+                        values.iter().map(move |value| {
+                            let mut extended = prefix.clone();
+                            extended.push(value.clone());
+                            extended
+                        })
+                    })
+                    .collect();
+                equality_fields += 1;
+                continue;
             }
-        }
-    }
 
-    /// Find text indexes for a field (for regex queries)
-    fn find_text_indexes_for_field(&self, field: &str, candidates: &mut Vec<IndexCandidate>) {
-        for index in &self.available_indexes {
-            if matches!(index.index_type, IndexType::Text { .. }) && index.can_use_for_field(field) {
-                if !candidates.iter().any(|c| c.name == index.name) {
-                    let mut candidate = index.clone();
-                    candidate.score += 5.0; // Bonus for text indexes on regex
-                    candidates.push(candidate);
+            if let Some(bounds) = predicates.ranges.get(field).filter(|_| !candidate.key_form.is_hashed()) {
+                let has_null_bound = [&bounds.lower, &bounds.upper]
+                    .iter()
+                    .any(|b| matches!(b, Some(KeyBound { value: Value::Null, .. })));
+                if !(candidate.sparse && has_null_bound) {
+                    lower = bounds.lower.clone();
+                    upper = bounds.upper.clone();
+                }
+                // Different elements may satisfy each end, so keep just one
+                if candidate.multikey && lower.is_some() && !bounds.same_element() {
+                    upper = None;
                 }
             }
+            break;
+        }
+
+        let has_range = lower.is_some() || upper.is_some();
+        if equality_fields == 0 && !has_range {
+            return None;
         }
-    }
 
-    /// Find compound indexes that cover multiple fields in an AND query
-    fn find_compound_indexes(&self, filters: &[Filter], candidates: &mut Vec<IndexCandidate>) {
-        let fields: Vec<String> = filters
-            .iter()
-            .filter_map(|f| self.get_filter_field(f))
+        let ranges = prefixes
+            .into_iter()
+            .map(|prefix| KeyRange {
+                prefix,
+                lower: lower.clone(),
+                upper: upper.clone(),
+            })
             .collect();
 
-        for index in &self.available_indexes {
-            if let IndexType::Compound { fields: index_fields } = &index.index_type {
-                // Check if the index covers the query fields
-                let covered_fields = fields
-                    .iter()
-                    .filter(|f| index_fields.contains(f))
-                    .count();
-
-                if covered_fields > 1 {
-                    if !candidates.iter().any(|c| c.name == index.name) {
-                        let mut candidate = index.clone();
-                        candidate.score += covered_fields as f64 * 2.0; // Bonus for compound coverage
-                        candidates.push(candidate);
-                    }
-                }
+        Some(PrefixMatch {
+            scan: IndexScan {
+                index: candidate.name.clone(),
+                fields: candidate.fields.clone(),
+                ranges,
+                reverse: false,
+                sorted: false,
+                multikey: candidate.multikey,
+                opaque_keys: !candidate.key_form.is_plain() || candidate.lossy_keys,
+            },
+            equality_fields,
+            has_range,
+        })
+    }
+
+    /// Check whether scanning an index yields the requested sort order
+    ///
+    /// Returns `Some(reverse)` when walking the matched key range forward,
+    /// or backward if `reverse`, produces documents already sorted. Sort
+    /// fields pinned by an equality prefix are constant and ignored.
+    fn scan_order(
+        &self,
+        candidate: &IndexCandidate,
+        sort: &Sort,
+        prefix_match: &PrefixMatch,
+    ) -> Option<bool> {
+        // Several $in ranges are each ordered but not ordered together
+        if prefix_match.scan.ranges.len() != 1 {
+            return None;
+        }
+
+        let constant = &candidate.fields[..prefix_match.equality_fields];
+        let mut position = prefix_match.equality_fields;
+        let mut reverse = None;
+
+        for (field, order) in &sort.fields {
+            if constant.contains(field) {
+                continue;
+            }
+            if candidate.fields.get(position) != Some(field) {
+                return None;
             }
+            let flipped = matches!(order, SortOrder::Descending) != candidate.is_descending(position);
+            if *reverse.get_or_insert(flipped) != flipped {
+                return None;
+            }
+            position += 1;
         }
+
+        Some(reverse.unwrap_or(false))
     }
 
-    /// Get the field name from a filter
-    fn get_filter_field(&self, filter: &Filter) -> Option<String> {
-        match filter {
-            Filter::Eq { field, .. } |
-            Filter::Ne { field, .. } |
-            Filter::Gt { field, .. } |
-            Filter::Gte { field, .. } |
-            Filter::Lt { field, .. } |
-            Filter::Lte { field, .. } |
-            Filter::In { field, .. } |
-            Filter::Nin { field, .. } |
-            Filter::Exists { field, .. } |
-            Filter::Regex { field, .. } => Some(field.clone()),
-            _ => None,
+    /// Find indexes that can be used for a specific field
+    pub fn find_indexes_for_field(&self, field: &str, candidates: &mut Vec<IndexCandidate>) {
+        for index in &self.available_indexes {
+            if index.can_use_for_field(field) {
+                if !candidates.iter().any(|c| c.name == index.name) {
+                    candidates.push(index.clone());
+                }
+            }
         }
     }
 
@@ -231,19 +342,290 @@ impl IndexSelector {
     }
 
     /// Check if index can help with sorting
+    ///
+    /// The sort fields must follow the index fields in order, with every
+    /// direction either matching the index or every direction reversed.
     fn can_help_with_sort(&self, candidate: &IndexCandidate, sort: &Sort) -> bool {
-        if let Some(first_sort_field) = sort.first_field() {
-            match &candidate.index_type {
-                IndexType::Single { field } => field == first_sort_field,
-                IndexType::Compound { fields } => {
-                    fields.first().map(|f| f == first_sort_field).unwrap_or(false)
+        if sort.fields.is_empty() || sort.fields.len() > candidate.fields.len() {
+            return false;
+        }
+        if matches!(
+            candidate.index_type,
+            IndexType::Text { .. } | IndexType::Hashed { .. } | IndexType::Vector { .. }
+        ) {
+            return false; // Text, hashed and vector indexes don't help with sorting
+        }
+
+        let mut forward = true;
+        let mut backward = true;
+        for (position, (field, order)) in sort.fields.iter().enumerate() {
+            if &candidate.fields[position] != field {
+                return false;
+            }
+            let descending = matches!(order, SortOrder::Descending);
+            if descending == candidate.is_descending(position) {
+                backward = false;
+            } else {
+                forward = false;
+            }
+        }
+        forward || backward
+    }
+}
+
+/// Upper bound on key ranges produced by `$in` expansion
+const MAX_KEY_RANGES: usize = 256;
+
+/// Result of matching an index prefix against query predicates
+struct PrefixMatch {
+    scan: IndexScan,
+    equality_fields: usize,
+    has_range: bool,
+}
+
+impl PrefixMatch {
+    /// Walk every key of the index
+    fn full_scan(candidate: &IndexCandidate) -> Self {
+        Self {
+            scan: IndexScan {
+                index: candidate.name.clone(),
+                fields: candidate.fields.clone(),
+                ranges: vec![KeyRange {
+                    prefix: Vec::new(),
+                    lower: None,
+                    upper: None,
+                }],
+                reverse: false,
+                sorted: false,
+                multikey: candidate.multikey,
+                opaque_keys: !candidate.key_form.is_plain() || candidate.lossy_keys,
+            },
+            equality_fields: 0,
+            has_range: false,
+        }
+    }
+}
+
+/// Range bounds collected for a single field
+#[derive(Default)]
+struct FieldBounds {
+    lower: Option<KeyBound>,
+    upper: Option<KeyBound>,
+    /// `$elemMatch` each bound came from, numbered in filter order
+    lower_element: Option<usize>,
+    upper_element: Option<usize>,
+}
+
+impl FieldBounds {
+    /// Whether both bounds must hold for the same array element
+    fn same_element(&self) -> bool {
+        self.lower_element.is_some() && self.lower_element == self.upper_element
+    }
+}
+
+/// Index-usable predicates from the top-level conjunction of a filter
+#[derive(Default)]
+struct FieldPredicates {
+    /// Candidate values per field from `$eq` and `$in`
+    equalities: HashMap<String, Vec<Value>>,
+    /// Tightest range per field
+    ranges: HashMap<String, FieldBounds>,
+    /// Number of `$elemMatch` filters seen so far
+    elements: usize,
+}
+
+impl FieldPredicates {
+    fn from_filter(filter: &Filter) -> Self {
+        let mut predicates = Self::default();
+        predicates.collect(filter, "", None);
+        predicates
+    }
+
+    /// Collect predicates, resolving fields under `path`
+    ///
+    /// Inside an `$elemMatch`, `path` is its array field and `element`
+    /// identifies it.
+    fn collect(&mut self, filter: &Filter, path: &str, element: Option<usize>) {
+        let resolve = |field: &str| match (path, field) {
+            ("", field) => field.to_string(),
+            (path, "") => path.to_string(),
+            (path, field) => format!("{}.{}", path, field),
+        };
+
+        match filter {
+            Filter::And(filters) => {
+                for f in filters {
+                    self.collect(f, path, element);
                 }
-                IndexType::Text { .. } => false, // Text indexes don't help with sorting
             }
-        } else {
-            false
+            Filter::ElemMatch { field, filter } => {
+                let id = self.elements;
+                self.elements += 1;
+                self.collect(filter, &resolve(field), Some(id));
+            }
+            Filter::Eq { field, value } if IndexValue::from_value(value).is_ok() => {
+                // Equality is at least as selective as any $in on the same field
+                self.equalities.insert(resolve(field), vec![value.clone()]);
+            }
+            Filter::In { field, values }
+                if !values.is_empty()
+                    && !self.equalities.contains_key(&resolve(field))
+                    && values.iter().all(|v| IndexValue::from_value(v).is_ok()) =>
+            {
+                let mut distinct: Vec<Value> = Vec::new();
+                for value in values {
+                    if !distinct.iter().any(|d| index_order(d, value) == Ordering::Equal) {
+                        distinct.push(value.clone());
+                    }
+                }
+                distinct.sort_by(index_order);
+                self.equalities.insert(resolve(field), distinct);
+            }
+            // Every value must be present, so any one of them bounds the scan
+            Filter::All { field, values }
+                if !self.equalities.contains_key(&resolve(field))
+                    && values.first().is_some_and(|v| IndexValue::from_value(v).is_ok()) =>
+            {
+                self.equalities.insert(resolve(field), vec![values[0].clone()]);
+            }
+            Filter::Gt { field, value } => self.tighten_lower(&resolve(field), value, false, element),
+            Filter::Gte { field, value } => self.tighten_lower(&resolve(field), value, true, element),
+            Filter::Lt { field, value } => self.tighten_upper(&resolve(field), value, false, element),
+            Filter::Lte { field, value } => self.tighten_upper(&resolve(field), value, true, element),
+            _ => {}
         }
     }
+
+    fn tighten_lower(&mut self, field: &str, value: &Value, inclusive: bool, element: Option<usize>) {
+        if IndexValue::from_value(value).is_err() {
+            return;
+        }
+        let bounds = self.ranges.entry(field.to_string()).or_default();
+        let replace = match &bounds.lower {
+            None => true,
+            Some(current) => match index_order(value, &current.value) {
+                Ordering::Greater => true,
+                Ordering::Equal => current.inclusive && !inclusive,
+                Ordering::Less => false,
+            },
+        };
+        if replace {
+            bounds.lower = Some(KeyBound { value: value.clone(), inclusive });
+            bounds.lower_element = element;
+        }
+    }
+
+    fn tighten_upper(&mut self, field: &str, value: &Value, inclusive: bool, element: Option<usize>) {
+        if IndexValue::from_value(value).is_err() {
+            return;
+        }
+        let bounds = self.ranges.entry(field.to_string()).or_default();
+        let replace = match &bounds.upper {
+            None => true,
+            Some(current) => match index_order(value, &current.value) {
+                Ordering::Less => true,
+                Ordering::Equal => current.inclusive && !inclusive,
+                Ordering::Greater => false,
+            },
+        };
+        if replace {
+            bounds.upper = Some(KeyBound { value: value.clone(), inclusive });
+            bounds.upper_element = element;
+        }
+    }
+}
+
+/// Compare two values the way the B-tree index orders them
+fn index_order(a: &Value, b: &Value) -> Ordering {
+    match (IndexValue::from_value(a), IndexValue::from_value(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => Ordering::Equal,
+    }
+}
+
+/// Whether every document matching `query` also matches `partial`
+///
+/// Each conjunct of `partial` must follow from a single conjunct of
+/// `query`: the same predicate, an equality or `$in` whose values satisfy
+/// it, or a range at least as tight. Anything else is treated as not
+/// implied, so a partial index is only ever skipped, never misused.
+fn implies(query: &Filter, partial: &Filter) -> bool {
+    match (query, partial) {
+        (_, Filter::Empty) => true,
+        (_, Filter::And(partials)) => partials.iter().all(|partial| implies(query, partial)),
+        (Filter::And(conjuncts), _) => conjuncts.iter().any(|conjunct| implies(conjunct, partial)),
+        _ if query == partial => true,
+        (Filter::Eq { field, value }, _) => {
+            predicate_field(partial) == Some(field) && satisfies(value, partial)
+        }
+        (Filter::In { field, values }, _) => {
+            predicate_field(partial) == Some(field)
+                && !values.is_empty()
+                && values.iter().all(|value| satisfies(value, partial))
+        }
+        (
+            Filter::Gt { field, value } | Filter::Gte { field, value },
+            Filter::Gt { field: other, value: bound } | Filter::Gte { field: other, value: bound },
+        ) if field == other && comparable(value, bound) => match index_order(value, bound) {
+            Ordering::Greater => true,
+            Ordering::Equal => matches!(query, Filter::Gt { .. }) || matches!(partial, Filter::Gte { .. }),
+            Ordering::Less => false,
+        },
+        (
+            Filter::Lt { field, value } | Filter::Lte { field, value },
+            Filter::Lt { field: other, value: bound } | Filter::Lte { field: other, value: bound },
+        ) if field == other && comparable(value, bound) => match index_order(value, bound) {
+            Ordering::Less => true,
+            Ordering::Equal => matches!(query, Filter::Lt { .. }) || matches!(partial, Filter::Lte { .. }),
+            Ordering::Greater => false,
+        },
+        // Ranges only match values of their own type, which are never null
+        (
+            Filter::Gt { field, .. } | Filter::Gte { field, .. } | Filter::Lt { field, .. } | Filter::Lte { field, .. },
+            Filter::Exists { field: other, exists: true },
+        ) => field == other,
+        _ => false,
+    }
+}
+
+/// Field a single-field predicate tests
+fn predicate_field(filter: &Filter) -> Option<&String> {
+    match filter {
+        Filter::Eq { field, .. }
+        | Filter::Gt { field, .. }
+        | Filter::Gte { field, .. }
+        | Filter::Lt { field, .. }
+        | Filter::Lte { field, .. }
+        | Filter::In { field, .. }
+        | Filter::Exists { field, .. } => Some(field),
+        _ => None,
+    }
+}
+
+/// Whether a field equal to `value` satisfies a single-field predicate
+fn satisfies(value: &Value, predicate: &Filter) -> bool {
+    let in_range = |bound: &Value, wanted: &[Ordering]| {
+        comparable(value, bound) && wanted.contains(&index_order(value, bound))
+    };
+    match predicate {
+        Filter::Eq { value: other, .. } => value == other,
+        Filter::In { values, .. } => values.contains(value),
+        Filter::Gt { value: bound, .. } => in_range(bound, &[Ordering::Greater]),
+        Filter::Gte { value: bound, .. } => in_range(bound, &[Ordering::Greater, Ordering::Equal]),
+        Filter::Lt { value: bound, .. } => in_range(bound, &[Ordering::Less]),
+        Filter::Lte { value: bound, .. } => in_range(bound, &[Ordering::Less, Ordering::Equal]),
+        // Equality with null also matches a missing field
+        Filter::Exists { exists, .. } => *exists != matches!(value, Value::Null),
+        _ => false,
+    }
+}
+
+/// Whether two values are ordered against each other rather than by type
+fn comparable(a: &Value, b: &Value) -> bool {
+    match (IndexValue::from_value(a), IndexValue::from_value(b)) {
+        (Ok(a), Ok(b)) => a.same_type(&b),
+        _ => false,
+    }
 }
 
 impl Default for IndexSelector {
@@ -259,10 +641,22 @@ pub struct IndexCandidate {
     pub name: String,
     /// Index type
     index_type: IndexType,
+    /// Indexed fields in key order
+    fields: Vec<String>,
+    /// Per-field descending flags
+    descending: Vec<bool>,
     /// Whether the index is unique
     unique: bool,
     /// Whether the index is sparse
     sparse: bool,
+    /// Whether the index holds one entry per array element
+    multikey: bool,
+    /// Whether stored keys may differ from the values of documents under them
+    lossy_keys: bool,
+    /// Filter every indexed document matches, for a partial index
+    partial_filter: Option<Filter>,
+    /// How field values become stored keys
+    key_form: KeyForm,
     /// Selection score (higher is better)
     score: f64,
 }
@@ -270,28 +664,46 @@ pub struct IndexCandidate {
 impl IndexCandidate {
     /// Create from index definition
     fn from_definition(def: IndexDefinition) -> Self {
+        let fields = def.fields();
+        let descending = (0..fields.len()).map(|p| def.is_descending(p)).collect();
+        let key_form = KeyForm::new(def.is_hashed(), def.collation);
         let index_type = match def.index_type {
             crate::schema::IndexType::Single { field } => IndexType::Single { field },
             crate::schema::IndexType::Compound { fields } => IndexType::Compound { fields },
             crate::schema::IndexType::Text { field } => IndexType::Text { field },
+            crate::schema::IndexType::Hashed { field } => IndexType::Hashed { field },
+            crate::schema::IndexType::Vector { field, .. } => IndexType::Vector { field },
             crate::schema::IndexType::Geospatial { field } => IndexType::Single { field }, // Treat as single for now
         };
 
         Self {
             name: def.name,
             index_type,
+            fields,
+            descending,
             unique: def.unique,
             sparse: def.sparse,
+            multikey: false,
+            lossy_keys: false,
+            partial_filter: def.partial_filter,
+            key_form,
             score: 0.0,
         }
     }
 
+    /// Check whether the field at `position` is stored in descending order
+    fn is_descending(&self, position: usize) -> bool {
+        self.descending.get(position).copied().unwrap_or(false)
+    }
+
     /// Check if this index can be used for a field
     fn can_use_for_field(&self, field: &str) -> bool {
         match &self.index_type {
             IndexType::Single { field: index_field } => index_field == field,
             IndexType::Compound { fields } => fields.contains(&field.to_string()),
-            IndexType::Text { field: index_field } => index_field == field,
+            IndexType::Text { field: index_field }
+            | IndexType::Hashed { field: index_field }
+            | IndexType::Vector { field: index_field } => index_field == field,
         }
     }
 }
@@ -305,6 +717,10 @@ enum IndexType {
     Compound { fields: Vec<String> },
     /// Text index for full-text search
     Text { field: String },
+    /// Index on hashes of a field's values
+    Hashed { field: String },
+    /// Nearest-neighbour index, used only by `$vectorSearch`
+    Vector { field: String },
 }
 
 /// Index selection errors
@@ -393,4 +809,271 @@ mod tests {
         
         assert!(selected.is_some());
     }
+
+    fn order_indexes() -> Vec<IndexDefinition> {
+        vec![
+            IndexDefinition::single("status".to_string()),
+            IndexDefinition::compound(vec![
+                "status".to_string(),
+                "region".to_string(),
+                "total".to_string(),
+            ])
+            .with_directions(vec![1, 1, -1]),
+        ]
+    }
+
+    #[test]
+    fn test_equality_prefix_then_range() {
+        let selector = IndexSelector::with_indexes(order_indexes());
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::eq("status", "open"),
+            Filter::eq("region", "eu"),
+            Filter::gt("total", 10i32),
+            Filter::lte("total", 100i32),
+            Filter::lt("total", 500i32),
+        ]));
+
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert_eq!(scan.index, "idx_status_region_total");
+        assert_eq!(scan.ranges.len(), 1);
+
+        let range = &scan.ranges[0];
+        assert_eq!(range.prefix, vec![Value::from("open"), Value::from("eu")]);
+        assert_eq!(range.lower, Some(KeyBound { value: Value::Int32(10), inclusive: false }));
+        assert_eq!(range.upper, Some(KeyBound { value: Value::Int32(100), inclusive: true }));
+    }
+
+    #[test]
+    fn test_in_on_prefix_expands_ranges() {
+        let selector = IndexSelector::with_indexes(order_indexes());
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::in_values("status", vec![Value::from("open"), Value::from("held")]),
+            Filter::in_values("region", vec![Value::from("eu"), Value::from("us"), Value::from("eu")]),
+            Filter::gte("total", 5i32),
+        ]));
+
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert_eq!(scan.index, "idx_status_region_total");
+        let prefixes: Vec<Vec<Value>> = scan.ranges.iter().map(|r| r.prefix.clone()).collect();
+        assert_eq!(
+            prefixes,
+            vec![
+                vec![Value::from("held"), Value::from("eu")],
+                vec![Value::from("held"), Value::from("us")],
+                vec![Value::from("open"), Value::from("eu")],
+                vec![Value::from("open"), Value::from("us")],
+            ]
+        );
+        assert!(scan.ranges.iter().all(|r| r.lower.is_some() && r.upper.is_none()));
+    }
+
+    #[test]
+    fn test_gap_in_prefix_stops_matching() {
+        let selector = IndexSelector::with_indexes(order_indexes());
+
+        // No predicate on region, so total cannot bound the compound index
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::eq("status", "open"),
+            Filter::gt("total", 10i32),
+        ]));
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert_eq!(scan.ranges.len(), 1);
+        assert_eq!(scan.ranges[0].prefix, vec![Value::from("open")]);
+        assert!(scan.ranges[0].lower.is_none());
+
+        // A non-leading field alone cannot use either index
+        let query = Query::with_filter(Filter::gt("total", 10i32));
+        assert!(selector.select_scan(&query).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sort_direction_must_match_index() {
+        let selector = IndexSelector::with_indexes(order_indexes());
+        let candidate = &selector.available_indexes[1];
+
+        let matching = Sort::new().asc("status").asc("region").desc("total");
+        let reversed = Sort::new().desc("status").desc("region").asc("total");
+        let mixed = Sort::new().asc("status").asc("region").asc("total");
+
+        assert!(selector.can_help_with_sort(candidate, &matching));
+        assert!(selector.can_help_with_sort(candidate, &reversed));
+        assert!(!selector.can_help_with_sort(candidate, &mixed));
+    }
+
+    #[test]
+    fn test_sorted_scan_follows_index_direction() {
+        let selector = IndexSelector::with_indexes(order_indexes());
+
+        // Equality on the leading fields leaves total in stored (descending) order
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::eq("status", "open"),
+            Filter::eq("region", "eu"),
+        ]))
+        .sort(Sort::new().asc("total"));
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert_eq!(scan.index, "idx_status_region_total");
+        assert!(scan.sorted);
+        assert!(scan.reverse);
+
+        // $in on a prefix field splits the scan, so it cannot supply order
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::in_values("status", vec![Value::from("open"), Value::from("held")]),
+            Filter::eq("region", "eu"),
+        ]))
+        .sort(Sort::new().desc("total"));
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert!(!scan.sorted);
+    }
+
+    #[test]
+    fn test_multikey_bounds_follow_elements() {
+        let multikey = HashSet::from(["idx_scores".to_string(), "idx_items.qty".to_string()]);
+        let selector = IndexSelector::with_indexes(vec![
+            IndexDefinition::single("scores".to_string()),
+            IndexDefinition::single("items.qty".to_string()),
+            IndexDefinition::single("tags".to_string()),
+        ])
+        .with_multikey(&multikey);
+        let lower = Some(KeyBound { value: Value::Int32(75), inclusive: false });
+        let upper = Some(KeyBound { value: Value::Int32(85), inclusive: false });
+
+        // Separate predicates may hold for different elements, so only one bounds the scan
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::gt("scores", 75i32),
+            Filter::lt("scores", 85i32),
+        ]));
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert!(scan.multikey);
+        assert_eq!(scan.ranges, vec![KeyRange { prefix: Vec::new(), lower: lower.clone(), upper: None }]);
+
+        // Within $elemMatch both hold for one element
+        let query = Query::with_filter(Filter::elem_match(
+            "scores",
+            Filter::and(vec![Filter::gt("", 75i32), Filter::lt("", 85i32)]),
+        ));
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert_eq!(scan.ranges[0].lower, lower);
+        assert_eq!(scan.ranges[0].upper, upper);
+
+        let query = Query::with_filter(Filter::elem_match(
+            "items",
+            Filter::and(vec![Filter::gte("qty", 2i32), Filter::lte("qty", 4i32)]),
+        ));
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert_eq!(scan.index, "idx_items.qty");
+        assert!(scan.ranges[0].lower.is_some() && scan.ranges[0].upper.is_some());
+
+        // $all probes its first value; a single-key index keeps its sort order
+        let query = Query::with_filter(Filter::all("tags", vec![Value::from("red"), Value::from("sale")]))
+            .sort(Sort::new().asc("tags"));
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert_eq!(scan.ranges[0].prefix, vec![Value::from("red")]);
+        assert!(scan.sorted && !scan.multikey);
+
+        // A multikey index cannot supply sort order
+        let query = Query::new().sort(Sort::new().asc("scores")).limit(5);
+        assert!(selector.select_scan(&query).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_limit_walks_index_in_sort_order() {
+        let selector = IndexSelector::with_indexes(vec![
+            IndexDefinition::single("created_at".to_string()),
+            IndexDefinition::single("deleted_at".to_string()).sparse(),
+        ]);
+
+        let query = Query::new().sort(Sort::new().desc("created_at")).limit(50);
+        let scan = selector.select_scan(&query).unwrap().unwrap();
+        assert_eq!(scan.index, "idx_created_at");
+        assert!(scan.sorted && scan.reverse);
+        assert_eq!(scan.ranges, vec![KeyRange { prefix: Vec::new(), lower: None, upper: None }]);
+
+        // Without a limit the whole collection is read either way
+        let unlimited = Query::new().sort(Sort::new().desc("created_at"));
+        assert!(selector.select_scan(&unlimited).unwrap().is_none());
+
+        // A sparse index would drop documents lacking the field
+        let sparse = Query::new().sort(Sort::new().asc("deleted_at")).limit(10);
+        assert!(selector.select_scan(&sparse).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_partial_index_needs_implied_filter() {
+        let partial = Filter::And(vec![Filter::eq("status", "active"), Filter::gte("age", 18)]);
+        let selector = IndexSelector::with_indexes(vec![
+            IndexDefinition::single("name".to_string()).with_partial_filter(partial),
+        ]);
+        let usable = |filter: Filter| selector.select_scan(&Query::with_filter(filter)).unwrap().is_some();
+
+        assert!(usable(Filter::And(vec![
+            Filter::eq("name", "Ann"),
+            Filter::eq("status", "active"),
+            Filter::gte("age", 18),
+        ])));
+        // Tighter ranges and equalities inside the range still imply it
+        assert!(usable(Filter::And(vec![
+            Filter::eq("name", "Ann"),
+            Filter::In { field: "status".to_string(), values: vec![Value::from("active")] },
+            Filter::gt("age", 40),
+        ])));
+        assert!(usable(Filter::And(vec![
+            Filter::eq("name", "Ann"),
+            Filter::eq("status", "active"),
+            Filter::eq("age", 18),
+        ])));
+
+        // Documents outside the filter could match these queries
+        assert!(!usable(Filter::eq("name", "Ann")));
+        assert!(!usable(Filter::And(vec![
+            Filter::eq("name", "Ann"),
+            Filter::eq("status", "active"),
+            Filter::gt("age", 10),
+        ])));
+        assert!(!usable(Filter::And(vec![
+            Filter::eq("name", "Ann"),
+            Filter::In {
+                field: "status".to_string(),
+                values: vec![Value::from("active"), Value::from("idle")],
+            },
+            Filter::gte("age", 18),
+        ])));
+        assert!(!usable(Filter::And(vec![
+            Filter::eq("name", "Ann"),
+            Filter::eq("status", "active"),
+            Filter::gte("age", "18"),
+        ])));
+    }
+
+    #[test]
+    fn test_collated_and_hashed_indexes_match_their_queries() {
+        use crate::query::Collation;
+
+        let case_insensitive = Collation::new("en").with_strength(2);
+        let selector = IndexSelector::with_indexes(vec![
+            IndexDefinition::single("email".to_string()).with_collation(case_insensitive.clone()),
+            IndexDefinition::hashed("user".to_string()),
+        ]);
+
+        // A collated index serves only queries with its collation, keyed alike
+        let plain = Query::with_filter(Filter::eq("email", "Ann@Example.com"));
+        assert!(selector.select_scan(&plain).unwrap().is_none());
+        let collated = plain.clone().collation(case_insensitive.clone());
+        let scan = selector.select_scan(&collated).unwrap().unwrap();
+        assert_eq!(scan.index, "idx_email");
+        assert_eq!(scan.ranges[0].prefix, vec![Value::from("ann@example.com")]);
+        assert!(scan.opaque_keys);
+
+        // A hashed index looks up the hash of each equality value
+        let lookup = Query::with_filter(Filter::eq("user", "ann"));
+        let scan = selector.select_scan(&lookup).unwrap().unwrap();
+        let hash = IndexValue::String("ann".to_string()).hashed().to_value().unwrap();
+        assert_eq!(scan.ranges[0].prefix, vec![hash]);
+
+        // but cannot answer ranges or supply sort order
+        assert!(selector.select_scan(&Query::with_filter(Filter::gt("user", "a"))).unwrap().is_none());
+        let sorted = Query::new()
+            .sort(Sort::new().asc("user"))
+            .limit(5);
+        assert!(selector.select_scan(&sorted).unwrap().is_none());
+    }
 }
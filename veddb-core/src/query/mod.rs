@@ -7,9 +7,15 @@ pub mod parser;
 pub mod executor;
 pub mod planner;
 pub mod index_selector;
+pub mod explain;
+pub mod plan_cache;
+pub mod collation;
 
-pub use ast::{Query, Filter, Projection, Sort, SortOrder};
+pub use ast::{ExprOp, ExprOperand, Filter, Projection, Query, Sort, SortOrder, ValueType};
 pub use parser::QueryParser;
-pub use executor::QueryExecutor;
+pub use executor::{DocumentSource, QueryExecutor};
 pub use planner::QueryPlanner;
 pub use index_selector::IndexSelector;
+pub use explain::{ExecutionStats, ExplainVerbosity};
+pub use plan_cache::{PlanCache, QueryShape};
+pub use collation::Collation;
@@ -2,10 +2,11 @@
 //!
 //! Parses MongoDB-style query JSON into internal Query structures
 
-use super::ast::{Filter, Projection, ProjectionType, Query, Sort, SortOrder};
+use super::collation::Collation;
+use super::ast::{ExprOp, ExprOperand, Filter, Projection, ProjectionType, Query, Sort, SortOrder, ValueType};
+use crate::document::extended_json;
 use crate::document::Value;
 use serde_json::Value as JsonValue;
-use std::collections::BTreeMap;
 
 /// Query parser for JSON queries
 pub struct QueryParser;
@@ -60,9 +61,24 @@ impl QueryParser {
             );
         }
 
+        // Parse collation
+        if let Some(collation_value) = obj.get("collation") {
+            query.collation = Some(Self::parse_collation(collation_value)?);
+        }
+
         Ok(query)
     }
 
+    /// Parse a collation such as `{"locale": "en", "strength": 2}`
+    pub fn parse_collation(value: &JsonValue) -> Result<Collation, QueryParseError> {
+        let collation: Collation = serde_json::from_value(value.clone())
+            .map_err(|e| QueryParseError::InvalidFormat(format!("Invalid collation: {}", e)))?;
+        collation
+            .validate()
+            .map_err(|e| QueryParseError::ValidationError(e.to_string()))?;
+        Ok(collation)
+    }
+
     /// Parse a filter from JSON
     pub fn parse_filter(value: &JsonValue) -> Result<Filter, QueryParseError> {
         match value {
@@ -97,6 +113,15 @@ impl QueryParser {
                                 let sub_filter = Self::parse_filter(val)?;
                                 return Ok(Filter::Not(Box::new(sub_filter)));
                             }
+                            "$nor" => {
+                                let arr = val.as_array().ok_or_else(|| {
+                                    QueryParseError::InvalidFormat("$nor must be an array".to_string())
+                                })?;
+                                let sub_filters: Result<Vec<_>, _> =
+                                    arr.iter().map(Self::parse_filter).collect();
+                                filters.push(Filter::Nor(sub_filters?));
+                            }
+                            "$expr" => filters.push(Self::parse_expr(val)?),
                             _ => {
                                 return Err(QueryParseError::UnsupportedOperator(key.clone()));
                             }
@@ -129,6 +154,9 @@ impl QueryParser {
                 if obj.is_empty() {
                     return Ok(Filter::eq(field, Self::json_to_value(value)?));
                 }
+                if let Some(typed) = Self::typed_value(obj)? {
+                    return Ok(Filter::eq(field, typed));
+                }
 
                 let mut filters = Vec::new();
 
@@ -156,12 +184,63 @@ impl QueryParser {
                                 arr.iter().map(Self::json_to_value).collect();
                             Filter::nin(field, values?)
                         }
+                        "$all" => {
+                            let arr = val.as_array().ok_or_else(|| {
+                                QueryParseError::InvalidFormat("$all must be an array".to_string())
+                            })?;
+                            let values: Result<Vec<_>, _> =
+                                arr.iter().map(Self::json_to_value).collect();
+                            Filter::all(field, values?)
+                        }
+                        "$elemMatch" => {
+                            let conditions = val.as_object().ok_or_else(|| {
+                                QueryParseError::InvalidFormat("$elemMatch must be an object".to_string())
+                            })?;
+                            // Operators apply to the element itself, field names to its fields
+                            let inner = if !conditions.is_empty() && conditions.keys().all(|k| k.starts_with('$')) {
+                                Self::parse_field_condition("", val)?
+                            } else {
+                                Self::parse_filter(val)?
+                            };
+                            Filter::elem_match(field, inner)
+                        }
                         "$exists" => {
                             let exists = val.as_bool().ok_or_else(|| {
                                 QueryParseError::InvalidFormat("$exists must be a boolean".to_string())
                             })?;
                             Filter::exists(field, exists)
                         }
+                        "$size" => {
+                            let size = val.as_u64().ok_or_else(|| {
+                                QueryParseError::InvalidFormat("$size must be a non-negative integer".to_string())
+                            })?;
+                            Filter::size(field, size as usize)
+                        }
+                        "$type" => {
+                            let types = match val {
+                                JsonValue::Array(names) => {
+                                    names.iter().map(Self::parse_type).collect::<Result<Vec<_>, _>>()?
+                                }
+                                name => vec![Self::parse_type(name)?],
+                            };
+                            Filter::Type { field: field.to_string(), types }
+                        }
+                        "$mod" => {
+                            let (divisor, remainder) = match val.as_array().map(Vec::as_slice) {
+                                Some([divisor, remainder]) => (Self::parse_integer(divisor)?, Self::parse_integer(remainder)?),
+                                _ => {
+                                    return Err(QueryParseError::InvalidFormat(
+                                        "$mod must be an array of [divisor, remainder]".to_string(),
+                                    ))
+                                }
+                            };
+                            if divisor == 0 {
+                                return Err(QueryParseError::InvalidFormat("$mod divisor must not be 0".to_string()));
+                            }
+                            Filter::modulo(field, divisor, remainder)
+                        }
+                        "$bitsAllSet" => Filter::bits_all_set(field, Self::parse_bitmask(op, val)?),
+                        "$bitsAnySet" => Filter::bits_any_set(field, Self::parse_bitmask(op, val)?),
                         "$regex" => {
                             let pattern = val.as_str().ok_or_else(|| {
                                 QueryParseError::InvalidFormat("$regex must be a string".to_string())
@@ -192,6 +271,89 @@ impl QueryParser {
         }
     }
 
+    /// Parse a `$type` name such as `"string"` or a BSON type number
+    fn parse_type(value: &JsonValue) -> Result<ValueType, QueryParseError> {
+        let value_type = match value {
+            JsonValue::String(alias) => ValueType::from_alias(alias),
+            JsonValue::Number(code) => code.as_i64().and_then(ValueType::from_code),
+            _ => None,
+        };
+        value_type.ok_or_else(|| QueryParseError::InvalidFormat(format!("Unknown $type {}", value)))
+    }
+
+    /// Parse a whole number, truncating a fractional one toward zero
+    fn parse_integer(value: &JsonValue) -> Result<i64, QueryParseError> {
+        value
+            .as_i64()
+            .or_else(|| value.as_f64().filter(|f| f.is_finite()).map(|f| f.trunc() as i64))
+            .ok_or_else(|| QueryParseError::InvalidFormat(format!("Expected a number, found {}", value)))
+    }
+
+    /// Parse a bitmask given as a non-negative number or a list of bit positions
+    fn parse_bitmask(op: &str, value: &JsonValue) -> Result<u64, QueryParseError> {
+        let invalid = || {
+            QueryParseError::InvalidFormat(format!(
+                "{} must be a non-negative integer or an array of bit positions 0-63",
+                op
+            ))
+        };
+        match value {
+            JsonValue::Number(mask) => mask.as_u64().ok_or_else(invalid),
+            JsonValue::Array(positions) => positions.iter().try_fold(0u64, |mask, position| {
+                match position.as_u64() {
+                    Some(position) if position < 64 => Ok(mask | (1 << position)),
+                    _ => Err(invalid()),
+                }
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Parse an `$expr` comparison such as `{"$gt": ["$spent", "$budget"]}`
+    ///
+    /// `$and`, `$or` and `$not` combine comparisons. A string starting
+    /// with `$` names a field; anything else is a constant.
+    fn parse_expr(value: &JsonValue) -> Result<Filter, QueryParseError> {
+        let invalid = |reason: &str| QueryParseError::InvalidFormat(format!("Invalid $expr: {}", reason));
+        let (op, args) = match value.as_object() {
+            Some(obj) if obj.len() == 1 => obj.iter().next().ok_or_else(|| invalid("empty expression"))?,
+            _ => return Err(invalid("expected an object with one operator")),
+        };
+        let expressions = || {
+            args.as_array()
+                .ok_or_else(|| invalid(&format!("{} needs an array", op)))?
+                .iter()
+                .map(Self::parse_expr)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        match op.as_str() {
+            "$and" => return Ok(Filter::And(expressions()?)),
+            "$or" => return Ok(Filter::Or(expressions()?)),
+            "$not" => {
+                let inner = match args {
+                    JsonValue::Array(inner) if inner.len() == 1 => &inner[0],
+                    inner => inner,
+                };
+                return Ok(Filter::Not(Box::new(Self::parse_expr(inner)?)));
+            }
+            _ => {}
+        }
+
+        let compare = ExprOp::from_operator(op).ok_or_else(|| QueryParseError::UnsupportedOperator(op.clone()))?;
+        let operand = |value: &JsonValue| match value {
+            JsonValue::String(path) if path.starts_with('$') => Ok(ExprOperand::Field(path[1..].to_string())),
+            value => Self::json_to_value(value).map(ExprOperand::Literal),
+        };
+        match args.as_array().map(Vec::as_slice) {
+            Some([left, right]) => Ok(Filter::Expr {
+                op: compare,
+                left: operand(left)?,
+                right: operand(right)?,
+            }),
+            _ => Err(invalid(&format!("{} needs two operands", op))),
+        }
+    }
+
     /// Parse projection from JSON
     fn parse_projection(value: &JsonValue) -> Result<Projection, QueryParseError> {
         let obj = value.as_object().ok_or_else(|| {
@@ -280,39 +442,16 @@ impl QueryParser {
         Ok(sort)
     }
 
-    /// Convert JSON value to internal Value
-    fn json_to_value(json: &JsonValue) -> Result<Value, QueryParseError> {
-        match json {
-            JsonValue::Null => Ok(Value::Null),
-            JsonValue::Bool(b) => Ok(Value::Bool(*b)),
-            JsonValue::Number(n) => {
-                if let Some(i) = n.as_i64() {
-                    if i >= i32::MIN as i64 && i <= i32::MAX as i64 {
-                        Ok(Value::Int32(i as i32))
-                    } else {
-                        Ok(Value::Int64(i))
-                    }
-                } else if let Some(f) = n.as_f64() {
-                    Ok(Value::Float64(f))
-                } else {
-                    Err(QueryParseError::InvalidFormat(
-                        "Invalid number format".to_string(),
-                    ))
-                }
-            }
-            JsonValue::String(s) => Ok(Value::String(s.clone())),
-            JsonValue::Array(arr) => {
-                let values: Result<Vec<_>, _> = arr.iter().map(Self::json_to_value).collect();
-                Ok(Value::Array(values?))
-            }
-            JsonValue::Object(obj) => {
-                let mut map = BTreeMap::new();
-                for (k, v) in obj {
-                    map.insert(k.clone(), Self::json_to_value(v)?);
-                }
-                Ok(Value::Object(map))
-            }
-        }
+    /// Convert Extended JSON to internal Value
+    pub(crate) fn json_to_value(json: &JsonValue) -> Result<Value, QueryParseError> {
+        extended_json::from_extended_json(json).map_err(|e| QueryParseError::InvalidFormat(e.to_string()))
+    }
+
+    /// Value of an Extended JSON wrapper such as `{"$oid": "..."}`
+    ///
+    /// Returns `None` for objects that are not a single known wrapper.
+    pub(crate) fn typed_value(obj: &serde_json::Map<String, JsonValue>) -> Result<Option<Value>, QueryParseError> {
+        extended_json::typed_value(obj).map_err(|e| QueryParseError::InvalidFormat(e.to_string()))
     }
 
     /// Validate a query
@@ -456,6 +595,98 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_array_operators() {
+        let json = r#"{"filter": {"tags": {"$all": ["rust", "db"]}}}"#;
+        let query = QueryParser::parse(json).unwrap();
+        assert_eq!(query.filter, Filter::all("tags", vec![Value::from("rust"), Value::from("db")]));
+
+        let json = r#"{"filter": {"scores": {"$elemMatch": {"$gte": 80, "$lt": 90}}}}"#;
+        let query = QueryParser::parse(json).unwrap();
+        assert_eq!(
+            query.filter,
+            Filter::elem_match("scores", Filter::and(vec![Filter::gte("", 80i32), Filter::lt("", 90i32)]))
+        );
+
+        let json = r#"{"filter": {"items": {"$elemMatch": {"sku": "a1", "qty": {"$gt": 2}}}}}"#;
+        let query = QueryParser::parse(json).unwrap();
+        assert_eq!(
+            query.filter,
+            Filter::elem_match("items", Filter::and(vec![Filter::gt("qty", 2i32), Filter::eq("sku", "a1")]))
+        );
+
+        assert!(QueryParser::parse(r#"{"filter": {"tags": {"$all": "rust"}}}"#).is_err());
+        assert!(QueryParser::parse(r#"{"filter": {"items": {"$elemMatch": [1]}}}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_type_and_bitwise_operators() {
+        let json = r#"{"filter": {"tags": {"$size": 2}, "age": {"$type": ["int", 18]}, "n": {"$mod": [4, 1]}}}"#;
+        let query = QueryParser::parse(json).unwrap();
+        assert_eq!(
+            query.filter,
+            Filter::and(vec![
+                Filter::Type {
+                    field: "age".to_string(),
+                    types: vec![ValueType::Int, ValueType::Long],
+                },
+                Filter::modulo("n", 4, 1),
+                Filter::size("tags", 2),
+            ])
+        );
+
+        let json = r#"{"filter": {"flags": {"$bitsAllSet": [0, 3], "$bitsAnySet": 6}}}"#;
+        let query = QueryParser::parse(json).unwrap();
+        assert_eq!(
+            query.filter,
+            Filter::and(vec![Filter::bits_all_set("flags", 0b1001), Filter::bits_any_set("flags", 6)])
+        );
+
+        for invalid in [
+            r#"{"filter": {"tags": {"$size": -1}}}"#,
+            r#"{"filter": {"age": {"$type": "integer"}}}"#,
+            r#"{"filter": {"n": {"$mod": [0, 1]}}}"#,
+            r#"{"filter": {"n": {"$mod": [3]}}}"#,
+            r#"{"filter": {"flags": {"$bitsAllSet": [64]}}}"#,
+        ] {
+            assert!(matches!(QueryParser::parse(invalid), Err(QueryParseError::InvalidFormat(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_nor_and_expr() {
+        let json = r#"{"filter": {"$nor": [{"status": "closed"}, {"age": {"$lt": 18}}]}}"#;
+        let query = QueryParser::parse(json).unwrap();
+        assert_eq!(
+            query.filter,
+            Filter::nor(vec![Filter::eq("status", "closed"), Filter::lt("age", 18i32)])
+        );
+
+        let json = r#"{"filter": {"$expr": {"$and": [{"$gt": ["$spent", "$budget"]}, {"$ne": ["$owner", "ann"]}]}, "active": true}}"#;
+        let query = QueryParser::parse(json).unwrap();
+        assert_eq!(
+            query.filter,
+            Filter::and(vec![
+                Filter::and(vec![
+                    Filter::fields_compare(ExprOp::Gt, "spent", "budget"),
+                    Filter::Expr {
+                        op: ExprOp::Ne,
+                        left: ExprOperand::Field("owner".to_string()),
+                        right: ExprOperand::Literal(Value::from("ann")),
+                    },
+                ]),
+                Filter::eq("active", true),
+            ])
+        );
+
+        assert!(matches!(
+            QueryParser::parse(r#"{"filter": {"$expr": {"$add": ["$a", 1]}}}"#),
+            Err(QueryParseError::UnsupportedOperator(_))
+        ));
+        assert!(QueryParser::parse(r#"{"filter": {"$expr": {"$gt": ["$a"]}}}"#).is_err());
+        assert!(QueryParser::parse(r#"{"filter": {"$nor": {"a": 1}}}"#).is_err());
+    }
+
     #[test]
     fn test_parse_and_operator() {
         let json = r#"{"filter": {"$and": [{"name": "John"}, {"age": {"$gt": 18}}]}}"#;
@@ -546,6 +777,21 @@ mod tests {
         assert_eq!(query.limit, Some(20));
     }
 
+    #[test]
+    fn test_parse_collation() {
+        let json = r#"{"filter": {"email": "Ann@Example.com"}, "collation": {"locale": "en", "strength": 2, "numericOrdering": true}}"#;
+        let query = QueryParser::parse(json).unwrap();
+        assert_eq!(
+            query.collation,
+            Some(Collation::new("en").with_strength(2).with_numeric_ordering())
+        );
+
+        let json = r#"{"filter": {}, "collation": {"locale": "en", "strength": 5}}"#;
+        assert!(matches!(QueryParser::parse(json), Err(QueryParseError::ValidationError(_))));
+        let json = r#"{"filter": {}, "collation": {"strength": 2}}"#;
+        assert!(matches!(QueryParser::parse(json), Err(QueryParseError::InvalidFormat(_))));
+    }
+
     #[test]
     fn test_parse_complex_query() {
         let json = r#"{
@@ -583,6 +829,65 @@ mod tests {
         assert!(QueryParser::validate(&query).is_ok());
     }
 
+    #[test]
+    fn test_parse_type_wrappers() {
+        let value = QueryParser::json_to_value(&serde_json::json!({
+            "price": {"$numberDecimal": "19.90"},
+            "id": {"$uuid": "67e55044-10b1-426f-9247-bb680e5fe0c8"},
+            "at": {"$timestamp": {"t": 7, "i": 2}},
+            "plain": {"$numberDecimal": "1", "other": 2}
+        }))
+        .unwrap();
+        let fields = value.as_object().unwrap();
+        assert_eq!(fields["price"], Value::Decimal128("19.9".parse().unwrap()));
+        assert!(matches!(fields["id"], Value::Uuid(_)));
+        assert_eq!(fields["at"], Value::Timestamp(crate::document::Timestamp::new(7, 2)));
+        // Only a lone wrapper key makes a typed value
+        assert!(fields["plain"].is_object());
+
+        for bad in [
+            serde_json::json!({"$numberDecimal": 1.5}),
+            serde_json::json!({"$numberDecimal": "1.5.0"}),
+            serde_json::json!({"$uuid": "not-a-uuid"}),
+            serde_json::json!({"$timestamp": {"t": -1, "i": 0}}),
+        ] {
+            assert!(matches!(QueryParser::json_to_value(&bad), Err(QueryParseError::InvalidFormat(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_extended_json_filters() {
+        let json = r#"{"filter": {
+            "created": {"$gte": {"$date": "2024-01-02T03:04:05.123456789Z"}},
+            "owner": {"$oid": "65a1f0c2e4b0a1b2c3d4e5f6"},
+            "blob": {"$binary": {"base64": "AAE=", "subType": "00"}},
+            "views": {"$numberLong": "42"}
+        }}"#;
+        let query = QueryParser::parse(json).unwrap();
+        let Filter::And(filters) = query.filter else {
+            panic!("expected an implicit and");
+        };
+        let value_of = |name: &str| {
+            filters
+                .iter()
+                .find_map(|f| match f {
+                    Filter::Eq { field, value } | Filter::Gte { field, value } if field == name => Some(value.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let Value::DateTime(created) = value_of("created") else {
+            panic!("expected a date");
+        };
+        assert_eq!(created.timestamp_subsec_nanos(), 123_456_789);
+        assert!(matches!(value_of("owner"), Value::ObjectId(oid) if oid.to_string() == "65a1f0c2e4b0a1b2c3d4e5f6"));
+        assert_eq!(value_of("blob"), Value::Binary(vec![0, 1]));
+        assert_eq!(value_of("views"), Value::Int64(42));
+
+        let bad = r#"{"filter": {"owner": {"$oid": "65a1"}}}"#;
+        assert!(matches!(QueryParser::parse(bad), Err(QueryParseError::InvalidFormat(_))));
+    }
+
     #[test]
     fn test_implicit_and() {
         let json = r#"{"filter": {"name": "John", "age": 30}}"#;
@@ -0,0 +1,484 @@
+//! Query plan cache
+//!
+//! Remembers which access path won for each query shape, so repeated
+//! queries skip costing. A shape is a query with its literal values
+//! removed; on a hit the cached indexes are re-scanned with the new
+//! values. Entries are dropped when the collection's indexes or their key
+//! distributions change, when its document count drifts too far from the
+//! one the plan was costed against, or after several executions in a row
+//! that do far more work than the first one did.
+
+use super::ast::{ExprOperand, Filter, ProjectionType, Query, SortOrder};
+use super::collation;
+use super::planner::QueryPlan;
+use crate::document::Value;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Query with its literal values removed
+///
+/// Built from the filter operators and fields, the sort, the projection,
+/// the collation and whether a limit or skip is set, since together they
+/// let sorted full index scans compete. The number of `$in` values is
+/// kept as each one is a separate index range. Operands of `$and` and
+/// `$or` are ordered, so clause order does not matter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct QueryShape(String);
+
+impl QueryShape {
+    /// Shape of a query
+    pub fn of(query: &Query) -> Self {
+        let mut shape = format!("filter={}", Self::filter_shape(&query.filter));
+
+        if let Some(sort) = &query.sort {
+            let fields: Vec<String> = sort
+                .fields
+                .iter()
+                .map(|(field, order)| match order {
+                    SortOrder::Ascending => format!("{}:1", field),
+                    SortOrder::Descending => format!("{}:-1", field),
+                })
+                .collect();
+            shape.push_str(&format!("|sort={}", fields.join(",")));
+        }
+        if let Some(projection) = &query.projection {
+            let fields: Vec<String> = projection
+                .fields
+                .iter()
+                .map(|(field, kind)| match kind {
+                    ProjectionType::Include => format!("{}:1", field),
+                    ProjectionType::Exclude => format!("{}:0", field),
+                })
+                .collect();
+            shape.push_str(&format!("|projection={}", fields.join(",")));
+        }
+        // Only indexes of the query's collation are candidates
+        if let Some(collation) = collation::effective(query.collation.as_ref()) {
+            shape.push_str(&format!(
+                "|collation={}:{}:{}",
+                collation.locale, collation.strength, collation.numeric_ordering
+            ));
+        }
+        if query.limit.is_some() {
+            shape.push_str("|limit");
+        }
+        if query.skip.is_some() {
+            shape.push_str("|skip");
+        }
+
+        Self(shape)
+    }
+
+    /// Shape as text
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn filter_shape(filter: &Filter) -> String {
+        let operands = |name: &str, filters: &[Filter]| {
+            let mut shapes: Vec<String> = filters.iter().map(Self::filter_shape).collect();
+            shapes.sort();
+            format!("{}({})", name, shapes.join(","))
+        };
+
+        match filter {
+            Filter::Empty => "{}".to_string(),
+            Filter::Eq { field, .. } => format!("eq({})", field),
+            Filter::Ne { field, .. } => format!("ne({})", field),
+            Filter::Gt { field, .. } => format!("gt({})", field),
+            Filter::Gte { field, .. } => format!("gte({})", field),
+            Filter::Lt { field, .. } => format!("lt({})", field),
+            Filter::Lte { field, .. } => format!("lte({})", field),
+            Filter::In { field, values } => format!("in({},{})", field, values.len()),
+            Filter::Nin { field, .. } => format!("nin({})", field),
+            // Matching present and missing fields are different predicates
+            Filter::Exists { field, exists } => format!("exists({},{})", field, exists),
+            Filter::Regex { field, .. } => format!("regex({})", field),
+            Filter::All { field, .. } => format!("all({})", field),
+            Filter::ElemMatch { field, filter } => {
+                format!("elem_match({},{})", field, Self::filter_shape(filter))
+            }
+            Filter::Size { field, .. } => format!("size({})", field),
+            Filter::Type { field, .. } => format!("type({})", field),
+            Filter::Mod { field, .. } => format!("mod({})", field),
+            Filter::BitsAllSet { field, .. } => format!("bits_all_set({})", field),
+            Filter::BitsAnySet { field, .. } => format!("bits_any_set({})", field),
+            Filter::Expr { op, left, right } => {
+                let operand = |operand: &ExprOperand| match operand {
+                    ExprOperand::Field(field) => format!("${}", field),
+                    ExprOperand::Literal(_) => "?".to_string(),
+                };
+                format!("expr({:?},{},{})", op, operand(left), operand(right))
+            }
+            Filter::And(filters) => operands("and", filters),
+            Filter::Or(filters) => operands("or", filters),
+            Filter::Nor(filters) => operands("nor", filters),
+            Filter::Not(filter) => format!("not({})", Self::filter_shape(filter)),
+        }
+    }
+}
+
+impl fmt::Display for QueryShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Access path remembered for a query shape
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedSolution {
+    /// Driving index first, then the indexes intersected with it; empty
+    /// for a collection scan
+    pub indexes: Vec<String>,
+    /// Cost the planner estimated when the plan was chosen
+    pub estimated_cost: f64,
+}
+
+impl CachedSolution {
+    /// Remember the access path of a plan
+    pub fn from_plan(plan: &QueryPlan) -> Self {
+        Self {
+            indexes: plan
+                .index_scan
+                .iter()
+                .chain(&plan.intersect)
+                .map(|scan| scan.index.clone())
+                .collect(),
+            estimated_cost: plan.estimated_cost,
+        }
+    }
+}
+
+/// Cached plan for one query shape
+#[derive(Debug, Clone)]
+pub struct PlanCacheEntry {
+    /// Query shape
+    pub shape: QueryShape,
+    /// Chosen access path
+    pub solution: CachedSolution,
+    /// Index catalog version the plan was chosen under
+    pub catalog_version: u64,
+    /// Document count the plan was costed against
+    pub documents: u64,
+    /// Executions recorded against the entry
+    pub executions: u64,
+    /// Keys plus documents examined by the first execution
+    pub baseline_works: Option<u64>,
+    /// Consecutive executions that regressed from the baseline
+    pub regressions: u32,
+    /// Logical time of the last lookup, for eviction order
+    last_used: u64,
+}
+
+impl PlanCacheEntry {
+    /// Describe the entry as a document value
+    pub fn to_value(&self) -> Value {
+        let strategy = match self.solution.indexes.len() {
+            0 => "collection_scan",
+            1 => "index_scan",
+            _ => "index_intersection",
+        };
+
+        Value::Object(BTreeMap::from([
+            ("shape".to_string(), Value::String(self.shape.to_string())),
+            ("strategy".to_string(), Value::String(strategy.to_string())),
+            (
+                "indexes".to_string(),
+                Value::Array(self.solution.indexes.iter().cloned().map(Value::String).collect()),
+            ),
+            ("estimated_cost".to_string(), Value::Float64(self.solution.estimated_cost)),
+            ("documents".to_string(), Value::Int64(self.documents as i64)),
+            ("executions".to_string(), Value::Int64(self.executions as i64)),
+            (
+                "baseline_works".to_string(),
+                self.baseline_works.map(|works| Value::Int64(works as i64)).unwrap_or(Value::Null),
+            ),
+            ("regressions".to_string(), Value::Int32(self.regressions as i32)),
+        ]))
+    }
+}
+
+/// Plan cache limits
+#[derive(Debug, Clone)]
+pub struct PlanCacheConfig {
+    /// Most shapes kept; the least recently used is evicted first
+    pub capacity: usize,
+    /// Largest ratio between the current and the costed document count
+    pub drift_ratio: f64,
+    /// Work over the baseline, as a multiple, that counts as a regression
+    pub regression_factor: f64,
+    /// Consecutive regressions that evict an entry
+    pub regression_limit: u32,
+}
+
+impl Default for PlanCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 5000,
+            drift_ratio: 2.0,
+            regression_factor: 10.0,
+            regression_limit: 3,
+        }
+    }
+}
+
+/// Per-collection cache of chosen query plans
+pub struct PlanCache {
+    config: PlanCacheConfig,
+    entries: Mutex<HashMap<QueryShape, PlanCacheEntry>>,
+    clock: AtomicU64,
+}
+
+impl PlanCache {
+    /// Create a cache with default limits
+    pub fn new() -> Self {
+        Self::with_config(PlanCacheConfig::default())
+    }
+
+    /// Create a cache with the given limits
+    pub fn with_config(config: PlanCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    /// Find the access path cached for a shape
+    ///
+    /// An entry chosen under another index catalog version, or against a
+    /// document count that has drifted past the configured ratio, is
+    /// evicted and `None` returned.
+    pub fn lookup(&self, shape: &QueryShape, catalog_version: u64, documents: u64) -> Option<CachedSolution> {
+        let mut entries = self.entries.lock();
+        let entry = entries.get_mut(shape)?;
+
+        let (low, high) = if entry.documents <= documents {
+            (entry.documents, documents)
+        } else {
+            (documents, entry.documents)
+        };
+        let drifted = high as f64 > low.max(1) as f64 * self.config.drift_ratio;
+        if entry.catalog_version != catalog_version || drifted {
+            entries.remove(shape);
+            return None;
+        }
+
+        entry.last_used = self.tick();
+        Some(entry.solution.clone())
+    }
+
+    /// Cache the access path chosen for a shape
+    pub fn insert(&self, shape: QueryShape, solution: CachedSolution, catalog_version: u64, documents: u64) {
+        if self.config.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock();
+        if !entries.contains_key(&shape) && entries.len() >= self.config.capacity {
+            let oldest = entries
+                .values()
+                .min_by_key(|entry| entry.last_used)
+                .map(|entry| entry.shape.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            shape.clone(),
+            PlanCacheEntry {
+                shape,
+                solution,
+                catalog_version,
+                documents,
+                executions: 0,
+                baseline_works: None,
+                regressions: 0,
+                last_used: self.tick(),
+            },
+        );
+    }
+
+    /// Record the work one execution of a cached shape did
+    ///
+    /// Work is keys plus documents examined. The first execution sets the
+    /// baseline; the entry is evicted once enough executions in a row
+    /// exceed it by the regression factor. Returns whether it was evicted.
+    pub fn record_execution(&self, shape: &QueryShape, works: u64) -> bool {
+        let mut entries = self.entries.lock();
+        let Some(entry) = entries.get_mut(shape) else {
+            return false;
+        };
+
+        entry.executions += 1;
+        let Some(baseline) = entry.baseline_works else {
+            entry.baseline_works = Some(works);
+            return false;
+        };
+        if works as f64 > baseline.max(1) as f64 * self.config.regression_factor {
+            entry.regressions += 1;
+        } else {
+            entry.regressions = 0;
+        }
+
+        if entry.regressions >= self.config.regression_limit {
+            entries.remove(shape);
+            return true;
+        }
+        false
+    }
+
+    /// Cached entries ordered by shape
+    pub fn entries(&self) -> Vec<PlanCacheEntry> {
+        let mut entries: Vec<PlanCacheEntry> = self.entries.lock().values().cloned().collect();
+        entries.sort_by(|a, b| a.shape.cmp(&b.shape));
+        entries
+    }
+
+    /// Remove the entry for one shape, returning whether it existed
+    pub fn remove(&self, shape: &QueryShape) -> bool {
+        self.entries.lock().remove(shape).is_some()
+    }
+
+    /// Remove every entry, returning how many there were
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock();
+        let cleared = entries.len();
+        entries.clear();
+        cleared
+    }
+
+    /// Number of cached shapes
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    /// Check whether the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Default for PlanCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::ast::{Projection, Sort};
+
+    fn solution(indexes: &[&str]) -> CachedSolution {
+        CachedSolution {
+            indexes: indexes.iter().map(|index| index.to_string()).collect(),
+            estimated_cost: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_shape_strips_literals() {
+        let shape = |age: i32, city: &str| {
+            QueryShape::of(
+                &Query::with_filter(Filter::and(vec![Filter::gt("age", age), Filter::eq("city", city)]))
+                    .sort(Sort::new().desc("age"))
+                    .limit(10),
+            )
+        };
+        assert_eq!(shape(30, "Oslo"), shape(65, "Bergen"));
+        assert_eq!(shape(30, "Oslo").as_str(), "filter=and(eq(city),gt(age))|sort=age:-1|limit");
+
+        // Clause order does not matter, operators and sort direction do
+        let reordered = Query::with_filter(Filter::and(vec![Filter::eq("city", "x"), Filter::gt("age", 1i32)]))
+            .sort(Sort::new().desc("age"))
+            .limit(1);
+        assert_eq!(QueryShape::of(&reordered), shape(30, "Oslo"));
+        let ascending = Query::with_filter(Filter::and(vec![Filter::gt("age", 1i32), Filter::eq("city", "x")]))
+            .sort(Sort::new().asc("age"))
+            .limit(1);
+        assert_ne!(QueryShape::of(&ascending), shape(30, "Oslo"));
+
+        let projected = Query::with_filter(Filter::eq("city", "x")).projection(Projection::new().include("age"));
+        assert_eq!(QueryShape::of(&projected).as_str(), "filter=eq(city)|projection=age:1");
+    }
+
+    #[test]
+    fn test_shape_keeps_skip_and_in_cardinality() {
+        let listed = |cities: &[&str]| {
+            QueryShape::of(&Query::with_filter(Filter::in_values(
+                "city",
+                cities.iter().map(|city| Value::from(*city)).collect(),
+            )))
+        };
+        assert_eq!(listed(&["Oslo", "Bergen"]), listed(&["Tromsø", "Bodø"]));
+        assert_ne!(listed(&["Oslo"]), listed(&["Oslo", "Bergen", "Bodø"]));
+        assert_eq!(listed(&["Oslo", "Bergen"]).as_str(), "filter=in(city,2)");
+
+        let page = Query::with_filter(Filter::eq("city", "x")).sort(Sort::new().asc("age")).limit(10);
+        let skipped = page.clone().skip(100);
+        assert_ne!(QueryShape::of(&page), QueryShape::of(&skipped));
+        assert_eq!(QueryShape::of(&skipped).as_str(), "filter=eq(city)|sort=age:1|limit|skip");
+    }
+
+    #[test]
+    fn test_lookup_evicts_on_catalog_change_and_drift() {
+        let cache = PlanCache::new();
+        let shape = QueryShape::of(&Query::with_filter(Filter::eq("status", "open")));
+
+        cache.insert(shape.clone(), solution(&["idx_status"]), 1, 1000);
+        assert_eq!(cache.lookup(&shape, 1, 1500), Some(solution(&["idx_status"])));
+        assert_eq!(cache.lookup(&shape, 2, 1000), None);
+        assert!(cache.is_empty());
+
+        cache.insert(shape.clone(), solution(&["idx_status"]), 1, 1000);
+        assert_eq!(cache.lookup(&shape, 1, 2001), None);
+        cache.insert(shape.clone(), solution(&["idx_status"]), 1, 1000);
+        assert_eq!(cache.lookup(&shape, 1, 400), None);
+    }
+
+    #[test]
+    fn test_repeated_regressions_evict() {
+        let cache = PlanCache::new();
+        let shape = QueryShape::of(&Query::with_filter(Filter::eq("status", "open")));
+        cache.insert(shape.clone(), solution(&["idx_status"]), 1, 1000);
+
+        assert!(!cache.record_execution(&shape, 20));
+        assert!(!cache.record_execution(&shape, 500));
+        assert!(!cache.record_execution(&shape, 500));
+        // A normal execution resets the run
+        assert!(!cache.record_execution(&shape, 30));
+        assert_eq!(cache.entries()[0].regressions, 0);
+
+        assert!(!cache.record_execution(&shape, 500));
+        assert!(!cache.record_execution(&shape, 500));
+        assert!(cache.record_execution(&shape, 500));
+        assert!(cache.lookup(&shape, 1, 1000).is_none());
+        assert!(!cache.record_execution(&shape, 500));
+    }
+
+    #[test]
+    fn test_capacity_evicts_least_recently_used() {
+        let cache = PlanCache::with_config(PlanCacheConfig {
+            capacity: 2,
+            ..PlanCacheConfig::default()
+        });
+        let shape = |field: &str| QueryShape::of(&Query::with_filter(Filter::eq(field, 1i32)));
+
+        cache.insert(shape("a"), solution(&["idx_a"]), 1, 10);
+        cache.insert(shape("b"), solution(&["idx_b"]), 1, 10);
+        assert!(cache.lookup(&shape("a"), 1, 10).is_some());
+        cache.insert(shape("c"), solution(&[]), 1, 10);
+
+        let shapes: Vec<_> = cache.entries().into_iter().map(|entry| entry.shape).collect();
+        assert_eq!(shapes, vec![shape("a"), shape("c")]);
+        assert_eq!(cache.clear(), 2);
+    }
+}
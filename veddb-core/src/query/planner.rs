@@ -1,13 +1,40 @@
 //! Query planner for optimizing query execution
 //!
-//! Creates execution plans with cost estimation and index selection
+//! Creates execution plans with cost estimation and index selection. With
+//! index statistics the planner prices every candidate index scan, pairwise
+//! index intersections and a collection scan, and picks the cheapest;
+//! without them it falls back to the index selector's heuristic scores.
 
-use super::ast::{Filter, Query};
+use super::ast::{Filter, ProjectionType, Query, SortOrder};
 use super::index_selector::IndexSelector;
+use super::plan_cache::CachedSolution;
+use crate::document::Value;
+use crate::index::btree::IndexValue;
+use crate::index::statistics::{CollectionStatistics, KeyDistribution};
+use crate::schema::IndexDefinition;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Cost of positioning the index on one key range
+const INDEX_SEEK_COST: f64 = 1.0;
+/// Cost of reading one index entry
+const INDEX_KEY_COST: f64 = 0.1;
+/// Cost of loading one document by id
+const DOCUMENT_FETCH_COST: f64 = 1.5;
+/// Cost of reading one document during a collection scan
+const DOCUMENT_SCAN_COST: f64 = 1.0;
+/// Cost of evaluating one unit of filter complexity against a document
+const FILTER_COST: f64 = 0.01;
+/// Cost of sorting, per `n * log2(n)` documents
+const SORT_COST: f64 = 0.02;
+/// Collections smaller than this use an applicable index even when a
+/// collection scan is priced lower, as the estimates are too coarse there
+const MIN_COSTED_DOCUMENTS: u64 = 100;
 
 /// Query planner for creating optimized execution plans
 pub struct QueryPlanner {
     index_selector: IndexSelector,
+    statistics: Option<CollectionStatistics>,
 }
 
 impl QueryPlanner {
@@ -15,107 +42,310 @@ impl QueryPlanner {
     pub fn new() -> Self {
         Self {
             index_selector: IndexSelector::new(),
+            statistics: None,
+        }
+    }
+
+    /// Create a query planner that can choose among the given indexes
+    pub fn with_indexes(indexes: Vec<IndexDefinition>) -> Self {
+        Self {
+            index_selector: IndexSelector::with_indexes(indexes),
+            statistics: None,
         }
     }
 
+    /// Flag the named indexes as holding one entry per array element
+    pub fn with_multikey(mut self, indexes: &HashSet<String>) -> Self {
+        self.index_selector = self.index_selector.with_multikey(indexes);
+        self
+    }
+
+    /// Flag the named indexes as storing keys that cannot cover queries
+    pub fn with_lossy_keys(mut self, indexes: &HashSet<String>) -> Self {
+        self.index_selector = self.index_selector.with_lossy_keys(indexes);
+        self
+    }
+
+    /// Choose plans by estimated cost using these index statistics
+    pub fn with_statistics(mut self, statistics: CollectionStatistics) -> Self {
+        self.statistics = Some(statistics);
+        self
+    }
+
     /// Create an execution plan for a query
+    ///
+    /// The chosen plan lists the access paths it was preferred over in
+    /// `rejected`, cheapest first. On collections below
+    /// `MIN_COSTED_DOCUMENTS` an index is chosen whenever one applies, and
+    /// the collection scan is listed last.
     pub fn create_plan(&self, query: &Query) -> Result<QueryPlan, QueryPlanError> {
-        let mut plan = QueryPlan::new();
+        let candidates = self
+            .index_selector
+            .candidate_scans(query)
+            .map_err(|e| QueryPlanError::PlanningError(e.to_string()))?;
+
+        let mut plans = Vec::new();
+        match &self.statistics {
+            Some(statistics) if !statistics.indexes.is_empty() => {
+                for access in self.price_access_paths(query, candidates, statistics)? {
+                    let mut plan = Self::build_plan(query, access.scans);
+                    plan.estimated_rows = Some(access.rows);
+                    plan.estimated_cost = access.cost;
+                    plans.push(plan);
+                }
+                // Stable, so ties keep the collection scan and then selector order
+                plans.sort_by(|a, b| a.estimated_cost.total_cmp(&b.estimated_cost));
+                if statistics.documents < MIN_COSTED_DOCUMENTS && plans.len() > 1 {
+                    plans.sort_by_key(|plan| plan.use_index.is_none());
+                }
+            }
+            _ => {
+                // Without statistics the selector's ranking decides
+                let scans = candidates.into_iter().map(|scan| vec![scan]).chain([Vec::new()]);
+                for scans in scans {
+                    let mut plan = Self::build_plan(query, scans);
+                    plan.estimated_cost = self.estimate_cost(query, &plan)?;
+                    plans.push(plan);
+                }
+            }
+        }
+
+        let mut plans = plans.into_iter();
+        let mut plan = plans
+            .next()
+            .ok_or_else(|| QueryPlanError::PlanningError("no access path for query".to_string()))?;
+        plan.rejected = plans.map(|rejected| rejected.candidate()).collect();
+        Ok(plan)
+    }
+
+    /// Rebuild a cached access path for this query's values
+    ///
+    /// Returns `None` when one of the cached indexes can no longer serve
+    /// the query, so it must be planned afresh.
+    pub fn plan_for_solution(
+        &self,
+        query: &Query,
+        solution: &CachedSolution,
+    ) -> Result<Option<QueryPlan>, QueryPlanError> {
+        let candidates = self
+            .index_selector
+            .candidate_scans(query)
+            .map_err(|e| QueryPlanError::PlanningError(e.to_string()))?;
 
-        // Analyze filter for index usage
-        let index_candidate = self.analyze_filter_for_index(&query.filter)?;
-        plan.use_index = index_candidate;
+        let mut scans = Vec::with_capacity(solution.indexes.len());
+        for index in &solution.indexes {
+            match candidates.iter().find(|scan| &scan.index == index) {
+                Some(scan) => scans.push(scan.clone()),
+                None => return Ok(None),
+            }
+        }
+
+        let mut plan = Self::build_plan(query, scans);
+        plan.estimated_cost = solution.estimated_cost;
+        Ok(Some(plan))
+    }
 
-        // Estimate costs
-        plan.estimated_cost = self.estimate_cost(query, &plan)?;
+    /// Build the plan that reads the collection through these index scans
+    ///
+    /// The first scan drives the walk and the rest are intersected with
+    /// it; no scans means a collection scan.
+    fn build_plan(query: &Query, scans: Vec<IndexScan>) -> QueryPlan {
+        let mut plan = QueryPlan::new();
+        let mut scans = scans.into_iter();
+        plan.index_scan = scans.next();
+        plan.intersect = scans.collect();
+        plan.use_index = plan.index_scan.as_ref().map(|scan| scan.index.clone());
 
         // Determine execution strategy
-        plan.execution_strategy = if plan.use_index.is_some() {
+        plan.execution_strategy = if !plan.intersect.is_empty() {
+            ExecutionStrategy::IndexIntersection
+        } else if plan.use_index.is_some() {
             ExecutionStrategy::IndexScan
         } else {
             ExecutionStrategy::CollectionScan
         };
 
+        plan.covered = plan.intersect.is_empty()
+            && plan
+                .index_scan
+                .as_ref()
+                .map(|scan| Self::is_covered(query, scan))
+                .unwrap_or(false);
+
         // Plan post-processing steps
-        plan.needs_sort = query.sort.is_some();
+        let sorted_by_index = plan.index_scan.as_ref().map(|scan| scan.sorted).unwrap_or(false);
+        plan.needs_sort = query.sort.is_some() && !sorted_by_index;
         plan.needs_projection = query.projection.is_some();
         plan.has_skip = query.skip.is_some() && query.skip.unwrap() > 0;
         plan.has_limit = query.limit.is_some();
 
-        Ok(plan)
+        plan
     }
 
-    /// Analyze filter to determine if an index can be used
-    fn analyze_filter_for_index(&self, filter: &Filter) -> Result<Option<String>, QueryPlanError> {
-        match filter {
-            Filter::Empty => Ok(None),
-            
-            // Single field filters that can use indexes
-            Filter::Eq { field, .. } |
-            Filter::Gt { field, .. } |
-            Filter::Gte { field, .. } |
-            Filter::Lt { field, .. } |
-            Filter::Lte { field, .. } |
-            Filter::In { field, .. } => {
-                // Check if an actual index exists for this field
-                // IndexSelector will verify index existence
-                let mut candidates = Vec::new();
-                self.index_selector.find_indexes_for_field(field, &mut candidates);
-                
-                if let Some(candidate) = candidates.first() {
-                    Ok(Some(candidate.name.clone()))
-                } else {
-                    // No index found - will use collection scan
-                    Ok(None)
-                }
+    /// Price each way of reading the collection
+    ///
+    /// Access paths are a collection scan, every index scan, and the
+    /// intersection of each pair of index scans that bound different
+    /// fields, in that order. Predicates on different indexes are assumed
+    /// independent.
+    fn price_access_paths(
+        &self,
+        query: &Query,
+        candidates: Vec<IndexScan>,
+        statistics: &CollectionStatistics,
+    ) -> Result<Vec<AccessPlan>, QueryPlanError> {
+        let documents = statistics.documents as f64;
+        let per_document = self.estimate_filter_cost(&query.filter) * FILTER_COST;
+        let wanted = query
+            .limit
+            .map(|limit| limit.saturating_add(query.skip.unwrap_or(0)) as f64);
+        let needs_sort = query.sort.is_some();
+        let sort_cost = |rows: f64| if rows > 1.0 { rows * rows.log2() * SORT_COST } else { 0.0 };
+
+        let mut priced = Vec::new();
+        for scan in candidates {
+            if let Some(distribution) = statistics.indexes.get(&scan.index) {
+                let rows = Self::estimate_scan_rows(&scan, distribution)?;
+                priced.push((scan, rows));
             }
-            
-            // Range queries can use indexes
-            Filter::And(filters) => {
-                // Look for range queries on the same field
-                let mut field_filters: std::collections::HashMap<String, Vec<&Filter>> = 
-                    std::collections::HashMap::new();
-                
-                for f in filters {
-                    if let Some(field) = self.get_filter_field(f) {
-                        field_filters.entry(field).or_insert_with(Vec::new).push(f);
-                    }
+        }
+
+        // The most selective index bounds the size of the result
+        let matching = priced.iter().map(|(_, rows)| *rows).fold(documents, f64::min);
+        let mut paths = vec![AccessPlan {
+            scans: Vec::new(),
+            rows: documents,
+            cost: documents * (DOCUMENT_SCAN_COST + per_document)
+                + if needs_sort { sort_cost(matching) } else { 0.0 },
+        }];
+
+        for (scan, rows) in &priced {
+            // An unsorted walk, or one in sort order, stops once the limit is met
+            let visited = match wanted {
+                Some(wanted) if !needs_sort || scan.sorted => rows.min(wanted),
+                _ => *rows,
+            };
+            let fetch = if Self::is_covered(query, scan) { 0.0 } else { DOCUMENT_FETCH_COST };
+            let mut cost = scan.ranges.len() as f64 * INDEX_SEEK_COST
+                + visited * (INDEX_KEY_COST + fetch + per_document);
+            if needs_sort && !scan.sorted {
+                cost += sort_cost(visited);
+            }
+            paths.push(AccessPlan { scans: vec![scan.clone()], rows: *rows, cost });
+        }
+
+        for (position, (a, rows_a)) in priced.iter().enumerate() {
+            for (b, rows_b) in &priced[position + 1..] {
+                let (bound_a, bound_b) = (a.bound_fields(), b.bound_fields());
+                if bound_a.is_empty()
+                    || bound_b.is_empty()
+                    || bound_a.iter().any(|field| bound_b.contains(field))
+                {
+                    continue;
                 }
-                
-                // Find the best field for index usage by checking available indexes
-                for (field, field_filters) in field_filters {
-                    if field_filters.len() >= 1 {
-                        // Check if an index exists for this field
-                        let mut candidates = Vec::new();
-                        self.index_selector.find_indexes_for_field(&field, &mut candidates);
-                        
-                        if let Some(candidate) = candidates.first() {
-                            return Ok(Some(candidate.name.clone()));
-                        }
-                    }
+
+                // Walk the smaller scan and keep ids the other scan also yields
+                let (primary, secondary) = if rows_a <= rows_b { (a, b) } else { (b, a) };
+                let rows = rows_a * rows_b / documents.max(1.0);
+                let mut cost = (a.ranges.len() + b.ranges.len()) as f64 * INDEX_SEEK_COST
+                    + (rows_a + rows_b) * INDEX_KEY_COST
+                    + rows * (DOCUMENT_FETCH_COST + per_document);
+                if needs_sort && !primary.sorted {
+                    cost += sort_cost(rows);
                 }
-                
-                Ok(None)
+                paths.push(AccessPlan {
+                    scans: vec![primary.clone(), secondary.clone()],
+                    rows,
+                    cost,
+                });
             }
-            
-            _ => Ok(None),
         }
+
+        Ok(paths)
+    }
+
+    /// Estimate the index entries a scan reads across all its key ranges
+    fn estimate_scan_rows(scan: &IndexScan, distribution: &KeyDistribution) -> Result<f64, QueryPlanError> {
+        let index_value = |value: &Value| {
+            IndexValue::from_value(value).map_err(|e| QueryPlanError::PlanningError(e.to_string()))
+        };
+        let bound = |bound: &Option<KeyBound>| {
+            bound
+                .as_ref()
+                .map(|b| index_value(&b.value).map(|value| (value, b.inclusive)))
+                .transpose()
+        };
+
+        let mut rows = 0.0;
+        for range in &scan.ranges {
+            let prefix = range.prefix.iter().map(index_value).collect::<Result<Vec<_>, _>>()?;
+            let lower = bound(&range.lower)?;
+            let upper = bound(&range.upper)?;
+            rows += distribution.estimate_rows(
+                &prefix,
+                lower.as_ref().map(|(value, inclusive)| (value, *inclusive)),
+                upper.as_ref().map(|(value, inclusive)| (value, *inclusive)),
+            );
+        }
+        Ok(rows.min(distribution.entries as f64))
+    }
+
+    /// Check whether index entries alone can answer the query
+    ///
+    /// The projection must include only index fields, and every field the
+    /// filter or sort reads must be an index field too. A multikey index
+    /// holds single elements, not the arrays the documents store, and a
+    /// hashed or collated index holds neither.
+    fn is_covered(query: &Query, scan: &IndexScan) -> bool {
+        let Some(projection) = &query.projection else {
+            return false;
+        };
+        if scan.multikey || scan.opaque_keys {
+            return false;
+        }
+        if !projection.is_inclusion() || projection.is_exclusion() {
+            return false;
+        }
+
+        let indexed = |field: &str| scan.fields.iter().any(|f| f == field);
+        let mut referenced = Vec::new();
+        Self::collect_filter_fields(&query.filter, &mut referenced);
+        if let Some(sort) = &query.sort {
+            referenced.extend(sort.fields.iter().map(|(field, _)| field.clone()));
+        }
+
+        projection.fields.keys().all(|field| field == "_id" || indexed(field))
+            && referenced.iter().all(|field| indexed(field))
     }
 
-    /// Get the field name from a filter if it's a single-field filter
-    fn get_filter_field(&self, filter: &Filter) -> Option<String> {
+    /// Collect every field a filter reads
+    fn collect_filter_fields(filter: &Filter, fields: &mut Vec<String>) {
         match filter {
-            Filter::Eq { field, .. } |
-            Filter::Ne { field, .. } |
-            Filter::Gt { field, .. } |
-            Filter::Gte { field, .. } |
-            Filter::Lt { field, .. } |
-            Filter::Lte { field, .. } |
-            Filter::In { field, .. } |
-            Filter::Nin { field, .. } |
-            Filter::Exists { field, .. } |
-            Filter::Regex { field, .. } => Some(field.clone()),
-            _ => None,
+            Filter::Empty => {}
+            Filter::Eq { field, .. }
+            | Filter::Ne { field, .. }
+            | Filter::Gt { field, .. }
+            | Filter::Gte { field, .. }
+            | Filter::Lt { field, .. }
+            | Filter::Lte { field, .. }
+            | Filter::In { field, .. }
+            | Filter::Nin { field, .. }
+            | Filter::Exists { field, .. }
+            | Filter::Regex { field, .. }
+            | Filter::All { field, .. }
+            | Filter::ElemMatch { field, .. }
+            | Filter::Size { field, .. }
+            | Filter::Type { field, .. }
+            | Filter::Mod { field, .. }
+            | Filter::BitsAllSet { field, .. }
+            | Filter::BitsAnySet { field, .. } => fields.push(field.clone()),
+            Filter::Expr { .. } => fields.extend(filter.get_fields()),
+            Filter::And(filters) | Filter::Or(filters) | Filter::Nor(filters) => {
+                for f in filters {
+                    Self::collect_filter_fields(f, fields);
+                }
+            }
+            Filter::Not(filter) => Self::collect_filter_fields(filter, fields),
         }
     }
 
@@ -136,7 +366,7 @@ impl QueryPlanner {
         cost += self.estimate_filter_cost(&query.filter) * 10.0;
 
         // Sort cost
-        if query.sort.is_some() {
+        if plan.needs_sort {
             cost += if plan.use_index.is_some() {
                 // Index might provide sorted results
                 50.0
@@ -165,8 +395,18 @@ impl QueryPlanner {
             Filter::Nin { values, .. } => values.len() as f64 * 0.7,
             Filter::Exists { .. } => 1.0,
             Filter::Regex { .. } => 10.0, // Regex is expensive
+            Filter::All { values, .. } => values.len() as f64,
+            Filter::ElemMatch { filter, .. } => self.estimate_filter_cost(filter) * 2.0,
+            Filter::Size { .. }
+            | Filter::Type { .. }
+            | Filter::Mod { .. }
+            | Filter::BitsAllSet { .. }
+            | Filter::BitsAnySet { .. } => 1.5,
+            Filter::Expr { .. } => 2.0,
             Filter::And(filters) => filters.iter().map(|f| self.estimate_filter_cost(f)).sum(),
-            Filter::Or(filters) => filters.iter().map(|f| self.estimate_filter_cost(f)).sum::<f64>() * 1.5,
+            Filter::Or(filters) | Filter::Nor(filters) => {
+                filters.iter().map(|f| self.estimate_filter_cost(f)).sum::<f64>() * 1.5
+            }
             Filter::Not(filter) => self.estimate_filter_cost(filter) * 1.2,
         }
     }
@@ -178,11 +418,29 @@ impl Default for QueryPlanner {
     }
 }
 
+/// Access path priced by the cost-based planner
+struct AccessPlan {
+    /// Index scans to intersect; empty for a collection scan
+    scans: Vec<IndexScan>,
+    /// Estimated documents read
+    rows: f64,
+    /// Estimated cost
+    cost: f64,
+}
+
 /// Query execution plan
 #[derive(Debug, Clone)]
 pub struct QueryPlan {
     /// Index to use (if any)
     pub use_index: Option<String>,
+    /// Key ranges to read from the chosen index
+    pub index_scan: Option<IndexScan>,
+    /// Further index scans whose documents the main scan is intersected with
+    pub intersect: Vec<IndexScan>,
+    /// Estimated documents read, when index statistics were available
+    pub estimated_rows: Option<f64>,
+    /// Whether results are built from index entries without loading documents
+    pub covered: bool,
     /// Execution strategy
     pub execution_strategy: ExecutionStrategy,
     /// Estimated cost
@@ -195,6 +453,8 @@ pub struct QueryPlan {
     pub has_skip: bool,
     /// Whether limit is used
     pub has_limit: bool,
+    /// Access paths the planner priced and did not choose, cheapest first
+    pub rejected: Vec<CandidatePlan>,
 }
 
 impl QueryPlan {
@@ -202,13 +462,137 @@ impl QueryPlan {
     pub fn new() -> Self {
         Self {
             use_index: None,
+            index_scan: None,
+            intersect: Vec::new(),
+            estimated_rows: None,
+            covered: false,
             execution_strategy: ExecutionStrategy::CollectionScan,
             estimated_cost: 0.0,
             needs_sort: false,
             needs_projection: false,
             has_skip: false,
             has_limit: false,
+            rejected: Vec::new(),
+        }
+    }
+
+    /// Summarize the access path of this plan
+    pub fn candidate(&self) -> CandidatePlan {
+        CandidatePlan {
+            execution_strategy: self.execution_strategy.clone(),
+            index_scan: self.index_scan.clone(),
+            intersect: self.intersect.clone(),
+            covered: self.covered,
+            estimated_rows: self.estimated_rows,
+            estimated_cost: self.estimated_cost,
+        }
+    }
+
+    /// Describe the plan for a query as a document value
+    ///
+    /// Stages are listed in execution order: the access path, then any
+    /// sort, skip, limit and projection the query needs.
+    pub fn describe(&self, query: &Query) -> Value {
+        let mut stages = vec![stage(self.execution_strategy.as_str(), Vec::new())];
+        if let (true, Some(sort)) = (self.needs_sort, &query.sort) {
+            let fields = sort
+                .fields
+                .iter()
+                .map(|(field, order)| {
+                    let order = match order {
+                        SortOrder::Ascending => 1,
+                        SortOrder::Descending => -1,
+                    };
+                    Value::Object(BTreeMap::from([
+                        ("field".to_string(), Value::String(field.clone())),
+                        ("order".to_string(), Value::Int32(order)),
+                    ]))
+                })
+                .collect();
+            stages.push(stage("sort", vec![("fields", Value::Array(fields))]));
+        }
+        if let (true, Some(skip)) = (self.has_skip, query.skip) {
+            stages.push(stage("skip", vec![("count", Value::Int64(skip as i64))]));
+        }
+        if let (true, Some(limit)) = (self.has_limit, query.limit) {
+            stages.push(stage("limit", vec![("count", Value::Int64(limit as i64))]));
         }
+        if let (true, Some(projection)) = (self.needs_projection, &query.projection) {
+            let fields = projection
+                .fields
+                .iter()
+                .map(|(field, kind)| {
+                    let flag = match kind {
+                        ProjectionType::Include => 1,
+                        ProjectionType::Exclude => 0,
+                    };
+                    (field.clone(), Value::Int32(flag))
+                })
+                .collect();
+            stages.push(stage("projection", vec![("fields", Value::Object(fields))]));
+        }
+
+        let Value::Object(mut description) = self.candidate().to_value() else {
+            unreachable!("candidate plans describe themselves as objects");
+        };
+        description.insert("stages".to_string(), Value::Array(stages));
+        description.insert(
+            "rejected_plans".to_string(),
+            Value::Array(self.rejected.iter().map(CandidatePlan::to_value).collect()),
+        );
+        Value::Object(description)
+    }
+}
+
+/// Build a plan stage description
+fn stage(name: &str, fields: Vec<(&str, Value)>) -> Value {
+    let mut description = BTreeMap::from([("stage".to_string(), Value::String(name.to_string()))]);
+    description.extend(fields.into_iter().map(|(key, value)| (key.to_string(), value)));
+    Value::Object(description)
+}
+
+/// Access path the planner priced for a query
+#[derive(Debug, Clone)]
+pub struct CandidatePlan {
+    /// Execution strategy
+    pub execution_strategy: ExecutionStrategy,
+    /// Key ranges to read from the driving index
+    pub index_scan: Option<IndexScan>,
+    /// Further index scans intersected with the driving one
+    pub intersect: Vec<IndexScan>,
+    /// Whether results are built from index entries without loading documents
+    pub covered: bool,
+    /// Estimated documents read, when index statistics were available
+    pub estimated_rows: Option<f64>,
+    /// Estimated cost
+    pub estimated_cost: f64,
+}
+
+impl CandidatePlan {
+    /// Describe the access path as a document value
+    pub fn to_value(&self) -> Value {
+        let index = self
+            .index_scan
+            .as_ref()
+            .map(|scan| Value::String(scan.index.clone()))
+            .unwrap_or(Value::Null);
+        let index_scan = self.index_scan.as_ref().map(IndexScan::to_value).unwrap_or(Value::Null);
+
+        Value::Object(BTreeMap::from([
+            ("strategy".to_string(), Value::String(self.execution_strategy.as_str().to_string())),
+            ("index".to_string(), index),
+            ("index_scan".to_string(), index_scan),
+            (
+                "intersect".to_string(),
+                Value::Array(self.intersect.iter().map(IndexScan::to_value).collect()),
+            ),
+            ("covered".to_string(), Value::Bool(self.covered)),
+            (
+                "estimated_rows".to_string(),
+                self.estimated_rows.map(Value::Float64).unwrap_or(Value::Null),
+            ),
+            ("estimated_cost".to_string(), Value::Float64(self.estimated_cost)),
+        ]))
     }
 }
 
@@ -218,6 +602,103 @@ impl Default for QueryPlan {
     }
 }
 
+/// Index access chosen by the planner
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexScan {
+    /// Index name
+    pub index: String,
+    /// Indexed fields in key order
+    pub fields: Vec<String>,
+    /// Key ranges to read; their results are unioned
+    pub ranges: Vec<KeyRange>,
+    /// Walk the ranges against stored key order
+    #[serde(default)]
+    pub reverse: bool,
+    /// Whether the walk yields documents in the query's sort order
+    #[serde(default)]
+    pub sorted: bool,
+    /// Whether the index holds one entry per array element, so a document
+    /// may be reached through several keys
+    #[serde(default)]
+    pub multikey: bool,
+    /// Whether the index stores collation keys or hashes in place of the
+    /// field values, or files unequal values under one key, so its keys
+    /// cannot rebuild documents
+    #[serde(default)]
+    pub opaque_keys: bool,
+}
+
+impl IndexScan {
+    /// Index fields the key ranges constrain
+    pub fn bound_fields(&self) -> &[String] {
+        let Some(range) = self.ranges.first() else {
+            return &[];
+        };
+        let bounded = range.lower.is_some() || range.upper.is_some();
+        let count = range.prefix.len() + usize::from(bounded);
+        &self.fields[..count.min(self.fields.len())]
+    }
+
+    /// Describe the scan as a document value, e.g. for index listings
+    pub fn to_value(&self) -> Value {
+        let bound = |bound: &Option<KeyBound>| match bound {
+            Some(bound) => Value::Object(BTreeMap::from([
+                ("value".to_string(), bound.value.clone()),
+                ("inclusive".to_string(), Value::Bool(bound.inclusive)),
+            ])),
+            None => Value::Null,
+        };
+
+        let ranges = self
+            .ranges
+            .iter()
+            .map(|range| {
+                Value::Object(BTreeMap::from([
+                    ("prefix".to_string(), Value::Array(range.prefix.clone())),
+                    ("lower".to_string(), bound(&range.lower)),
+                    ("upper".to_string(), bound(&range.upper)),
+                ]))
+            })
+            .collect();
+
+        Value::Object(BTreeMap::from([
+            ("index".to_string(), Value::String(self.index.clone())),
+            (
+                "fields".to_string(),
+                Value::Array(self.fields.iter().cloned().map(Value::String).collect()),
+            ),
+            ("ranges".to_string(), Value::Array(ranges)),
+            ("reverse".to_string(), Value::Bool(self.reverse)),
+            ("sorted".to_string(), Value::Bool(self.sorted)),
+            ("multikey".to_string(), Value::Bool(self.multikey)),
+            ("opaque_keys".to_string(), Value::Bool(self.opaque_keys)),
+        ]))
+    }
+}
+
+/// Contiguous run of index keys
+///
+/// The leading fields equal `prefix` and the field after the prefix lies
+/// between `lower` and `upper`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyRange {
+    /// Values for the leading index fields
+    pub prefix: Vec<Value>,
+    /// Lower bound on the field after the prefix
+    pub lower: Option<KeyBound>,
+    /// Upper bound on the field after the prefix
+    pub upper: Option<KeyBound>,
+}
+
+/// One end of a key range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyBound {
+    /// Bound value
+    pub value: Value,
+    /// Whether keys equal to the bound are included
+    pub inclusive: bool,
+}
+
 /// Execution strategy
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExecutionStrategy {
@@ -225,6 +706,19 @@ pub enum ExecutionStrategy {
     CollectionScan,
     /// Use an index for scanning
     IndexScan,
+    /// Intersect the documents of several index scans
+    IndexIntersection,
+}
+
+impl ExecutionStrategy {
+    /// Stage name used in plan descriptions
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExecutionStrategy::CollectionScan => "collection_scan",
+            ExecutionStrategy::IndexScan => "index_scan",
+            ExecutionStrategy::IndexIntersection => "index_intersection",
+        }
+    }
 }
 
 /// Query planning errors
@@ -240,7 +734,14 @@ pub enum QueryPlanError {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::document::Value;
+
+    fn test_planner() -> QueryPlanner {
+        QueryPlanner::with_indexes(vec![
+            IndexDefinition::single("name".to_string()),
+            IndexDefinition::single("age".to_string()),
+            IndexDefinition::compound(vec!["city".to_string(), "age".to_string()]),
+        ])
+    }
 
     #[test]
     fn test_create_plan_empty_query() {
@@ -256,7 +757,7 @@ mod tests {
 
     #[test]
     fn test_create_plan_with_equality_filter() {
-        let planner = QueryPlanner::new();
+        let planner = test_planner();
         let query = Query::with_filter(Filter::eq("name", "John"));
         
         let plan = planner.create_plan(&query).unwrap();
@@ -266,7 +767,7 @@ mod tests {
 
     #[test]
     fn test_create_plan_with_range_filter() {
-        let planner = QueryPlanner::new();
+        let planner = test_planner();
         let query = Query::with_filter(Filter::and(vec![
             Filter::gte("age", 18i32),
             Filter::lte("age", 65i32),
@@ -275,6 +776,39 @@ mod tests {
         let plan = planner.create_plan(&query).unwrap();
         assert_eq!(plan.execution_strategy, ExecutionStrategy::IndexScan);
         assert_eq!(plan.use_index, Some("idx_age".to_string()));
+
+        let range = &plan.index_scan.unwrap().ranges[0];
+        assert!(range.prefix.is_empty());
+        assert_eq!(range.lower, Some(KeyBound { value: Value::Int32(18), inclusive: true }));
+        assert_eq!(range.upper, Some(KeyBound { value: Value::Int32(65), inclusive: true }));
+    }
+
+    #[test]
+    fn test_create_plan_prefers_compound_prefix() {
+        let planner = test_planner();
+        let query = Query::with_filter(Filter::and(vec![
+            Filter::eq("city", "Oslo"),
+            Filter::gt("age", 30i32),
+        ]));
+
+        let plan = planner.create_plan(&query).unwrap();
+        let scan = plan.index_scan.unwrap();
+        assert_eq!(scan.index, "idx_city_age");
+        assert_eq!(scan.ranges[0].prefix, vec![Value::from("Oslo")]);
+        assert_eq!(scan.ranges[0].lower, Some(KeyBound { value: Value::Int32(30), inclusive: false }));
+    }
+
+    #[test]
+    fn test_create_plan_without_usable_index() {
+        let planner = test_planner();
+        let query = Query::with_filter(Filter::or(vec![
+            Filter::eq("name", "John"),
+            Filter::eq("age", 30i32),
+        ]));
+
+        let plan = planner.create_plan(&query).unwrap();
+        assert_eq!(plan.execution_strategy, ExecutionStrategy::CollectionScan);
+        assert!(plan.index_scan.is_none());
     }
 
     #[test]
@@ -305,7 +839,7 @@ mod tests {
 
     #[test]
     fn test_estimate_cost_with_index() {
-        let planner = QueryPlanner::new();
+        let planner = test_planner();
         let query = Query::with_filter(Filter::eq("name", "John"));
         
         let plan = planner.create_plan(&query).unwrap();
@@ -321,4 +855,222 @@ mod tests {
         let plan = planner.create_plan(&query).unwrap();
         assert!(plan.estimated_cost > 1000.0); // Should be high without index
     }
+
+    #[test]
+    fn test_covered_projection() {
+        let planner = test_planner();
+        let filter = Filter::and(vec![Filter::eq("city", "Oslo"), Filter::gt("age", 30i32)]);
+
+        let covered = Query::with_filter(filter.clone())
+            .projection(crate::query::ast::Projection::new().include("age").include("city"));
+        assert!(planner.create_plan(&covered).unwrap().covered);
+
+        // name is not in idx_city_age, so documents must be loaded
+        let uncovered = Query::with_filter(filter.clone())
+            .projection(crate::query::ast::Projection::new().include("name"));
+        assert!(!planner.create_plan(&uncovered).unwrap().covered);
+
+        let residual = Query::with_filter(Filter::and(vec![filter, Filter::exists("name", true)]))
+            .projection(crate::query::ast::Projection::new().include("age"));
+        assert!(!planner.create_plan(&residual).unwrap().covered);
+    }
+
+    #[test]
+    fn test_index_order_skips_sort() {
+        let planner = test_planner();
+        let query = Query::with_filter(Filter::eq("city", "Oslo"))
+            .sort(crate::query::ast::Sort::new().desc("age"))
+            .limit(10);
+
+        let plan = planner.create_plan(&query).unwrap();
+        let scan = plan.index_scan.unwrap();
+        assert!(scan.sorted && scan.reverse);
+        assert!(!plan.needs_sort);
+
+        let unsorted = Query::with_filter(Filter::eq("city", "Oslo"))
+            .sort(crate::query::ast::Sort::new().asc("name"));
+        assert!(planner.create_plan(&unsorted).unwrap().needs_sort);
+    }
+
+    /// Planner costed against 1000 documents indexed on status, `a` and `b`
+    ///
+    /// One document in a hundred is open; `a` and `b` take 10 and 7
+    /// evenly spread values.
+    fn analyzed_planner() -> QueryPlanner {
+        use crate::document::{Document, DocumentId};
+        use crate::index::IndexManager;
+
+        let documents: Vec<Document> = (0..1000)
+            .map(|i| {
+                let mut doc = Document::with_id(DocumentId::new());
+                let status = if i % 100 == 0 { "open" } else { "closed" };
+                doc.insert("status".to_string(), Value::from(status));
+                doc.insert("a".to_string(), Value::Int64(i % 10));
+                doc.insert("b".to_string(), Value::Int64(i % 7));
+                doc
+            })
+            .collect();
+
+        let manager = IndexManager::new("orders".to_string());
+        for field in ["status", "a", "b"] {
+            let definition = IndexDefinition::single(field.to_string());
+            let name = definition.name.clone();
+            manager.register_index(definition).unwrap();
+            manager.populate_index(&name, &documents).unwrap();
+        }
+
+        QueryPlanner::with_indexes(manager.definitions()).with_statistics(manager.collection_statistics())
+    }
+
+    #[test]
+    fn test_cost_based_plan_follows_selectivity() {
+        let planner = analyzed_planner();
+
+        let rare = planner.create_plan(&Query::with_filter(Filter::eq("status", "open"))).unwrap();
+        assert_eq!(rare.use_index, Some("idx_status".to_string()));
+        assert_eq!(rare.estimated_rows, Some(10.0));
+
+        // Nearly every document is closed, so reading them all is cheaper
+        let common = planner.create_plan(&Query::with_filter(Filter::eq("status", "closed"))).unwrap();
+        assert_eq!(common.execution_strategy, ExecutionStrategy::CollectionScan);
+        assert!(common.estimated_cost > rare.estimated_cost);
+
+        let listed = planner
+            .create_plan(&Query::with_filter(Filter::in_values(
+                "status",
+                vec![Value::from("open"), Value::from("closed")],
+            )))
+            .unwrap();
+        assert_eq!(listed.execution_strategy, ExecutionStrategy::CollectionScan);
+    }
+
+    #[test]
+    fn test_small_collection_keeps_applicable_index() {
+        use crate::document::{Document, DocumentId};
+        use crate::index::IndexManager;
+
+        let documents: Vec<Document> = (0..20)
+            .map(|i| {
+                let mut doc = Document::with_id(DocumentId::new());
+                let status = if i == 0 { "open" } else { "closed" };
+                doc.insert("status".to_string(), Value::from(status));
+                doc
+            })
+            .collect();
+        let manager = IndexManager::new("orders".to_string());
+        let definition = IndexDefinition::single("status".to_string());
+        manager.register_index(definition).unwrap();
+        manager.populate_index("idx_status", &documents).unwrap();
+        let planner =
+            QueryPlanner::with_indexes(manager.definitions()).with_statistics(manager.collection_statistics());
+
+        // The scan is priced lower, but the collection is too small to trust that
+        let common = planner.create_plan(&Query::with_filter(Filter::eq("status", "closed"))).unwrap();
+        assert_eq!(common.use_index, Some("idx_status".to_string()));
+        assert_eq!(common.estimated_rows, Some(19.0));
+        let scan = common.rejected.last().unwrap();
+        assert_eq!(scan.execution_strategy, ExecutionStrategy::CollectionScan);
+        assert!(scan.estimated_cost < common.estimated_cost);
+    }
+
+    #[test]
+    fn test_cost_based_plan_intersects_indexes() {
+        let planner = analyzed_planner();
+        let query = Query::with_filter(Filter::and(vec![Filter::eq("a", 3i64), Filter::eq("b", 2i64)]));
+
+        let plan = planner.create_plan(&query).unwrap();
+        assert_eq!(plan.execution_strategy, ExecutionStrategy::IndexIntersection);
+        assert_eq!(plan.use_index, Some("idx_a".to_string()));
+        assert_eq!(plan.intersect.len(), 1);
+        assert_eq!(plan.intersect[0].index, "idx_b");
+        assert!(!plan.covered);
+
+        // Indexes on the same field are never intersected
+        let same = Query::with_filter(Filter::and(vec![Filter::eq("a", 3i64), Filter::lt("a", 5i64)]));
+        assert!(planner.create_plan(&same).unwrap().intersect.is_empty());
+    }
+
+    #[test]
+    fn test_plan_lists_rejected_candidates() {
+        let planner = analyzed_planner();
+        let query = Query::with_filter(Filter::and(vec![Filter::eq("a", 3i64), Filter::eq("b", 2i64)]));
+
+        let plan = planner.create_plan(&query).unwrap();
+        let strategies: Vec<_> = plan.rejected.iter().map(|c| c.execution_strategy.clone()).collect();
+        assert_eq!(plan.rejected.len(), 3);
+        assert!(strategies.contains(&ExecutionStrategy::CollectionScan));
+        assert_eq!(strategies.iter().filter(|s| **s == ExecutionStrategy::IndexScan).count(), 2);
+        assert!(plan.rejected.iter().all(|c| c.estimated_cost >= plan.estimated_cost));
+        assert!(plan.rejected.windows(2).all(|w| w[0].estimated_cost <= w[1].estimated_cost));
+
+        // Without statistics the selector's ranking is kept and priced heuristically
+        let heuristic = test_planner().create_plan(&Query::with_filter(Filter::eq("name", "John"))).unwrap();
+        assert_eq!(heuristic.use_index, Some("idx_name".to_string()));
+        let last = heuristic.rejected.last().unwrap();
+        assert_eq!(last.execution_strategy, ExecutionStrategy::CollectionScan);
+        assert!(last.estimated_cost > heuristic.estimated_cost);
+    }
+
+    #[test]
+    fn test_describe_plan_stages() {
+        let planner = test_planner();
+        let query = Query::with_filter(Filter::eq("city", "Oslo"))
+            .sort(crate::query::ast::Sort::new().asc("score"))
+            .skip(5)
+            .limit(10)
+            .projection(crate::query::ast::Projection::new().include("name"));
+
+        let Value::Object(description) = planner.create_plan(&query).unwrap().describe(&query) else {
+            panic!("plan should describe itself as an object");
+        };
+        assert_eq!(description["strategy"], Value::from("index_scan"));
+        assert_eq!(description["index"], Value::from("idx_city_age"));
+        let Value::Array(stages) = &description["stages"] else {
+            panic!("stages should be an array");
+        };
+        let names: Vec<_> = stages
+            .iter()
+            .map(|stage| match stage {
+                Value::Object(stage) => stage["stage"].clone(),
+                _ => Value::Null,
+            })
+            .collect();
+        assert_eq!(
+            names,
+            ["index_scan", "sort", "skip", "limit", "projection"].map(Value::from).to_vec()
+        );
+        let Value::Array(rejected) = &description["rejected_plans"] else {
+            panic!("rejected plans should be an array");
+        };
+        assert!(!rejected.is_empty());
+
+        let plain = Query::new();
+        let Value::Object(description) = QueryPlanner::new().create_plan(&plain).unwrap().describe(&plain) else {
+            panic!("plan should describe itself as an object");
+        };
+        assert_eq!(description["index"], Value::Null);
+        assert_eq!(description["stages"], Value::Array(vec![stage("collection_scan", Vec::new())]));
+        assert_eq!(description["rejected_plans"], Value::Array(Vec::new()));
+    }
+
+    #[test]
+    fn test_cost_based_plan_rejects_unindexable_bounds() {
+        let planner = analyzed_planner();
+        let scan = IndexScan {
+            index: "idx_a".to_string(),
+            fields: vec!["a".to_string()],
+            ranges: vec![KeyRange {
+                prefix: vec![Value::Array(Vec::new())],
+                lower: None,
+                upper: None,
+            }],
+            reverse: false,
+            sorted: false,
+            multikey: false,
+            opaque_keys: false,
+        };
+        let statistics = planner.statistics.as_ref().unwrap();
+        let result = QueryPlanner::estimate_scan_rows(&scan, &statistics.indexes["idx_a"]);
+        assert!(matches!(result, Err(QueryPlanError::PlanningError(_))));
+    }
 }
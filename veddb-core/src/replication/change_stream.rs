@@ -0,0 +1,461 @@
+//! Change streams
+//!
+//! A change stream turns the WAL's document operations into insert,
+//! update, replace and delete events. Live events come from
+//! [`SyncManager::subscribe_to_wal_stream`](super::SyncManager::subscribe_to_wal_stream);
+//! a stream resumed after a token first reads the WAL files from that
+//! point. Every event carries a resume token derived from its WAL sequence.
+
+use crate::document::{Document, DocumentId, Value};
+use crate::query::{Filter, QueryExecutor};
+use crate::storage::HybridStorageEngine;
+use crate::wal::{scan_wal_files, Operation, WalEntry, WalError, WalReader, WalWriter};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+
+/// Kind of document change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Replace,
+    Delete,
+}
+
+impl ChangeKind {
+    /// Name used in event documents
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Insert => "insert",
+            ChangeKind::Update => "update",
+            ChangeKind::Replace => "replace",
+            ChangeKind::Delete => "delete",
+        }
+    }
+}
+
+/// Position in the change history: the WAL sequence of an event
+///
+/// Written as 16 hex digits, so tokens compare in history order as strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResumeToken(u64);
+
+impl ResumeToken {
+    /// Token of the WAL entry with this sequence
+    pub fn new(sequence: u64) -> Self {
+        Self(sequence)
+    }
+
+    /// WAL sequence of the event
+    pub fn sequence(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = ChangeStreamError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        if token.len() != 16 {
+            return Err(ChangeStreamError::InvalidResumeToken(token.to_string()));
+        }
+        u64::from_str_radix(token, 16)
+            .map(Self)
+            .map_err(|_| ChangeStreamError::InvalidResumeToken(token.to_string()))
+    }
+}
+
+/// Change stream errors
+#[derive(Debug, thiserror::Error)]
+pub enum ChangeStreamError {
+    #[error("Change streams need a WAL attached to the storage engine")]
+    WalNotAttached,
+
+    #[error("Invalid resume token '{0}'")]
+    InvalidResumeToken(String),
+
+    #[error("Resume token {0} is older than the WAL kept on disk")]
+    HistoryLost(ResumeToken),
+
+    #[error("WAL error: {0}")]
+    Wal(#[from] WalError),
+
+    #[error("Filter error: {0}")]
+    Filter(String),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    #[error("The WAL stream closed")]
+    Closed,
+}
+
+/// One change to a document
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+    pub token: ResumeToken,
+    pub kind: ChangeKind,
+    pub collection: String,
+    pub document_id: DocumentId,
+    /// When the change was written to the WAL
+    pub timestamp: DateTime<Utc>,
+    /// The document after the change: always for inserts and replacements,
+    /// for updates when the stream asks for post-images
+    pub full_document: Option<Document>,
+    /// Top-level fields an update set, with their new values
+    pub updated_fields: BTreeMap<String, Value>,
+    /// Top-level fields an update removed
+    pub removed_fields: Vec<String>,
+}
+
+impl ChangeEvent {
+    /// Event for a WAL entry, if it records a document change
+    pub fn from_entry(entry: &WalEntry) -> Option<Self> {
+        let event = |kind, collection: &String, document_id| Self {
+            token: ResumeToken(entry.sequence),
+            kind,
+            collection: collection.clone(),
+            document_id,
+            timestamp: entry.timestamp,
+            full_document: None,
+            updated_fields: BTreeMap::new(),
+            removed_fields: Vec::new(),
+        };
+        match &entry.operation {
+            Operation::Insert { collection, doc } => Some(Self {
+                full_document: Some(doc.clone()),
+                ..event(ChangeKind::Insert, collection, doc.id)
+            }),
+            Operation::Replace { collection, doc } => Some(Self {
+                full_document: Some(doc.clone()),
+                ..event(ChangeKind::Replace, collection, doc.id)
+            }),
+            Operation::Update { collection, id, changes, removed } => Some(Self {
+                updated_fields: changes.clone(),
+                removed_fields: removed.clone(),
+                ..event(ChangeKind::Update, collection, *id)
+            }),
+            Operation::Delete { collection, id } => Some(event(ChangeKind::Delete, collection, *id)),
+            _ => None,
+        }
+    }
+
+    /// Event as a document, the form `$match` filters and clients see
+    ///
+    /// Fields are `_id` (the resume token), `operationType`, `ns.coll`,
+    /// `documentKey._id`, `clusterTime`, `fullDocument` when present and,
+    /// for updates, `updateDescription.updatedFields` and `removedFields`.
+    pub fn to_document(&self) -> Document {
+        let mut doc = Document::with_id(self.document_id);
+        doc.insert("_id".to_string(), Value::String(self.token.to_string()));
+        doc.insert("operationType".to_string(), Value::String(self.kind.as_str().to_string()));
+        doc.insert("ns".to_string(), object([("coll", Value::String(self.collection.clone()))]));
+        doc.insert("documentKey".to_string(), object([("_id", Value::String(self.document_id.to_string()))]));
+        doc.insert("clusterTime".to_string(), Value::DateTime(self.timestamp));
+        if let Some(full) = &self.full_document {
+            let mut fields = full.fields.clone();
+            fields.insert("_id".to_string(), Value::String(full.id.to_string()));
+            doc.insert("fullDocument".to_string(), Value::Object(fields));
+        }
+        if self.kind == ChangeKind::Update {
+            let removed = self.removed_fields.iter().cloned().map(Value::String).collect();
+            doc.insert(
+                "updateDescription".to_string(),
+                object([
+                    ("updatedFields", Value::Object(self.updated_fields.clone())),
+                    ("removedFields", Value::Array(removed)),
+                ]),
+            );
+        }
+        doc
+    }
+}
+
+fn object<const N: usize>(members: [(&str, Value); N]) -> Value {
+    Value::Object(members.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+}
+
+/// What a change stream follows
+#[derive(Debug, Clone, Default)]
+pub struct ChangeStreamOptions {
+    /// Collection to watch; every collection when absent
+    pub collection: Option<String>,
+    /// Filter over the event documents, as in a `$match` stage
+    pub filter: Option<Filter>,
+    /// Start after this event instead of at the next change
+    pub resume_after: Option<ResumeToken>,
+    /// Attach the document as it is when the event is read to update events
+    pub full_document: bool,
+}
+
+/// Stream of change events from a storage engine's WAL
+pub struct ChangeStream {
+    storage: Arc<HybridStorageEngine>,
+    writer: Arc<WalWriter>,
+    live: broadcast::Receiver<WalEntry>,
+    /// Entries read from the WAL files and not yet delivered
+    backlog: VecDeque<WalEntry>,
+    /// Sequence of the newest entry passed, matching or not
+    position: Option<u64>,
+    options: ChangeStreamOptions,
+    executor: QueryExecutor,
+}
+
+impl ChangeStream {
+    /// Open a change stream on the WAL attached to `storage`
+    ///
+    /// Without a resume token the stream starts after the newest entry
+    /// already written. Fails with [`ChangeStreamError::HistoryLost`] if
+    /// the entries after the token were compacted away.
+    pub async fn open(storage: Arc<HybridStorageEngine>, options: ChangeStreamOptions) -> Result<Self, ChangeStreamError> {
+        let (writer, sync) = storage.wal().ok_or(ChangeStreamError::WalNotAttached)?;
+        // Subscribe before reading the position so no entry falls in between
+        let live = sync.subscribe_to_wal_stream();
+        let position = match options.resume_after {
+            Some(token) if token.sequence() >= writer.current_sequence() => {
+                return Err(ChangeStreamError::InvalidResumeToken(token.to_string()));
+            }
+            Some(token) => Some(token.sequence()),
+            None => writer.current_sequence().checked_sub(1),
+        };
+        let mut stream = Self {
+            storage,
+            writer,
+            live,
+            backlog: VecDeque::new(),
+            position,
+            options,
+            executor: QueryExecutor::new(),
+        };
+        if let Some(token) = stream.options.resume_after {
+            stream.read_backlog().await?;
+            if stream.backlog.front().is_some_and(|first| first.sequence > token.sequence() + 1) {
+                return Err(ChangeStreamError::HistoryLost(token));
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Token of the newest entry the stream has passed, matching or not
+    ///
+    /// Resuming after it continues exactly where this stream stands.
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        self.position.map(ResumeToken)
+    }
+
+    /// Wait for the next events and return up to `max` of them
+    ///
+    /// Returns as soon as one event is ready, with any others already waiting.
+    pub async fn next_batch(&mut self, max: usize) -> Result<Vec<ChangeEvent>, ChangeStreamError> {
+        let max = max.max(1);
+        let mut events = Vec::new();
+        loop {
+            while events.len() < max {
+                let entry = match self.backlog.pop_front() {
+                    Some(entry) => entry,
+                    None => match self.live.try_recv() {
+                        Ok(entry) => entry,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Lagged(_)) => {
+                            self.read_backlog().await?;
+                            continue;
+                        }
+                        Err(TryRecvError::Closed) => return Err(ChangeStreamError::Closed),
+                    },
+                };
+                if let Some(event) = self.accept(entry).await? {
+                    events.push(event);
+                }
+            }
+            if !events.is_empty() {
+                return Ok(events);
+            }
+            match self.live.recv().await {
+                Ok(entry) => {
+                    if let Some(event) = self.accept(entry).await? {
+                        events.push(event);
+                    }
+                }
+                // Entries the channel dropped are still in the WAL files
+                Err(RecvError::Lagged(_)) => self.read_backlog().await?,
+                Err(RecvError::Closed) => return Err(ChangeStreamError::Closed),
+            }
+        }
+    }
+
+    /// Queue the WAL file entries after the stream's position
+    async fn read_backlog(&mut self) -> Result<(), ChangeStreamError> {
+        self.writer.flush().await?;
+        self.backlog.clear();
+        for path in scan_wal_files(self.writer.wal_dir())? {
+            let mut reader = WalReader::open(&path)?;
+            while let Some(entry) = reader.next_entry()? {
+                if self.position.is_none_or(|position| entry.sequence > position) {
+                    self.backlog.push_back(entry);
+                }
+            }
+        }
+        self.backlog.make_contiguous().sort_by_key(|entry| entry.sequence);
+        Ok(())
+    }
+
+    /// Event for an entry if it is new, in the watched collection and matches the filter
+    async fn accept(&mut self, entry: WalEntry) -> Result<Option<ChangeEvent>, ChangeStreamError> {
+        if self.position.is_some_and(|position| entry.sequence <= position) {
+            return Ok(None);
+        }
+        self.position = Some(entry.sequence);
+
+        let Some(mut event) = ChangeEvent::from_entry(&entry) else { return Ok(None) };
+        if self.options.collection.as_ref().is_some_and(|collection| *collection != event.collection) {
+            return Ok(None);
+        }
+        if self.options.full_document && event.kind == ChangeKind::Update {
+            event.full_document = self
+                .storage
+                .get_document(&event.collection, event.document_id)
+                .await
+                .map_err(|e| ChangeStreamError::Storage(e.to_string()))?;
+        }
+        if let Some(filter) = &self.options.filter {
+            let matched = self
+                .executor
+                .matches_filter(&event.to_document(), filter)
+                .map_err(|e| ChangeStreamError::Filter(e.to_string()))?;
+            if !matched {
+                return Ok(None);
+            }
+        }
+        Ok(Some(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::cache_layer::CacheConfig;
+    use crate::query::QueryParser;
+    use crate::replication::SyncManager;
+    use crate::storage::PersistentLayer;
+    use crate::wal::WalConfig;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn engine_with_wal(dir: &TempDir) -> Arc<HybridStorageEngine> {
+        let persistent = Arc::new(PersistentLayer::new(dir.path().join("data")).unwrap());
+        let engine = Arc::new(HybridStorageEngine::new(CacheConfig::default(), persistent));
+        let wal_dir = dir.path().join("wal");
+        let writer = WalWriter::new(WalConfig { wal_dir: wal_dir.clone(), ..WalConfig::default() }).unwrap();
+        let sync = SyncManager::new(wal_dir, dir.path().join("snapshots"));
+        engine.attach_wal(Arc::new(writer), Arc::new(sync));
+        engine
+    }
+
+    fn task(title: &str, done: bool) -> Document {
+        let mut doc = Document::new();
+        doc.insert("title".to_string(), Value::String(title.to_string()));
+        doc.insert("done".to_string(), Value::Bool(done));
+        doc
+    }
+
+    async fn next(stream: &mut ChangeStream) -> Vec<ChangeEvent> {
+        tokio::time::timeout(Duration::from_secs(5), stream.next_batch(10)).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_events_for_each_kind_of_write() {
+        let dir = TempDir::new().unwrap();
+        let engine = engine_with_wal(&dir);
+        let options = ChangeStreamOptions { collection: Some("tasks".to_string()), full_document: true, ..Default::default() };
+        let mut stream = ChangeStream::open(engine.clone(), options).await.unwrap();
+        assert_eq!(stream.resume_token(), None);
+
+        let id = engine.insert_document("tasks", task("write docs", false)).await.unwrap();
+        engine.insert_document("notes", task("other collection", false)).await.unwrap();
+        let mut patched = task("write docs", true);
+        patched.id = id;
+        patched.remove("title");
+        engine.patch_document("tasks", id, patched).await.unwrap();
+        let mut replaced = task("ship", false);
+        replaced.id = id;
+        engine.update_document("tasks", id, replaced).await.unwrap();
+        engine.delete_document("tasks", id).await.unwrap();
+
+        let events = next(&mut stream).await;
+        let kinds: Vec<ChangeKind> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, [ChangeKind::Insert, ChangeKind::Update, ChangeKind::Replace, ChangeKind::Delete]);
+        assert!(events.iter().all(|event| event.document_id == id));
+        assert!(events.windows(2).all(|pair| pair[0].token < pair[1].token));
+
+        let update = &events[1];
+        assert_eq!(update.updated_fields.get("done"), Some(&Value::Bool(true)));
+        assert_eq!(update.removed_fields, ["title"]);
+        // The post-image is looked up as the event is read, after the delete
+        assert!(update.full_document.is_none());
+        assert_eq!(events[2].full_document.as_ref().unwrap().get("title"), Some(&Value::String("ship".to_string())));
+
+        let Value::Object(description) = update.to_document().get("updateDescription").unwrap().clone() else { panic!("not an object") };
+        assert_eq!(description["removedFields"], Value::Array(vec![Value::String("title".to_string())]));
+        assert_eq!(stream.resume_token(), Some(events[3].token));
+    }
+
+    #[tokio::test]
+    async fn test_resume_and_match_filter() {
+        let dir = TempDir::new().unwrap();
+        let engine = engine_with_wal(&dir);
+        engine.insert_document("tasks", task("first", false)).await.unwrap();
+        let mut stream = ChangeStream::open(engine.clone(), ChangeStreamOptions::default()).await.unwrap();
+        let start = stream.resume_token().unwrap();
+        engine.insert_document("tasks", task("second", true)).await.unwrap();
+        let seen = next(&mut stream).await;
+        drop(stream);
+
+        // Writes while disconnected are read back from the WAL files
+        engine.insert_document("tasks", task("third", false)).await.unwrap();
+        engine.insert_document("tasks", task("fourth", true)).await.unwrap();
+        let filter = QueryParser::parse_filter(&serde_json::json!({
+            "operationType": "insert",
+            "fullDocument.done": true
+        }))
+        .unwrap();
+        let options = ChangeStreamOptions { resume_after: Some(seen[0].token), filter: Some(filter), ..Default::default() };
+        let mut resumed = ChangeStream::open(engine.clone(), options).await.unwrap();
+        let events = next(&mut resumed).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].full_document.as_ref().unwrap().get("title"), Some(&Value::String("fourth".to_string())));
+
+        // Resuming from the first token replays every insert after it
+        let options = ChangeStreamOptions { resume_after: Some(start), ..Default::default() };
+        let mut replay = ChangeStream::open(engine.clone(), options).await.unwrap();
+        assert_eq!(next(&mut replay).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_tokens_and_missing_wal_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        let engine = engine_with_wal(&dir);
+        engine.insert_document("tasks", task("only", false)).await.unwrap();
+        let ahead = ChangeStreamOptions { resume_after: Some(ResumeToken::new(5)), ..Default::default() };
+        assert!(matches!(ChangeStream::open(engine, ahead).await, Err(ChangeStreamError::InvalidResumeToken(_))));
+
+        assert!(matches!("00zz".parse::<ResumeToken>(), Err(ChangeStreamError::InvalidResumeToken(_))));
+        let token = ResumeToken::new(42);
+        assert_eq!(token.to_string().parse::<ResumeToken>().unwrap(), token);
+
+        let persistent = Arc::new(PersistentLayer::new(dir.path().join("plain")).unwrap());
+        let plain = Arc::new(HybridStorageEngine::new(CacheConfig::default(), persistent));
+        assert!(matches!(ChangeStream::open(plain, ChangeStreamOptions::default()).await, Err(ChangeStreamError::WalNotAttached)));
+    }
+}
@@ -8,12 +8,14 @@
 //! - Read operations on slave nodes
 //! - Slave promotion to master
 
+pub mod change_stream;
 pub mod manager;
 pub mod manager_extensions;
 pub mod message;
 pub mod connection;
 pub mod sync;
 
+pub use change_stream::{ChangeEvent, ChangeKind, ChangeStream, ChangeStreamError, ChangeStreamOptions, ResumeToken};
 pub use manager::*;
 pub use message::*;
 pub use connection::*;
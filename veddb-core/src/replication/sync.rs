@@ -11,7 +11,7 @@ use std::time::{Duration, Instant};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::{broadcast, mpsc}; // Added mpsc if it was unused, but keeping imports clean is good. 
-use crate::protocol::IndexField; // Import IndexField
+use crate::protocol::{IndexField, IndexOptions};
 use tracing::{debug, error, info, warn};
 
 /// Synchronization manager for handling full and incremental sync
@@ -577,7 +577,9 @@ impl SyncManager {
                         .map_err(|e| ReplicationError::SnapshotError(e.to_string()))?;
                     // Convert schema::IndexDefinition to Vec<protocol::IndexField>
                     let fields = match &index_def.index_type {
-                        crate::schema::IndexType::Single { field } => vec![IndexField {
+                        crate::schema::IndexType::Single { field }
+                        | crate::schema::IndexType::Hashed { field }
+                        | crate::schema::IndexType::Vector { field, .. } => vec![IndexField {
                             field: field.clone(),
                             direction: 1,
                         }],
@@ -593,7 +595,12 @@ impl SyncManager {
                             continue;
                         }
                     };
-                    storage.create_index(&col_header.name, &index_def.name, fields, index_def.unique)
+                    storage.create_index(
+                        &col_header.name,
+                        &index_def.name,
+                        fields,
+                        IndexOptions::from(&index_def),
+                    )
                         .map_err(|e| ReplicationError::StorageError(e.to_string()))?;
                 }
             }
@@ -622,16 +629,8 @@ impl SyncManager {
                     storage.insert_document(collection, doc.clone())
                         .await.map_err(|e| ReplicationError::StorageError(e.to_string()))?;
                 }
-                Update { collection, id, changes } => {
-                     // Storage update_document takes complete document usually, or we need fetch-modify-write
-                     // But wait, Operation::Update has 'changes'.
-                     // HybridStorageEngine::update_document takes 'doc: Document' (replacement)
-                     // If WAL logs partial update, we have a mismatch.
-                     // But WAL likely logs full document for idempotency or the Operation define partials.
-                     // Looking at Operation::Update definition: changes: BTreeMap<String, Value>.
-                     // This is a partial update.
-                     // We need to fetch, apply changes, and update.
-                     
+                Update { collection, id, changes, removed } => {
+                     // Updates log only the fields they set and remove
                      if let Some(mut doc) = storage.get_document(collection, *id)
                         .await.map_err(|e| ReplicationError::StorageError(e.to_string()))? {
                         
@@ -639,11 +638,18 @@ impl SyncManager {
                         for (k, v) in changes {
                             doc.insert(k.clone(), v.clone());
                         }
+                        for k in removed {
+                            doc.remove(k);
+                        }
                         
-                        storage.update_document(collection, *id, doc)
+                        storage.patch_document(collection, *id, doc)
                             .await.map_err(|e| ReplicationError::StorageError(e.to_string()))?;
                      }
                 }
+                Replace { collection, doc } => {
+                    storage.update_document(collection, doc.id, doc.clone())
+                        .await.map_err(|e| ReplicationError::StorageError(e.to_string()))?;
+                }
                 Delete { collection, id } => {
                     storage.delete_document(collection, *id)
                         .await.map_err(|e| ReplicationError::StorageError(e.to_string()))?;
@@ -659,7 +665,9 @@ impl SyncManager {
                 CreateIndex { collection, index } => {
                      // Convert schema::IndexDefinition to Vec<protocol::IndexField>
                     let fields = match &index.index_type {
-                        crate::schema::IndexType::Single { field } => vec![IndexField {
+                        crate::schema::IndexType::Single { field }
+                        | crate::schema::IndexType::Hashed { field }
+                        | crate::schema::IndexType::Vector { field, .. } => vec![IndexField {
                             field: field.clone(),
                             direction: 1,
                         }],
@@ -675,7 +683,7 @@ impl SyncManager {
                             return Ok(());
                         }
                     };
-                    storage.create_index(collection, &index.name, fields, index.unique)
+                    storage.create_index(collection, &index.name, fields, IndexOptions::from(index))
                          .map_err(|e| ReplicationError::StorageError(e.to_string()))?;
                 }
                 DropIndex { collection, index_name } => {
@@ -3,10 +3,15 @@
 //! This module provides schema definition, field validation, and JSON Schema support
 
 use crate::document::{Document, Value};
+use crate::query::{Collation, Filter};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
+pub mod validators;
+
+pub use validators::{CustomValidator, Expression, ExpressionError, ValidationContext, ValidatorRegistry};
+
 /// Schema definition for a collection
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Schema {
@@ -20,6 +25,15 @@ pub struct Schema {
     pub cache_config: CollectionCacheConfig,
     /// Optional JSON Schema for validation
     pub validation: Option<String>,
+    /// Reject fields without a definition
+    #[serde(default)]
+    pub strict: bool,
+    /// Whether a failed validation rejects the write or only logs it
+    #[serde(default)]
+    pub validation_action: ValidationAction,
+    /// Validators applied to the whole document, given as an object value
+    #[serde(default)]
+    pub validators: Vec<Validator>,
 }
 
 impl Schema {
@@ -31,7 +45,54 @@ impl Schema {
             indexes: Vec::new(),
             cache_config: CollectionCacheConfig::default(),
             validation: None,
+            strict: false,
+            validation_action: ValidationAction::Error,
+            validators: Vec::new(),
+        }
+    }
+
+    /// Parse a schema from a JSON Schema subset or the native field form
+    ///
+    /// `{"$jsonSchema": {...}}`, or an object carrying `bsonType`, `type` or
+    /// `properties`, is read as JSON Schema: `additionalProperties: false` at
+    /// the top level turns on strict mode.
+    /// `{"fields": {...}, "strict": bool, "validators": [...]}` holds
+    /// serialized [`FieldDefinition`]s and document validators.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, ValidationError> {
+        let serde_json::Value::Object(root) = json else {
+            return Err(ValidationError::InvalidSchema("schema must be an object".to_string()));
+        };
+
+        let mut schema = Schema::new();
+        if root.contains_key("fields") {
+            let native: NativeSchema = serde_json::from_value(json.clone())
+                .map_err(|e| ValidationError::InvalidSchema(e.to_string()))?;
+            schema.fields = native.fields;
+            schema.strict = native.strict;
+            schema.validators = native.validators;
+        } else {
+            let spec = match root.get("$jsonSchema") {
+                Some(spec) if root.len() == 1 => spec,
+                Some(_) => {
+                    return Err(ValidationError::InvalidSchema(
+                        "$jsonSchema must be the only top-level key".to_string(),
+                    ))
+                }
+                None => json,
+            };
+            let (fields, strict) = json_schema::object_fields(spec, "")?;
+            schema.fields = fields;
+            schema.strict = strict;
+            schema.validation = Some(spec.to_string());
+        }
+
+        for (name, field) in &schema.fields {
+            field.check_validators(name)?;
+        }
+        for validator in &schema.validators {
+            validator.check()?;
         }
+        Ok(schema)
     }
 
     /// Add a field definition
@@ -45,7 +106,17 @@ impl Schema {
     }
 
     /// Validate a document against this schema
+    ///
+    /// [`Validator::Custom`] validators fail as unknown; see
+    /// [`validate_with`](Self::validate_with) to run registered ones.
     pub fn validate(&self, doc: &Document) -> Result<(), ValidationError> {
+        self.validate_with(doc, &ValidatorRegistry::new())
+    }
+
+    /// Validate a document, resolving custom validators in `registry`
+    pub fn validate_with(&self, doc: &Document, registry: &ValidatorRegistry) -> Result<(), ValidationError> {
+        let context = ValidationContext { document: doc, registry };
+
         // Validate each field
         for (field_name, field_def) in &self.fields {
             let value = doc.get(field_name);
@@ -57,18 +128,25 @@ impl Schema {
 
             // Validate field if present
             if let Some(value) = value {
-                field_def.validate(field_name, value)?;
+                field_def.validate_in(field_name, value, &context)?;
             }
         }
 
-        // Check for unknown fields if strict mode
-        for field_name in doc.fields.keys() {
-            if !self.fields.contains_key(field_name) {
-                // Allow _id field
-                if field_name != "_id" {
-                    // In non-strict mode, we allow unknown fields
-                    // In strict mode, this would be an error
-                }
+        // Strict mode rejects fields without a definition; _id is always allowed
+        if self.strict {
+            let unknown = doc
+                .fields
+                .keys()
+                .find(|name| name.as_str() != "_id" && !self.fields.contains_key(*name));
+            if let Some(name) = unknown {
+                return Err(ValidationError::UnknownField(name.clone()));
+            }
+        }
+
+        if !self.validators.is_empty() {
+            let root = Value::Object(doc.fields.clone());
+            for validator in &self.validators {
+                validator.validate_in("", &root, &context)?;
             }
         }
 
@@ -94,26 +172,236 @@ impl Default for Schema {
     }
 }
 
+/// What happens to a write that fails schema validation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationAction {
+    /// Reject the write
+    #[default]
+    Error,
+    /// Accept the write and log a warning
+    Warn,
+}
+
+/// Native schema form: serialized field definitions plus strict mode
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NativeSchema {
+    fields: BTreeMap<String, FieldDefinition>,
+    #[serde(default)]
+    strict: bool,
+    #[serde(default)]
+    validators: Vec<Validator>,
+}
+
+/// Translation of the supported JSON Schema keywords into field definitions
+mod json_schema {
+    use super::{FieldDefinition, FieldType, ValidationError, Validator};
+    use crate::query::parser::QueryParser;
+    use serde_json::{Map, Value as JsonValue};
+    use std::collections::BTreeMap;
+
+    fn invalid(path: &str, message: impl std::fmt::Display) -> ValidationError {
+        if path.is_empty() {
+            ValidationError::InvalidSchema(message.to_string())
+        } else {
+            ValidationError::InvalidSchema(format!("'{}': {}", path, message))
+        }
+    }
+
+    fn as_object<'a>(spec: &'a JsonValue, path: &str) -> Result<&'a Map<String, JsonValue>, ValidationError> {
+        spec.as_object().ok_or_else(|| invalid(path, "schema must be an object"))
+    }
+
+    fn type_name<'a>(spec: &'a Map<String, JsonValue>, path: &str) -> Result<Option<&'a str>, ValidationError> {
+        if spec.contains_key("bsonType") && spec.contains_key("type") {
+            return Err(invalid(path, "use either bsonType or type, not both"));
+        }
+        match spec.get("bsonType").or_else(|| spec.get("type")) {
+            None => Ok(None),
+            Some(JsonValue::String(name)) => Ok(Some(name)),
+            Some(_) => Err(invalid(path, "type must be a single type name")),
+        }
+    }
+
+    fn length(value: &JsonValue, path: &str, keyword: &str) -> Result<usize, ValidationError> {
+        value
+            .as_u64()
+            .map(|n| n as usize)
+            .ok_or_else(|| invalid(path, format!("{} must be a non-negative integer", keyword)))
+    }
+
+    fn bound(value: &JsonValue, path: &str, keyword: &str) -> Result<f64, ValidationError> {
+        value
+            .as_f64()
+            .ok_or_else(|| invalid(path, format!("{} must be a number", keyword)))
+    }
+
+    fn value(json: &JsonValue, path: &str) -> Result<crate::document::Value, ValidationError> {
+        QueryParser::json_to_value(json).map_err(|e| invalid(path, e))
+    }
+
+    /// Fields of an object schema and whether it forbids other properties
+    pub(super) fn object_fields(
+        spec: &JsonValue,
+        path: &str,
+    ) -> Result<(BTreeMap<String, FieldDefinition>, bool), ValidationError> {
+        let spec = as_object(spec, path)?;
+        match type_name(spec, path)? {
+            None | Some("object") => {}
+            Some(other) => return Err(invalid(path, format!("expected an object schema, got '{}'", other))),
+        }
+
+        let mut fields = BTreeMap::new();
+        let mut strict = false;
+        let mut required = Vec::new();
+        for (keyword, argument) in spec {
+            match keyword.as_str() {
+                "bsonType" | "type" | "title" | "description" => {}
+                "properties" => {
+                    let properties = argument
+                        .as_object()
+                        .ok_or_else(|| invalid(path, "properties must be an object"))?;
+                    for (name, property) in properties {
+                        let field_path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+                        fields.insert(name.clone(), field_definition(property, &field_path)?);
+                    }
+                }
+                "required" => {
+                    let names = argument
+                        .as_array()
+                        .ok_or_else(|| invalid(path, "required must be an array of field names"))?;
+                    for name in names {
+                        let name = name
+                            .as_str()
+                            .ok_or_else(|| invalid(path, "required must be an array of field names"))?;
+                        required.push(name.to_string());
+                    }
+                }
+                "additionalProperties" => {
+                    strict = !argument
+                        .as_bool()
+                        .ok_or_else(|| invalid(path, "additionalProperties must be a boolean"))?;
+                }
+                other => return Err(invalid(path, format!("unsupported keyword '{}'", other))),
+            }
+        }
+
+        for name in required {
+            fields
+                .entry(name)
+                .or_insert_with(|| FieldDefinition::new(FieldType::Any))
+                .required = true;
+        }
+        Ok((fields, strict))
+    }
+
+    /// Definition of one property
+    fn field_definition(spec: &JsonValue, path: &str) -> Result<FieldDefinition, ValidationError> {
+        let object = as_object(spec, path)?;
+        let is_object = match type_name(object, path)? {
+            Some(name) => name == "object",
+            None => object.contains_key("properties"),
+        };
+        if is_object {
+            let (fields, strict) = object_fields(spec, path)?;
+            if strict {
+                return Err(invalid(path, "additionalProperties is supported only at the top level"));
+            }
+            return Ok(FieldDefinition::new(FieldType::Object { fields }));
+        }
+
+        let field_type = match type_name(object, path)? {
+            None => FieldType::Any,
+            Some("string") => FieldType::String { max_length: None },
+            Some("int") => FieldType::Int32,
+            Some("long") | Some("integer") => FieldType::Int64,
+            Some("double") | Some("number") => FieldType::Float64,
+            Some("decimal") => FieldType::Decimal128,
+            Some("bool") | Some("boolean") => FieldType::Boolean,
+            Some("date") => FieldType::Date,
+            Some("timestamp") => FieldType::Timestamp,
+            Some("binData") => FieldType::Binary,
+            Some("objectId") => FieldType::ObjectId,
+            Some("array") => {
+                let item_type = match object.get("items") {
+                    Some(items) => {
+                        let item = field_definition(items, &format!("{}[]", path))?;
+                        if !item.validators.is_empty() || item.default.is_some() {
+                            return Err(invalid(path, "items may only constrain the element type"));
+                        }
+                        item.field_type
+                    }
+                    None => FieldType::Any,
+                };
+                FieldType::Array { item_type: Box::new(item_type) }
+            }
+            Some(other) => return Err(invalid(path, format!("unknown type '{}'", other))),
+        };
+
+        let mut field = FieldDefinition::new(field_type);
+        for (keyword, argument) in object {
+            match keyword.as_str() {
+                "bsonType" | "type" | "title" | "description" => {}
+                "items" if matches!(field.field_type, FieldType::Array { .. }) => {}
+                "minimum" => field.validators.push(Validator::Min(bound(argument, path, keyword)?)),
+                "maximum" => field.validators.push(Validator::Max(bound(argument, path, keyword)?)),
+                "minLength" | "minItems" => {
+                    field.validators.push(Validator::MinLength(length(argument, path, keyword)?))
+                }
+                "maxLength" | "maxItems" => {
+                    field.validators.push(Validator::MaxLength(length(argument, path, keyword)?))
+                }
+                "pattern" => {
+                    let pattern = argument
+                        .as_str()
+                        .ok_or_else(|| invalid(path, "pattern must be a string"))?;
+                    field.validators.push(Validator::Regex(pattern.to_string()));
+                }
+                "enum" => {
+                    let allowed = argument
+                        .as_array()
+                        .ok_or_else(|| invalid(path, "enum must be an array"))?
+                        .iter()
+                        .map(|allowed| value(allowed, path))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    field.validators.push(Validator::Enum(allowed));
+                }
+                "default" => field.default = Some(value(argument, path)?),
+                other => return Err(invalid(path, format!("unsupported keyword '{}'", other))),
+            }
+        }
+        Ok(field)
+    }
+}
+
 /// Field definition with type, constraints, and validators
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FieldDefinition {
     /// Field type
     pub field_type: FieldType,
     /// Whether the field is required
+    #[serde(default)]
     pub required: bool,
     /// Default value if not provided
+    #[serde(default)]
     pub default: Option<Value>,
     /// Whether the field must be unique
+    #[serde(default)]
     pub unique: bool,
     /// Whether the field should be indexed
+    #[serde(default)]
     pub indexed: bool,
     /// Validators to apply
+    #[serde(default)]
     pub validators: Vec<Validator>,
     
     // Encryption settings (Phase 5)
     /// Is this field encrypted at rest?
+    #[serde(default)]
     pub encrypted: bool,
     /// Encryption key ID (if encrypted)
+    #[serde(default)]
     pub encryption_key_id: Option<String>,
 }
 
@@ -163,9 +451,27 @@ impl FieldDefinition {
     }
 
     /// Validate a value against this field definition
+    ///
+    /// Custom and expression validators see a document holding only this field.
     pub fn validate(&self, field_name: &str, value: &Value) -> Result<(), ValidationError> {
-        // Check type compatibility
-        if !self.field_type.is_compatible(value) {
+        let document = detached_document(field_name, value);
+        let context = ValidationContext { document: &document, registry: &ValidatorRegistry::new() };
+        self.validate_in(field_name, value, &context)
+    }
+
+    /// Validate a value found at `field_name` in the context's document
+    pub fn validate_in(&self, field_name: &str, value: &Value, context: &ValidationContext) -> Result<(), ValidationError> {
+        // Nested objects are validated field by field so their validators apply
+        if let (FieldType::Object { fields }, Value::Object(obj)) = (&self.field_type, value) {
+            for (name, field_def) in fields {
+                let path = format!("{}.{}", field_name, name);
+                match obj.get(name) {
+                    Some(value) => field_def.validate_in(&path, value, context)?,
+                    None if field_def.required => return Err(ValidationError::RequiredFieldMissing(path)),
+                    None => {}
+                }
+            }
+        } else if !self.field_type.is_compatible(value) {
             return Err(ValidationError::TypeMismatch {
                 field: field_name.to_string(),
                 expected: format!("{:?}", self.field_type),
@@ -175,11 +481,24 @@ impl FieldDefinition {
 
         // Apply validators
         for validator in &self.validators {
-            validator.validate(field_name, value)?;
+            validator.validate_in(field_name, value, context)?;
         }
 
         Ok(())
     }
+
+    /// Reject regex and expression validators that do not compile, here or in nested fields
+    fn check_validators(&self, field_name: &str) -> Result<(), ValidationError> {
+        for validator in &self.validators {
+            validator.check()?;
+        }
+        if let FieldType::Object { fields } = &self.field_type {
+            for (name, field_def) in fields {
+                field_def.check_validators(&format!("{}.{}", field_name, name))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Field type enumeration
@@ -193,12 +512,18 @@ pub enum FieldType {
     Int64,
     /// 64-bit floating point
     Float64,
+    /// Exact decimal; integers are accepted too
+    Decimal128,
     /// Boolean
     Boolean,
     /// Date/time
     Date,
-    /// Binary data
+    /// BSON-style timestamp
+    Timestamp,
+    /// Binary data, UUIDs included
     Binary,
+    /// UUID
+    Uuid,
     /// ObjectId
     ObjectId,
     /// Array with item type
@@ -207,8 +532,16 @@ pub enum FieldType {
     Object {
         fields: BTreeMap<String, FieldDefinition>,
     },
-    /// Reference to another collection
-    Reference { collection: String },
+    /// Reference to a document of another collection, held as its id string
+    Reference {
+        collection: String,
+        /// Reject inserts and updates whose referenced document does not exist
+        #[serde(default)]
+        enforce: bool,
+        /// What deleting the referenced document does to this document
+        #[serde(default)]
+        on_delete: OnDelete,
+    },
     /// Any type (no validation)
     Any,
 }
@@ -223,9 +556,12 @@ impl FieldType {
             (FieldType::Float64, Value::Float64(_))
             | (FieldType::Float64, Value::Int32(_))
             | (FieldType::Float64, Value::Int64(_)) => true,
+            (FieldType::Decimal128, Value::Decimal128(_) | Value::Int32(_) | Value::Int64(_)) => true,
             (FieldType::Boolean, Value::Bool(_)) => true,
             (FieldType::Date, Value::DateTime(_)) => true,
-            (FieldType::Binary, Value::Binary(_)) => true,
+            (FieldType::Timestamp, Value::Timestamp(_)) => true,
+            (FieldType::Binary, Value::Binary(_) | Value::Uuid(_)) => true,
+            (FieldType::Uuid, Value::Uuid(_)) => true,
             (FieldType::ObjectId, Value::ObjectId(_)) => true,
             (FieldType::Array { item_type }, Value::Array(arr)) => {
                 arr.iter().all(|v| item_type.is_compatible(v))
@@ -244,13 +580,29 @@ impl FieldType {
                 }
                 true
             }
-            (FieldType::Reference { .. }, Value::ObjectId(_)) => true,
+            (FieldType::Reference { .. }, Value::ObjectId(_) | Value::Null) => true,
+            (FieldType::Reference { .. }, Value::String(id)) => uuid::Uuid::parse_str(id).is_ok(),
             (FieldType::Any, _) => true,
             _ => false,
         }
     }
 }
 
+/// Effect of deleting a referenced document on the documents referencing it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnDelete {
+    /// Leave the reference dangling
+    #[default]
+    Ignore,
+    /// Refuse the delete while references exist
+    Restrict,
+    /// Delete the referencing documents too
+    Cascade,
+    /// Set the reference to null
+    SetNull,
+}
+
 /// Validator for field values
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Validator {
@@ -266,13 +618,24 @@ pub enum Validator {
     Regex(String),
     /// Enum of allowed values
     Enum(Vec<Value>),
-    /// Custom validator (JavaScript function name)
+    /// Name of a validator registered in a [`ValidatorRegistry`]
     Custom(String),
+    /// Boolean expression over the document's fields, e.g. `end_date > start_date`
+    Expression(String),
 }
 
 impl Validator {
     /// Validate a value
+    ///
+    /// Custom and expression validators see a document holding only this field.
     pub fn validate(&self, field_name: &str, value: &Value) -> Result<(), ValidationError> {
+        let document = detached_document(field_name, value);
+        let context = ValidationContext { document: &document, registry: &ValidatorRegistry::new() };
+        self.validate_in(field_name, value, &context)
+    }
+
+    /// Validate a value found at `field_name` in the context's document
+    pub fn validate_in(&self, field_name: &str, value: &Value, context: &ValidationContext) -> Result<(), ValidationError> {
         match self {
             Validator::Min(min) => {
                 if let Some(num) = value.as_f64() {
@@ -352,17 +715,47 @@ impl Validator {
                     });
                 }
             }
-            Validator::Custom(_func_name) => {
-                // Custom validators would be implemented by the application
-                // For now, we just pass validation
+            Validator::Custom(name) => {
+                context.registry.validate(name, field_name, value, context.document)?;
+            }
+            Validator::Expression(source) => {
+                validators::check_expression(source, field_name, context.document)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject regex patterns and expressions that do not compile
+    fn check(&self) -> Result<(), ValidationError> {
+        match self {
+            Validator::Regex(pattern) => {
+                Regex::new(pattern).map_err(|e| ValidationError::InvalidRegex {
+                    pattern: pattern.clone(),
+                    error: e.to_string(),
+                })?;
+            }
+            Validator::Expression(source) => {
+                Expression::parse(source).map_err(|e| {
+                    ValidationError::InvalidSchema(format!("invalid validator expression '{}': {}", source, e))
+                })?;
             }
+            _ => {}
         }
         Ok(())
     }
 }
 
+/// A document holding only `value` at `path`, for validating a value on its own
+fn detached_document(path: &str, value: &Value) -> Document {
+    let mut document = Document::new();
+    if !path.is_empty() && document.set_by_path(path, value.clone()).is_err() {
+        document.insert(path.to_string(), value.clone());
+    }
+    document
+}
+
 /// Index definition
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IndexDefinition {
     /// Index name
     pub name: String,
@@ -372,6 +765,18 @@ pub struct IndexDefinition {
     pub unique: bool,
     /// Whether the index is sparse (only indexes documents with the field)
     pub sparse: bool,
+    /// Sort direction per indexed field (1 ascending, -1 descending); empty means all ascending
+    #[serde(default)]
+    pub directions: Vec<i32>,
+    /// Only documents matching this filter are indexed
+    #[serde(default)]
+    pub partial_filter: Option<Filter>,
+    /// Documents expire this many seconds after the date in the indexed field
+    #[serde(default)]
+    pub expire_after_seconds: Option<u64>,
+    /// String comparison rules applied to the index keys
+    #[serde(default)]
+    pub collation: Option<Collation>,
 }
 
 impl IndexDefinition {
@@ -382,6 +787,10 @@ impl IndexDefinition {
             index_type: IndexType::Single { field },
             unique: false,
             sparse: false,
+            directions: Vec::new(),
+            partial_filter: None,
+            expire_after_seconds: None,
+            collation: None,
         }
     }
 
@@ -393,9 +802,70 @@ impl IndexDefinition {
             index_type: IndexType::Compound { fields },
             unique: false,
             sparse: false,
+            directions: Vec::new(),
+            partial_filter: None,
+            expire_after_seconds: None,
+            collation: None,
+        }
+    }
+
+    /// Create a hashed index for equality lookups on one field
+    pub fn hashed(field: String) -> Self {
+        Self {
+            name: format!("idx_{}_hashed", field),
+            index_type: IndexType::Hashed { field },
+            unique: false,
+            sparse: false,
+            directions: Vec::new(),
+            partial_filter: None,
+            expire_after_seconds: None,
+            collation: None,
+        }
+    }
+
+    /// Create a vector index of `dims`-dimensional embeddings
+    pub fn vector(field: String, dims: usize, metric: VectorMetric) -> Self {
+        Self {
+            name: format!("idx_{}_vector", field),
+            index_type: IndexType::Vector { field, dims, metric },
+            unique: false,
+            sparse: false,
+            directions: Vec::new(),
+            partial_filter: None,
+            expire_after_seconds: None,
+            collation: None,
         }
     }
 
+    /// Set the index name
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Set per-field sort directions (1 ascending, -1 descending)
+    pub fn with_directions(mut self, directions: Vec<i32>) -> Self {
+        self.directions = directions;
+        self
+    }
+
+    /// Indexed fields in key order
+    pub fn fields(&self) -> Vec<String> {
+        match &self.index_type {
+            IndexType::Single { field }
+            | IndexType::Text { field }
+            | IndexType::Hashed { field }
+            | IndexType::Vector { field, .. }
+            | IndexType::Geospatial { field } => vec![field.clone()],
+            IndexType::Compound { fields } => fields.clone(),
+        }
+    }
+
+    /// Whether the field at `position` is stored in descending order
+    pub fn is_descending(&self, position: usize) -> bool {
+        self.directions.get(position).map(|d| *d < 0).unwrap_or(false)
+    }
+
     /// Set as unique
     pub fn unique(mut self) -> Self {
         self.unique = true;
@@ -407,10 +877,46 @@ impl IndexDefinition {
         self.sparse = true;
         self
     }
+
+    /// Index only the documents matching `filter`
+    pub fn with_partial_filter(mut self, filter: Filter) -> Self {
+        self.partial_filter = Some(filter);
+        self
+    }
+
+    /// Expire documents `seconds` after the date held in the indexed field
+    pub fn expire_after(mut self, seconds: u64) -> Self {
+        self.expire_after_seconds = Some(seconds);
+        self
+    }
+
+    /// Compare the indexed strings under `collation`
+    pub fn with_collation(mut self, collation: Collation) -> Self {
+        self.collation = Some(collation);
+        self
+    }
+
+    /// Whether the index stores hashes of the field values
+    pub fn is_hashed(&self) -> bool {
+        matches!(self.index_type, IndexType::Hashed { .. })
+    }
+
+    /// Dimensions and metric of a vector index
+    pub fn vector_spec(&self) -> Option<(usize, VectorMetric)> {
+        match &self.index_type {
+            IndexType::Vector { dims, metric, .. } => Some((*dims, *metric)),
+            _ => None,
+        }
+    }
+
+    /// Whether every document of the collection has an entry
+    pub fn covers_all_documents(&self) -> bool {
+        !self.sparse && self.partial_filter.is_none() && self.vector_spec().is_none()
+    }
 }
 
 /// Index type
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum IndexType {
     /// Single field index
     Single { field: String },
@@ -418,20 +924,39 @@ pub enum IndexType {
     Compound { fields: Vec<String> },
     /// Text index for full-text search
     Text { field: String },
+    /// Index on a hash of the field value, for equality lookups only
+    Hashed { field: String },
+    /// Nearest-neighbour index over fixed-length numeric arrays
+    Vector { field: String, dims: usize, metric: VectorMetric },
     /// Geospatial index (future)
     Geospatial { field: String },
 }
 
+/// How a vector index measures similarity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorMetric {
+    /// Angle between the vectors, ignoring their length
+    Cosine,
+    /// Euclidean distance
+    L2,
+    /// Dot product, for vectors normalized by the client
+    Dot,
+}
+
 /// Cache configuration for a collection
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CollectionCacheConfig {
     /// Cache strategy
     pub strategy: CacheStrategy,
     /// Time-to-live in seconds
+    #[serde(default)]
     pub ttl: Option<u64>,
     /// Specific fields to cache (None means all fields)
+    #[serde(default)]
     pub fields: Option<Vec<String>>,
     /// Cache warming strategy
+    #[serde(default)]
     pub warming: CacheWarmingStrategy,
 }
 
@@ -460,9 +985,10 @@ pub enum CacheStrategy {
 }
 
 /// Cache warming strategy
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CacheWarmingStrategy {
     /// No warming
+    #[default]
     None,
     /// Preload on startup
     PreloadOnStartup { limit: usize },
@@ -532,6 +1058,18 @@ pub enum ValidationError {
 
     #[error("Unique constraint violation for field '{0}'")]
     UniqueConstraintViolation(String),
+
+    #[error("Unknown field '{0}' is not allowed by the strict schema")]
+    UnknownField(String),
+
+    #[error("Invalid schema: {0}")]
+    InvalidSchema(String),
+
+    #[error("Custom validation '{validator}' failed: {message}")]
+    CustomValidationFailed { validator: String, message: String },
+
+    #[error("Custom validator '{0}' is not registered")]
+    UnknownValidator(String),
 }
 
 #[cfg(test)]
@@ -579,6 +1117,13 @@ mod tests {
         assert!(!array_type.is_compatible(&Value::Array(vec![
             Value::String("test".to_string())
         ])));
+
+        assert!(FieldType::Decimal128.is_compatible(&Value::Decimal128("1.50".parse().unwrap())));
+        assert!(FieldType::Decimal128.is_compatible(&Value::Int64(2)));
+        assert!(!FieldType::Decimal128.is_compatible(&Value::Float64(0.1)));
+        assert!(FieldType::Binary.is_compatible(&Value::Uuid(uuid::Uuid::new_v4())));
+        assert!(!FieldType::Uuid.is_compatible(&Value::Binary(vec![0; 16])));
+        assert!(FieldType::Timestamp.is_compatible(&Value::Timestamp(crate::document::Timestamp::new(1, 0))));
     }
 
     #[test]
@@ -689,6 +1234,179 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_schema_strict_mode() {
+        let mut schema = Schema::new();
+        schema.add_field("name".to_string(), FieldDefinition::new(FieldType::String { max_length: None }));
+
+        let mut doc = Document::with_id(DocumentId::new());
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        doc.insert("nickname".to_string(), Value::String("JJ".to_string()));
+        assert!(schema.validate(&doc).is_ok());
+
+        schema.strict = true;
+        assert!(matches!(schema.validate(&doc), Err(ValidationError::UnknownField(field)) if field == "nickname"));
+        doc.remove("nickname");
+        doc.insert("_id".to_string(), Value::String("user-1".to_string()));
+        assert!(schema.validate(&doc).is_ok());
+    }
+
+    #[test]
+    fn test_schema_from_json_schema() {
+        let json = serde_json::json!({
+            "$jsonSchema": {
+                "bsonType": "object",
+                "required": ["email", "address"],
+                "additionalProperties": false,
+                "properties": {
+                    "email": {"bsonType": "string", "pattern": "^[^@]+@[^@]+$"},
+                    "age": {"bsonType": "int", "minimum": 0, "maximum": 150},
+                    "status": {"enum": ["active", "banned"], "default": "active"},
+                    "tags": {"bsonType": "array", "items": {"bsonType": "string"}, "maxItems": 2},
+                    "address": {
+                        "bsonType": "object",
+                        "required": ["city"],
+                        "properties": {"city": {"bsonType": "string", "minLength": 2}}
+                    }
+                }
+            }
+        });
+        let schema = Schema::from_json(&json).unwrap();
+        assert!(schema.strict);
+        assert!(schema.fields["email"].required);
+        assert!(schema.validation.is_some());
+
+        let mut address = BTreeMap::new();
+        address.insert("city".to_string(), Value::String("Oslo".to_string()));
+        let mut doc = Document::with_id(DocumentId::new());
+        doc.insert("email".to_string(), Value::String("a@example.com".to_string()));
+        doc.insert("age".to_string(), Value::Int32(30));
+        doc.insert("tags".to_string(), Value::Array(vec![Value::String("x".to_string())]));
+        doc.insert("address".to_string(), Value::Object(address.clone()));
+        schema.apply_defaults(&mut doc).unwrap();
+        assert_eq!(doc.get("status").unwrap().as_str(), Some("active"));
+        assert!(schema.validate(&doc).is_ok());
+
+        let invalid = |field: &str, value: Value| {
+            let mut bad = doc.clone();
+            bad.insert(field.to_string(), value);
+            schema.validate(&bad).unwrap_err()
+        };
+        assert!(matches!(invalid("email", Value::String("nope".to_string())), ValidationError::RegexMismatch { .. }));
+        assert!(matches!(invalid("age", Value::Int32(200)), ValidationError::MaxValueViolation { .. }));
+        assert!(matches!(invalid("age", Value::Int64(20)), ValidationError::TypeMismatch { .. }));
+        assert!(matches!(invalid("status", Value::String("gone".to_string())), ValidationError::EnumViolation { .. }));
+        assert!(matches!(invalid("tags", Value::Array(vec![Value::Int32(1)])), ValidationError::TypeMismatch { .. }));
+        assert!(matches!(invalid("extra", Value::Null), ValidationError::UnknownField(_)));
+
+        // Nested fields keep their own validators and required flags
+        address.insert("city".to_string(), Value::String("X".to_string()));
+        assert!(matches!(
+            invalid("address", Value::Object(address)),
+            ValidationError::MinLengthViolation { field, .. } if field == "address.city"
+        ));
+        assert!(matches!(
+            invalid("address", Value::Object(BTreeMap::new())),
+            ValidationError::RequiredFieldMissing(field) if field == "address.city"
+        ));
+        let mut missing = doc.clone();
+        missing.remove("email");
+        assert!(matches!(schema.validate(&missing), Err(ValidationError::RequiredFieldMissing(field)) if field == "email"));
+    }
+
+    #[test]
+    fn test_schema_from_native_form() {
+        let json = serde_json::json!({
+            "fields": {
+                "name": {"field_type": {"String": {"max_length": null}}, "required": true},
+                "age": {"field_type": "Int64", "validators": [{"Min": 0.0}]}
+            },
+            "strict": true
+        });
+        let schema = Schema::from_json(&json).unwrap();
+        assert!(schema.strict);
+        assert!(schema.fields["name"].required);
+        assert!(schema.validation.is_none());
+
+        let mut doc = Document::with_id(DocumentId::new());
+        doc.insert("name".to_string(), Value::String("Ann".to_string()));
+        doc.insert("age".to_string(), Value::Int32(-1));
+        assert!(matches!(schema.validate(&doc), Err(ValidationError::MinValueViolation { .. })));
+    }
+
+    #[test]
+    fn test_expression_and_custom_validators() {
+        let json = serde_json::json!({
+            "fields": {
+                "end_date": {"field_type": "Date", "validators": [{"Expression": "end_date > start_date"}]},
+                "sku": {"field_type": {"String": {"max_length": null}}, "validators": [{"Custom": "known_sku"}]}
+            },
+            "validators": [{"Expression": "len(items) <= max_items"}]
+        });
+        let schema = Schema::from_json(&json).unwrap();
+        let registry = ValidatorRegistry::new();
+        registry.register("known_sku", |value, _| match value.as_str() {
+            Some(sku) if sku.starts_with("SKU-") => Ok(()),
+            _ => Err("unknown SKU".to_string()),
+        });
+
+        let start = chrono::Utc::now();
+        let mut doc = Document::with_id(DocumentId::new());
+        doc.insert("start_date".to_string(), Value::DateTime(start));
+        doc.insert("end_date".to_string(), Value::DateTime(start + chrono::Duration::hours(1)));
+        doc.insert("items".to_string(), Value::Array(vec![Value::Int32(1)]));
+        doc.insert("max_items".to_string(), Value::Int32(1));
+        doc.insert("sku".to_string(), Value::String("SKU-1".to_string()));
+        schema.validate_with(&doc, &registry).unwrap();
+
+        // Without a registry the custom validator is unknown
+        assert!(matches!(schema.validate(&doc), Err(ValidationError::UnknownValidator(_))));
+
+        let mut invalid = doc.clone();
+        invalid.insert("end_date".to_string(), Value::DateTime(start));
+        let err = schema.validate_with(&invalid, &registry).unwrap_err();
+        assert!(matches!(err, ValidationError::CustomValidationFailed { ref validator, .. } if validator == "end_date > start_date"));
+
+        let mut invalid = doc.clone();
+        invalid.insert("sku".to_string(), Value::String("X".to_string()));
+        let err = schema.validate_with(&invalid, &registry).unwrap_err();
+        assert_eq!(err.to_string(), "Custom validation 'known_sku' failed: field 'sku': unknown SKU");
+
+        let mut invalid = doc.clone();
+        invalid.insert("max_items".to_string(), Value::Int32(0));
+        let err = schema.validate_with(&invalid, &registry).unwrap_err();
+        assert_eq!(err.to_string(), "Custom validation 'len(items) <= max_items' failed: expression is false");
+
+        // A field validator on its own sees only its field
+        let validator = Validator::Expression("quantity >= 1".to_string());
+        assert!(validator.validate("quantity", &Value::Int32(1)).is_ok());
+        assert!(validator.validate("quantity", &Value::Int32(0)).is_err());
+
+        let bad = serde_json::json!({"fields": {}, "validators": [{"Expression": "a >"}]});
+        assert!(matches!(Schema::from_json(&bad), Err(ValidationError::InvalidSchema(_))));
+    }
+
+    #[test]
+    fn test_schema_from_json_rejects_unsupported_schemas() {
+        let rejected = [
+            serde_json::json!([]),
+            serde_json::json!({"properties": {"a": {"bsonType": "uuid"}}}),
+            serde_json::json!({"properties": {"a": {"bsonType": "string", "format": "email"}}}),
+            serde_json::json!({"properties": {"a": {"bsonType": ["string", "null"]}}}),
+            serde_json::json!({"properties": {"a": {"bsonType": "int", "minimum": "1"}}}),
+            serde_json::json!({"properties": {"a": {"bsonType": "object", "additionalProperties": false}}}),
+            serde_json::json!({"bsonType": "array"}),
+            serde_json::json!({"$jsonSchema": {}, "extra": 1}),
+            serde_json::json!({"fields": {}, "unknown": true}),
+        ];
+        for json in rejected {
+            assert!(matches!(Schema::from_json(&json), Err(ValidationError::InvalidSchema(_))), "{}", json);
+        }
+
+        let bad_pattern = serde_json::json!({"properties": {"a": {"bsonType": "string", "pattern": "("}}});
+        assert!(matches!(Schema::from_json(&bad_pattern), Err(ValidationError::InvalidRegex { .. })));
+    }
+
     #[test]
     fn test_index_definition() {
         let index = IndexDefinition::single("email".to_string()).unique();
@@ -697,6 +1415,12 @@ mod tests {
 
         let compound = IndexDefinition::compound(vec!["first_name".to_string(), "last_name".to_string()]);
         assert_eq!(compound.name, "idx_first_name_last_name");
+
+        let directed = compound.with_directions(vec![1, -1]);
+        assert!(!directed.is_descending(0));
+        assert!(directed.is_descending(1));
+        assert!(!directed.is_descending(2));
+        assert_eq!(directed.fields(), vec!["first_name".to_string(), "last_name".to_string()]);
     }
 
     #[test]
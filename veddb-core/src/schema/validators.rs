@@ -0,0 +1,758 @@
+//! Custom validators: named Rust closures and validator expressions
+//!
+//! [`Validator::Custom`](super::Validator::Custom) names a closure registered
+//! in a [`ValidatorRegistry`] by the embedding application.
+//! [`Validator::Expression`](super::Validator::Expression) holds a boolean
+//! expression over the document's fields, such as `end_date > start_date`
+//! or `len(items) <= max_items`.
+//!
+//! Expressions support field paths (`address.city`), number, string,
+//! `true`, `false` and `null` literals, `+ - * / %`, the comparisons
+//! `== != < <= > >=`, `&&`/`and`, `||`/`or`, `!`/`not`, parentheses and the
+//! functions `len`, `exists`, `abs`, `lower`, `upper` and `now`. Ordering
+//! comparisons between values of different kinds, or with a missing field,
+//! are false.
+
+use super::ValidationError;
+use crate::document::{Decimal128, DecimalError, Document, Value};
+use chrono::Utc;
+use parking_lot::RwLock;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A custom validator: receives the validated value and its document, and
+/// returns a message describing the failure
+pub type CustomValidator = dyn Fn(&Value, &Document) -> Result<(), String> + Send + Sync;
+
+/// Named custom validators registered by the embedding application
+#[derive(Default)]
+pub struct ValidatorRegistry {
+    validators: RwLock<HashMap<String, Arc<CustomValidator>>>,
+}
+
+impl ValidatorRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a validator under `name`, replacing any previous one
+    ///
+    /// Returns whether a validator with that name was replaced.
+    pub fn register<F>(&self, name: impl Into<String>, validator: F) -> bool
+    where
+        F: Fn(&Value, &Document) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.validators.write().insert(name.into(), Arc::new(validator)).is_some()
+    }
+
+    /// Remove a validator; returns whether it was registered
+    pub fn unregister(&self, name: &str) -> bool {
+        self.validators.write().remove(name).is_some()
+    }
+
+    /// Whether a validator is registered under `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.validators.read().contains_key(name)
+    }
+
+    /// Names of the registered validators, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.validators.read().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Run the validator registered under `name`
+    ///
+    /// `field` names the validated value in the failure message; it is empty
+    /// for validators applied to the whole document.
+    pub fn validate(&self, name: &str, field: &str, value: &Value, doc: &Document) -> Result<(), ValidationError> {
+        // Cloned out so a validator may use the registry itself
+        let validator = self
+            .validators
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ValidationError::UnknownValidator(name.to_string()))?;
+        validator(value, doc).map_err(|message| failure(name, field, message))
+    }
+}
+
+impl std::fmt::Debug for ValidatorRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ValidatorRegistry").field("validators", &self.names()).finish()
+    }
+}
+
+/// The document and registry custom validators run against
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationContext<'a> {
+    /// Document being validated
+    pub document: &'a Document,
+    /// Registry resolving [`Validator::Custom`](super::Validator::Custom) names
+    pub registry: &'a ValidatorRegistry,
+}
+
+/// Build the error reported for a failed custom or expression validator
+pub(super) fn failure(validator: &str, field: &str, message: String) -> ValidationError {
+    let message = if field.is_empty() { message } else { format!("field '{}': {}", field, message) };
+    ValidationError::CustomValidationFailed { validator: validator.to_string(), message }
+}
+
+/// Check a validator expression against a document
+pub(super) fn check_expression(source: &str, field: &str, doc: &Document) -> Result<(), ValidationError> {
+    let expression = Expression::parse(source)
+        .map_err(|e| ValidationError::InvalidSchema(format!("invalid validator expression '{}': {}", source, e)))?;
+    match expression.evaluate(doc) {
+        Ok(Value::Bool(true)) => Ok(()),
+        Ok(Value::Bool(false)) => Err(failure(source, field, "expression is false".to_string())),
+        Ok(other) => Err(failure(source, field, format!("expression produced {:?}, not a boolean", other))),
+        Err(e) => Err(failure(source, field, e.to_string())),
+    }
+}
+
+/// Errors parsing or evaluating a validator expression
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ExpressionError {
+    #[error("syntax error at offset {offset}: {message}")]
+    Syntax { offset: usize, message: String },
+
+    #[error("unknown function '{0}'")]
+    UnknownFunction(String),
+
+    #[error("{function}() takes {expected} argument(s), got {actual}")]
+    Arity {
+        function: String,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("cannot apply '{operator}' to {operand}")]
+    Type { operator: String, operand: String },
+
+    #[error("division by zero")]
+    DivisionByZero,
+
+    #[error("'{0}' overflows a decimal")]
+    Overflow(String),
+}
+
+/// A parsed validator expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    root: Expr,
+}
+
+impl Expression {
+    /// Parse an expression
+    pub fn parse(source: &str) -> Result<Self, ExpressionError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, position: 0, end: source.len() };
+        let root = parser.or()?;
+        if let Some((offset, token)) = parser.tokens.get(parser.position) {
+            return Err(ExpressionError::Syntax { offset: *offset, message: format!("unexpected {:?}", token) });
+        }
+        Ok(Self { root })
+    }
+
+    /// Evaluate the expression against a document; missing fields are null
+    pub fn evaluate(&self, doc: &Document) -> Result<Value, ExpressionError> {
+        evaluate(&self.root, doc)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(Value),
+    Path(String),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "||",
+            BinaryOp::And => "&&",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Remainder => "%",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Len,
+    Exists,
+    Abs,
+    Lower,
+    Upper,
+    Now,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "len" => Function::Len,
+            "exists" => Function::Exists,
+            "abs" => Function::Abs,
+            "lower" => Function::Lower,
+            "upper" => Function::Upper,
+            "now" => Function::Now,
+            _ => return None,
+        })
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Function::Now => 0,
+            _ => 1,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Len => "len",
+            Function::Exists => "exists",
+            Function::Abs => "abs",
+            Function::Lower => "lower",
+            Function::Upper => "upper",
+            Function::Now => "now",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Value),
+    Str(String),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 19] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "+", "-", "*", "/", "%", "(", ")", ",", ".", "=",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(offset, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut end = offset;
+            let mut float = false;
+            while let Some(&(i, c)) = chars.peek() {
+                let decimal_point = c == '.'
+                    && !float
+                    && source[i + 1..].starts_with(|next: char| next.is_ascii_digit());
+                if !(c.is_ascii_digit() || decimal_point) {
+                    break;
+                }
+                float |= decimal_point;
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let text = &source[offset..end];
+            let number = match float {
+                true => text.parse().map(Value::Float64).ok(),
+                false => text.parse().map(Value::Int64).ok(),
+            };
+            let number = number.ok_or_else(|| ExpressionError::Syntax {
+                offset,
+                message: format!("invalid number '{}'", text),
+            })?;
+            tokens.push((offset, Token::Number(number)));
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let unterminated = || ExpressionError::Syntax { offset, message: "unterminated string".to_string() };
+            let mut text = String::new();
+            loop {
+                match chars.next().ok_or_else(unterminated)? {
+                    (_, '\\') => text.push(chars.next().ok_or_else(unterminated)?.1),
+                    (_, close) if close == c => break,
+                    (_, other) => text.push(other),
+                }
+            }
+            tokens.push((offset, Token::Str(text)));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let mut end = offset;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '$') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push((offset, Token::Ident(source[offset..end].to_string())));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| source[offset..].starts_with(**symbol))
+                .ok_or_else(|| ExpressionError::Syntax { offset, message: format!("unexpected character '{}'", c) })?;
+            if *symbol == "=" {
+                return Err(ExpressionError::Syntax { offset, message: "use '==' to compare".to_string() });
+            }
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push((offset, Token::Symbol(symbol)));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, one method per precedence level
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.end, |(offset, _)| *offset)
+    }
+
+    /// Consume the next token if it is one of `symbols` or the matching keyword
+    fn operator(&mut self, symbols: &[(&str, Option<&str>)]) -> Option<&'static str> {
+        let found = symbols.iter().find_map(|(symbol, keyword)| match self.peek() {
+            Some(Token::Symbol(s)) if s == symbol => Some(*s),
+            Some(Token::Ident(name)) if Some(name.as_str()) == *keyword => {
+                SYMBOLS.iter().find(|s| *s == symbol).copied()
+            }
+            _ => None,
+        })?;
+        self.position += 1;
+        Some(found)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), ExpressionError> {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => {
+                self.position += 1;
+                Ok(())
+            }
+            _ => Err(ExpressionError::Syntax { offset: self.offset(), message: format!("expected '{}'", symbol) }),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.and()?;
+        while self.operator(&[("||", Some("or"))]).is_some() {
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.not()?;
+        while self.operator(&[("&&", Some("and"))]).is_some() {
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ExpressionError> {
+        if self.operator(&[("!", Some("not"))]).is_some() {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ExpressionError> {
+        let left = self.sum()?;
+        let comparisons = [("==", None), ("!=", None), ("<=", None), (">=", None), ("<", None), (">", None)];
+        let Some(symbol) = self.operator(&comparisons) else {
+            return Ok(left);
+        };
+        let op = match symbol {
+            "==" => BinaryOp::Eq,
+            "!=" => BinaryOp::Ne,
+            "<=" => BinaryOp::Le,
+            ">=" => BinaryOp::Ge,
+            "<" => BinaryOp::Lt,
+            _ => BinaryOp::Gt,
+        };
+        Ok(Expr::Binary(op, Box::new(left), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.product()?;
+        while let Some(symbol) = self.operator(&[("+", None), ("-", None)]) {
+            let op = if symbol == "+" { BinaryOp::Add } else { BinaryOp::Subtract };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expr, ExpressionError> {
+        let mut left = self.unary()?;
+        while let Some(symbol) = self.operator(&[("*", None), ("/", None), ("%", None)]) {
+            let op = match symbol {
+                "*" => BinaryOp::Multiply,
+                "/" => BinaryOp::Divide,
+                _ => BinaryOp::Remainder,
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExpressionError> {
+        if self.operator(&[("-", None)]).is_some() {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExpressionError> {
+        let offset = self.offset();
+        let Some((_, token)) = self.tokens.get(self.position).cloned() else {
+            return Err(ExpressionError::Syntax { offset, message: "unexpected end of expression".to_string() });
+        };
+        self.position += 1;
+        match token {
+            Token::Number(value) => Ok(Expr::Literal(value)),
+            Token::Str(text) => Ok(Expr::Literal(Value::String(text))),
+            Token::Symbol("(") => {
+                let inner = self.or()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Ident(name) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ if self.peek() == Some(&Token::Symbol("(")) => self.call(name),
+                _ => self.path(name),
+            },
+            other => Err(ExpressionError::Syntax { offset, message: format!("unexpected {:?}", other) }),
+        }
+    }
+
+    fn call(&mut self, name: String) -> Result<Expr, ExpressionError> {
+        let function = Function::from_name(&name).ok_or(ExpressionError::UnknownFunction(name))?;
+        self.expect("(")?;
+        let mut args = Vec::new();
+        if self.peek() != Some(&Token::Symbol(")")) {
+            args.push(self.or()?);
+            while self.operator(&[(",", None)]).is_some() {
+                args.push(self.or()?);
+            }
+        }
+        self.expect(")")?;
+        if args.len() != function.arity() {
+            return Err(ExpressionError::Arity {
+                function: function.name().to_string(),
+                expected: function.arity(),
+                actual: args.len(),
+            });
+        }
+        if function == Function::Exists && !matches!(args[0], Expr::Path(_)) {
+            return Err(ExpressionError::Syntax { offset: self.offset(), message: "exists() takes a field path".to_string() });
+        }
+        Ok(Expr::Call(function, args))
+    }
+
+    fn path(&mut self, first: String) -> Result<Expr, ExpressionError> {
+        let mut path = first;
+        while self.operator(&[(".", None)]).is_some() {
+            match self.tokens.get(self.position) {
+                Some((_, Token::Ident(segment))) => {
+                    path.push('.');
+                    path.push_str(segment);
+                    self.position += 1;
+                }
+                Some((_, Token::Number(Value::Int64(index)))) => {
+                    path.push('.');
+                    path.push_str(&index.to_string());
+                    self.position += 1;
+                }
+                _ => {
+                    return Err(ExpressionError::Syntax { offset: self.offset(), message: "expected a field name".to_string() })
+                }
+            }
+        }
+        Ok(Expr::Path(path))
+    }
+}
+
+/// Look up a field path; `_id` is the document id
+fn field<'a>(doc: &'a Document, path: &str) -> Option<std::borrow::Cow<'a, Value>> {
+    if path == "_id" {
+        return Some(std::borrow::Cow::Owned(Value::String(doc.id.to_string())));
+    }
+    doc.get_by_path(path).map(std::borrow::Cow::Borrowed)
+}
+
+fn evaluate(expr: &Expr, doc: &Document) -> Result<Value, ExpressionError> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Path(path) => Ok(field(doc, path).map(|value| value.into_owned()).unwrap_or(Value::Null)),
+        Expr::Not(inner) => Ok(Value::Bool(!boolean("!", evaluate(inner, doc)?)?)),
+        Expr::Negate(inner) => match evaluate(inner, doc)? {
+            Value::Int32(n) => Ok(Value::Int64(-(n as i64))),
+            Value::Int64(n) => n.checked_neg().map(Value::Int64).ok_or_else(|| type_error("-", &Value::Int64(n))),
+            Value::Float64(n) => Ok(Value::Float64(-n)),
+            other => Err(type_error("-", &other)),
+        },
+        Expr::Binary(BinaryOp::Or, left, right) => {
+            Ok(Value::Bool(boolean("||", evaluate(left, doc)?)? || boolean("||", evaluate(right, doc)?)?))
+        }
+        Expr::Binary(BinaryOp::And, left, right) => {
+            Ok(Value::Bool(boolean("&&", evaluate(left, doc)?)? && boolean("&&", evaluate(right, doc)?)?))
+        }
+        Expr::Binary(op, left, right) => binary(*op, evaluate(left, doc)?, evaluate(right, doc)?),
+        Expr::Call(function, args) => call(*function, args, doc),
+    }
+}
+
+fn boolean(operator: &str, value: Value) -> Result<bool, ExpressionError> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        other => Err(type_error(operator, &other)),
+    }
+}
+
+fn type_error(operator: &str, operand: &Value) -> ExpressionError {
+    ExpressionError::Type { operator: operator.to_string(), operand: format!("{:?}", operand) }
+}
+
+/// Order two values of the same kind; numbers of any width compare by value
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::DateTime(a), Value::DateTime(b)) => Some(a.cmp(b)),
+        (Value::Timestamp(a), Value::Timestamp(b)) => Some(a.cmp(b)),
+        (Value::Uuid(a), Value::Uuid(b)) => Some(a.cmp(b)),
+        (Value::Decimal128(_), _) | (_, Value::Decimal128(_)) => Some(left.as_decimal()?.cmp(&right.as_decimal()?)),
+        _ => match (left.as_i64(), right.as_i64()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => left.as_f64()?.partial_cmp(&right.as_f64()?),
+        },
+    }
+}
+
+fn binary(op: BinaryOp, left: Value, right: Value) -> Result<Value, ExpressionError> {
+    let ordering = || compare(&left, &right);
+    let result = match op {
+        BinaryOp::Eq => Value::Bool(ordering().map_or(left == right, Ordering::is_eq)),
+        BinaryOp::Ne => Value::Bool(!ordering().map_or(left == right, Ordering::is_eq)),
+        BinaryOp::Lt => Value::Bool(ordering() == Some(Ordering::Less)),
+        BinaryOp::Le => Value::Bool(matches!(ordering(), Some(Ordering::Less | Ordering::Equal))),
+        BinaryOp::Gt => Value::Bool(ordering() == Some(Ordering::Greater)),
+        BinaryOp::Ge => Value::Bool(matches!(ordering(), Some(Ordering::Greater | Ordering::Equal))),
+        BinaryOp::Add => match (&left, &right) {
+            (Value::String(a), Value::String(b)) => Value::String(format!("{}{}", a, b)),
+            _ => arithmetic(op, &left, &right)?,
+        },
+        BinaryOp::Subtract | BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Remainder => {
+            arithmetic(op, &left, &right)?
+        }
+        BinaryOp::Or | BinaryOp::And => unreachable!("logical operators short-circuit in evaluate"),
+    };
+    Ok(result)
+}
+
+fn arithmetic(op: BinaryOp, left: &Value, right: &Value) -> Result<Value, ExpressionError> {
+    let symbol = op.symbol();
+    if matches!(left, Value::Decimal128(_)) || matches!(right, Value::Decimal128(_)) {
+        let a = left.as_decimal().ok_or_else(|| type_error(symbol, left))?;
+        let b = right.as_decimal().ok_or_else(|| type_error(symbol, right))?;
+        return decimal_arithmetic(op, a, b).map(Value::Decimal128).map_err(|error| match error {
+            DecimalError::DivisionByZero => ExpressionError::DivisionByZero,
+            _ => ExpressionError::Overflow(symbol.to_string()),
+        });
+    }
+    if let (Some(a), Some(b)) = (left.as_i64(), right.as_i64()) {
+        let result = match op {
+            BinaryOp::Add => a.checked_add(b),
+            BinaryOp::Subtract => a.checked_sub(b),
+            BinaryOp::Multiply => a.checked_mul(b),
+            BinaryOp::Remainder if b == 0 => return Err(ExpressionError::DivisionByZero),
+            BinaryOp::Remainder => a.checked_rem(b),
+            // Division is exact, so it goes through floats
+            _ => None,
+        };
+        if let Some(result) = result {
+            return Ok(Value::Int64(result));
+        }
+    }
+
+    let a = left.as_f64().ok_or_else(|| type_error(symbol, left))?;
+    let b = right.as_f64().ok_or_else(|| type_error(symbol, right))?;
+    let result = match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide | BinaryOp::Remainder if b == 0.0 => return Err(ExpressionError::DivisionByZero),
+        BinaryOp::Divide => a / b,
+        _ => a % b,
+    };
+    Ok(Value::Float64(result))
+}
+
+/// Exact arithmetic once either operand is a decimal
+fn decimal_arithmetic(op: BinaryOp, a: Decimal128, b: Decimal128) -> Result<Decimal128, DecimalError> {
+    match op {
+        BinaryOp::Add => a.checked_add(b),
+        BinaryOp::Subtract => a.checked_sub(b),
+        BinaryOp::Multiply => a.checked_mul(b),
+        BinaryOp::Divide => a.checked_div(b),
+        _ => a.checked_sub(a.checked_div(b)?.trunc().checked_mul(b)?),
+    }
+}
+
+fn call(function: Function, args: &[Expr], doc: &Document) -> Result<Value, ExpressionError> {
+    if let (Function::Exists, [Expr::Path(path)]) = (function, args) {
+        return Ok(Value::Bool(field(doc, path).is_some()));
+    }
+    if function == Function::Now {
+        return Ok(Value::DateTime(Utc::now()));
+    }
+
+    let argument = evaluate(&args[0], doc)?;
+    let name = function.name();
+    match (function, &argument) {
+        (Function::Len, Value::String(s)) => Ok(Value::Int64(s.chars().count() as i64)),
+        (Function::Len, Value::Array(items)) => Ok(Value::Int64(items.len() as i64)),
+        (Function::Len, Value::Object(fields)) => Ok(Value::Int64(fields.len() as i64)),
+        (Function::Len, Value::Binary(bytes)) => Ok(Value::Int64(bytes.len() as i64)),
+        (Function::Abs, Value::Float64(n)) => Ok(Value::Float64(n.abs())),
+        (Function::Abs, Value::Decimal128(d)) => Ok(Value::Decimal128(if d.is_negative() { -*d } else { *d })),
+        (Function::Abs, number) if number.as_i64().is_some() => number
+            .as_i64()
+            .and_then(i64::checked_abs)
+            .map(Value::Int64)
+            .ok_or_else(|| type_error(name, number)),
+        (Function::Lower, Value::String(s)) => Ok(Value::String(s.to_lowercase())),
+        (Function::Upper, Value::String(s)) => Ok(Value::String(s.to_uppercase())),
+        _ => Err(type_error(name, &argument)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn order() -> Document {
+        let start = Utc::now();
+        let mut doc = Document::new();
+        doc.insert("start_date".to_string(), Value::DateTime(start));
+        doc.insert("end_date".to_string(), Value::DateTime(start + Duration::days(3)));
+        doc.insert("max_items".to_string(), Value::Int32(2));
+        doc.insert(
+            "items".to_string(),
+            Value::Array(vec![Value::String("a".to_string()), Value::String("b".to_string())]),
+        );
+        doc.insert("price".to_string(), Value::Float64(9.5));
+        doc.insert("quantity".to_string(), Value::Int64(4));
+        doc.set_by_path("customer.name", Value::String("Ada".to_string())).unwrap();
+        doc
+    }
+
+    fn holds(source: &str, doc: &Document) -> bool {
+        match Expression::parse(source).unwrap().evaluate(doc).unwrap() {
+            Value::Bool(b) => b,
+            other => panic!("{} produced {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn test_expressions_over_fields() {
+        let doc = order();
+        assert!(holds("end_date > start_date", &doc));
+        assert!(!holds("end_date <= start_date", &doc));
+        assert!(holds("len(items) <= max_items", &doc));
+        assert!(holds("price * quantity == 38", &doc));
+        assert!(holds("quantity % 3 == 1 and -quantity < 0", &doc));
+        assert!(holds("lower(customer.name) == 'ada' && len(customer.name) == 3", &doc));
+        assert!(holds("!exists(discount) || discount <= price", &doc));
+        assert!(holds("not (missing > 0) and missing == null", &doc));
+        assert!(holds("start_date <= now()", &doc));
+        assert!(holds("abs(0 - quantity) == 4 && 7 / 2 == 3.5", &doc));
+        assert!(holds("upper(\"a\") + 'b' == \"Ab\" && items.1 == 'b'", &doc));
+        // Values of different kinds are neither smaller nor larger
+        assert!(!holds("price > 'abc'", &doc) && !holds("price <= 'abc'", &doc));
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        let doc = order();
+        assert!(matches!(Expression::parse("a >"), Err(ExpressionError::Syntax { .. })));
+        assert!(matches!(Expression::parse("a = 1"), Err(ExpressionError::Syntax { .. })));
+        assert!(matches!(Expression::parse("(a > 1"), Err(ExpressionError::Syntax { .. })));
+        assert!(matches!(Expression::parse("'open"), Err(ExpressionError::Syntax { .. })));
+        assert!(matches!(Expression::parse("a > 1 b"), Err(ExpressionError::Syntax { .. })));
+        assert!(matches!(Expression::parse("size(items) > 1"), Err(ExpressionError::UnknownFunction(_))));
+        assert!(matches!(Expression::parse("len(a, b)"), Err(ExpressionError::Arity { expected: 1, actual: 2, .. })));
+        assert!(matches!(Expression::parse("exists(1)"), Err(ExpressionError::Syntax { .. })));
+
+        let evaluate = |source: &str| Expression::parse(source).unwrap().evaluate(&doc);
+        assert_eq!(evaluate("quantity / 0"), Err(ExpressionError::DivisionByZero));
+        assert!(matches!(evaluate("len(quantity)"), Err(ExpressionError::Type { .. })));
+        assert!(matches!(evaluate("items && true"), Err(ExpressionError::Type { .. })));
+        assert!(matches!(evaluate("customer.name - 1"), Err(ExpressionError::Type { .. })));
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = ValidatorRegistry::new();
+        assert!(!registry.register("even", |value, _| match value.as_i64() {
+            Some(n) if n % 2 == 0 => Ok(()),
+            _ => Err("must be even".to_string()),
+        }));
+        assert!(registry.contains("even"));
+        assert_eq!(registry.names(), vec!["even".to_string()]);
+
+        let doc = Document::new();
+        registry.validate("even", "count", &Value::Int32(4), &doc).unwrap();
+        let err = registry.validate("even", "count", &Value::Int32(3), &doc).unwrap_err();
+        assert_eq!(err.to_string(), "Custom validation 'even' failed: field 'count': must be even");
+        assert!(matches!(
+            registry.validate("odd", "count", &Value::Int32(3), &doc),
+            Err(ValidationError::UnknownValidator(_))
+        ));
+
+        assert!(registry.unregister("even"));
+        assert!(!registry.contains("even"));
+    }
+}
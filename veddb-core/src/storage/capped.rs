@@ -0,0 +1,318 @@
+//! Capped collections
+//!
+//! A capped collection holds at most `max_documents` documents and/or
+//! `max_bytes` bytes of document data. Inserts get ids that increase with
+//! every insert, so the persistent layer's key order is insertion order;
+//! once an insert goes over a cap the oldest documents are evicted first.
+//! Tailable cursors read a capped collection in that order and wait on
+//! [`CappedCollection::notified`] for documents not yet inserted.
+
+use crate::document::{Document, DocumentId};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Size limits of a capped collection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CappedOptions {
+    /// Largest total size of the documents, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Largest number of documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_documents: Option<u64>,
+}
+
+impl CappedOptions {
+    /// Check that at least one limit is set and none is zero
+    pub fn validate(&self) -> Result<(), CappedError> {
+        let limits = [self.max_bytes, self.max_documents];
+        if limits.iter().all(Option::is_none) || limits.contains(&Some(0)) {
+            return Err(CappedError::InvalidOptions);
+        }
+        Ok(())
+    }
+
+    fn exceeded_by(&self, documents: usize, bytes: u64) -> bool {
+        self.max_documents.is_some_and(|max| documents as u64 > max)
+            || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+}
+
+/// Capped collection errors
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum CappedError {
+    #[error("A capped collection needs max_bytes, max_documents or both, each above zero")]
+    InvalidOptions,
+
+    #[error("Document of {size} bytes exceeds the {max} byte cap of collection '{collection}'")]
+    DocumentTooLarge { collection: String, size: u64, max: u64 },
+
+    #[error("Collection '{0}' is not capped")]
+    NotCapped(String),
+}
+
+/// Size of a document as counted against `max_bytes`
+pub fn document_size(doc: &Document) -> u64 {
+    doc.fields.iter().map(|(name, value)| (name.len() + value.size_bytes()) as u64).sum()
+}
+
+/// Insertion order and size accounting of one capped collection
+pub struct CappedCollection {
+    options: CappedOptions,
+    state: Mutex<CappedState>,
+    /// Serializes inserts so ids enter the order in the order they were issued
+    inserts: tokio::sync::Mutex<()>,
+    /// Woken after every insert and when the collection is dropped
+    inserted: Notify,
+}
+
+#[derive(Default)]
+struct CappedState {
+    /// Ids and sizes of the documents, oldest first
+    entries: VecDeque<(DocumentId, u64)>,
+    bytes: u64,
+    /// Newest id handed out, inserted or not
+    last_id: Option<DocumentId>,
+}
+
+impl CappedCollection {
+    /// Empty capped collection
+    pub fn new(options: CappedOptions) -> Self {
+        Self {
+            options,
+            state: Mutex::new(CappedState::default()),
+            inserts: tokio::sync::Mutex::new(()),
+            inserted: Notify::new(),
+        }
+    }
+
+    /// Capped collection holding `documents`, given in key order
+    pub fn restore<'a>(options: CappedOptions, documents: impl IntoIterator<Item = &'a Document>) -> Self {
+        let capped = Self::new(options);
+        {
+            let mut state = capped.state.lock();
+            for doc in documents {
+                let size = document_size(doc);
+                state.entries.push_back((doc.id, size));
+                state.bytes += size;
+            }
+            state.last_id = state.entries.back().map(|(id, _)| *id);
+        }
+        capped
+    }
+
+    /// Size limits
+    pub fn options(&self) -> &CappedOptions {
+        &self.options
+    }
+
+    /// Number of documents
+    pub fn len(&self) -> usize {
+        self.state.lock().entries.len()
+    }
+
+    /// Whether the collection holds no documents
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total size of the documents
+    pub fn size_bytes(&self) -> u64 {
+        self.state.lock().bytes
+    }
+
+    /// Hold until no other insert into this collection is in progress
+    pub async fn lock_inserts(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.inserts.lock().await
+    }
+
+    /// Id for the next insert, greater than every id handed out before
+    ///
+    /// Ids are UUIDv7: a millisecond timestamp then random bits, which a
+    /// clock running backwards or two ids in one millisecond replace by
+    /// the previous id plus one.
+    pub fn next_id(&self) -> DocumentId {
+        let mut state = self.state.lock();
+        let millis = chrono::Utc::now().timestamp_millis().max(0) as u64;
+        let mut random = [0u8; 10];
+        random.copy_from_slice(&Uuid::new_v4().as_bytes()[6..]);
+        let candidate = uuid::Builder::from_unix_timestamp_millis(millis, &random).into_uuid();
+        let id = match state.last_id {
+            Some(last) if candidate <= *last.as_uuid() => Uuid::from_u128(last.as_uuid().as_u128() + 1),
+            _ => candidate,
+        };
+        let id = DocumentId::from_uuid(id);
+        state.last_id = Some(id);
+        id
+    }
+
+    /// Reject a document too large to fit the byte cap on its own
+    pub fn check_size(&self, collection: &str, size: u64) -> Result<(), CappedError> {
+        match self.options.max_bytes {
+            Some(max) if size > max => Err(CappedError::DocumentTooLarge {
+                collection: collection.to_string(),
+                size,
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record an insert and wake waiting cursors
+    ///
+    /// Returns the ids of the oldest documents that no longer fit, which
+    /// the caller deletes.
+    pub fn push(&self, id: DocumentId, size: u64) -> Vec<DocumentId> {
+        let evicted = {
+            let mut state = self.state.lock();
+            state.entries.push_back((id, size));
+            state.bytes += size;
+            self.evict(&mut state)
+        };
+        self.inserted.notify_waiters();
+        evicted
+    }
+
+    /// Record a document's new size after an update
+    ///
+    /// Caps are enforced on insert, so growth only evicts at the next one.
+    pub fn resize(&self, id: DocumentId, size: u64) {
+        let mut state = self.state.lock();
+        if let Some(position) = state.position(id) {
+            let previous = std::mem::replace(&mut state.entries[position].1, size);
+            state.bytes = state.bytes - previous + size;
+        }
+    }
+
+    /// Forget a deleted document
+    pub fn remove(&self, id: DocumentId) {
+        let mut state = self.state.lock();
+        if let Some(position) = state.position(id) {
+            if let Some((_, size)) = state.entries.remove(position) {
+                state.bytes -= size;
+            }
+        }
+    }
+
+    /// Ids of up to `limit` documents inserted after `after`, oldest first
+    ///
+    /// `after` need not be in the collection any more: a cursor whose last
+    /// document was evicted resumes at the oldest document still present.
+    pub fn ids_after(&self, after: Option<DocumentId>, limit: usize) -> Vec<DocumentId> {
+        let state = self.state.lock();
+        let start = after.map_or(0, |after| state.entries.partition_point(|(id, _)| *id <= after));
+        state.entries.iter().skip(start).take(limit).map(|(id, _)| *id).collect()
+    }
+
+    /// Future completing at the next insert
+    ///
+    /// Create it before reading with [`ids_after`](Self::ids_after) so an
+    /// insert in between is not missed.
+    pub fn notified(&self) -> Notified<'_> {
+        self.inserted.notified()
+    }
+
+    /// Wake every waiting cursor, as when the collection is dropped
+    pub fn wake_all(&self) {
+        self.inserted.notify_waiters();
+    }
+
+    /// Pop the oldest documents while a cap is exceeded, always keeping the newest
+    fn evict(&self, state: &mut CappedState) -> Vec<DocumentId> {
+        let mut evicted = Vec::new();
+        while state.entries.len() > 1 && self.options.exceeded_by(state.entries.len(), state.bytes) {
+            if let Some((id, size)) = state.entries.pop_front() {
+                state.bytes -= size;
+                evicted.push(id);
+            }
+        }
+        evicted
+    }
+}
+
+impl CappedState {
+    /// Position of an id; the entries are in id order
+    fn position(&self, id: DocumentId) -> Option<usize> {
+        let position = self.entries.partition_point(|(entry, _)| *entry < id);
+        (self.entries.get(position).map(|(entry, _)| *entry) == Some(id)).then_some(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_increase_and_oldest_are_evicted() {
+        let capped = CappedCollection::new(CappedOptions { max_bytes: Some(100), max_documents: Some(3) });
+        let ids: Vec<DocumentId> = (0..5).map(|_| capped.next_id()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        // Key order in storage is the order of the hyphenated strings
+        assert!(ids.windows(2).all(|pair| pair[0].to_string() < pair[1].to_string()));
+
+        assert!(capped.push(ids[0], 10).is_empty());
+        assert!(capped.push(ids[1], 10).is_empty());
+        assert!(capped.push(ids[2], 10).is_empty());
+        assert_eq!(capped.push(ids[3], 10), vec![ids[0]]);
+        // The byte cap evicts two more to fit a large document
+        assert_eq!(capped.push(ids[4], 85), vec![ids[1], ids[2]]);
+        assert_eq!((capped.len(), capped.size_bytes()), (2, 95));
+
+        assert_eq!(capped.ids_after(None, 10), vec![ids[3], ids[4]]);
+        assert_eq!(capped.ids_after(Some(ids[3]), 10), vec![ids[4]]);
+        // An evicted position resumes at the oldest document left
+        assert_eq!(capped.ids_after(Some(ids[0]), 10), vec![ids[3], ids[4]]);
+        assert_eq!(capped.ids_after(Some(ids[4]), 10), Vec::<DocumentId>::new());
+
+        capped.resize(ids[4], 100);
+        assert_eq!(capped.size_bytes(), 110);
+        capped.remove(ids[4]);
+        capped.remove(ids[3]);
+        assert!(capped.is_empty());
+        assert_eq!(capped.size_bytes(), 0);
+    }
+
+    #[test]
+    fn test_options_and_sizes_are_checked() {
+        assert_eq!(CappedOptions::default().validate(), Err(CappedError::InvalidOptions));
+        assert_eq!(CappedOptions { max_bytes: Some(0), max_documents: Some(5) }.validate(), Err(CappedError::InvalidOptions));
+        assert!(CappedOptions { max_bytes: None, max_documents: Some(5) }.validate().is_ok());
+
+        let capped = CappedCollection::new(CappedOptions { max_bytes: Some(8), max_documents: None });
+        assert!(capped.check_size("log", 8).is_ok());
+        assert!(matches!(capped.check_size("log", 9), Err(CappedError::DocumentTooLarge { size: 9, max: 8, .. })));
+    }
+
+    #[test]
+    fn test_restore_continues_after_newest_id() {
+        let first = CappedCollection::new(CappedOptions { max_bytes: None, max_documents: Some(10) });
+        let docs: Vec<Document> = (0..3).map(|_| Document::with_id(first.next_id())).collect();
+        let restored = CappedCollection::restore(*first.options(), &docs);
+        assert_eq!(restored.len(), 3);
+        assert!(restored.next_id() > docs[2].id);
+    }
+
+    #[tokio::test]
+    async fn test_insert_wakes_waiters() {
+        let capped = std::sync::Arc::new(CappedCollection::new(CappedOptions { max_bytes: None, max_documents: Some(10) }));
+        let waiter = {
+            let capped = capped.clone();
+            tokio::spawn(async move {
+                let notified = capped.notified();
+                if capped.ids_after(None, 1).is_empty() {
+                    notified.await;
+                }
+                capped.ids_after(None, 1)
+            })
+        };
+        tokio::task::yield_now().await;
+        let id = capped.next_id();
+        capped.push(id, 1);
+        let seen = tokio::time::timeout(std::time::Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        assert_eq!(seen, vec![id]);
+    }
+}
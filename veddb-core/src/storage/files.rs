@@ -0,0 +1,512 @@
+//! Chunked file storage
+//!
+//! Files too large for one document are split into fixed-size chunks. A
+//! bucket keeps one document per file in its `{bucket}.files` collection,
+//! holding the name, length, chunk size, content type, user metadata and,
+//! once the upload is finished, the SHA-256 checksum of the content. The
+//! content lives in `{bucket}.chunks`, one document per chunk with the
+//! chunk's number, bytes and CRC32.
+//!
+//! Uploads are resumable: bytes are appended at the file's current length,
+//! which [`FileStore::info`] reports after a disconnect, and a file can only
+//! be read once [`FileStore::finish`] has sealed it. Chunk ids derive from
+//! the file id and the chunk number, so writing a chunk again replaces it.
+//! Appends, finishing and deleting hold the file document's lock
+//! ([`HybridStorageEngine::lock_document`]) and write it through the
+//! engine's `_locked` methods, so two appends at one offset cannot both
+//! pass the length check.
+
+use crate::document::{Document, DocumentId, Value};
+use crate::query::{Filter, Query};
+use crate::storage::HybridStorageEngine;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Bucket used when a request names none
+pub const DEFAULT_BUCKET: &str = "fs";
+
+/// Chunk size used when an upload asks for none
+pub const DEFAULT_CHUNK_SIZE: u64 = 255 * 1024;
+
+/// Largest chunk size, well under the document size limit
+pub const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// File storage errors
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FileError {
+    #[error("Invalid bucket name '{0}'")]
+    InvalidBucket(String),
+
+    #[error("Chunk size must be between 1 and {max} bytes, got {size}")]
+    InvalidChunkSize { size: u64, max: u64 },
+
+    #[error("File {0} not found")]
+    NotFound(DocumentId),
+
+    #[error("File {id} has {expected} bytes; writes must continue at that offset, not {offset}")]
+    OffsetMismatch { id: DocumentId, expected: u64, offset: u64 },
+
+    #[error("File {0} is finished and cannot be written")]
+    Finished(DocumentId),
+
+    #[error("File {0} is still being uploaded")]
+    Unfinished(DocumentId),
+
+    #[error("Range {start}..{end} is outside file {id} of {length} bytes")]
+    InvalidRange { id: DocumentId, start: u64, end: u64, length: u64 },
+
+    #[error("Chunk {n} of file {id} is missing or corrupt")]
+    CorruptChunk { id: DocumentId, n: u64 },
+
+    #[error("Malformed file document {0}")]
+    Malformed(DocumentId),
+}
+
+/// Settings of a new upload
+#[derive(Debug, Clone, Default)]
+pub struct NewFile {
+    pub filename: String,
+    pub content_type: Option<String>,
+    /// Application fields stored with the file
+    pub metadata: BTreeMap<String, Value>,
+    /// Bytes per chunk; [`DEFAULT_CHUNK_SIZE`] when absent
+    pub chunk_size: Option<u64>,
+}
+
+/// A file's document in the bucket's files collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub id: DocumentId,
+    pub filename: String,
+    /// Bytes uploaded so far
+    pub length: u64,
+    pub chunk_size: u64,
+    pub content_type: Option<String>,
+    pub metadata: BTreeMap<String, Value>,
+    /// When the upload began, then when it was finished
+    pub upload_date: DateTime<Utc>,
+    /// Hex SHA-256 of the content, set when the upload is finished
+    pub sha256: Option<String>,
+}
+
+impl FileInfo {
+    /// Whether the upload is finished and the file readable
+    pub fn is_finished(&self) -> bool {
+        self.sha256.is_some()
+    }
+
+    /// Number of chunks holding the file's bytes
+    pub fn chunk_count(&self) -> u64 {
+        self.length.div_ceil(self.chunk_size)
+    }
+
+    /// The file as stored in the files collection
+    pub fn to_document(&self) -> Document {
+        let mut doc = Document::with_id(self.id);
+        doc.insert("filename".to_string(), Value::String(self.filename.clone()));
+        doc.insert("length".to_string(), Value::Int64(self.length as i64));
+        doc.insert("chunk_size".to_string(), Value::Int64(self.chunk_size as i64));
+        if let Some(content_type) = &self.content_type {
+            doc.insert("content_type".to_string(), Value::String(content_type.clone()));
+        }
+        doc.insert("metadata".to_string(), Value::Object(self.metadata.clone()));
+        doc.insert("upload_date".to_string(), Value::DateTime(self.upload_date));
+        doc.insert("finished".to_string(), Value::Bool(self.is_finished()));
+        if let Some(sha256) = &self.sha256 {
+            doc.insert("sha256".to_string(), Value::String(sha256.clone()));
+        }
+        doc
+    }
+
+    /// Read a file back from its document
+    pub fn from_document(doc: &Document) -> Result<Self, FileError> {
+        let malformed = || FileError::Malformed(doc.id);
+        let count = |field: &str| {
+            doc.get(field)
+                .and_then(Value::as_i64)
+                .and_then(|n| u64::try_from(n).ok())
+                .ok_or_else(malformed)
+        };
+        let text = |field: &str| doc.get(field).and_then(Value::as_str).map(str::to_string);
+        let chunk_size = count("chunk_size")?;
+        if chunk_size == 0 {
+            return Err(malformed());
+        }
+        Ok(Self {
+            id: doc.id,
+            filename: text("filename").ok_or_else(malformed)?,
+            length: count("length")?,
+            chunk_size,
+            content_type: text("content_type"),
+            metadata: doc.get("metadata").and_then(Value::as_object).cloned().unwrap_or_default(),
+            upload_date: match doc.get("upload_date") {
+                Some(Value::DateTime(date)) => *date,
+                _ => return Err(malformed()),
+            },
+            sha256: text("sha256"),
+        })
+    }
+}
+
+/// Files of one bucket
+pub struct FileStore {
+    engine: Arc<HybridStorageEngine>,
+    files: String,
+    chunks: String,
+}
+
+impl FileStore {
+    /// Open a bucket, [`DEFAULT_BUCKET`] when `bucket` is `None`
+    ///
+    /// The bucket's collections are created with its first file.
+    pub fn new(engine: Arc<HybridStorageEngine>, bucket: Option<&str>) -> Result<Self, FileError> {
+        let bucket = bucket.unwrap_or(DEFAULT_BUCKET);
+        if bucket.is_empty() || bucket.contains(':') || bucket.chars().any(char::is_whitespace) {
+            return Err(FileError::InvalidBucket(bucket.to_string()));
+        }
+        Ok(Self {
+            engine,
+            files: format!("{}.files", bucket),
+            chunks: format!("{}.chunks", bucket),
+        })
+    }
+
+    /// Name of the collection holding the bucket's file documents
+    pub fn files_collection(&self) -> &str {
+        &self.files
+    }
+
+    /// Name of the collection holding the bucket's chunks
+    pub fn chunks_collection(&self) -> &str {
+        &self.chunks
+    }
+
+    /// Start an empty upload
+    pub async fn begin(&self, file: NewFile) -> Result<FileInfo> {
+        let chunk_size = file.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(FileError::InvalidChunkSize { size: chunk_size, max: MAX_CHUNK_SIZE }.into());
+        }
+        let info = FileInfo {
+            id: DocumentId::new(),
+            filename: file.filename,
+            length: 0,
+            chunk_size,
+            content_type: file.content_type,
+            metadata: file.metadata,
+            upload_date: Utc::now(),
+            sha256: None,
+        };
+        self.engine.insert_document(&self.files, info.to_document()).await?;
+        Ok(info)
+    }
+
+    /// Append `data` to an unfinished upload at `offset`, its current length
+    ///
+    /// A partly filled last chunk is completed first. The file's length is
+    /// only advanced once every chunk is written, so an append cut short is
+    /// repeated from the same offset.
+    pub async fn append(&self, id: DocumentId, offset: u64, data: &[u8]) -> Result<FileInfo> {
+        let file = self.engine.lock_document(&self.files, id).await;
+        let mut info = self.info(id).await?;
+        if info.is_finished() {
+            return Err(FileError::Finished(id).into());
+        }
+        if offset != info.length {
+            return Err(FileError::OffsetMismatch { id, expected: info.length, offset }.into());
+        }
+        if data.is_empty() {
+            return Ok(info);
+        }
+
+        let mut n = info.length / info.chunk_size;
+        let mut pending = data;
+        let filled = (info.length % info.chunk_size) as usize;
+        let mut chunk = match filled {
+            0 => Vec::new(),
+            _ => self.chunk_data(&info, n).await?,
+        };
+        while !pending.is_empty() {
+            let take = pending.len().min(info.chunk_size as usize - chunk.len());
+            chunk.extend_from_slice(&pending[..take]);
+            pending = &pending[take..];
+            self.write_chunk(id, n, &chunk).await?;
+            chunk.clear();
+            n += 1;
+        }
+
+        info.length += data.len() as u64;
+        self.engine.update_document_locked(&file, info.to_document()).await?;
+        Ok(info)
+    }
+
+    /// Seal an upload, recording its checksum; the file becomes readable
+    pub async fn finish(&self, id: DocumentId) -> Result<FileInfo> {
+        let file = self.engine.lock_document(&self.files, id).await;
+        let mut info = self.info(id).await?;
+        if info.is_finished() {
+            return Err(FileError::Finished(id).into());
+        }
+        let mut hasher = Sha256::new();
+        for n in 0..info.chunk_count() {
+            hasher.update(self.chunk_data(&info, n).await?);
+        }
+        info.sha256 = Some(hex::encode(hasher.finalize()));
+        info.upload_date = Utc::now();
+        self.engine.update_document_locked(&file, info.to_document()).await?;
+        Ok(info)
+    }
+
+    /// Store a whole file at once
+    pub async fn put(&self, file: NewFile, data: &[u8]) -> Result<FileInfo> {
+        let info = self.begin(file).await?;
+        self.append(info.id, 0, data).await?;
+        self.finish(info.id).await
+    }
+
+    /// A file's document, finished or not
+    pub async fn info(&self, id: DocumentId) -> Result<FileInfo> {
+        let doc = self.engine.get_document(&self.files, id).await?.ok_or(FileError::NotFound(id))?;
+        Ok(FileInfo::from_document(&doc)?)
+    }
+
+    /// Bytes `start..end` of a finished file; `end` is clamped to its length
+    pub async fn read_range(&self, info: &FileInfo, start: u64, end: u64) -> Result<Vec<u8>> {
+        if !info.is_finished() {
+            return Err(FileError::Unfinished(info.id).into());
+        }
+        let end = end.min(info.length);
+        if start > end {
+            return Err(FileError::InvalidRange { id: info.id, start, end, length: info.length }.into());
+        }
+        let mut data = Vec::with_capacity((end - start) as usize);
+        let mut position = start;
+        while position < end {
+            let n = position / info.chunk_size;
+            let chunk = self.chunk_data(info, n).await?;
+            let from = (position - n * info.chunk_size) as usize;
+            let to = chunk.len().min((end - n * info.chunk_size) as usize);
+            data.extend_from_slice(&chunk[from..to]);
+            position = n * info.chunk_size + to as u64;
+        }
+        Ok(data)
+    }
+
+    /// Read a whole finished file
+    pub async fn read(&self, id: DocumentId) -> Result<Vec<u8>> {
+        let info = self.info(id).await?;
+        self.read_range(&info, 0, info.length).await
+    }
+
+    /// Delete a file and its chunks, finished or not
+    ///
+    /// Returns whether the file existed.
+    pub async fn delete(&self, id: DocumentId) -> Result<bool> {
+        let file = self.engine.lock_document(&self.files, id).await;
+        let info = match self.info(id).await {
+            Ok(info) => info,
+            Err(e) if e.downcast_ref::<FileError>() == Some(&FileError::NotFound(id)) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        // The file document goes first so a partial delete leaves no readable file
+        self.engine.delete_document_locked(&file).await?;
+        // Chunks past the length are left by appends cut short
+        let mut n = 0;
+        while n < info.chunk_count() || self.engine.get_document(&self.chunks, chunk_id(id, n)).await?.is_some() {
+            self.engine.delete_document(&self.chunks, chunk_id(id, n)).await?;
+            n += 1;
+        }
+        Ok(true)
+    }
+
+    /// Files matching `filter` over their documents, by filename
+    pub fn list(&self, filter: Option<Filter>) -> Result<Vec<FileInfo>> {
+        if !self.engine.list_collections()?.contains(&self.files) {
+            return Ok(Vec::new());
+        }
+        let query = Query::with_filter(filter.unwrap_or(Filter::Empty))
+            .sort(crate::query::Sort::new().asc("filename").asc("upload_date"));
+        self.engine
+            .query(&self.files, &query)?
+            .iter()
+            .map(|doc| FileInfo::from_document(doc).map_err(Into::into))
+            .collect()
+    }
+
+    /// Bytes of chunk `n`, checked against its CRC32 and the file's length
+    async fn chunk_data(&self, info: &FileInfo, n: u64) -> Result<Vec<u8>> {
+        let corrupt = || FileError::CorruptChunk { id: info.id, n };
+        let doc = self.engine.get_document(&self.chunks, chunk_id(info.id, n)).await?.ok_or_else(corrupt)?;
+        let Some(Value::Binary(data)) = doc.get("data") else {
+            return Err(corrupt().into());
+        };
+        let expected_len = info.chunk_size.min(info.length - n * info.chunk_size);
+        let crc = doc.get("crc32").and_then(Value::as_i64);
+        if crc != Some(crc32fast::hash(data) as i64) || data.len() as u64 != expected_len {
+            return Err(corrupt().into());
+        }
+        Ok(data.clone())
+    }
+
+    async fn write_chunk(&self, id: DocumentId, n: u64, data: &[u8]) -> Result<()> {
+        let mut doc = Document::with_id(chunk_id(id, n));
+        doc.insert("files_id".to_string(), Value::String(id.to_string()));
+        doc.insert("n".to_string(), Value::Int64(n as i64));
+        doc.insert("data".to_string(), Value::Binary(data.to_vec()));
+        doc.insert("crc32".to_string(), Value::Int64(crc32fast::hash(data) as i64));
+        match self.engine.get_document(&self.chunks, doc.id).await? {
+            Some(_) => self.engine.update_document(&self.chunks, doc.id, doc).await,
+            None => self.engine.insert_document(&self.chunks, doc).await.map(|_| ()),
+        }
+    }
+}
+
+/// Id of chunk `n` of a file
+fn chunk_id(file: DocumentId, n: u64) -> DocumentId {
+    DocumentId::from_uuid(Uuid::new_v5(file.as_uuid(), &n.to_be_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::cache_layer::CacheConfig;
+    use crate::storage::PersistentLayer;
+    use tempfile::TempDir;
+
+    fn create_store(bucket: Option<&str>) -> (FileStore, Arc<HybridStorageEngine>, TempDir) {
+        let temp_dir = TempDir::new().unwrap();
+        let persistent = Arc::new(PersistentLayer::new(temp_dir.path()).unwrap());
+        let engine = Arc::new(HybridStorageEngine::new(CacheConfig::default(), persistent));
+        (FileStore::new(engine.clone(), bucket).unwrap(), engine, temp_dir)
+    }
+
+    fn named(filename: &str, chunk_size: u64) -> NewFile {
+        NewFile { filename: filename.to_string(), chunk_size: Some(chunk_size), ..NewFile::default() }
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn file_error(error: &anyhow::Error) -> Option<&FileError> {
+        error.downcast_ref::<FileError>()
+    }
+
+    #[tokio::test]
+    async fn test_put_and_read_ranges() {
+        let (store, engine, _temp_dir) = create_store(None);
+        let data = content(2500);
+        let mut file = named("report.pdf", 1000);
+        file.content_type = Some("application/pdf".to_string());
+        file.metadata.insert("owner".to_string(), Value::String("ana".to_string()));
+        let info = store.put(file, &data).await.unwrap();
+
+        assert_eq!((info.length, info.chunk_count()), (2500, 3));
+        assert_eq!(info.sha256.as_deref(), Some(hex::encode(Sha256::digest(&data)).as_str()));
+        assert_eq!(engine.scan_collection("fs.chunks").unwrap().len(), 3);
+        assert_eq!(store.info(info.id).await.unwrap(), info);
+
+        assert_eq!(store.read(info.id).await.unwrap(), data);
+        assert_eq!(store.read_range(&info, 990, 2010).await.unwrap(), data[990..2010]);
+        assert_eq!(store.read_range(&info, 2400, 9999).await.unwrap(), data[2400..]);
+        assert!(store.read_range(&info, 2500, 2500).await.unwrap().is_empty());
+        let err = store.read_range(&info, 3000, 4000).await.unwrap_err();
+        assert!(matches!(file_error(&err), Some(FileError::InvalidRange { .. })));
+
+        let empty = store.put(named("empty", 10), &[]).await.unwrap();
+        assert!(store.read(empty.id).await.unwrap().is_empty());
+
+        let err = store.begin(named("huge", MAX_CHUNK_SIZE + 1)).await.unwrap_err();
+        assert!(matches!(file_error(&err), Some(FileError::InvalidChunkSize { .. })));
+        assert!(FileStore::new(engine, Some("bad bucket")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resumable_upload() {
+        let (store, _engine, _temp_dir) = create_store(Some("uploads"));
+        let data = content(1050);
+        let info = store.begin(named("video.mp4", 100)).await.unwrap();
+
+        // Appends continue partly filled chunks
+        store.append(info.id, 0, &data[..250]).await.unwrap();
+        store.append(info.id, 250, &data[250..333]).await.unwrap();
+        let err = store.append(info.id, 250, &data[250..]).await.unwrap_err();
+        assert_eq!(file_error(&err), Some(&FileError::OffsetMismatch { id: info.id, expected: 333, offset: 250 }));
+        let err = store.read(info.id).await.unwrap_err();
+        assert_eq!(file_error(&err), Some(&FileError::Unfinished(info.id)));
+
+        // After a disconnect the client resumes from the reported length
+        let resumed = store.info(info.id).await.unwrap();
+        assert_eq!((resumed.length, resumed.is_finished()), (333, false));
+        store.append(info.id, resumed.length, &data[333..]).await.unwrap();
+        let finished = store.finish(info.id).await.unwrap();
+        assert_eq!(store.read(info.id).await.unwrap(), data);
+        assert_eq!(store.list(None).unwrap(), [finished]);
+
+        let err = store.append(info.id, 1050, b"more").await.unwrap_err();
+        assert_eq!(file_error(&err), Some(&FileError::Finished(info.id)));
+    }
+
+    #[tokio::test]
+    async fn test_delete_list_and_corruption() {
+        let (store, engine, _temp_dir) = create_store(None);
+        let kept = store.put(named("b.txt", 4), b"hello world").await.unwrap();
+        let doomed = store.put(named("a.txt", 4), b"goodbye").await.unwrap();
+        let pending = store.begin(named("c.txt", 4)).await.unwrap();
+
+        let names: Vec<String> = store.list(None).unwrap().into_iter().map(|f| f.filename).collect();
+        assert_eq!(names, ["a.txt", "b.txt", "c.txt"]);
+        let finished = store.list(Some(Filter::eq("finished", true))).unwrap();
+        assert_eq!(finished.len(), 2);
+
+        assert!(store.delete(doomed.id).await.unwrap());
+        assert!(!store.delete(doomed.id).await.unwrap());
+        assert!(store.delete(pending.id).await.unwrap());
+        let err = store.info(doomed.id).await.unwrap_err();
+        assert_eq!(file_error(&err), Some(&FileError::NotFound(doomed.id)));
+        assert_eq!(engine.scan_collection("fs.chunks").unwrap().len(), 3);
+
+        // A damaged chunk fails its checksum instead of returning bad bytes
+        let id = chunk_id(kept.id, 1);
+        let mut chunk = engine.get_document("fs.chunks", id).await.unwrap().unwrap();
+        chunk.insert("data".to_string(), Value::Binary(b"WORL".to_vec()));
+        engine.update_document("fs.chunks", id, chunk).await.unwrap();
+        assert_eq!(store.read_range(&kept, 0, 4).await.unwrap(), b"hell");
+        let err = store.read(kept.id).await.unwrap_err();
+        assert_eq!(file_error(&err), Some(&FileError::CorruptChunk { id: kept.id, n: 1 }));
+
+        let (empty, _engine, _temp_dir) = create_store(Some("unused"));
+        assert!(empty.list(None).unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_appends_at_one_offset() {
+        let (store, _engine, _temp_dir) = create_store(None);
+        let store = Arc::new(store);
+        let info = store.begin(named("race.bin", 10)).await.unwrap();
+
+        let appends: Vec<_> = (0..8u8)
+            .map(|byte| {
+                let store = store.clone();
+                tokio::spawn(async move { store.append(info.id, 0, &[byte; 25]).await.map(|_| byte) })
+            })
+            .collect();
+        let mut winners = Vec::new();
+        for append in appends {
+            match append.await.unwrap() {
+                Ok(byte) => winners.push(byte),
+                Err(e) => assert_eq!(file_error(&e), Some(&FileError::OffsetMismatch { id: info.id, expected: 25, offset: 0 })),
+            }
+        }
+
+        // Exactly one append lands; the file holds its bytes alone
+        assert_eq!(winners.len(), 1);
+        let finished = store.finish(info.id).await.unwrap();
+        assert_eq!(finished.length, 25);
+        assert_eq!(store.read(info.id).await.unwrap(), [winners[0]; 25]);
+    }
+}
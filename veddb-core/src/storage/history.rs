@@ -0,0 +1,337 @@
+//! Document version history
+//!
+//! A collection with history enabled keeps every version of its documents
+//! in the persistent layer's "history" column family. Each insert, update
+//! and delete adds a [`DocumentVersion`], a delete as a tombstone without a
+//! document, in the same batch as the write itself. Documents written
+//! before history was enabled get their state at that moment as version 1
+//! on their first write after it.
+//!
+//! Past states are read at a point in time ([`HistoryPoint::AsOf`]) or by
+//! version number ([`HistoryPoint::Version`]). A [`VersionDirectory`] of
+//! each document's [`VersionStamp`]s picks the version answering a point,
+//! so a query reads one version per document. Retention drops the oldest
+//! versions once they have been superseded for longer than `max_age_secs`,
+//! or once a document has more than `max_versions` past versions.
+
+use crate::document::{Document, DocumentId};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Retention of a collection's past versions; unlimited when neither is set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryOptions {
+    /// Seconds a version is kept after being superseded
+    #[serde(default, alias = "maxAgeSecs", skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+    /// Most past versions kept per document, besides its current one
+    #[serde(default, alias = "maxVersions", skip_serializing_if = "Option::is_none")]
+    pub max_versions: Option<u64>,
+}
+
+impl HistoryOptions {
+    /// Check that no limit is zero
+    pub fn validate(&self) -> Result<(), HistoryError> {
+        if self.max_age_secs == Some(0) || self.max_versions == Some(0) {
+            return Err(HistoryError::InvalidOptions);
+        }
+        Ok(())
+    }
+
+    /// Oldest moment whose state is still retained at `now`
+    fn age_cutoff(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let secs = i64::try_from(self.max_age_secs?).unwrap_or(i64::MAX);
+        let age = chrono::Duration::try_seconds(secs).unwrap_or(chrono::Duration::MAX);
+        Some(now.checked_sub_signed(age).unwrap_or(DateTime::<Utc>::MIN_UTC))
+    }
+}
+
+/// History settings of a collection, as saved with its metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    #[serde(flatten)]
+    pub options: HistoryOptions,
+    /// When history was enabled; no earlier state can be read
+    pub enabled_at: DateTime<Utc>,
+}
+
+impl HistoryConfig {
+    /// Earliest moment whose state can be read at `now`
+    pub fn horizon(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self.options.age_cutoff(now) {
+            Some(cutoff) => cutoff.max(self.enabled_at),
+            None => self.enabled_at,
+        }
+    }
+}
+
+/// One version of a document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentVersion {
+    pub id: DocumentId,
+    /// Starts at 1 and grows by one with every write
+    pub version: u64,
+    /// When the version was written
+    pub written_at: DateTime<Utc>,
+    /// The document as written, or `None` for a delete
+    pub document: Option<Document>,
+}
+
+impl DocumentVersion {
+    /// The document with its metadata carrying this version, unless deleted
+    pub fn state(&self) -> Option<Document> {
+        let mut doc = self.document.clone()?;
+        doc.metadata.version = self.version;
+        doc.metadata.updated_at = self.written_at;
+        Some(doc)
+    }
+}
+
+/// A version's number, write time and whether it is a delete, without its document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionStamp {
+    pub version: u64,
+    pub written_at: DateTime<Utc>,
+    pub deleted: bool,
+}
+
+impl From<&DocumentVersion> for VersionStamp {
+    fn from(version: &DocumentVersion) -> Self {
+        Self { version: version.version, written_at: version.written_at, deleted: version.document.is_none() }
+    }
+}
+
+/// Retained version stamps of a collection's documents, oldest first
+pub type VersionDirectory = BTreeMap<DocumentId, Vec<VersionStamp>>;
+
+/// What [`version_at`] reads of a version
+pub trait Versioned {
+    fn version(&self) -> u64;
+    fn written_at(&self) -> DateTime<Utc>;
+    fn is_delete(&self) -> bool;
+}
+
+impl Versioned for DocumentVersion {
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn written_at(&self) -> DateTime<Utc> {
+        self.written_at
+    }
+
+    fn is_delete(&self) -> bool {
+        self.document.is_none()
+    }
+}
+
+impl Versioned for VersionStamp {
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn written_at(&self) -> DateTime<Utc> {
+        self.written_at
+    }
+
+    fn is_delete(&self) -> bool {
+        self.deleted
+    }
+}
+
+/// A collection's documents at a history point, as far as their versions tell
+#[derive(Debug, Default)]
+pub struct HistorySnapshot {
+    /// The version of each document that existed at the point, by document id
+    pub versions: Vec<DocumentVersion>,
+    /// Documents with retained versions
+    pub tracked: HashSet<DocumentId>,
+    /// Tracked documents whose latest version is not a delete
+    pub live: u64,
+}
+
+/// Where in a collection's history to read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryPoint {
+    /// Each document as it was at this moment
+    AsOf(DateTime<Utc>),
+    /// Each document that had this version, as written in it
+    Version(u64),
+}
+
+/// Version history errors
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum HistoryError {
+    #[error("History retention limits must be above zero")]
+    InvalidOptions,
+
+    #[error("Collection '{0}' does not keep history")]
+    NotEnabled(String),
+
+    #[error("History of collection '{collection}' starts at {start}")]
+    BeforeHistory { collection: String, start: DateTime<Utc> },
+
+    #[error("History of document {id} in collection '{collection}' no longer reaches back to {point}")]
+    Pruned { collection: String, id: DocumentId, point: String },
+}
+
+impl std::fmt::Display for HistoryPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryPoint::AsOf(time) => write!(f, "{}", time.to_rfc3339()),
+            HistoryPoint::Version(version) => write!(f, "version {}", version),
+        }
+    }
+}
+
+/// The version of a document found at a history point
+#[derive(Debug)]
+pub enum VersionLookup<'a, V = DocumentVersion> {
+    /// The document existed in this version
+    Found(&'a V),
+    /// The document did not exist at that point
+    Absent,
+    /// The versions that would answer have been dropped by retention
+    Pruned,
+}
+
+/// Look up a document's version at `point` among its retained `versions`,
+/// which are in version order
+pub fn version_at<V: Versioned>(versions: &[V], point: HistoryPoint) -> VersionLookup<'_, V> {
+    let Some(oldest) = versions.first() else {
+        return VersionLookup::Absent;
+    };
+    let found = match point {
+        HistoryPoint::AsOf(time) => match versions.partition_point(|v| v.written_at() <= time) {
+            0 if oldest.version() > 1 => return VersionLookup::Pruned,
+            0 => return VersionLookup::Absent,
+            after => &versions[after - 1],
+        },
+        HistoryPoint::Version(version) => match versions.binary_search_by_key(&version, V::version) {
+            Ok(index) => &versions[index],
+            Err(_) if version < oldest.version() => return VersionLookup::Pruned,
+            Err(_) => return VersionLookup::Absent,
+        },
+    };
+    match found.is_delete() {
+        false => VersionLookup::Found(found),
+        true => VersionLookup::Absent,
+    }
+}
+
+/// Number of a document's oldest `versions` that retention drops at `now`
+///
+/// The current version is only dropped when it is a tombstone older than
+/// `max_age_secs`, and then with all the versions before it.
+pub fn expired_versions(versions: &[DocumentVersion], options: &HistoryOptions, now: DateTime<Utc>) -> usize {
+    let Some(current) = versions.last() else {
+        return 0;
+    };
+    let past = versions.len() - 1;
+    let mut expired = match options.max_versions {
+        Some(max) => past.saturating_sub(usize::try_from(max).unwrap_or(usize::MAX)),
+        None => 0,
+    };
+    if let Some(cutoff) = options.age_cutoff(now) {
+        if current.document.is_none() && current.written_at < cutoff {
+            return versions.len();
+        }
+        // A version is superseded when the next one is written
+        let superseded = versions.windows(2).take_while(|pair| pair[1].written_at < cutoff).count();
+        expired = expired.max(superseded);
+    }
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Value;
+
+    fn versions(id: DocumentId, states: &[(u64, i64, Option<i32>)]) -> Vec<DocumentVersion> {
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        states
+            .iter()
+            .map(|&(version, secs, n)| DocumentVersion {
+                id,
+                version,
+                written_at: start + chrono::Duration::seconds(secs),
+                document: n.map(|n| {
+                    let mut doc = Document::with_id(id);
+                    doc.insert("n".to_string(), Value::Int32(n));
+                    doc
+                }),
+            })
+            .collect()
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::<Utc>::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn n(lookup: VersionLookup<'_>) -> Option<i64> {
+        match lookup {
+            VersionLookup::Found(version) => version.state().and_then(|doc| doc.get("n").and_then(Value::as_i64)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_version_at_reads_states_and_tombstones() {
+        let id = DocumentId::new();
+        let history = versions(id, &[(1, 0, Some(1)), (2, 10, Some(2)), (3, 20, None)]);
+
+        assert!(matches!(version_at(&history, HistoryPoint::AsOf(at(-1))), VersionLookup::Absent));
+        assert_eq!(n(version_at(&history, HistoryPoint::AsOf(at(0)))), Some(1));
+        assert_eq!(n(version_at(&history, HistoryPoint::AsOf(at(15)))), Some(2));
+        assert!(matches!(version_at(&history, HistoryPoint::AsOf(at(25))), VersionLookup::Absent));
+        assert_eq!(n(version_at(&history, HistoryPoint::Version(2))), Some(2));
+        assert!(matches!(version_at(&history, HistoryPoint::Version(4)), VersionLookup::Absent));
+
+        let VersionLookup::Found(found) = version_at(&history, HistoryPoint::Version(2)) else {
+            panic!("version 2 not found");
+        };
+        let state = found.state().unwrap();
+        assert_eq!((state.metadata.version, state.metadata.updated_at), (2, at(10)));
+
+        // Without version 1, earlier points can no longer be answered
+        assert!(matches!(version_at(&history[1..], HistoryPoint::AsOf(at(5))), VersionLookup::Pruned));
+        assert!(matches!(version_at(&history[1..], HistoryPoint::Version(1)), VersionLookup::Pruned));
+        assert!(matches!(version_at::<DocumentVersion>(&[], HistoryPoint::Version(1)), VersionLookup::Absent));
+
+        // Stamps answer the same without the documents
+        let stamps: Vec<VersionStamp> = history.iter().map(VersionStamp::from).collect();
+        assert!(matches!(version_at(&stamps, HistoryPoint::AsOf(at(15))), VersionLookup::Found(stamp) if stamp.version == 2));
+        assert!(matches!(version_at(&stamps, HistoryPoint::AsOf(at(25))), VersionLookup::Absent));
+        assert!(matches!(version_at(&stamps[1..], HistoryPoint::Version(1)), VersionLookup::Pruned));
+    }
+
+    #[test]
+    fn test_retention_by_count_and_age() {
+        let id = DocumentId::new();
+        let history = versions(id, &[(1, 0, Some(1)), (2, 10, Some(2)), (3, 20, Some(3)), (4, 30, Some(4))]);
+        let unlimited = HistoryOptions::default();
+        assert_eq!(expired_versions(&history, &unlimited, at(1000)), 0);
+
+        let count = HistoryOptions { max_age_secs: None, max_versions: Some(2) };
+        assert_eq!(expired_versions(&history, &count, at(1000)), 1);
+
+        // Versions 1 and 2 were superseded more than 15 seconds before
+        let age = HistoryOptions { max_age_secs: Some(15), max_versions: None };
+        assert_eq!(expired_versions(&history, &age, at(40)), 2);
+        assert_eq!(expired_versions(&history, &age, at(1000)), 3);
+
+        let deleted = versions(id, &[(1, 0, Some(1)), (2, 10, None)]);
+        // A deleted document goes whole once its delete is past the limit
+        assert_eq!(expired_versions(&deleted, &age, at(20)), 0);
+        assert_eq!(expired_versions(&deleted, &age, at(30)), 2);
+
+        let config = HistoryConfig { options: age, enabled_at: at(0) };
+        assert_eq!(config.horizon(at(10)), at(0));
+        assert_eq!(config.horizon(at(100)), at(85));
+
+        assert_eq!(HistoryOptions { max_age_secs: Some(0), max_versions: None }.validate(), Err(HistoryError::InvalidOptions));
+        assert!(count.validate().is_ok());
+    }
+}
@@ -7,18 +7,73 @@
 
 use crate::cache::cache_layer::{CacheLayer, CacheConfig};
 use crate::cache::data_structures::CacheData;
-use crate::document::{Document, DocumentId};
-use crate::schema::{CacheStrategy, CacheWarmingStrategy, Schema};
+use crate::document::{Document, DocumentId, Value};
+use crate::schema::{
+    CacheStrategy, CacheWarmingStrategy, CollectionCacheConfig, IndexDefinition, Schema, ValidationAction,
+    ValidatorRegistry,
+};
+use crate::storage::capped::{self, CappedCollection, CappedError, CappedOptions};
+use crate::storage::history::{DocumentVersion, HistoryError, HistoryOptions, HistoryPoint};
 use crate::storage::persistent::PersistentLayer;
+use crate::storage::references::{self, DeleteOutcome, DeletePlan, ReferenceError, ReferenceRule};
 use crate::index::manager::IndexManager; // Import IndexManager
+use crate::index::builder::{IndexBuild, IndexBuildProgress, IndexBuildState, IndexBuilder};
+use crate::index::btree::IndexError;
+use crate::protocol::IndexOptions;
+use crate::index::statistics::{KeyDistribution, PerformanceTimer};
+use crate::query::explain::{ExplainVerbosity, StageStats};
+use crate::query::plan_cache::{PlanCache, PlanCacheEntry};
+use crate::query::executor::{DocumentSource, QueryExecutionError};
+use crate::storage::views::{ViewDefinition, ViewKind, ViewRegistry};
+use crate::replication::SyncManager;
+use crate::wal::{Operation, WalWriter};
 use anyhow::{Context, Result};
 use parking_lot::RwLock;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 
+/// Metadata key holding the serialized view definitions
+const VIEWS_METADATA_KEY: &str = "views";
+
+/// Most violations listed by a [`RevalidationReport`]; the rest are only counted
+const MAX_REPORTED_VIOLATIONS: usize = 1000;
+
+/// Most finished index builds kept for progress reports; older ones are forgotten
+const MAX_FINISHED_INDEX_BUILDS: usize = 100;
+
+/// Lock of each document by collection and id, held weakly so unused ones can be dropped
+type DocumentLocks = HashMap<(String, DocumentId), std::sync::Weak<tokio::sync::Mutex<()>>>;
+
+/// Metadata key holding a collection's schema
+fn collection_schema_key(collection: &str) -> String {
+    format!("collection:{}", collection)
+}
+
+/// WAL operation recording an update as the top-level fields it sets and removes
+fn update_operation(collection: &str, old: &Document, new: &Document) -> Operation {
+    let changes = new
+        .fields
+        .iter()
+        .filter(|(name, value)| old.fields.get(*name) != Some(*value))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    let removed = old.fields.keys().filter(|name| !new.fields.contains_key(*name)).cloned().collect();
+    Operation::Update { collection: collection.to_string(), id: new.id, changes, removed }
+}
+
+/// Metadata key holding a capped collection's size limits
+fn capped_options_key(collection: &str) -> String {
+    format!("capped:{}", collection)
+}
+
+/// Metadata key of an index's latest build record
+fn index_build_key(collection: &str, index: &str) -> String {
+    format!("index_build:{}:{}", collection, index)
+}
+
 /// Hybrid Storage Engine coordinating cache and persistent layers
 pub struct HybridStorageEngine {
     /// Cache layer for in-memory storage
@@ -27,10 +82,30 @@ pub struct HybridStorageEngine {
     persistent_layer: Arc<PersistentLayer>,
     /// Collection schemas
     schemas: Arc<RwLock<HashMap<String, Schema>>>,
+    /// Custom validators named by schemas
+    validators: Arc<ValidatorRegistry>,
     /// Index managers per collection
     index_managers: Arc<RwLock<HashMap<String, Arc<IndexManager>>>>,
+    /// Query plan caches per collection
+    plan_caches: Arc<RwLock<HashMap<String, Arc<PlanCache>>>>,
+    /// Index builds by id, running or finished
+    index_builds: Arc<RwLock<HashMap<String, Arc<IndexBuild>>>>,
+    /// Streams documents into indexes being built
+    index_builder: Arc<IndexBuilder>,
     /// Write-behind queue
     write_behind_queue: Arc<RwLock<Vec<WriteBehindEntry>>>,
+    /// Plain and materialized views
+    views: Arc<ViewRegistry>,
+    /// Insertion order and size accounting of capped collections
+    capped: Arc<RwLock<HashMap<String, Arc<CappedCollection>>>>,
+    /// Serializes reference checks with deletes of referenced documents
+    references_lock: tokio::sync::Mutex<()>,
+    /// Locks of documents read and rewritten by their holders, dropped once unused
+    document_locks: parking_lot::Mutex<DocumentLocks>,
+    /// Write-ahead log of document writes and the sync manager broadcasting its entries
+    wal: RwLock<Option<(Arc<WalWriter>, Arc<SyncManager>)>>,
+    /// Held across a WAL append and its broadcasts only, keeping both in sequence order
+    wal_order: tokio::sync::Mutex<()>,
     /// Statistics
     stats: Arc<HybridStorageStats>,
 }
@@ -45,17 +120,131 @@ impl HybridStorageEngine {
             cache_layer: Arc::new(CacheLayer::new(cache_config)),
             persistent_layer,
             schemas: Arc::new(RwLock::new(HashMap::new())),
+            validators: Arc::new(ValidatorRegistry::new()),
             index_managers: Arc::new(RwLock::new(HashMap::new())),
+            plan_caches: Arc::new(RwLock::new(HashMap::new())),
+            index_builds: Arc::new(RwLock::new(HashMap::new())),
+            index_builder: Arc::new(IndexBuilder::new()),
             write_behind_queue: Arc::new(RwLock::new(Vec::new())),
+            views: Arc::new(ViewRegistry::new()),
+            capped: Arc::new(RwLock::new(HashMap::new())),
+            references_lock: tokio::sync::Mutex::new(()),
+            document_locks: parking_lot::Mutex::new(HashMap::new()),
+            wal: RwLock::new(None),
+            wal_order: tokio::sync::Mutex::new(()),
             stats: Arc::new(HybridStorageStats::default()),
         }
     }
 
     /// Register a collection schema
+    ///
+    /// The schema lives in memory only; see
+    /// [`create_collection_with_schema`](Self::create_collection_with_schema)
+    /// for one that survives a restart.
     pub fn register_schema(&self, collection: String, schema: Schema) {
         self.schemas.write().insert(collection, schema);
     }
 
+    /// Reload the schemas saved with their collections by a previous run
+    pub fn restore_schemas(&self) -> Result<usize> {
+        let mut restored = 0;
+        for collection in self.list_collections()? {
+            let Some(data) = self.persistent_layer.get_metadata(&collection_schema_key(&collection))? else {
+                continue;
+            };
+            let schema: Schema = serde_json::from_slice(&data)
+                .with_context(|| format!("Failed to parse schema of collection '{}'", collection))?;
+            self.schemas.write().insert(collection, schema);
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Apply schema defaults (on insert) and validate a document against its collection's schema
+    ///
+    /// A violation fails the write unless the schema's validation action is `warn`.
+    fn enforce_schema(&self, collection: &str, doc: &mut Document, apply_defaults: bool) -> Result<()> {
+        let schemas = self.schemas.read();
+        let Some(schema) = schemas.get(collection) else {
+            return Ok(());
+        };
+
+        let defaults = if apply_defaults { schema.apply_defaults(doc) } else { Ok(()) };
+        match (defaults.and_then(|()| schema.validate_with(doc, &self.validators)), schema.validation_action) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), ValidationAction::Error) => Err(anyhow::Error::new(e)
+                .context(format!("Document failed validation for collection '{}'", collection))),
+            (Err(e), ValidationAction::Warn) => {
+                log::warn!("Document {} in collection '{}' failed validation: {}", doc.id, collection, e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reference fields declared by the registered schemas
+    fn reference_rules(&self) -> Vec<ReferenceRule> {
+        references::rules(&self.schemas.read())
+    }
+
+    /// Hold the references lock while writing to a collection with enforced references
+    async fn lock_references(
+        &self,
+        rules: &[ReferenceRule],
+        collection: &str,
+    ) -> Option<tokio::sync::MutexGuard<'_, ()>> {
+        if rules.iter().any(|rule| rule.enforce && rule.collection == collection) {
+            Some(self.references_lock.lock().await)
+        } else {
+            None
+        }
+    }
+
+    /// Hold until no other holder of a document's lock is done with it
+    ///
+    /// Updates and deletes take the lock themselves, so each reads the state
+    /// it replaces with no other write of the document in between. Callers
+    /// keeping a read and the write computed from it together hold the lock
+    /// and write through the `_locked` variants, as the lock is not reentrant.
+    pub async fn lock_document(&self, collection: &str, doc_id: DocumentId) -> DocumentLock {
+        let lock = {
+            let mut locks = self.document_locks.lock();
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            let key = (collection.to_string(), doc_id);
+            match locks.get(&key).and_then(std::sync::Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    locks.insert(key, Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        DocumentLock { collection: collection.to_string(), doc_id, _guard: lock.lock_owned().await }
+    }
+
+    /// Check that the enforced references of a document point at existing documents
+    fn check_references(&self, rules: &[ReferenceRule], collection: &str, doc: &Document) -> Result<()> {
+        for (rule, id) in references::required(rules, collection, doc)? {
+            if self.get_with_pending_writes(&rule.target, id)?.is_none() {
+                return Err(ReferenceError::Missing {
+                    field: rule.field.clone(),
+                    target: rule.target.clone(),
+                    id,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Registry of the custom validators schemas refer to by name
+    ///
+    /// Validators must be registered before documents using them are written;
+    /// a schema naming an unregistered validator rejects every write.
+    pub fn validator_registry(&self) -> &Arc<ValidatorRegistry> {
+        &self.validators
+    }
+
     /// Get a collection schema
     pub fn get_schema(&self, collection: &str) -> Option<Schema> {
         self.schemas.read().get(collection).cloned()
@@ -77,12 +266,74 @@ impl HybridStorageEngine {
     }
 
     /// Insert a document
+    ///
+    /// In a capped collection the document gets a new id, returned here,
+    /// that orders it after every earlier insert, and the oldest documents
+    /// are evicted once a cap is exceeded.
     pub async fn insert_document(
         &self,
         collection: &str,
-        doc: Document,
+        mut doc: Document,
     ) -> Result<DocumentId> {
-        let doc_id = doc.id;
+        self.ensure_writable(collection)?;
+        let capped = self.capped_collection(collection);
+        let _capped_inserts = match &capped {
+            Some(capped) => {
+                let guard = capped.lock_inserts().await;
+                doc.id = capped.next_id();
+                Some(guard)
+            }
+            None => None,
+        };
+        self.enforce_schema(collection, &mut doc, true)?;
+        let size = capped::document_size(&doc);
+        if let Some(capped) = &capped {
+            capped.check_size(collection, size)?;
+        }
+
+        let doc_id = {
+            let rules = self.reference_rules();
+            let _references = self.lock_references(&rules, collection).await;
+            self.check_references(&rules, collection, &doc)?;
+            let doc_id = doc.id;
+
+            // Index first so a unique violation aborts the write
+            let indexes = self.index_manager(collection)?;
+            indexes.insert_document(doc_id, &doc)?;
+            if let Err(e) = self.insert_into_layers(collection, doc_id, &doc).await {
+                indexes.remove_document(doc_id, &doc)?;
+                return Err(e);
+            }
+
+            if self.logs_writes() {
+                let operation = Operation::Insert { collection: collection.to_string(), doc: doc.clone() };
+                if let Err(e) = self.log_writes(vec![operation]).await {
+                    self.undo_write(collection, doc_id, Some(&doc), None).await;
+                    return Err(e);
+                }
+            }
+            self.maintain_views(collection, doc_id, &doc);
+            doc_id
+        };
+
+        if let Some(capped) = &capped {
+            for evicted in capped.push(doc_id, size) {
+                if let Err(e) = self.delete_document(collection, evicted).await {
+                    log::error!("Failed to evict document {} from capped collection '{}': {}", evicted, collection, e);
+                }
+            }
+        }
+
+        Ok(doc_id)
+    }
+
+    /// Write a new document to cache and persistent storage per the collection's strategy
+    async fn insert_into_layers(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+        doc: &Document,
+    ) -> Result<()> {
         let schema = self.get_schema(collection);
 
         if let Some(schema) = schema {
@@ -91,31 +342,31 @@ impl HybridStorageEngine {
             match strategy {
                 CacheStrategy::None => {
                     // Only persistent storage
-                    self.persistent_layer.insert_document(collection, doc_id, &doc)?;
+                    self.persistent_layer.insert_document(collection, doc_id, doc)?;
                     self.stats.record_persistent_write();
                 }
                 CacheStrategy::WriteThrough => {
                     // Write to both cache and persistent storage atomically
-                    self.write_through(collection, doc_id, &doc, &schema).await?;
+                    self.write_through(collection, doc_id, doc, &schema).await?;
                 }
                 CacheStrategy::WriteBehind { delay_ms } => {
                     // Write to cache immediately, queue persistent write
-                    self.write_behind(collection, doc_id, &doc, &schema, *delay_ms).await?;
+                    self.write_behind(collection, doc_id, doc, &schema, *delay_ms).await?;
                 }
                 CacheStrategy::ReadThrough => {
                     // Write to persistent, invalidate cache
-                    self.persistent_layer.insert_document(collection, doc_id, &doc)?;
+                    self.persistent_layer.insert_document(collection, doc_id, doc)?;
                     self.invalidate_cache_entry(collection, doc_id);
                     self.stats.record_persistent_write();
                 }
             }
         } else {
             // No schema, default to persistent only
-            self.persistent_layer.insert_document(collection, doc_id, &doc)?;
+            self.persistent_layer.insert_document(collection, doc_id, doc)?;
             self.stats.record_persistent_write();
         }
 
-        Ok(doc_id)
+        Ok(())
     }
 
     /// Get a document by ID
@@ -191,6 +442,102 @@ impl HybridStorageEngine {
         collection: &str,
         doc_id: DocumentId,
         doc: Document,
+    ) -> Result<()> {
+        let lock = self.lock_document(collection, doc_id).await;
+        self.write_update(&lock, doc, false).await
+    }
+
+    /// Update a document, recording the change as the fields it sets and removes
+    ///
+    /// Stores the same result as [`update_document`](Self::update_document);
+    /// only the WAL entry, and so the change event, differs from a replacement.
+    pub async fn patch_document(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+        doc: Document,
+    ) -> Result<()> {
+        let lock = self.lock_document(collection, doc_id).await;
+        self.write_update(&lock, doc, true).await
+    }
+
+    /// [`update_document`](Self::update_document) for a caller holding the document's lock
+    pub async fn update_document_locked(&self, lock: &DocumentLock, doc: Document) -> Result<()> {
+        self.write_update(lock, doc, false).await
+    }
+
+    /// [`patch_document`](Self::patch_document) for a caller holding the document's lock
+    pub async fn patch_document_locked(&self, lock: &DocumentLock, doc: Document) -> Result<()> {
+        self.write_update(lock, doc, true).await
+    }
+
+    /// Write an updated document, logging it as a patch or a replacement
+    ///
+    /// The document's lock keeps the read of the replaced version, the
+    /// index update, the write, its WAL entry and the views in one step.
+    async fn write_update(
+        &self,
+        lock: &DocumentLock,
+        mut doc: Document,
+        patch: bool,
+    ) -> Result<()> {
+        let (collection, doc_id) = (lock.collection(), lock.doc_id());
+        self.ensure_writable(collection)?;
+        self.enforce_schema(collection, &mut doc, false)?;
+        if let Some(capped) = self.capped_collection(collection) {
+            capped.check_size(collection, capped::document_size(&doc))?;
+        }
+        let rules = self.reference_rules();
+        let _references = self.lock_references(&rules, collection).await;
+        self.check_references(&rules, collection, &doc)?;
+
+        let indexes = self.index_manager(collection)?;
+        let logs_writes = self.logs_writes();
+        let previous = if indexes.has_indexes() || logs_writes {
+            self.get_document(collection, doc_id).await?
+        } else {
+            None
+        };
+
+        // A unique violation leaves the old entries in place
+        match &previous {
+            Some(old) => indexes.update_document(doc_id, old, &doc)?,
+            None => indexes.insert_document(doc_id, &doc)?,
+        }
+
+        if let Err(e) = self.update_in_layers(collection, doc_id, &doc).await {
+            match &previous {
+                Some(old) => indexes.update_document(doc_id, &doc, old)?,
+                None => indexes.remove_document(doc_id, &doc)?,
+            }
+            return Err(e);
+        }
+
+        if logs_writes {
+            let operation = match (&previous, patch) {
+                (Some(old), true) => update_operation(collection, old, &doc),
+                _ => Operation::Replace { collection: collection.to_string(), doc: doc.clone() },
+            };
+            if let Err(e) = self.log_writes(vec![operation]).await {
+                self.undo_write(collection, doc_id, Some(&doc), previous.as_ref()).await;
+                return Err(e);
+            }
+        }
+
+        self.maintain_views(collection, doc_id, &doc);
+        if let Some(capped) = self.capped_collection(collection) {
+            capped.resize(doc_id, capped::document_size(&doc));
+        }
+
+        Ok(())
+    }
+
+    /// Write an updated document to cache and persistent storage per the collection's strategy
+    async fn update_in_layers(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+        doc: &Document,
     ) -> Result<()> {
         let schema = self.get_schema(collection);
 
@@ -199,23 +546,23 @@ impl HybridStorageEngine {
             
             match strategy {
                 CacheStrategy::None => {
-                    self.persistent_layer.update_document(collection, doc_id, &doc)?;
+                    self.persistent_layer.update_document(collection, doc_id, doc)?;
                     self.stats.record_persistent_write();
                 }
                 CacheStrategy::WriteThrough => {
-                    self.write_through(collection, doc_id, &doc, &schema).await?;
+                    self.write_through(collection, doc_id, doc, &schema).await?;
                 }
                 CacheStrategy::WriteBehind { delay_ms } => {
-                    self.write_behind(collection, doc_id, &doc, &schema, *delay_ms).await?;
+                    self.write_behind(collection, doc_id, doc, &schema, *delay_ms).await?;
                 }
                 CacheStrategy::ReadThrough => {
-                    self.persistent_layer.update_document(collection, doc_id, &doc)?;
+                    self.persistent_layer.update_document(collection, doc_id, doc)?;
                     self.invalidate_cache_entry(collection, doc_id);
                     self.stats.record_persistent_write();
                 }
             }
         } else {
-            self.persistent_layer.update_document(collection, doc_id, &doc)?;
+            self.persistent_layer.update_document(collection, doc_id, doc)?;
             self.stats.record_persistent_write();
         }
 
@@ -227,6 +574,96 @@ impl HybridStorageEngine {
         &self,
         collection: &str,
         doc_id: DocumentId,
+    ) -> Result<bool> {
+        Ok(self.delete_document_cascading(collection, doc_id).await?.deleted)
+    }
+
+    /// [`delete_document_cascading`](Self::delete_document_cascading) for a
+    /// caller holding the document's lock
+    pub async fn delete_document_locked(&self, lock: &DocumentLock) -> Result<DeleteOutcome> {
+        self.delete_cascading(lock).await
+    }
+
+    /// Delete a document and apply the `on_delete` actions of references to it
+    ///
+    /// The documents deleted by `cascade` and updated by `set_null` are
+    /// written with the requested delete in one batch. Fails with
+    /// [`ReferenceError::Restricted`] and changes nothing if a `restrict`
+    /// reference points at a deleted document.
+    pub async fn delete_document_cascading(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+    ) -> Result<DeleteOutcome> {
+        let lock = self.lock_document(collection, doc_id).await;
+        self.delete_cascading(&lock).await
+    }
+
+    /// Delete a locked document and apply the `on_delete` actions of references to it
+    ///
+    /// Documents deleted by `cascade` or updated by `set_null` are not locked.
+    async fn delete_cascading(&self, lock: &DocumentLock) -> Result<DeleteOutcome> {
+        let (collection, doc_id) = (lock.collection(), lock.doc_id());
+        self.ensure_writable(collection)?;
+
+        let rules = self.reference_rules();
+        if !rules.iter().any(|rule| rule.target == collection) {
+            let deleted = self.delete_unreferenced(collection, doc_id).await?;
+            return Ok(DeleteOutcome { deleted, ..Default::default() });
+        }
+
+        let _references = self.references_lock.lock().await;
+        // Queued writes go first so the batch below is not overwritten by older ones
+        self.flush_write_behind(collection).await?;
+        for rule in &rules {
+            self.flush_write_behind(&rule.collection).await?;
+        }
+
+        let Some(doc) = self.persistent_layer.get_document(collection, doc_id)? else {
+            return Ok(DeleteOutcome::default());
+        };
+        let plan = DeletePlan::build(&rules, collection, doc, |name| self.scan_with_pending_writes(name))?;
+        self.apply_delete_plan(&plan).await?;
+        Ok(plan.outcome())
+    }
+
+    /// Delete a document no reference can point at
+    async fn delete_unreferenced(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+    ) -> Result<bool> {
+        let indexes = self.index_manager(collection)?;
+        let logs_writes = self.logs_writes();
+        let previous = if indexes.has_indexes() || logs_writes {
+            self.get_document(collection, doc_id).await?
+        } else {
+            None
+        };
+
+        let deleted = self.delete_from_layers(collection, doc_id).await?;
+        if let Some(old) = &previous {
+            indexes.remove_document(doc_id, old)?;
+        }
+        if deleted && logs_writes {
+            let operation = Operation::Delete { collection: collection.to_string(), id: doc_id };
+            if let Err(e) = self.log_writes(vec![operation]).await {
+                self.undo_write(collection, doc_id, None, previous.as_ref()).await;
+                return Err(e);
+            }
+        }
+        self.views.apply_delete(collection, doc_id);
+        if let Some(capped) = self.capped_collection(collection) {
+            capped.remove(doc_id);
+        }
+        Ok(deleted)
+    }
+
+    /// Delete a document from cache and persistent storage per the collection's strategy
+    async fn delete_from_layers(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
     ) -> Result<bool> {
         let schema = self.get_schema(collection);
 
@@ -278,682 +715,3735 @@ impl HybridStorageEngine {
         }
     }
 
-    /// Create a collection
-    pub fn create_collection(&self, name: &str) -> Result<()> {
-        self.persistent_layer.create_collection(name)
-    }
-
-    /// List collections
-    pub fn list_collections(&self) -> Result<Vec<String>> {
-        self.persistent_layer.list_collections()
-    }
-
-    /// Scan all documents in a collection (for snapshots/backups)
-    /// Note: This primarily scans persistent storage. For full consistency,
-    /// consider calling flush() before scanning.
-    pub fn scan_collection(&self, collection: &str) -> Result<Vec<Document>> {
-        self.persistent_layer.scan_collection(collection)
-    }
+    /// Write a delete plan's deletes and updates to storage, indexes, caches and views
+    async fn apply_delete_plan(&self, plan: &DeletePlan) -> Result<()> {
+        // Index updates first so a unique violation aborts the batch
+        let mut indexed = Vec::new();
+        for (collection, old, new) in &plan.updates {
+            let result = self
+                .index_manager(collection)
+                .and_then(|indexes| Ok(indexes.update_document(new.id, old, new)?));
+            if let Err(e) = result {
+                self.revert_index_updates(&indexed);
+                return Err(e);
+            }
+            indexed.push((collection.as_str(), old, new));
+        }
 
-    /// Drop a collection
-    pub fn drop_collection(&self, collection: &str) -> Result<()> {
-        // Clear from cache first
-        self.invalidate_collection_cache(collection);
-        // Drop from persistent storage
-        self.persistent_layer.drop_collection(collection)
-    }
+        let writes: Vec<(&str, DocumentId, Option<&Document>)> = plan
+            .deletes
+            .iter()
+            .map(|(collection, doc)| (collection.as_str(), doc.id, None))
+            .chain(plan.updates.iter().map(|(collection, _, new)| (collection.as_str(), new.id, Some(new))))
+            .collect();
+        if let Err(e) = self.persistent_layer.write_documents(&writes) {
+            self.revert_index_updates(&indexed);
+            return Err(e);
+        }
+        self.stats.record_persistent_write();
 
-    /// Create an index
-    pub fn create_index(&self, collection: &str, name: &str, fields: Vec<crate::protocol::IndexField>, unique: bool) -> Result<()> {
-        self.persistent_layer.create_index(collection, name, fields, unique)
-    }
+        if self.logs_writes() {
+            let operations = plan
+                .deletes
+                .iter()
+                .map(|(collection, doc)| Operation::Delete { collection: collection.clone(), id: doc.id })
+                .chain(plan.updates.iter().map(|(collection, old, new)| update_operation(collection, old, new)))
+                .collect();
+            if let Err(e) = self.log_writes(operations).await {
+                let restores: Vec<(&str, DocumentId, Option<&Document>)> = plan
+                    .deletes
+                    .iter()
+                    .map(|(collection, doc)| (collection.as_str(), doc.id, Some(doc)))
+                    .chain(plan.updates.iter().map(|(collection, old, _)| (collection.as_str(), old.id, Some(old))))
+                    .collect();
+                if let Err(restore) = self.persistent_layer.write_documents(&restores) {
+                    log::error!("Failed to restore documents of an unlogged delete: {}", restore);
+                }
+                self.revert_index_updates(&indexed);
+                return Err(e);
+            }
+        }
 
-    /// List indexes
-    pub fn list_indexes(&self, collection: &str) -> Result<Vec<serde_json::Value>> {
-        self.persistent_layer.list_indexes(collection)
+        for (collection, doc) in &plan.deletes {
+            self.invalidate_cache_entry(collection, doc.id);
+            self.index_manager(collection)?.remove_document(doc.id, doc)?;
+            self.views.apply_delete(collection, doc.id);
+            if let Some(capped) = self.capped_collection(collection) {
+                capped.remove(doc.id);
+            }
+        }
+        for (collection, _, new) in &plan.updates {
+            self.invalidate_cache_entry(collection, new.id);
+            self.maintain_views(collection, new.id, new);
+            if let Some(capped) = self.capped_collection(collection) {
+                capped.resize(new.id, capped::document_size(new));
+            }
+        }
+        Ok(())
     }
 
-    /// Drop an index
-    pub fn drop_index(&self, collection: &str, name: &str) -> Result<()> {
-        self.persistent_layer.drop_index(collection, name)
+    /// Record document writes in `writer` and broadcast each entry through `sync`
+    ///
+    /// Each write is appended once applied to storage and indexes, and before
+    /// views and capped collections see it; a write whose entry cannot be
+    /// appended is undone and fails, so the log holds committed changes only.
+    /// Change streams follow `sync`'s WAL stream and read the WAL files to resume.
+    pub fn attach_wal(&self, writer: Arc<WalWriter>, sync: Arc<SyncManager>) {
+        *self.wal.write() = Some((writer, sync));
     }
 
-    /// Get or create IndexManager for a collection
-    pub fn get_index_manager(&self, collection: &str) -> Arc<IndexManager> {
-        let mut managers = self.index_managers.write();
-        managers
-            .entry(collection.to_string())
-            .or_insert_with(|| Arc::new(IndexManager::new(collection.to_string())))
-            .clone()
+    /// Attached write-ahead log and the sync manager broadcasting its entries
+    pub fn wal(&self) -> Option<(Arc<WalWriter>, Arc<SyncManager>)> {
+        self.wal.read().clone()
     }
 
-    /// Execute a query with index optimization
-    pub fn query(&self, collection: &str, query: &crate::query::Query) -> Result<Vec<Document>> {
-        use crate::query::QueryExecutor;
-        
-        // Get all documents from collection
-        let documents = self.scan_collection(collection)?;
-        
-        // Create executor
-        let mut executor = QueryExecutor::new();
-        
-        // If index manager exists for this collection, use it
-        let index_manager = self.get_index_manager(collection);
-        executor.set_index_manager(index_manager);
-        
-        // Execute query
-        executor.execute(documents, query)
-            .map_err(|e| anyhow::anyhow!("Query execution error: {}", e))
+    /// Whether document writes are appended to a WAL
+    fn logs_writes(&self) -> bool {
+        self.wal.read().is_some()
     }
 
-    /// Write-through strategy: update both cache and persistent storage
-    async fn write_through(
-        &self,
-        collection: &str,
-        doc_id: DocumentId,
-        doc: &Document,
-        schema: &Schema,
-    ) -> Result<()> {
-        // Write to persistent storage first
-        self.persistent_layer.insert_document(collection, doc_id, doc)?;
-        
-        // Then update cache
-        self.populate_cache(collection, doc_id, doc, schema).await?;
-        
-        self.stats.record_cache_write();
-        self.stats.record_persistent_write();
-        
+    /// Append applied document writes to the WAL in one batch and broadcast the entries
+    ///
+    /// `wal_order` is held across the append and the broadcasts only, so
+    /// entries reach change streams in sequence order.
+    async fn log_writes(&self, operations: Vec<Operation>) -> Result<()> {
+        let Some((writer, sync)) = self.wal() else { return Ok(()) };
+        let _order = self.wal_order.lock().await;
+        let entries = writer.append_entries(operations).await.context("Failed to append writes to the WAL")?;
+        for entry in entries {
+            let sequence = entry.sequence;
+            sync.update_sequence(sequence);
+            if let Err(e) = sync.broadcast_wal_entry(entry).await {
+                log::error!("Failed to broadcast WAL entry {}: {}", sequence, e);
+            }
+        }
         Ok(())
     }
 
-    /// Write-behind strategy: update cache immediately, queue persistent write
-    async fn write_behind(
+    /// Put back the document a write replaced after its WAL append failed
+    ///
+    /// `written` is the document the write stored, if any, and `previous`
+    /// the one it replaced or deleted. Failures are logged, as the write's
+    /// own error is the one returned.
+    async fn undo_write(
         &self,
         collection: &str,
         doc_id: DocumentId,
-        doc: &Document,
-        schema: &Schema,
-        delay_ms: u64,
-    ) -> Result<()> {
-        // Update cache immediately
-        self.populate_cache(collection, doc_id, doc, schema).await?;
-        
-        // Queue persistent write
-        self.queue_write_behind(WriteBehindEntry {
-            collection: collection.to_string(),
-            doc_id,
-            operation: WriteBehindOperation::Write(doc.clone()),
-            delay_ms,
-            queued_at: std::time::Instant::now(),
+        written: Option<&Document>,
+        previous: Option<&Document>,
+    ) {
+        let restored = match (written, previous) {
+            (_, None) => self.delete_from_layers(collection, doc_id).await.map(|_| ()),
+            (Some(_), Some(old)) => self.update_in_layers(collection, doc_id, old).await,
+            (None, Some(old)) => self.insert_into_layers(collection, doc_id, old).await,
+        };
+        if let Err(e) = restored {
+            log::error!("Failed to undo unlogged write of document {} in '{}': {}", doc_id, collection, e);
+        }
+
+        let reindexed = self.index_manager(collection).and_then(|indexes| {
+            match (written, previous) {
+                (Some(new), Some(old)) => indexes.update_document(doc_id, new, old)?,
+                (Some(new), None) => indexes.remove_document(doc_id, new)?,
+                (None, Some(old)) => indexes.insert_document(doc_id, old)?,
+                (None, None) => {}
+            }
+            Ok(())
         });
-        
-        self.stats.record_cache_write();
-        
-        Ok(())
+        if let Err(e) = reindexed {
+            log::error!("Failed to restore index entries of document {} in '{}': {}", doc_id, collection, e);
+        }
     }
 
-    /// Populate cache with document data
-    async fn populate_cache(
-        &self,
-        collection: &str,
-        doc_id: DocumentId,
-        doc: &Document,
-        schema: &Schema,
-    ) -> Result<()> {
-        let cache_key = self.make_cache_key(collection, doc_id);
-        let cache_data = self.document_to_cache_data(doc, schema)?;
-        let ttl = schema.cache_config.ttl;
-        
-        self.cache_layer.set_with_ttl(cache_key, cache_data, ttl)?;
-        
-        Ok(())
+    /// Apply a stored write to the materialized views over its collection
+    ///
+    /// The write is already committed, so a view that cannot take it is left
+    /// stale, to be rebuilt on its next read, rather than failing the write.
+    fn maintain_views(&self, collection: &str, doc_id: DocumentId, doc: &Document) {
+        if let Err(e) = self.views.apply_write(collection, doc_id, doc) {
+            log::warn!("Write of document {} in '{}' left a view stale: {}", doc_id, collection, e);
+        }
     }
 
-    /// Invalidate a cache entry
-    fn invalidate_cache_entry(&self, collection: &str, doc_id: DocumentId) {
-        let cache_key = self.make_cache_key(collection, doc_id);
-        self.cache_layer.remove(&cache_key);
-        self.stats.record_cache_invalidation();
+    /// Restore the index entries of documents whose update was not written
+    fn revert_index_updates(&self, indexed: &[(&str, &Document, &Document)]) {
+        for (collection, old, new) in indexed {
+            let reverted = self
+                .index_manager(collection)
+                .and_then(|indexes| Ok(indexes.update_document(old.id, new, old)?));
+            if let Err(e) = reverted {
+                log::error!("Failed to restore index entries of document {} in '{}': {}", old.id, collection, e);
+            }
+        }
     }
 
-    /// Invalidate all cache entries for a collection
-    pub fn invalidate_collection_cache(&self, collection: &str) {
-        // This is a simplified implementation
-        // In production, we'd need a more efficient way to track collection keys
-        let _prefix = format!("doc:{}:", collection);
-        
-        // For now, we'll just clear the entire cache if it's a collection invalidation
-        // A better approach would be to maintain a collection -> keys mapping
-        self.cache_layer.clear();
-        self.stats.record_cache_invalidation();
-    }
-
-    /// Queue a write-behind operation
-    fn queue_write_behind(&self, entry: WriteBehindEntry) {
-        self.write_behind_queue.write().push(entry);
+    /// Create a collection
+    pub fn create_collection(&self, name: &str) -> Result<()> {
+        self.persistent_layer.create_collection(name)
     }
 
-    /// Process write-behind queue (background task)
-    async fn process_write_behind_queue(&self) {
-        loop {
-            sleep(Duration::from_millis(50)).await;
-            
-            let now = std::time::Instant::now();
-            
-            // Collect entries ready to be processed
-            let entries_to_process: Vec<WriteBehindEntry> = {
-                let mut queue = self.write_behind_queue.write();
-                let mut entries = Vec::new();
-                let mut i = 0;
-                
-                while i < queue.len() {
-                    let entry = &queue[i];
-                    let elapsed = now.duration_since(entry.queued_at).as_millis() as u64;
-                    
-                    if elapsed >= entry.delay_ms {
-                        entries.push(queue.remove(i));
-                    } else {
-                        i += 1;
-                    }
-                }
-                
-                entries
-            }; // Lock is released here
-            
-            // Process entries without holding the lock
-            for entry in entries_to_process {
-                if let Err(e) = self.process_write_behind_entry(entry).await {
-                    eprintln!("Error processing write-behind entry: {}", e);
-                }
+    /// Change a collection's validation rules, cache policy and history retention
+    ///
+    /// The new settings are persisted and swapped in together, so the next
+    /// write sees all of them. Leaving `WriteBehind` persists the collection's
+    /// queued writes, and any change of cache policy drops cached entries so
+    /// they are reloaded under the new policy. History options are checked
+    /// before anything changes, and the schema is put back if history cannot
+    /// be enabled. Returns the schema version, new if the schema changed.
+    pub async fn modify_collection(&self, collection: &str, modification: CollectionModification) -> Result<u32> {
+        self.ensure_writable(collection)?;
+        if !self.list_collections()?.iter().any(|c| c == collection) {
+            anyhow::bail!("Collection '{}' not found", collection);
+        }
+        if let Some(options) = &modification.history {
+            options.validate()?;
+        }
+        let changes_schema = modification.schema.is_some()
+            || modification.validation_action.is_some()
+            || modification.cache_config.is_some();
+        if !changes_schema {
+            if let Some(options) = modification.history {
+                self.enable_history(collection, options).await?;
             }
+            return Ok(self.get_schema(collection).unwrap_or_default().version);
         }
-    }
 
-    /// Process a single write-behind entry
-    async fn process_write_behind_entry(&self, entry: WriteBehindEntry) -> Result<()> {
-        match entry.operation {
-            WriteBehindOperation::Write(doc) => {
-                self.persistent_layer.insert_document(&entry.collection, entry.doc_id, &doc)?;
-                self.stats.record_persistent_write();
+        let (stored, previous_cache, cache_config, version) = {
+            let mut schemas = self.schemas.write();
+            let stored = schemas.get(collection).cloned();
+            let current = stored.clone().unwrap_or_default();
+            let mut updated = current.clone();
+            if let Some(schema) = modification.schema {
+                updated.fields = schema.fields;
+                updated.strict = schema.strict;
+                updated.validation = schema.validation;
+                updated.validators = schema.validators;
             }
-            WriteBehindOperation::Delete => {
-                self.persistent_layer.delete_document(&entry.collection, entry.doc_id)?;
-                self.stats.record_persistent_write();
+            if let Some(action) = modification.validation_action {
+                updated.validation_action = action;
+            }
+            if let Some(cache_config) = modification.cache_config {
+                updated.cache_config = cache_config;
+            }
+            updated.version = current.version + 1;
+
+            let data = serde_json::to_vec(&updated).context("Failed to serialize schema")?;
+            self.persistent_layer.store_metadata(&collection_schema_key(collection), &data)?;
+            let (cache_config, version) = (updated.cache_config.clone(), updated.version);
+            schemas.insert(collection.to_string(), updated);
+            (stored, current.cache_config, cache_config, version)
+        };
+
+        if let Some(options) = modification.history {
+            if let Err(e) = self.enable_history(collection, options).await {
+                self.restore_schema(collection, stored);
+                return Err(e);
             }
         }
-        
-        Ok(())
+
+        if matches!(previous_cache.strategy, CacheStrategy::WriteBehind { .. })
+            && !matches!(cache_config.strategy, CacheStrategy::WriteBehind { .. })
+        {
+            self.flush_write_behind(collection).await?;
+        }
+        if previous_cache != cache_config {
+            self.invalidate_collection_cache(collection);
+        }
+        Ok(version)
     }
 
-    /// Warm caches based on warming strategies (background task)
-    async fn warm_caches(&self) {
-        // Wait a bit before starting cache warming
-        sleep(Duration::from_secs(5)).await;
-        
-        loop {
-            let schemas = self.schemas.read().clone();
-            
-            for (collection, schema) in schemas.iter() {
-                match &schema.cache_config.warming {
-                    CacheWarmingStrategy::None => {
-                        // No warming
-                    }
-                    CacheWarmingStrategy::PreloadOnStartup { limit } => {
-                        // This runs once at startup (we check if already warmed)
-                        if self.stats.cache_warmed.load(Ordering::Relaxed) == 0 {
-                            if let Err(e) = self.preload_collection(collection, *limit).await {
-                                eprintln!("Error preloading collection {}: {}", collection, e);
-                            }
-                        }
-                    }
-                    CacheWarmingStrategy::LazyLoad => {
-                        // Lazy load happens on access, nothing to do here
-                    }
-                    CacheWarmingStrategy::ScheduledRefresh { interval_seconds } => {
-                        // Refresh cache periodically
-                        if let Err(e) = self.refresh_collection_cache(collection).await {
-                            eprintln!("Error refreshing cache for {}: {}", collection, e);
-                        }
-                        sleep(Duration::from_secs(*interval_seconds)).await;
-                    }
+    /// Put back the schema a collection had before a modification that failed
+    fn restore_schema(&self, collection: &str, schema: Option<Schema>) {
+        let mut schemas = self.schemas.write();
+        let key = collection_schema_key(collection);
+        let restored = match &schema {
+            Some(schema) => serde_json::to_vec(schema)
+                .context("Failed to serialize schema")
+                .and_then(|data| self.persistent_layer.store_metadata(&key, &data)),
+            None => self.persistent_layer.delete_metadata(&key),
+        };
+        if let Err(e) = restored {
+            log::error!("Failed to restore the schema of '{}': {}", collection, e);
+        }
+        match schema {
+            Some(schema) => schemas.insert(collection.to_string(), schema),
+            None => schemas.remove(collection),
+        };
+    }
+
+    /// Check a collection's stored documents against its current schema
+    ///
+    /// Works on a snapshot of the collection, so writes are not blocked.
+    pub fn revalidate_collection(&self, collection: &str) -> Result<RevalidationReport> {
+        let schema = self
+            .get_schema(collection)
+            .with_context(|| format!("Collection '{}' has no schema", collection))?;
+
+        let mut report = RevalidationReport::default();
+        for doc in self.scan_with_pending_writes(collection)? {
+            report.checked += 1;
+            if let Err(e) = schema.validate_with(&doc, &self.validators) {
+                report.invalid += 1;
+                if report.violations.len() < MAX_REPORTED_VIOLATIONS {
+                    report.violations.push((doc.id, e.to_string()));
                 }
             }
-            
-            // Mark cache as warmed after first pass
-            self.stats.cache_warmed.store(1, Ordering::Relaxed);
-            
-            // Sleep before next warming cycle
-            sleep(Duration::from_secs(60)).await;
         }
+        Ok(report)
     }
 
-    /// Preload a collection into cache
-    async fn preload_collection(&self, collection: &str, limit: usize) -> Result<()> {
-        let schema = self.get_schema(collection)
-            .context("Schema not found")?;
-        
-        // Scan collection from persistent storage
-        let documents = self.persistent_layer.scan_collection(collection)?;
-        
-        // Load up to limit documents into cache
-        for (i, doc) in documents.iter().enumerate() {
-            if i >= limit {
-                break;
-            }
-            
-            self.populate_cache(collection, doc.id, doc, &schema).await?;
+    /// Create a collection whose inserts and updates are validated against `schema`
+    ///
+    /// The schema is saved with the collection's metadata and dropped with it.
+    pub fn create_collection_with_schema(&self, name: &str, schema: Schema) -> Result<()> {
+        if self.views.is_view(name) {
+            anyhow::bail!("Cannot create collection '{}': a view with that name exists", name);
         }
-        
+        let data = serde_json::to_vec(&schema).context("Failed to serialize schema")?;
+        self.persistent_layer.create_collection(name)?;
+        self.persistent_layer.store_metadata(&collection_schema_key(name), &data)?;
+        self.schemas.write().insert(name.to_string(), schema);
         Ok(())
     }
 
-    /// Refresh collection cache
-    async fn refresh_collection_cache(&self, collection: &str) -> Result<()> {
-        let schema = self.get_schema(collection)
-            .context("Schema not found")?;
-        
-        // Get all documents from persistent storage
-        let documents = self.persistent_layer.scan_collection(collection)?;
-        
-        // Update cache for each document
-        for doc in documents.iter() {
-            self.populate_cache(collection, doc.id, doc, &schema).await?;
+    /// Create a capped collection, optionally validated against `schema`
+    ///
+    /// The size limits are saved with the collection's metadata and dropped
+    /// with it.
+    pub fn create_capped_collection(&self, name: &str, options: CappedOptions, schema: Option<Schema>) -> Result<()> {
+        options.validate()?;
+        if self.views.is_view(name) {
+            anyhow::bail!("Cannot create collection '{}': a view with that name exists", name);
         }
-        
+        if self.list_collections()?.iter().any(|c| c == name) {
+            anyhow::bail!("Cannot create capped collection '{}': the collection exists", name);
+        }
+        let data = serde_json::to_vec(&options).context("Failed to serialize capped options")?;
+        self.persistent_layer.store_metadata(&capped_options_key(name), &data)?;
+        match schema {
+            Some(schema) => self.create_collection_with_schema(name, schema)?,
+            None => self.create_collection(name)?,
+        }
+        self.capped.write().insert(name.to_string(), Arc::new(CappedCollection::new(options)));
         Ok(())
     }
 
-    /// Make a cache key for a document
-    fn make_cache_key(&self, collection: &str, doc_id: DocumentId) -> String {
-        format!("doc:{}:{}", collection, doc_id)
+    /// Reload the capped collections of a previous run
+    ///
+    /// Their insertion order is the key order of their stored documents.
+    pub fn restore_capped_collections(&self) -> Result<usize> {
+        let mut restored = 0;
+        for collection in self.list_collections()? {
+            let Some(data) = self.persistent_layer.get_metadata(&capped_options_key(&collection))? else {
+                continue;
+            };
+            let options: CappedOptions = serde_json::from_slice(&data)
+                .with_context(|| format!("Failed to parse capped options of collection '{}'", collection))?;
+            let documents = self.persistent_layer.scan_collection(&collection)?;
+            let capped = CappedCollection::restore(options, &documents);
+            self.capped.write().insert(collection, Arc::new(capped));
+            restored += 1;
+        }
+        Ok(restored)
     }
 
-    /// Convert document to cache data
-    fn document_to_cache_data(&self, doc: &Document, schema: &Schema) -> Result<CacheData> {
-        // If specific fields are configured for caching, extract only those
-        if let Some(fields) = &schema.cache_config.fields {
-            let mut hash = crate::cache::data_structures::CacheHash::new();
-            
-            for field_name in fields {
-                if let Some(value) = doc.get(field_name) {
-                    hash.hset(field_name.clone(), value.clone());
+    /// Size limits of a capped collection
+    pub fn capped_options(&self, collection: &str) -> Option<CappedOptions> {
+        self.capped_collection(collection).map(|capped| *capped.options())
+    }
+
+    fn capped_collection(&self, collection: &str) -> Option<Arc<CappedCollection>> {
+        self.capped.read().get(collection).cloned()
+    }
+
+    /// Up to `limit` documents of a capped collection inserted after `after`, oldest first
+    pub async fn tail(&self, collection: &str, after: Option<DocumentId>, limit: usize) -> Result<Vec<Document>> {
+        let capped = self
+            .capped_collection(collection)
+            .ok_or_else(|| CappedError::NotCapped(collection.to_string()))?;
+        let mut position = after;
+        loop {
+            let ids = capped.ids_after(position, limit);
+            let Some(&last) = ids.last() else {
+                return Ok(Vec::new());
+            };
+            let mut documents = Vec::with_capacity(ids.len());
+            for id in ids {
+                // Skips documents evicted since the ids were read
+                if let Some(doc) = self.get_document(collection, id).await? {
+                    documents.push(doc);
                 }
             }
-            
-            Ok(CacheData::Hash(hash))
-        } else {
-            // Cache entire document as a hash
-            let mut hash = crate::cache::data_structures::CacheHash::new();
-            for (key, value) in &doc.fields {
-                hash.hset(key.clone(), value.clone());
+            if !documents.is_empty() {
+                return Ok(documents);
             }
-            Ok(CacheData::Hash(hash))
+            position = Some(last);
         }
     }
 
-    /// Convert cache data to document
-    fn cache_data_to_document(&self, cache_data: CacheData, doc_id: DocumentId) -> Result<Document> {
-        match cache_data {
-            CacheData::Hash(hash) => {
-                let mut doc = Document::with_id(doc_id);
-                doc.fields = hash.hgetall();
-                Ok(doc)
+    /// Like [`tail`](Self::tail), but when nothing has been inserted after
+    /// `after`, wait up to `max_wait` for an insert
+    ///
+    /// Returns no documents if the wait ends without one. Fails with
+    /// [`CappedError::NotCapped`] if the collection is dropped meanwhile.
+    pub async fn tail_wait(
+        &self,
+        collection: &str,
+        after: Option<DocumentId>,
+        limit: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<Document>> {
+        let deadline = tokio::time::Instant::now() + max_wait;
+        loop {
+            let capped = self
+                .capped_collection(collection)
+                .ok_or_else(|| CappedError::NotCapped(collection.to_string()))?;
+            let inserted = capped.notified();
+            let documents = self.tail(collection, after, limit).await?;
+            if !documents.is_empty() {
+                return Ok(documents);
+            }
+            if tokio::time::timeout_at(deadline, inserted).await.is_err() {
+                return Ok(Vec::new());
             }
-            _ => anyhow::bail!("Invalid cache data format"),
         }
     }
 
-    /// Get cache layer reference
-    pub fn cache_layer(&self) -> &Arc<CacheLayer> {
-        &self.cache_layer
-    }
-
-    /// Get persistent layer reference
-    pub fn persistent_layer(&self) -> &Arc<PersistentLayer> {
-        &self.persistent_layer
+    /// Keep every version of a collection's documents from now on, or
+    /// change the retention of a collection already keeping them
+    ///
+    /// Queued write-behind writes are persisted first so they are recorded
+    /// as written before history was enabled.
+    pub async fn enable_history(&self, collection: &str, options: HistoryOptions) -> Result<()> {
+        options.validate()?;
+        if self.views.is_view(collection) {
+            anyhow::bail!("Cannot keep history of '{}': it is a view", collection);
+        }
+        if !self.list_collections()?.iter().any(|c| c == collection) {
+            anyhow::bail!("Collection '{}' not found", collection);
+        }
+        self.flush_write_behind(collection).await?;
+        self.persistent_layer.enable_history(collection, options)?;
+        Ok(())
     }
 
-    /// Get statistics
-    pub fn stats(&self) -> &HybridStorageStats {
-        &self.stats
+    /// Retention of a collection keeping history, or `None` if it keeps none
+    pub fn history_options(&self, collection: &str) -> Result<Option<HistoryOptions>> {
+        Ok(self.persistent_layer.history_config(collection)?.map(|config| config.options))
     }
 
-    /// Flush all pending writes
-    pub async fn flush(&self) -> Result<()> {
-        // Process all pending write-behind entries
-        let queue = self.write_behind_queue.write().drain(..).collect::<Vec<_>>();
-        
-        for entry in queue {
-            self.process_write_behind_entry(entry).await?;
+    /// Retained versions of a document, oldest first
+    pub async fn document_history(&self, collection: &str, doc_id: DocumentId) -> Result<Vec<DocumentVersion>> {
+        if self.persistent_layer.history_config(collection)?.is_none() {
+            return Err(HistoryError::NotEnabled(collection.to_string()).into());
         }
-        
-        // Flush persistent layer
-        self.persistent_layer.flush()?;
-        
-        Ok(())
+        self.flush_write_behind(collection).await?;
+        self.persistent_layer.document_versions(collection, doc_id)
     }
-}
 
-/// Write-behind queue entry
-struct WriteBehindEntry {
-    collection: String,
-    doc_id: DocumentId,
-    operation: WriteBehindOperation,
-    delay_ms: u64,
-    queued_at: std::time::Instant,
-}
+    /// Run a query over a collection as it was at `point`
+    ///
+    /// Matches carry the version they were read from in their metadata.
+    /// Fails if the point is before history was enabled or past the age
+    /// limit, or if retention dropped the version a document had there.
+    pub async fn query_history(
+        &self,
+        collection: &str,
+        query: &crate::query::Query,
+        point: HistoryPoint,
+    ) -> Result<Vec<Document>> {
+        let config = self
+            .persistent_layer
+            .history_config(collection)?
+            .ok_or_else(|| HistoryError::NotEnabled(collection.to_string()))?;
+        if let HistoryPoint::AsOf(time) = point {
+            let start = config.horizon(chrono::Utc::now());
+            if time < start {
+                return Err(HistoryError::BeforeHistory { collection: collection.to_string(), start }.into());
+            }
+        }
+        self.flush_write_behind(collection).await?;
 
-/// Write-behind operation type
-enum WriteBehindOperation {
-    Write(Document),
-    Delete,
-}
+        let snapshot = self.persistent_layer.versions_at(collection, point)?;
+        let mut documents: Vec<Document> = snapshot.versions.iter().filter_map(DocumentVersion::state).collect();
+        // Documents not written since history was enabled are still in version 1;
+        // the collection is only scanned while some stored document has no versions
+        let untracked = self.persistent_layer.count_documents(collection)? > snapshot.live;
+        if untracked && matches!(point, HistoryPoint::AsOf(_) | HistoryPoint::Version(1)) {
+            for mut doc in self.persistent_layer.scan_collection(collection)? {
+                if !snapshot.tracked.contains(&doc.id) {
+                    doc.metadata.version = 1;
+                    doc.metadata.updated_at = config.enabled_at;
+                    documents.push(doc);
+                }
+            }
+        }
 
-/// Hybrid storage statistics
-#[derive(Debug, Default)]
-pub struct HybridStorageStats {
-    /// Cache hits
-    cache_hits: AtomicU64,
-    /// Cache misses
-    cache_misses: AtomicU64,
-    /// Cache writes
-    cache_writes: AtomicU64,
-    /// Cache invalidations
-    cache_invalidations: AtomicU64,
-    /// Persistent reads
-    persistent_reads: AtomicU64,
-    /// Persistent writes
-    persistent_writes: AtomicU64,
-    /// Cache warmed flag
-    cache_warmed: AtomicU64,
-}
+        crate::query::QueryExecutor::new()
+            .execute(documents, query)
+            .map_err(|e| anyhow::anyhow!("Query execution error: {}", e))
+    }
 
-impl HybridStorageStats {
-    /// Record a cache hit
-    fn record_cache_hit(&self) {
-        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    /// Drop the versions past the age limit of every collection keeping history
+    ///
+    /// Returns the number of versions dropped.
+    pub fn prune_history(&self) -> Result<usize> {
+        let now = chrono::Utc::now();
+        let mut pruned = 0;
+        for collection in self.list_collections()? {
+            pruned += self.persistent_layer.prune_history(&collection, now)?;
+        }
+        Ok(pruned)
     }
 
-    /// Record a cache miss
-    fn record_cache_miss(&self) {
-        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    /// List collections
+    pub fn list_collections(&self) -> Result<Vec<String>> {
+        self.persistent_layer.list_collections()
     }
 
-    /// Record a cache write
-    fn record_cache_write(&self) {
+    /// Scan all documents in a collection (for snapshots/backups)
+    /// Note: This primarily scans persistent storage. For full consistency,
+    /// consider calling flush() before scanning.
+    pub fn scan_collection(&self, collection: &str) -> Result<Vec<Document>> {
+        self.persistent_layer.scan_collection(collection)
+    }
+
+    /// Drop a collection, or a view if the name refers to one
+    pub fn drop_collection(&self, collection: &str) -> Result<()> {
+        if self.views.remove(collection) {
+            return self.save_views();
+        }
+
+        // Stop index builds before their indexes disappear
+        for build in self.index_builds.read().values() {
+            let progress = build.progress();
+            if progress.collection == collection {
+                build.cancel();
+                self.persistent_layer.delete_metadata(&index_build_key(collection, &progress.index))?;
+            }
+        }
+
+        // Clear from cache first
+        self.invalidate_collection_cache(collection);
+        // Drop from persistent storage
+        self.persistent_layer.drop_collection(collection)?;
+        self.index_managers.write().remove(collection);
+        self.plan_caches.write().remove(collection);
+        self.schemas.write().remove(collection);
+        self.views.reset_source(collection);
+        if let Some(capped) = self.capped.write().remove(collection) {
+            self.persistent_layer.delete_metadata(&capped_options_key(collection))?;
+            capped.wake_all();
+        }
+        Ok(())
+    }
+
+    /// Create a plain or materialized view over a collection
+    pub fn create_view(&self, definition: ViewDefinition) -> Result<()> {
+        if self.list_collections()?.iter().any(|c| c == &definition.name) {
+            anyhow::bail!("Cannot create view '{}': a collection with that name exists", definition.name);
+        }
+        self.views.create(definition, |source| self.scan_with_pending_writes(source))?;
+        self.save_views()
+    }
+
+    /// List view definitions
+    pub fn list_views(&self) -> Vec<ViewDefinition> {
+        self.views.definitions()
+    }
+
+    /// Reload view definitions saved by a previous run, rebuilding materialized views
+    pub fn restore_views(&self) -> Result<usize> {
+        let definitions: Vec<ViewDefinition> = match self.persistent_layer.get_metadata(VIEWS_METADATA_KEY)? {
+            Some(data) => serde_json::from_slice(&data).context("Failed to parse view definitions")?,
+            None => return Ok(0),
+        };
+
+        let mut restored = 0;
+        for definition in definitions {
+            let name = definition.name.clone();
+            self.views
+                .create(definition, |source| self.scan_with_pending_writes(source))
+                .with_context(|| format!("Failed to restore view '{}'", name))?;
+            restored += 1;
+        }
+
+        Ok(restored)
+    }
+
+    /// Read all documents of a collection or view
+    pub fn scan_collection_or_view(&self, name: &str) -> Result<Vec<Document>> {
+        let Some(definition) = self.views.definition(name) else {
+            return self.scan_collection(name);
+        };
+
+        match definition.kind {
+            ViewKind::Plain => {
+                let documents = self.scan_collection_or_view(&definition.source)?;
+                crate::aggregation::Pipeline::new(definition.pipeline)
+                    .execute(documents)
+                    .map_err(|e| anyhow::anyhow!("View '{}' failed: {}", name, e))
+            }
+            ViewKind::Materialized => {
+                self.views
+                    .rebuild_stale(name, |source| self.scan_with_pending_writes(source))
+                    .with_context(|| format!("Failed to rebuild materialized view '{}'", name))?;
+                self.views
+                    .materialized_rows(name)
+                    .with_context(|| format!("Materialized view '{}' has no state", name))
+            }
+        }
+    }
+
+    /// Check whether a name refers to a view
+    pub fn is_view(&self, name: &str) -> bool {
+        self.views.is_view(name)
+    }
+
+    /// Reject writes addressed to a view
+    fn ensure_writable(&self, collection: &str) -> Result<()> {
+        if self.views.is_view(collection) {
+            anyhow::bail!("Cannot write to view '{}'", collection);
+        }
+        Ok(())
+    }
+
+    /// Persist the view definitions
+    fn save_views(&self) -> Result<()> {
+        let data = serde_json::to_vec(&self.views.definitions())
+            .context("Failed to serialize view definitions")?;
+        self.persistent_layer.store_metadata(VIEWS_METADATA_KEY, &data)
+    }
+
+    /// Scan persistent storage with queued write-behind operations applied on top
+    fn scan_with_pending_writes(&self, collection: &str) -> Result<Vec<Document>> {
+        let pending: Vec<(DocumentId, Option<Document>)> = self
+            .write_behind_queue
+            .read()
+            .iter()
+            .filter(|entry| entry.collection == collection)
+            .map(|entry| match &entry.operation {
+                WriteBehindOperation::Write(doc) => (entry.doc_id, Some(doc.clone())),
+                WriteBehindOperation::Delete => (entry.doc_id, None),
+            })
+            .collect();
+
+        let mut documents = self.persistent_layer.scan_collection(collection)?;
+        for (doc_id, doc) in pending {
+            documents.retain(|d| d.id != doc_id);
+            if let Some(doc) = doc {
+                documents.push(doc);
+            }
+        }
+        Ok(documents)
+    }
+
+    /// Create an index and build it from the collection's current documents
+    ///
+    /// Builds in the calling thread; see
+    /// [`start_index_build`](Self::start_index_build) for background builds.
+    /// Creating an existing index again succeeds only with the same
+    /// definition.
+    pub fn create_index(&self, collection: &str, name: &str, fields: Vec<crate::protocol::IndexField>, options: IndexOptions) -> Result<()> {
+        let existing = self
+            .index_manager(collection)?
+            .definitions()
+            .into_iter()
+            .find(|definition| definition.name == name);
+        if let Some(existing) = existing {
+            if existing == Self::index_definition(name, &fields, &options)? {
+                return Ok(());
+            }
+            anyhow::bail!("Index '{}' already exists on '{}' with a different definition", name, collection);
+        }
+
+        let build = self.begin_index_build(collection, name, fields, options)?;
+        build.claim();
+        let outcome = self.build_index_scan(&build, |index, next_batch| {
+            self.index_builder.run_blocking(index, &build, next_batch)
+        });
+        if let Some(failure) = self.finish_index_build(&build, outcome)? {
+            return Err(failure.context(format!("Building index '{}' failed", name)));
+        }
+
+        let progress = build.progress();
+        match progress.state {
+            IndexBuildState::Completed => Ok(()),
+            state => anyhow::bail!("Building index '{}' was {}", name, state.as_str()),
+        }
+    }
+
+    /// Start building an index in the background and return the build's id
+    ///
+    /// The index is invisible to queries until the build completes. Its
+    /// progress is reported by [`get_index_build`](Self::get_index_build).
+    pub fn start_index_build(
+        self: &Arc<Self>,
+        collection: &str,
+        name: &str,
+        fields: Vec<crate::protocol::IndexField>,
+        options: IndexOptions,
+    ) -> Result<String> {
+        let build = self.begin_index_build(collection, name, fields, options)?;
+        build.claim();
+        self.spawn_index_build(build.clone());
+        Ok(build.id())
+    }
+
+    /// Continue builds interrupted by a restart, returning their ids
+    pub fn resume_index_builds(self: &Arc<Self>) -> Result<Vec<String>> {
+        let mut resumed = Vec::new();
+        for collection in self.list_collections()? {
+            let indexes = self.index_manager(&collection)?;
+            let builds: Vec<Arc<IndexBuild>> = self.index_builds.read().values().cloned().collect();
+            for build in builds {
+                let progress = build.progress();
+                if progress.collection == collection
+                    && progress.state == IndexBuildState::Running
+                    && indexes.building_index(&progress.index).is_some()
+                    && build.claim()
+                {
+                    resumed.push(progress.id);
+                    self.spawn_index_build(build);
+                }
+            }
+        }
+        Ok(resumed)
+    }
+
+    /// Index builds, optionally of one collection, oldest first
+    pub fn list_index_builds(&self, collection: Option<&str>) -> Vec<IndexBuildProgress> {
+        let mut builds: Vec<IndexBuildProgress> = self
+            .index_builds
+            .read()
+            .values()
+            .map(|build| build.progress())
+            .filter(|progress| collection.is_none_or(|c| progress.collection == c))
+            .collect();
+        builds.sort_by(|a, b| a.started_at.cmp(&b.started_at).then_with(|| a.id.cmp(&b.id)));
+        builds
+    }
+
+    /// Progress of one index build
+    pub fn get_index_build(&self, id: &str) -> Option<IndexBuildProgress> {
+        self.index_builds.read().get(id).map(|build| build.progress())
+    }
+
+    /// Ask a running build to stop; its index is dropped once it does
+    pub fn cancel_index_build(&self, id: &str) -> Result<IndexBuildProgress> {
+        let build = self
+            .index_builds
+            .read()
+            .get(id)
+            .cloned()
+            .with_context(|| format!("Index build '{}' not found", id))?;
+        let progress = build.progress();
+        if progress.state != IndexBuildState::Running {
+            anyhow::bail!("Index build '{}' is already {}", id, progress.state.as_str());
+        }
+        build.cancel();
+        Ok(progress)
+    }
+
+    /// Estimated time left for a running build
+    pub fn index_build_eta(&self, progress: &IndexBuildProgress) -> Option<Duration> {
+        (progress.state == IndexBuildState::Running)
+            .then(|| self.index_builder.estimate_build_time(progress.documents_remaining() as usize))
+    }
+
+    /// Register a building index in memory and on disk, and track its build
+    fn begin_index_build(&self, collection: &str, name: &str, fields: Vec<crate::protocol::IndexField>, options: IndexOptions) -> Result<Arc<IndexBuild>> {
+        if self.is_view(collection) {
+            anyhow::bail!("Cannot create index on view '{}'", collection);
+        }
+
+        let indexes = self.index_manager(collection)?;
+        indexes.begin_build(Self::index_definition(name, &fields, &options)?)?;
+
+        // Writes queued before the side-write buffer existed would be
+        // missed by both the buffer and the scan
+        let begun = self
+            .flush_collection_writes(collection)
+            .and_then(|_| self.persistent_layer.begin_index_build(collection, name, fields, &options));
+        match begun {
+            Ok(true) => {}
+            Ok(false) => {
+                indexes.abort_build(name);
+                anyhow::bail!("Index '{}' already exists on '{}'", name, collection);
+            }
+            Err(e) => {
+                indexes.abort_build(name);
+                return Err(e);
+            }
+        }
+
+        let total = self.persistent_layer.count_documents(collection)?;
+        indexes.set_document_count(total);
+        let progress = IndexBuildProgress::new(
+            uuid::Uuid::new_v4().to_string(),
+            collection.to_string(),
+            name.to_string(),
+            total,
+        );
+        self.track_index_build(progress)
+    }
+
+    /// Run a build's scan on a Tokio task and finish it
+    fn spawn_index_build(self: &Arc<Self>, build: Arc<IndexBuild>) {
+        let engine = self.clone();
+        tokio::spawn(async move {
+            let builder = engine.index_builder.clone();
+            let index = engine.building_index(&build);
+            let outcome = match index {
+                Ok(index) => {
+                    builder
+                        .run(&index, &build, |limit| engine.next_index_build_batch(&build, limit))
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = engine.finish_index_build(&build, outcome) {
+                log::error!("Failed to finish index build '{}': {}", build.id(), e);
+            }
+        });
+    }
+
+    /// Scan for a build with `run`, feeding it batches from persistent storage
+    fn build_index_scan<F>(&self, build: &IndexBuild, run: F) -> Result<bool, IndexError>
+    where
+        F: FnOnce(
+            &crate::index::BTreeIndex,
+            &mut dyn FnMut(usize) -> Result<Vec<Document>, IndexError>,
+        ) -> Result<bool, IndexError>,
+    {
+        let index = self.building_index(build)?;
+        run(&index, &mut |limit| self.next_index_build_batch(build, limit))
+    }
+
+    /// The in-memory index a build fills
+    fn building_index(&self, build: &IndexBuild) -> Result<Arc<crate::index::BTreeIndex>, IndexError> {
+        let progress = build.progress();
+        self.index_manager(&progress.collection)
+            .map_err(|e| IndexError::OperationFailed(e.to_string()))?
+            .building_index(&progress.index)
+            .ok_or_else(|| IndexError::OperationFailed(format!("Index '{}' is not building", progress.index)))
+    }
+
+    /// Checkpoint a build's progress and fetch its next batch of documents
+    fn next_index_build_batch(&self, build: &IndexBuild, limit: usize) -> Result<Vec<Document>, IndexError> {
+        let progress = build.progress();
+        self.save_index_build(build)
+            .and_then(|_| self.persistent_layer.index_build_batch(&progress.collection, &progress.index, limit))
+            .map_err(|e| match e.downcast::<IndexError>() {
+                Ok(e) => e,
+                Err(e) => IndexError::OperationFailed(e.to_string()),
+            })
+    }
+
+    /// Activate a scanned index, or drop it if the scan failed or was cancelled
+    ///
+    /// Returns why the build failed, if it did.
+    fn finish_index_build(&self, build: &IndexBuild, outcome: Result<bool, IndexError>) -> Result<Option<anyhow::Error>> {
+        let progress = build.progress();
+        let (collection, name) = (progress.collection.as_str(), progress.index.as_str());
+        let indexes = self.index_manager(collection)?;
+
+        let finished: Result<Option<u64>> = match outcome {
+            Ok(true) => (|| {
+                let tail = self.persistent_layer.finish_index_build(collection, name)?;
+                let index = self.building_index(build)?;
+                self.index_builder.index_batch(&index, build, &tail)?;
+                Ok(Some(indexes.finish_build(name)?))
+            })(),
+            Ok(false) => Ok(None),
+            Err(e) => Err(e.into()),
+        };
+
+        let failure = match finished {
+            Ok(Some(applied)) => {
+                build.finish(IndexBuildState::Completed, applied, None);
+                None
+            }
+            Ok(None) => {
+                self.discard_index_build(collection, name)?;
+                build.finish(IndexBuildState::Cancelled, 0, None);
+                None
+            }
+            Err(e) => {
+                self.discard_index_build(collection, name)?;
+                build.finish(IndexBuildState::Failed, 0, Some(e.to_string()));
+                Some(e)
+            }
+        };
+        self.save_index_build(build)?;
+        self.prune_index_builds();
+        Ok(failure)
+    }
+
+    /// Remove what a stopped build left behind
+    fn discard_index_build(&self, collection: &str, name: &str) -> Result<()> {
+        self.index_manager(collection)?.abort_build(name);
+        self.persistent_layer.drop_index(collection, name)
+    }
+
+    /// Start tracking a build and persist its record
+    fn track_index_build(&self, progress: IndexBuildProgress) -> Result<Arc<IndexBuild>> {
+        let build = Arc::new(IndexBuild::new(progress));
+        self.save_index_build(&build)?;
+        self.index_builds.write().insert(build.id(), build.clone());
+        Ok(build)
+    }
+
+    /// Forget all but the `MAX_FINISHED_INDEX_BUILDS` most recently finished builds
+    ///
+    /// Running builds are always kept.
+    fn prune_index_builds(&self) {
+        let mut builds = self.index_builds.write();
+        let mut finished: Vec<IndexBuildProgress> = builds
+            .values()
+            .map(|build| build.progress())
+            .filter(|progress| progress.state != IndexBuildState::Running)
+            .collect();
+        if finished.len() > MAX_FINISHED_INDEX_BUILDS {
+            finished.sort_by(|a, b| b.finished_at.cmp(&a.finished_at).then_with(|| b.id.cmp(&a.id)));
+            for progress in &finished[MAX_FINISHED_INDEX_BUILDS..] {
+                builds.remove(&progress.id);
+            }
+        }
+    }
+
+    /// Persist a build's progress record
+    fn save_index_build(&self, build: &IndexBuild) -> Result<()> {
+        let progress = build.progress();
+        let data = serde_json::to_vec(&progress).context("Failed to serialize index build")?;
+        self.persistent_layer
+            .store_metadata(&index_build_key(&progress.collection, &progress.index), &data)
+    }
+
+    /// Apply a collection's queued write-behind operations to persistent storage
+    fn flush_collection_writes(&self, collection: &str) -> Result<()> {
+        let entries: Vec<WriteBehindEntry> = {
+            let mut queue = self.write_behind_queue.write();
+            let (matching, rest) = queue.drain(..).partition(|entry| entry.collection == collection);
+            *queue = rest;
+            matching
+        };
+
+        for entry in entries {
+            match entry.operation {
+                WriteBehindOperation::Write(doc) => {
+                    self.persistent_layer.insert_document(&entry.collection, entry.doc_id, &doc)?;
+                }
+                WriteBehindOperation::Delete => {
+                    self.persistent_layer.delete_document(&entry.collection, entry.doc_id)?;
+                }
+            }
+            self.stats.record_persistent_write();
+        }
+        Ok(())
+    }
+
+    /// List indexes
+    pub fn list_indexes(&self, collection: &str) -> Result<Vec<serde_json::Value>> {
+        self.persistent_layer.list_indexes(collection)
+    }
+
+    /// Drop an index, cancelling its build if it is still building
+    pub fn drop_index(&self, collection: &str, name: &str) -> Result<()> {
+        for build in self.index_builds.read().values() {
+            let progress = build.progress();
+            if progress.collection == collection && progress.index == name {
+                build.cancel();
+            }
+        }
+
+        self.persistent_layer.drop_index(collection, name)?;
+        self.persistent_layer.delete_metadata(&index_build_key(collection, name))?;
+        if let Some(indexes) = self.index_managers.read().get(collection) {
+            indexes.unregister_index(name);
+            indexes.abort_build(name);
+        }
+        Ok(())
+    }
+
+    /// Get or create IndexManager for a collection
+    ///
+    /// If the collection's persisted indexes cannot be loaded, the error is
+    /// logged and an empty manager is returned without being kept, so the
+    /// next call loads them again.
+    pub fn get_index_manager(&self, collection: &str) -> Arc<IndexManager> {
+        self.index_manager(collection).unwrap_or_else(|e| {
+            log::error!("Failed to load indexes of collection '{}': {}", collection, e);
+            Arc::new(IndexManager::new(collection.to_string()))
+        })
+    }
+
+    /// Get the IndexManager for a collection
+    ///
+    /// On first use the collection's persisted indexes are registered and
+    /// loaded from their persisted entries, or rebuilt from the current
+    /// documents when those entries fail validation.
+    fn index_manager(&self, collection: &str) -> Result<Arc<IndexManager>> {
+        if let Some(indexes) = self.index_managers.read().get(collection) {
+            return Ok(indexes.clone());
+        }
+
+        // Persisted entries are reused as-is; an index is rebuilt from the
+        // documents only when its entries fail their version or checksum check
+        let indexes = Arc::new(IndexManager::new(collection.to_string()));
+        let mut documents: Option<Vec<Document>> = None;
+        for index in self.persistent_layer.list_indexes(collection)? {
+            let name = index.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+            let fields: Vec<crate::protocol::IndexField> =
+                serde_json::from_value(index.get("fields").cloned().unwrap_or_default())
+                    .with_context(|| format!("Invalid fields for index '{}'", name))?;
+            let options: IndexOptions = serde_json::from_value(index.clone())
+                .with_context(|| format!("Invalid options for index '{}'", name))?;
+            let definition = Self::index_definition(name, &fields, &options)?;
+
+            let building = self.persistent_layer.index_state(collection, name)?.is_some_and(|state| state.building);
+            if building {
+                self.restore_index_build(&indexes, collection, definition)?;
+                continue;
+            }
+            indexes.register_index(definition)?;
+
+            let loaded = match self.persistent_layer.load_index(collection, name)? {
+                Some(entries) => indexes.load_entries(name, entries).is_ok(),
+                None => false,
+            };
+            if loaded {
+                self.restore_multikey(&indexes, collection, name)?;
+            }
+            if !loaded {
+                let documents = match &documents {
+                    Some(documents) => documents,
+                    None => documents.insert(self.scan_with_pending_writes(collection)?),
+                };
+                indexes.populate_index(name, documents)?;
+                self.persistent_layer.rebuild_index(collection, name)?;
+            }
+        }
+
+        if indexes.has_indexes() {
+            indexes.set_document_count(self.persistent_layer.count_documents(collection)?);
+        }
+
+        let mut managers = self.index_managers.write();
+        Ok(managers
+            .entry(collection.to_string())
+            .or_insert(indexes)
+            .clone())
+    }
+
+    /// Carry an index's persisted multikey flag over to its reloaded entries
+    fn restore_multikey(&self, indexes: &IndexManager, collection: &str, name: &str) -> Result<()> {
+        if self.persistent_layer.index_state(collection, name)?.is_some_and(|state| state.multikey) {
+            indexes.mark_multikey(name)?;
+        }
+        Ok(())
+    }
+
+    /// Register a build interrupted by a restart, with the entries it had written
+    ///
+    /// The build continues from its checkpoint once
+    /// [`resume_index_builds`](Self::resume_index_builds) picks it up; until
+    /// then writes are captured in its side-write buffer.
+    fn restore_index_build(&self, indexes: &IndexManager, collection: &str, definition: IndexDefinition) -> Result<()> {
+        let name = definition.name.clone();
+        indexes.begin_build(definition)?;
+
+        let loaded = match self.persistent_layer.load_index(collection, &name)? {
+            Some(entries) => {
+                let documents: HashSet<DocumentId> = entries.iter().map(|(doc_id, _)| *doc_id).collect();
+                let count = documents.len() as u64;
+                indexes.load_entries(&name, entries).is_ok().then_some(count)
+            }
+            None => None,
+        };
+        let documents_processed = match loaded {
+            Some(count) => {
+                self.restore_multikey(indexes, collection, &name)?;
+                count
+            }
+            None => {
+                if let Some(index) = indexes.building_index(&name) {
+                    index.clear();
+                }
+                self.persistent_layer.restart_index_build(collection, &name)?;
+                0
+            }
+        };
+
+        let record = self.persistent_layer.get_metadata(&index_build_key(collection, &name))?;
+        let mut progress = match record {
+            Some(data) => serde_json::from_slice(&data).context("Failed to parse index build")?,
+            None => IndexBuildProgress::new(
+                uuid::Uuid::new_v4().to_string(),
+                collection.to_string(),
+                name.clone(),
+                self.persistent_layer.count_documents(collection)?,
+            ),
+        };
+        progress.state = IndexBuildState::Running;
+        progress.documents_processed = documents_processed;
+        if !self.index_builds.read().contains_key(&progress.id) {
+            self.track_index_build(progress)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the documents of every collection whose TTL has run out
+    ///
+    /// Each document goes through [`delete_document`](Self::delete_document),
+    /// so caches, indexes and views see the delete like any other. Returns
+    /// the number of documents deleted.
+    pub async fn reap_expired(&self) -> Result<usize> {
+        let mut deleted = 0;
+        for collection in self.list_collections()? {
+            if self.is_view(&collection) {
+                continue;
+            }
+            let indexes = self.index_manager(&collection)?;
+            let now = chrono::Utc::now();
+            for doc_id in indexes.expired_documents(now)? {
+                let Some(doc) = self.get_document(&collection, doc_id).await? else {
+                    continue;
+                };
+                if indexes.is_expired(&doc, now)? && self.delete_document(&collection, doc_id).await? {
+                    deleted += 1;
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Reap expired documents and versions past their history's age limit
+    /// every `interval` on a Tokio task
+    pub fn start_ttl_monitor(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let engine = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                match engine.reap_expired().await {
+                    Ok(0) => {}
+                    Ok(deleted) => log::debug!("TTL monitor deleted {} expired documents", deleted),
+                    Err(e) => log::error!("TTL monitor failed: {}", e),
+                }
+                match engine.prune_history() {
+                    Ok(0) => {}
+                    Ok(pruned) => log::debug!("TTL monitor dropped {} expired document versions", pruned),
+                    Err(e) => log::error!("History pruning failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Plan a query against a collection's indexes without running it
+    pub fn plan_query(&self, collection: &str, query: &crate::query::Query) -> Result<crate::query::planner::QueryPlan> {
+        let indexes = self.index_manager(collection)?;
+        crate::query::QueryPlanner::with_indexes(indexes.definitions())
+            .with_multikey(&indexes.multikey_indexes())
+            .with_lossy_keys(&indexes.lossy_key_indexes())
+            .with_statistics(indexes.collection_statistics())
+            .create_plan(query)
+            .map_err(|e| anyhow::anyhow!("Query planning error: {}", e))
+    }
+
+    /// Rebuild the key distributions of one index, or of every index
+    pub fn analyze(&self, collection: &str, index: Option<&str>) -> Result<Vec<(String, KeyDistribution)>> {
+        if self.is_view(collection) {
+            anyhow::bail!("Cannot analyze view '{}'", collection);
+        }
+        let indexes = self.index_manager(collection)?;
+        Ok(indexes.analyze(index)?)
+    }
+
+    /// Build an index definition from wire-level index fields
+    fn index_definition(name: &str, fields: &[crate::protocol::IndexField], options: &IndexOptions) -> Result<IndexDefinition> {
+        let names: Vec<String> = fields.iter().map(|f| f.field.clone()).collect();
+        let definition = match (names.len(), options.vector) {
+            (0, _) => anyhow::bail!("Index '{}' has no fields", name),
+            (1, Some(_)) if options.hashed => anyhow::bail!("Vector index '{}' cannot be hashed", name),
+            (1, Some(vector)) => IndexDefinition::vector(names[0].clone(), vector.dims, vector.metric),
+            (_, Some(_)) => anyhow::bail!("Vector index '{}' must have exactly one field", name),
+            (1, None) if options.hashed => IndexDefinition::hashed(names[0].clone()),
+            (1, None) => IndexDefinition::single(names[0].clone()),
+            _ if options.hashed => anyhow::bail!("Hashed index '{}' must have exactly one field", name),
+            _ => IndexDefinition::compound(names),
+        };
+        let mut definition = definition
+            .named(name)
+            .with_directions(fields.iter().map(|f| f.direction).collect());
+        definition.unique = options.unique;
+        definition.sparse = options.sparse;
+        definition.partial_filter = options.partial_filter.clone();
+        definition.expire_after_seconds = options.expire_after_seconds;
+        definition.collation = options.collation.clone();
+        Ok(definition)
+    }
+
+    /// Execute a query with index optimization
+    ///
+    /// Index scans fetch only the documents they visit, so covered and
+    /// limited queries never load the whole collection.
+    pub fn query(&self, collection: &str, query: &crate::query::Query) -> Result<Vec<Document>> {
+        let source = CollectionSource {
+            engine: self,
+            collection,
+        };
+        let mut executor = self.query_executor(collection)?;
+        if let Some(plan_cache) = self.plan_cache(collection) {
+            executor.set_plan_cache(plan_cache);
+        }
+        executor
+            .execute_from(&source, query)
+            .map_err(|e| anyhow::anyhow!("Query execution error: {}", e))
+    }
+
+    /// Explain the plan chosen for a query and the plans it beat
+    ///
+    /// With `ExecutionStats` the query also runs and the result carries
+    /// an `execution_stats` entry; its documents are discarded.
+    pub fn explain_query(
+        &self,
+        collection: &str,
+        query: &crate::query::Query,
+        verbosity: ExplainVerbosity,
+    ) -> Result<Value> {
+        let executor = self.query_executor(collection)?;
+        let (plan, stats) = match verbosity {
+            ExplainVerbosity::QueryPlanner => (
+                executor.plan(query).map_err(|e| anyhow::anyhow!("Query planning error: {}", e))?,
+                None,
+            ),
+            ExplainVerbosity::ExecutionStats => {
+                let source = CollectionSource {
+                    engine: self,
+                    collection,
+                };
+                let (_, plan, stats) = executor
+                    .execute_with_stats(&source, query)
+                    .map_err(|e| anyhow::anyhow!("Query execution error: {}", e))?;
+                (plan, Some(stats))
+            }
+        };
+
+        let Value::Object(mut explain) = plan.describe(query) else {
+            anyhow::bail!("Query plan description is not an object");
+        };
+        if let Some(stats) = stats {
+            explain.insert("execution_stats".to_string(), stats.to_value());
+        }
+        Ok(Value::Object(explain))
+    }
+
+    /// Run an aggregation pipeline over a collection
+    ///
+    /// A leading `$vectorSearch` stage reads its documents from a vector
+    /// index; otherwise the pipeline reads the whole collection.
+    pub fn aggregate(&self, collection: &str, pipeline: &crate::aggregation::Pipeline) -> Result<Vec<Document>> {
+        let (documents, rest) = self.pipeline_input(collection, pipeline)?;
+        Ok(rest.execute(documents)?)
+    }
+
+    /// Documents a pipeline starts from and the stages left to run on them
+    fn pipeline_input(
+        &self,
+        collection: &str,
+        pipeline: &crate::aggregation::Pipeline,
+    ) -> Result<(Vec<Document>, crate::aggregation::Pipeline)> {
+        match pipeline.stages.split_first() {
+            Some((crate::aggregation::PipelineStage::VectorSearch(search), rest)) => Ok((
+                self.vector_search(collection, search)?,
+                crate::aggregation::Pipeline::new(rest.to_vec()),
+            )),
+            _ => Ok((self.scan_collection_or_view(collection)?, pipeline.clone())),
+        }
+    }
+
+    /// Name of the vector index a `$vectorSearch` stage reads
+    fn vector_search_index(&self, collection: &str, search: &crate::aggregation::VectorSearch) -> Result<String> {
+        if self.is_view(collection) {
+            anyhow::bail!("Cannot run $vectorSearch on view '{}'", collection);
+        }
+        let indexes = self.index_manager(collection)?;
+        match &search.index {
+            Some(name) => Ok(name.clone()),
+            None => indexes
+                .vector_index_for(&search.path)
+                .ok_or_else(|| anyhow::anyhow!("No vector index on '{}' in '{}'", search.path, collection)),
+        }
+    }
+
+    /// Nearest documents to a query vector, most similar first
+    ///
+    /// The stage's filter is checked as the graph is searched, so up to
+    /// `k` matching documents come back however selective it is. Each
+    /// document gets its similarity score in the stage's score field.
+    pub fn vector_search(&self, collection: &str, search: &crate::aggregation::VectorSearch) -> Result<Vec<Document>> {
+        let index = self.vector_search_index(collection, search)?;
+        let indexes = self.index_manager(collection)?;
+        let filter = search
+            .filter
+            .as_ref()
+            .map(crate::query::QueryParser::parse_filter)
+            .transpose()
+            .context("Invalid $vectorSearch filter")?;
+
+        // Documents the filter accepted, kept so they are read only once
+        let executor = crate::query::QueryExecutor::new();
+        let mut accepted: HashMap<DocumentId, Document> = HashMap::new();
+        let mut failure: Option<anyhow::Error> = None;
+        let results = indexes.vector_search(&index, &search.query_vector, search.k, search.candidates(), |doc_id| {
+            if failure.is_some() {
+                return false;
+            }
+            let document = match self.get_with_pending_writes(collection, doc_id) {
+                Ok(Some(document)) => document,
+                Ok(None) => return false,
+                Err(e) => {
+                    failure = Some(e);
+                    return false;
+                }
+            };
+            let matches = match &filter {
+                Some(filter) => executor.matches_filter(&document, filter),
+                None => Ok(true),
+            };
+            match matches {
+                Ok(true) => {
+                    accepted.insert(doc_id, document);
+                    true
+                }
+                Ok(false) => false,
+                Err(e) => {
+                    failure = Some(e.into());
+                    false
+                }
+            }
+        })?;
+        if let Some(e) = failure {
+            return Err(e.context("$vectorSearch failed"));
+        }
+
+        Ok(results
+            .into_iter()
+            .filter_map(|(doc_id, score)| {
+                let mut document = accepted.remove(&doc_id)?;
+                document.insert(search.score_field.clone(), Value::Float64(score));
+                Some(document)
+            })
+            .collect())
+    }
+
+    /// Explain an aggregation pipeline over a collection
+    ///
+    /// A pipeline reads either the whole collection or, when it starts
+    /// with `$vectorSearch`, that stage's vector index, so there are no
+    /// rejected plans.
+    pub fn explain_aggregate(
+        &self,
+        collection: &str,
+        pipeline: &crate::aggregation::Pipeline,
+        verbosity: ExplainVerbosity,
+    ) -> Result<Value> {
+        let mut scan = BTreeMap::new();
+        let (source, index, rest) = match pipeline.stages.split_first() {
+            Some((crate::aggregation::PipelineStage::VectorSearch(search), rest)) => {
+                let index = self.vector_search_index(collection, search)?;
+                scan.insert("index".to_string(), Value::String(index.clone()));
+                ("vector_search", Value::String(index), rest)
+            }
+            _ => ("collection_scan", Value::Null, &pipeline.stages[..]),
+        };
+        scan.insert("stage".to_string(), Value::String(source.to_string()));
+        let mut stages = vec![Value::Object(scan)];
+        stages.extend(crate::aggregation::Pipeline::new(rest.to_vec()).describe());
+
+        let mut explain = BTreeMap::from([
+            ("strategy".to_string(), Value::String(source.to_string())),
+            ("index".to_string(), index),
+            ("stages".to_string(), Value::Array(stages)),
+            ("rejected_plans".to_string(), Value::Array(Vec::new())),
+        ]);
+        if verbosity == ExplainVerbosity::ExecutionStats {
+            let total = PerformanceTimer::start();
+            let timer = PerformanceTimer::start();
+            let (documents, rest) = self.pipeline_input(collection, pipeline)?;
+            let scanned = documents.len();
+            let scan_time = timer.elapsed();
+
+            let (_, mut stats) = rest
+                .execute_with_stats(documents)
+                .map_err(|e| anyhow::anyhow!("Aggregation failed: {}", e))?;
+            stats.stages.insert(
+                0,
+                StageStats {
+                    stage: source.to_string(),
+                    documents_out: scanned as u64,
+                    time: scan_time,
+                },
+            );
+            stats.execution_time = total.elapsed();
+            explain.insert("execution_stats".to_string(), stats.to_value());
+        }
+        Ok(Value::Object(explain))
+    }
+
+    /// Get the plan cache for a collection with indexes
+    ///
+    /// Views, collections without indexes and unknown names only ever scan,
+    /// so no cache is created for them. Dropping the collection drops it.
+    pub fn plan_cache(&self, collection: &str) -> Option<Arc<PlanCache>> {
+        if let Some(cache) = self.plan_caches.read().get(collection) {
+            return Some(cache.clone());
+        }
+        let indexed = self
+            .index_managers
+            .read()
+            .get(collection)
+            .map(|indexes| indexes.has_indexes())
+            .unwrap_or(false);
+        if !indexed || self.is_view(collection) {
+            return None;
+        }
+        Some(
+            self.plan_caches
+                .write()
+                .entry(collection.to_string())
+                .or_default()
+                .clone(),
+        )
+    }
+
+    /// Cached plans of one collection, or of every collection
+    pub fn list_plan_cache(&self, collection: Option<&str>) -> Vec<(String, PlanCacheEntry)> {
+        let caches = self.plan_caches.read();
+        let mut names: Vec<&String> = caches
+            .keys()
+            .filter(|name| collection.map(|c| c == name.as_str()).unwrap_or(true))
+            .collect();
+        names.sort();
+
+        names
+            .into_iter()
+            .flat_map(|name| {
+                caches[name]
+                    .entries()
+                    .into_iter()
+                    .map(move |entry| (name.clone(), entry))
+            })
+            .collect()
+    }
+
+    /// Drop cached plans, returning how many were removed
+    ///
+    /// Limited to one collection and, with `shape`, to the entry whose
+    /// shape text matches.
+    pub fn clear_plan_cache(&self, collection: Option<&str>, shape: Option<&str>) -> usize {
+        let caches = self.plan_caches.read();
+        caches
+            .iter()
+            .filter(|(name, _)| collection.map(|c| c == name.as_str()).unwrap_or(true))
+            .map(|(_, cache)| match shape {
+                Some(shape) => cache
+                    .entries()
+                    .into_iter()
+                    .filter(|entry| entry.shape.as_str() == shape)
+                    .filter(|entry| cache.remove(&entry.shape))
+                    .count(),
+                None => cache.clear(),
+            })
+            .sum()
+    }
+
+    /// Query executor planning against a collection's indexes
+    fn query_executor(&self, collection: &str) -> Result<crate::query::QueryExecutor> {
+        let mut executor = crate::query::QueryExecutor::new();
+
+        // Views have no indexes of their own
+        if !self.is_view(collection) {
+            executor.set_index_manager(self.index_manager(collection)?);
+        }
+        Ok(executor)
+    }
+
+    /// Read one document from persistent storage with queued writes applied
+    fn get_with_pending_writes(&self, collection: &str, doc_id: DocumentId) -> Result<Option<Document>> {
+        let pending = self
+            .write_behind_queue
+            .read()
+            .iter()
+            .rev()
+            .find(|entry| entry.collection == collection && entry.doc_id == doc_id)
+            .map(|entry| match &entry.operation {
+                WriteBehindOperation::Write(doc) => Some(doc.clone()),
+                WriteBehindOperation::Delete => None,
+            });
+
+        match pending {
+            Some(doc) => Ok(doc),
+            None => self.persistent_layer.get_document(collection, doc_id),
+        }
+    }
+
+    /// Write-through strategy: update both cache and persistent storage
+    async fn write_through(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+        doc: &Document,
+        schema: &Schema,
+    ) -> Result<()> {
+        // Write to persistent storage first
+        self.persistent_layer.insert_document(collection, doc_id, doc)?;
+        
+        // Then update cache
+        self.populate_cache(collection, doc_id, doc, schema).await?;
+        
+        self.stats.record_cache_write();
+        self.stats.record_persistent_write();
+        
+        Ok(())
+    }
+
+    /// Write-behind strategy: update cache immediately, queue persistent write
+    async fn write_behind(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+        doc: &Document,
+        schema: &Schema,
+        delay_ms: u64,
+    ) -> Result<()> {
+        // Update cache immediately
+        self.populate_cache(collection, doc_id, doc, schema).await?;
+        
+        // Queue persistent write
+        self.queue_write_behind(WriteBehindEntry {
+            collection: collection.to_string(),
+            doc_id,
+            operation: WriteBehindOperation::Write(doc.clone()),
+            delay_ms,
+            queued_at: std::time::Instant::now(),
+        });
+        
+        self.stats.record_cache_write();
+        
+        Ok(())
+    }
+
+    /// Populate cache with document data
+    async fn populate_cache(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+        doc: &Document,
+        schema: &Schema,
+    ) -> Result<()> {
+        let cache_key = self.make_cache_key(collection, doc_id);
+        let cache_data = self.document_to_cache_data(doc, schema)?;
+        let ttl = schema.cache_config.ttl;
+        
+        self.cache_layer.set_with_ttl(cache_key, cache_data, ttl)?;
+        
+        Ok(())
+    }
+
+    /// Invalidate a cache entry
+    fn invalidate_cache_entry(&self, collection: &str, doc_id: DocumentId) {
+        let cache_key = self.make_cache_key(collection, doc_id);
+        self.cache_layer.remove(&cache_key);
+        self.stats.record_cache_invalidation();
+    }
+
+    /// Invalidate all cache entries for a collection
+    pub fn invalidate_collection_cache(&self, collection: &str) {
+        // This is a simplified implementation
+        // In production, we'd need a more efficient way to track collection keys
+        let _prefix = format!("doc:{}:", collection);
+        
+        // For now, we'll just clear the entire cache if it's a collection invalidation
+        // A better approach would be to maintain a collection -> keys mapping
+        self.cache_layer.clear();
+        self.stats.record_cache_invalidation();
+    }
+
+    /// Queue a write-behind operation
+    fn queue_write_behind(&self, entry: WriteBehindEntry) {
+        self.write_behind_queue.write().push(entry);
+    }
+
+    /// Process write-behind queue (background task)
+    async fn process_write_behind_queue(&self) {
+        loop {
+            sleep(Duration::from_millis(50)).await;
+            
+            let now = std::time::Instant::now();
+            
+            // Collect entries ready to be processed
+            let entries_to_process: Vec<WriteBehindEntry> = {
+                let mut queue = self.write_behind_queue.write();
+                let mut entries = Vec::new();
+                let mut i = 0;
+                
+                while i < queue.len() {
+                    let entry = &queue[i];
+                    let elapsed = now.duration_since(entry.queued_at).as_millis() as u64;
+                    
+                    if elapsed >= entry.delay_ms {
+                        entries.push(queue.remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+                
+                entries
+            }; // Lock is released here
+            
+            // Process entries without holding the lock
+            for entry in entries_to_process {
+                if let Err(e) = self.process_write_behind_entry(entry).await {
+                    eprintln!("Error processing write-behind entry: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Process a single write-behind entry
+    async fn process_write_behind_entry(&self, entry: WriteBehindEntry) -> Result<()> {
+        match entry.operation {
+            WriteBehindOperation::Write(doc) => {
+                self.persistent_layer.insert_document(&entry.collection, entry.doc_id, &doc)?;
+                self.stats.record_persistent_write();
+            }
+            WriteBehindOperation::Delete => {
+                self.persistent_layer.delete_document(&entry.collection, entry.doc_id)?;
+                self.stats.record_persistent_write();
+            }
+        }
+        
+        Ok(())
+    }
+
+    /// Warm caches based on warming strategies (background task)
+    async fn warm_caches(&self) {
+        // Wait a bit before starting cache warming
+        sleep(Duration::from_secs(5)).await;
+        
+        loop {
+            let schemas = self.schemas.read().clone();
+            
+            for (collection, schema) in schemas.iter() {
+                match &schema.cache_config.warming {
+                    CacheWarmingStrategy::None => {
+                        // No warming
+                    }
+                    CacheWarmingStrategy::PreloadOnStartup { limit } => {
+                        // This runs once at startup (we check if already warmed)
+                        if self.stats.cache_warmed.load(Ordering::Relaxed) == 0 {
+                            if let Err(e) = self.preload_collection(collection, *limit).await {
+                                eprintln!("Error preloading collection {}: {}", collection, e);
+                            }
+                        }
+                    }
+                    CacheWarmingStrategy::LazyLoad => {
+                        // Lazy load happens on access, nothing to do here
+                    }
+                    CacheWarmingStrategy::ScheduledRefresh { interval_seconds } => {
+                        // Refresh cache periodically
+                        if let Err(e) = self.refresh_collection_cache(collection).await {
+                            eprintln!("Error refreshing cache for {}: {}", collection, e);
+                        }
+                        sleep(Duration::from_secs(*interval_seconds)).await;
+                    }
+                }
+            }
+            
+            // Mark cache as warmed after first pass
+            self.stats.cache_warmed.store(1, Ordering::Relaxed);
+            
+            // Sleep before next warming cycle
+            sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    /// Preload a collection into cache
+    async fn preload_collection(&self, collection: &str, limit: usize) -> Result<()> {
+        let schema = self.get_schema(collection)
+            .context("Schema not found")?;
+        
+        // Scan collection from persistent storage
+        let documents = self.persistent_layer.scan_collection(collection)?;
+        
+        // Load up to limit documents into cache
+        for (i, doc) in documents.iter().enumerate() {
+            if i >= limit {
+                break;
+            }
+            
+            self.populate_cache(collection, doc.id, doc, &schema).await?;
+        }
+        
+        Ok(())
+    }
+
+    /// Refresh collection cache
+    async fn refresh_collection_cache(&self, collection: &str) -> Result<()> {
+        let schema = self.get_schema(collection)
+            .context("Schema not found")?;
+        
+        // Get all documents from persistent storage
+        let documents = self.persistent_layer.scan_collection(collection)?;
+        
+        // Update cache for each document
+        for doc in documents.iter() {
+            self.populate_cache(collection, doc.id, doc, &schema).await?;
+        }
+        
+        Ok(())
+    }
+
+    /// Make a cache key for a document
+    fn make_cache_key(&self, collection: &str, doc_id: DocumentId) -> String {
+        format!("doc:{}:{}", collection, doc_id)
+    }
+
+    /// Convert document to cache data
+    fn document_to_cache_data(&self, doc: &Document, schema: &Schema) -> Result<CacheData> {
+        // If specific fields are configured for caching, extract only those
+        if let Some(fields) = &schema.cache_config.fields {
+            let mut hash = crate::cache::data_structures::CacheHash::new();
+            
+            for field_name in fields {
+                if let Some(value) = doc.get(field_name) {
+                    hash.hset(field_name.clone(), value.clone());
+                }
+            }
+            
+            Ok(CacheData::Hash(hash))
+        } else {
+            // Cache entire document as a hash
+            let mut hash = crate::cache::data_structures::CacheHash::new();
+            for (key, value) in &doc.fields {
+                hash.hset(key.clone(), value.clone());
+            }
+            Ok(CacheData::Hash(hash))
+        }
+    }
+
+    /// Convert cache data to document
+    fn cache_data_to_document(&self, cache_data: CacheData, doc_id: DocumentId) -> Result<Document> {
+        match cache_data {
+            CacheData::Hash(hash) => {
+                let mut doc = Document::with_id(doc_id);
+                doc.fields = hash.hgetall();
+                Ok(doc)
+            }
+            _ => anyhow::bail!("Invalid cache data format"),
+        }
+    }
+
+    /// Get cache layer reference
+    pub fn cache_layer(&self) -> &Arc<CacheLayer> {
+        &self.cache_layer
+    }
+
+    /// Get persistent layer reference
+    pub fn persistent_layer(&self) -> &Arc<PersistentLayer> {
+        &self.persistent_layer
+    }
+
+    /// Get statistics
+    pub fn stats(&self) -> &HybridStorageStats {
+        &self.stats
+    }
+
+    /// Persist the queued write-behind operations of one collection
+    async fn flush_write_behind(&self, collection: &str) -> Result<()> {
+        let pending: Vec<WriteBehindEntry> = {
+            let mut queue = self.write_behind_queue.write();
+            let (pending, rest) = queue.drain(..).partition(|entry| entry.collection == collection);
+            *queue = rest;
+            pending
+        };
+
+        for entry in pending {
+            self.process_write_behind_entry(entry).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush all pending writes
+    pub async fn flush(&self) -> Result<()> {
+        // Process all pending write-behind entries
+        let queue = self.write_behind_queue.write().drain(..).collect::<Vec<_>>();
+        
+        for entry in queue {
+            self.process_write_behind_entry(entry).await?;
+        }
+        
+        // Flush persistent layer
+        self.persistent_layer.flush()?;
+        
+        Ok(())
+    }
+}
+
+/// A document's lock, held until dropped; see [`HybridStorageEngine::lock_document`]
+pub struct DocumentLock {
+    collection: String,
+    doc_id: DocumentId,
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+}
+
+impl DocumentLock {
+    /// Collection of the locked document
+    pub fn collection(&self) -> &str {
+        &self.collection
+    }
+
+    /// Id of the locked document
+    pub fn doc_id(&self) -> DocumentId {
+        self.doc_id
+    }
+}
+
+/// Settings changed by [`HybridStorageEngine::modify_collection`]; `None` keeps the current one
+#[derive(Debug, Clone, Default)]
+pub struct CollectionModification {
+    /// Replaces the field definitions, document validators, strict mode and JSON Schema text
+    pub schema: Option<Schema>,
+    /// Replaces the validation action
+    pub validation_action: Option<ValidationAction>,
+    /// Replaces the cache configuration
+    pub cache_config: Option<CollectionCacheConfig>,
+    /// Keeps history with this retention, enabling it if the collection keeps none
+    pub history: Option<HistoryOptions>,
+}
+
+/// Stored documents checked against a collection's schema
+#[derive(Debug, Clone, Default)]
+pub struct RevalidationReport {
+    /// Documents checked
+    pub checked: usize,
+    /// Documents failing validation
+    pub invalid: usize,
+    /// Id and reason of the first failing documents
+    pub violations: Vec<(DocumentId, String)>,
+}
+
+impl RevalidationReport {
+    /// Protocol representation of the report
+    pub fn to_value(&self) -> Value {
+        let violations = self
+            .violations
+            .iter()
+            .map(|(id, reason)| {
+                Value::Object(BTreeMap::from([
+                    ("_id".to_string(), Value::String(id.to_string())),
+                    ("reason".to_string(), Value::String(reason.clone())),
+                ]))
+            })
+            .collect();
+        Value::Object(BTreeMap::from([
+            ("checked".to_string(), Value::Int64(self.checked as i64)),
+            ("invalid".to_string(), Value::Int64(self.invalid as i64)),
+            ("violations".to_string(), Value::Array(violations)),
+        ]))
+    }
+}
+
+/// Write-behind queue entry
+struct WriteBehindEntry {
+    collection: String,
+    doc_id: DocumentId,
+    operation: WriteBehindOperation,
+    delay_ms: u64,
+    queued_at: std::time::Instant,
+}
+
+/// Write-behind operation type
+enum WriteBehindOperation {
+    Write(Document),
+    Delete,
+}
+
+/// Documents of one collection or view, loaded on demand by the query executor
+struct CollectionSource<'a> {
+    engine: &'a HybridStorageEngine,
+    collection: &'a str,
+}
+
+impl DocumentSource for CollectionSource<'_> {
+    fn scan(&self) -> std::result::Result<Vec<Document>, QueryExecutionError> {
+        self.engine
+            .scan_collection_or_view(self.collection)
+            .map_err(|e| QueryExecutionError::ExecutionError(e.to_string()))
+    }
+
+    fn fetch(
+        &self,
+        doc_id: DocumentId,
+    ) -> std::result::Result<Option<Document>, QueryExecutionError> {
+        self.engine
+            .get_with_pending_writes(self.collection, doc_id)
+            .map_err(|e| QueryExecutionError::ExecutionError(e.to_string()))
+    }
+}
+
+/// Hybrid storage statistics
+#[derive(Debug, Default)]
+pub struct HybridStorageStats {
+    /// Cache hits
+    cache_hits: AtomicU64,
+    /// Cache misses
+    cache_misses: AtomicU64,
+    /// Cache writes
+    cache_writes: AtomicU64,
+    /// Cache invalidations
+    cache_invalidations: AtomicU64,
+    /// Persistent reads
+    persistent_reads: AtomicU64,
+    /// Persistent writes
+    persistent_writes: AtomicU64,
+    /// Cache warmed flag
+    cache_warmed: AtomicU64,
+}
+
+impl HybridStorageStats {
+    /// Record a cache hit
+    fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a cache miss
+    fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a cache write
+    fn record_cache_write(&self) {
         self.cache_writes.fetch_add(1, Ordering::Relaxed);
     }
 
-    /// Record a cache invalidation
-    fn record_cache_invalidation(&self) {
-        self.cache_invalidations.fetch_add(1, Ordering::Relaxed);
+    /// Record a cache invalidation
+    fn record_cache_invalidation(&self) {
+        self.cache_invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a persistent read
+    fn record_persistent_read(&self) {
+        self.persistent_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a persistent write
+    fn record_persistent_write(&self) {
+        self.persistent_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get cache hits
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Get cache misses
+    pub fn cache_misses(&self) -> u64 {
+        self.cache_misses.load(Ordering::Relaxed)
+    }
+
+    /// Get cache writes
+    pub fn cache_writes(&self) -> u64 {
+        self.cache_writes.load(Ordering::Relaxed)
+    }
+
+    /// Get cache invalidations
+    pub fn cache_invalidations(&self) -> u64 {
+        self.cache_invalidations.load(Ordering::Relaxed)
+    }
+
+    /// Get persistent reads
+    pub fn persistent_reads(&self) -> u64 {
+        self.persistent_reads.load(Ordering::Relaxed)
+    }
+
+    /// Get persistent writes
+    pub fn persistent_writes(&self) -> u64 {
+        self.persistent_writes.load(Ordering::Relaxed)
+    }
+
+    /// Calculate cache hit rate
+    pub fn cache_hit_rate(&self) -> f64 {
+        let hits = self.cache_hits();
+        let total = hits + self.cache_misses();
+        
+        if total == 0 {
+            0.0
+        } else {
+            hits as f64 / total as f64
+        }
+    }
+
+    /// Get total operations
+    pub fn total_operations(&self) -> u64 {
+        self.cache_hits() + self.cache_misses() + self.cache_writes()
+            + self.persistent_reads() + self.persistent_writes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Value;
+    use crate::schema::{FieldDefinition, FieldType, OnDelete, ValidationError};
+    use tempfile::TempDir;
+
+    fn create_test_engine() -> (Arc<HybridStorageEngine>, TempDir) {
+        let temp_dir = TempDir::new().unwrap();
+        let persistent = Arc::new(PersistentLayer::new(temp_dir.path()).unwrap());
+        let cache_config = CacheConfig::default();
+        
+        let engine = Arc::new(HybridStorageEngine::new(cache_config, persistent));
+        (engine, temp_dir)
+    }
+
+    fn create_test_schema(strategy: CacheStrategy) -> Schema {
+        let mut schema = Schema::new();
+        schema.add_field(
+            "name".to_string(),
+            FieldDefinition::new(FieldType::String { max_length: None }),
+        );
+        schema.cache_config.strategy = strategy;
+        schema
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_engine_creation() {
+        let (engine, _temp_dir) = create_test_engine();
+        assert_eq!(engine.stats().total_operations(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_insert_with_no_cache_strategy() {
+        let (engine, _temp_dir) = create_test_engine();
+        
+        let schema = create_test_schema(CacheStrategy::None);
+        engine.register_schema("users".to_string(), schema);
+        
+        let mut doc = Document::new();
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        
+        let doc_id = engine.insert_document("users", doc).await.unwrap();
+        
+        // Should only write to persistent
+        assert_eq!(engine.stats().persistent_writes(), 1);
+        assert_eq!(engine.stats().cache_writes(), 0);
+        
+        // Retrieve document
+        let retrieved = engine.get_document("users", doc_id).await.unwrap();
+        assert!(retrieved.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_insert_with_write_through() {
+        let (engine, _temp_dir) = create_test_engine();
+        
+        let schema = create_test_schema(CacheStrategy::WriteThrough);
+        engine.register_schema("users".to_string(), schema);
+        
+        let mut doc = Document::new();
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        let doc_id = doc.id;
+        
+        engine.insert_document("users", doc).await.unwrap();
+        
+        // Should write to both cache and persistent
+        assert_eq!(engine.stats().persistent_writes(), 1);
+        assert_eq!(engine.stats().cache_writes(), 1);
+        
+        // Retrieve should hit cache
+        let retrieved = engine.get_document("users", doc_id).await.unwrap();
+        assert!(retrieved.is_some());
+        assert_eq!(engine.stats().cache_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_insert_with_write_behind() {
+        let (engine, _temp_dir) = create_test_engine();
+        
+        let schema = create_test_schema(CacheStrategy::WriteBehind { delay_ms: 100 });
+        engine.register_schema("users".to_string(), schema);
+        
+        let mut doc = Document::new();
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        let doc_id = doc.id;
+        
+        engine.insert_document("users", doc).await.unwrap();
+        
+        // Should write to cache immediately
+        assert_eq!(engine.stats().cache_writes(), 1);
+        assert_eq!(engine.stats().persistent_writes(), 0);
+        
+        // Wait for write-behind to process
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        
+        // Flush to ensure write-behind is processed
+        engine.flush().await.unwrap();
+        
+        // Now persistent write should have happened
+        assert!(engine.stats().persistent_writes() >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_with_cache_miss() {
+        let (engine, _temp_dir) = create_test_engine();
+        
+        let schema = create_test_schema(CacheStrategy::WriteThrough);
+        engine.register_schema("users".to_string(), schema);
+        
+        // Insert directly to persistent (bypassing cache)
+        let mut doc = Document::new();
+        let doc_id = doc.id;
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        engine.persistent_layer().insert_document("users", doc_id, &doc).unwrap();
+        
+        // Get should miss cache, then populate it
+        let retrieved = engine.get_document("users", doc_id).await.unwrap();
+        assert!(retrieved.is_some());
+        assert_eq!(engine.stats().cache_misses(), 1);
+        assert_eq!(engine.stats().persistent_reads(), 1);
+        
+        // Second get should hit cache
+        let retrieved2 = engine.get_document("users", doc_id).await.unwrap();
+        assert!(retrieved2.is_some());
+        assert_eq!(engine.stats().cache_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_document() {
+        let (engine, _temp_dir) = create_test_engine();
+        
+        let schema = create_test_schema(CacheStrategy::WriteThrough);
+        engine.register_schema("users".to_string(), schema);
+        
+        let mut doc = Document::new();
+        let doc_id = doc.id;
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        
+        engine.insert_document("users", doc.clone()).await.unwrap();
+        
+        // Update
+        doc.insert("name".to_string(), Value::String("Jane".to_string()));
+        engine.update_document("users", doc_id, doc).await.unwrap();
+        
+        // Retrieve updated document
+        let retrieved = engine.get_document("users", doc_id).await.unwrap().unwrap();
+        assert_eq!(retrieved.get("name").unwrap().as_str(), Some("Jane"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_document() {
+        let (engine, _temp_dir) = create_test_engine();
+        
+        let schema = create_test_schema(CacheStrategy::WriteThrough);
+        engine.register_schema("users".to_string(), schema);
+        
+        let mut doc = Document::new();
+        let doc_id = doc.id;
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        
+        engine.insert_document("users", doc).await.unwrap();
+        
+        // Delete
+        let deleted = engine.delete_document("users", doc_id).await.unwrap();
+        assert!(deleted);
+        
+        // Should not exist
+        let retrieved = engine.get_document("users", doc_id).await.unwrap();
+        assert!(retrieved.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cache_invalidation() {
+        let (engine, _temp_dir) = create_test_engine();
+        
+        let schema = create_test_schema(CacheStrategy::ReadThrough);
+        engine.register_schema("users".to_string(), schema);
+        
+        let mut doc = Document::new();
+        let doc_id = doc.id;
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        
+        // Insert with ReadThrough invalidates cache
+        engine.insert_document("users", doc).await.unwrap();
+        
+        assert_eq!(engine.stats().cache_invalidations(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_statistics() {
+        let (engine, _temp_dir) = create_test_engine();
+        
+        let schema = create_test_schema(CacheStrategy::WriteThrough);
+        engine.register_schema("users".to_string(), schema);
+        
+        let mut doc = Document::new();
+        let doc_id = doc.id;
+        doc.insert("name".to_string(), Value::String("John".to_string()));
+        
+        engine.insert_document("users", doc).await.unwrap();
+        engine.get_document("users", doc_id).await.unwrap();
+        
+        let stats = engine.stats();
+        assert!(stats.total_operations() > 0);
+        assert!(stats.cache_hit_rate() >= 0.0 && stats.cache_hit_rate() <= 1.0);
+    }
+
+    fn sales_by_region() -> Vec<crate::aggregation::PipelineStage> {
+        use crate::aggregation::{AggregateOp, PipelineStage};
+        let mut fields = HashMap::new();
+        fields.insert("revenue".to_string(), AggregateOp::Sum(serde_json::json!("$amount")));
+        fields.insert("sales".to_string(), AggregateOp::Count {});
+        vec![PipelineStage::Group {
+            _id: serde_json::json!("$region"),
+            fields,
+        }]
+    }
+
+    fn sale(region: &str, amount: i64) -> Document {
+        let mut doc = Document::new();
+        doc.insert("region".to_string(), Value::String(region.to_string()));
+        doc.insert("amount".to_string(), Value::Int64(amount));
+        doc
+    }
+
+    #[tokio::test]
+    async fn test_materialized_view_follows_writes() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.register_schema("sales".to_string(), create_test_schema(CacheStrategy::WriteThrough));
+
+        let first = sale("eu", 100);
+        engine.insert_document("sales", first.clone()).await.unwrap();
+        engine
+            .create_view(ViewDefinition::materialized("by_region".to_string(), "sales".to_string(), sales_by_region()))
+            .unwrap();
+
+        let second = sale("eu", 50);
+        engine.insert_document("sales", second.clone()).await.unwrap();
+        engine.insert_document("sales", sale("us", 70)).await.unwrap();
+
+        let mut updated = first.clone();
+        updated.insert("amount".to_string(), Value::Int64(120));
+        engine.update_document("sales", first.id, updated).await.unwrap();
+        engine.delete_document("sales", second.id).await.unwrap();
+
+        let rows = engine.scan_collection_or_view("by_region").unwrap();
+        assert_eq!(rows.len(), 2);
+        let eu_key = Value::String(format!("{:?}", Value::String("eu".to_string())));
+        let eu = rows.iter().find(|d| d.get("_id") == Some(&eu_key)).unwrap();
+        assert_eq!(eu.get("revenue"), Some(&Value::Float64(120.0)));
+        assert_eq!(eu.get("sales"), Some(&Value::Int64(1)));
+
+        // Aggregations and their explain read the view's rows
+        let pipeline = crate::aggregation::Pipeline::new(vec![crate::aggregation::PipelineStage::Match {
+            filter: serde_json::json!({"sales": 1}),
+        }]);
+        assert_eq!(engine.aggregate("by_region", &pipeline).unwrap().len(), 2);
+        let explain = engine
+            .explain_aggregate("by_region", &pipeline, ExplainVerbosity::ExecutionStats)
+            .unwrap();
+        let Value::Object(explain) = explain else {
+            panic!("explain should be an object");
+        };
+        let Some(Value::Object(stats)) = explain.get("execution_stats") else {
+            panic!("execution stats should be an object");
+        };
+        let Some(Value::Array(stages)) = stats.get("stages") else {
+            panic!("stages should be an array");
+        };
+        let scanned: Vec<_> = stages
+            .iter()
+            .map(|stage| match stage {
+                Value::Object(stage) => stage.get("documents_out").cloned(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(scanned, vec![Some(Value::Int64(2)), Some(Value::Int64(2))]);
+
+        // The view is queryable like a collection
+        let query = crate::query::Query::with_filter(crate::query::Filter::eq("sales", Value::Int64(1)));
+        assert_eq!(engine.query("by_region", &query).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_plain_view_reruns_pipeline() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine
+            .create_view(ViewDefinition::plain("by_region".to_string(), "sales".to_string(), sales_by_region()))
+            .unwrap();
+        assert!(engine.scan_collection_or_view("by_region").unwrap().is_empty());
+
+        engine.insert_document("sales", sale("eu", 10)).await.unwrap();
+        let rows = engine.scan_collection_or_view("by_region").unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get("revenue"), Some(&Value::Float64(10.0)));
+    }
+
+    #[tokio::test]
+    async fn test_view_rejects_writes_and_restores() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.insert_document("sales", sale("eu", 10)).await.unwrap();
+        engine
+            .create_view(ViewDefinition::materialized("by_region".to_string(), "sales".to_string(), sales_by_region()))
+            .unwrap();
+
+        assert!(engine.insert_document("by_region", sale("eu", 1)).await.is_err());
+        assert!(engine.delete_document("by_region", DocumentId::new()).await.is_err());
+        assert!(engine
+            .create_view(ViewDefinition::plain("sales".to_string(), "other".to_string(), Vec::new()))
+            .is_err());
+
+        // A fresh engine over the same storage rebuilds the view
+        let restored = HybridStorageEngine::new(CacheConfig::default(), engine.persistent_layer().clone());
+        assert_eq!(restored.restore_views().unwrap(), 1);
+        assert_eq!(restored.scan_collection_or_view("by_region").unwrap().len(), 1);
+
+        // Dropping the view name removes the view, not the source
+        engine.drop_collection("by_region").unwrap();
+        assert!(!engine.is_view("by_region"));
+        assert_eq!(engine.scan_collection("sales").unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_collection_schema_enforced_and_restored() {
+        let (engine, _temp_dir) = create_test_engine();
+        let schema = Schema::from_json(&serde_json::json!({
+            "required": ["email"],
+            "additionalProperties": false,
+            "properties": {
+                "email": {"bsonType": "string"},
+                "plan": {"enum": ["free", "pro"], "default": "free"}
+            }
+        }))
+        .unwrap();
+        engine.create_collection_with_schema("accounts", schema).unwrap();
+
+        let account = |email: Option<&str>| {
+            let mut doc = Document::new();
+            if let Some(email) = email {
+                doc.insert("email".to_string(), Value::String(email.to_string()));
+            }
+            doc
+        };
+
+        // Defaults are applied before validation
+        let id = engine.insert_document("accounts", account(Some("a@example.com"))).await.unwrap();
+        let stored = engine.get_document("accounts", id).await.unwrap().unwrap();
+        assert_eq!(stored.get("plan").unwrap().as_str(), Some("free"));
+
+        let err = engine.insert_document("accounts", account(None)).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ValidationError>(), Some(ValidationError::RequiredFieldMissing(_))));
+        let mut extra = account(Some("b@example.com"));
+        extra.insert("admin".to_string(), Value::Bool(true));
+        assert!(engine.insert_document("accounts", extra).await.is_err());
+
+        // Updates are validated but keep their fields as given
+        let mut updated = stored.clone();
+        updated.insert("plan".to_string(), Value::String("gold".to_string()));
+        assert!(engine.update_document("accounts", id, updated).await.is_err());
+        assert_eq!(engine.get_document("accounts", id).await.unwrap().unwrap().get("plan"), stored.get("plan"));
+        assert_eq!(engine.scan_collection("accounts").unwrap().len(), 1);
+
+        // A fresh engine over the same storage enforces the saved schema
+        let restarted = HybridStorageEngine::new(CacheConfig::default(), engine.persistent_layer().clone());
+        assert_eq!(restarted.restore_schemas().unwrap(), 1);
+        assert!(restarted.insert_document("accounts", account(None)).await.is_err());
+
+        // Dropping the collection drops its schema too
+        engine.drop_collection("accounts").unwrap();
+        assert!(engine.get_schema("accounts").is_none());
+        engine.insert_document("accounts", account(None)).await.unwrap();
+        let restarted = HybridStorageEngine::new(CacheConfig::default(), engine.persistent_layer().clone());
+        assert_eq!(restarted.restore_schemas().unwrap(), 0);
     }
 
-    /// Record a persistent read
-    fn record_persistent_read(&self) {
-        self.persistent_reads.fetch_add(1, Ordering::Relaxed);
+    #[tokio::test]
+    async fn test_collection_schema_warn_action_accepts_invalid_documents() {
+        let (engine, _temp_dir) = create_test_engine();
+        let mut schema = Schema::from_json(&serde_json::json!({
+            "$jsonSchema": {"required": ["email"], "properties": {"email": {"bsonType": "string"}}}
+        }))
+        .unwrap();
+        schema.validation_action = ValidationAction::Warn;
+        engine.create_collection_with_schema("leads", schema).unwrap();
+
+        engine.insert_document("leads", Document::new()).await.unwrap();
+        assert_eq!(engine.scan_collection("leads").unwrap().len(), 1);
     }
 
-    /// Record a persistent write
-    fn record_persistent_write(&self) {
-        self.persistent_writes.fetch_add(1, Ordering::Relaxed);
+    #[tokio::test]
+    async fn test_registered_validators_run_on_writes() {
+        let (engine, _temp_dir) = create_test_engine();
+        let mut schema = Schema::new();
+        schema.validators.push(crate::schema::Validator::Custom("balanced".to_string()));
+        engine.create_collection_with_schema("ledger", schema).unwrap();
+        let entry = |debit: i64, credit: i64| {
+            let mut doc = Document::new();
+            doc.insert("debit".to_string(), Value::Int64(debit));
+            doc.insert("credit".to_string(), Value::Int64(credit));
+            doc
+        };
+
+        // Unregistered validators reject writes
+        let err = engine.insert_document("ledger", entry(5, 5)).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ValidationError>(), Some(ValidationError::UnknownValidator(_))));
+
+        engine.validator_registry().register("balanced", |_, doc| {
+            match (doc.get("debit").and_then(Value::as_i64), doc.get("credit").and_then(Value::as_i64)) {
+                (Some(debit), Some(credit)) if debit == credit => Ok(()),
+                _ => Err("debit and credit differ".to_string()),
+            }
+        });
+        let id = engine.insert_document("ledger", entry(5, 5)).await.unwrap();
+        let err = engine.update_document("ledger", id, entry(5, 4)).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ValidationError>(),
+            Some(ValidationError::CustomValidationFailed { .. })
+        ));
+
+        // Revalidation runs the same validators
+        engine.validator_registry().register("balanced", |_, _| Err("closed".to_string()));
+        assert_eq!(engine.revalidate_collection("ledger").unwrap().invalid, 1);
     }
 
-    /// Get cache hits
-    pub fn cache_hits(&self) -> u64 {
-        self.cache_hits.load(Ordering::Relaxed)
+    #[tokio::test]
+    async fn test_modify_collection_schema_and_revalidate() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.create_collection("contacts").unwrap();
+        let legacy = engine.insert_document("contacts", Document::new()).await.unwrap();
+
+        let schema = Schema::from_json(&serde_json::json!({
+            "required": ["email"],
+            "properties": {"email": {"bsonType": "string"}}
+        }))
+        .unwrap();
+        let modification = CollectionModification { schema: Some(schema), ..Default::default() };
+        assert_eq!(engine.modify_collection("contacts", modification).await.unwrap(), 2);
+
+        // New writes see the schema at once; stored documents are only reported
+        assert!(engine.insert_document("contacts", Document::new()).await.is_err());
+        let mut valid = Document::new();
+        valid.insert("email".to_string(), Value::String("a@example.com".to_string()));
+        engine.insert_document("contacts", valid).await.unwrap();
+        let report = engine.revalidate_collection("contacts").unwrap();
+        assert_eq!((report.checked, report.invalid), (2, 1));
+        assert_eq!(report.violations[0].0, legacy);
+        assert!(report.violations[0].1.contains("email"));
+
+        let warn = CollectionModification { validation_action: Some(ValidationAction::Warn), ..Default::default() };
+        assert_eq!(engine.modify_collection("contacts", warn).await.unwrap(), 3);
+        engine.insert_document("contacts", Document::new()).await.unwrap();
+
+        // The settings survive a restart
+        let restarted = HybridStorageEngine::new(CacheConfig::default(), engine.persistent_layer().clone());
+        restarted.restore_schemas().unwrap();
+        let restored = restarted.get_schema("contacts").unwrap();
+        assert_eq!((restored.version, restored.validation_action), (3, ValidationAction::Warn));
+        assert!(restored.fields["email"].required);
+
+        assert!(engine.modify_collection("missing", CollectionModification::default()).await.is_err());
+        assert!(engine.revalidate_collection("missing").is_err());
     }
 
-    /// Get cache misses
-    pub fn cache_misses(&self) -> u64 {
-        self.cache_misses.load(Ordering::Relaxed)
+    #[tokio::test]
+    async fn test_modify_collection_checks_history_before_changing() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.create_collection("events").unwrap();
+        let invalid = CollectionModification {
+            validation_action: Some(ValidationAction::Warn),
+            history: Some(HistoryOptions { max_age_secs: Some(0), max_versions: None }),
+            ..Default::default()
+        };
+        let err = engine.modify_collection("events", invalid).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<HistoryError>(), Some(&HistoryError::InvalidOptions));
+        assert!(engine.get_schema("events").is_none());
+        assert_eq!(engine.history_options("events").unwrap(), None);
+
+        let options = HistoryOptions { max_age_secs: None, max_versions: Some(3) };
+        let valid = CollectionModification {
+            validation_action: Some(ValidationAction::Warn),
+            history: Some(options),
+            ..Default::default()
+        };
+        assert_eq!(engine.modify_collection("events", valid).await.unwrap(), 2);
+        assert_eq!(engine.get_schema("events").unwrap().validation_action, ValidationAction::Warn);
+        assert_eq!(engine.history_options("events").unwrap(), Some(options));
+
+        // Retention alone leaves the schema version as it is
+        let retention = CollectionModification { history: Some(HistoryOptions::default()), ..Default::default() };
+        assert_eq!(engine.modify_collection("events", retention).await.unwrap(), 2);
+        assert_eq!(engine.history_options("events").unwrap(), Some(HistoryOptions::default()));
     }
 
-    /// Get cache writes
-    pub fn cache_writes(&self) -> u64 {
-        self.cache_writes.load(Ordering::Relaxed)
+    #[tokio::test]
+    async fn test_modify_collection_switches_cache_strategy() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.create_collection("events").unwrap();
+        let write_behind = CollectionCacheConfig {
+            strategy: CacheStrategy::WriteBehind { delay_ms: 60_000 },
+            ..Default::default()
+        };
+        let modification = CollectionModification { cache_config: Some(write_behind), ..Default::default() };
+        engine.modify_collection("events", modification).await.unwrap();
+
+        let mut doc = Document::new();
+        doc.insert("kind".to_string(), Value::String("click".to_string()));
+        let id = engine.insert_document("events", doc).await.unwrap();
+        assert!(engine.persistent_layer().get_document("events", id).unwrap().is_none());
+
+        // Leaving write-behind persists the queued writes
+        let write_through = CollectionCacheConfig { strategy: CacheStrategy::WriteThrough, ..Default::default() };
+        let modification = CollectionModification { cache_config: Some(write_through), ..Default::default() };
+        engine.modify_collection("events", modification).await.unwrap();
+        assert!(engine.persistent_layer().get_document("events", id).unwrap().is_some());
+        assert!(engine.write_behind_queue.read().is_empty());
+        assert_eq!(engine.get_document("events", id).await.unwrap().unwrap().get("kind").unwrap().as_str(), Some("click"));
+        assert_eq!(engine.get_schema("events").unwrap().cache_config.strategy, CacheStrategy::WriteThrough);
+    }
+
+    fn reference_schema(field: &str, target: &str, enforce: bool, on_delete: OnDelete) -> Schema {
+        let mut schema = Schema::new();
+        schema.add_field(
+            field.to_string(),
+            FieldDefinition::new(FieldType::Reference { collection: target.to_string(), enforce, on_delete }),
+        );
+        schema
+    }
+
+    fn referencing(field: &str, id: DocumentId) -> Document {
+        let mut doc = Document::new();
+        doc.insert(field.to_string(), Value::String(id.to_string()));
+        doc
+    }
+
+    #[tokio::test]
+    async fn test_enforced_reference_requires_existing_document() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine
+            .create_collection_with_schema("posts", reference_schema("author", "users", true, OnDelete::Ignore))
+            .unwrap();
+
+        let err = engine.insert_document("posts", referencing("author", DocumentId::new())).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ReferenceError>(), Some(ReferenceError::Missing { .. })));
+        let mut unresolvable = Document::new();
+        unresolvable.insert("author".to_string(), Value::ObjectId(crate::document::ObjectId::new()));
+        let err = engine.insert_document("posts", unresolvable).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ReferenceError>(), Some(ReferenceError::Unresolvable { .. })));
+
+        let user = engine.insert_document("users", Document::new()).await.unwrap();
+        let post = engine.insert_document("posts", referencing("author", user)).await.unwrap();
+
+        let mut moved = referencing("author", DocumentId::new());
+        moved.id = post;
+        assert!(engine.update_document("posts", post, moved).await.is_err());
+        let stored = engine.get_document("posts", post).await.unwrap().unwrap();
+        assert_eq!(stored.get("author").unwrap().as_str(), Some(user.to_string().as_str()));
+
+        // Unenforced references are only type-checked
+        engine
+            .create_collection_with_schema("drafts", reference_schema("author", "users", false, OnDelete::Ignore))
+            .unwrap();
+        engine.insert_document("drafts", referencing("author", DocumentId::new())).await.unwrap();
+        assert!(engine.delete_document("users", user).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete_cascades_and_sets_references_to_null() {
+        let (engine, _temp_dir) = create_test_engine();
+        let mut posts = reference_schema("author", "users", true, OnDelete::Cascade);
+        posts.cache_config.strategy = CacheStrategy::WriteBehind { delay_ms: 60_000 };
+        engine.create_collection_with_schema("posts", posts).unwrap();
+        engine
+            .create_collection_with_schema("comments", reference_schema("post", "posts", true, OnDelete::Cascade))
+            .unwrap();
+        engine
+            .create_collection_with_schema("teams", reference_schema("lead", "users", false, OnDelete::SetNull))
+            .unwrap();
+        engine.create_index("teams", "lead_1", index_fields(&[("lead", 1)]), IndexOptions::default()).unwrap();
+
+        let user = engine.insert_document("users", Document::new()).await.unwrap();
+        let other = engine.insert_document("users", Document::new()).await.unwrap();
+        let post = engine.insert_document("posts", referencing("author", user)).await.unwrap();
+        engine.insert_document("posts", referencing("author", other)).await.unwrap();
+        for _ in 0..2 {
+            engine.insert_document("comments", referencing("post", post)).await.unwrap();
+        }
+        let team = engine.insert_document("teams", referencing("lead", user)).await.unwrap();
+
+        let outcome = engine.delete_document_cascading("users", user).await.unwrap();
+        assert!(outcome.deleted);
+        assert_eq!(outcome.cascaded, BTreeMap::from([("comments".to_string(), 2), ("posts".to_string(), 1)]));
+        assert_eq!(outcome.nulled, BTreeMap::from([("teams".to_string(), 1)]));
+
+        assert!(engine.get_document("users", user).await.unwrap().is_none());
+        assert!(engine.get_document("posts", post).await.unwrap().is_none());
+        assert_eq!(engine.scan_collection("posts").unwrap().len(), 1);
+        assert!(engine.scan_collection("comments").unwrap().is_empty());
+        let team = engine.get_document("teams", team).await.unwrap().unwrap();
+        assert_eq!(team.get("lead"), Some(&Value::Null));
+        let query = crate::query::QueryParser::parse_from_value(&serde_json::json!({"filter": {"lead": null}})).unwrap();
+        assert_eq!(engine.query("teams", &query).unwrap().len(), 1);
+
+        // Deleting a missing document reports nothing
+        let outcome = engine.delete_document_cascading("users", user).await.unwrap();
+        assert_eq!(outcome, DeleteOutcome::default());
+    }
+
+    #[tokio::test]
+    async fn test_restricted_delete_changes_nothing() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine
+            .create_collection_with_schema("orders", reference_schema("customer", "customers", true, OnDelete::Restrict))
+            .unwrap();
+        let customer = engine.insert_document("customers", Document::new()).await.unwrap();
+        let order = engine.insert_document("orders", referencing("customer", customer)).await.unwrap();
+
+        let err = engine.delete_document("customers", customer).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ReferenceError>(),
+            Some(ReferenceError::Restricted { count: 1, .. })
+        ));
+        assert!(engine.get_document("customers", customer).await.unwrap().is_some());
+
+        assert!(engine.delete_document("orders", order).await.unwrap());
+        assert!(engine.delete_document("customers", customer).await.unwrap());
+    }
+
+    fn order(status: &str, total: i64) -> Document {
+        let mut doc = Document::new();
+        doc.insert("status".to_string(), Value::String(status.to_string()));
+        doc.insert("total".to_string(), Value::Int64(total));
+        doc
+    }
+
+    /// Add closed orders so that status lookups on "open" are selective
+    async fn insert_closed_orders(engine: &HybridStorageEngine, count: i64) {
+        for total in 0..count {
+            engine.insert_document("orders", order("closed", total)).await.unwrap();
+        }
+    }
+
+    fn index_fields(fields: &[(&str, i32)]) -> Vec<crate::protocol::IndexField> {
+        fields
+            .iter()
+            .map(|(field, direction)| crate::protocol::IndexField {
+                field: field.to_string(),
+                direction: *direction,
+            })
+            .collect()
+    }
+
+    fn open_between(low: i64, high: i64) -> crate::query::Query {
+        crate::query::Query::with_filter(crate::query::Filter::and(vec![
+            crate::query::Filter::eq("status", "open"),
+            crate::query::Filter::gte("total", low),
+            crate::query::Filter::lte("total", high),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn test_compound_index_follows_writes() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.insert_document("orders", order("open", 10)).await.unwrap();
+        engine.insert_document("orders", order("closed", 20)).await.unwrap();
+
+        engine
+            .create_index("orders", "status_total", index_fields(&[("status", 1), ("total", -1)]), IndexOptions::default())
+            .unwrap();
+
+        let late = order("open", 30);
+        let late_id = engine.insert_document("orders", late.clone()).await.unwrap();
+
+        let plan = engine.plan_query("orders", &open_between(5, 50)).unwrap();
+        assert_eq!(plan.use_index, Some("status_total".to_string()));
+        assert_eq!(engine.query("orders", &open_between(5, 50)).unwrap().len(), 2);
+
+        // Moving an order out of range and deleting another keeps the index exact
+        let mut moved = late;
+        moved.insert("total".to_string(), Value::Int64(100));
+        engine.update_document("orders", late_id, moved).await.unwrap();
+        assert_eq!(engine.query("orders", &open_between(5, 50)).unwrap().len(), 1);
+        assert_eq!(engine.query("orders", &open_between(60, 150)).unwrap().len(), 1);
+
+        engine.delete_document("orders", late_id).await.unwrap();
+        assert!(engine.query("orders", &open_between(60, 150)).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_paged_listing_uses_index_order() {
+        use crate::query::{Projection, Query, Sort};
+
+        let (engine, _temp_dir) = create_test_engine();
+        for created_at in 0..60 {
+            let mut doc = order("open", created_at);
+            doc.insert("created_at".to_string(), Value::Int64(created_at));
+            engine.insert_document("orders", doc).await.unwrap();
+        }
+        engine
+            .create_index("orders", "created", index_fields(&[("created_at", -1)]), IndexOptions::default())
+            .unwrap();
+
+        let page = Query::new().sort(Sort::new().desc("created_at")).skip(50).limit(50);
+        let plan = engine.plan_query("orders", &page).unwrap();
+        assert_eq!(plan.use_index, Some("created".to_string()));
+        assert!(!plan.needs_sort);
+
+        let created = |docs: &[Document]| {
+            docs.iter()
+                .map(|d| d.get("created_at").cloned().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let results = engine.query("orders", &page).unwrap();
+        assert_eq!(created(&results), (0..10).rev().map(Value::Int64).collect::<Vec<_>>());
+
+        // Projecting only the indexed field is answered from the index
+        let covered = Query::new()
+            .projection(Projection::new().include("created_at"))
+            .sort(Sort::new().asc("created_at"))
+            .limit(3);
+        assert!(engine.plan_query("orders", &covered).unwrap().covered);
+        let results = engine.query("orders", &covered).unwrap();
+        assert_eq!(created(&results), (0..3).map(Value::Int64).collect::<Vec<_>>());
+        assert!(results.iter().all(|d| d.get("status").is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_unique_index_rejects_duplicates() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.insert_document("orders", order("open", 10)).await.unwrap();
+        engine.insert_document("orders", order("open", 10)).await.unwrap();
+
+        // Existing duplicates prevent building the index
+        let error = engine
+            .create_index("orders", "status_total", index_fields(&[("status", 1), ("total", 1)]), IndexOptions::unique())
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<IndexError>(),
+            Some(IndexError::UniqueConstraintViolation { index, .. }) if index == "status_total"
+        ));
+        assert!(engine.list_indexes("orders").unwrap().is_empty());
+        assert!(engine.persistent_layer().index_state("orders", "status_total").unwrap().is_none());
+
+        engine
+            .create_index("orders", "total", index_fields(&[("total", 1)]), IndexOptions::default())
+            .unwrap();
+        engine
+            .create_index("invoices", "number", index_fields(&[("number", 1)]), IndexOptions::unique())
+            .unwrap();
+
+        let mut first = Document::new();
+        first.insert("number".to_string(), Value::Int64(1));
+        engine.insert_document("invoices", first).await.unwrap();
+
+        let mut duplicate = Document::new();
+        duplicate.insert("number".to_string(), Value::Int64(1));
+        assert!(engine.insert_document("invoices", duplicate).await.is_err());
+        assert_eq!(engine.scan_collection("invoices").unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_updates_of_one_document_keep_one_index_entry() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.create_index("orders", "total", index_fields(&[("total", 1)]), IndexOptions::unique()).unwrap();
+        let id = engine.insert_document("orders", order("open", 0)).await.unwrap();
+
+        let updates: Vec<_> = (1..=16)
+            .map(|total| {
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    let mut doc = order("open", total);
+                    doc.id = id;
+                    engine.update_document("orders", id, doc).await
+                })
+            })
+            .collect();
+        for update in updates {
+            update.await.unwrap().unwrap();
+        }
+
+        // The entry of every replaced total is gone; only the stored one is left
+        let stored = engine.get_document("orders", id).await.unwrap().unwrap();
+        let index = engine.index_manager("orders").unwrap().get_index("total").unwrap();
+        assert_eq!(index.entry_count(), 1);
+        let total = stored.get("total").unwrap().clone();
+        let found = engine.query("orders", &crate::query::Query::with_filter(crate::query::Filter::eq("total", total))).unwrap();
+        assert_eq!(found.len(), 1);
+
+        // Deletes take the same lock and leave no entry behind
+        let deletes: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = engine.clone();
+                tokio::spawn(async move { engine.delete_document("orders", id).await })
+            })
+            .collect();
+        let mut deleted = 0;
+        for delete in deletes {
+            deleted += delete.await.unwrap().unwrap() as usize;
+        }
+        assert_eq!(deleted, 1);
+        assert_eq!(index.entry_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_document_locks_exclude_holders_and_are_dropped() {
+        let (engine, _temp_dir) = create_test_engine();
+        let id = DocumentId::new();
+        let held = engine.lock_document("fs.files", id).await;
+        let waiting = {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                let _lock = engine.lock_document("fs.files", id).await;
+            })
+        };
+        sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        // Other documents are not held back
+        let other = engine.lock_document("fs.files", DocumentId::new()).await;
+
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        drop(other);
+        let _fresh = engine.lock_document("fs.files", DocumentId::new()).await;
+        assert_eq!(engine.document_locks.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_writes_the_wal_cannot_log_are_undone() {
+        let (engine, _temp_dir) = create_test_engine();
+        let wal_root = TempDir::new().unwrap();
+        let wal_dir = wal_root.path().join("wal");
+        // Every append rotates to a new file, which fails once the directory is gone
+        let config = crate::wal::WalConfig { wal_dir: wal_dir.clone(), max_file_size: 1, ..Default::default() };
+        let writer = WalWriter::new(config).unwrap();
+        engine.attach_wal(Arc::new(writer), Arc::new(SyncManager::new(wal_dir.clone(), wal_root.path().join("snapshots"))));
+        engine
+            .create_index("users", "name", index_fields(&[("name", 1)]), IndexOptions::unique())
+            .unwrap();
+        let ann = engine.insert_document("users", user("ann", None)).await.unwrap();
+
+        std::fs::remove_dir_all(&wal_dir).unwrap();
+        let bob = user("bob", None);
+        let bob_id = bob.id;
+        assert!(engine.insert_document("users", bob).await.is_err());
+        assert!(engine.get_document("users", bob_id).await.unwrap().is_none());
+
+        let mut renamed = user("bob", None);
+        renamed.id = ann;
+        assert!(engine.update_document("users", ann, renamed).await.is_err());
+        assert!(engine.delete_document("users", ann).await.is_err());
+        let stored = engine.get_document("users", ann).await.unwrap().unwrap();
+        assert_eq!(stored.get("name"), Some(&Value::String("ann".to_string())));
+
+        // The index holds "ann" only, as before the failed writes
+        std::fs::create_dir_all(&wal_dir).unwrap();
+        engine.insert_document("users", user("bob", None)).await.unwrap();
+        let duplicate = engine.insert_document("users", user("ann", None)).await.unwrap_err();
+        assert!(is_duplicate(&duplicate, "name"));
+        assert_eq!(engine.scan_collection("users").unwrap().len(), 2);
+    }
+
+    fn user(name: &str, email: Option<&str>) -> Document {
+        let mut doc = Document::new();
+        doc.insert("name".to_string(), Value::String(name.to_string()));
+        if let Some(email) = email {
+            doc.insert("email".to_string(), Value::String(email.to_string()));
+        }
+        doc
+    }
+
+    fn is_duplicate(error: &anyhow::Error, expected_index: &str) -> bool {
+        matches!(
+            error.downcast_ref::<IndexError>(),
+            Some(IndexError::UniqueConstraintViolation { index, .. }) if index == expected_index
+        )
+    }
+
+    #[tokio::test]
+    async fn test_unique_index_enforced_under_every_cache_strategy() {
+        let (engine, _temp_dir) = create_test_engine();
+        let strategies = [
+            CacheStrategy::None,
+            CacheStrategy::WriteThrough,
+            CacheStrategy::WriteBehind { delay_ms: 100 },
+            CacheStrategy::ReadThrough,
+        ];
+
+        for (i, strategy) in strategies.into_iter().enumerate() {
+            let collection = format!("users_{}", i);
+            engine.register_schema(collection.clone(), create_test_schema(strategy.clone()));
+            engine
+                .create_index(&collection, "email", index_fields(&[("email", 1)]), IndexOptions::unique())
+                .unwrap();
+
+            engine.insert_document(&collection, user("a", Some("a@example.com"))).await.unwrap();
+            let error = engine.insert_document(&collection, user("a2", Some("a@example.com"))).await.unwrap_err();
+            assert!(is_duplicate(&error, "email"), "{:?}: {}", strategy, error);
+
+            // A rejected update keeps the document and its index entries
+            let b = engine.insert_document(&collection, user("b", Some("b@example.com"))).await.unwrap();
+            let mut renamed = user("b", Some("a@example.com"));
+            renamed.id = b;
+            let error = engine.update_document(&collection, b, renamed).await.unwrap_err();
+            assert!(is_duplicate(&error, "email"));
+            let stored = engine.get_document(&collection, b).await.unwrap().unwrap();
+            assert_eq!(stored.get("email"), Some(&Value::String("b@example.com".to_string())));
+
+            // Moving to a free key releases the old one
+            let mut moved = user("b", Some("c@example.com"));
+            moved.id = b;
+            engine.update_document(&collection, b, moved).await.unwrap();
+            engine.insert_document(&collection, user("d", Some("b@example.com"))).await.unwrap();
+
+            engine.flush().await.unwrap();
+            assert_eq!(engine.persistent_layer().count_documents(&collection).unwrap(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sparse_unique_index_and_persistent_writes() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine
+            .create_index("users", "email", index_fields(&[("email", 1)]), IndexOptions::unique().sparse())
+            .unwrap();
+        assert_eq!(engine.list_indexes("users").unwrap()[0]["sparse"], serde_json::Value::Bool(true));
+
+        // Documents without the field stay out of a sparse unique index
+        engine.insert_document("users", user("a", None)).await.unwrap();
+        engine.insert_document("users", user("b", None)).await.unwrap();
+        engine.insert_document("users", user("c", Some("c@example.com"))).await.unwrap();
+        let error = engine.insert_document("users", user("d", Some("c@example.com"))).await.unwrap_err();
+        assert!(is_duplicate(&error, "email"));
+
+        // Writes that bypass the engine are checked against the persisted entries
+        let persistent = engine.persistent_layer();
+        let duplicate = user("e", Some("c@example.com"));
+        let error = persistent.insert_document("users", duplicate.id, &duplicate).unwrap_err();
+        assert!(is_duplicate(&error, "email"));
+        assert_eq!(persistent.count_documents("users").unwrap(), 3);
+        assert_eq!(persistent.index_state("users", "email").unwrap().unwrap().entries, 1);
+
+        // The options survive a restart
+        let restarted = HybridStorageEngine::new(CacheConfig::default(), persistent.clone());
+        restarted.insert_document("users", user("f", None)).await.unwrap();
+        assert!(restarted.insert_document("users", user("g", Some("c@example.com"))).await.is_err());
+
+        // Without `sparse`, a missing field is a key like any other
+        engine
+            .create_index("people", "email", index_fields(&[("email", 1)]), IndexOptions::unique())
+            .unwrap();
+        engine.insert_document("people", user("a", None)).await.unwrap();
+        let error = engine.insert_document("people", user("b", None)).await.unwrap_err();
+        assert!(is_duplicate(&error, "email"));
+    }
+
+    #[tokio::test]
+    async fn test_multikey_index_persists_element_entries() {
+        use crate::query::{Filter, Query};
+
+        let (engine, _temp_dir) = create_test_engine();
+        let product = |tags: &[&str]| {
+            let mut doc = Document::new();
+            doc.insert("tags".to_string(), Value::Array(tags.iter().map(|t| Value::from(*t)).collect()));
+            doc
+        };
+        for _ in 0..40 {
+            engine.insert_document("products", product(&["plain"])).await.unwrap();
+        }
+        engine
+            .create_index("products", "tags", index_fields(&[("tags", 1)]), IndexOptions::default())
+            .unwrap();
+        let single = product(&["red", "sale", "red"]);
+        let id = single.id;
+        engine.insert_document("products", single).await.unwrap();
+
+        let persistent = engine.persistent_layer();
+        let state = persistent.index_state("products", "tags").unwrap().unwrap();
+        assert!(state.multikey);
+        assert_eq!(state.entries, 42);
+
+        // Dropping an element removes just its entry
+        let mut updated = product(&["red"]);
+        updated.id = id;
+        engine.update_document("products", id, updated).await.unwrap();
+        assert_eq!(persistent.index_state("products", "tags").unwrap().unwrap().entries, 41);
+
+        let red = Query::with_filter(Filter::eq("tags", "red"));
+        let plan = engine.plan_query("products", &red).unwrap();
+        assert!(plan.index_scan.as_ref().is_some_and(|scan| scan.multikey));
+        assert_eq!(engine.query("products", &red).unwrap().len(), 1);
+
+        // The flag survives a restart that reloads the persisted entries
+        let restarted = HybridStorageEngine::new(CacheConfig::default(), persistent.clone());
+        let plan = restarted.plan_query("products", &red).unwrap();
+        assert!(plan.index_scan.as_ref().is_some_and(|scan| scan.multikey));
+        assert_eq!(restarted.query("products", &red).unwrap()[0].id, id);
+
+        // Two array fields in one compound key cannot be indexed
+        engine
+            .create_index("pairs", "a_b", index_fields(&[("a", 1), ("b", 1)]), IndexOptions::default())
+            .unwrap();
+        let mut pair = Document::new();
+        pair.insert("a".to_string(), Value::Array(vec![Value::Int32(1)]));
+        pair.insert("b".to_string(), Value::Array(vec![Value::Int32(2)]));
+        let error = engine.insert_document("pairs", pair).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<IndexError>(),
+            Some(IndexError::ParallelArrays(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_partial_index_persists_matching_entries() {
+        use crate::query::{Filter, Query};
+
+        let (engine, _temp_dir) = create_test_engine();
+        let user = |name: &str, status: &str| {
+            let mut doc = Document::new();
+            doc.insert("name".to_string(), Value::from(name));
+            doc.insert("status".to_string(), Value::from(status));
+            doc
+        };
+        for i in 0..40 {
+            engine.insert_document("users", user(&format!("gone{}", i), "inactive")).await.unwrap();
+        }
+        engine.insert_document("users", user("ann", "active")).await.unwrap();
+        let options = IndexOptions::default().partial(Filter::eq("status", "active"));
+        engine.create_index("users", "active_names", index_fields(&[("name", 1)]), options).unwrap();
+        engine.insert_document("users", user("bob", "active")).await.unwrap();
+        engine.insert_document("users", user("cid", "inactive")).await.unwrap();
+
+        let persistent = engine.persistent_layer();
+        assert_eq!(persistent.index_state("users", "active_names").unwrap().unwrap().entries, 2);
+
+        // Only queries confined to active users may use the index
+        let active = Query::with_filter(Filter::And(vec![
+            Filter::eq("name", "bob"),
+            Filter::eq("status", "active"),
+        ]));
+        let plan = engine.plan_query("users", &active).unwrap();
+        assert_eq!(plan.index_scan.map(|scan| scan.index), Some("active_names".to_string()));
+        assert_eq!(engine.query("users", &active).unwrap().len(), 1);
+        let any = Query::with_filter(Filter::eq("name", "cid"));
+        assert!(engine.plan_query("users", &any).unwrap().index_scan.is_none());
+        assert_eq!(engine.query("users", &any).unwrap().len(), 1);
+
+        // The filter is persisted with the definition and survives a restart
+        let restarted = HybridStorageEngine::new(CacheConfig::default(), persistent.clone());
+        assert!(restarted.plan_query("users", &any).unwrap().index_scan.is_none());
+        assert!(restarted.plan_query("users", &active).unwrap().index_scan.is_some());
+
+        let unsupported = IndexOptions::default().partial(Filter::ne("status", "active"));
+        assert!(engine.create_index("users", "bad", index_fields(&[("name", 1)]), unsupported).is_err());
+        assert!(persistent.index_state("users", "bad").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_collated_unique_and_hashed_indexes() {
+        use crate::query::{Collation, Filter, Query};
+
+        let (engine, _temp_dir) = create_test_engine();
+        let user = |name: &str, email: &str| {
+            let mut doc = Document::new();
+            doc.insert("name".to_string(), Value::from(name));
+            doc.insert("email".to_string(), Value::from(email));
+            doc
+        };
+        for i in 0..40 {
+            engine.insert_document("users", user(&format!("user{}", i), &format!("user{}@example.com", i))).await.unwrap();
+        }
+        let case_insensitive = Collation::new("en").with_strength(2);
+        let options = IndexOptions::unique().collation(case_insensitive.clone());
+        engine.create_index("users", "email_ci", index_fields(&[("email", 1)]), options).unwrap();
+        engine.create_index("users", "name_hashed", index_fields(&[("name", 1)]), IndexOptions::hashed()).unwrap();
+        engine.insert_document("users", user("ann", "Ann@Example.com")).await.unwrap();
+
+        // Emails differing only in case are duplicates
+        let err = engine.insert_document("users", user("ann2", "ann@EXAMPLE.com")).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<IndexError>(), Some(IndexError::UniqueConstraintViolation { .. })));
+
+        let by_email = Query::with_filter(Filter::eq("email", "ANN@example.com")).collation(case_insensitive);
+        let by_name = Query::with_filter(Filter::eq("name", "ann"));
+        for engine in [&engine, &HybridStorageEngine::new(CacheConfig::default(), engine.persistent_layer().clone())] {
+            let plan = engine.plan_query("users", &by_email).unwrap();
+            assert_eq!(plan.index_scan.map(|scan| scan.index), Some("email_ci".to_string()));
+            assert_eq!(engine.query("users", &by_email).unwrap().len(), 1);
+
+            let plan = engine.plan_query("users", &by_name).unwrap();
+            assert_eq!(plan.index_scan.map(|scan| scan.index), Some("name_hashed".to_string()));
+            let found = engine.query("users", &by_name).unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].get("email"), Some(&Value::from("Ann@Example.com")));
+        }
+
+        let unique_hashed = IndexOptions { unique: true, ..IndexOptions::hashed() };
+        assert!(engine.create_index("users", "bad", index_fields(&[("name", 1)]), unique_hashed).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_vector_search_stage() {
+        use crate::aggregation::{Pipeline, PipelineStage};
+        use crate::schema::VectorMetric;
+
+        let (engine, _temp_dir) = create_test_engine();
+        let item = |category: &str, embedding: [f64; 3]| {
+            let mut doc = Document::new();
+            doc.insert("category".to_string(), Value::from(category));
+            doc.insert(
+                "embedding".to_string(),
+                Value::Array(embedding.iter().map(|x| Value::Float64(*x)).collect()),
+            );
+            doc
+        };
+        let near = engine.insert_document("items", item("books", [1.0, 0.1, 0.0])).await.unwrap();
+        let far = engine.insert_document("items", item("books", [0.0, 0.0, 1.0])).await.unwrap();
+        let options = IndexOptions::vector(3, VectorMetric::Cosine);
+        engine.create_index("items", "embedding_cos", index_fields(&[("embedding", 1)]), options).unwrap();
+        let music = engine.insert_document("items", item("music", [1.0, 0.0, 0.0])).await.unwrap();
+        let moved = engine.insert_document("items", item("books", [0.0, 1.0, 0.0])).await.unwrap();
+        let gone = engine.insert_document("items", item("books", [1.0, 0.0, 0.01])).await.unwrap();
+        let mut updated = item("books", [1.0, 0.3, 0.0]);
+        updated.id = moved;
+        engine.update_document("items", moved, updated).await.unwrap();
+        engine.delete_document("items", gone).await.unwrap();
+
+        let search: PipelineStage = serde_json::from_value(serde_json::json!({
+            "$": "vectorSearch",
+            "path": "embedding",
+            "queryVector": [1.0, 0.0, 0.0],
+            "k": 2,
+            "numCandidates": 10,
+            "filter": {"category": "books"}
+        }))
+        .unwrap();
+        let pipeline = Pipeline::new(vec![search.clone(), PipelineStage::Limit { count: 5 }]);
+        for engine in [&engine, &HybridStorageEngine::new(CacheConfig::default(), engine.persistent_layer().clone())] {
+            let found = engine.aggregate("items", &pipeline).unwrap();
+            // The music item is nearest but filtered out during the search
+            assert_eq!(found.iter().map(|d| d.id).collect::<Vec<_>>(), vec![near, moved]);
+            let Some(Value::Float64(score)) = found[0].get("score") else {
+                panic!("missing score in {:?}", found[0]);
+            };
+            assert!(*score > 0.99 && *score <= 1.0);
+
+            let explain = engine.explain_aggregate("items", &pipeline, ExplainVerbosity::ExecutionStats).unwrap();
+            let Value::Object(explain) = explain else { panic!("explain is not an object") };
+            assert_eq!(explain.get("strategy"), Some(&Value::from("vector_search")));
+            assert_eq!(explain.get("index"), Some(&Value::from("embedding_cos")));
+        }
+
+        let PipelineStage::VectorSearch(spec) = search else {
+            panic!("expected a vector search stage");
+        };
+        let unfiltered = Pipeline::new(vec![PipelineStage::VectorSearch(crate::aggregation::VectorSearch {
+            filter: None,
+            k: 4,
+            ..spec
+        })]);
+        let found = engine.aggregate("items", &unfiltered).unwrap();
+        assert_eq!(found.iter().map(|d| d.id).collect::<Vec<_>>(), vec![music, near, moved, far]);
+
+        // Without a vector index on the path there is nothing to search
+        let mut missing = Pipeline::new(unfiltered.stages.clone());
+        if let PipelineStage::VectorSearch(search) = &mut missing.stages[0] {
+            search.path = "category".to_string();
+        }
+        assert!(engine.aggregate("items", &missing).is_err());
+        let view = ViewDefinition::plain("nearby".to_string(), "items".to_string(), unfiltered.stages);
+        assert!(view.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ttl_index_reaps_expired_documents() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine
+            .create_index("sessions", "seen", index_fields(&[("seen_at", 1)]), IndexOptions::default().expire_after(3600))
+            .unwrap();
+
+        let now = chrono::Utc::now();
+        let session = |seen_at: Value| {
+            let mut doc = Document::new();
+            doc.insert("seen_at".to_string(), seen_at);
+            doc
+        };
+        let stale = session(Value::DateTime(now - chrono::Duration::hours(2)));
+        let fresh = session(Value::DateTime(now));
+        let undated = session(Value::Null);
+        let stale_id = stale.id;
+        for doc in [stale, fresh, undated] {
+            engine.insert_document("sessions", doc).await.unwrap();
+        }
+
+        assert_eq!(engine.reap_expired().await.unwrap(), 1);
+        assert!(engine.get_document("sessions", stale_id).await.unwrap().is_none());
+        assert_eq!(engine.scan_collection("sessions").unwrap().len(), 2);
+        // The delete went through the index and persisted its entry removal
+        let state = engine.persistent_layer().index_state("sessions", "seen").unwrap().unwrap();
+        assert_eq!(state.entries, 2);
+        assert_eq!(engine.reap_expired().await.unwrap(), 0);
+
+        let compound = IndexOptions::default().expire_after(60);
+        assert!(engine
+            .create_index("sessions", "pair", index_fields(&[("a", 1), ("b", 1)]), compound)
+            .is_err());
     }
 
-    /// Get cache invalidations
-    pub fn cache_invalidations(&self) -> u64 {
-        self.cache_invalidations.load(Ordering::Relaxed)
+    #[tokio::test]
+    async fn test_large_collection_scans_for_common_values() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.insert_document("orders", order("open", 10)).await.unwrap();
+        insert_closed_orders(&engine, 150).await;
+        engine
+            .create_index("orders", "status", index_fields(&[("status", 1)]), IndexOptions::default())
+            .unwrap();
+
+        let open = crate::query::Query::with_filter(crate::query::Filter::eq("status", "open"));
+        assert_eq!(engine.plan_query("orders", &open).unwrap().use_index, Some("status".to_string()));
+
+        // Reading nearly every document through the index costs more than a scan
+        let closed = crate::query::Query::with_filter(crate::query::Filter::eq("status", "closed"));
+        let plan = engine.plan_query("orders", &closed).unwrap();
+        assert_eq!(plan.use_index, None);
+        assert_eq!(plan.rejected.len(), 1);
+        assert_eq!(engine.query("orders", &closed).unwrap().len(), 150);
+    }
+
+    #[tokio::test]
+    async fn test_analyze_refreshes_index_statistics() {
+        let (engine, _temp_dir) = create_test_engine();
+        insert_closed_orders(&engine, 30).await;
+        engine
+            .create_index("orders", "status", index_fields(&[("status", 1)]), IndexOptions::default())
+            .unwrap();
+
+        // Statistics gathered at build time follow later writes
+        for total in 0..3 {
+            engine.insert_document("orders", order("open", total)).await.unwrap();
+        }
+        let open = crate::query::Query::with_filter(crate::query::Filter::eq("status", "open"));
+        let plan = engine.plan_query("orders", &open).unwrap();
+        assert_eq!(plan.use_index, Some("status".to_string()));
+        assert_eq!(plan.estimated_rows, Some(3.0));
+
+        let analyzed = engine.analyze("orders", None).unwrap();
+        assert_eq!(analyzed.len(), 1);
+        let (name, distribution) = &analyzed[0];
+        assert_eq!(name, "status");
+        assert_eq!(distribution.entries, 33);
+        assert_eq!(distribution.distinct_keys(), 2);
+        assert_eq!(distribution.modifications, 0);
+
+        assert!(engine.analyze("orders", Some("missing")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_explain_reports_plans_and_execution_stats() {
+        use crate::query::ExplainVerbosity;
+
+        let (engine, _temp_dir) = create_test_engine();
+        insert_closed_orders(&engine, 30).await;
+        for total in 0..3 {
+            engine.insert_document("orders", order("open", total)).await.unwrap();
+        }
+        engine
+            .create_index("orders", "status", index_fields(&[("status", 1)]), IndexOptions::default())
+            .unwrap();
+        let entry = |value: &Value, key: &str| match value {
+            Value::Object(map) => map.get(key).cloned().unwrap_or(Value::Null),
+            _ => Value::Null,
+        };
+
+        let open = crate::query::Query::with_filter(crate::query::Filter::eq("status", "open"));
+        let planned = engine.explain_query("orders", &open, ExplainVerbosity::QueryPlanner).unwrap();
+        assert_eq!(entry(&planned, "strategy"), Value::from("index_scan"));
+        assert_eq!(entry(&planned, "index"), Value::from("status"));
+        assert_eq!(entry(&planned, "execution_stats"), Value::Null);
+        let Value::Array(rejected) = entry(&planned, "rejected_plans") else {
+            panic!("rejected plans should be an array");
+        };
+        assert_eq!(entry(&rejected[0], "strategy"), Value::from("collection_scan"));
+
+        let executed = engine.explain_query("orders", &open, ExplainVerbosity::ExecutionStats).unwrap();
+        let stats = entry(&executed, "execution_stats");
+        assert_eq!(entry(&stats, "keys_examined"), Value::Int64(3));
+        assert_eq!(entry(&stats, "documents_examined"), Value::Int64(3));
+        assert_eq!(entry(&stats, "documents_returned"), Value::Int64(3));
+
+        let pipeline = crate::aggregation::Pipeline::new(vec![crate::aggregation::PipelineStage::Match {
+            filter: serde_json::json!({"status": "open"}),
+        }]);
+        let aggregated = engine
+            .explain_aggregate("orders", &pipeline, ExplainVerbosity::ExecutionStats)
+            .unwrap();
+        let Value::Array(stages) = entry(&entry(&aggregated, "execution_stats"), "stages") else {
+            panic!("stages should be an array");
+        };
+        let counts: Vec<_> = stages
+            .iter()
+            .map(|stage| (entry(stage, "stage"), entry(stage, "documents_out")))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (Value::from("collection_scan"), Value::Int64(33)),
+                (Value::from("match"), Value::Int64(3)),
+            ]
+        );
+
+        // Planning alone does not evaluate the filter; running it does
+        let invalid = crate::query::Query::with_filter(crate::query::Filter::regex("status", "("));
+        assert!(engine.explain_query("orders", &invalid, ExplainVerbosity::QueryPlanner).is_ok());
+        assert!(engine.explain_query("orders", &invalid, ExplainVerbosity::ExecutionStats).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plan_cache_follows_index_changes() {
+        let (engine, _temp_dir) = create_test_engine();
+        insert_closed_orders(&engine, 30).await;
+        engine.insert_document("orders", order("open", 5)).await.unwrap();
+        engine
+            .create_index("orders", "status", index_fields(&[("status", 1)]), IndexOptions::default())
+            .unwrap();
+
+        let status = |value: &str| crate::query::Query::with_filter(crate::query::Filter::eq("status", value));
+        assert_eq!(engine.query("orders", &status("open")).unwrap().len(), 1);
+        assert_eq!(engine.query("orders", &status("pending")).unwrap().len(), 0);
+
+        let cached = engine.list_plan_cache(Some("orders"));
+        assert_eq!(cached.len(), 1);
+        let (collection, entry) = &cached[0];
+        assert_eq!(collection, "orders");
+        assert_eq!(entry.shape.as_str(), "filter=eq(status)");
+        assert_eq!(entry.solution.indexes, vec!["status".to_string()]);
+        assert_eq!(entry.executions, 2);
+        assert!(engine.list_plan_cache(Some("customers")).is_empty());
+
+        // Dropping the index invalidates the cached plan
+        engine.drop_index("orders", "status").unwrap();
+        assert_eq!(engine.query("orders", &status("open")).unwrap().len(), 1);
+        let (_, entry) = &engine.list_plan_cache(None)[0];
+        assert!(entry.solution.indexes.is_empty());
+        assert_eq!(entry.executions, 1);
+
+        assert_eq!(engine.clear_plan_cache(Some("orders"), Some("filter=eq(total)")), 0);
+        assert_eq!(engine.clear_plan_cache(Some("orders"), Some("filter=eq(status)")), 1);
+        assert_eq!(engine.clear_plan_cache(None, None), 0);
     }
 
-    /// Get persistent reads
-    pub fn persistent_reads(&self) -> u64 {
-        self.persistent_reads.load(Ordering::Relaxed)
-    }
+    #[tokio::test]
+    async fn test_plan_cache_only_for_indexed_collections() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.insert_document("orders", order("open", 5)).await.unwrap();
+        engine.insert_document("customers", order("open", 5)).await.unwrap();
+        engine
+            .create_index("orders", "status", index_fields(&[("status", 1)]), IndexOptions::default())
+            .unwrap();
 
-    /// Get persistent writes
-    pub fn persistent_writes(&self) -> u64 {
-        self.persistent_writes.load(Ordering::Relaxed)
+        let open = crate::query::Query::with_filter(crate::query::Filter::eq("status", "open"));
+        for collection in ["orders", "customers", "missing"] {
+            engine.query(collection, &open).unwrap();
+        }
+        assert!(engine.plan_cache("customers").is_none());
+        assert!(engine.plan_cache("missing").is_none());
+        let cached: Vec<_> = engine.list_plan_cache(None).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(cached, vec!["orders".to_string()]);
+
+        engine.drop_collection("orders").unwrap();
+        assert!(engine.list_plan_cache(None).is_empty());
+        assert!(engine.plan_cache("orders").is_none());
     }
 
-    /// Calculate cache hit rate
-    pub fn cache_hit_rate(&self) -> f64 {
-        let hits = self.cache_hits();
-        let total = hits + self.cache_misses();
-        
-        if total == 0 {
-            0.0
-        } else {
-            hits as f64 / total as f64
+    #[tokio::test]
+    async fn test_plan_cache_follows_statistics_drift() {
+        let (engine, _temp_dir) = create_test_engine();
+        insert_closed_orders(&engine, 150).await;
+        engine
+            .create_index("orders", "status", index_fields(&[("status", 1)]), IndexOptions::default())
+            .unwrap();
+
+        let open = crate::query::Query::with_filter(crate::query::Filter::eq("status", "open"));
+        engine.query("orders", &open).unwrap();
+        engine.query("orders", &open).unwrap();
+        assert_eq!(engine.list_plan_cache(Some("orders"))[0].1.executions, 2);
+
+        // A few writes keep the plan; a tenth of the index changing drops it
+        for total in 0..5 {
+            engine.insert_document("orders", order("open", total)).await.unwrap();
+        }
+        engine.query("orders", &open).unwrap();
+        assert_eq!(engine.list_plan_cache(Some("orders"))[0].1.executions, 3);
+
+        for total in 0..15 {
+            engine.insert_document("orders", order("open", total)).await.unwrap();
         }
+        engine.query("orders", &open).unwrap();
+        assert_eq!(engine.list_plan_cache(Some("orders"))[0].1.executions, 1);
     }
 
-    /// Get total operations
-    pub fn total_operations(&self) -> u64 {
-        self.cache_hits() + self.cache_misses() + self.cache_writes()
-            + self.persistent_reads() + self.persistent_writes()
+    #[tokio::test]
+    async fn test_indexes_restore_from_metadata() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.insert_document("orders", order("open", 10)).await.unwrap();
+        engine
+            .create_index("orders", "status_total", index_fields(&[("status", 1), ("total", 1)]), IndexOptions::default())
+            .unwrap();
+
+        let restored = HybridStorageEngine::new(CacheConfig::default(), engine.persistent_layer().clone());
+        let plan = restored.plan_query("orders", &open_between(0, 50)).unwrap();
+        assert_eq!(plan.use_index, Some("status_total".to_string()));
+        assert_eq!(restored.query("orders", &open_between(0, 50)).unwrap().len(), 1);
+
+        restored.drop_index("orders", "status_total").unwrap();
+        assert!(restored.plan_query("orders", &open_between(0, 50)).unwrap().use_index.is_none());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::document::Value;
-    use crate::schema::{FieldDefinition, FieldType};
-    use tempfile::TempDir;
+    #[tokio::test]
+    async fn test_indexes_reload_persisted_entries_and_rebuild_on_mismatch() {
+        let (engine, _temp_dir) = create_test_engine();
+        engine.insert_document("orders", order("open", 10)).await.unwrap();
+        insert_closed_orders(&engine, 20).await;
+        engine
+            .create_index("orders", "status_total", index_fields(&[("status", 1), ("total", 1)]), IndexOptions::default())
+            .unwrap();
+        engine.insert_document("orders", order("open", 30)).await.unwrap();
 
-    fn create_test_engine() -> (Arc<HybridStorageEngine>, TempDir) {
-        let temp_dir = TempDir::new().unwrap();
-        let persistent = Arc::new(PersistentLayer::new(temp_dir.path()).unwrap());
-        let cache_config = CacheConfig::default();
-        
-        let engine = Arc::new(HybridStorageEngine::new(cache_config, persistent));
-        (engine, temp_dir)
+        let persistent = engine.persistent_layer().clone();
+        let state = persistent.index_state("orders", "status_total").unwrap().unwrap();
+        assert_eq!(state.entries, 22);
+        assert!(persistent.load_index("orders", "status_total").unwrap().is_some());
+
+        let restored = HybridStorageEngine::new(CacheConfig::default(), persistent.clone());
+        assert_eq!(restored.query("orders", &open_between(0, 50)).unwrap().len(), 2);
+        assert_eq!(persistent.index_state("orders", "status_total").unwrap(), Some(state));
+
+        // A state that no longer matches the entries forces a rebuild
+        let corrupt = crate::storage::persistent::IndexState { checksum: state.checksum ^ 1, ..state };
+        persistent
+            .store_metadata("index_state:orders:status_total", &serde_json::to_vec(&corrupt).unwrap())
+            .unwrap();
+        let rebuilt = HybridStorageEngine::new(CacheConfig::default(), persistent.clone());
+        assert_eq!(rebuilt.query("orders", &open_between(0, 50)).unwrap().len(), 2);
+        assert_eq!(persistent.index_state("orders", "status_total").unwrap(), Some(state));
     }
 
-    fn create_test_schema(strategy: CacheStrategy) -> Schema {
-        let mut schema = Schema::new();
-        schema.add_field(
-            "name".to_string(),
-            FieldDefinition::new(FieldType::String { max_length: None }),
-        );
-        schema.cache_config.strategy = strategy;
-        schema
+    /// Poll a background build until it leaves the running state
+    async fn wait_for_build(engine: &HybridStorageEngine, id: &str) -> IndexBuildProgress {
+        for _ in 0..200 {
+            let progress = engine.get_index_build(id).unwrap();
+            if progress.state != IndexBuildState::Running {
+                return progress;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("index build {} did not finish", id);
     }
 
     #[tokio::test]
-    async fn test_hybrid_engine_creation() {
+    async fn test_index_build_applies_side_writes() {
         let (engine, _temp_dir) = create_test_engine();
-        assert_eq!(engine.stats().total_operations(), 0);
+        let first = engine.insert_document("orders", order("open", 10)).await.unwrap();
+        insert_closed_orders(&engine, 20).await;
+
+        let build = engine
+            .begin_index_build("orders", "status_total", index_fields(&[("status", 1), ("total", 1)]), IndexOptions::default())
+            .unwrap();
+        build.claim();
+        assert_eq!(engine.query("orders", &open_between(0, 50)).unwrap().len(), 1);
+
+        // Writes while the index is building reach it through the side-write buffer
+        engine.insert_document("orders", order("open", 30)).await.unwrap();
+        engine.delete_document("orders", first).await.unwrap();
+
+        let outcome = engine.build_index_scan(&build, |index, next_batch| {
+            engine.index_builder.run_blocking(index, &build, next_batch)
+        });
+        engine.finish_index_build(&build, outcome).unwrap();
+
+        let progress = build.progress();
+        assert_eq!(progress.state, IndexBuildState::Completed);
+        assert_eq!(progress.side_writes_applied, 2);
+        assert_eq!(progress.percent_done(), 100.0);
+        assert!(engine.index_build_eta(&progress).is_none());
+
+        let matches = engine.query("orders", &open_between(0, 50)).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].get("total"), Some(&Value::Int64(30)));
+        let state = engine.persistent_layer().index_state("orders", "status_total").unwrap().unwrap();
+        assert!(!state.building);
+        assert_eq!(state.entries, 21);
     }
 
     #[tokio::test]
-    async fn test_insert_with_no_cache_strategy() {
+    async fn test_index_build_resumes_from_checkpoint() {
         let (engine, _temp_dir) = create_test_engine();
-        
-        let schema = create_test_schema(CacheStrategy::None);
-        engine.register_schema("users".to_string(), schema);
-        
-        let mut doc = Document::new();
-        doc.insert("name".to_string(), Value::String("John".to_string()));
-        
-        let doc_id = engine.insert_document("users", doc).await.unwrap();
-        
-        // Should only write to persistent
-        assert_eq!(engine.stats().persistent_writes(), 1);
-        assert_eq!(engine.stats().cache_writes(), 0);
-        
-        // Retrieve document
-        let retrieved = engine.get_document("users", doc_id).await.unwrap();
-        assert!(retrieved.is_some());
+        insert_closed_orders(&engine, 11).await;
+        engine.insert_document("orders", order("open", 10)).await.unwrap();
+
+        // Stop after one batch, as if the server had crashed mid-build
+        let persistent = engine.persistent_layer().clone();
+        assert!(persistent
+            .begin_index_build("orders", "status_total", index_fields(&[("status", 1), ("total", 1)]), &IndexOptions::default())
+            .unwrap());
+        assert_eq!(persistent.index_build_batch("orders", "status_total", 5).unwrap().len(), 5);
+
+        let restarted = Arc::new(HybridStorageEngine::new(CacheConfig::default(), persistent.clone()));
+        let indexes = restarted.get_index_manager("orders");
+        assert!(!indexes.has_index("status_total"));
+        assert_eq!(indexes.building_index("status_total").unwrap().entry_count(), 5);
+
+        let builds = restarted.list_index_builds(Some("orders"));
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].state, IndexBuildState::Running);
+        assert_eq!(builds[0].documents_processed, 5);
+        assert_eq!(builds[0].documents_total, 12);
+        assert!(restarted.index_build_eta(&builds[0]).is_some());
+
+        let resumed = restarted.resume_index_builds().unwrap();
+        assert_eq!(resumed, vec![builds[0].id.clone()]);
+        assert!(restarted.resume_index_builds().unwrap().is_empty());
+
+        let progress = wait_for_build(&restarted, &resumed[0]).await;
+        assert_eq!(progress.state, IndexBuildState::Completed);
+        assert_eq!(progress.documents_processed, 12);
+        assert_eq!(restarted.query("orders", &open_between(0, 50)).unwrap().len(), 1);
+        assert_eq!(persistent.index_state("orders", "status_total").unwrap().unwrap().entries, 12);
     }
 
     #[tokio::test]
-    async fn test_insert_with_write_through() {
+    async fn test_index_build_cancel_drops_index() {
         let (engine, _temp_dir) = create_test_engine();
-        
-        let schema = create_test_schema(CacheStrategy::WriteThrough);
-        engine.register_schema("users".to_string(), schema);
-        
-        let mut doc = Document::new();
-        doc.insert("name".to_string(), Value::String("John".to_string()));
-        let doc_id = doc.id;
-        
-        engine.insert_document("users", doc).await.unwrap();
-        
-        // Should write to both cache and persistent
-        assert_eq!(engine.stats().persistent_writes(), 1);
-        assert_eq!(engine.stats().cache_writes(), 1);
-        
-        // Retrieve should hit cache
-        let retrieved = engine.get_document("users", doc_id).await.unwrap();
-        assert!(retrieved.is_some());
-        assert_eq!(engine.stats().cache_hits(), 1);
+        insert_closed_orders(&engine, 5).await;
+
+        let id = engine
+            .start_index_build("orders", "by_total", index_fields(&[("total", 1)]), IndexOptions::default())
+            .unwrap();
+        assert!(engine.start_index_build("orders", "by_total", index_fields(&[("total", 1)]), IndexOptions::default()).is_err());
+        assert_eq!(engine.cancel_index_build(&id).unwrap().state, IndexBuildState::Running);
+
+        let progress = wait_for_build(&engine, &id).await;
+        assert_eq!(progress.state, IndexBuildState::Cancelled);
+        assert!(engine.cancel_index_build(&id).is_err());
+        assert!(engine.cancel_index_build("missing").is_err());
+        assert!(engine.list_indexes("orders").unwrap().is_empty());
+        assert!(engine.persistent_layer().index_state("orders", "by_total").unwrap().is_none());
+        assert!(engine.get_index_manager("orders").building_index("by_total").is_none());
     }
 
     #[tokio::test]
-    async fn test_insert_with_write_behind() {
+    async fn test_background_index_build_reports_failure() {
         let (engine, _temp_dir) = create_test_engine();
-        
-        let schema = create_test_schema(CacheStrategy::WriteBehind { delay_ms: 100 });
-        engine.register_schema("users".to_string(), schema);
-        
-        let mut doc = Document::new();
-        doc.insert("name".to_string(), Value::String("John".to_string()));
-        let doc_id = doc.id;
-        
-        engine.insert_document("users", doc).await.unwrap();
-        
-        // Should write to cache immediately
-        assert_eq!(engine.stats().cache_writes(), 1);
-        assert_eq!(engine.stats().persistent_writes(), 0);
-        
-        // Wait for write-behind to process
-        tokio::time::sleep(Duration::from_millis(200)).await;
-        
-        // Flush to ensure write-behind is processed
-        engine.flush().await.unwrap();
-        
-        // Now persistent write should have happened
-        assert!(engine.stats().persistent_writes() >= 1);
+        insert_closed_orders(&engine, 3).await;
+        engine.insert_document("orders", order("open", 1)).await.unwrap();
+
+        let id = engine
+            .start_index_build("orders", "by_total", index_fields(&[("total", 1)]), IndexOptions::unique())
+            .unwrap();
+        let progress = wait_for_build(&engine, &id).await;
+        assert_eq!(progress.state, IndexBuildState::Failed);
+        assert!(progress.error.is_some());
+        assert!(engine.list_indexes("orders").unwrap().is_empty());
+
+        let id = engine
+            .start_index_build("orders", "by_status", index_fields(&[("status", 1)]), IndexOptions::default())
+            .unwrap();
+        assert_eq!(wait_for_build(&engine, &id).await.state, IndexBuildState::Completed);
+        assert_eq!(engine.list_index_builds(Some("orders")).len(), 2);
+        assert!(engine.list_index_builds(Some("users")).is_empty());
     }
 
     #[tokio::test]
-    async fn test_get_with_cache_miss() {
+    async fn test_create_index_accepts_only_the_same_definition_again() {
         let (engine, _temp_dir) = create_test_engine();
-        
-        let schema = create_test_schema(CacheStrategy::WriteThrough);
-        engine.register_schema("users".to_string(), schema);
-        
-        // Insert directly to persistent (bypassing cache)
-        let mut doc = Document::new();
-        let doc_id = doc.id;
-        doc.insert("name".to_string(), Value::String("John".to_string()));
-        engine.persistent_layer().insert_document("users", doc_id, &doc).unwrap();
-        
-        // Get should miss cache, then populate it
-        let retrieved = engine.get_document("users", doc_id).await.unwrap();
-        assert!(retrieved.is_some());
-        assert_eq!(engine.stats().cache_misses(), 1);
-        assert_eq!(engine.stats().persistent_reads(), 1);
-        
-        // Second get should hit cache
-        let retrieved2 = engine.get_document("users", doc_id).await.unwrap();
-        assert!(retrieved2.is_some());
-        assert_eq!(engine.stats().cache_hits(), 1);
+        insert_closed_orders(&engine, 3).await;
+
+        let fields = || index_fields(&[("status", 1), ("total", -1)]);
+        engine.create_index("orders", "status_total", fields(), IndexOptions::default()).unwrap();
+        engine.create_index("orders", "status_total", fields(), IndexOptions::default()).unwrap();
+
+        let reordered = index_fields(&[("status", 1), ("total", 1)]);
+        let err = engine
+            .create_index("orders", "status_total", reordered, IndexOptions::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("different definition"), "{}", err);
+        assert!(engine.create_index("orders", "status_total", fields(), IndexOptions::unique()).is_err());
+        assert_eq!(engine.list_indexes("orders").unwrap().len(), 1);
     }
 
     #[tokio::test]
-    async fn test_update_document() {
+    async fn test_finished_index_builds_are_pruned() {
         let (engine, _temp_dir) = create_test_engine();
-        
-        let schema = create_test_schema(CacheStrategy::WriteThrough);
-        engine.register_schema("users".to_string(), schema);
-        
+        insert_closed_orders(&engine, 1).await;
+
+        for n in 0..MAX_FINISHED_INDEX_BUILDS + 5 {
+            let name = format!("by_total_{}", n);
+            engine.create_index("orders", &name, index_fields(&[("total", 1)]), IndexOptions::default()).unwrap();
+            engine.drop_index("orders", &name).unwrap();
+        }
+
+        let builds = engine.list_index_builds(Some("orders"));
+        assert_eq!(builds.len(), MAX_FINISHED_INDEX_BUILDS);
+        // The oldest builds are the ones forgotten
+        assert!(builds.iter().all(|build| build.index != "by_total_0"));
+        let last = format!("by_total_{}", MAX_FINISHED_INDEX_BUILDS + 4);
+        assert!(builds.iter().any(|build| build.index == last));
+    }
+
+    fn entry(n: i32) -> Document {
         let mut doc = Document::new();
-        let doc_id = doc.id;
-        doc.insert("name".to_string(), Value::String("John".to_string()));
-        
-        engine.insert_document("users", doc.clone()).await.unwrap();
-        
-        // Update
-        doc.insert("name".to_string(), Value::String("Jane".to_string()));
-        engine.update_document("users", doc_id, doc).await.unwrap();
-        
-        // Retrieve updated document
-        let retrieved = engine.get_document("users", doc_id).await.unwrap().unwrap();
-        assert_eq!(retrieved.get("name").unwrap().as_str(), Some("Jane"));
+        doc.insert("n".to_string(), Value::Int32(n));
+        doc
+    }
+
+    fn numbers(docs: &[Document]) -> Vec<i32> {
+        docs.iter().map(|doc| doc.get("n").and_then(Value::as_i64).unwrap() as i32).collect()
     }
 
     #[tokio::test]
-    async fn test_delete_document() {
+    async fn test_capped_collection_evicts_oldest_first() {
         let (engine, _temp_dir) = create_test_engine();
-        
-        let schema = create_test_schema(CacheStrategy::WriteThrough);
-        engine.register_schema("users".to_string(), schema);
-        
-        let mut doc = Document::new();
-        let doc_id = doc.id;
-        doc.insert("name".to_string(), Value::String("John".to_string()));
-        
-        engine.insert_document("users", doc).await.unwrap();
-        
-        // Delete
-        let deleted = engine.delete_document("users", doc_id).await.unwrap();
-        assert!(deleted);
-        
-        // Should not exist
-        let retrieved = engine.get_document("users", doc_id).await.unwrap();
-        assert!(retrieved.is_none());
+        let options = CappedOptions { max_bytes: Some(1024), max_documents: Some(3) };
+        engine.create_capped_collection("activity", options, None).unwrap();
+
+        let mut ids = Vec::new();
+        for n in 0..5 {
+            let mut doc = entry(n);
+            // Caller-chosen ids are replaced by ordered ones
+            doc.id = DocumentId::new();
+            ids.push(engine.insert_document("activity", doc).await.unwrap());
+        }
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(numbers(&engine.scan_collection("activity").unwrap()), [2, 3, 4]);
+        assert!(engine.get_document("activity", ids[0]).await.unwrap().is_none());
+
+        let mut large = entry(9);
+        large.insert("blob".to_string(), Value::Binary(vec![0; 2048]));
+        let err = engine.insert_document("activity", large).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<CappedError>(), Some(CappedError::DocumentTooLarge { .. })));
+
+        // Deletes free their slot
+        assert!(engine.delete_document("activity", ids[3]).await.unwrap());
+        engine.insert_document("activity", entry(5)).await.unwrap();
+        assert_eq!(numbers(&engine.scan_collection("activity").unwrap()), [2, 4, 5]);
+
+        // The order and the caps survive a restart
+        let restarted = HybridStorageEngine::new(CacheConfig::default(), engine.persistent_layer().clone());
+        assert_eq!(restarted.restore_capped_collections().unwrap(), 1);
+        assert_eq!(restarted.capped_options("activity"), Some(options));
+        restarted.insert_document("activity", entry(6)).await.unwrap();
+        assert_eq!(numbers(&restarted.scan_collection("activity").unwrap()), [4, 5, 6]);
+
+        assert!(restarted.create_capped_collection("activity", options, None).is_err());
+        let err = restarted.create_capped_collection("other", CappedOptions::default(), None).unwrap_err();
+        assert_eq!(err.downcast_ref::<CappedError>(), Some(&CappedError::InvalidOptions));
+
+        restarted.drop_collection("activity").unwrap();
+        assert_eq!(restarted.capped_options("activity"), None);
+        assert_eq!(restarted.restore_capped_collections().unwrap(), 0);
     }
 
     #[tokio::test]
-    async fn test_cache_invalidation() {
+    async fn test_tail_waits_for_inserts() {
         let (engine, _temp_dir) = create_test_engine();
-        
-        let schema = create_test_schema(CacheStrategy::ReadThrough);
-        engine.register_schema("users".to_string(), schema);
-        
-        let mut doc = Document::new();
-        let doc_id = doc.id;
-        doc.insert("name".to_string(), Value::String("John".to_string()));
-        
-        // Insert with ReadThrough invalidates cache
-        engine.insert_document("users", doc).await.unwrap();
-        
-        assert_eq!(engine.stats().cache_invalidations(), 1);
+        let options = CappedOptions { max_bytes: None, max_documents: Some(100) };
+        engine.create_capped_collection("queue", options, None).unwrap();
+        engine.insert_document("queue", entry(1)).await.unwrap();
+        let last = engine.insert_document("queue", entry(2)).await.unwrap();
+
+        assert_eq!(numbers(&engine.tail("queue", None, 10).await.unwrap()), [1, 2]);
+        assert_eq!(numbers(&engine.tail("queue", None, 1).await.unwrap()), [1]);
+        assert!(engine.tail_wait("queue", Some(last), 10, Duration::from_millis(20)).await.unwrap().is_empty());
+
+        let waiter = {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.tail_wait("queue", Some(last), 10, Duration::from_secs(10)).await })
+        };
+        sleep(Duration::from_millis(20)).await;
+        engine.insert_document("queue", entry(3)).await.unwrap();
+        let batch = tokio::time::timeout(Duration::from_secs(10), waiter).await.unwrap().unwrap().unwrap();
+        assert_eq!(numbers(&batch), [3]);
+
+        // Dropping the collection ends waiting cursors
+        let waiter = {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.tail_wait("queue", Some(batch[0].id), 10, Duration::from_secs(10)).await })
+        };
+        sleep(Duration::from_millis(20)).await;
+        engine.drop_collection("queue").unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(10), waiter).await.unwrap().unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref::<CappedError>(), Some(CappedError::NotCapped(_))));
+
+        engine.create_collection("plain").unwrap();
+        assert!(engine.tail("plain", None, 10).await.is_err());
+    }
+
+    fn history_query(low: i32) -> crate::query::Query {
+        crate::query::Query::with_filter(crate::query::Filter::gte("n", low)).sort(crate::query::Sort::new().asc("n"))
     }
 
     #[tokio::test]
-    async fn test_statistics() {
+    async fn test_history_reads_past_states() {
         let (engine, _temp_dir) = create_test_engine();
-        
-        let schema = create_test_schema(CacheStrategy::WriteThrough);
-        engine.register_schema("users".to_string(), schema);
-        
-        let mut doc = Document::new();
-        let doc_id = doc.id;
-        doc.insert("name".to_string(), Value::String("John".to_string()));
-        
-        engine.insert_document("users", doc).await.unwrap();
-        engine.get_document("users", doc_id).await.unwrap();
-        
-        let stats = engine.stats();
-        assert!(stats.total_operations() > 0);
-        assert!(stats.cache_hit_rate() >= 0.0 && stats.cache_hit_rate() <= 1.0);
+        engine.create_collection("accounts").unwrap();
+        let untouched = entry(1);
+        engine.insert_document("accounts", untouched.clone()).await.unwrap();
+        let mut changed = entry(2);
+        engine.insert_document("accounts", changed.clone()).await.unwrap();
+        engine.enable_history("accounts", HistoryOptions::default()).await.unwrap();
+        let enabled = chrono::Utc::now();
+
+        sleep(Duration::from_millis(5)).await;
+        changed.insert("n".to_string(), Value::Int32(20));
+        engine.update_document("accounts", changed.id, changed.clone()).await.unwrap();
+        let added = entry(3);
+        engine.insert_document("accounts", added.clone()).await.unwrap();
+        sleep(Duration::from_millis(5)).await;
+        let before_delete = chrono::Utc::now();
+        sleep(Duration::from_millis(5)).await;
+        assert!(engine.delete_document("accounts", added.id).await.unwrap());
+
+        let at_enable = engine.query_history("accounts", &history_query(0), HistoryPoint::AsOf(enabled)).await.unwrap();
+        assert_eq!(numbers(&at_enable), [1, 2]);
+        assert!(at_enable.iter().all(|doc| doc.metadata.version == 1));
+
+        let before = engine.query_history("accounts", &history_query(0), HistoryPoint::AsOf(before_delete)).await.unwrap();
+        assert_eq!(numbers(&before), [1, 3, 20]);
+        assert_eq!(before[2].metadata.version, 2);
+        assert_eq!(numbers(&engine.query_history("accounts", &history_query(3), HistoryPoint::AsOf(chrono::Utc::now())).await.unwrap()), [20]);
+        assert_eq!(numbers(&engine.query_history("accounts", &history_query(0), HistoryPoint::Version(2)).await.unwrap()), [20]);
+        assert_eq!(numbers(&engine.query_history("accounts", &history_query(0), HistoryPoint::Version(1)).await.unwrap()), [1, 2, 3]);
+
+        let versions = engine.document_history("accounts", added.id).await.unwrap();
+        assert_eq!(versions.iter().map(|v| (v.version, v.document.is_some())).collect::<Vec<_>>(), [(1, true), (2, false)]);
+        assert!(engine.document_history("accounts", untouched.id).await.unwrap().is_empty());
+
+        // Nothing before history was enabled can be read
+        let early = enabled - chrono::Duration::seconds(60);
+        let err = engine.query_history("accounts", &history_query(0), HistoryPoint::AsOf(early)).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<HistoryError>(), Some(HistoryError::BeforeHistory { .. })));
+        engine.create_collection("plain").unwrap();
+        let err = engine.query_history("plain", &history_query(0), HistoryPoint::Version(1)).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<HistoryError>(), Some(&HistoryError::NotEnabled("plain".to_string())));
+        assert!(engine.enable_history("missing", HistoryOptions::default()).await.is_err());
+
+        // Count retention drops version 1, which version queries then report
+        let options = HistoryOptions { max_age_secs: None, max_versions: Some(1) };
+        engine.enable_history("accounts", options).await.unwrap();
+        assert_eq!(engine.history_options("accounts").unwrap(), Some(options));
+        changed.insert("n".to_string(), Value::Int32(200));
+        engine.update_document("accounts", changed.id, changed.clone()).await.unwrap();
+        let err = engine.query_history("accounts", &history_query(0), HistoryPoint::Version(1)).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<HistoryError>(), Some(HistoryError::Pruned { id, .. }) if *id == changed.id));
+        assert_eq!(numbers(&engine.query_history("accounts", &history_query(0), HistoryPoint::Version(3)).await.unwrap()), [200]);
     }
 }
 
@@ -981,7 +4471,21 @@ impl crate::encryption::EncryptedStorage for HybridStorageEngine {
     
     fn update_encrypted_document(&self, collection: &str, doc_id: crate::document::DocumentId, new_encrypted_data: Vec<u8>) -> anyhow::Result<()> {
         let doc: crate::document::Document = serde_json::from_slice(&new_encrypted_data)?;
-        self.persistent_layer.update_document(collection, doc_id, &doc)?;
+
+        // Keep the collection's indexes, and their unique constraints, in step
+        let indexes = self.index_manager(collection)?;
+        let previous = self.persistent_layer.get_document(collection, doc_id)?;
+        match &previous {
+            Some(old) => indexes.update_document(doc_id, old, &doc)?,
+            None => indexes.insert_document(doc_id, &doc)?,
+        }
+        if let Err(e) = self.persistent_layer.update_document(collection, doc_id, &doc) {
+            match &previous {
+                Some(old) => indexes.update_document(doc_id, &doc, old)?,
+                None => indexes.remove_document(doc_id, &doc)?,
+            }
+            return Err(e);
+        }
         Ok(())
     }
     
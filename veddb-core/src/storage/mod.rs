@@ -4,9 +4,19 @@
 //! and the hybrid storage engine coordinating cache and persistent layers
 
 pub mod persistent;
+pub mod capped;
 pub mod collection;
+pub mod files;
+pub mod history;
 pub mod hybrid;
+pub mod references;
+pub mod views;
 
 pub use persistent::*;
+pub use capped::{CappedError, CappedOptions};
 pub use collection::*;
+pub use files::{FileError, FileInfo, FileStore, NewFile};
+pub use history::{DocumentVersion, HistoryError, HistoryOptions, HistoryPoint};
 pub use hybrid::*;
+pub use references::{DeleteOutcome, ReferenceError};
+pub use views::*;
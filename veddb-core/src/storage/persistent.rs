@@ -3,20 +3,48 @@
 //! Note: RocksDB requires LLVM/Clang to be installed on Windows.
 //! Install from https://releases.llvm.org/ and set LIBCLANG_PATH environment variable.
 //! Enable with the "rocksdb-storage" feature flag.
+//!
+//! Secondary index entries live in the "indexes" column family, keyed by
+//! collection, index name and the order-preserving encoding from
+//! [`crate::index::encoding`]. They are written in the same batch as the
+//! document they belong to, alongside a per-index state record holding
+//! the entry count and checksum used to validate them on reload.
+//!
+//! Collections with history enabled also get a version record per write
+//! in the "history" column family, keyed by collection, document id and
+//! zero-padded version number; see [`crate::storage::history`].
 
 use crate::document::{Document, DocumentId};
+use crate::index::btree::{DuplicateKey, IndexEntry, IndexError, IndexValue, KeyForm};
+use crate::index::encoding::{self, INDEX_FORMAT_VERSION};
+use crate::index::vector::VectorSpec;
+use crate::protocol::IndexOptions;
+use crate::query::{Filter, QueryExecutor};
+use crate::storage::history::{
+    self, DocumentVersion, HistoryConfig, HistoryError, HistoryOptions, HistoryPoint, HistorySnapshot, VersionDirectory,
+    VersionLookup, VersionStamp,
+};
 use anyhow::{Context, Result};
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[cfg(feature = "rocksdb-storage")]
-use rocksdb::{ColumnFamilyDescriptor, Options, DB};
+use rocksdb::{ColumnFamilyDescriptor, Options, WriteBatch, DB};
 
 #[cfg(not(feature = "rocksdb-storage"))]
-use std::collections::HashMap;
-#[cfg(not(feature = "rocksdb-storage"))]
-use parking_lot::RwLock;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A persisted index entry: the document and its indexed field values
+pub type IndexEntryValues = (DocumentId, Vec<IndexValue>);
+
+/// An index's raw state record, if any, and its entry keys
+type PersistedIndex = (Option<Vec<u8>>, Vec<Vec<u8>>);
+
+/// Number of index entries written per batch while building an index
+const INDEX_BUILD_BATCH: usize = 1024;
 
 /// Persistent storage layer backed by RocksDB (or in-memory fallback)
 pub struct PersistentLayer {
@@ -25,12 +53,33 @@ pub struct PersistentLayer {
     db: Arc<DB>,
     
     #[cfg(not(feature = "rocksdb-storage"))]
-    /// In-memory storage (fallback when RocksDB is not available)
-    documents: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+    /// In-memory storage (fallback when RocksDB is not available), kept
+    /// in key order like RocksDB so scans can resume after a key
+    documents: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
     
     #[cfg(not(feature = "rocksdb-storage"))]
     /// In-memory metadata storage
     metadata: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+
+    #[cfg(not(feature = "rocksdb-storage"))]
+    /// In-memory index entry storage, kept in key order
+    index_entries: Arc<RwLock<BTreeSet<Vec<u8>>>>,
+
+    #[cfg(not(feature = "rocksdb-storage"))]
+    /// In-memory document version storage, kept in key order
+    history: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+
+    /// Index definitions and states per collection, loaded on first write
+    index_catalog: Arc<RwLock<HashMap<String, Vec<IndexSpec>>>>,
+
+    /// History settings per collection, `None` without history, loaded on first write
+    history_catalog: Arc<RwLock<HashMap<String, Option<HistoryConfig>>>>,
+
+    /// Version stamps per collection keeping history, loaded on first history read
+    version_directories: Arc<RwLock<HashMap<String, VersionDirectory>>>,
+
+    /// Serializes writes that read a document before replacing its index entries
+    write_lock: Arc<Mutex<()>>,
     
     /// Data directory path
     data_dir: PathBuf,
@@ -62,6 +111,7 @@ impl PersistentLayer {
                 ColumnFamilyDescriptor::new("documents", Options::default()),
                 ColumnFamilyDescriptor::new("metadata", Options::default()),
                 ColumnFamilyDescriptor::new("indexes", Options::default()),
+                ColumnFamilyDescriptor::new("history", Options::default()),
             ];
 
             // Open database
@@ -70,6 +120,10 @@ impl PersistentLayer {
 
             Ok(Self {
                 db: Arc::new(db),
+                index_catalog: Arc::new(RwLock::new(HashMap::new())),
+                history_catalog: Arc::new(RwLock::new(HashMap::new())),
+                version_directories: Arc::new(RwLock::new(HashMap::new())),
+                write_lock: Arc::new(Mutex::new(())),
                 data_dir,
             })
         }
@@ -78,8 +132,14 @@ impl PersistentLayer {
         {
             // Use in-memory storage as fallback
             Ok(Self {
-                documents: Arc::new(RwLock::new(HashMap::new())),
+                documents: Arc::new(RwLock::new(BTreeMap::new())),
                 metadata: Arc::new(RwLock::new(HashMap::new())),
+                index_entries: Arc::new(RwLock::new(BTreeSet::new())),
+                history: Arc::new(RwLock::new(BTreeMap::new())),
+                index_catalog: Arc::new(RwLock::new(HashMap::new())),
+                history_catalog: Arc::new(RwLock::new(HashMap::new())),
+                version_directories: Arc::new(RwLock::new(HashMap::new())),
+                write_lock: Arc::new(Mutex::new(())),
                 data_dir,
             })
         }
@@ -97,23 +157,7 @@ impl PersistentLayer {
         doc_id: DocumentId,
         doc: &Document,
     ) -> Result<()> {
-        let key = Self::make_document_key(collection, doc_id);
-        let value = serde_json::to_vec(doc)
-            .context("Failed to serialize document")?;
-
-        #[cfg(feature = "rocksdb-storage")]
-        {
-            let cf = self.db.cf_handle("documents")
-                .context("Documents column family not found")?;
-            self.db.put_cf(cf, key, value)
-                .context("Failed to insert document")?;
-        }
-
-        #[cfg(not(feature = "rocksdb-storage"))]
-        {
-            self.documents.write().insert(key, value);
-        }
-
+        self.write_document(collection, doc_id, Some(doc))?;
         Ok(())
     }
 
@@ -160,7 +204,7 @@ impl PersistentLayer {
         doc_id: DocumentId,
         doc: &Document,
     ) -> Result<()> {
-        // For now, update is the same as insert (overwrite)
+        // Writing replaces the previous version and its index entries
         self.insert_document(collection, doc_id, doc)
     }
 
@@ -169,29 +213,281 @@ impl PersistentLayer {
         &self,
         collection: &str,
         doc_id: DocumentId,
+    ) -> Result<bool> {
+        self.write_document(collection, doc_id, None)
+    }
+
+    /// Write or delete a document together with its index entries
+    ///
+    /// Returns whether the document existed before a delete.
+    fn write_document(&self, collection: &str, doc_id: DocumentId, doc: Option<&Document>) -> Result<bool> {
+        Ok(self.write_documents(&[(collection, doc_id, doc)])?[0])
+    }
+
+    /// Write or delete several documents, in any collections, atomically
+    ///
+    /// Each entry is a collection, a document id and the new version of the
+    /// document, or `None` to delete it. The documents, their old and new
+    /// index entries, the updated index states and, in collections keeping
+    /// history, the new versions go into one batch. Returns whether each
+    /// document existed before.
+    pub fn write_documents(&self, writes: &[(&str, DocumentId, Option<&Document>)]) -> Result<Vec<bool>> {
+        let _guard = self.write_lock.lock();
+        let mut catalogs: HashMap<String, Vec<IndexSpec>> = HashMap::new();
+        let mut ops = Vec::new();
+        let mut stamps = Vec::new();
+        let mut existed = Vec::with_capacity(writes.len());
+        for &(collection, doc_id, doc) in writes {
+            if !catalogs.contains_key(collection) {
+                catalogs.insert(collection.to_string(), self.index_specs(collection)?);
+            }
+            let specs = catalogs.get_mut(collection).context("Index catalog missing")?;
+            let written = self.document_ops(collection, doc_id, doc, specs, &mut ops)?;
+            if written || doc.is_some() {
+                if let Some(config) = self.history_config(collection)? {
+                    if let Some(retained) = self.history_ops(collection, doc_id, doc, &config, &mut ops)? {
+                        stamps.push((collection, doc_id, retained));
+                    }
+                }
+            }
+            existed.push(written);
+        }
+        self.commit(ops)?;
+        for (collection, doc_id, retained) in stamps {
+            self.restamp(collection, doc_id, retained);
+        }
+
+        let mut catalog = self.index_catalog.write();
+        for (collection, specs) in catalogs {
+            if !specs.is_empty() {
+                catalog.insert(collection, specs);
+            }
+        }
+        Ok(existed)
+    }
+
+    /// Append the batch operations writing or deleting one document
+    ///
+    /// Index states in `specs` are updated in place so subsequent writes to the
+    /// same collection in the batch build on them. Returns whether the
+    /// document existed before a delete.
+    fn document_ops(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+        doc: Option<&Document>,
+        specs: &mut [IndexSpec],
+        ops: &mut Vec<BatchOp>,
     ) -> Result<bool> {
         let key = Self::make_document_key(collection, doc_id);
-        
+        let previous = if specs.is_empty() {
+            None
+        } else {
+            self.get_document(collection, doc_id)?
+        };
+        let existed = match (doc, specs.is_empty()) {
+            (None, true) => self.exists(collection, doc_id)?,
+            _ => previous.is_some(),
+        };
+        if doc.is_none() && !existed {
+            return Ok(false);
+        }
+
+        for spec in specs.iter_mut() {
+            // Indexes without a state are rebuilt when next loaded
+            let Some(mut state) = spec.state else { continue };
+            // A build in progress indexes documents past its checkpoint itself
+            if !state.covers(doc_id) {
+                continue;
+            }
+            let old = match &previous {
+                Some(d) => spec.entry_keys(collection, doc_id, d)?,
+                None => Vec::new(),
+            };
+            let new = match doc {
+                Some(d) => spec.entry_keys(collection, doc_id, d)?,
+                None => Vec::new(),
+            };
+            let multikey = match doc {
+                Some(doc) if !state.multikey => spec.is_multikey(doc)?,
+                _ => false,
+            };
+            if old == new && !multikey {
+                continue;
+            }
+            let removed: Vec<Vec<u8>> = old.iter().filter(|key| !new.contains(key)).cloned().collect();
+            let added: Vec<Vec<u8>> = new.into_iter().filter(|key| !old.contains(key)).collect();
+            // Entries added earlier in the batch count as holders too
+            let batched: Vec<Vec<u8>> = match spec.unique {
+                true => ops
+                    .iter()
+                    .filter_map(|op| match op {
+                        BatchOp::PutIndexEntry(key) => Some(key.clone()),
+                        _ => None,
+                    })
+                    .collect(),
+                false => Vec::new(),
+            };
+            for key in &added {
+                self.check_unique(collection, spec, key, &batched)?;
+            }
+            for key in removed {
+                state.remove(&key);
+                ops.push(BatchOp::DeleteIndexEntry(key));
+            }
+            for key in added {
+                state.add(&key);
+                ops.push(BatchOp::PutIndexEntry(key));
+            }
+            state.multikey |= multikey;
+            ops.push(BatchOp::PutMetadata(
+                Self::index_state_key(collection, &spec.name),
+                serde_json::to_vec(&state).context("Failed to serialize index state")?,
+            ));
+            spec.state = Some(state);
+        }
+
+        match doc {
+            Some(doc) => {
+                let value = serde_json::to_vec(doc).context("Failed to serialize document")?;
+                ops.push(BatchOp::PutDocument(key, value));
+            }
+            None => ops.push(BatchOp::DeleteDocument(key)),
+        }
+        Ok(existed)
+    }
+
+    /// Append the batch operations recording a write in a collection's history
+    ///
+    /// A document without versions that is already stored was written before
+    /// history was enabled; its stored state becomes version 1, dated when
+    /// history was enabled. Versions past the retention limits are dropped.
+    /// Returns the stamps of the versions retained, unless nothing is recorded.
+    fn history_ops(
+        &self,
+        collection: &str,
+        doc_id: DocumentId,
+        doc: Option<&Document>,
+        config: &HistoryConfig,
+        ops: &mut Vec<BatchOp>,
+    ) -> Result<Option<Vec<VersionStamp>>> {
+        let mut versions = self.document_versions(collection, doc_id)?;
+        if versions.is_empty() {
+            if let Some(prior) = self.get_document(collection, doc_id)? {
+                let version = DocumentVersion { id: doc_id, version: 1, written_at: config.enabled_at, document: Some(prior) };
+                ops.push(Self::put_version(collection, &version)?);
+                versions.push(version);
+            }
+        }
+        let last = versions.last();
+        if doc.is_none() && last.is_none_or(|v| v.document.is_none()) {
+            return Ok(None);
+        }
+
+        // Versions stay in time order even if the clock steps back
+        let now = chrono::Utc::now();
+        let written_at = last.map_or(now, |v| v.written_at.max(now));
+        let version = DocumentVersion {
+            id: doc_id,
+            version: last.map_or(1, |v| v.version + 1),
+            written_at,
+            document: doc.cloned(),
+        };
+        ops.push(Self::put_version(collection, &version)?);
+        versions.push(version);
+
+        let expired = history::expired_versions(&versions, &config.options, now);
+        for version in &versions[..expired] {
+            ops.push(BatchOp::DeleteHistory(Self::make_history_key(collection, doc_id, version.version)));
+        }
+        Ok(Some(versions[expired..].iter().map(VersionStamp::from).collect()))
+    }
+
+    /// Record a document's retained versions in its collection's version
+    /// directory, if loaded; called with the write lock held, after commit
+    fn restamp(&self, collection: &str, doc_id: DocumentId, retained: Vec<VersionStamp>) {
+        if let Some(directory) = self.version_directories.write().get_mut(collection) {
+            if retained.is_empty() {
+                directory.remove(&doc_id);
+            } else {
+                directory.insert(doc_id, retained);
+            }
+        }
+    }
+
+    fn put_version(collection: &str, version: &DocumentVersion) -> Result<BatchOp> {
+        let key = Self::make_history_key(collection, version.id, version.version);
+        let value = serde_json::to_vec(version).context("Failed to serialize document version")?;
+        Ok(BatchOp::PutHistory(key, value))
+    }
+
+    /// Apply a set of changes atomically
+    fn commit(&self, ops: Vec<BatchOp>) -> Result<()> {
         #[cfg(feature = "rocksdb-storage")]
         {
-            let cf = self.db.cf_handle("documents")
+            let documents = self.db.cf_handle("documents")
                 .context("Documents column family not found")?;
-            
-            // Check if document exists
-            let exists = self.db.get_cf(cf, &key)?.is_some();
-            
-            if exists {
-                self.db.delete_cf(cf, key)
-                    .context("Failed to delete document")?;
+            let metadata = self.db.cf_handle("metadata")
+                .context("Metadata column family not found")?;
+            let indexes = self.db.cf_handle("indexes")
+                .context("Indexes column family not found")?;
+            let versions = self.db.cf_handle("history")
+                .context("History column family not found")?;
+
+            let mut batch = WriteBatch::default();
+            for op in ops {
+                match op {
+                    BatchOp::PutDocument(key, value) => batch.put_cf(documents, key, value),
+                    BatchOp::DeleteDocument(key) => batch.delete_cf(documents, key),
+                    BatchOp::PutMetadata(key, value) => batch.put_cf(metadata, key.as_bytes(), value),
+                    BatchOp::DeleteMetadata(key) => batch.delete_cf(metadata, key.as_bytes()),
+                    BatchOp::PutIndexEntry(key) => batch.put_cf(indexes, key, []),
+                    BatchOp::DeleteIndexEntry(key) => batch.delete_cf(indexes, key),
+                    BatchOp::PutHistory(key, value) => batch.put_cf(versions, key, value),
+                    BatchOp::DeleteHistory(key) => batch.delete_cf(versions, key),
+                }
             }
-
-            Ok(exists)
+            self.db.write(batch)
+                .context("Failed to write batch")?;
         }
 
         #[cfg(not(feature = "rocksdb-storage"))]
         {
-            Ok(self.documents.write().remove(&key).is_some())
+            let mut documents = self.documents.write();
+            let mut metadata = self.metadata.write();
+            let mut index_entries = self.index_entries.write();
+            let mut versions = self.history.write();
+            for op in ops {
+                match op {
+                    BatchOp::PutDocument(key, value) => {
+                        documents.insert(key, value);
+                    }
+                    BatchOp::DeleteDocument(key) => {
+                        documents.remove(&key);
+                    }
+                    BatchOp::PutMetadata(key, value) => {
+                        metadata.insert(key, value);
+                    }
+                    BatchOp::DeleteMetadata(key) => {
+                        metadata.remove(&key);
+                    }
+                    BatchOp::PutIndexEntry(key) => {
+                        index_entries.insert(key);
+                    }
+                    BatchOp::DeleteIndexEntry(key) => {
+                        index_entries.remove(&key);
+                    }
+                    BatchOp::PutHistory(key, value) => {
+                        versions.insert(key, value);
+                    }
+                    BatchOp::DeleteHistory(key) => {
+                        versions.remove(&key);
+                    }
+                }
+            }
         }
+
+        Ok(())
     }
 
     /// Check if a document exists
@@ -251,6 +547,96 @@ impl PersistentLayer {
         Ok(documents)
     }
 
+    /// Documents of a collection in key order, starting after `after`
+    pub fn scan_documents_after(
+        &self,
+        collection: &str,
+        after: Option<DocumentId>,
+        limit: usize,
+    ) -> Result<Vec<(DocumentId, Document)>> {
+        let prefix = format!("{}:", collection);
+        let start = match after {
+            Some(doc_id) => Self::make_document_key(collection, doc_id),
+            None => prefix.clone().into_bytes(),
+        };
+        let mut documents = Vec::new();
+
+        #[cfg(feature = "rocksdb-storage")]
+        {
+            let cf = self.db.cf_handle("documents")
+                .context("Documents column family not found")?;
+
+            let iter = self.db.iterator_cf(cf, rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward));
+            for item in iter {
+                if documents.len() >= limit {
+                    break;
+                }
+                let (key, value) = item?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                if after.is_some() && *key == *start {
+                    continue;
+                }
+                let doc = serde_json::from_slice(&value)
+                    .context("Failed to deserialize document")?;
+                documents.push((Self::parse_document_key(&prefix, &key)?, doc));
+            }
+        }
+
+        #[cfg(not(feature = "rocksdb-storage"))]
+        {
+            use std::ops::Bound;
+
+            let lower = match after {
+                Some(_) => Bound::Excluded(start),
+                None => Bound::Included(start),
+            };
+            let docs = self.documents.read();
+            for (key, value) in docs
+                .range((lower, Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+                .take(limit)
+            {
+                let doc = serde_json::from_slice(value)
+                    .context("Failed to deserialize document")?;
+                documents.push((Self::parse_document_key(&prefix, key)?, doc));
+            }
+        }
+
+        Ok(documents)
+    }
+
+    /// Number of documents in a collection
+    pub fn count_documents(&self, collection: &str) -> Result<u64> {
+        let prefix = format!("{}:", collection);
+
+        #[cfg(feature = "rocksdb-storage")]
+        {
+            let cf = self.db.cf_handle("documents")
+                .context("Documents column family not found")?;
+
+            let mut count = 0;
+            for item in self.db.prefix_iterator_cf(cf, prefix.as_bytes()) {
+                let (key, _) = item?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                count += 1;
+            }
+            Ok(count)
+        }
+
+        #[cfg(not(feature = "rocksdb-storage"))]
+        {
+            let docs = self.documents.read();
+            Ok(docs
+                .range(prefix.clone().into_bytes()..)
+                .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+                .count() as u64)
+        }
+    }
+
     /// Store collection metadata
     pub fn store_metadata(&self, key: &str, value: &[u8]) -> Result<()> {
         #[cfg(feature = "rocksdb-storage")]
@@ -390,6 +776,7 @@ impl PersistentLayer {
     /// Drop a collection (delete all documents in the collection)
     pub fn drop_collection(&self, collection: &str) -> Result<()> {
         let prefix = format!("{}:", collection);
+        let _guard = self.write_lock.lock();
 
         #[cfg(feature = "rocksdb-storage")]
         {
@@ -437,24 +824,89 @@ impl PersistentLayer {
             self.save_collections_list(&collections)?;
         }
         
-        // Remove indexes
-        self.delete_metadata(&format!("indexes:{}", collection))?;
+        // Remove indexes, their entries and their states
+        let mut ops: Vec<BatchOp> = self
+            .scan_index_keys(&Self::make_index_prefix(collection, None))?
+            .into_iter()
+            .map(BatchOp::DeleteIndexEntry)
+            .collect();
+        for spec in self.index_specs(collection)? {
+            ops.push(BatchOp::DeleteMetadata(Self::index_state_key(collection, &spec.name)));
+        }
+        ops.push(BatchOp::DeleteMetadata(format!("indexes:{}", collection)));
+
+        // Remove the collection's history and its settings
+        for (key, _) in self.scan_history(format!("{}:", collection).as_bytes())? {
+            ops.push(BatchOp::DeleteHistory(key));
+        }
+        ops.push(BatchOp::DeleteMetadata(Self::history_config_key(collection)));
+        self.commit(ops)?;
+        self.index_catalog.write().remove(collection);
+        self.history_catalog.write().remove(collection);
+        self.version_directories.write().remove(collection);
 
         Ok(())
     }
 
-    /// Create an index
-    pub fn create_index(&self, collection: &str, name: &str, fields: Vec<crate::protocol::IndexField>, unique: bool) -> Result<()> {
+    /// Create an index and persist an entry for every existing document
+    pub fn create_index(&self, collection: &str, name: &str, fields: Vec<crate::protocol::IndexField>, options: IndexOptions) -> Result<()> {
+        if !self.begin_index_build(collection, name, fields, &options)? {
+            return Ok(()); // Already exists
+        }
+
+        let built = self.complete_index_build(collection, name);
+        if let Err(e) = built {
+            self.drop_index(collection, name)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Register an index and start building its entries
+    ///
+    /// Returns `false` if an index of that name already exists. Documents
+    /// are then indexed with [`index_build_batch`](Self::index_build_batch)
+    /// until it returns none, and the build is completed with
+    /// [`finish_index_build`](Self::finish_index_build). Writes in between
+    /// keep the entries of already-indexed documents current, so an
+    /// interrupted build can continue after its last batch.
+    pub fn begin_index_build(&self, collection: &str, name: &str, fields: Vec<crate::protocol::IndexField>, options: &IndexOptions) -> Result<bool> {
+        let _guard = self.write_lock.lock();
         let mut indexes = self.get_indexes_list(collection)?;
         
         // Check if index exists
         if indexes.iter().any(|idx| idx.get("name").and_then(|v| v.as_str()) == Some(name)) {
-            return Ok(()); // Already exists
+            return Ok(false);
         }
         
         let mut index_def = serde_json::Map::new();
         index_def.insert("name".to_string(), serde_json::Value::String(name.to_string()));
-        index_def.insert("unique".to_string(), serde_json::Value::Bool(unique));
+        index_def.insert("unique".to_string(), serde_json::Value::Bool(options.unique));
+        index_def.insert("sparse".to_string(), serde_json::Value::Bool(options.sparse));
+        if let Some(filter) = &options.partial_filter {
+            index_def.insert(
+                "partial_filter".to_string(),
+                serde_json::to_value(filter).context("Failed to serialize partial filter")?,
+            );
+        }
+        if let Some(seconds) = options.expire_after_seconds {
+            index_def.insert("expire_after_seconds".to_string(), serde_json::Value::from(seconds));
+        }
+        if options.hashed {
+            index_def.insert("hashed".to_string(), serde_json::Value::Bool(true));
+        }
+        if let Some(collation) = &options.collation {
+            index_def.insert(
+                "collation".to_string(),
+                serde_json::to_value(collation).context("Failed to serialize collation")?,
+            );
+        }
+        if let Some(vector) = &options.vector {
+            index_def.insert(
+                "vector".to_string(),
+                serde_json::to_value(vector).context("Failed to serialize vector options")?,
+            );
+        }
         
         let fields_val: Vec<serde_json::Value> = fields.into_iter().map(|f| {
             let mut map = serde_json::Map::new();
@@ -467,27 +919,549 @@ impl PersistentLayer {
         
         indexes.push(serde_json::Value::Object(index_def));
         self.save_indexes_list(collection, &indexes)?;
-        
+        self.index_catalog.write().remove(collection);
+
+        self.reset_index_build(collection, name)?;
+        Ok(true)
+    }
+
+    /// Index the next documents of a build and return them
+    ///
+    /// The entries and the advanced checkpoint are written in one batch, so
+    /// after a crash the build resumes after the last returned document.
+    /// Returns no documents once the collection is exhausted.
+    pub fn index_build_batch(&self, collection: &str, name: &str, limit: usize) -> Result<Vec<Document>> {
+        let _guard = self.write_lock.lock();
+        let (spec, mut state) = self.building_index(collection, name)?;
+        let documents = self.scan_documents_after(collection, state.resume_after, limit)?;
+        let Some((last, _)) = documents.last() else {
+            return Ok(Vec::new());
+        };
+        state.resume_after = Some(*last);
+
+        let mut ops = Vec::with_capacity(documents.len() + 1);
+        let mut keys = Vec::with_capacity(documents.len());
+        for (doc_id, doc) in &documents {
+            for key in spec.entry_keys(collection, *doc_id, doc)? {
+                self.check_unique(collection, &spec, &key, &keys)?;
+                keys.push(key);
+            }
+            state.multikey = state.multikey || spec.is_multikey(doc)?;
+        }
+        for key in keys {
+            state.add(&key);
+            ops.push(BatchOp::PutIndexEntry(key));
+        }
+        ops.push(BatchOp::PutMetadata(
+            Self::index_state_key(collection, name),
+            serde_json::to_vec(&state).context("Failed to serialize index state")?,
+        ));
+        self.commit(ops)?;
+        self.index_catalog.write().remove(collection);
+
+        Ok(documents.into_iter().map(|(_, doc)| doc).collect())
+    }
+
+    /// Complete a build, indexing any documents added after its last batch
+    ///
+    /// Returns those documents; from here on every write maintains the
+    /// index's entries.
+    pub fn finish_index_build(&self, collection: &str, name: &str) -> Result<Vec<Document>> {
+        let _guard = self.write_lock.lock();
+        let (spec, mut state) = self.building_index(collection, name)?;
+        let documents = self.scan_documents_after(collection, state.resume_after, usize::MAX)?;
+
+        let mut ops = Vec::with_capacity(documents.len() + 1);
+        let mut keys = Vec::with_capacity(documents.len());
+        for (doc_id, doc) in &documents {
+            for key in spec.entry_keys(collection, *doc_id, doc)? {
+                self.check_unique(collection, &spec, &key, &keys)?;
+                keys.push(key);
+            }
+            state.multikey = state.multikey || spec.is_multikey(doc)?;
+        }
+        for key in keys {
+            state.add(&key);
+            ops.push(BatchOp::PutIndexEntry(key));
+        }
+        state.building = false;
+        state.resume_after = None;
+        ops.push(BatchOp::PutMetadata(
+            Self::index_state_key(collection, name),
+            serde_json::to_vec(&state).context("Failed to serialize index state")?,
+        ));
+        self.commit(ops)?;
+        self.index_catalog.write().remove(collection);
+
+        Ok(documents.into_iter().map(|(_, doc)| doc).collect())
+    }
+
+    /// Restart a build from the first document, discarding its entries
+    pub fn restart_index_build(&self, collection: &str, name: &str) -> Result<()> {
+        let _guard = self.write_lock.lock();
+        self.reset_index_build(collection, name)
+    }
+
+    /// Run a build to completion in the calling thread
+    fn complete_index_build(&self, collection: &str, name: &str) -> Result<()> {
+        while !self.index_build_batch(collection, name, INDEX_BUILD_BATCH)?.is_empty() {}
+        self.finish_index_build(collection, name)?;
+        Ok(())
+    }
+
+    /// Clear an index's entries and mark it building from the start; the
+    /// caller holds the write lock
+    fn reset_index_build(&self, collection: &str, name: &str) -> Result<()> {
+        let mut ops: Vec<BatchOp> = self
+            .scan_index_keys(&Self::make_index_prefix(collection, Some(name)))?
+            .into_iter()
+            .map(BatchOp::DeleteIndexEntry)
+            .collect();
+        let state = IndexState { building: true, ..IndexState::empty() };
+        ops.push(BatchOp::PutMetadata(
+            Self::index_state_key(collection, name),
+            serde_json::to_vec(&state).context("Failed to serialize index state")?,
+        ));
+        self.commit(ops)?;
+        self.index_catalog.write().remove(collection);
         Ok(())
     }
 
+    /// Fail if another document already holds `key`'s values in a unique index
+    ///
+    /// `pending` holds keys staged earlier in the same batch. Entry keys end
+    /// with the document id after self-delimiting values, so entries with
+    /// equal values share everything before it.
+    fn check_unique(&self, collection: &str, spec: &IndexSpec, key: &[u8], pending: &[Vec<u8>]) -> Result<()> {
+        if !spec.unique {
+            return Ok(());
+        }
+
+        let (values, doc_id) = key.split_at(key.len() - 16);
+        let mut holders = self.scan_index_keys(values)?.into_iter().chain(
+            pending.iter().filter(|other| other.starts_with(values)).cloned(),
+        );
+        if !holders.any(|other| &other[values.len()..] != doc_id) {
+            return Ok(());
+        }
+
+        let prefix_len = Self::make_index_prefix(collection, Some(&spec.name)).len();
+        let (decoded, _) = encoding::decode_entry(&key[prefix_len..], spec.fields.len(), &spec.descending)?;
+        Err(IndexError::UniqueConstraintViolation {
+            index: spec.name.clone(),
+            key: DuplicateKey::new(&spec.fields, &decoded),
+        }
+        .into())
+    }
+
+    /// Definition and state of an index that is being built
+    fn building_index(&self, collection: &str, name: &str) -> Result<(IndexSpec, IndexState)> {
+        let spec = self
+            .index_specs(collection)?
+            .into_iter()
+            .find(|spec| spec.name == name)
+            .with_context(|| format!("Index '{}' not found", name))?;
+        match spec.state {
+            Some(state) if state.building => Ok((spec, state)),
+            _ => anyhow::bail!("Index '{}' is not being built", name),
+        }
+    }
+
     /// List indexes
     pub fn list_indexes(&self, collection: &str) -> Result<Vec<serde_json::Value>> {
         self.get_indexes_list(collection)
     }
 
-    /// Drop an index
+    /// Drop an index along with its persisted entries
     pub fn drop_index(&self, collection: &str, name: &str) -> Result<()> {
+        let _guard = self.write_lock.lock();
+        self.remove_index(collection, name)
+    }
+
+    /// Remove an index's definition, entries and state; the caller holds the write lock
+    fn remove_index(&self, collection: &str, name: &str) -> Result<()> {
         let mut indexes = self.get_indexes_list(collection)?;
         
         if let Some(pos) = indexes.iter().position(|idx| idx.get("name").and_then(|v| v.as_str()) == Some(name)) {
             indexes.remove(pos);
             self.save_indexes_list(collection, &indexes)?;
         }
+
+        let mut ops: Vec<BatchOp> = self
+            .scan_index_keys(&Self::make_index_prefix(collection, Some(name)))?
+            .into_iter()
+            .map(BatchOp::DeleteIndexEntry)
+            .collect();
+        ops.push(BatchOp::DeleteMetadata(Self::index_state_key(collection, name)));
+        self.commit(ops)?;
+        self.index_catalog.write().remove(collection);
         
         Ok(())
     }
 
+    /// Load an index's persisted entries, if they can be trusted
+    ///
+    /// Returns `None` when the entries are missing, were written with
+    /// another encoding version, or no longer match their recorded count
+    /// and checksum; the index must then be rebuilt. For an index that is
+    /// still building, the entries cover the documents indexed so far.
+    ///
+    /// The state record and the entries are read from one consistent view
+    /// of the store rather than under the write lock, so loading an index
+    /// does not hold up writes.
+    pub fn load_index(&self, collection: &str, name: &str) -> Result<Option<Vec<IndexEntryValues>>> {
+        let Some(spec) = self.index_specs(collection)?.into_iter().find(|spec| spec.name == name) else {
+            return Ok(None);
+        };
+        let prefix = Self::make_index_prefix(collection, Some(name));
+        // Read the state record itself rather than the cached copy
+        let (state, keys) = self.read_persisted_index(&Self::index_state_key(collection, name), &prefix)?;
+        let expected = match state {
+            Some(data) => serde_json::from_slice::<IndexState>(&data).context("Failed to parse index state")?,
+            None => return Ok(None),
+        };
+        if expected.format_version != INDEX_FORMAT_VERSION {
+            return Ok(None);
+        }
+
+        let mut actual = IndexState::empty();
+        let mut entries = Vec::new();
+        for key in keys {
+            actual.add(&key);
+            match encoding::decode_entry(&key[prefix.len()..], spec.fields.len(), &spec.descending) {
+                Ok((values, doc_id)) => entries.push((doc_id, values)),
+                Err(_) => return Ok(None),
+            }
+        }
+
+        let matches = actual.entries == expected.entries && actual.checksum == expected.checksum;
+        Ok(matches.then_some(entries))
+    }
+
+    /// Rewrite an index's persisted entries from the collection's documents
+    pub fn rebuild_index(&self, collection: &str, name: &str) -> Result<()> {
+        self.restart_index_build(collection, name)?;
+        self.complete_index_build(collection, name)
+    }
+
+    /// Recorded state of an index's persisted entries
+    pub fn index_state(&self, collection: &str, name: &str) -> Result<Option<IndexState>> {
+        match self.get_metadata(&Self::index_state_key(collection, name))? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data).context("Failed to parse index state")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Index definitions and states of a collection
+    fn index_specs(&self, collection: &str) -> Result<Vec<IndexSpec>> {
+        if let Some(specs) = self.index_catalog.read().get(collection) {
+            return Ok(specs.clone());
+        }
+
+        let mut specs = Vec::new();
+        for index in self.get_indexes_list(collection)? {
+            let name = index.get("name").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+            let fields: Vec<crate::protocol::IndexField> =
+                serde_json::from_value(index.get("fields").cloned().unwrap_or_default())
+                    .with_context(|| format!("Invalid fields for index '{}'", name))?;
+            let options: IndexOptions = serde_json::from_value(index.clone())
+                .with_context(|| format!("Invalid options for index '{}'", name))?;
+            let state = self.index_state(collection, &name)?;
+            specs.push(IndexSpec {
+                fields: fields.iter().map(|f| f.field.clone()).collect(),
+                descending: fields.iter().map(|f| f.direction < 0).collect(),
+                unique: options.unique,
+                sparse: options.sparse,
+                partial_filter: options.partial_filter,
+                key_form: KeyForm::new(options.hashed, options.collation),
+                vector: options.vector.map(|v| VectorSpec::new(v.dims, v.metric)),
+                name,
+                state,
+            });
+        }
+
+        self.index_catalog.write().insert(collection.to_string(), specs.clone());
+        Ok(specs)
+    }
+
+    /// An index's state record and entry keys, read together
+    ///
+    /// Batches are applied atomically, so the keys are exactly those the
+    /// returned state record accounts for.
+    fn read_persisted_index(&self, state_key: &str, prefix: &[u8]) -> Result<PersistedIndex> {
+        let mut keys = Vec::new();
+
+        #[cfg(feature = "rocksdb-storage")]
+        {
+            let metadata = self.db.cf_handle("metadata")
+                .context("Metadata column family not found")?;
+            let indexes = self.db.cf_handle("indexes")
+                .context("Indexes column family not found")?;
+
+            let snapshot = self.db.snapshot();
+            let state = snapshot.get_cf(metadata, state_key.as_bytes())?;
+            let iter = snapshot.iterator_cf(indexes, rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward));
+            for item in iter {
+                let (key, _) = item?;
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                keys.push(key.to_vec());
+            }
+            Ok((state, keys))
+        }
+
+        #[cfg(not(feature = "rocksdb-storage"))]
+        {
+            // Same lock order as `commit`
+            let metadata = self.metadata.read();
+            let entries = self.index_entries.read();
+            keys.extend(
+                entries
+                    .range(prefix.to_vec()..)
+                    .take_while(|key| key.starts_with(prefix))
+                    .cloned(),
+            );
+            Ok((metadata.get(state_key).cloned(), keys))
+        }
+    }
+
+    /// Keys of every index entry under `prefix`, in key order
+    fn scan_index_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+
+        #[cfg(feature = "rocksdb-storage")]
+        {
+            let cf = self.db.cf_handle("indexes")
+                .context("Indexes column family not found")?;
+
+            let iter = self.db.prefix_iterator_cf(cf, prefix);
+            for item in iter {
+                let (key, _) = item?;
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                keys.push(key.to_vec());
+            }
+        }
+
+        #[cfg(not(feature = "rocksdb-storage"))]
+        {
+            let entries = self.index_entries.read();
+            keys.extend(
+                entries
+                    .range(prefix.to_vec()..)
+                    .take_while(|key| key.starts_with(prefix))
+                    .cloned(),
+            );
+        }
+
+        Ok(keys)
+    }
+
+    /// Keep the history of a collection's documents from now on, or change
+    /// its retention if it already does
+    ///
+    /// Returns the resulting settings; `enabled_at` stays that of the first call.
+    pub fn enable_history(&self, collection: &str, options: HistoryOptions) -> Result<HistoryConfig> {
+        options.validate()?;
+        let _guard = self.write_lock.lock();
+        let enabled_at = match self.history_config(collection)? {
+            Some(config) => config.enabled_at,
+            None => chrono::Utc::now(),
+        };
+        let config = HistoryConfig { options, enabled_at };
+        let data = serde_json::to_vec(&config).context("Failed to serialize history settings")?;
+        self.store_metadata(&Self::history_config_key(collection), &data)?;
+        self.history_catalog.write().insert(collection.to_string(), Some(config));
+        Ok(config)
+    }
+
+    /// History settings of a collection, or `None` if it keeps no history
+    pub fn history_config(&self, collection: &str) -> Result<Option<HistoryConfig>> {
+        if let Some(config) = self.history_catalog.read().get(collection) {
+            return Ok(*config);
+        }
+        let config = match self.get_metadata(&Self::history_config_key(collection))? {
+            Some(data) => Some(
+                serde_json::from_slice(&data)
+                    .with_context(|| format!("Failed to parse history settings of collection '{}'", collection))?,
+            ),
+            None => None,
+        };
+        self.history_catalog.write().insert(collection.to_string(), config);
+        Ok(config)
+    }
+
+    /// Retained versions of one document, oldest first
+    pub fn document_versions(&self, collection: &str, doc_id: DocumentId) -> Result<Vec<DocumentVersion>> {
+        let prefix = format!("{}:{}:", collection, doc_id);
+        self.scan_history(prefix.as_bytes())?
+            .into_iter()
+            .map(|(_, value)| serde_json::from_slice(&value).context("Failed to deserialize document version"))
+            .collect()
+    }
+
+    /// Retained versions of every document in a collection, by document
+    /// and oldest first
+    pub fn collection_versions(&self, collection: &str) -> Result<Vec<Vec<DocumentVersion>>> {
+        let mut documents: Vec<Vec<DocumentVersion>> = Vec::new();
+        for (_, value) in self.scan_history(format!("{}:", collection).as_bytes())? {
+            let version: DocumentVersion =
+                serde_json::from_slice(&value).context("Failed to deserialize document version")?;
+            match documents.last_mut() {
+                Some(versions) if versions[0].id == version.id => versions.push(version),
+                _ => documents.push(vec![version]),
+            }
+        }
+        Ok(documents)
+    }
+
+    /// Drop the versions of a collection past its age limit at `now`
+    ///
+    /// Writes apply the limits to the documents they touch; this catches up
+    /// on documents that are not written. Returns the number of versions dropped.
+    pub fn prune_history(&self, collection: &str, now: chrono::DateTime<chrono::Utc>) -> Result<usize> {
+        let Some(config) = self.history_config(collection)? else {
+            return Ok(0);
+        };
+        if config.options.max_age_secs.is_none() {
+            return Ok(0);
+        }
+        let _guard = self.write_lock.lock();
+        let mut ops = Vec::new();
+        let mut stamps = Vec::new();
+        for versions in self.collection_versions(collection)? {
+            let expired = history::expired_versions(&versions, &config.options, now);
+            if expired == 0 {
+                continue;
+            }
+            for version in &versions[..expired] {
+                ops.push(BatchOp::DeleteHistory(Self::make_history_key(collection, version.id, version.version)));
+            }
+            stamps.push((versions[0].id, versions[expired..].iter().map(VersionStamp::from).collect()));
+        }
+        let pruned = ops.len();
+        self.commit(ops)?;
+        for (doc_id, retained) in stamps {
+            self.restamp(collection, doc_id, retained);
+        }
+        Ok(pruned)
+    }
+
+    /// The version each document with retained versions had at `point`
+    ///
+    /// Versions are picked from the collection's version directory, loaded
+    /// with one scan of its history on first use, and only the picked ones
+    /// are read. Fails if retention dropped the version a document had there.
+    pub fn versions_at(&self, collection: &str, point: HistoryPoint) -> Result<HistorySnapshot> {
+        self.load_version_directory(collection)?;
+        let mut snapshot = HistorySnapshot::default();
+        let mut picked = Vec::new();
+        {
+            let directories = self.version_directories.read();
+            let Some(directory) = directories.get(collection) else {
+                return Ok(snapshot);
+            };
+            for (&doc_id, stamps) in directory {
+                snapshot.tracked.insert(doc_id);
+                if stamps.last().is_some_and(|stamp| !stamp.deleted) {
+                    snapshot.live += 1;
+                }
+                match history::version_at(stamps, point) {
+                    VersionLookup::Found(stamp) => picked.push((doc_id, stamp.version)),
+                    VersionLookup::Absent => {}
+                    VersionLookup::Pruned => {
+                        return Err(HistoryError::Pruned {
+                            collection: collection.to_string(),
+                            id: doc_id,
+                            point: point.to_string(),
+                        }
+                        .into());
+                    }
+                }
+            }
+        }
+        for (doc_id, version) in picked {
+            // A version pruned since it was picked is left out
+            if let Some(version) = self.document_version(collection, doc_id, version)? {
+                snapshot.versions.push(version);
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Build a collection's version directory unless already loaded
+    fn load_version_directory(&self, collection: &str) -> Result<()> {
+        if self.version_directories.read().contains_key(collection) {
+            return Ok(());
+        }
+        // Writes update a loaded directory, so none may land between the scan and the insert
+        let _guard = self.write_lock.lock();
+        if self.version_directories.read().contains_key(collection) {
+            return Ok(());
+        }
+        let directory: VersionDirectory = self
+            .collection_versions(collection)?
+            .into_iter()
+            .map(|versions| (versions[0].id, versions.iter().map(VersionStamp::from).collect()))
+            .collect();
+        self.version_directories.write().insert(collection.to_string(), directory);
+        Ok(())
+    }
+
+    /// One version of a document, if retained
+    fn document_version(&self, collection: &str, doc_id: DocumentId, version: u64) -> Result<Option<DocumentVersion>> {
+        let key = Self::make_history_key(collection, doc_id, version);
+
+        #[cfg(feature = "rocksdb-storage")]
+        let value = {
+            let cf = self.db.cf_handle("history")
+                .context("History column family not found")?;
+            self.db.get_cf(cf, key)?
+        };
+
+        #[cfg(not(feature = "rocksdb-storage"))]
+        let value = self.history.read().get(&key).cloned();
+
+        value
+            .map(|value| serde_json::from_slice(&value).context("Failed to deserialize document version"))
+            .transpose()
+    }
+
+    /// Version records under `prefix`, in key order
+    fn scan_history(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut records = Vec::new();
+
+        #[cfg(feature = "rocksdb-storage")]
+        {
+            let cf = self.db.cf_handle("history")
+                .context("History column family not found")?;
+
+            let iter = self.db.prefix_iterator_cf(cf, prefix);
+            for item in iter {
+                let (key, value) = item?;
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                records.push((key.to_vec(), value.to_vec()));
+            }
+        }
+
+        #[cfg(not(feature = "rocksdb-storage"))]
+        {
+            let versions = self.history.read();
+            records.extend(
+                versions
+                    .range(prefix.to_vec()..)
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+
+        Ok(records)
+    }
+
     // Helper to get collections list by scanning actual keys
     fn get_collections_list(&self) -> Result<Vec<String>> {
         use std::collections::HashSet;
@@ -528,6 +1502,12 @@ impl PersistentLayer {
             }
         }
         
+        // Collections created explicitly exist before their first document
+        if let Some(data) = self.get_metadata("collections")? {
+            let created: Vec<String> = serde_json::from_slice(&data).context("Failed to parse collections list")?;
+            collections.extend(created);
+        }
+        
         // Convert HashSet to sorted Vec for consistent ordering
         let mut result: Vec<String> = collections.into_iter().collect();
         result.sort();
@@ -558,6 +1538,184 @@ impl PersistentLayer {
     fn make_document_key(collection: &str, doc_id: DocumentId) -> Vec<u8> {
         format!("{}:{}", collection, doc_id).into_bytes()
     }
+
+    /// Document ID from a key made by [`make_document_key`](Self::make_document_key)
+    fn parse_document_key(prefix: &str, key: &[u8]) -> Result<DocumentId> {
+        let id = std::str::from_utf8(&key[prefix.len()..]).context("Document key is not UTF-8")?;
+        let uuid = uuid::Uuid::parse_str(id).context("Invalid document ID in key")?;
+        Ok(DocumentId::from_uuid(uuid))
+    }
+
+    /// Prefix of a collection's index entries, or of one index's entries
+    fn make_index_prefix(collection: &str, index: Option<&str>) -> Vec<u8> {
+        let mut prefix = collection.as_bytes().to_vec();
+        prefix.push(0);
+        if let Some(index) = index {
+            prefix.extend_from_slice(index.as_bytes());
+            prefix.push(0);
+        }
+        prefix
+    }
+
+    /// Key of a document version record; the padded version keeps key order numeric
+    fn make_history_key(collection: &str, doc_id: DocumentId, version: u64) -> Vec<u8> {
+        format!("{}:{}:{:020}", collection, doc_id, version).into_bytes()
+    }
+
+    /// Metadata key of a collection's history settings
+    fn history_config_key(collection: &str) -> String {
+        format!("history:{}", collection)
+    }
+
+    /// Metadata key of an index's state record
+    fn index_state_key(collection: &str, index: &str) -> String {
+        format!("index_state:{}:{}", collection, index)
+    }
+}
+
+/// Recorded state of an index's persisted entries
+///
+/// Checked on reload; entries whose count or checksum disagree with the
+/// state, or that were written by another encoding version, are rebuilt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexState {
+    /// Entry encoding version
+    pub format_version: u32,
+    /// Number of entries
+    pub entries: u64,
+    /// Wrapping sum of the entries' checksums
+    pub checksum: u64,
+    /// Whether the index is still being built
+    #[serde(default)]
+    pub building: bool,
+    /// Whether some document was indexed under an array value
+    #[serde(default)]
+    pub multikey: bool,
+    /// Last document a build in progress has indexed
+    #[serde(default)]
+    pub resume_after: Option<DocumentId>,
+}
+
+impl IndexState {
+    fn empty() -> Self {
+        Self {
+            format_version: INDEX_FORMAT_VERSION,
+            entries: 0,
+            checksum: 0,
+            building: false,
+            multikey: false,
+            resume_after: None,
+        }
+    }
+
+    /// Whether writes to `doc_id` must maintain the index's entries
+    ///
+    /// While building, only documents up to the checkpoint are maintained;
+    /// the build's scan reaches the rest.
+    fn covers(&self, doc_id: DocumentId) -> bool {
+        !self.building || self.resume_after.is_some_and(|after| doc_id <= after)
+    }
+
+    fn add(&mut self, key: &[u8]) {
+        self.entries += 1;
+        self.checksum = self.checksum.wrapping_add(encoding::entry_checksum(key));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.entries = self.entries.saturating_sub(1);
+        self.checksum = self.checksum.wrapping_sub(encoding::entry_checksum(key));
+    }
+}
+
+/// An index as the persistent layer maintains it
+#[derive(Debug, Clone)]
+struct IndexSpec {
+    name: String,
+    fields: Vec<String>,
+    descending: Vec<bool>,
+    unique: bool,
+    sparse: bool,
+    /// Only documents matching this filter have entries
+    partial_filter: Option<Filter>,
+    /// How field values become stored key values
+    key_form: KeyForm,
+    /// Dimensions and metric, for a vector index
+    vector: Option<VectorSpec>,
+    /// `None` while the entries are being built or after they were lost
+    state: Option<IndexState>,
+}
+
+impl IndexSpec {
+    /// Full storage keys of a document's entries in this index
+    ///
+    /// A document has one entry per element of an array-valued field. A
+    /// sparse index leaves out entries holding a null or missing value, and
+    /// a partial index leaves out documents not matching its filter. Keys
+    /// hold collation keys or hashes for a collated or hashed index. A
+    /// vector index has a single entry holding the whole vector, or none.
+    fn entry_keys(&self, collection: &str, doc_id: DocumentId, doc: &Document) -> Result<Vec<Vec<u8>>> {
+        if !self.covers(doc)? {
+            return Ok(Vec::new());
+        }
+        if let Some(spec) = &self.vector {
+            let entry = IndexEntry::from_document(doc, &self.fields);
+            let value = self.fields.first().and_then(|field| entry.get_field(field));
+            let prefix = PersistentLayer::make_index_prefix(collection, Some(&self.name));
+            return Ok(spec
+                .key(value)
+                .map(|value| {
+                    let mut key = prefix;
+                    key.extend(encoding::encode_entry(&[value], &self.descending, doc_id));
+                    key
+                })
+                .into_iter()
+                .collect());
+        }
+        let keys = encoding::key_values(doc, &self.fields)
+            .with_context(|| format!("Failed to index document for '{}'", self.name))?;
+        let prefix = PersistentLayer::make_index_prefix(collection, Some(&self.name));
+        let mut entries: Vec<Vec<u8>> = keys
+            .into_iter()
+            .filter(|values| {
+                !(self.sparse && values.iter().any(|v| matches!(v, IndexValue::Null | IndexValue::Missing)))
+            })
+            .map(|values| {
+                let values: Vec<IndexValue> = values.into_iter().map(|v| self.key_form.apply(v)).collect();
+                let mut key = prefix.clone();
+                key.extend(encoding::encode_entry(&values, &self.descending, doc_id));
+                key
+            })
+            .collect();
+        // Array elements that collate or hash alike share one entry
+        entries.sort();
+        entries.dedup();
+        Ok(entries)
+    }
+
+    /// Whether a document holds an array in any indexed field
+    fn is_multikey(&self, doc: &Document) -> Result<bool> {
+        Ok(self.vector.is_none() && self.covers(doc)? && IndexEntry::from_document(doc, &self.fields).is_multikey(&self.fields))
+    }
+
+    /// Whether a document matches the index's partial filter, if any
+    fn covers(&self, doc: &Document) -> Result<bool> {
+        match &self.partial_filter {
+            Some(filter) => Ok(QueryExecutor::new().matches_filter(doc, filter)?),
+            None => Ok(true),
+        }
+    }
+}
+
+/// A change applied as part of an atomic batch
+enum BatchOp {
+    PutDocument(Vec<u8>, Vec<u8>),
+    DeleteDocument(Vec<u8>),
+    PutMetadata(String, Vec<u8>),
+    DeleteMetadata(String),
+    PutIndexEntry(Vec<u8>),
+    DeleteIndexEntry(Vec<u8>),
+    PutHistory(Vec<u8>, Vec<u8>),
+    DeleteHistory(Vec<u8>),
 }
 
 /// Storage statistics
@@ -659,6 +1817,18 @@ mod tests {
         assert_eq!(documents.len(), 5);
     }
 
+    #[test]
+    fn test_created_collections_are_listed_before_their_first_document() {
+        let (storage, _temp_dir) = create_test_storage();
+        storage.create_collection("empty").unwrap();
+        let doc = Document::new();
+        storage.insert_document("filled", doc.id, &doc).unwrap();
+        assert_eq!(storage.list_collections().unwrap(), vec!["empty".to_string(), "filled".to_string()]);
+
+        storage.drop_collection("empty").unwrap();
+        assert_eq!(storage.list_collections().unwrap(), vec!["filled".to_string()]);
+    }
+
     #[test]
     fn test_metadata_operations() {
         let (storage, _temp_dir) = create_test_storage();
@@ -686,6 +1856,263 @@ mod tests {
         assert!(stats.total_size_bytes >= 0);
     }
 
+    fn age_index() -> Vec<crate::protocol::IndexField> {
+        vec![crate::protocol::IndexField { field: "age".to_string(), direction: -1 }]
+    }
+
+    fn person(age: i32) -> Document {
+        let mut doc = Document::new();
+        doc.insert("age".to_string(), Value::Int32(age));
+        doc
+    }
+
+    fn loaded_ages(storage: &PersistentLayer) -> Vec<(DocumentId, Vec<IndexValue>)> {
+        let mut entries = storage.load_index("users", "age_idx").unwrap().unwrap();
+        entries.sort_by(|a, b| a.1.cmp(&b.1));
+        entries
+    }
+
+    #[test]
+    fn test_index_entries_follow_document_writes() {
+        let (storage, _temp_dir) = create_test_storage();
+        let first = person(30);
+        storage.insert_document("users", first.id, &first).unwrap();
+        storage.create_index("users", "age_idx", age_index(), IndexOptions::default()).unwrap();
+
+        let second = person(20);
+        storage.insert_document("users", second.id, &second).unwrap();
+        assert_eq!(
+            loaded_ages(&storage),
+            vec![(second.id, vec![IndexValue::Int32(20)]), (first.id, vec![IndexValue::Int32(30)])]
+        );
+
+        let mut updated = first.clone();
+        updated.insert("age".to_string(), Value::Int32(40));
+        storage.update_document("users", first.id, &updated).unwrap();
+        assert!(storage.delete_document("users", second.id).unwrap());
+        assert_eq!(loaded_ages(&storage), vec![(first.id, vec![IndexValue::Int32(40)])]);
+        assert_eq!(storage.index_state("users", "age_idx").unwrap().unwrap().entries, 1);
+
+        storage.drop_index("users", "age_idx").unwrap();
+        assert!(storage.load_index("users", "age_idx").unwrap().is_none());
+        assert!(storage.index_state("users", "age_idx").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_write_documents_is_atomic() {
+        let (storage, _temp_dir) = create_test_storage();
+        let unique = IndexOptions { unique: true, ..Default::default() };
+        storage.create_index("users", "age_idx", age_index(), unique).unwrap();
+        let kept = person(30);
+        let doomed = person(40);
+        storage.insert_document("users", kept.id, &kept).unwrap();
+        storage.insert_document("users", doomed.id, &doomed).unwrap();
+
+        // Two documents taking the same key in one batch conflict with each other
+        let (first, second) = (person(50), person(50));
+        let log = Document::new();
+        let writes = [
+            ("users", doomed.id, None),
+            ("logs", log.id, Some(&log)),
+            ("users", first.id, Some(&first)),
+            ("users", second.id, Some(&second)),
+        ];
+        assert!(storage.write_documents(&writes).is_err());
+        assert!(storage.get_document("users", doomed.id).unwrap().is_some());
+        assert!(storage.get_document("logs", log.id).unwrap().is_none());
+        assert_eq!(loaded_ages(&storage).len(), 2);
+
+        let existed = storage.write_documents(&writes[..3]).unwrap();
+        assert_eq!(existed, vec![true, false, false]);
+        assert!(storage.get_document("users", doomed.id).unwrap().is_none());
+        assert!(storage.get_document("logs", log.id).unwrap().is_some());
+        assert_eq!(
+            loaded_ages(&storage),
+            vec![(kept.id, vec![IndexValue::Int32(30)]), (first.id, vec![IndexValue::Int32(50)])]
+        );
+    }
+
+    #[test]
+    fn test_load_index_rejects_mismatched_state() {
+        let (storage, _temp_dir) = create_test_storage();
+        let doc = person(30);
+        storage.insert_document("users", doc.id, &doc).unwrap();
+        storage.create_index("users", "age_idx", age_index(), IndexOptions::default()).unwrap();
+        let state = storage.index_state("users", "age_idx").unwrap().unwrap();
+        let state_key = PersistentLayer::index_state_key("users", "age_idx");
+
+        let wrong_checksum = IndexState { checksum: state.checksum ^ 1, ..state };
+        storage.store_metadata(&state_key, &serde_json::to_vec(&wrong_checksum).unwrap()).unwrap();
+        assert!(storage.load_index("users", "age_idx").unwrap().is_none());
+
+        let old_version = IndexState { format_version: INDEX_FORMAT_VERSION + 1, ..state };
+        storage.store_metadata(&state_key, &serde_json::to_vec(&old_version).unwrap()).unwrap();
+        assert!(storage.load_index("users", "age_idx").unwrap().is_none());
+
+        storage.rebuild_index("users", "age_idx").unwrap();
+        assert_eq!(storage.index_state("users", "age_idx").unwrap(), Some(state));
+        assert_eq!(loaded_ages(&storage), vec![(doc.id, vec![IndexValue::Int32(30)])]);
+    }
+
+    #[test]
+    fn test_load_index_reads_a_consistent_view_beside_writes() {
+        let (storage, _temp_dir) = create_test_storage();
+        let doc = person(30);
+        storage.insert_document("users", doc.id, &doc).unwrap();
+        storage.create_index("users", "age_idx", age_index(), IndexOptions::default()).unwrap();
+
+        // Loading does not wait for writers
+        {
+            let _writer = storage.write_lock.lock();
+            assert_eq!(loaded_ages(&storage), vec![(doc.id, vec![IndexValue::Int32(30)])]);
+        }
+
+        // Entries and state written together are always seen together
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for age in 0..200 {
+                    let other = person(age);
+                    storage.insert_document("users", other.id, &other).unwrap();
+                    storage.delete_document("users", other.id).unwrap();
+                }
+            });
+            for _ in 0..200 {
+                assert!(storage.load_index("users", "age_idx").unwrap().is_some());
+            }
+        });
+        assert_eq!(loaded_ages(&storage), vec![(doc.id, vec![IndexValue::Int32(30)])]);
+    }
+
+    #[test]
+    fn test_create_index_rolls_back_on_unindexable_document() {
+        let (storage, _temp_dir) = create_test_storage();
+        let mut doc = person(30);
+        doc.insert("age".to_string(), Value::Array(vec![Value::Int32(1)]));
+        doc.insert("rank".to_string(), Value::Array(vec![Value::Int32(2)]));
+        storage.insert_document("users", doc.id, &doc).unwrap();
+
+        // Two array fields in one key are parallel arrays
+        let mut fields = age_index();
+        fields.push(crate::protocol::IndexField { field: "rank".to_string(), direction: 1 });
+        assert!(storage.create_index("users", "age_idx", fields, IndexOptions::default()).is_err());
+        assert!(storage.list_indexes("users").unwrap().is_empty());
+        assert!(storage.index_state("users", "age_idx").unwrap().is_none());
+
+        // Later writes are unaffected by the abandoned index
+        let other = person(10);
+        storage.insert_document("users", other.id, &other).unwrap();
+    }
+
+    #[test]
+    fn test_drop_collection_removes_index_entries() {
+        let (storage, _temp_dir) = create_test_storage();
+        let doc = person(30);
+        storage.insert_document("users", doc.id, &doc).unwrap();
+        storage.create_index("users", "age_idx", age_index(), IndexOptions::default()).unwrap();
+
+        storage.drop_collection("users").unwrap();
+        assert!(storage.index_state("users", "age_idx").unwrap().is_none());
+        assert!(storage
+            .scan_index_keys(&PersistentLayer::make_index_prefix("users", None))
+            .unwrap()
+            .is_empty());
+    }
+
+    fn ages(versions: &[DocumentVersion]) -> Vec<(u64, Option<i64>)> {
+        versions
+            .iter()
+            .map(|v| (v.version, v.document.as_ref().and_then(|d| d.get("age")).and_then(Value::as_i64)))
+            .collect()
+    }
+
+    #[test]
+    fn test_history_records_every_write() {
+        let (storage, _temp_dir) = create_test_storage();
+        let mut doc = person(30);
+        storage.insert_document("users", doc.id, &doc).unwrap();
+        assert!(storage.document_versions("users", doc.id).unwrap().is_empty());
+
+        let options = HistoryOptions { max_age_secs: None, max_versions: Some(2) };
+        let config = storage.enable_history("users", options).unwrap();
+        doc.insert("age".to_string(), Value::Int32(31));
+        storage.update_document("users", doc.id, &doc).unwrap();
+        // The state from before history is kept as version 1
+        let versions = storage.document_versions("users", doc.id).unwrap();
+        assert_eq!(ages(&versions), [(1, Some(30)), (2, Some(31))]);
+        assert_eq!(versions[0].written_at, config.enabled_at);
+
+        doc.insert("age".to_string(), Value::Int32(32));
+        storage.update_document("users", doc.id, &doc).unwrap();
+        assert!(storage.delete_document("users", doc.id).unwrap());
+        assert!(!storage.delete_document("users", doc.id).unwrap());
+        assert_eq!(ages(&storage.document_versions("users", doc.id).unwrap()), [(2, Some(31)), (3, Some(32)), (4, None)]);
+
+        // The settings survive a reload and keep their start
+        let reloaded = storage.enable_history("users", HistoryOptions::default()).unwrap();
+        assert_eq!(reloaded.enabled_at, config.enabled_at);
+        storage.history_catalog.write().clear();
+        assert_eq!(storage.history_config("users").unwrap(), Some(reloaded));
+        assert_eq!(storage.history_config("other").unwrap(), None);
+        assert!(storage.enable_history("other", HistoryOptions { max_age_secs: None, max_versions: Some(0) }).is_err());
+
+        storage.drop_collection("users").unwrap();
+        assert!(storage.collection_versions("users").unwrap().is_empty());
+        assert_eq!(storage.history_config("users").unwrap(), None);
+    }
+
+    #[test]
+    fn test_prune_history_by_age() {
+        let (storage, _temp_dir) = create_test_storage();
+        storage.enable_history("logs", HistoryOptions { max_age_secs: Some(60), max_versions: None }).unwrap();
+        let mut kept = person(1);
+        storage.insert_document("logs", kept.id, &kept).unwrap();
+        kept.insert("age".to_string(), Value::Int32(2));
+        storage.update_document("logs", kept.id, &kept).unwrap();
+        let deleted = person(3);
+        storage.insert_document("logs", deleted.id, &deleted).unwrap();
+        storage.delete_document("logs", deleted.id).unwrap();
+
+        assert_eq!(storage.prune_history("logs", chrono::Utc::now()).unwrap(), 0);
+        // Past the limit, superseded versions and whole deleted documents go
+        let future = chrono::Utc::now() + chrono::Duration::seconds(120);
+        assert_eq!(storage.prune_history("logs", future).unwrap(), 3);
+        let remaining = storage.collection_versions("logs").unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(ages(&remaining[0]), [(2, Some(2))]);
+        assert_eq!(storage.prune_history("unknown", future).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_versions_at_follow_writes_and_pruning() {
+        let (storage, _temp_dir) = create_test_storage();
+        storage.enable_history("logs", HistoryOptions { max_age_secs: Some(60), max_versions: None }).unwrap();
+        let mut doc = person(1);
+        storage.insert_document("logs", doc.id, &doc).unwrap();
+        let first = storage.versions_at("logs", HistoryPoint::Version(1)).unwrap();
+        assert_eq!(ages(&first.versions), [(1, Some(1))]);
+        assert_eq!(first.live, 1);
+
+        // Writes after the directory is loaded are stamped into it
+        doc.insert("age".to_string(), Value::Int32(2));
+        storage.update_document("logs", doc.id, &doc).unwrap();
+        let removed = person(3);
+        storage.insert_document("logs", removed.id, &removed).unwrap();
+        storage.delete_document("logs", removed.id).unwrap();
+        let now = storage.versions_at("logs", HistoryPoint::AsOf(chrono::Utc::now())).unwrap();
+        assert_eq!(ages(&now.versions), [(2, Some(2))]);
+        assert_eq!((now.tracked.len(), now.live), (2, 1));
+        assert_eq!(ages(&storage.versions_at("logs", HistoryPoint::Version(2)).unwrap().versions), [(2, Some(2))]);
+
+        let future = chrono::Utc::now() + chrono::Duration::seconds(120);
+        assert_eq!(storage.prune_history("logs", future).unwrap(), 3);
+        let err = storage.versions_at("logs", HistoryPoint::Version(1)).unwrap_err();
+        assert!(matches!(err.downcast_ref::<HistoryError>(), Some(HistoryError::Pruned { id, .. }) if *id == doc.id));
+        assert_eq!(storage.versions_at("logs", HistoryPoint::Version(2)).unwrap().tracked.len(), 1);
+
+        storage.drop_collection("logs").unwrap();
+        assert!(storage.versions_at("logs", HistoryPoint::Version(2)).unwrap().tracked.is_empty());
+    }
+
     #[test]
     fn test_flush_and_compact() {
         let (storage, _temp_dir) = create_test_storage();
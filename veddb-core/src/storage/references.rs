@@ -0,0 +1,355 @@
+//! Referential integrity for reference fields
+//!
+//! A [`FieldType::Reference`] field holds the id string of a document in its
+//! target collection. Enforced references are checked on insert and update;
+//! deleting a referenced document applies the reference's [`OnDelete`]
+//! action to the documents pointing at it.
+
+use crate::document::{Document, DocumentId, Value};
+use crate::schema::{FieldDefinition, FieldType, OnDelete, Schema};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// A reference field declared by a collection's schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReferenceRule {
+    /// Collection holding the reference
+    pub collection: String,
+    /// Dotted path of the reference field
+    pub field: String,
+    /// Collection of the referenced documents
+    pub target: String,
+    /// Whether inserts and updates must reference an existing document
+    pub enforce: bool,
+    /// Effect of deleting a referenced document
+    pub on_delete: OnDelete,
+}
+
+impl ReferenceRule {
+    /// Id of the document `doc` references through this rule's field
+    pub fn referenced_id(&self, doc: &Document) -> Option<DocumentId> {
+        doc.get_by_path(&self.field).and_then(reference_id)
+    }
+}
+
+/// Reference rules declared by every schema
+///
+/// References are found in top-level and nested object fields; references
+/// held inside arrays are type-checked only.
+pub fn rules(schemas: &HashMap<String, Schema>) -> Vec<ReferenceRule> {
+    let mut rules = Vec::new();
+    for (collection, schema) in schemas {
+        collect_rules(collection, &schema.fields, "", &mut rules);
+    }
+    rules
+}
+
+fn collect_rules(
+    collection: &str,
+    fields: &BTreeMap<String, FieldDefinition>,
+    prefix: &str,
+    rules: &mut Vec<ReferenceRule>,
+) {
+    for (name, field) in fields {
+        let path = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+        match &field.field_type {
+            FieldType::Reference { collection: target, enforce, on_delete } => rules.push(ReferenceRule {
+                collection: collection.to_string(),
+                field: path,
+                target: target.clone(),
+                enforce: *enforce,
+                on_delete: *on_delete,
+            }),
+            FieldType::Object { fields } => collect_rules(collection, fields, &path, rules),
+            _ => {}
+        }
+    }
+}
+
+/// Document id held by a reference value
+pub fn reference_id(value: &Value) -> Option<DocumentId> {
+    match value {
+        Value::String(id) => uuid::Uuid::parse_str(id).ok().map(DocumentId::from_uuid),
+        _ => None,
+    }
+}
+
+/// Documents that must exist for `doc` to be written to `collection`
+///
+/// Missing and null references are not checked; values that cannot hold a
+/// document id fail.
+pub fn required<'a>(
+    rules: &'a [ReferenceRule],
+    collection: &str,
+    doc: &Document,
+) -> Result<Vec<(&'a ReferenceRule, DocumentId)>, ReferenceError> {
+    let mut required = Vec::new();
+    for rule in rules.iter().filter(|rule| rule.enforce && rule.collection == collection) {
+        match doc.get_by_path(&rule.field) {
+            None | Some(Value::Null) => {}
+            Some(value) => {
+                let id = reference_id(value).ok_or_else(|| ReferenceError::Unresolvable {
+                    field: rule.field.clone(),
+                    target: rule.target.clone(),
+                })?;
+                required.push((rule, id));
+            }
+        }
+    }
+    Ok(required)
+}
+
+/// Documents deleted or changed by deleting one document
+#[derive(Debug, Clone, Default)]
+pub struct DeletePlan {
+    /// Documents to delete and their collections, the requested one first
+    pub deletes: Vec<(String, Document)>,
+    /// Documents whose references are set to null: collection, old and new version
+    pub updates: Vec<(String, Document, Document)>,
+}
+
+impl DeletePlan {
+    /// Follow the `on_delete` actions of the references to `doc`
+    ///
+    /// `scan` loads the current documents of a referencing collection. Fails
+    /// with [`ReferenceError::Restricted`] if a `restrict` reference points
+    /// at a document being deleted from a document that is not.
+    pub fn build<F>(rules: &[ReferenceRule], collection: &str, doc: Document, mut scan: F) -> Result<Self>
+    where
+        F: FnMut(&str) -> Result<Vec<Document>>,
+    {
+        let mut deleting = HashSet::from([(collection.to_string(), doc.id)]);
+        let mut deletes = vec![(collection.to_string(), doc)];
+        let mut nulled: BTreeMap<(String, DocumentId), (Document, Document)> = BTreeMap::new();
+        let mut restricted: Vec<(&ReferenceRule, DocumentId, DocumentId)> = Vec::new();
+        let mut scans: HashMap<String, Vec<Document>> = HashMap::new();
+
+        let mut next = 0;
+        while next < deletes.len() {
+            let (target, target_id) = (deletes[next].0.clone(), deletes[next].1.id);
+            next += 1;
+
+            for rule in rules.iter().filter(|rule| rule.target == target && rule.on_delete != OnDelete::Ignore) {
+                if !scans.contains_key(&rule.collection) {
+                    let documents = scan(&rule.collection)
+                        .with_context(|| format!("Failed to scan referencing collection '{}'", rule.collection))?;
+                    scans.insert(rule.collection.clone(), documents);
+                }
+                let referencing = scans[&rule.collection]
+                    .iter()
+                    .filter(|candidate| rule.referenced_id(candidate) == Some(target_id));
+
+                for candidate in referencing {
+                    let key = (rule.collection.clone(), candidate.id);
+                    match rule.on_delete {
+                        OnDelete::Ignore => {}
+                        OnDelete::Restrict => restricted.push((rule, target_id, candidate.id)),
+                        OnDelete::Cascade => {
+                            if deleting.insert(key) {
+                                deletes.push((rule.collection.clone(), candidate.clone()));
+                            }
+                        }
+                        OnDelete::SetNull => {
+                            let (_, updated) = nulled
+                                .entry(key)
+                                .or_insert_with(|| (candidate.clone(), candidate.clone()));
+                            updated
+                                .set_by_path(&rule.field, Value::Null)
+                                .with_context(|| format!("Failed to clear reference '{}'", rule.field))?;
+                        }
+                    }
+                }
+            }
+        }
+
+        // A restricting document deleted by the same cascade does not block it
+        for (rule, target_id, referencing_id) in &restricted {
+            if deleting.contains(&(rule.collection.clone(), *referencing_id)) {
+                continue;
+            }
+            let count = restricted
+                .iter()
+                .filter(|(other, id, referencing)| {
+                    other == rule && id == target_id && !deleting.contains(&(rule.collection.clone(), *referencing))
+                })
+                .count();
+            return Err(ReferenceError::Restricted {
+                collection: rule.target.clone(),
+                id: *target_id,
+                referencing: rule.collection.clone(),
+                field: rule.field.clone(),
+                count,
+            }
+            .into());
+        }
+
+        let updates = nulled
+            .into_iter()
+            .filter(|(key, _)| !deleting.contains(key))
+            .map(|((collection, _), (old, new))| (collection, old, new))
+            .collect();
+        Ok(Self { deletes, updates })
+    }
+
+    /// Counts reported for the delete
+    pub fn outcome(&self) -> DeleteOutcome {
+        let mut outcome = DeleteOutcome { deleted: true, ..Default::default() };
+        for (collection, _) in self.deletes.iter().skip(1) {
+            *outcome.cascaded.entry(collection.clone()).or_default() += 1;
+        }
+        for (collection, _, _) in &self.updates {
+            *outcome.nulled.entry(collection.clone()).or_default() += 1;
+        }
+        outcome
+    }
+}
+
+/// Result of a delete, with the changes made by `on_delete` actions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeleteOutcome {
+    /// Whether the requested document existed
+    pub deleted: bool,
+    /// Documents deleted by `cascade` references, per collection
+    pub cascaded: BTreeMap<String, u64>,
+    /// Documents whose reference was set to null, per collection
+    pub nulled: BTreeMap<String, u64>,
+}
+
+/// Referential integrity errors
+#[derive(Debug, thiserror::Error)]
+pub enum ReferenceError {
+    #[error("Field '{field}' references document {id}, which does not exist in collection '{target}'")]
+    Missing {
+        field: String,
+        target: String,
+        id: DocumentId,
+    },
+
+    #[error("Field '{field}' must hold the id of a document in collection '{target}'")]
+    Unresolvable { field: String, target: String },
+
+    #[error("Cannot delete document {id} from '{collection}': {count} documents in '{referencing}' reference it through '{field}'")]
+    Restricted {
+        collection: String,
+        id: DocumentId,
+        referencing: String,
+        field: String,
+        count: usize,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(target: &str, enforce: bool, on_delete: OnDelete) -> FieldDefinition {
+        FieldDefinition::new(FieldType::Reference { collection: target.to_string(), enforce, on_delete })
+    }
+
+    fn referencing(field: &str, target: &Document) -> Document {
+        let mut doc = Document::new();
+        doc.insert(field.to_string(), Value::String(target.id.to_string()));
+        doc
+    }
+
+    #[test]
+    fn test_rules_and_required_references() {
+        let mut posts = Schema::new();
+        posts.add_field("author".to_string(), reference("users", true, OnDelete::Cascade));
+        let mut meta = BTreeMap::new();
+        meta.insert("editor".to_string(), reference("users", false, OnDelete::SetNull));
+        posts.add_field("meta".to_string(), FieldDefinition::new(FieldType::Object { fields: meta }));
+        let rules = rules(&HashMap::from([("posts".to_string(), posts)]));
+        assert_eq!(rules.len(), 2);
+        assert!(rules.iter().any(|rule| rule.field == "meta.editor" && !rule.enforce));
+
+        let user = Document::new();
+        let post = referencing("author", &user);
+        let needed = required(&rules, "posts", &post).unwrap();
+        assert_eq!(needed.len(), 1);
+        assert_eq!(needed[0].1, user.id);
+        assert!(required(&rules, "users", &post).unwrap().is_empty());
+
+        let mut unlinked = Document::new();
+        unlinked.insert("author".to_string(), Value::Null);
+        assert!(required(&rules, "posts", &unlinked).unwrap().is_empty());
+        unlinked.insert("author".to_string(), Value::String("not-an-id".to_string()));
+        assert!(matches!(required(&rules, "posts", &unlinked), Err(ReferenceError::Unresolvable { .. })));
+    }
+
+    #[test]
+    fn test_delete_plan_follows_actions() {
+        let rules = vec![
+            ReferenceRule {
+                collection: "posts".to_string(),
+                field: "author".to_string(),
+                target: "users".to_string(),
+                enforce: true,
+                on_delete: OnDelete::Cascade,
+            },
+            ReferenceRule {
+                collection: "comments".to_string(),
+                field: "post".to_string(),
+                target: "posts".to_string(),
+                enforce: true,
+                on_delete: OnDelete::Cascade,
+            },
+            ReferenceRule {
+                collection: "teams".to_string(),
+                field: "lead".to_string(),
+                target: "users".to_string(),
+                enforce: false,
+                on_delete: OnDelete::SetNull,
+            },
+        ];
+        let user = Document::new();
+        let post = referencing("author", &user);
+        let comments = vec![referencing("post", &post), referencing("post", &post), referencing("post", &Document::new())];
+        let team = referencing("lead", &user);
+        let collections = HashMap::from([
+            ("posts".to_string(), vec![post.clone()]),
+            ("comments".to_string(), comments),
+            ("teams".to_string(), vec![team.clone()]),
+        ]);
+
+        let plan = DeletePlan::build(&rules, "users", user.clone(), |name| Ok(collections[name].clone())).unwrap();
+        assert_eq!(plan.deletes[0].1.id, user.id);
+        assert_eq!(plan.deletes.len(), 4);
+        assert_eq!(plan.updates.len(), 1);
+        assert_eq!(plan.updates[0].2.get("lead"), Some(&Value::Null));
+        let outcome = plan.outcome();
+        assert!(outcome.deleted);
+        assert_eq!(outcome.cascaded, BTreeMap::from([("comments".to_string(), 2), ("posts".to_string(), 1)]));
+        assert_eq!(outcome.nulled, BTreeMap::from([("teams".to_string(), 1)]));
+    }
+
+    #[test]
+    fn test_delete_plan_restrict() {
+        let mut rules = vec![ReferenceRule {
+            collection: "orders".to_string(),
+            field: "customer".to_string(),
+            target: "customers".to_string(),
+            enforce: true,
+            on_delete: OnDelete::Restrict,
+        }];
+        let customer = Document::new();
+        let orders = vec![referencing("customer", &customer), referencing("customer", &customer)];
+
+        let err = DeletePlan::build(&rules, "customers", customer.clone(), |_| Ok(orders.clone())).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ReferenceError>(),
+            Some(ReferenceError::Restricted { count: 2, .. })
+        ));
+
+        // Restricting documents removed by the same cascade do not block it
+        rules.push(ReferenceRule {
+            collection: "orders".to_string(),
+            field: "customer".to_string(),
+            target: "customers".to_string(),
+            enforce: false,
+            on_delete: OnDelete::Cascade,
+        });
+        let plan = DeletePlan::build(&rules, "customers", customer, |_| Ok(orders.clone())).unwrap();
+        assert_eq!(plan.deletes.len(), 3);
+    }
+}
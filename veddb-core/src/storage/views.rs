@@ -0,0 +1,1114 @@
+//! Views over aggregation pipelines for VedDB v0.2.0
+//!
+//! A view exposes the output of an aggregation pipeline under its own name:
+//! - Plain views re-run the pipeline against the source collection on every read
+//! - Materialized views keep their result set in memory and are maintained
+//!   incrementally by the hybrid storage engine as source documents change
+//!
+//! Only pipelines made of `$match`/`$project` stages, optionally followed by a
+//! single `$group` with decomposable accumulators (`$sum`, `$count`, `$avg`,
+//! `$min`, `$max`), can be materialized.
+
+use crate::aggregation::{AggregateOp, AggregationError, Pipeline, PipelineStage};
+use crate::document::{Decimal128, Document, DocumentId, Value};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+/// How a view produces its documents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ViewKind {
+    /// Pipeline is re-run on every read
+    Plain,
+    /// Result set is stored and maintained on every source write
+    Materialized,
+}
+
+/// View definition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ViewDefinition {
+    /// View name, queryable like a collection
+    pub name: String,
+    /// Source collection (or, for plain views, another view)
+    pub source: String,
+    /// Aggregation pipeline applied to the source
+    pub pipeline: Vec<PipelineStage>,
+    /// View kind
+    pub kind: ViewKind,
+}
+
+impl ViewDefinition {
+    /// Create a plain view definition
+    pub fn plain(name: String, source: String, pipeline: Vec<PipelineStage>) -> Self {
+        Self {
+            name,
+            source,
+            pipeline,
+            kind: ViewKind::Plain,
+        }
+    }
+
+    /// Create a materialized view definition
+    pub fn materialized(name: String, source: String, pipeline: Vec<PipelineStage>) -> Self {
+        Self {
+            name,
+            source,
+            pipeline,
+            kind: ViewKind::Materialized,
+        }
+    }
+
+    /// Check that the definition is well formed for its kind
+    pub fn validate(&self) -> Result<(), ViewError> {
+        if self.name.is_empty() || self.source.is_empty() {
+            return Err(ViewError::InvalidDefinition(
+                "view name and source must not be empty".to_string(),
+            ));
+        }
+        if self.name == self.source {
+            return Err(ViewError::InvalidDefinition(format!(
+                "view '{}' cannot use itself as source",
+                self.name
+            )));
+        }
+        // A view's pipeline runs over documents, never over an index
+        if self.pipeline.iter().any(|stage| matches!(stage, PipelineStage::VectorSearch(_))) {
+            return Err(ViewError::InvalidDefinition(
+                "$vectorSearch cannot be used in a view".to_string(),
+            ));
+        }
+        if self.kind == ViewKind::Materialized {
+            MaterializedView::split_pipeline(&self.pipeline)?;
+        }
+        Ok(())
+    }
+}
+
+/// Registry of views with the incremental state of materialized views
+pub struct ViewRegistry {
+    views: RwLock<HashMap<String, RegisteredView>>,
+    /// Held while materialized state is loaded from its source, so loads run
+    /// one at a time without holding `views`
+    builds: Mutex<()>,
+}
+
+struct RegisteredView {
+    definition: ViewDefinition,
+    state: Option<MaterializedView>,
+    /// Set when a source write could not be applied; the state is rebuilt
+    /// from the source before the next read
+    stale: bool,
+    /// Source changes seen while the state is loaded, replayed on top of it
+    pending: Option<Vec<SourceChange>>,
+    /// False until the initial load finished; such views are not listed
+    created: bool,
+}
+
+/// Source change recorded while a materialized view is loaded
+enum SourceChange {
+    Write(DocumentId, Document),
+    Delete(DocumentId),
+    Reset,
+}
+
+impl RegisteredView {
+    /// Install a loaded state after replaying the changes recorded meanwhile
+    fn finish_build(&mut self, mut state: MaterializedView) -> Result<(), ViewError> {
+        for change in self.pending.take().unwrap_or_default() {
+            match change {
+                SourceChange::Write(doc_id, doc) => state.apply_write(doc_id, &doc)?,
+                SourceChange::Delete(doc_id) => state.apply_delete(doc_id),
+                SourceChange::Reset => state.clear(),
+            }
+        }
+        self.state = Some(state);
+        self.stale = false;
+        self.created = true;
+        Ok(())
+    }
+}
+
+impl ViewRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self {
+            views: RwLock::new(HashMap::new()),
+            builds: Mutex::new(()),
+        }
+    }
+
+    /// Register a view.
+    ///
+    /// For materialized views `load_source` is called with the source name to
+    /// build the initial result set. The view is registered as stale first and
+    /// the source is read without holding the registry lock; writes applied
+    /// meanwhile are recorded and replayed on top of the loaded state. The
+    /// engine applies writes to one document under that document's lock, so
+    /// they are recorded in the order they were stored.
+    pub fn create<F>(&self, definition: ViewDefinition, load_source: F) -> Result<(), ViewError>
+    where
+        F: FnOnce(&str) -> anyhow::Result<Vec<Document>>,
+    {
+        definition.validate()?;
+
+        let _build = self.builds.lock();
+        let mut views = self.views.write();
+        if views.contains_key(&definition.name) {
+            return Err(ViewError::AlreadyExists(definition.name));
+        }
+
+        // Follow the chain of plain views to reject cycles
+        let mut current = definition.source.clone();
+        while let Some(view) = views.get(&current) {
+            if view.definition.source == definition.name {
+                return Err(ViewError::InvalidDefinition(format!(
+                    "view '{}' would form a cycle through '{}'",
+                    definition.name, current
+                )));
+            }
+            current = view.definition.source.clone();
+        }
+
+        let materialized = definition.kind == ViewKind::Materialized;
+        if materialized && views.contains_key(&definition.source) {
+            return Err(ViewError::InvalidDefinition(format!(
+                "materialized view '{}' must read from a collection, '{}' is a view",
+                definition.name, definition.source
+            )));
+        }
+
+        let name = definition.name.clone();
+        let (source, pipeline) = (definition.source.clone(), definition.pipeline.clone());
+        views.insert(
+            name.clone(),
+            RegisteredView {
+                definition,
+                state: None,
+                stale: materialized,
+                pending: materialized.then(Vec::new),
+                created: !materialized,
+            },
+        );
+        drop(views);
+        if !materialized {
+            return Ok(());
+        }
+
+        let loaded = Self::load(&source, &pipeline, load_source);
+        let mut views = self.views.write();
+        // Dropped while loading
+        let Some(view) = views.get_mut(&name) else {
+            return Err(ViewError::NotFound(name));
+        };
+        let result = loaded.and_then(|state| view.finish_build(state));
+        if result.is_err() {
+            views.remove(&name);
+        }
+        result
+    }
+
+    /// Build materialized state from the current source documents
+    fn load<F>(source: &str, pipeline: &[PipelineStage], load_source: F) -> Result<MaterializedView, ViewError>
+    where
+        F: FnOnce(&str) -> anyhow::Result<Vec<Document>>,
+    {
+        let documents = load_source(source).map_err(|e| ViewError::SourceUnavailable(e.to_string()))?;
+        let mut state = MaterializedView::new(pipeline)?;
+        for doc in &documents {
+            state.apply_write(doc.id, doc)?;
+        }
+        Ok(state)
+    }
+
+    /// Remove a view, returning whether it existed
+    pub fn remove(&self, name: &str) -> bool {
+        self.views.write().remove(name).is_some()
+    }
+
+    /// Check whether a name refers to a view
+    pub fn is_view(&self, name: &str) -> bool {
+        self.views.read().contains_key(name)
+    }
+
+    /// Get a view definition
+    pub fn definition(&self, name: &str) -> Option<ViewDefinition> {
+        self.views.read().get(name).map(|v| v.definition.clone())
+    }
+
+    /// List all view definitions, ordered by name
+    ///
+    /// Views still loading their initial state are left out.
+    pub fn definitions(&self) -> Vec<ViewDefinition> {
+        let mut defs: Vec<ViewDefinition> = self
+            .views
+            .read()
+            .values()
+            .filter(|v| v.created)
+            .map(|v| v.definition.clone())
+            .collect();
+        defs.sort_by(|a, b| a.name.cmp(&b.name));
+        defs
+    }
+
+    /// Current result set of a materialized view, or `None` while it is stale
+    pub fn materialized_rows(&self, name: &str) -> Option<Vec<Document>> {
+        self.views
+            .read()
+            .get(name)
+            .filter(|v| !v.stale)
+            .and_then(|v| v.state.as_ref())
+            .map(|state| state.rows())
+    }
+
+    /// Whether a materialized view missed a source write and must be rebuilt
+    pub fn is_stale(&self, name: &str) -> bool {
+        self.views.read().get(name).is_some_and(|v| v.stale)
+    }
+
+    /// Rebuild a stale materialized view from its source
+    ///
+    /// Like [`create`](Self::create), `load_source` runs without the registry
+    /// lock and writes applied meanwhile are replayed on top of its result.
+    /// Callers that find the view being loaded wait for that load instead.
+    pub fn rebuild_stale<F>(&self, name: &str, load_source: F) -> Result<(), ViewError>
+    where
+        F: FnOnce(&str) -> anyhow::Result<Vec<Document>>,
+    {
+        let _build = self.builds.lock();
+        let (source, pipeline) = {
+            let mut views = self.views.write();
+            let Some(view) = views.get_mut(name).filter(|v| v.stale) else {
+                return Ok(());
+            };
+            view.pending = Some(Vec::new());
+            (view.definition.source.clone(), view.definition.pipeline.clone())
+        };
+
+        let loaded = Self::load(&source, &pipeline, load_source);
+        let mut views = self.views.write();
+        let Some(view) = views.get_mut(name) else {
+            return Ok(());
+        };
+        match loaded {
+            Ok(state) => view.finish_build(state),
+            Err(e) => {
+                view.pending = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Apply an inserted or replaced source document to dependent materialized views
+    ///
+    /// A view the write cannot be applied to is marked stale, its state is
+    /// dropped, and the first such error is returned once every other view
+    /// has been updated.
+    pub fn apply_write(&self, source: &str, doc_id: DocumentId, doc: &Document) -> Result<(), ViewError> {
+        let mut views = self.views.write();
+        let mut failure = None;
+        for view in views.values_mut().filter(|v| v.definition.source == source) {
+            if let Some(pending) = view.pending.as_mut() {
+                pending.push(SourceChange::Write(doc_id, doc.clone()));
+                continue;
+            }
+            if view.stale {
+                continue;
+            }
+            let Some(state) = view.state.as_mut() else {
+                continue;
+            };
+            if let Err(e) = state.apply_write(doc_id, doc) {
+                view.state = None;
+                view.stale = true;
+                failure.get_or_insert(ViewError::Stale { view: view.definition.name.clone(), reason: e.to_string() });
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Apply a deleted source document to dependent materialized views
+    pub fn apply_delete(&self, source: &str, doc_id: DocumentId) {
+        let mut views = self.views.write();
+        for view in views.values_mut().filter(|v| v.definition.source == source) {
+            if let Some(pending) = view.pending.as_mut() {
+                pending.push(SourceChange::Delete(doc_id));
+            } else if let Some(state) = view.state.as_mut() {
+                state.apply_delete(doc_id);
+            }
+        }
+    }
+
+    /// Clear materialized state for views over a dropped collection
+    pub fn reset_source(&self, source: &str) {
+        let mut views = self.views.write();
+        for view in views.values_mut().filter(|v| v.definition.source == source) {
+            if let Some(pending) = view.pending.as_mut() {
+                pending.push(SourceChange::Reset);
+            } else if let Some(state) = view.state.as_mut() {
+                state.clear();
+            }
+        }
+    }
+}
+
+impl Default for ViewRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Incrementally maintained result set of a materialized view
+struct MaterializedView {
+    /// `$match`/`$project` stages applied to each source document
+    per_document: Pipeline,
+    /// Trailing `$group` stage, if any
+    group: Option<GroupSpec>,
+    /// Source document id -> what the view keeps of it
+    members: HashMap<DocumentId, Member>,
+    /// Group key -> running aggregates
+    groups: HashMap<String, GroupState>,
+}
+
+/// Part of a source document a materialized view keeps
+enum Member {
+    /// Output row of a view without `$group`
+    Row(Document),
+    /// Group key and accumulator inputs, all that is needed to take the
+    /// document back out of its group
+    Grouped { key: String, inputs: HashMap<String, Value> },
+}
+
+struct GroupSpec {
+    id: serde_json::Value,
+    accumulators: HashMap<String, AggregateOp>,
+}
+
+impl GroupSpec {
+    /// Accumulator inputs of a document, keyed by output field
+    fn inputs(&self, doc: &Document) -> HashMap<String, Value> {
+        self.accumulators
+            .iter()
+            .filter_map(|(field, op)| Some((field.clone(), accumulator_input(op, doc)?.clone())))
+            .collect()
+    }
+}
+
+impl MaterializedView {
+    fn new(pipeline: &[PipelineStage]) -> Result<Self, ViewError> {
+        let (per_document, group) = Self::split_pipeline(pipeline)?;
+        Ok(Self {
+            per_document: Pipeline::new(per_document),
+            group,
+            members: HashMap::new(),
+            groups: HashMap::new(),
+        })
+    }
+
+    /// Split a pipeline into per-document stages and a trailing group
+    fn split_pipeline(
+        pipeline: &[PipelineStage],
+    ) -> Result<(Vec<PipelineStage>, Option<GroupSpec>), ViewError> {
+        let mut per_document = Vec::new();
+        let mut group = None;
+
+        for stage in pipeline {
+            if group.is_some() {
+                return Err(ViewError::NotMaterializable(
+                    "$group must be the last stage".to_string(),
+                ));
+            }
+            match stage {
+                PipelineStage::Match { .. } | PipelineStage::Project { .. } => {
+                    per_document.push(stage.clone());
+                }
+                PipelineStage::Group { _id, fields } => {
+                    group = Some(GroupSpec {
+                        id: _id.clone(),
+                        accumulators: fields.clone(),
+                    });
+                }
+                PipelineStage::Sort { .. } => {
+                    return Err(ViewError::NotMaterializable("$sort".to_string()))
+                }
+                PipelineStage::Limit { .. } => {
+                    return Err(ViewError::NotMaterializable("$limit".to_string()))
+                }
+                PipelineStage::Skip { .. } => {
+                    return Err(ViewError::NotMaterializable("$skip".to_string()))
+                }
+                PipelineStage::VectorSearch(_) => {
+                    return Err(ViewError::NotMaterializable("$vectorSearch".to_string()))
+                }
+            }
+        }
+
+        Ok((per_document, group))
+    }
+
+    fn apply_write(&mut self, doc_id: DocumentId, doc: &Document) -> Result<(), ViewError> {
+        self.apply_delete(doc_id);
+
+        let mut output = self.per_document.execute(vec![doc.clone()])?;
+        let Some(mut transformed) = output.pop() else {
+            return Ok(());
+        };
+        transformed.id = doc_id;
+
+        let member = match &self.group {
+            Some(group) => {
+                let key = Pipeline::extract_group_key(&transformed, &group.id);
+                let inputs = group.inputs(&transformed);
+                self.groups
+                    .entry(key.clone())
+                    .or_insert_with(GroupState::new)
+                    .add(&group.accumulators, &inputs);
+                Member::Grouped { key, inputs }
+            }
+            None => Member::Row(transformed),
+        };
+        self.members.insert(doc_id, member);
+        Ok(())
+    }
+
+    fn apply_delete(&mut self, doc_id: DocumentId) {
+        let (Some(Member::Grouped { key, inputs }), Some(group)) = (self.members.remove(&doc_id), &self.group)
+        else {
+            return;
+        };
+        if let Some(state) = self.groups.get_mut(&key) {
+            state.remove(&group.accumulators, &inputs);
+            if state.members == 0 {
+                self.groups.remove(&key);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.members.clear();
+        self.groups.clear();
+    }
+
+    fn rows(&self) -> Vec<Document> {
+        match &self.group {
+            Some(group) => self
+                .groups
+                .iter()
+                .map(|(key, state)| state.to_document(key, &group.accumulators))
+                .collect(),
+            None => self
+                .members
+                .values()
+                .filter_map(|member| match member {
+                    Member::Row(doc) => Some(doc.clone()),
+                    Member::Grouped { .. } => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Running aggregates for one group
+struct GroupState {
+    /// Stable id of the output row
+    id: DocumentId,
+    /// Number of source documents in the group
+    members: usize,
+    tallies: HashMap<String, Tally>,
+}
+
+/// Decomposable accumulator state; min/max keep a multiset so deletes can be undone
+///
+/// Like the aggregation engine, results switch to exact decimals while a
+/// decimal input is present and every input has a decimal value.
+#[derive(Default)]
+struct Tally {
+    sum: f64,
+    count: usize,
+    values: BTreeMap<TotalF64, usize>,
+    exact_sum: Decimal128,
+    exact_values: BTreeMap<Decimal128, usize>,
+    /// Inputs that were decimals
+    decimals: usize,
+    /// Inputs without a decimal value (infinite or NaN floats)
+    inexact: usize,
+}
+
+impl Tally {
+    fn add_exact(&mut self, value: &Value, extremes: bool) {
+        self.decimals += usize::from(matches!(value, Value::Decimal128(_)));
+        match exact_input(value) {
+            Some(d) if extremes => *self.exact_values.entry(d).or_insert(0) += 1,
+            Some(d) => match self.exact_sum.checked_add(d) {
+                Ok(sum) => self.exact_sum = sum,
+                Err(_) => self.inexact += 1,
+            },
+            None => self.inexact += 1,
+        }
+    }
+
+    fn remove_exact(&mut self, value: &Value, extremes: bool) {
+        self.decimals = self.decimals.saturating_sub(usize::from(matches!(value, Value::Decimal128(_))));
+        match exact_input(value) {
+            Some(d) if extremes => {
+                if let Some(occurrences) = self.exact_values.get_mut(&d) {
+                    *occurrences -= 1;
+                    if *occurrences == 0 {
+                        self.exact_values.remove(&d);
+                    }
+                }
+            }
+            Some(d) => match self.exact_sum.checked_sub(d) {
+                Ok(sum) => self.exact_sum = sum,
+                Err(_) => self.inexact = self.inexact.saturating_sub(1),
+            },
+            None => self.inexact = self.inexact.saturating_sub(1),
+        }
+    }
+
+    /// Whether the output should be the exact decimal
+    fn is_decimal(&self) -> bool {
+        self.decimals > 0 && self.inexact == 0
+    }
+}
+
+impl GroupState {
+    fn new() -> Self {
+        Self {
+            id: DocumentId::new(),
+            members: 0,
+            tallies: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, accumulators: &HashMap<String, AggregateOp>, inputs: &HashMap<String, Value>) {
+        self.members += 1;
+        for (field, op) in accumulators {
+            let tally = self.tallies.entry(field.clone()).or_default();
+            match op {
+                AggregateOp::Count {} => tally.count += 1,
+                AggregateOp::Sum(_) | AggregateOp::Avg(_) => {
+                    if let Some(value) = inputs.get(field) {
+                        tally.sum += value.as_f64().unwrap_or(0.0);
+                        tally.count += 1;
+                        tally.add_exact(value, false);
+                    }
+                }
+                AggregateOp::Min(_) | AggregateOp::Max(_) => {
+                    if let Some(value) = inputs.get(field) {
+                        *tally.values.entry(TotalF64(value.as_f64().unwrap_or(0.0))).or_insert(0) += 1;
+                        tally.add_exact(value, true);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, accumulators: &HashMap<String, AggregateOp>, inputs: &HashMap<String, Value>) {
+        self.members = self.members.saturating_sub(1);
+        for (field, op) in accumulators {
+            let Some(tally) = self.tallies.get_mut(field) else {
+                continue;
+            };
+            match op {
+                AggregateOp::Count {} => tally.count = tally.count.saturating_sub(1),
+                AggregateOp::Sum(_) | AggregateOp::Avg(_) => {
+                    if let Some(value) = inputs.get(field) {
+                        tally.sum -= value.as_f64().unwrap_or(0.0);
+                        tally.count = tally.count.saturating_sub(1);
+                        tally.remove_exact(value, false);
+                    }
+                }
+                AggregateOp::Min(_) | AggregateOp::Max(_) => {
+                    if let Some(value) = inputs.get(field) {
+                        tally.remove_exact(value, true);
+                        let key = TotalF64(value.as_f64().unwrap_or(0.0));
+                        if let Some(occurrences) = tally.values.get_mut(&key) {
+                            *occurrences -= 1;
+                            if *occurrences == 0 {
+                                tally.values.remove(&key);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Build the output row in the same shape as `Pipeline::execute` produces
+    fn to_document(&self, key: &str, accumulators: &HashMap<String, AggregateOp>) -> Document {
+        let mut doc = Document::with_id(self.id);
+        doc.insert("_id".to_string(), Value::String(key.to_string()));
+
+        for (field, op) in accumulators {
+            let tally = self.tallies.get(field);
+            let exact = tally.filter(|t| t.is_decimal()).and_then(|t| match op {
+                AggregateOp::Sum(_) => Some(t.exact_sum),
+                AggregateOp::Avg(_) => t.exact_sum.checked_div(Decimal128::from(t.count as i64)).ok(),
+                AggregateOp::Min(_) => t.exact_values.keys().next().copied(),
+                AggregateOp::Max(_) => t.exact_values.keys().next_back().copied(),
+                AggregateOp::Count {} => None,
+            });
+            if let Some(exact) = exact {
+                doc.insert(field.clone(), Value::Decimal128(exact));
+                continue;
+            }
+            let value = match op {
+                AggregateOp::Sum(_) => Value::Float64(tally.map_or(0.0, |t| t.sum)),
+                AggregateOp::Count {} => Value::Int64(tally.map_or(0, |t| t.count) as i64),
+                AggregateOp::Avg(_) => match tally {
+                    Some(t) if t.count > 0 => Value::Float64(t.sum / t.count as f64),
+                    _ => Value::Float64(0.0),
+                },
+                AggregateOp::Min(_) => Value::Float64(
+                    tally
+                        .and_then(|t| t.values.keys().next())
+                        .map_or(0.0, |v| v.0),
+                ),
+                AggregateOp::Max(_) => Value::Float64(
+                    tally
+                        .and_then(|t| t.values.keys().next_back())
+                        .map_or(0.0, |v| v.0),
+                ),
+            };
+            doc.insert(field.clone(), value);
+        }
+
+        doc
+    }
+}
+
+/// Input of an accumulator for a document, matching the aggregation engine
+fn accumulator_input<'a>(op: &AggregateOp, doc: &'a Document) -> Option<&'a Value> {
+    let value = match op {
+        AggregateOp::Sum(field_ref) => {
+            let field = field_ref.as_str()?.strip_prefix('$')?;
+            doc.get(field)?
+        }
+        AggregateOp::Avg(field) | AggregateOp::Min(field) | AggregateOp::Max(field) => {
+            doc.get(field)?
+        }
+        AggregateOp::Count {} => return None,
+    };
+    Some(value)
+}
+
+/// Exact value of an accumulator input; non-numbers count as zero
+fn exact_input(value: &Value) -> Option<Decimal128> {
+    if value.is_number() {
+        value.as_decimal()
+    } else {
+        Some(Decimal128::ZERO)
+    }
+}
+
+/// f64 with a total order for min/max multisets
+#[derive(Debug, Clone, Copy)]
+struct TotalF64(f64);
+
+impl PartialEq for TotalF64 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TotalF64 {}
+
+impl PartialOrd for TotalF64 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TotalF64 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// View errors
+#[derive(Debug, thiserror::Error)]
+pub enum ViewError {
+    #[error("View already exists: {0}")]
+    AlreadyExists(String),
+
+    #[error("View not found: {0}")]
+    NotFound(String),
+
+    #[error("Invalid view definition: {0}")]
+    InvalidDefinition(String),
+
+    #[error("Pipeline cannot be materialized: {0}")]
+    NotMaterializable(String),
+
+    #[error("Failed to load view source: {0}")]
+    SourceUnavailable(String),
+
+    #[error("View '{view}' is stale until rebuilt: {reason}")]
+    Stale { view: String, reason: String },
+
+    #[error("Aggregation error: {0}")]
+    Aggregation(#[from] AggregationError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(category: &str, amount: i64) -> Document {
+        let mut doc = Document::new();
+        doc.insert("category".to_string(), Value::String(category.to_string()));
+        doc.insert("amount".to_string(), Value::Int64(amount));
+        doc
+    }
+
+    fn totals_pipeline() -> Vec<PipelineStage> {
+        let mut fields = HashMap::new();
+        fields.insert("total".to_string(), AggregateOp::Sum(serde_json::json!("$amount")));
+        fields.insert("orders".to_string(), AggregateOp::Count {});
+        fields.insert("smallest".to_string(), AggregateOp::Min("amount".to_string()));
+        fields.insert("largest".to_string(), AggregateOp::Max("amount".to_string()));
+        vec![
+            PipelineStage::Match {
+                filter: serde_json::json!({"amount": {"$gt": 0}}),
+            },
+            PipelineStage::Group {
+                _id: serde_json::json!("$category"),
+                fields,
+            },
+        ]
+    }
+
+    fn row<'a>(rows: &'a [Document], key: &str) -> Option<&'a Document> {
+        let key = format!("{:?}", Value::String(key.to_string()));
+        rows.iter().find(|d| d.get("_id") == Some(&Value::String(key.clone())))
+    }
+
+    #[test]
+    fn test_materialized_group_matches_pipeline() {
+        let docs = vec![order("books", 10), order("books", 30), order("games", 5), order("games", -1)];
+        let registry = ViewRegistry::new();
+        let source = docs.clone();
+        registry
+            .create(
+                ViewDefinition::materialized("totals".to_string(), "orders".to_string(), totals_pipeline()),
+                move |_| Ok(source),
+            )
+            .unwrap();
+
+        let rows = registry.materialized_rows("totals").unwrap();
+        let expected = Pipeline::new(totals_pipeline()).execute(docs).unwrap();
+        assert_eq!(rows.len(), expected.len());
+        for exp in &expected {
+            let actual = rows.iter().find(|r| r.get("_id") == exp.get("_id")).unwrap();
+            assert_eq!(actual.fields, exp.fields);
+        }
+    }
+
+    #[test]
+    fn test_stale_view_is_rebuilt_from_source() {
+        let docs = vec![order("books", 10), order("books", 30)];
+        let registry = ViewRegistry::new();
+        registry
+            .create(
+                ViewDefinition::materialized("totals".to_string(), "orders".to_string(), totals_pipeline()),
+                |_| Ok(Vec::new()),
+            )
+            .unwrap();
+        registry.apply_write("orders", docs[0].id, &docs[0]).unwrap();
+
+        // As left by a write the view could not take
+        if let Some(view) = registry.views.write().get_mut("totals") {
+            view.state = None;
+            view.stale = true;
+        }
+        assert!(registry.is_stale("totals"));
+        assert!(registry.materialized_rows("totals").is_none());
+        registry.apply_write("orders", docs[1].id, &docs[1]).unwrap();
+
+        let source = docs.clone();
+        registry.rebuild_stale("totals", move |_| Ok(source)).unwrap();
+        assert!(!registry.is_stale("totals"));
+        let rows = registry.materialized_rows("totals").unwrap();
+        assert_eq!(row(&rows, "books").unwrap().get("total"), Some(&Value::Float64(40.0)));
+
+        // A fresh view is not reloaded
+        registry.rebuild_stale("totals", |_| panic!("fresh view reloaded")).unwrap();
+    }
+
+    #[test]
+    fn test_writes_during_initial_load_are_replayed() {
+        let docs = vec![order("books", 10), order("books", 30), order("games", 5)];
+        let registry = ViewRegistry::new();
+        let added = order("games", 20);
+        let mut changed = order("books", 15);
+        changed.id = docs[0].id;
+
+        let source = docs.clone();
+        registry
+            .create(
+                ViewDefinition::materialized("totals".to_string(), "orders".to_string(), totals_pipeline()),
+                |_| {
+                    // The registry stays usable while the source is read
+                    assert!(registry.is_view("totals"));
+                    assert!(registry.definitions().is_empty());
+                    registry.apply_write("orders", added.id, &added).unwrap();
+                    registry.apply_write("orders", changed.id, &changed).unwrap();
+                    registry.apply_delete("orders", docs[1].id);
+                    Ok(source)
+                },
+            )
+            .unwrap();
+
+        assert_eq!(registry.definitions().len(), 1);
+        let rows = registry.materialized_rows("totals").unwrap();
+        let expected = Pipeline::new(totals_pipeline())
+            .execute(vec![changed.clone(), docs[2].clone(), added.clone()])
+            .unwrap();
+        assert_eq!(rows.len(), expected.len());
+        for exp in &expected {
+            let actual = rows.iter().find(|r| r.get("_id") == exp.get("_id")).unwrap();
+            assert_eq!(actual.fields, exp.fields);
+        }
+    }
+
+    #[test]
+    fn test_writes_during_rebuild_are_replayed() {
+        let docs = vec![order("books", 10), order("books", 30)];
+        let registry = ViewRegistry::new();
+        registry
+            .create(
+                ViewDefinition::materialized("totals".to_string(), "orders".to_string(), totals_pipeline()),
+                |_| Ok(Vec::new()),
+            )
+            .unwrap();
+        if let Some(view) = registry.views.write().get_mut("totals") {
+            view.state = None;
+            view.stale = true;
+        }
+
+        let added = order("books", 5);
+        let source = docs.clone();
+        registry
+            .rebuild_stale("totals", |_| {
+                registry.apply_write("orders", added.id, &added).unwrap();
+                Ok(source)
+            })
+            .unwrap();
+        let rows = registry.materialized_rows("totals").unwrap();
+        assert_eq!(row(&rows, "books").unwrap().get("total"), Some(&Value::Float64(45.0)));
+
+        // A collection dropped during the load discards what was read before the drop
+        if let Some(view) = registry.views.write().get_mut("totals") {
+            view.state = None;
+            view.stale = true;
+        }
+        let source = docs.clone();
+        registry
+            .rebuild_stale("totals", |_| {
+                registry.reset_source("orders");
+                registry.apply_write("orders", added.id, &added).unwrap();
+                Ok(source)
+            })
+            .unwrap();
+        let rows = registry.materialized_rows("totals").unwrap();
+        assert_eq!(row(&rows, "books").unwrap().get("total"), Some(&Value::Float64(5.0)));
+    }
+
+    #[test]
+    fn test_materialized_group_incremental_maintenance() {
+        let registry = ViewRegistry::new();
+        registry
+            .create(
+                ViewDefinition::materialized("totals".to_string(), "orders".to_string(), totals_pipeline()),
+                |_| Ok(Vec::new()),
+            )
+            .unwrap();
+
+        let small = order("books", 10);
+        let large = order("books", 40);
+        registry.apply_write("orders", small.id, &small).unwrap();
+        registry.apply_write("orders", large.id, &large).unwrap();
+        // Writes to other collections are ignored
+        registry.apply_write("other", DocumentId::new(), &order("books", 99)).unwrap();
+
+        let rows = registry.materialized_rows("totals").unwrap();
+        let books = row(&rows, "books").unwrap();
+        assert_eq!(books.get("total"), Some(&Value::Float64(50.0)));
+        assert_eq!(books.get("orders"), Some(&Value::Int64(2)));
+        assert_eq!(books.get("smallest"), Some(&Value::Float64(10.0)));
+        assert_eq!(books.get("largest"), Some(&Value::Float64(40.0)));
+
+        // Deleting the maximum restores the previous maximum
+        registry.apply_delete("orders", large.id);
+        let rows = registry.materialized_rows("totals").unwrap();
+        let books = row(&rows, "books").unwrap();
+        assert_eq!(books.get("total"), Some(&Value::Float64(10.0)));
+        assert_eq!(books.get("largest"), Some(&Value::Float64(10.0)));
+
+        // Updating a document moves it between groups
+        let moved = {
+            let mut doc = order("games", 7);
+            doc.id = small.id;
+            doc
+        };
+        registry.apply_write("orders", small.id, &moved).unwrap();
+        let rows = registry.materialized_rows("totals").unwrap();
+        assert!(row(&rows, "books").is_none());
+        assert_eq!(row(&rows, "games").unwrap().get("total"), Some(&Value::Float64(7.0)));
+
+        // Updating a document so it no longer matches removes its contribution
+        let filtered = {
+            let mut doc = order("games", -3);
+            doc.id = small.id;
+            doc
+        };
+        registry.apply_write("orders", small.id, &filtered).unwrap();
+        assert!(registry.materialized_rows("totals").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_grouped_view_keeps_only_group_inputs() {
+        let registry = ViewRegistry::new();
+        registry
+            .create(
+                ViewDefinition::materialized("totals".to_string(), "orders".to_string(), totals_pipeline()),
+                |_| Ok(Vec::new()),
+            )
+            .unwrap();
+
+        let mut doc = order("books", 10);
+        doc.insert("notes".to_string(), Value::String("x".repeat(4096)));
+        registry.apply_write("orders", doc.id, &doc).unwrap();
+
+        let views = registry.views.read();
+        let state = views["totals"].state.as_ref().unwrap();
+        let Some(Member::Grouped { key, inputs }) = state.members.get(&doc.id) else {
+            panic!("grouped view kept a full row");
+        };
+        assert_eq!(key, &format!("{:?}", Value::String("books".to_string())));
+        let mut fields: Vec<&str> = inputs.keys().map(String::as_str).collect();
+        fields.sort_unstable();
+        assert_eq!(fields, ["largest", "smallest", "total"]);
+        assert!(inputs.values().all(|v| v == &Value::Int64(10)));
+    }
+
+    #[test]
+    fn test_materialized_decimal_totals_match_pipeline() {
+        let priced = |category: &str, amount: &str| {
+            let mut doc = order(category, 0);
+            doc.insert("amount".to_string(), Value::Decimal128(amount.parse().unwrap()));
+            doc
+        };
+        let registry = ViewRegistry::new();
+        registry
+            .create(
+                ViewDefinition::materialized("totals".to_string(), "orders".to_string(), totals_pipeline()),
+                |_| Ok(Vec::new()),
+            )
+            .unwrap();
+
+        let docs = vec![priced("books", "0.10"), priced("books", "0.20"), order("books", 3)];
+        for doc in &docs {
+            registry.apply_write("orders", doc.id, doc).unwrap();
+        }
+        let rows = registry.materialized_rows("totals").unwrap();
+        let expected = Pipeline::new(totals_pipeline()).execute(docs.clone()).unwrap();
+        assert_eq!(row(&rows, "books").unwrap().fields, expected[0].fields);
+        assert_eq!(row(&rows, "books").unwrap().get("total"), Some(&Value::Decimal128("3.30".parse().unwrap())));
+
+        // Once the decimals are gone the totals are floats again
+        registry.apply_delete("orders", docs[0].id);
+        registry.apply_delete("orders", docs[1].id);
+        let rows = registry.materialized_rows("totals").unwrap();
+        let books = row(&rows, "books").unwrap();
+        assert!(matches!(books.get("total"), Some(Value::Float64(total)) if (total - 3.0).abs() < 1e-9));
+        assert_eq!(books.get("smallest"), Some(&Value::Float64(3.0)));
+    }
+
+    #[test]
+    fn test_definition_round_trips_through_json() {
+        let definition =
+            ViewDefinition::materialized("totals".to_string(), "orders".to_string(), totals_pipeline());
+        let json = serde_json::to_string(&definition).unwrap();
+        let decoded: ViewDefinition = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.kind, ViewKind::Materialized);
+        assert!(decoded.validate().is_ok());
+
+        let count: AggregateOp = serde_json::from_str(r#"{"$":"count"}"#).unwrap();
+        assert!(matches!(count, AggregateOp::Count {}));
+    }
+
+    #[test]
+    fn test_materialized_projection_rows() {
+        let mut fields = HashMap::new();
+        fields.insert("category".to_string(), 1);
+        let pipeline = vec![PipelineStage::Project { fields }];
+        let registry = ViewRegistry::new();
+        registry
+            .create(
+                ViewDefinition::materialized("names".to_string(), "orders".to_string(), pipeline),
+                |_| Ok(Vec::new()),
+            )
+            .unwrap();
+
+        let doc = order("books", 10);
+        registry.apply_write("orders", doc.id, &doc).unwrap();
+        let rows = registry.materialized_rows("names").unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].id, doc.id);
+        assert!(rows[0].get("amount").is_none());
+
+        registry.apply_delete("orders", doc.id);
+        assert!(registry.materialized_rows("names").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_materialized_rejects_unsupported_stages() {
+        let registry = ViewRegistry::new();
+        let pipeline = vec![PipelineStage::Limit { count: 10 }];
+        let result = registry.create(
+            ViewDefinition::materialized("top".to_string(), "orders".to_string(), pipeline.clone()),
+            |_| Ok(Vec::new()),
+        );
+        assert!(matches!(result, Err(ViewError::NotMaterializable(_))));
+
+        // The same pipeline is fine as a plain view
+        registry
+            .create(
+                ViewDefinition::plain("top".to_string(), "orders".to_string(), pipeline),
+                |_| Ok(Vec::new()),
+            )
+            .unwrap();
+        assert!(registry.is_view("top"));
+        assert!(registry.materialized_rows("top").is_none());
+    }
+
+    #[test]
+    fn test_view_cycles_and_duplicates_rejected() {
+        let registry = ViewRegistry::new();
+        registry
+            .create(ViewDefinition::plain("a".to_string(), "b".to_string(), Vec::new()), |_| Ok(Vec::new()))
+            .unwrap();
+
+        let duplicate = registry.create(
+            ViewDefinition::plain("a".to_string(), "orders".to_string(), Vec::new()),
+            |_| Ok(Vec::new()),
+        );
+        assert!(matches!(duplicate, Err(ViewError::AlreadyExists(_))));
+
+        let cycle = registry.create(
+            ViewDefinition::plain("b".to_string(), "a".to_string(), Vec::new()),
+            |_| Ok(Vec::new()),
+        );
+        assert!(matches!(cycle, Err(ViewError::InvalidDefinition(_))));
+
+        let load_failure = registry.create(
+            ViewDefinition::materialized("c".to_string(), "orders".to_string(), Vec::new()),
+            |_| Err(anyhow::anyhow!("disk unavailable")),
+        );
+        assert!(matches!(load_failure, Err(ViewError::SourceUnavailable(_))));
+        assert!(!registry.is_view("c"));
+    }
+}
@@ -66,6 +66,14 @@ pub enum Operation {
         collection: String,
         id: DocumentId,
         changes: BTreeMap<String, Value>,
+        /// Top-level fields the update removed
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        removed: Vec<String>,
+    },
+    /// Replace an existing document as a whole
+    Replace {
+        collection: String,
+        doc: Document,
     },
     /// Delete a document
     Delete {
@@ -99,6 +107,7 @@ impl Operation {
         match self {
             Operation::Insert { collection, .. } => format!("Insert into {}", collection),
             Operation::Update { collection, id, .. } => format!("Update {} in {}", id, collection),
+            Operation::Replace { collection, doc } => format!("Replace {} in {}", doc.id, collection),
             Operation::Delete { collection, id } => format!("Delete {} from {}", id, collection),
             Operation::CreateCollection { name, .. } => format!("Create collection {}", name),
             Operation::DropCollection { name } => format!("Drop collection {}", name),
@@ -88,16 +88,22 @@ pub async fn apply_operation(
         Operation::Insert { collection, doc } => {
             persistent_layer.insert_document(collection, doc.id, doc)?;
         }
-        Operation::Update { collection, id, changes } => {
+        Operation::Update { collection, id, changes, removed } => {
             // Get existing document
             if let Some(mut doc) = persistent_layer.get_document(collection, *id)? {
                 // Apply changes
                 for (key, value) in changes {
                     doc.insert(key.clone(), value.clone());
                 }
+                for key in removed {
+                    doc.remove(key);
+                }
                 persistent_layer.update_document(collection, *id, &doc)?;
             }
         }
+        Operation::Replace { collection, doc } => {
+            persistent_layer.update_document(collection, doc.id, doc)?;
+        }
         Operation::Delete { collection, id } => {
             persistent_layer.delete_document(collection, *id)?;
         }
@@ -91,16 +91,32 @@ impl WalWriter {
 
     /// Append an operation to the WAL
     pub async fn append(&self, operation: Operation) -> Result<u64, WalError> {
-        let sequence = self.current_sequence.fetch_add(1, Ordering::SeqCst);
-
-        // Create WAL entry
-        let mut entry = WalEntry::new(sequence, operation);
+        Ok(self.append_entry(operation).await?.sequence)
+    }
 
-        // Compute checksum
-        entry.checksum = entry.compute_checksum()?;
+    /// Append an operation to the WAL, returning the entry written
+    pub async fn append_entry(&self, operation: Operation) -> Result<WalEntry, WalError> {
+        let mut entries = self.append_entries(vec![operation]).await?;
+        Ok(entries.remove(0))
+    }
 
-        // Serialize entry
-        let bytes = self.serialize_entry(&entry)?;
+    /// Append operations to the WAL in one write, returning the entries written
+    ///
+    /// The entries get consecutive sequence numbers and share a file, so a
+    /// batch is never split by a rotation.
+    pub async fn append_entries(&self, operations: Vec<Operation>) -> Result<Vec<WalEntry>, WalError> {
+        let count = operations.len() as u64;
+        let first = self.current_sequence.fetch_add(count, Ordering::SeqCst);
+
+        // Create and serialize the entries
+        let mut entries = Vec::with_capacity(operations.len());
+        let mut bytes = Vec::new();
+        for (sequence, operation) in (first..).zip(operations) {
+            let mut entry = WalEntry::new(sequence, operation);
+            entry.checksum = entry.compute_checksum()?;
+            bytes.extend_from_slice(&self.serialize_entry(&entry)?);
+            entries.push(entry);
+        }
 
         // Check if we need to rotate
         if self.should_rotate(bytes.len()).await {
@@ -123,7 +139,7 @@ impl WalWriter {
         self.current_file_size
             .fetch_add(bytes.len() as u64, Ordering::Relaxed);
 
-        Ok(sequence)
+        Ok(entries)
     }
 
     /// Flush the WAL to disk
@@ -139,6 +155,11 @@ impl WalWriter {
         self.current_sequence.load(Ordering::Relaxed)
     }
 
+    /// Directory holding the WAL files
+    pub fn wal_dir(&self) -> &Path {
+        &self.config.wal_dir
+    }
+
     /// Start background fsync task (for EverySecond policy)
     pub async fn start_background_fsync(self: Arc<Self>) {
         if self.config.fsync_policy != FsyncPolicy::EverySecond {
@@ -235,7 +256,7 @@ impl WalWriter {
         }
 
         let mut max_file_number = 0u64;
-        let mut global_max_sequence = 0u64;
+        let mut global_max_sequence: Option<u64> = None;
 
         for entry in std::fs::read_dir(wal_dir)? {
             let entry = entry?;
@@ -268,28 +289,24 @@ impl WalWriter {
             }
         }
 
-        // Next sequence is one more than the max found
-        let next_sequence = if global_max_sequence > 0 {
-            global_max_sequence + 1
-        } else {
-            0
-        };
+        // Next sequence is one more than the max found, even when that is entry 0
+        let next_sequence = global_max_sequence.map_or(0, |max| max + 1);
 
         Ok((next_sequence, max_file_number))
     }
 
     /// Scan a single WAL file to find the maximum sequence number it contains.
-    /// Returns 0 if file is empty or unreadable.
-    fn scan_file_for_max_sequence(path: &Path) -> Result<u64, WalError> {
+    /// Returns `None` if file is empty or unreadable.
+    fn scan_file_for_max_sequence(path: &Path) -> Result<Option<u64>, WalError> {
         use super::reader::WalReader;
         
         let mut reader = WalReader::open(path)?;
-        let mut max_seq = 0u64;
+        let mut max_seq: Option<u64> = None;
 
         loop {
             match reader.next_entry() {
                 Ok(Some(entry)) => {
-                    max_seq = max_seq.max(entry.sequence);
+                    max_seq = max_seq.max(Some(entry.sequence));
                 }
                 Ok(None) => {
                     // End of file
@@ -297,7 +314,7 @@ impl WalWriter {
                 }
                 Err(WalError::CorruptedEntry(seq)) => {
                     // Skip corrupted entry but record sequence if valid
-                    max_seq = max_seq.max(seq);
+                    max_seq = max_seq.max(Some(seq));
                     // Continue reading - may find more valid entries
                     continue;
                 }
@@ -444,6 +461,36 @@ mod tests {
         assert_eq!(seq2, 1);
     }
 
+    #[tokio::test]
+    async fn test_wal_append_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = WalConfig {
+            wal_dir: temp_dir.path().to_path_buf(),
+            fsync_policy: FsyncPolicy::Always,
+            ..Default::default()
+        };
+
+        let writer = WalWriter::new(config).unwrap();
+        writer.append(Operation::Insert { collection: "users".to_string(), doc: Document::new() }).await.unwrap();
+        let entries = writer
+            .append_entries(vec![
+                Operation::Delete { collection: "users".to_string(), id: DocumentId::new() },
+                Operation::Delete { collection: "users".to_string(), id: DocumentId::new() },
+            ])
+            .await
+            .unwrap();
+        let sequences: Vec<u64> = entries.iter().map(|entry| entry.sequence).collect();
+        assert_eq!(sequences, [1, 2]);
+
+        let files = crate::wal::scan_wal_files(temp_dir.path()).unwrap();
+        let mut reader = crate::wal::WalReader::open(&files[0]).unwrap();
+        let mut read = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            read.push(entry.sequence);
+        }
+        assert_eq!(read, [0, 1, 2]);
+    }
+
     #[tokio::test]
     async fn test_wal_rotation() {
         let temp_dir = TempDir::new().unwrap();
@@ -18,6 +18,7 @@ use veddb_core::{
     ConnectionManager,
     BackupManager, BackupConfig,
     EncryptionEngine, EncryptionConfig,
+    SyncManager, WalConfig, WalWriter,
 };
 use tokio::sync::RwLock as TokioRwLock;
 
@@ -46,6 +47,14 @@ struct Args {
     #[arg(short = 'd', long)]
     debug: bool,
 
+    /// Seconds between sweeps deleting documents expired by TTL indexes
+    #[arg(long, default_value = "60")]
+    ttl_monitor_secs: u64,
+
+    /// Log document writes to a WAL under <data_dir>/wal, which change streams read
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set)]
+    wal: bool,
+
     /// Enable backup functionality
     #[arg(long)]
     enable_backups: bool,
@@ -90,6 +99,8 @@ async fn main() -> Result<()> {
     info!("  • Listen Address: {}:{}", args.host, args.port);
     info!("  • Cache Size: {}MB", args.cache_size_mb);
     info!("  • Debug Mode: {}", args.debug);
+    info!("  • TTL Monitor Interval: {}s", args.ttl_monitor_secs);
+    info!("  • WAL: {}", if args.wal { "enabled" } else { "disabled" });
     info!("");
 
     // Create data directory if it doesn't exist
@@ -110,6 +121,40 @@ async fn main() -> Result<()> {
         persistent_layer.clone(),
     ));
 
+    let restored_schemas = storage.restore_schemas()?;
+    if restored_schemas > 0 {
+        info!("Restored {} collection schemas", restored_schemas);
+    }
+
+    let restored_capped = storage.restore_capped_collections()?;
+    if restored_capped > 0 {
+        info!("Restored {} capped collections", restored_capped);
+    }
+
+    let restored_views = storage.restore_views()?;
+    if restored_views > 0 {
+        info!("Restored {} views", restored_views);
+    }
+
+    let resumed_builds = storage.resume_index_builds()?;
+    if !resumed_builds.is_empty() {
+        info!("Resumed {} index builds", resumed_builds.len());
+    }
+
+    // Document writes go to the WAL, which change streams follow
+    if args.wal {
+        let wal_dir = args.data_dir.join("wal");
+        let wal_writer = Arc::new(WalWriter::new(WalConfig {
+            wal_dir: wal_dir.clone(),
+            ..WalConfig::default()
+        })?);
+        wal_writer.clone().start_background_fsync().await;
+        let sync_manager = Arc::new(SyncManager::new(wal_dir, args.data_dir.join("snapshots")));
+        storage.attach_wal(wal_writer, sync_manager);
+    }
+
+    storage.start_ttl_monitor(std::time::Duration::from_secs(args.ttl_monitor_secs.max(1)));
+
     info!("✓ Storage engine initialized");
     info!("");
 